@@ -42,6 +42,13 @@ _xcp() {
     return
     ;;
 
+  --max-memory)
+    local num="${cur%%[^0-9]*}"
+    local unit="${cur##*[0-9]}"
+    COMPREPLY=($(compgen -P "$num" -W "$units" -- "$unit"))
+    return
+    ;;
+
   --reflink)
     COMPREPLY=($(compgen -W "$reflink" -- "$cur"))
     return
@@ -68,6 +75,9 @@ _xcp() {
     return
   fi
 
+  if ((cword == 1)) && [[ verify == "$cur"* ]]; then
+    COMPREPLY=(verify)
+  fi
   _filedir # suggest files if nothing else matched
 } && complete -F _xcp xcp
 
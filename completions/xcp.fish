@@ -3,6 +3,12 @@ set -l drivers '
   parblock\t"parallelise at the block level"
 '
 
+set -l executors '
+  threads\t"start threads for the copy (default)"
+  sequential\t"run the copy on a single thread"
+  rayon\t"run the copy on a rayon pool, if supported"
+'
+
 set -l reflinks '
   auto\t"attempt to reflink and fallback to a copy (default)"
   always\t"return an error if it cannot reflink"
@@ -13,31 +19,194 @@ set -l backup '
   none\t"no backups (default)"
   numbered\t"follow the semantics of cp numbered backups"
   auto\t"create a numbered backup if previous backup exists"
+  existing\t"as cp; makes a numbered backup"
+  simple\t"as cp; makes a numbered backup"
+'
+
+set -l sparse '
+  auto\t"copy block devices in full (default)"
+  always\t"create sparse images of block devices"
+'
+
+set -l clobbermodes '
+  skip\t"skip existing files in recursive copies (default)"
+  fail\t"abort if any destination exists"
+  rename\t"keep both, copying under a new name"
+'
+
+set -l skipsame '
+  checksum\t"read and compare both files"
+  stamp\t"trust checksums stamped with --stamp-checksum"
+'
+
+set -l dirmodes '
+  preserve-existing\t"leave existing directories untouched (default)"
+  overwrite\t"apply source metadata to existing directories"
+'
+
+set -l orders '
+  scan\t"the order files are found (default)"
+  largest-first\t"largest files first"
+  smallest-first\t"smallest files first"
+'
+
+set -l profiles '
+  auto	"tune for network filesystems when detected (default)"
+  local	"no tuning"
+  network	"tune for a network filesystem"
+'
+
+set -l invalidnames '
+  error\t"report an error (default)"
+  skip\t"skip the entry"
+  sanitize\t"replace invalid characters with _"
+'
+
+set -l progress '
+  bar\t"progress bar on stderr (default)"
+  json\t"JSON events on stdout"
+'
+
+set -l replayed '
+  skipped\t"files skipped because the destination existed"
+  errored\t"files that failed to copy"
+'
+
+set -l colors '
+  auto\t"only on a terminal, unless NO_COLOR is set (default)"
+  always\t"always colour output"
+  never\t"never colour output"
+'
+
+set -l logtargets '
+  auto\t"journald if stderr is the journal, else stderr (default)"
+  stderr\t"the terminal"
+  syslog\t"the local syslog daemon"
+  journald\t"the systemd journal"
+  file:\t"append to a file"
+'
+
+set -l preserve '
+  mode\t"the file mode"
+  ownership\t"the user and group"
+  timestamps\t"access and modification times"
+  links\t"hard-links between copied files"
+  context\t"the SELinux security context"
+  xattr\t"extended attributes"
+  flags\t"inode flags such as immutable"
+  all\t"all of the above"
+'
+
+set -l hashes '
+  blake3\t"BLAKE3 (default)"
+  sha256\t"SHA-256"
 '
 
 # short + long
 complete -c xcp -s T -l no-target-directory -d 'Overwrite target directory, do not create a subdirectory'
 complete -c xcp -s g -l glob -d 'Expand (glob) filename patterns'
 complete -c xcp -s h -l help -f -d 'Print help'
-complete -c xcp -s n -l no-clobber -d 'Do not overwrite an existing file'
+complete -c xcp -s n -l no-clobber -d 'Do not overwrite an existing file' -a "$clobbermodes"
+complete -c xcp -l conflict-suffix -d 'Name copies kept with --no-clobber=rename using TEMPLATE' -x
 complete -c xcp -s f -l force -d 'Compatibility only option'
+complete -c xcp -s i -l interactive -d 'Skip existing destination files (compatibility only)'
+complete -c xcp -s b -d 'Make a numbered backup of each overwritten file'
+complete -c xcp -s t -l target-directory -d 'Copy into a subdirectory of the target' -r -f -a "(__fish_complete_directories)"
+complete -c xcp -s x -l one-file-system -d 'Stay on the filesystem of each source'
 complete -c xcp -s r -l recursive -d 'Copy directories recursively'
 complete -c xcp -s v -l verbose -d 'Increase verbosity (can be repeated)'
+complete -c xcp -s q -l quiet -d 'Only print errors; twice to print nothing'
 complete -c xcp -s w -l workers -d 'Workers for recursive copies (0=auto)' -x -a '(seq 0 (getconf _NPROCESSORS_ONLN))'
+complete -c xcp -l readers-per-device -d 'Read at most N files at once from each source device' -x
+complete -c xcp -l metadata-workers -d 'Apply the metadata of copied files on N separate workers' -x
 complete -c xcp -s L -l dereference -d 'Dereference symlinks in source'
+complete -c xcp -s P -l no-dereference -d 'Copy symlinks in source as symlinks'
+complete -c xcp -s H -l dereference-command-line -d 'Follow symlinks given as sources'
+complete -c xcp -s a -l archive -d 'Archive mode; the same as -r --preserve=all'
 complete -c xcp -s o -l ownership -d 'Copy ownship (user/group)'
+complete -c xcp -s p -d 'Same as --preserve=mode,ownership,timestamps'
+complete -c xcp -s u -l update -d 'Copy only when the source is newer than the destination'
 
 # long
+complete -c xcp -l forbid-overwrite-newer -d 'Do not overwrite destination files newer than the source'
+complete -c xcp -l skip-same -d 'Skip files whose destination has the same contents' -x -a "$skipsame"
+complete -c xcp -l stamp-checksum -d 'Stamp the checksum of each copied file on the copy'
 complete -c xcp -l fsync -d 'Sync each file to disk after it is written'
-complete -c xcp -l target-directory -d 'Copy into a subdirectory of the target'
+complete -c xcp -l staging-dir -d 'Write files under this directory and move them into place once complete' -r -f -a "(__fish_complete_directories)"
+complete -c xcp -l no-fallocate -d 'Do not preallocate destination files'
+complete -c xcp -l force-parblock -d 'Write blocks out of order on any destination with parblock'
+complete -c xcp -l batch-dirents -d 'Create small files a directory at a time'
+complete -c xcp -l no-tiny-file-fastpath -d 'Copy tiny files the same way as any other file'
+complete -c xcp -l order -d 'The order to copy files in' -x -a "$orders"
+complete -c xcp -l profile -d "Tune the copy for the destination's filesystem" -x -a "$profiles"
+complete -c xcp -l sparse -d 'Create sparse images of block devices' -f -a "$sparse"
+complete -c xcp -l no-direct-io -d 'Read block devices through the page cache'
+complete -c xcp -l fanout -d 'Copy a single source to several destinations, reading it once'
+complete -c xcp -l dest-subdir-from-source -d 'Copy each source into a subdirectory of the target named after it'
 complete -c xcp -l gitignore -d 'Use .gitignore if present'
+complete -c xcp -l exclude -d "Don't copy entries matching PATTERN" -x
+complete -c xcp -l exclude-from -d 'Read exclude patterns from FILE' -r -F
+complete -c xcp -l include -d 'Copy entries matching PATTERN even if they are excluded' -x
+complete -c xcp -l preserve -d 'Copy the given file attributes' -f -a "$preserve"
+complete -c xcp -l no-preserve -d 'Do not copy the given file attributes' -x -a "$preserve"
 complete -c xcp -l no-perms -d 'Do not copy file permissions'
 complete -c xcp -l no-timestamps -d 'Do not copy file timestamps'
+complete -c xcp -l no-dir-timestamps -d 'Do not copy directory timestamps'
+complete -c xcp -l preserve-subvolumes -d 'Recreate btrfs subvolumes as subvolumes'
+complete -c xcp -l follow-dest-symlinks -d 'Write through a destination symlink to a nonexistent target'
+complete -c xcp -l mkdir-parents -d 'Create missing parents of a dangling symlink target'
+complete -c xcp -l remove-destination -d 'Replace destination symlinks with regular files'
 complete -c xcp -l no-progress -d 'Disable progress bar'
+complete -c xcp -l progress -d 'Progress output mode' -x -a "$progress"
+complete -c xcp -l color -d 'When to colour terminal output' -x -a "$colors"
+complete -c xcp -l show-current -d 'Show the files currently being copied' -x
+complete -c xcp -l stall-warning -d 'Warn when the copy stalls for SECS seconds' -x
+complete -c xcp -l stall-timeout -d 'Abort the copy if it stalls for SECS seconds' -x
+complete -c xcp -l metrics-interval -d 'Report the state of the copy workers every SECS seconds' -x
+complete -c xcp -l timeout -d 'Stop the copy after DURATION' -x
+complete -c xcp -l file-timeout -d 'Abandon any file that takes longer than DURATION to copy' -x
+complete -c xcp -l no-lock -d "Don't lock the destination"
+complete -c xcp -l wait-lock -d "Wait for another copy's lock on the destination" -f
 complete -c xcp -l block-size -d 'Block size for file operations' -x -a '(seq 1 16){B,K,M,G}'
+complete -c xcp -l max-memory -d 'Maximum memory for copy buffers' -x -a '(seq 1 16){K,M,G}'
+complete -c xcp -l preserve-hardlinks -d 'Preserve hard-links between copied files'
+complete -c xcp -l cache-linked-sources -d 'Copy hard-linked sources from the destination'
+complete -c xcp -l chmod -d 'Override the mode of copied files' -x
+complete -c xcp -l xattr-value-limit -d 'Skip xattrs with values larger than this' -x
+complete -c xcp -l chown -d 'Override the ownership of copied files' -x -a '(__fish_complete_users)'
 complete -c xcp -l driver -d 'Parallelise at the file or at the block level' -x -a "$drivers"
+complete -c xcp -l executor -d 'How the copy drivers run their work' -x -a "$executors"
 complete -c xcp -l reflink -d 'Whether and how to use reflinks' -x -a "$reflinks"
 complete -c xcp -l backup -d 'Whether to create backups of overwritten files' -x -a "$backup"
+complete -c xcp -l dir-mode -d 'Whether to apply source metadata to existing directories' -x -a "$dirmodes"
+complete -c xcp -l new-dir-mode -d 'The mode of directories with no source counterpart' -x
+complete -c xcp -l prune-empty-dirs -d "Don't create destination directories that would be left empty"
+complete -c xcp -l invalid-name -d 'How to handle names the destination cannot represent' -x -a "$invalidnames"
+complete -c xcp -l continue-on-error -d 'Continue copying after errors'
+complete -c xcp -l max-errors -d 'Stop after N errors with --continue-on-error' -x
+complete -c xcp -l max-error-rate -d 'Stop after more than N errors in 1000 files with --continue-on-error' -x
+complete -c xcp -l really-continue-on-enospc -d 'Continue copying when the destination is full'
+complete -c xcp -l read-retries -d 'Retry failed reads of a source file N times' -x
+complete -c xcp -l fill-errors -d 'Write zeros for unreadable parts of source files'
+complete -c xcp -l journal -d 'Append a record of every action taken to a journal' -r -F
+complete -c xcp -l replay -d 'Copy again the files a journal recorded as skipped or failed' -r -F
+complete -c xcp -l only -d 'Only replay the files skipped, or those that failed' -x -a "$replayed"
+complete -c xcp -l manifest -d 'Write a manifest of the copied files' -r -F
+complete -c xcp -n __fish_is_first_arg -a verify -d 'Check files against a manifest written with --manifest'
+complete -c xcp -l manifest-hash -d 'Checksum algorithm for the manifest' -x -a "$hashes"
+complete -c xcp -l dry-run -d 'Show what would be copied without modifying the destination'
+complete -c xcp -l itemize -d 'Print a summary of the changes made to the destination'
+complete -c xcp -l delete -d 'Delete extraneous files from the destination'
+complete -c xcp -l compare-only -d 'Compare the source and destination trees without copying'
+complete -c xcp -l compare-checksum -d 'Compare file contents with this checksum algorithm' -x -a "$hashes"
+complete -c xcp -l yes -d 'Do not ask for confirmation of risky copies'
+complete -c xcp -l confirm-threshold -d 'Confirm --delete copies into destinations with more than N entries' -x
+complete -c xcp -l allow-dotdot-dest -d "Allow '..' in the destination to climb above its existing parent"
+complete -c xcp -l offset -x -d 'Copy only the bytes from this offset of the source'
+complete -c xcp -l length -x -d 'Copy only this many bytes of the source'
+complete -c xcp -l dest-offset -x -d 'Write the copied range at this offset in the destination'
+complete -c xcp -l log-target -d 'Where to write log messages' -x -a "$logtargets"
+complete -c xcp -l trace-out -r -F -d 'Write a Chrome trace of the copy to FILE (requires the tracing feature)'
 
 # docs: https://fishshell.com/docs/current/completions.html
 # path: /usr/share/fish/vendor_completions.d/xcp.fish
@@ -15,56 +15,230 @@
  */
 
 
-use log::{debug, warn};
-use rustix::fs::{fsync, ftruncate};
-use rustix::io::{pread, pwrite};
+use log::debug;
+use rustix::fs::{fchmod, flock, fsync, ftruncate, statvfs, FlockOperation, Mode, RawMode};
+use rustix::io::{pread, pwrite, Errno};
 use std::cmp;
-use std::fs::{File, FileTimes};
-use std::io::{ErrorKind, Read, Write};
-use std::os::unix::fs::{fchown, MetadataExt};
+use std::fmt;
+use std::ffi::{CString, OsStr};
+use std::fs::{remove_file, File, FileTimes, Metadata};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::fs::{fchown, MetadataExt, OpenOptionsExt};
 use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use xattr::FileExt;
 
+use crate::counters::{count, Syscall};
 use crate::errors::{Result, Error};
-use crate::{Extent, XATTR_SUPPORTED, copy_sparse, probably_sparse, copy_file_bytes};
+use crate::backend::{link_xattr_size, xattr_size};
+use crate::{Extent, XATTR_SUPPORTED, copy_sparse, probably_sparse, copy_file_bytes, warn_repeated};
 
-fn copy_xattr(infd: &File, outfd: &File) -> Result<()> {
-    // FIXME: Flag for xattr.
+// Portable values; libc's constants vary in type between platforms.
+const S_ISUID: u32 = 0o4000;
+const S_ISGID: u32 = 0o2000;
+
+/// The xattr holding a file's SELinux security context.
+pub const SELINUX_XATTR: &str = "security.selinux";
+
+// The xattrs of an open file, or of a path without following
+// symlinks.
+#[derive(Clone, Copy)]
+enum XattrNode<'a> {
+    File(&'a File),
+    Link(&'a Path),
+}
+
+impl fmt::Debug for XattrNode<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XattrNode::File(fd) => fd.fmt(f),
+            XattrNode::Link(path) => path.fmt(f),
+        }
+    }
+}
+
+impl XattrNode<'_> {
+    fn list(self) -> io::Result<xattr::XAttrs> {
+        match self {
+            XattrNode::File(fd) => fd.list_xattr(),
+            XattrNode::Link(path) => xattr::list(path),
+        }
+    }
+
+    fn size(self, name: &OsStr) -> Result<Option<u64>> {
+        match self {
+            XattrNode::File(fd) => xattr_size(fd, name),
+            XattrNode::Link(path) => link_xattr_size(path, name),
+        }
+    }
+
+    // The device, for grouping repeated warnings; see [crate::dedup].
+    fn dev(self) -> u64 {
+        let meta = match self {
+            XattrNode::File(fd) => fd.metadata(),
+            XattrNode::Link(path) => path.symlink_metadata(),
+        };
+        meta.map_or(0, |m| m.dev())
+    }
+
+    // The xattr crate resizes and retries if the value grows between
+    // sizing and reading it.
+    fn get(self, name: &OsStr) -> io::Result<Option<Vec<u8>>> {
+        match self {
+            XattrNode::File(fd) => fd.get_xattr(name),
+            XattrNode::Link(path) => xattr::get(path, name),
+        }
+    }
+
+    fn set(self, name: &OsStr, value: &[u8]) -> io::Result<()> {
+        count(Syscall::Metadata);
+        match self {
+            XattrNode::File(fd) => fd.set_xattr(name, value),
+            XattrNode::Link(path) => xattr::set(path, name, value),
+        }
+    }
+}
+
+fn copy_node_xattrs(from: XattrNode, to: XattrNode, value_limit: Option<u64>, include: &dyn Fn(&OsStr) -> bool) -> Result<()> {
     if XATTR_SUPPORTED {
         debug!("Starting xattr copy...");
-        for attr in infd.list_xattr()? {
-            if let Some(val) = infd.get_xattr(&attr)? {
-                debug!("Copy xattr {:?}", attr);
-                outfd.set_xattr(attr, val.as_slice())?;
+        let too_large = |size| value_limit.is_some_and(|limit| size > limit);
+        // Names are not necessarily UTF-8, so are only ever handled as
+        // OsStr.
+        for attr in from.list()?.filter(|attr| include(attr)) {
+            if let Some(size) = from.size(&attr)? {
+                if too_large(size) {
+                    warn_repeated!(from.dev(), "Skipping xattr {:?} of {:?}; value of {} bytes exceeds limit", attr, from, size);
+                    continue;
+                }
+            }
+            let Some(val) = from.get(&attr)? else {
+                continue;
+            };
+            if too_large(val.len() as u64) {
+                warn_repeated!(from.dev(), "Skipping xattr {:?} of {:?}; value of {} bytes exceeds limit", attr, from, val.len());
+                continue;
+            }
+            debug!("Copy xattr {:?} ({} bytes)", attr, val.len());
+            if let Err(e) = to.set(&attr, val.as_slice()) {
+                match e.raw_os_error() {
+                    // The destination has a lower limit on xattr size
+                    // or total space; copy what we can.
+                    Some(libc::ENOSPC) | Some(libc::E2BIG) => {
+                        warn_repeated!(to.dev(), "Failed to copy xattr {:?} to {:?}: {}", attr, to, e);
+                    }
+                    _ => return Err(e.into()),
+                }
             }
         }
     }
     Ok(())
 }
 
+/// Set an [xattr](https://man7.org/linux/man-pages/man7/xattr.7.html)
+/// on an open file. Returns false if xattrs aren't supported by the OS
+/// or the filesystem.
+pub fn set_xattr(fd: &File, name: &str, value: &[u8]) -> Result<bool> {
+    if !XATTR_SUPPORTED {
+        return Ok(false);
+    }
+    match XattrNode::File(fd).set(OsStr::new(name), value) {
+        Ok(()) => Ok(true),
+        Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Read an [xattr](https://man7.org/linux/man-pages/man7/xattr.7.html)
+/// of a path, without following symlinks. Returns `None` if it isn't
+/// set, or xattrs aren't supported by the OS or the filesystem.
+pub fn get_xattr(path: &Path, name: &str) -> Result<Option<Vec<u8>>> {
+    if !XATTR_SUPPORTED {
+        return Ok(None);
+    }
+    match XattrNode::Link(path).get(OsStr::new(name)) {
+        Ok(value) => Ok(value),
+        Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Copy the [xattrs](https://man7.org/linux/man-pages/man7/xattr.7.html)
+/// for which `include` returns true, if supported. The files may be
+/// directories.
+///
+/// Values larger than `value_limit` bytes are skipped with a warning,
+/// as are values the destination doesn't have space for.
+pub fn copy_xattrs(infd: &File, outfd: &File, value_limit: Option<u64>, include: &dyn Fn(&OsStr) -> bool) -> Result<()> {
+    copy_node_xattrs(XattrNode::File(infd), XattrNode::File(outfd), value_limit, include)
+}
+
+/// Copy the xattrs of the symlink `from` to the symlink `to`, rather
+/// than those of their targets; otherwise as [copy_xattrs]. Linux
+/// only allows some namespaces on symlinks, e.g. `trusted` and
+/// `security` but not `user`.
+pub fn copy_link_xattrs(from: &Path, to: &Path, value_limit: Option<u64>, include: &dyn Fn(&OsStr) -> bool) -> Result<()> {
+    copy_node_xattrs(XattrNode::Link(from), XattrNode::Link(to), value_limit, include)
+}
+
 /// Copy file permissions. Will also copy
 /// [xattr](https://man7.org/linux/man-pages/man7/xattr.7.html)'s if
 /// possible.
-pub fn copy_permissions(infd: &File, outfd: &File) -> Result<()> {
-    let xr = copy_xattr(infd, outfd);
+///
+/// See [copy_mode] for the handling of the setuid and setgid bits.
+///
+/// Xattr values larger than `xattr_value_limit` bytes are skipped
+/// with a warning, as are values the destination doesn't have space
+/// for.
+pub fn copy_permissions(infd: &File, outfd: &File, preserve_setid: bool, xattr_value_limit: Option<u64>) -> Result<()> {
+    let xr = copy_xattrs(infd, outfd, xattr_value_limit, &|_| true);
     if let Err(e) = xr {
         // FIXME: We don't have a way of detecting if the
         // target FS supports XAttr, so assume any error is
         // "Unsupported" for now.
-        warn!("Failed to copy xattrs from {:?}: {}", infd, e);
+        warn_repeated!(outfd.metadata().map_or(0, |m| m.dev()), "Failed to copy xattrs from {:?}: {}", infd, e);
     }
 
-    // FIXME: ACLs, selinux, etc.
+    // FIXME: ACLs, etc.
 
+    copy_mode(infd, outfd, preserve_setid)
+}
+
+/// Copy the file mode.
+///
+/// The full mode is copied, including the setuid, setgid and sticky
+/// bits. As with `cp -p`, unless `preserve_setid` is set the setuid
+/// and setgid bits are cleared if the destination does not have the
+/// same owner and group respectively as the source; ownership should
+/// therefore be copied first.
+pub fn copy_mode(infd: &File, outfd: &File, preserve_setid: bool) -> Result<()> {
     let inmeta = infd.metadata()?;
+    let mut mode = inmeta.mode() & 0o7777;
+    if !preserve_setid && mode & (S_ISUID | S_ISGID) != 0 {
+        let outmeta = outfd.metadata()?;
+        if outmeta.uid() != inmeta.uid() {
+            mode &= !S_ISUID;
+        }
+        if outmeta.gid() != inmeta.gid() {
+            mode &= !S_ISGID;
+        }
+        if mode != inmeta.mode() & 0o7777 {
+            debug!("Ownership differs, clearing setuid/setgid bits");
+        }
+    }
 
     debug!("Performing permissions copy");
-    outfd.set_permissions(inmeta.permissions())?;
+    count(Syscall::Metadata);
+    fchmod(outfd, Mode::from_raw_mode(mode as RawMode))?;
 
     Ok(())
 }
 
 /// Copy file timestamps.
+///
+/// Timestamps are set at full precision; filesystems with a coarser
+/// resolution will truncate them, so the destination may not compare
+/// equal to the source. See [timestamp_granularity].
 pub fn copy_timestamps(infd: &File, outfd: &File) -> Result<()> {
     let inmeta = infd.metadata()?;
 
@@ -72,93 +246,292 @@ pub fn copy_timestamps(infd: &File, outfd: &File) -> Result<()> {
     let ftime = FileTimes::new()
         .set_accessed(inmeta.accessed()?)
         .set_modified(inmeta.modified()?);
+    count(Syscall::Metadata);
     outfd.set_times(ftime)?;
 
     Ok(())
 }
 
+/// The allocation unit and free space of a filesystem; see
+/// [fs_space].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FsSpace {
+    /// The fundamental block size (`f_frsize`); each file takes a
+    /// whole number of these.
+    pub block_size: u64,
+    /// The bytes available to unprivileged users.
+    pub available: u64,
+}
+
+/// The allocation unit and free space of the filesystem containing
+/// `path`.
+pub fn fs_space(path: &Path) -> Result<FsSpace> {
+    let st = statvfs(path)?;
+    // Some filesystems leave the fragment size unset.
+    let block_size = if st.f_frsize > 0 { st.f_frsize } else { st.f_bsize }.max(1);
+    Ok(FsSpace {
+        block_size,
+        available: st.f_bavail.saturating_mul(block_size),
+    })
+}
+
+/// Probe the timestamp resolution of the filesystem containing
+/// `dir`. This creates a temporary file in `dir`, sets a known
+/// modification time on it and reads it back.
+pub fn timestamp_granularity(dir: &Path) -> Result<Duration> {
+    let probe = dir.join(format!(".tsprobe-{}", std::process::id()));
+    let set = UNIX_EPOCH + Duration::new(1_000_000_001, 123_456_789);
+
+    let fd = File::create(&probe)?;
+    let got = fd.set_modified(set)
+        .and_then(|_| fd.metadata()?.modified());
+    drop(fd);
+    remove_file(&probe)?;
+
+    Ok(granularity_from_probe(set, got?))
+}
+
+// Infer the resolution from how a probe timestamp was truncated;
+// e.g. FAT rounds to 2 seconds, NTFS to 100ns.
+fn granularity_from_probe(set: SystemTime, got: SystemTime) -> Duration {
+    let set = set.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let got = got.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    if got == set {
+        Duration::from_nanos(1)
+    } else if got.as_secs() != set.as_secs() {
+        Duration::from_secs(2)
+    } else {
+        // Resolution is the number of trailing decimal zeros.
+        let zeros = format!("{:09}", got.subsec_nanos())
+            .bytes()
+            .rev()
+            .take_while(|b| *b == b'0')
+            .count();
+        Duration::from_nanos(10_u64.pow(zeros as u32))
+    }
+}
+
 pub fn copy_owner(infd: &File, outfd: &File) -> Result<()> {
     let inmeta = infd.metadata()?;
+    count(Syscall::Metadata);
     fchown(outfd, Some(inmeta.uid()), Some(inmeta.gid()))?;
 
     Ok(())
 }
 
-pub(crate) fn read_bytes(fd: &File, buf: &mut [u8], off: usize) -> Result<usize> {
-    Ok(pread(fd, buf, off as u64)?)
+/// Look up a user id by name in the users database. Returns `None`
+/// if there is no such user.
+pub fn lookup_user(name: &str) -> Result<Option<u32>> {
+    let cname = CString::new(name).map_err(|_| Error::InvalidName(name.to_string()))?;
+    lookup_db(|pwd: &mut libc::passwd, buf, len, result| unsafe {
+        libc::getpwnam_r(cname.as_ptr(), pwd, buf, len, result)
+    }, |pwd| pwd.pw_uid)
+}
+
+/// Look up a group id by name in the groups database. Returns `None`
+/// if there is no such group.
+pub fn lookup_group(name: &str) -> Result<Option<u32>> {
+    let cname = CString::new(name).map_err(|_| Error::InvalidName(name.to_string()))?;
+    lookup_db(|grp: &mut libc::group, buf, len, result| unsafe {
+        libc::getgrnam_r(cname.as_ptr(), grp, buf, len, result)
+    }, |grp| grp.gr_gid)
+}
+
+// Common handling for the reentrant getXXnam_r() functions, which
+// require a caller-supplied buffer that may need to be grown.
+fn lookup_db<T, F, G>(lookup: F, id: G) -> Result<Option<u32>>
+where
+    F: Fn(&mut T, *mut libc::c_char, libc::size_t, *mut *mut T) -> libc::c_int,
+    G: Fn(&T) -> u32,
+{
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        let mut entry = unsafe { std::mem::zeroed::<T>() };
+        let mut result = std::ptr::null_mut();
+        let ret = lookup(&mut entry, buf.as_mut_ptr(), buf.len(), &mut result);
+        match ret {
+            0 if result.is_null() => return Ok(None),
+            0 => return Ok(Some(id(&entry))),
+            libc::ERANGE if buf.len() < 1024 * 1024 => buf.resize(buf.len() * 2, 0),
+            // Some systems report a missing entry as an error.
+            libc::ENOENT | libc::ESRCH | libc::EBADF | libc::EPERM => return Ok(None),
+            err => return Err(std::io::Error::from_raw_os_error(err).into()),
+        }
+    }
+}
+
+/// The largest buffer used for userspace copies; larger copies are
+/// done in chunks. This also keeps buffer sizes within `usize` on
+/// 32-bit platforms.
+const USPACE_BUF_MAX: u64 = 64 * 1024 * 1024;
+
+fn uspace_buffer(nbytes: u64) -> Vec<u8> {
+    vec![0; cmp::min(nbytes, USPACE_BUF_MAX) as usize]
 }
 
-pub(crate) fn write_bytes(fd: &File, buf: &mut [u8], off: usize) -> Result<usize> {
-    Ok(pwrite(fd, buf, off as u64)?)
+pub(crate) fn read_bytes(fd: &File, buf: &mut [u8], off: u64) -> Result<usize> {
+    count(Syscall::Read);
+    Ok(pread(fd, buf, off)?)
+}
+
+pub(crate) fn write_bytes(fd: &File, buf: &mut [u8], off: u64) -> Result<usize> {
+    count(Syscall::Write);
+    Ok(pwrite(fd, buf, off)?)
 }
 
 /// Copy a block of bytes at an offset between files. Uses Posix pread/pwrite.
-pub(crate) fn copy_range_uspace(reader: &File, writer: &File, nbytes: usize, off: usize) -> Result<usize> {
+pub(crate) fn copy_range_uspace(reader: &File, writer: &File, nbytes: u64, off: u64) -> Result<u64> {
+    copy_range_uspace_observed(reader, writer, nbytes, off, &mut |_| {})
+}
+
+/// As [copy_range_uspace], but passes each block of data to
+/// `observer` after it has been written.
+pub(crate) fn copy_range_uspace_observed(
+    reader: &File,
+    writer: &File,
+    nbytes: u64,
+    off: u64,
+    observer: &mut dyn FnMut(&[u8]),
+) -> Result<u64> {
+    copy_between_uspace_observed(reader, off, writer, off, nbytes, observer)
+}
+
+/// As [copy_range_uspace], but reads and writes at different offsets.
+pub(crate) fn copy_between_uspace(
+    reader: &File,
+    in_off: u64,
+    writer: &File,
+    out_off: u64,
+    nbytes: u64,
+) -> Result<u64> {
+    copy_between_uspace_observed(reader, in_off, writer, out_off, nbytes, &mut |_| {})
+}
+
+fn copy_between_uspace_observed(
+    reader: &File,
+    in_off: u64,
+    writer: &File,
+    out_off: u64,
+    nbytes: u64,
+    observer: &mut dyn FnMut(&[u8]),
+) -> Result<u64> {
     // FIXME: For larger buffers we should use a pre-allocated thread-local?
-    let mut buf = vec![0; nbytes];
+    let mut buf = uspace_buffer(nbytes);
 
-    let mut written: usize = 0;
+    let mut written: u64 = 0;
     while written < nbytes {
-        let next = cmp::min(nbytes - written, nbytes);
-        let noff = off + written;
+        let next = cmp::min(nbytes - written, buf.len() as u64) as usize;
+        let (roff, woff) = (in_off + written, out_off + written);
 
-        let rlen = match read_bytes(reader, &mut buf[..next], noff) {
-            Ok(0) => return Err(Error::InvalidSource("Source file ended prematurely.")),
+        let rlen = match read_bytes(reader, &mut buf[..next], roff) {
+            Ok(0) => return Err(Error::SourceEnded { copied: written }),
             Ok(len) => len,
             Err(e) => return Err(e),
         };
 
-        let _wlen = match write_bytes(writer, &mut buf[..rlen], noff) {
-            Ok(len) if len < rlen => {
-                return Err(Error::InvalidSource("Failed write to file."))
-            }
-            Ok(len) => len,
-            Err(e) => return Err(e),
-        };
+        // Retry short writes; if the device is full the retry will
+        // return the underlying error (i.e. ENOSPC).
+        let mut wlen = 0;
+        while wlen < rlen {
+            wlen += match write_bytes(writer, &mut buf[wlen..rlen], woff + wlen as u64) {
+                Ok(0) => return Err(Error::WriteFailed { offset: woff + wlen as u64 }),
+                Ok(len) => len,
+                Err(e) => return Err(e),
+            };
+        }
+        observer(&buf[..rlen]);
 
-        written += rlen;
+        written += rlen as u64;
     }
     Ok(written)
 }
 
 /// Slightly modified version of io::copy() that only copies a set amount of bytes.
-pub(crate) fn copy_bytes_uspace(mut reader: &File, mut writer: &File, nbytes: usize) -> Result<usize> {
-    let mut buf = vec![0; nbytes];
+pub(crate) fn copy_bytes_uspace(reader: &File, writer: &File, nbytes: u64) -> Result<u64> {
+    copy_bytes_uspace_observed(reader, writer, nbytes, &mut |_| {})
+}
 
-    let mut written = 0;
+/// As [copy_bytes_uspace], but passes each block of data to
+/// `observer` after it has been written.
+pub(crate) fn copy_bytes_uspace_observed(
+    mut reader: &File,
+    mut writer: &File,
+    nbytes: u64,
+    observer: &mut dyn FnMut(&[u8]),
+) -> Result<u64> {
+    let mut buf = uspace_buffer(nbytes);
+
+    let mut written: u64 = 0;
     while written < nbytes {
-        let next = cmp::min(nbytes - written, nbytes);
+        let next = cmp::min(nbytes - written, buf.len() as u64) as usize;
+        count(Syscall::Read);
         let len = match reader.read(&mut buf[..next]) {
-            Ok(0) => return Err(Error::InvalidSource("Source file ended prematurely.")),
+            Ok(0) => return Err(Error::SourceEnded { copied: written }),
             Ok(len) => len,
             Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
             Err(e) => return Err(e.into())
         };
+        count(Syscall::Write);
         writer.write_all(&buf[..len])?;
-        written += len;
+        observer(&buf[..len]);
+        written += len as u64;
     }
     Ok(written)
 }
 
+/// Copy a set amount of bytes from the current file cursors, passing
+/// each block of data to `observer` as it is written. Unlike
+/// [copy_file_bytes](crate::copy_file_bytes) this always copies via
+/// userspace, which allows e.g. checksumming the data without
+/// re-reading the file.
+pub fn copy_file_bytes_observed(
+    infd: &File,
+    outfd: &File,
+    bytes: u64,
+    observer: &mut dyn FnMut(&[u8]),
+) -> Result<u64> {
+    copy_bytes_uspace_observed(infd, outfd, bytes, observer)
+}
+
+// Copy exactly `bytes` from the current cursors. A single
+// copy_file_range call may copy less than requested, e.g. over about
+// 2GB.
+pub(crate) fn copy_file_bytes_all(infd: &File, outfd: &File, bytes: u64) -> Result<u64> {
+    let mut copied = 0;
+    while copied < bytes {
+        match copy_file_bytes(infd, outfd, bytes - copied)? {
+            0 => return Err(Error::SourceEnded { copied }),
+            n => copied += n,
+        }
+    }
+    Ok(copied)
+}
+
 /// Allocate file space on disk. Uses Posix ftruncate().
 pub fn allocate_file(fd: &File, len: u64) -> Result<()> {
+    count(Syscall::Preallocate);
     Ok(ftruncate(fd, len)?)
 }
 
-/// Merge any contiguous extents in a list. See [merge_extents].
-pub fn merge_extents(extents: Vec<Extent>) -> Result<Vec<Extent>> {
+/// Merge any contiguous or overlapping extents in a list. Extents
+/// are half-open ranges, so `0..10` and `10..20` are contiguous. The
+/// result is sorted by start offset. A merged extent is only marked
+/// as shared if all of its parts are.
+pub fn merge_extents(mut extents: Vec<Extent>) -> Result<Vec<Extent>> {
+    extents.sort_by_key(|e| e.start);
     let mut merged: Vec<Extent> = vec![];
 
     let mut prev: Option<Extent> = None;
     for e in extents {
         match prev {
             Some(p) => {
-                if e.start == p.end + 1 {
+                if e.start <= p.end {
                     // Current & prev are contiguous, merge & see what
                     // comes next.
                     prev = Some(Extent {
                         start: p.start,
-                        end: e.end,
+                        end: cmp::max(p.end, e.end),
                         shared: p.shared & e.shared,
                     });
                 } else {
@@ -178,14 +551,69 @@ pub fn merge_extents(extents: Vec<Extent>) -> Result<Vec<Extent>> {
 }
 
 
-/// Determine if two files are the same by examining their inodes.
-pub fn is_same_file(src: &Path, dest: &Path) -> Result<bool> {
-    let sstat = src.metadata()?;
-    let dstat = dest.metadata()?;
-    let same = (sstat.ino() == dstat.ino())
-        && (sstat.dev() == dstat.dev());
+/// The result of comparing a source and destination with
+/// [same_inode].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SameFile {
+    /// Both paths refer to the same inode on the same device.
+    Same,
+    /// The paths refer to different files.
+    Different,
+    /// The destination does not exist. When following symlinks this
+    /// includes a dangling link.
+    DestMissing,
+}
+
+/// Determine if a destination refers to the same file as a source by
+/// comparing their device and inode numbers. With `follow` symlinks
+/// are resolved for both paths, otherwise a symlink is compared as
+/// itself. The source must exist; a missing destination is not an
+/// error.
+pub fn same_inode(src: &Path, dest: &Path, follow: bool) -> Result<SameFile> {
+    let stat = |path: &Path| if follow { path.metadata() } else { path.symlink_metadata() };
+    let sstat = stat(src)?;
+    let dstat = match stat(dest) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(SameFile::DestMissing),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(compare_ids((sstat.dev(), sstat.ino()), (dstat.dev(), dstat.ino())))
+}
+
+// Inode numbers are only unique within a device.
+fn compare_ids(src: (u64, u64), dest: (u64, u64)) -> SameFile {
+    if src == dest {
+        SameFile::Same
+    } else {
+        SameFile::Different
+    }
+}
+
+/// Open the directory at `path` for reading. This fails if it is not
+/// a directory, or unless `follow` is set if it is a symlink to one,
+/// so the checks made on the open directory hold for the rest of its
+/// use; see [is_not_dir](crate::is_not_dir).
+pub fn open_dir(path: &Path, follow: bool) -> Result<File> {
+    let flags = if follow { libc::O_DIRECTORY } else { libc::O_DIRECTORY | libc::O_NOFOLLOW };
+    Ok(File::options()
+        .read(true)
+        .custom_flags(flags)
+        .open(path)?)
+}
+
+/// Determine if two paths are on the same device, and so whether one
+/// can be renamed to the other. Symlinks are followed.
+pub fn same_device(a: &Path, b: &Path) -> Result<bool> {
+    Ok(a.metadata()?.dev() == b.metadata()?.dev())
+}
 
-    Ok(same)
+/// Determine if an entry found while walking a directory tree is the
+/// directory `dir`, using metadata that has already been fetched. As
+/// this compares the device and inode rather than the path it also
+/// matches the same directory reached via a bind mount or symlink.
+pub fn is_same_dir_tree_entry(entry: &Metadata, dir: &Metadata) -> bool {
+    entry.is_dir() && dir.is_dir()
+        && compare_ids((entry.dev(), entry.ino()), (dir.dev(), dir.ino())) == SameFile::Same
 }
 
 /// Copy a file. This differs from [std::fs::copy] in that it looks
@@ -200,12 +628,62 @@ pub fn copy_file(from: &Path, to: &Path) -> Result<u64> {
     let total = if probably_sparse(&infd)? {
         copy_sparse(&infd, &outfd)?
     } else {
-        copy_file_bytes(&infd, &outfd, len)? as u64
+        copy_file_bytes_all(&infd, &outfd, len)?
     };
 
     Ok(total)
 }
 
+/// The largest IO size [preferred_io_size] will choose by default.
+pub const MAX_IO_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Choose the IO size for copying between files whose preferred IO
+/// sizes (`st_blksize`) are `src_blksize` and `dest_blksize`. This is
+/// the larger of them and `floor`, capped at `limit`. Where possible
+/// the result is a multiple of the larger preferred size, so that
+/// blocks don't split e.g. RAID stripes; it is rounded up to one if
+/// that stays within `limit`, and otherwise down.
+pub fn preferred_io_size(src_blksize: u64, dest_blksize: u64, floor: u64, limit: u64) -> u64 {
+    let preferred = cmp::max(src_blksize, dest_blksize).max(1);
+    let limit = limit.max(1);
+    let size = cmp::max(preferred, floor).min(limit);
+    if preferred > limit {
+        return size;
+    }
+    match size.div_ceil(preferred).checked_mul(preferred) {
+        Some(up) if up <= limit => up,
+        _ => size - size % preferred,
+    }
+}
+
+/// Try to take an exclusive advisory lock on an open file, without
+/// blocking. Returns `false` if another process holds a lock. The lock
+/// is released when the file is closed. Uses `flock(2)`.
+pub fn try_lock_file(fd: &File) -> Result<bool> {
+    match flock(fd, FlockOperation::NonBlockingLockExclusive) {
+        Ok(()) => Ok(true),
+        Err(Errno::WOULDBLOCK) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether a process with the given pid exists, e.g. to check if the
+/// holder of a lock recorded in a file has exited. A process owned by
+/// another user exists even though it can't be signalled. Uses
+/// `kill(2)` with signal 0.
+pub fn process_exists(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    if pid <= 0 {
+        return false;
+    }
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
 /// Sync an open file to disk. Uses `fsync(2)`.
 pub fn sync(fd: &File) -> Result<()> {
     Ok(fsync(fd)?)
@@ -214,9 +692,13 @@ pub fn sync(fd: &File) -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::read;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use std::collections::BTreeSet;
+    use std::fs::{read, read_dir};
     use std::ops::Range;
     use tempfile::tempdir;
+    use crate::is_not_dir;
 
     impl From<Range<u64>> for Extent {
         fn from(r: Range<u64>) -> Self {
@@ -234,7 +716,7 @@ mod tests {
         let from = dir.path().join("from.bin");
         let to = dir.path().join("to.bin");
         let size = 128 * 1024;
-        let data = "X".repeat(size);
+        let data = "X".repeat(size as usize);
 
         {
             let mut fd: File = File::create(&from).unwrap();
@@ -264,7 +746,7 @@ mod tests {
         let from = dir.path().join("from.bin");
         let to = dir.path().join("to.bin");
         let size = 128 * 1024;
-        let data = "X".repeat(size);
+        let data = "X".repeat(size as usize);
 
         {
             let mut fd: File = File::create(&from).unwrap();
@@ -294,6 +776,218 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_copy_between_offsets() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        std::fs::write(&from, "0123456789")?;
+        std::fs::write(&to, "abcdefghij")?;
+
+        let infd = File::open(&from)?;
+        let outfd = File::options().write(true).open(&to)?;
+        assert_eq!(4, copy_between_uspace(&infd, 2, &outfd, 8, 4)?);
+        assert_eq!(b"abcdefgh2345".to_vec(), read(&to)?);
+
+        assert_eq!(3, crate::copy_file_at(&infd, 7, &outfd, 0, 3)?);
+        assert_eq!(b"789defgh2345".to_vec(), read(&to)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_same_dir_tree_entry() -> Result<()> {
+        let dir = tempdir()?;
+        let sub = dir.path().join("sub");
+        std::fs::create_dir(&sub)?;
+        std::os::unix::fs::symlink(&sub, dir.path().join("link"))?;
+        let file = dir.path().join("file");
+        std::fs::write(&file, "data")?;
+
+        let meta = sub.metadata()?;
+        assert!(is_same_dir_tree_entry(&meta, &dir.path().join("link").metadata()?));
+        assert!(!is_same_dir_tree_entry(&meta, &dir.path().metadata()?));
+        assert!(!is_same_dir_tree_entry(&file.metadata()?, &file.metadata()?));
+        Ok(())
+    }
+
+    #[test]
+    fn test_same_inode() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("file");
+        std::fs::write(&file, "data")?;
+        let other = dir.path().join("other");
+        std::fs::write(&other, "data")?;
+        let missing = dir.path().join("missing");
+
+        assert_eq!(SameFile::Same, same_inode(&file, &file, false)?);
+        assert_eq!(SameFile::Different, same_inode(&file, &other, false)?);
+        assert_eq!(SameFile::DestMissing, same_inode(&file, &missing, false)?);
+        assert!(same_inode(&missing, &file, false).is_err());
+
+        let hard = dir.path().join("hard");
+        std::fs::hard_link(&file, &hard)?;
+        assert_eq!(SameFile::Same, same_inode(&file, &hard, false)?);
+
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(&file, &link)?;
+        assert_eq!(SameFile::Same, same_inode(&file, &link, true)?);
+        assert_eq!(SameFile::Different, same_inode(&file, &link, false)?);
+        assert_eq!(SameFile::Different, same_inode(&link, &file, false)?);
+
+        let dangling = dir.path().join("dangling");
+        std::os::unix::fs::symlink(&missing, &dangling)?;
+        assert_eq!(SameFile::DestMissing, same_inode(&file, &dangling, true)?);
+        assert_eq!(SameFile::Different, same_inode(&file, &dangling, false)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_dir() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("file");
+        std::fs::write(&file, "data")?;
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(dir.path(), &link)?;
+
+        assert!(open_dir(dir.path(), false)?.metadata()?.is_dir());
+        assert!(open_dir(&link, true)?.metadata()?.is_dir());
+        let errno = |r: Result<File>| match r {
+            Err(Error::IOError(e)) => e.raw_os_error(),
+            _ => None,
+        };
+        assert!(is_not_dir(&open_dir(&link, false).unwrap_err()));
+        assert!(is_not_dir(&open_dir(&file, true).unwrap_err()));
+        assert_eq!(Some(libc::ENOENT), errno(open_dir(&dir.path().join("missing"), true)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare_ids() {
+        assert_eq!(SameFile::Same, compare_ids((1, 100), (1, 100)));
+        assert_eq!(SameFile::Different, compare_ids((1, 100), (1, 101)));
+        // The same inode number on another device is another file.
+        assert_eq!(SameFile::Different, compare_ids((1, 100), (2, 100)));
+    }
+
+    #[test]
+    fn test_preferred_io_size() {
+        const K: u64 = 1024;
+        const M: u64 = 1024 * K;
+        // Typical local filesystems prefer 4KiB, below the floor.
+        assert_eq!(M, preferred_io_size(4 * K, 4 * K, M, MAX_IO_SIZE));
+        // Fuse with a large max_write.
+        assert_eq!(4 * M, preferred_io_size(4 * K, 4 * M, M, MAX_IO_SIZE));
+        // A 3-disk RAID5 with 256KiB chunks has 768KiB stripes; the
+        // floor is rounded up to a whole number of them.
+        assert_eq!(3 * 512 * K, preferred_io_size(4 * K, 768 * K, M, MAX_IO_SIZE));
+        // ...or down if that would exceed the limit.
+        assert_eq!(768 * K, preferred_io_size(768 * K, 4 * K, M, M));
+        // Unreasonably large preferred sizes are capped.
+        assert_eq!(MAX_IO_SIZE, preferred_io_size(4 * K, 1024 * M, M, MAX_IO_SIZE));
+        assert_eq!(3 * M, preferred_io_size(5 * M, 4 * K, M, 3 * M));
+        // Zero sizes, as some filesystems report, are ignored.
+        assert_eq!(M, preferred_io_size(0, 0, M, MAX_IO_SIZE));
+        assert_eq!(4 * K, preferred_io_size(0, 4 * K, 0, MAX_IO_SIZE));
+        assert_eq!(1, preferred_io_size(0, 0, 0, 0));
+    }
+
+    #[test]
+    fn test_same_device() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("file");
+        std::fs::write(&file, "data")?;
+        assert!(same_device(&file, dir.path())?);
+        assert!(same_device(dir.path().join("missing").as_path(), &file).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_lock_file() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("lock");
+        let fd = File::create(&path)?;
+        assert!(try_lock_file(&fd)?);
+        // Locks are per open file, so a second open conflicts.
+        let other = File::open(&path)?;
+        assert!(!try_lock_file(&other)?);
+        drop(fd);
+        assert!(try_lock_file(&other)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_exists() {
+        assert!(process_exists(std::process::id()));
+        // init, owned by root.
+        assert!(process_exists(1));
+        assert!(!process_exists(0));
+        assert!(!process_exists(i32::MAX as u32));
+    }
+
+    #[test]
+    fn test_lookup_user_group() -> Result<()> {
+        assert_eq!(Some(0), lookup_user("root")?);
+        assert_eq!(Some(0), lookup_group("root")?);
+        assert_eq!(None, lookup_user("no-such-user-xcp")?);
+        assert_eq!(None, lookup_group("no-such-group-xcp")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fs_space() -> Result<()> {
+        let dir = tempdir()?;
+        let space = fs_space(dir.path())?;
+        assert!(space.block_size.is_power_of_two());
+        assert_eq!(0, space.available % space.block_size);
+        assert!(fs_space(&dir.path().join("missing")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_timestamp_granularity() -> Result<()> {
+        let dir = tempdir()?;
+        let gran = timestamp_granularity(dir.path())?;
+        assert!(gran <= Duration::from_secs(2));
+        assert!(read_dir(dir.path())?.next().is_none());
+
+        let set = UNIX_EPOCH + Duration::new(1_000_000_001, 123_456_789);
+        let probe = |s, n| granularity_from_probe(set, UNIX_EPOCH + Duration::new(s, n));
+        assert_eq!(Duration::from_nanos(1), probe(1_000_000_001, 123_456_789));
+        assert_eq!(Duration::from_nanos(100), probe(1_000_000_001, 123_456_700));
+        assert_eq!(Duration::from_micros(1), probe(1_000_000_001, 123_456_000));
+        assert_eq!(Duration::from_secs(1), probe(1_000_000_001, 0));
+        assert_eq!(Duration::from_secs(2), probe(1_000_000_000, 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_bytes_observed() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let size = 128 * 1024;
+        let data = "X".repeat(size as usize);
+
+        {
+            let mut fd: File = File::create(&from)?;
+            write!(fd, "{}", data)?;
+        }
+
+        let mut seen = Vec::new();
+        {
+            let infd = File::open(&from)?;
+            let outfd = File::create(&to)?;
+            let written = copy_file_bytes_observed(&infd, &outfd, size, &mut |b| seen.extend_from_slice(b))?;
+            assert_eq!(written, size);
+        }
+
+        assert_eq!(data.as_bytes(), seen.as_slice());
+        assert_eq!(read(&from)?, read(&to)?);
+
+        Ok(())
+    }
+
     #[test]
     fn test_extent_merge() -> Result<()> {
         assert_eq!(merge_extents(vec!())?, vec!());
@@ -308,13 +1002,19 @@ mod tests {
                 (10..20).into()));
         assert_eq!(merge_extents(
             vec!((0..10).into(),
-                (11..20).into()))?,
+                (10..20).into()))?,
             vec!((0..20).into()));
+        // Not contiguous; 10 is missing.
+        assert_eq!(merge_extents(
+            vec!((0..10).into(),
+                (11..20).into()))?,
+            vec!((0..10).into(),
+                (11..20).into()));
         assert_eq!(
             merge_extents(
                 vec!((0..5).into(),
                     (11..20).into(),
-                    (21..30).into(),
+                    (20..30).into(),
                     (40..50).into()))?,
             vec!((0..5).into(),
                 (11..30).into(),
@@ -323,25 +1023,93 @@ mod tests {
         assert_eq!(
             merge_extents(vec!((0..5).into(),
                 (11..20).into(),
-                (21..30).into(),
+                (20..30).into(),
                 (40..50).into(),
-                (51..60).into()))?,
+                (50..60).into()))?,
             vec!((0..5).into(),
                 (11..30).into(),
                 (40..60).into())
         );
+        // Unsorted and overlapping.
         assert_eq!(
             merge_extents(
-                vec!((0..10).into(),
-                    (11..20).into(),
-                    (21..30).into(),
-                    (31..50).into(),
-                    (51..60).into()))?,
+                vec!((30..50).into(),
+                    (0..10).into(),
+                    (20..40).into(),
+                    (10..20).into(),
+                    (50..60).into()))?,
             vec!((0..60).into())
         );
         Ok(())
     }
 
+    proptest! {
+        #[test]
+        fn prop_extent_merge(ranges in vec((0u64..1000, 1u64..100), 0..50)) {
+            let covered = ranges.iter()
+                .flat_map(|&(start, len)| start..start + len)
+                .collect::<BTreeSet<u64>>();
+            let extents = ranges.iter()
+                .map(|&(start, len)| (start..start + len).into())
+                .collect::<Vec<Extent>>();
+
+            let merged = merge_extents(extents).unwrap();
+
+            // Sorted, non-empty, and neither overlapping nor adjacent.
+            prop_assert!(merged.iter().all(|e| e.start < e.end));
+            prop_assert!(merged.windows(2).all(|w| w[0].end < w[1].start));
+            let merged = merged.iter()
+                .flat_map(|e| e.start..e.end)
+                .collect::<BTreeSet<u64>>();
+            prop_assert_eq!(covered, merged);
+        }
+    }
+
+
+    #[test]
+    fn test_copy_link_xattrs() -> Result<()> {
+        let dir = tempdir()?;
+        let target = dir.path().join("target");
+        File::create(&target)?;
+        let (from, to) = (dir.path().join("from"), dir.path().join("to"));
+        std::os::unix::fs::symlink(&target, &from)?;
+        std::os::unix::fs::symlink(&target, &to)?;
+
+        // Only privileged namespaces are allowed on symlinks.
+        if xattr::set(&from, "trusted.keep", b"keep").is_err() {
+            println!("Symlink xattrs not supported; skipping");
+            return Ok(());
+        }
+        xattr::set(&from, "trusted.skip", b"skip")?;
+
+        copy_link_xattrs(&from, &to, None, &|name| name != "trusted.skip")?;
+        assert_eq!(Some(b"keep".to_vec()), xattr::get(&to, "trusted.keep")?);
+        assert_eq!(None, xattr::get(&to, "trusted.skip")?);
+        assert_eq!(None, xattr::get(&target, "trusted.keep")?);
+
+        // Too large values are skipped.
+        let to = dir.path().join("limited");
+        std::os::unix::fs::symlink(&target, &to)?;
+        copy_link_xattrs(&from, &to, Some(3), &|_| true)?;
+        assert_eq!(0, xattr::list(&to)?.count());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_xattr() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("file");
+        let fd = File::create(&path)?;
+        if !set_xattr(&fd, "user.xcp.test", b"value")? {
+            println!("Xattrs not supported; skipping");
+            return Ok(());
+        }
+        assert_eq!(Some(b"value".to_vec()), xattr::get(&path, "user.xcp.test")?);
+        assert_eq!(Some(b"value".to_vec()), get_xattr(&path, "user.xcp.test")?);
+        assert_eq!(None, get_xattr(&path, "user.xcp.unset")?);
+        Ok(())
+    }
 
     #[test]
     fn test_copy_file() -> Result<()> {
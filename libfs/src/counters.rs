@@ -0,0 +1,266 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Rough counts of the syscalls made by libfs, for comparing the work
+//! done by a copy with other tools. These are only collected with the
+//! `stats` feature; without it counting compiles to nothing and the
+//! counts are always zero.
+//!
+//! Each thread counts into its own slot, so copy workers don't
+//! contend on shared counters; [counters] sums them. The counts of a
+//! thread that has exited are folded into a single total.
+
+use std::fmt;
+use std::ops::Sub;
+#[cfg(feature = "stats")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "stats")]
+use std::sync::{Arc, Mutex};
+
+/// Counts of syscalls by kind; see the [module documentation](self).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counters {
+    /// `copy_file_range` calls, including those that turn out to be
+    /// unsupported.
+    pub copy_file_range: u64,
+    /// Reflink ioctls, of whole files or ranges.
+    pub reflink: u64,
+    /// Reads by userspace copies.
+    pub reads: u64,
+    /// Writes by userspace copies; a short write that is retried
+    /// counts again.
+    pub writes: u64,
+    /// Preallocations of destination files; see
+    /// [allocate_file](crate::allocate_file).
+    pub preallocate: u64,
+    /// Changes to the ownership, mode, timestamps and xattrs of
+    /// copied entries.
+    pub metadata: u64,
+}
+
+impl fmt::Display for Counters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} copy_file_range, {} reflink, {} read, {} write, {} preallocate, {} metadata",
+               self.copy_file_range, self.reflink, self.reads, self.writes, self.preallocate, self.metadata)
+    }
+}
+
+impl Sub for Counters {
+    type Output = Counters;
+
+    /// The counts since `earlier`.
+    fn sub(self, earlier: Counters) -> Counters {
+        Counters {
+            copy_file_range: self.copy_file_range.saturating_sub(earlier.copy_file_range),
+            reflink: self.reflink.saturating_sub(earlier.reflink),
+            reads: self.reads.saturating_sub(earlier.reads),
+            writes: self.writes.saturating_sub(earlier.writes),
+            preallocate: self.preallocate.saturating_sub(earlier.preallocate),
+            metadata: self.metadata.saturating_sub(earlier.metadata),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Syscall {
+    CopyFileRange,
+    Reflink,
+    Read,
+    Write,
+    Preallocate,
+    Metadata,
+}
+
+#[cfg(feature = "stats")]
+const SYSCALLS: usize = Syscall::Metadata as usize + 1;
+
+#[cfg(feature = "stats")]
+struct Slot([AtomicU64; SYSCALLS]);
+
+#[cfg(feature = "stats")]
+impl Slot {
+    const fn new() -> Slot {
+        Slot([const { AtomicU64::new(0) }; SYSCALLS])
+    }
+
+    fn add_to(&self, counts: &mut [u64; SYSCALLS]) {
+        for (total, count) in counts.iter_mut().zip(&self.0) {
+            *total += count.load(Ordering::Relaxed);
+        }
+    }
+
+    fn reset(&self) {
+        for count in &self.0 {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The slots of running threads.
+#[cfg(feature = "stats")]
+static SLOTS: Mutex<Vec<Arc<Slot>>> = Mutex::new(Vec::new());
+
+/// The counts of threads that have exited.
+#[cfg(feature = "stats")]
+static EXITED: Slot = Slot::new();
+
+// The slot of this thread, which is registered on first use and
+// folded into [EXITED] when the thread exits.
+#[cfg(feature = "stats")]
+struct Local(Arc<Slot>);
+
+#[cfg(feature = "stats")]
+impl Local {
+    fn new() -> Local {
+        let slot = Arc::new(Slot::new());
+        SLOTS.lock().unwrap().push(slot.clone());
+        Local(slot)
+    }
+}
+
+#[cfg(feature = "stats")]
+impl Drop for Local {
+    fn drop(&mut self) {
+        let mut slots = SLOTS.lock().unwrap();
+        slots.retain(|s| !Arc::ptr_eq(s, &self.0));
+        for (exited, count) in EXITED.0.iter().zip(&self.0.0) {
+            exited.fetch_add(count.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(feature = "stats")]
+thread_local! {
+    static LOCAL: Local = Local::new();
+}
+
+#[cfg(feature = "stats")]
+impl From<[u64; SYSCALLS]> for Counters {
+    fn from(counts: [u64; SYSCALLS]) -> Counters {
+        Counters {
+            copy_file_range: counts[Syscall::CopyFileRange as usize],
+            reflink: counts[Syscall::Reflink as usize],
+            reads: counts[Syscall::Read as usize],
+            writes: counts[Syscall::Write as usize],
+            preallocate: counts[Syscall::Preallocate as usize],
+            metadata: counts[Syscall::Metadata as usize],
+        }
+    }
+}
+
+/// Count a syscall made by this thread.
+#[inline(always)]
+pub(crate) fn count(call: Syscall) {
+    #[cfg(feature = "stats")]
+    LOCAL.with(|local| local.0.0[call as usize].fetch_add(1, Ordering::Relaxed));
+    #[cfg(not(feature = "stats"))]
+    let _ = call;
+}
+
+/// The syscalls made by all threads since the process started, or
+/// since [reset_counters].
+pub fn counters() -> Counters {
+    #[cfg(feature = "stats")]
+    {
+        let mut counts = [0; SYSCALLS];
+        let slots = SLOTS.lock().unwrap();
+        for slot in slots.iter() {
+            slot.add_to(&mut counts);
+        }
+        EXITED.add_to(&mut counts);
+        Counters::from(counts)
+    }
+    #[cfg(not(feature = "stats"))]
+    Counters::default()
+}
+
+/// The syscalls made by the calling thread, e.g. to measure work done
+/// on it alone while other threads are copying.
+pub fn thread_counters() -> Counters {
+    #[cfg(feature = "stats")]
+    {
+        let mut counts = [0; SYSCALLS];
+        LOCAL.with(|local| local.0.add_to(&mut counts));
+        Counters::from(counts)
+    }
+    #[cfg(not(feature = "stats"))]
+    Counters::default()
+}
+
+/// Reset the counts of all threads to zero.
+pub fn reset_counters() {
+    #[cfg(feature = "stats")]
+    {
+        let slots = SLOTS.lock().unwrap();
+        for slot in slots.iter() {
+            slot.reset();
+        }
+        EXITED.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::thread;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{allocate_file, copy_file_bytes_observed, copy_mode, copy_timestamps};
+    use crate::errors::Result;
+
+    // A file copied once in the kernel, preallocated, and once via
+    // userspace, with its mode and timestamps.
+    fn workload(dir: &std::path::Path) -> Result<()> {
+        let (from, to) = (dir.join("from"), dir.join("to"));
+        fs::write(&from, vec![1; 10_000])?;
+        let infd = File::open(&from)?;
+        let outfd = File::create(&to)?;
+        allocate_file(&outfd, 10_000)?;
+        assert_eq!(10_000, crate::copy_file_at(&infd, 0, &outfd, 0, 10_000)?);
+        assert_eq!(10_000, copy_file_bytes_observed(&infd, &outfd, 10_000, &mut |_| {})?);
+        copy_mode(&infd, &outfd, false)?;
+        copy_timestamps(&infd, &outfd)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_counters() -> Result<()> {
+        let dir = TempDir::new()?;
+        let before = thread_counters();
+        workload(dir.path())?;
+        let counted = thread_counters() - before;
+
+        let expected = if cfg!(feature = "stats") {
+            Counters { copy_file_range: 1, reflink: 0, reads: 1, writes: 1, preallocate: 1, metadata: 2 }
+        } else {
+            Counters::default()
+        };
+        assert_eq!(expected, counted);
+
+        // The counts of other threads are included in the totals,
+        // including once they have exited.
+        let before = counters();
+        let path = dir.path().join("thread");
+        fs::create_dir(&path)?;
+        thread::spawn(move || workload(&path)).join().unwrap()?;
+        let counted = counters() - before;
+        assert!(counted.metadata >= expected.metadata);
+        assert!(counted.preallocate >= expected.preallocate);
+        Ok(())
+    }
+}
@@ -0,0 +1,198 @@
+/*
+ * Copyright © 2018, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Suppression of log messages repeated for many files, such as a
+//! warning for each file copied to a filesystem without xattrs, which
+//! would otherwise flood the log and hide other problems.
+//!
+//! Messages logged with [log_repeated!] or [warn_repeated!] are keyed
+//! on their level, format string and a device, rather than the
+//! formatted message, so that the paths in them don't make each one
+//! unique. Each is logged the first [REPEAT_LIMIT] times and then
+//! counted; [take_repeats] returns the counts for a summary.
+//!
+//! [log_repeated!]: crate::log_repeated
+//! [warn_repeated!]: crate::warn_repeated
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use log::Level;
+use rustix::fs::{major, minor};
+
+/// The number of times a repeated message is logged before the rest
+/// are suppressed.
+pub const REPEAT_LIMIT: u64 = 5;
+
+type Key = (Level, &'static str, u64);
+
+static REPEATS: Mutex<BTreeMap<Key, u64>> = Mutex::new(BTreeMap::new());
+
+/// Log a message unless it has already been logged [REPEAT_LIMIT]
+/// times for the device; see the [module](self) docs. The format
+/// string must be a literal, as it identifies the message.
+#[macro_export]
+macro_rules! log_repeated {
+    ($level:expr, $dev:expr, $fmt:literal $($arg:tt)*) => {
+        if $crate::repeat_allowed($level, $fmt, $dev) {
+            $crate::__log::log!($level, $fmt $($arg)*);
+        }
+    };
+}
+
+/// As [log_repeated!] for warnings.
+#[macro_export]
+macro_rules! warn_repeated {
+    ($dev:expr, $fmt:literal $($arg:tt)*) => {
+        $crate::log_repeated!($crate::__log::Level::Warn, $dev, $fmt $($arg)*)
+    };
+}
+
+/// Count a message logged with [log_repeated!], returning whether it
+/// should be logged.
+pub fn repeat_allowed(level: Level, template: &'static str, dev: u64) -> bool {
+    if level > log::max_level() {
+        return false;
+    }
+    let mut repeats = REPEATS.lock().unwrap();
+    let count = repeats.entry((level, template, dev)).or_default();
+    *count += 1;
+    *count <= REPEAT_LIMIT
+}
+
+/// A message logged with [log_repeated!] more than [REPEAT_LIMIT]
+/// times.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Repeated {
+    pub level: Level,
+    /// The format string of the message.
+    pub template: &'static str,
+    /// The device the message was logged for, or 0 if none.
+    pub dev: u64,
+    /// The number of times the message was logged, including those
+    /// suppressed.
+    pub count: u64,
+}
+
+/// Take the counts of the messages that have been suppressed, most
+/// repeated first, and reset them all.
+pub fn take_repeats() -> Vec<Repeated> {
+    let mut repeats = std::mem::take(&mut *REPEATS.lock().unwrap()).into_iter()
+        .filter(|(_, count)| *count > REPEAT_LIMIT)
+        .map(|((level, template, dev), count)| Repeated { level, template, dev, count })
+        .collect::<Vec<_>>();
+    repeats.sort_by_key(|r| Reverse(r.count));
+    repeats
+}
+
+impl fmt::Display for Repeated {
+    /// The message with its arguments elided, and how often it was
+    /// repeated.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\": message repeated {} times", elide_args(self.template), group_digits(self.count))?;
+        if self.dev != 0 {
+            write!(f, " on device {}:{}", major(self.dev), minor(self.dev))?;
+        }
+        write!(f, "; only the first {} were logged", REPEAT_LIMIT)
+    }
+}
+
+// Replace the arguments of a format string with '…'.
+fn elide_args(template: &str) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                for c in chars.by_ref() {
+                    if c == '}' {
+                        break;
+                    }
+                }
+                out.push('…');
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// Format a number with thousands separators, e.g. 382,113.
+fn group_digits(n: u64) -> String {
+    let digits = n.to_string();
+    let mut out = String::with_capacity(digits.len() * 4 / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_elide_args() {
+        assert_eq!("Failed to copy xattrs from …: …", elide_args("Failed to copy xattrs from {:?}: {}"));
+        assert_eq!("{literal} … of …", elide_args("{{literal}} {0} of {path:?}"));
+    }
+
+    #[test]
+    fn test_group_digits() {
+        assert_eq!("0", group_digits(0));
+        assert_eq!("999", group_digits(999));
+        assert_eq!("1,000", group_digits(1000));
+        assert_eq!("382,113", group_digits(382_113));
+        assert_eq!("12,345,678", group_digits(12_345_678));
+    }
+
+    #[test]
+    fn test_repeat_limit() {
+        log::set_max_level(log::LevelFilter::Warn);
+        let template = "Test repeat {}";
+        let allowed = (0..10).filter(|_| repeat_allowed(Level::Warn, template, 42)).count();
+        assert_eq!(REPEAT_LIMIT as usize, allowed);
+        // Counted separately for each device.
+        assert!(repeat_allowed(Level::Warn, template, 43));
+        // Only logged messages are counted.
+        assert!(!repeat_allowed(Level::Debug, template, 42));
+
+        let repeats = take_repeats().into_iter()
+            .filter(|r| r.template == template)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![Repeated { level: Level::Warn, template, dev: 42, count: 10 }], repeats);
+        assert!(repeat_allowed(Level::Warn, template, 42));
+    }
+
+    #[test]
+    fn test_repeat_display() {
+        let repeated = Repeated { level: Level::Warn, template: "Failed on {:?}", dev: 0, count: 382_113 };
+        assert_eq!("\"Failed on …\": message repeated 382,113 times; only the first 5 were logged", repeated.to_string());
+    }
+}
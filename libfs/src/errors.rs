@@ -17,13 +17,24 @@
 use std::path::PathBuf;
 
 #[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
 pub enum Error {
-    #[error("Invalid source: {0}")]
-    InvalidSource(&'static str),
+    #[error("Source file ended prematurely after {copied} bytes")]
+    SourceEnded {
+        copied: u64,
+    },
+
+    #[error("Failed write to file at offset {offset}")]
+    WriteFailed {
+        offset: u64,
+    },
 
     #[error("Invalid path: {0}")]
     InvalidPath(PathBuf),
 
+    #[error("Invalid name: {0}")]
+    InvalidName(String),
+
     #[error(transparent)]
     IOError(#[from] std::io::Error),
 
@@ -35,3 +46,123 @@ pub enum Error {
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Returns true if the error, or any error in its source chain, is
+/// caused by the filesystem being full (`ENOSPC`).
+pub fn is_no_space(err: &(dyn std::error::Error + 'static)) -> bool {
+    has_errno(err, &[libc::ENOSPC])
+}
+
+/// Returns true if the error, or any error in its source chain,
+/// indicates the operation is not supported by the filesystem
+/// (`EOPNOTSUPP`/`ENOTSUP`, `EINVAL` or `ENOSYS`).
+pub fn is_unsupported(err: &(dyn std::error::Error + 'static)) -> bool {
+    has_errno(err, &[libc::EOPNOTSUPP, libc::ENOTSUP, libc::EINVAL, libc::ENOSYS])
+}
+
+/// Returns true if the error, or any error in its source chain, is
+/// caused by the destination already existing (`EEXIST`).
+pub fn is_exists(err: &(dyn std::error::Error + 'static)) -> bool {
+    has_errno(err, &[libc::EEXIST])
+}
+
+/// Returns true if the error, or any error in its source chain, is
+/// caused by a path not being a directory where one was required
+/// (`ENOTDIR`), including a symlink that wasn't followed, which some
+/// systems report as `ELOOP`; see [open_dir](crate::open_dir).
+pub fn is_not_dir(err: &(dyn std::error::Error + 'static)) -> bool {
+    has_errno(err, &[libc::ENOTDIR, libc::ELOOP])
+}
+
+/// Returns true if the error, or any error in its source chain, is
+/// caused by a path being a directory where a file was required
+/// (`EISDIR`).
+pub fn is_dir_error(err: &(dyn std::error::Error + 'static)) -> bool {
+    has_errno(err, &[libc::EISDIR])
+}
+
+/// Returns true if the error, or any error in its source chain, is a
+/// low-level I/O error (`EIO`), e.g. from an unreadable sector.
+pub fn is_io_error(err: &(dyn std::error::Error + 'static)) -> bool {
+    has_errno(err, &[libc::EIO])
+}
+
+/// Returns true if the error, or any error in its source chain, is
+/// an interrupted system call (`EINTR`) that can be retried.
+pub fn is_interrupted(err: &(dyn std::error::Error + 'static)) -> bool {
+    has_errno(err, &[libc::EINTR])
+}
+
+fn has_errno(err: &(dyn std::error::Error + 'static), errnos: &[i32]) -> bool {
+    let mut cause = Some(err);
+    while let Some(e) = cause {
+        // Transparent errors don't report their inner error as the
+        // source, so check these explicitly.
+        match e.downcast_ref::<Error>() {
+            Some(Error::IOError(ioe)) => return has_errno(ioe, errnos),
+            Some(Error::OSError(errno)) => return has_errno(errno, errnos),
+            _ => {}
+        }
+        if let Some(ioe) = e.downcast_ref::<std::io::Error>() {
+            if ioe.raw_os_error().is_some_and(|n| errnos.contains(&n)) {
+                return true;
+            }
+        }
+        if let Some(errno) = e.downcast_ref::<rustix::io::Errno>() {
+            if errnos.contains(&errno.raw_os_error()) {
+                return true;
+            }
+        }
+        cause = e.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_no_space() {
+        let nospc = std::io::Error::from_raw_os_error(libc::ENOSPC);
+        assert!(is_no_space(&nospc));
+        assert!(is_no_space(&Error::from(nospc)));
+        assert!(is_no_space(&Error::from(rustix::io::Errno::NOSPC)));
+        assert!(!is_no_space(&Error::from(rustix::io::Errno::IO)));
+        assert!(!is_no_space(&Error::SourceEnded { copied: 0 }));
+    }
+
+    #[test]
+    fn test_is_unsupported() {
+        assert!(is_unsupported(&Error::from(rustix::io::Errno::OPNOTSUPP)));
+        assert!(is_unsupported(&Error::from(std::io::Error::from_raw_os_error(libc::EINVAL))));
+        assert!(!is_unsupported(&Error::from(rustix::io::Errno::NOSPC)));
+    }
+
+    #[test]
+    fn test_is_interrupted() {
+        assert!(is_interrupted(&Error::from(rustix::io::Errno::INTR)));
+        assert!(is_interrupted(&std::io::Error::from_raw_os_error(libc::EINTR)));
+        assert!(!is_interrupted(&Error::from(rustix::io::Errno::IO)));
+    }
+
+    #[test]
+    fn test_is_io_error() {
+        assert!(is_io_error(&Error::from(rustix::io::Errno::IO)));
+        assert!(is_io_error(&std::io::Error::from_raw_os_error(libc::EIO)));
+        assert!(!is_io_error(&Error::from(rustix::io::Errno::NOSPC)));
+    }
+
+    #[test]
+    fn test_is_exists() {
+        assert!(is_exists(&Error::from(rustix::io::Errno::EXIST)));
+        assert!(!is_exists(&Error::from(rustix::io::Errno::NOENT)));
+    }
+
+    #[test]
+    fn test_is_dir_error() {
+        assert!(is_dir_error(&Error::from(rustix::io::Errno::ISDIR)));
+        assert!(is_dir_error(&std::io::Error::from_raw_os_error(libc::EISDIR)));
+        assert!(!is_dir_error(&Error::from(rustix::io::Errno::NOTDIR)));
+    }
+}
@@ -14,21 +14,88 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use std::ffi::OsStr;
 use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::ops::Range;
 use std::path::Path;
 
 use log::warn;
 
-use crate::Extent;
-use crate::common::{copy_bytes_uspace, copy_range_uspace};
+use crate::{Extent, FsType};
+use crate::common::{copy_between_uspace, copy_bytes_uspace, copy_range_uspace};
 use crate::errors::{Result, Error};
 
-pub fn copy_file_bytes(infd: &File, outfd: &File, bytes: u64) -> Result<usize> {
-    copy_bytes_uspace(infd, outfd, bytes as usize)
+pub fn copy_file_bytes(infd: &File, outfd: &File, bytes: u64) -> Result<u64> {
+    copy_bytes_uspace(infd, outfd, bytes)
 }
 
-pub fn copy_file_offset(infd: &File, outfd: &File, bytes: u64, off: i64) -> Result<usize> {
-    copy_range_uspace(infd, outfd, bytes as usize, off as usize)
+pub fn copy_file_offset(infd: &File, outfd: &File, bytes: u64, off: u64) -> Result<u64> {
+    copy_range_uspace(infd, outfd, bytes, off)
+}
+
+pub fn copy_file_at(infd: &File, in_off: u64, outfd: &File, out_off: u64, bytes: u64) -> Result<u64> {
+    copy_between_uspace(infd, in_off, outfd, out_off, bytes)
+}
+
+pub fn try_copy_file_bytes(_infd: &File, _outfd: &File, _bytes: u64) -> Result<Option<u64>> {
+    Ok(None)
+}
+
+pub fn fs_type(_fd: &File) -> Result<FsType> {
+    Ok(FsType::Other)
+}
+
+pub fn mount_option(_path: &Path, _name: &str) -> Result<Option<String>> {
+    Ok(None)
+}
+
+pub fn device_size(mut fd: &File) -> Result<u64> {
+    let pos = fd.stream_position()?;
+    let size = fd.seek(SeekFrom::End(0))?;
+    fd.seek(SeekFrom::Start(pos))?;
+    Ok(size)
+}
+
+pub fn device_model(_fd: &File) -> Option<String> {
+    None
+}
+
+pub fn is_rotational(_dev: u64) -> Option<bool> {
+    None
+}
+
+pub fn is_casefolded(_fd: &File) -> bool {
+    false
+}
+
+pub fn is_compressed(_fd: &File) -> bool {
+    false
+}
+
+pub fn copy_flags(_infd: &File, _outfd: &File) -> Result<()> {
+    Ok(())
+}
+
+pub fn is_immutable(_fd: &File) -> bool {
+    false
+}
+
+pub fn clear_immutable(_fd: &File) -> Result<bool> {
+    Ok(false)
+}
+
+pub fn open_direct(path: &Path) -> Result<File> {
+    Ok(File::open(path)?)
+}
+
+// Not known without reading the value.
+pub(crate) fn xattr_size(_fd: &File, _name: &OsStr) -> Result<Option<u64>> {
+    Ok(None)
+}
+
+pub(crate) fn link_xattr_size(_path: &Path, _name: &OsStr) -> Result<Option<u64>> {
+    Ok(None)
 }
 
 // No sparse file handling by default, needs to be implemented
@@ -42,6 +109,11 @@ pub fn map_extents(_fd: &File) -> Result<Option<Vec<Extent>>> {
     Ok(None)
 }
 
+pub fn extents(fd: &File) -> Result<Vec<Range<u64>>> {
+    let len = fd.metadata()?.len();
+    Ok((len > 0).then_some(0..len).into_iter().collect())
+}
+
 pub fn next_sparse_segments(_infd: &File, _outfd: &File, _pos: u64) -> Result<(u64, u64)> {
     // FIXME: Implement for *BSD with lseek?
     Err(Error::UnsupportedOperation {})
@@ -49,8 +121,7 @@ pub fn next_sparse_segments(_infd: &File, _outfd: &File, _pos: u64) -> Result<(u
 
 pub fn copy_sparse(infd: &File, outfd: &File) -> Result<u64> {
     let len = infd.metadata()?.len();
-    copy_file_bytes(&infd, &outfd, len)
-        .map(|i| i as u64)
+    copy_file_bytes(infd, outfd, len)
 }
 
 pub fn copy_node(src: &Path, _dest: &Path) -> Result<()> {
@@ -62,3 +133,11 @@ pub fn copy_node(src: &Path, _dest: &Path) -> Result<()> {
 pub fn reflink(_infd: &File, _outfd: &File) -> Result<bool> {
     Ok(false)
 }
+
+pub fn clone_range(_infd: &File, _outfd: &File, _src_off: u64, _dst_off: u64, _len: u64) -> Result<bool> {
+    Ok(false)
+}
+
+pub fn clone_file(_infd: &File, _outfd: &File) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "reflinks are not supported by this OS"))
+}
@@ -15,7 +15,10 @@
  */
 
 mod common;
+mod counters;
+mod dedup;
 mod errors;
+mod ops;
 
 use std::{fs, ops::Range};
 
@@ -32,26 +35,69 @@ cfg_if! {
     }
 }
 pub use backend::{
+    clear_immutable,
+    clone_file,
+    clone_range,
+    copy_file_at,
     copy_file_bytes,
     copy_file_offset,
+    copy_flags,
     copy_node,
     copy_sparse,
+    device_model,
+    device_size,
+    extents,
+    fs_type,
+    is_casefolded,
+    is_compressed,
+    is_immutable,
+    is_rotational,
     probably_sparse,
     next_sparse_segments,
     map_extents,
+    mount_option,
+    open_direct,
     reflink,
+    try_copy_file_bytes,
 };
+#[cfg(all(target_os = "linux", feature = "use_linux", feature = "btrfs"))]
+pub use linux::{create_subvolume, is_subvolume};
 pub use common::{
     allocate_file,
     copy_file,
+    copy_file_bytes_observed,
+    copy_link_xattrs,
+    copy_mode,
     copy_owner,
     copy_permissions,
     copy_timestamps,
-    is_same_file,
+    copy_xattrs,
+    fs_space,
+    FsSpace,
+    get_xattr,
+    is_same_dir_tree_entry,
+    lookup_group,
+    lookup_user,
+    MAX_IO_SIZE,
     merge_extents,
+    open_dir,
+    preferred_io_size,
+    process_exists,
+    same_device,
+    same_inode,
+    SELINUX_XATTR,
+    SameFile,
+    set_xattr,
     sync,
+    timestamp_granularity,
+    try_lock_file,
 };
-pub use errors::Error;
+pub use counters::{counters, reset_counters, thread_counters, Counters};
+pub use dedup::{repeat_allowed, take_repeats, Repeated, REPEAT_LIMIT};
+#[doc(hidden)]
+pub use log as __log;
+pub use ops::{classify_entry, Attribute, FaultInjectingFs, FsOp, FsOps, Observer, RealFs};
+pub use errors::{is_dir_error, is_exists, is_interrupted, is_io_error, is_no_space, is_not_dir, is_unsupported, Error};
 
 /// Flag whether the current OS support
 /// [xattrs](https://man7.org/linux/man-pages/man7/xattr.7.html).
@@ -70,7 +116,7 @@ pub const XATTR_SUPPORTED: bool = {
 
 /// Enum mapping for various *nix file types. Mapped from
 /// [std::fs::FileType] and [rustix::fs::FileTypeExt].
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum FileType {
     File,
     Dir,
@@ -104,6 +150,53 @@ impl From<fs::FileType> for FileType {
     }
 }
 
+/// Filesystem types that need special handling. Network filesystems
+/// may support server-side copies with `copy_file_range`, avoiding
+/// transferring the data to the client and back. FAT, exFAT and NTFS
+/// cannot represent all Unix filenames, and ext4 and F2FS directories
+/// may be case-insensitive. Compressed files on btrfs, and FUSE
+/// filesystems, handle out-of-order writes poorly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsType {
+    Nfs,
+    Cifs,
+    Fat,
+    Exfat,
+    Ntfs,
+    /// ext2, ext3 and ext4, which share a magic number.
+    Ext4,
+    F2fs,
+    Btrfs,
+    /// A FUSE filesystem; the underlying type is not known.
+    Fuse,
+    Other,
+}
+
+impl FsType {
+    /// Map a `statfs` filesystem magic number.
+    pub fn from_magic(magic: u32) -> FsType {
+        match magic {
+            0x6969 => FsType::Nfs,
+            // CIFS, SMB2 and the legacy SMB client.
+            0xff53_4d42 | 0xfe53_4d42 | 0x517b => FsType::Cifs,
+            0x4d44 => FsType::Fat,
+            0x2011_bab0 => FsType::Exfat,
+            // The legacy ntfs and newer ntfs3 drivers.
+            0x5346_544e | 0x7366_746e => FsType::Ntfs,
+            0xef53 => FsType::Ext4,
+            0xf2f5_2010 => FsType::F2fs,
+            0x9123_683e => FsType::Btrfs,
+            0x6573_5546 => FsType::Fuse,
+            _ => FsType::Other,
+        }
+    }
+
+    /// Whether this is a network filesystem.
+    pub fn is_network(self) -> bool {
+        matches!(self, FsType::Nfs | FsType::Cifs)
+    }
+}
+
 /// Struct representing a file extent metadata.
 #[derive(Debug, PartialEq)]
 pub struct Extent {
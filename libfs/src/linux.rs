@@ -14,18 +14,38 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::{fs::File, path::Path};
+use std::{cmp, ffi::OsStr, fs::{self, File}, iter, ops::Range, path::{Path, PathBuf}};
 use std::io;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
 use std::os::unix::io::AsRawFd;
 use std::os::unix::prelude::PermissionsExt;
-
-use linux_raw_sys::ioctl::{FS_IOC_FIEMAP, FIEMAP_EXTENT_LAST, FICLONE, FIEMAP_EXTENT_SHARED};
-use rustix::fs::CWD;
-use rustix::{fs::{copy_file_range, seek, mknodat, FileType, Mode, RawMode, SeekFrom}, io::Errno};
-
-use crate::Extent;
+#[cfg(feature = "btrfs")]
+use std::os::unix::ffi::OsStrExt;
+
+#[cfg(feature = "btrfs")]
+use linux_raw_sys::btrfs::{btrfs_ioctl_vol_args, BTRFS_FIRST_FREE_OBJECTID, BTRFS_PATH_NAME_MAX};
+use linux_raw_sys::general::file_clone_range;
+#[cfg(feature = "btrfs")]
+use linux_raw_sys::ioctl::BTRFS_IOC_SUBVOL_CREATE;
+use linux_raw_sys::general::{
+    FS_APPEND_FL, FS_CASEFOLD_FL, FS_COMPR_FL, FS_DIRSYNC_FL, FS_IMMUTABLE_FL, FS_NOATIME_FL, FS_NODUMP_FL, FS_SYNC_FL,
+};
+use linux_raw_sys::ioctl::{BLKGETSIZE64, FS_IOC_FIEMAP, FS_IOC_GETFLAGS, FS_IOC_SETFLAGS, FIEMAP_EXTENT_LAST, FICLONE, FICLONERANGE, FIEMAP_EXTENT_SHARED};
+use rustix::fs::{major, minor, CWD};
+use rustix::{fs::{copy_file_range, fgetxattr, fstatfs, lgetxattr, seek, mknodat, FileType, Mode, RawMode, SeekFrom}, io::Errno};
+
+use crate::{Extent, FsType};
+use crate::counters::{count, Syscall};
 use crate::errors::Result;
-use crate::common::{copy_bytes_uspace, copy_range_uspace};
+#[cfg(feature = "btrfs")]
+use crate::errors::Error;
+use crate::common::{copy_between_uspace, copy_bytes_uspace, copy_file_bytes_all, copy_range_uspace};
+
+// The kernel limits a single read/write to a little under 2GB anyway,
+// so clamp rather than truncate lengths on 32-bit platforms.
+fn syscall_len(bytes: u64) -> usize {
+    usize::try_from(bytes).unwrap_or(usize::MAX)
+}
 
 // Wrapper for copy_file_range(2) that checks for non-fatal errors due
 // to limitations of the syscall.
@@ -35,12 +55,13 @@ fn try_copy_file_range(
     outfd: &File,
     out_off: Option<&mut u64>,
     bytes: u64,
-) -> Option<Result<usize>> {
-    let cfr_ret = copy_file_range(infd, in_off, outfd, out_off, bytes as usize);
+) -> Option<Result<u64>> {
+    count(Syscall::CopyFileRange);
+    let cfr_ret = copy_file_range(infd, in_off, outfd, out_off, syscall_len(bytes));
 
     match cfr_ret {
         Ok(retval) => {
-            Some(Ok(retval))
+            Some(Ok(retval as u64))
         },
         Err(Errno::NOSYS) | Err(Errno::PERM) | Err(Errno::XDEV) => {
             None
@@ -55,20 +76,238 @@ fn try_copy_file_range(
 /// underlying call.  On Linux this attempts to use
 /// [copy_file_range](https://man7.org/linux/man-pages/man2/copy_file_range.2.html)
 /// and falls back to user-space if that is not available.
-pub fn copy_file_bytes(infd: &File, outfd: &File, bytes: u64) -> Result<usize> {
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all, fields(bytes)))]
+pub fn copy_file_bytes(infd: &File, outfd: &File, bytes: u64) -> Result<u64> {
     try_copy_file_range(infd, None, outfd, None, bytes)
-        .unwrap_or_else(|| copy_bytes_uspace(infd, outfd, bytes as usize))
+        .unwrap_or_else(|| copy_bytes_uspace(infd, outfd, bytes))
 }
 
 /// File copy operation that that copies a block at offset`off`.  On
 /// Linux this attempts to use
 /// [copy_file_range](https://man7.org/linux/man-pages/man2/copy_file_range.2.html)
 /// and falls back to user-space if that is not available.
-pub fn copy_file_offset(infd: &File, outfd: &File, bytes: u64, off: i64) -> Result<usize> {
-    let mut off_in = off as u64;
-    let mut off_out = off as u64;
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all, fields(bytes, off)))]
+pub fn copy_file_offset(infd: &File, outfd: &File, bytes: u64, off: u64) -> Result<u64> {
+    let mut off_in = off;
+    let mut off_out = off;
     try_copy_file_range(infd, Some(&mut off_in), outfd, Some(&mut off_out), bytes)
-        .unwrap_or_else(|| copy_range_uspace(infd, outfd, bytes as usize, off as usize))
+        .unwrap_or_else(|| copy_range_uspace(infd, outfd, bytes, off))
+}
+
+/// File copy operation that copies `bytes` from offset `in_off` in
+/// the source to offset `out_off` in the destination. The file
+/// cursors are not used or updated. On Linux this attempts to use
+/// [copy_file_range](https://man7.org/linux/man-pages/man2/copy_file_range.2.html)
+/// and falls back to user-space if that is not available.
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all, fields(bytes, in_off, out_off)))]
+pub fn copy_file_at(infd: &File, in_off: u64, outfd: &File, out_off: u64, bytes: u64) -> Result<u64> {
+    let mut off_in = in_off;
+    let mut off_out = out_off;
+    try_copy_file_range(infd, Some(&mut off_in), outfd, Some(&mut off_out), bytes)
+        .unwrap_or_else(|| copy_between_uspace(infd, in_off, outfd, out_off, bytes))
+}
+
+/// Attempt a single in-kernel copy of up to `bytes` from the current
+/// file positions, without falling back to userspace. On NFSv4.2 and
+/// CIFS this may be performed server-side. Returns `None` if the
+/// kernel can't copy between the files.
+pub fn try_copy_file_bytes(infd: &File, outfd: &File, bytes: u64) -> Result<Option<u64>> {
+    count(Syscall::CopyFileRange);
+    match copy_file_range(infd, None, outfd, None, syscall_len(bytes)) {
+        Ok(n) => Ok(Some(n as u64)),
+        Err(Errno::NOSYS | Errno::PERM | Errno::XDEV | Errno::OPNOTSUPP | Errno::INVAL) => Ok(None),
+        Err(errno) => Err(errno.into()),
+    }
+}
+
+// As xattr_size, for a path without following symlinks.
+pub(crate) fn link_xattr_size(path: &Path, name: &OsStr) -> Result<Option<u64>> {
+    match lgetxattr(path, name, &mut []) {
+        Ok(size) => Ok(Some(size as u64)),
+        Err(Errno::NODATA) => Ok(None),
+        Err(errno) => Err(errno.into()),
+    }
+}
+
+/// Identify the type of filesystem containing the file.
+pub fn fs_type(fd: &File) -> Result<FsType> {
+    let stat = fstatfs(fd)?;
+    Ok(FsType::from_magic(stat.f_type as u32))
+}
+
+/// The value of the mount option `name`, e.g. the `max_write` of a
+/// FUSE filesystem, for the filesystem containing `path`. Both the
+/// per-mount and superblock options in `/proc/self/mountinfo` are
+/// searched.
+pub fn mount_option(path: &Path, name: &str) -> Result<Option<String>> {
+    let dev = path.metadata()?.dev();
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    Ok(mountinfo_option(&mountinfo, dev, name))
+}
+
+// Find a mount option in the mountinfo line of device `dev`. A device
+// mounted more than once has the same superblock options, and the
+// last mount of it is used for the per-mount options.
+fn mountinfo_option(mountinfo: &str, dev: u64, name: &str) -> Option<String> {
+    let devno = format!("{}:{}", major(dev), minor(dev));
+    let line = mountinfo.lines()
+        .rev()
+        .find(|line| line.split(' ').nth(2) == Some(devno.as_str()))?;
+    let (mount, sb) = line.split_once(" - ")?;
+    let mount_opts = mount.split(' ').nth(5).unwrap_or("");
+    let sb_opts = sb.split(' ').nth(2).unwrap_or("");
+    mount_opts.split(',')
+        .chain(sb_opts.split(','))
+        .find_map(|opt| opt.strip_prefix(name)?.strip_prefix('='))
+        .map(str::to_string)
+}
+
+// The sysfs directory of a block device.
+fn sys_block_dir(fd: &File) -> Result<PathBuf> {
+    Ok(sys_dev_dir(fd.metadata()?.rdev()))
+}
+
+// The sysfs directory of a device number.
+fn sys_dev_dir(dev: u64) -> PathBuf {
+    PathBuf::from(format!("/sys/dev/block/{}:{}", major(dev), minor(dev)))
+}
+
+/// Whether the block device `dev`, e.g. the `st_dev` of a file, is
+/// rotational. For partitions this is the parent device. `None` if
+/// it isn't known, e.g. for network and virtual filesystems.
+pub fn is_rotational(dev: u64) -> Option<bool> {
+    let dir = sys_dev_dir(dev);
+    ["queue/rotational", "../queue/rotational"].iter()
+        .find_map(|p| fs::read_to_string(dir.join(p)).ok())
+        .map(|r| r.trim() == "1")
+}
+
+/// Whether the directory `fd` is case-insensitive, i.e. has the
+/// casefold attribute set (see `chattr(1)`). Filesystems without
+/// inode flags are treated as case-sensitive.
+pub fn is_casefolded(fd: &File) -> bool {
+    let mut flags: libc::c_int = 0;
+    let ret = unsafe { libc::ioctl(fd.as_raw_fd(), FS_IOC_GETFLAGS as libc::Ioctl, &mut flags) };
+    ret == 0 && flags as u32 & FS_CASEFOLD_FL != 0
+}
+
+/// Whether data written to the file is compressed by the filesystem,
+/// i.e. it has the compression attribute set (see `chattr(1)`) or a
+/// btrfs compression property. New files inherit these from their
+/// directory. Compression enabled by a mount option is not detected.
+pub fn is_compressed(fd: &File) -> bool {
+    let mut flags: libc::c_int = 0;
+    let ret = unsafe { libc::ioctl(fd.as_raw_fd(), FS_IOC_GETFLAGS as libc::Ioctl, &mut flags) };
+    (ret == 0 && flags as u32 & FS_COMPR_FL != 0)
+        || fgetxattr(fd, "btrfs.compression", &mut []).is_ok_and(|size| size > 0)
+}
+
+/// The inode flags copied by [copy_flags]; see `chattr(1)`. Others,
+/// such as the compression and extent flags, belong to the
+/// destination filesystem.
+const COPIED_FLAGS: u32 = FS_SYNC_FL | FS_IMMUTABLE_FL | FS_APPEND_FL | FS_NODUMP_FL | FS_NOATIME_FL | FS_DIRSYNC_FL;
+
+/// The flags that prevent a file being modified, renamed or removed.
+const PROTECTED_FLAGS: u32 = FS_IMMUTABLE_FL | FS_APPEND_FL;
+
+// The inode flags of `fd`, or None if its filesystem has none.
+fn inode_flags(fd: &File) -> Result<Option<u32>> {
+    let mut flags: libc::c_int = 0;
+    if unsafe { libc::ioctl(fd.as_raw_fd(), FS_IOC_GETFLAGS as libc::Ioctl, &mut flags) } != 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENOTTY | libc::EOPNOTSUPP | libc::EINVAL) => Ok(None),
+            _ => Err(err.into()),
+        };
+    }
+    Ok(Some(flags as u32))
+}
+
+fn set_inode_flags(fd: &File, flags: u32) -> Result<()> {
+    let flags = flags as libc::c_int;
+    if unsafe { libc::ioctl(fd.as_raw_fd(), FS_IOC_SETFLAGS as libc::Ioctl, &flags) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Copy the inode flags shown by `lsattr(1)`, such as immutable,
+/// append-only and nodump, keeping the destination's own flags.
+/// Nothing else about the destination can be changed once it is
+/// immutable or append-only, so this should be the last change made
+/// to it. Setting either flag requires `CAP_LINUX_IMMUTABLE`.
+/// Nothing is copied if either filesystem has no inode flags.
+pub fn copy_flags(infd: &File, outfd: &File) -> Result<()> {
+    let (Some(src), Some(dest)) = (inode_flags(infd)?, inode_flags(outfd)?) else {
+        return Ok(());
+    };
+    let flags = (dest & !COPIED_FLAGS) | (src & COPIED_FLAGS);
+    if flags != dest {
+        set_inode_flags(outfd, flags)?;
+    }
+    Ok(())
+}
+
+/// Whether the file is immutable or append-only, so can't be
+/// rewritten, renamed or removed; see `chattr(1)`.
+pub fn is_immutable(fd: &File) -> bool {
+    inode_flags(fd).is_ok_and(|f| f.is_some_and(|f| f & PROTECTED_FLAGS != 0))
+}
+
+/// Clear the immutable and append-only flags of the file, returning
+/// whether either was set. This requires `CAP_LINUX_IMMUTABLE`.
+pub fn clear_immutable(fd: &File) -> Result<bool> {
+    match inode_flags(fd)? {
+        Some(flags) if flags & PROTECTED_FLAGS != 0 => {
+            set_inode_flags(fd, flags & !PROTECTED_FLAGS)?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// The size of a block device in bytes. This uses the `BLKGETSIZE64`
+/// ioctl, falling back to the sector count in `/sys` if that fails.
+pub fn device_size(fd: &File) -> Result<u64> {
+    let mut size: u64 = 0;
+    if unsafe { libc::ioctl(fd.as_raw_fd(), BLKGETSIZE64 as libc::Ioctl, &mut size) } == 0 {
+        return Ok(size);
+    }
+    let err = io::Error::last_os_error();
+    // The sysfs size is always in 512-byte sectors, whatever the
+    // device's sector size.
+    fs::read_to_string(sys_block_dir(fd)?.join("size")).ok()
+        .and_then(|s| s.trim().parse::<u64>().ok())
+        .map(|sectors| sectors * 512)
+        .ok_or_else(|| err.into())
+}
+
+/// The model name of a block device, if known. For partitions this is
+/// the model of the parent device.
+pub fn device_model(fd: &File) -> Option<String> {
+    let dir = sys_block_dir(fd).ok()?;
+    ["device/model", "../device/model"].iter()
+        .find_map(|p| fs::read_to_string(dir.join(p)).ok())
+        .map(|m| m.trim().to_string())
+        .filter(|m| !m.is_empty())
+}
+
+/// Open a file for reading with `O_DIRECT`, bypassing the page
+/// cache. Reads must be aligned to the device's logical block size.
+pub fn open_direct(path: &Path) -> Result<File> {
+    Ok(File::options()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)?)
+}
+
+// The size of an xattr value, without reading it.
+pub(crate) fn xattr_size(fd: &File, name: &OsStr) -> Result<Option<u64>> {
+    match fgetxattr(fd, name, &mut []) {
+        Ok(size) => Ok(Some(size as u64)),
+        Err(Errno::NODATA) => Ok(None),
+        Err(errno) => Err(errno.into()),
+    }
 }
 
 /// Guestimate if file is sparse; if it has less blocks that would be
@@ -150,7 +389,7 @@ fn fiemap(fd: &File, req: &mut FiemapReq) -> Result<bool> {
     // FIXME: Rustix has an IOCTL mini-framework but it's a little
     // tricky and is unsafe anyway. This is simpler for now.
     let req_ptr: *mut FiemapReq = req;
-    if unsafe { libc::ioctl(fd.as_raw_fd(), FS_IOC_FIEMAP as u64, req_ptr) } != 0 {
+    if unsafe { libc::ioctl(fd.as_raw_fd(), FS_IOC_FIEMAP as libc::Ioctl, req_ptr) } != 0 {
         let oserr = io::Error::last_os_error();
         if oserr.raw_os_error() == Some(libc::EOPNOTSUPP) {
             return Ok(false)
@@ -219,6 +458,44 @@ pub fn next_sparse_segments(infd: &File, outfd: &File, pos: u64) -> Result<(u64,
     Ok((next_data, next_hole))
 }
 
+/// List the data ranges of a file, skipping any holes. This uses
+/// `SEEK_DATA`/`SEEK_HOLE`, which unlike [map_extents] is supported
+/// by most filesystems. If the filesystem doesn't support them the
+/// whole file is returned as a single range. Empty files have no
+/// data ranges. The file position is preserved.
+pub fn extents(fd: &File) -> Result<Vec<Range<u64>>> {
+    let len = fd.metadata()?.len();
+    let cursor = seek(fd, SeekFrom::Current(0))?;
+    let ranges = data_ranges(fd, len);
+    seek(fd, SeekFrom::Start(cursor))?;
+    ranges
+}
+
+fn data_ranges(fd: &File, len: u64) -> Result<Vec<Range<u64>>> {
+    let mut ranges = Vec::new();
+
+    let mut pos = 0;
+    while pos < len {
+        let data = match seek(fd, SeekFrom::Data(pos as i64)) {
+            Ok(off) => off,
+            Err(Errno::NXIO) => break,
+            Err(Errno::INVAL) if pos == 0 => return Ok(iter::once(0..len).collect()),
+            Err(err) => return Err(err.into()),
+        };
+        let hole = match lseek(fd, SeekFrom::Hole(data as i64))? {
+            SeekOff::Offset(off) => cmp::min(off, len),
+            SeekOff::EOF => len,
+        };
+        if hole <= data {
+            break;
+        }
+        ranges.push(data..hole);
+        pos = hole;
+    }
+
+    Ok(ranges)
+}
+
 /// Copy data between files, looking for sparse blocks and skipping
 /// them.
 pub fn copy_sparse(infd: &File, outfd: &File) -> Result<u64> {
@@ -228,7 +505,7 @@ pub fn copy_sparse(infd: &File, outfd: &File) -> Result<u64> {
     while pos < len {
         let (next_data, next_hole) = next_sparse_segments(infd, outfd, pos)?;
 
-        let _written = copy_file_bytes(infd, outfd, next_hole - next_data)?;
+        copy_file_bytes_all(infd, outfd, next_hole - next_data)?;
         pos = next_hole;
     }
 
@@ -237,7 +514,6 @@ pub fn copy_sparse(infd: &File, outfd: &File) -> Result<u64> {
 
 /// Create a clone of a special file (unix socket, char-device, etc.)
 pub fn copy_node(src: &Path, dest: &Path) -> Result<()> {
-    use std::os::unix::fs::MetadataExt;
     let meta = src.metadata()?;
     let rmode = RawMode::from(meta.permissions().mode());
     let mode = Mode::from_raw_mode(rmode);
@@ -253,8 +529,7 @@ pub fn copy_node(src: &Path, dest: &Path) -> Result<()> {
 /// updates. Only certain filesystems support this; if not supported
 /// the function returns `false`.
 pub fn reflink(infd: &File, outfd: &File) -> Result<bool> {
-    if unsafe { libc::ioctl(outfd.as_raw_fd(), FICLONE as u64, infd.as_raw_fd()) } != 0 {
-        let oserr = io::Error::last_os_error();
+    if let Err(oserr) = clone_file(infd, outfd) {
         match oserr.raw_os_error() {
             Some(libc::EOPNOTSUPP)
                 | Some(libc::EINVAL)
@@ -268,6 +543,81 @@ pub fn reflink(infd: &File, outfd: &File) -> Result<bool> {
     Ok(true)
 }
 
+/// Reflink a file, returning the underlying OS error on failure. This
+/// is the same as [reflink], but allows callers that require a
+/// reflink to report why it was not possible.
+pub fn clone_file(infd: &File, outfd: &File) -> io::Result<()> {
+    count(Syscall::Reflink);
+    if unsafe { libc::ioctl(outfd.as_raw_fd(), FICLONE as libc::Ioctl, infd.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether `path` is the root directory of a btrfs subvolume. Every
+/// subvolume root has inode number 256 (`BTRFS_FIRST_FREE_OBJECTID`),
+/// which no other directory can have.
+#[cfg(feature = "btrfs")]
+pub fn is_subvolume(path: &Path) -> Result<bool> {
+    let meta = path.symlink_metadata()?;
+    if !meta.is_dir() || meta.ino() != u64::from(BTRFS_FIRST_FREE_OBJECTID) {
+        return Ok(false);
+    }
+    Ok(fs_type(&File::open(path)?)? == FsType::Btrfs)
+}
+
+/// Create `path` as a new btrfs subvolume, rather than a plain
+/// directory, with the `BTRFS_IOC_SUBVOL_CREATE` ioctl. The parent
+/// must exist and be on btrfs; the ioctl fails with `ENOTTY` on other
+/// filesystems.
+#[cfg(feature = "btrfs")]
+pub fn create_subvolume(path: &Path) -> Result<()> {
+    let name = path.file_name()
+        .ok_or_else(|| Error::InvalidPath(path.to_path_buf()))?;
+    let parent = path.parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let mut args = btrfs_ioctl_vol_args { fd: 0, name: [0; BTRFS_PATH_NAME_MAX as usize + 1] };
+    if name.len() > BTRFS_PATH_NAME_MAX as usize {
+        return Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG).into());
+    }
+    for (dst, src) in args.name.iter_mut().zip(name.as_bytes()) {
+        *dst = *src as libc::c_char;
+    }
+    let dir = File::open(parent)?;
+    if unsafe { libc::ioctl(dir.as_raw_fd(), BTRFS_IOC_SUBVOL_CREATE as libc::Ioctl, &args) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Reflink a byte range of one file into another with the
+/// `FICLONERANGE` ioctl. `src_off`, `dst_off` and `len` must be
+/// multiples of the filesystem block size, except that the range may
+/// extend to the end of the source file. If the filesystem doesn't
+/// support this, or the range isn't suitably aligned, nothing is
+/// cloned and the function returns `false`.
+pub fn clone_range(infd: &File, outfd: &File, src_off: u64, dst_off: u64, len: u64) -> Result<bool> {
+    let req = file_clone_range {
+        src_fd: infd.as_raw_fd() as i64,
+        src_offset: src_off,
+        src_length: len,
+        dest_offset: dst_off,
+    };
+    count(Syscall::Reflink);
+    if unsafe { libc::ioctl(outfd.as_raw_fd(), FICLONERANGE as libc::Ioctl, &req) } != 0 {
+        let oserr = io::Error::last_os_error();
+        return match oserr.raw_os_error() {
+            Some(libc::EOPNOTSUPP)
+                | Some(libc::EINVAL)
+                | Some(libc::EXDEV)
+                | Some(libc::ETXTBSY) => Ok(false),
+            _ => Err(oserr.into()),
+        };
+    }
+    Ok(true)
+}
+
 #[cfg(test)]
 #[allow(unused)]
 mod tests {
@@ -291,6 +641,161 @@ mod tests {
         Ok(tempdir_in(current_dir()?.join("../target"))?)
     }
 
+    #[test]
+    fn test_fs_type() -> Result<()> {
+        assert_eq!(FsType::Nfs, FsType::from_magic(0x6969));
+        assert_eq!(FsType::Cifs, FsType::from_magic(0xfe53_4d42));
+        assert_eq!(FsType::Fat, FsType::from_magic(0x4d44));
+        assert_eq!(FsType::Ntfs, FsType::from_magic(0x7366_746e));
+        assert_eq!(FsType::Ext4, FsType::from_magic(0xef53));
+        assert_eq!(FsType::Btrfs, FsType::from_magic(0x9123_683e));
+        assert_eq!(FsType::Other, FsType::from_magic(0x5846_5342));
+        assert!(!FsType::Fuse.is_network());
+
+        let dir = tempdir()?;
+        let file = File::create(dir.path().join("file.bin"))?;
+        assert!(!fs_type(&file)?.is_network());
+        Ok(())
+    }
+
+    #[test]
+    fn test_mountinfo_option() -> Result<()> {
+        let mountinfo = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw,errors=remount-ro
+41 22 0:45 / /mnt/remote rw,nosuid,nodev,relatime shared:40 - fuse.sshfs host:/data rw,user_id=0,group_id=0,max_read=65536
+52 22 0:46 / /mnt/bucket rw,nosuid,max_write=131072 - fuse.s3fs s3fs rw,user_id=0,group_id=0
+";
+        let dev = |major, minor| rustix::fs::makedev(major, minor);
+        assert_eq!(Some("65536"), mountinfo_option(mountinfo, dev(0, 45), "max_read").as_deref());
+        assert_eq!(Some("131072"), mountinfo_option(mountinfo, dev(0, 46), "max_write").as_deref());
+        assert_eq!(Some("remount-ro"), mountinfo_option(mountinfo, dev(8, 1), "errors").as_deref());
+        // Only whole option names match.
+        assert_eq!(None, mountinfo_option(mountinfo, dev(0, 45), "max"));
+        assert_eq!(None, mountinfo_option(mountinfo, dev(0, 45), "max_write"));
+        assert_eq!(None, mountinfo_option(mountinfo, dev(0, 99), "max_write"));
+
+        // The filesystem of the test directory is in the real mountinfo.
+        let dir = tempdir()?;
+        assert!(mount_option(dir.path(), "nonexistent_option")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_casefolded() -> Result<()> {
+        // Casefolding must be enabled per directory, and isn't by
+        // default.
+        let dir = tempdir()?;
+        assert!(!is_casefolded(&File::open(dir.path())?));
+        assert!(!is_casefolded(&File::create(dir.path().join("file.bin"))?));
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_flags() -> Result<()> {
+        let dir = tempdir()?;
+        let (from, to) = (dir.path().join("from"), dir.path().join("to"));
+        let infd = File::create(&from)?;
+        let outfd = File::create(&to)?;
+        // The nodump flag needs no privileges, but not every
+        // filesystem has inode flags.
+        let Some(flags) = inode_flags(&infd)? else {
+            return Ok(());
+        };
+        if set_inode_flags(&infd, flags | FS_NODUMP_FL).is_err() {
+            return Ok(());
+        }
+        copy_flags(&infd, &outfd)?;
+        assert_ne!(0, inode_flags(&outfd)?.unwrap() & FS_NODUMP_FL);
+        assert!(!is_immutable(&outfd));
+        assert!(!clear_immutable(&outfd)?);
+
+        set_inode_flags(&infd, flags)?;
+        copy_flags(&infd, &outfd)?;
+        assert_eq!(0, inode_flags(&outfd)?.unwrap() & FS_NODUMP_FL);
+        Ok(())
+    }
+
+    #[test]
+    fn test_device_size() -> Result<()> {
+        let dir = tempdir()?;
+        let img = dir.path().join("disk.img");
+        File::create(&img)?.set_len(3 * 1024 * 1024 + 512)?;
+        assert!(device_size(&File::open(&img)?).is_err());
+
+        // Attaching a loop device requires root.
+        let out = Command::new("losetup")
+            .args(["-f", "--show", img.to_str().unwrap()])
+            .output();
+        let dev = match out {
+            Ok(out) if out.status.success() => String::from_utf8(out.stdout).unwrap().trim().to_string(),
+            _ => {
+                warn!("Cannot create loop device, skipping");
+                return Ok(());
+            }
+        };
+        let size = device_size(&File::open(&dev)?);
+        let direct = open_direct(Path::new(&dev)).map(|fd| device_size(&fd));
+        let rotational = File::open(&dev)?.metadata().map(|m| is_rotational(m.rdev()));
+        Command::new("losetup").args(["-d", &dev]).output()?;
+
+        assert_eq!(3 * 1024 * 1024 + 512, size?);
+        assert_eq!(3 * 1024 * 1024 + 512, direct??);
+        assert!(rotational?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_rotational_unknown() {
+        // Anonymous devices, e.g. of tmpfs, have no queue.
+        assert_eq!(None, is_rotational(rustix::fs::makedev(0, 9999)));
+    }
+
+    #[test]
+    fn test_try_copy_file_bytes() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        std::fs::write(&from, "X".repeat(4096))?;
+
+        let infd = File::open(&from)?;
+        let outfd = File::create(&to)?;
+        if let Some(n) = try_copy_file_bytes(&infd, &outfd, 4096)? {
+            assert_eq!(4096, n);
+            assert_eq!(read(&from)?, read(&to)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn test_extents() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("sparse.bin");
+        let mb = 1024 * 1024;
+
+        let fd = File::create(&file)?;
+        assert!(extents(&fd)?.is_empty());
+
+        let mut fd = OpenOptions::new().read(true).write(true).open(&file)?;
+        fd.set_len(4 * mb)?;
+        assert!(extents(&fd)?.is_empty());
+
+        fd.write_all(b"data")?;
+        fd.seek(io::SeekFrom::Start(2 * mb))?;
+        fd.write_all(b"data")?;
+        fd.sync_all()?;
+        fd.seek(io::SeekFrom::Start(1234))?;
+
+        let ranges = extents(&fd)?;
+        assert_eq!(2, ranges.len());
+        assert!(ranges[0].contains(&0) && ranges[0].contains(&3));
+        assert!(ranges[1].contains(&(2 * mb)) && ranges[1].contains(&(2 * mb + 3)));
+        assert!(!ranges.iter().any(|r| r.contains(&mb) || r.contains(&(3 * mb))));
+        assert_eq!(1234, fd.stream_position()?);
+
+        Ok(())
+    }
+
     #[test]
     #[cfg_attr(feature = "test_no_reflink", ignore = "No FS support")]
     fn test_reflink() -> Result<()> {
@@ -337,6 +842,100 @@ mod tests {
         Ok(())
     }
 
+    // A reflink-capable filesystem image mounted on a loop device,
+    // unmounted on drop.
+    struct ReflinkMount {
+        mnt: PathBuf,
+    }
+
+    impl ReflinkMount {
+        // Creating and mounting the image requires root and either
+        // mkfs.btrfs or mkfs.xfs, so this returns None if it fails.
+        fn new(dir: &Path) -> Option<ReflinkMount> {
+            Self::with(dir, &[("mkfs.btrfs", vec!["-q"]), ("mkfs.xfs", vec!["-q", "-m", "reflink=1"])])
+        }
+
+        #[cfg(feature = "btrfs")]
+        fn btrfs(dir: &Path) -> Option<ReflinkMount> {
+            Self::with(dir, &[("mkfs.btrfs", vec!["-q"])])
+        }
+
+        fn with(dir: &Path, mkfs: &[(&str, Vec<&str>)]) -> Option<ReflinkMount> {
+            let img = dir.join("fs.img");
+            let mnt = dir.join("mnt");
+            File::create(&img).ok()?.set_len(512 * 1024 * 1024).ok()?;
+            fs::create_dir(&mnt).ok()?;
+            let made = mkfs.iter()
+                .any(|(mkfs, args)| Command::new(mkfs).args(args).arg(&img).output()
+                     .is_ok_and(|out| out.status.success()));
+            if !made {
+                return None;
+            }
+            Command::new("mount").args(["-o", "loop"]).arg(&img).arg(&mnt).output()
+                .ok()
+                .filter(|out| out.status.success())
+                .map(|_| ReflinkMount { mnt })
+        }
+    }
+
+    impl Drop for ReflinkMount {
+        fn drop(&mut self) {
+            let _ = Command::new("umount").arg(&self.mnt).output();
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "btrfs")]
+    fn test_subvolume() -> Result<()> {
+        let dir = tempdir()?;
+        if fs_type(&File::open(dir.path())?)? != FsType::Btrfs {
+            assert!(!is_subvolume(dir.path())?);
+            assert!(create_subvolume(&dir.path().join("sub")).is_err());
+        }
+        let Some(mount) = ReflinkMount::btrfs(dir.path()) else {
+            warn!("Cannot mount a btrfs filesystem, skipping");
+            return Ok(());
+        };
+        // The top-level subvolume.
+        assert!(is_subvolume(&mount.mnt)?);
+        let sub = mount.mnt.join("sub");
+        create_subvolume(&sub)?;
+        assert!(is_subvolume(&sub)?);
+        fs::create_dir(sub.join("plain"))?;
+        assert!(!is_subvolume(&sub.join("plain"))?);
+        assert!(create_subvolume(&sub).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_clone_range() -> Result<()> {
+        let dir = tempdir()?;
+        let Some(mount) = ReflinkMount::new(dir.path()) else {
+            warn!("Cannot mount a reflink-capable filesystem, skipping");
+            return Ok(());
+        };
+        let from = mount.mnt.join("file.bin");
+        let to = mount.mnt.join("copy.bin");
+        let data = (0..3 * 4096 + 100).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        fs::write(&from, &data)?;
+
+        let from_fd = File::open(&from)?;
+        let to_fd = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&to)?;
+
+        // Block aligned.
+        assert!(clone_range(&from_fd, &to_fd, 4096, 0, 4096)?);
+        // Unaligned offsets and lengths aren't cloned.
+        assert!(!clone_range(&from_fd, &to_fd, 100, 4096, 4096)?);
+        assert!(!clone_range(&from_fd, &to_fd, 0, 4096, 1000)?);
+        // An unaligned length is allowed up to the end of the source.
+        assert!(clone_range(&from_fd, &to_fd, 2 * 4096, 4096, 4096 + 100)?);
+
+        let copied = read(&to)?;
+        assert_eq!(4096 + 4096 + 100, copied.len());
+        assert_eq!(&data[4096..], &copied[..]);
+        Ok(())
+    }
+
     #[test]
     #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
     fn test_sparse_detection_small_data() -> Result<()> {
@@ -376,7 +975,7 @@ mod tests {
         {
             let mut fd = OpenOptions::new().write(true).append(false).open(&file)?;
             let s = "x".repeat(512*1024);
-            fd.write(s.as_bytes())?;
+            fd.write_all(s.as_bytes())?;
             assert!(probably_sparse(&fd)?);
         }
 
@@ -484,7 +1083,7 @@ mod tests {
             let infd = File::open(&from)?;
             let outfd: File = OpenOptions::new().write(true).append(false).open(&file)?;
             let copied =
-                copy_file_offset(&infd, &outfd, data.len() as u64, offset as i64)?;
+                copy_file_offset(&infd, &outfd, data.len() as u64, offset as u64)?;
             assert_eq!(copied as usize, data.len());
         }
 
@@ -674,8 +1273,8 @@ mod tests {
         assert!(extents_p.is_some());
         let extents = extents_p.unwrap();
         assert_eq!(extents.len(), 1);
-        assert_eq!(extents[0].start, offset as u64);
-        assert_eq!(extents[0].end, offset as u64 + 4 * 1024); // FIXME: Assume 4k blocks
+        assert_eq!(extents[0].start, offset);
+        assert_eq!(extents[0].end, offset + 4 * 1024); // FIXME: Assume 4k blocks
         assert!(!extents[0].shared);
 
         Ok(())
@@ -695,7 +1294,7 @@ mod tests {
         let fsize = 1024 * 1024;
         // FIXME: Assumes 4k blocks
         let bsize = 4 * 1024;
-        let block = iter::repeat(0xff_u8).take(bsize).collect::<Vec<u8>>();
+        let block = iter::repeat_n(0xff_u8, bsize).collect::<Vec<u8>>();
 
         let mut fd = OpenOptions::new().write(true).append(false).open(&file)?;
         // Skip every-other block
@@ -731,7 +1330,7 @@ mod tests {
         let extents = extents_p.unwrap();
 
         assert_eq!(1, extents.len());
-        assert_eq!(0 as u64, extents[0].start);
+        assert_eq!(0_u64, extents[0].start);
         assert_eq!(size as u64, extents[0].end);
 
         Ok(())
@@ -816,7 +1415,7 @@ mod tests {
         {
             let from_fd: File = File::open(&from)?;
             let to_fd: File = File::open(&to)?;
-            copy_permissions(&from_fd, &to_fd)?;
+            copy_permissions(&from_fd, &to_fd, false, None)?;
         }
 
         let to_acl = getfacl(&from, None)?;
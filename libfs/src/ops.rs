@@ -0,0 +1,494 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The filesystem operations performed by copy drivers, behind a
+//! trait so they can be substituted. [RealFs] performs them on the
+//! real filesystem, and [FaultInjectingFs] wraps another
+//! implementation to fail chosen operations on chosen paths, allowing
+//! error handling to be tested without e.g. filling a disk, or to slow
+//! them down, as on a remote filesystem.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::{self, DirBuilder, File, Metadata, OpenOptions};
+use std::io;
+use std::ops::Range;
+use std::os::unix::fs::{DirBuilderExt, FileExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::errors::Result;
+use crate::FileType;
+use crate::{copy_file_bytes, copy_file_bytes_observed, copy_file_offset, copy_flags, copy_mode, copy_owner, copy_timestamps};
+
+/// The operations of [FsOps], for selecting faults to inject.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FsOp {
+    OpenSource,
+    CreateDest,
+    CopyBytes,
+    ReadAt,
+    Mkdir,
+    Stat,
+    SetMetadata,
+    Truncate,
+}
+
+/// Metadata copied from a source file to its destination by
+/// [FsOps::set_metadata].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Attribute {
+    /// The user and group.
+    Owner,
+    /// The permission bits, optionally including setuid and setgid;
+    /// see [copy_mode].
+    Mode { setid: bool },
+    /// The access and modification times.
+    Timestamps,
+    /// The inode flags, such as immutable; see [copy_flags].
+    Flags,
+}
+
+/// Receives each block of data copied by [FsOps::copy_bytes].
+pub type Observer<'a> = &'a mut dyn FnMut(&[u8]);
+
+/// Filesystem operations used when copying a tree. Only the
+/// operations around each file are included; the data is copied in
+/// blocks, so dynamic dispatch is not a significant cost.
+pub trait FsOps: Debug + Send + Sync {
+    /// Open a source file for reading.
+    fn open_source(&self, path: &Path) -> io::Result<File>;
+
+    /// Create or open a destination file with `options`.
+    fn create_dest(&self, path: &Path, options: &OpenOptions) -> io::Result<File>;
+
+    /// Copy `len` bytes from the current file cursors, to the file at
+    /// `to`. If an observer is given the data passes through
+    /// userspace and is passed to it as it is written; see
+    /// [copy_file_bytes_observed].
+    fn copy_bytes(
+        &self,
+        to: &Path,
+        infd: &File,
+        outfd: &File,
+        len: u64,
+        observer: Option<Observer<'_>>,
+    ) -> Result<u64>;
+
+    /// Copy `len` bytes at offset `off` in both files, to the file at
+    /// `to`.
+    fn copy_bytes_at(&self, to: &Path, infd: &File, outfd: &File, len: u64, off: u64) -> Result<u64>;
+
+    /// Read into `buf` at offset `off` of `fd`, the source file at
+    /// `path`. Only used to recover data after a copy fails, so it
+    /// is done through userspace.
+    fn read_at(&self, path: &Path, fd: &File, buf: &mut [u8], off: u64) -> io::Result<usize>;
+
+    /// Create a single directory with `mode`, before the umask.
+    fn mkdir(&self, path: &Path, mode: u32) -> io::Result<()>;
+
+    /// The metadata of a path, without following symlinks.
+    fn stat(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Copy an attribute of `infd` to `outfd`, the file at `path`.
+    fn set_metadata(&self, path: &Path, infd: &File, outfd: &File, attr: Attribute) -> Result<()>;
+
+    /// Set the length of `fd`, the file at `path`.
+    fn truncate(&self, path: &Path, fd: &File, len: u64) -> io::Result<()>;
+
+    /// The type readdir reported for the directory entry `path`, or
+    /// None if it is unknown (`DT_UNKNOWN`), as on XFS without ftype
+    /// and some FUSE filesystems. The standard library already stats
+    /// such entries when asked their type, so by default `reported`
+    /// is trusted; see [classify_entry].
+    fn dirent_type(&self, _path: &Path, reported: fs::FileType) -> Option<fs::FileType> {
+        Some(reported)
+    }
+}
+
+/// Classify the directory entry `path` by the type readdir reported,
+/// falling back to a stat only if it is unknown; see
+/// [FsOps::dirent_type].
+pub fn classify_entry(fs: &dyn FsOps, path: &Path, reported: fs::FileType) -> io::Result<FileType> {
+    let ft = match fs.dirent_type(path, reported) {
+        Some(ft) => ft,
+        None => fs.stat(path)?.file_type(),
+    };
+    Ok(FileType::from(ft))
+}
+
+/// The real filesystem.
+#[derive(Debug, Default)]
+pub struct RealFs;
+
+impl FsOps for RealFs {
+    fn open_source(&self, path: &Path) -> io::Result<File> {
+        File::open(path)
+    }
+
+    fn create_dest(&self, path: &Path, options: &OpenOptions) -> io::Result<File> {
+        options.open(path)
+    }
+
+    fn copy_bytes(
+        &self,
+        _to: &Path,
+        infd: &File,
+        outfd: &File,
+        len: u64,
+        observer: Option<Observer<'_>>,
+    ) -> Result<u64> {
+        match observer {
+            Some(observer) => copy_file_bytes_observed(infd, outfd, len, observer),
+            None => copy_file_bytes(infd, outfd, len),
+        }
+    }
+
+    fn copy_bytes_at(&self, _to: &Path, infd: &File, outfd: &File, len: u64, off: u64) -> Result<u64> {
+        copy_file_offset(infd, outfd, len, off)
+    }
+
+    fn read_at(&self, _path: &Path, fd: &File, buf: &mut [u8], off: u64) -> io::Result<usize> {
+        fd.read_at(buf, off)
+    }
+
+    fn mkdir(&self, path: &Path, mode: u32) -> io::Result<()> {
+        DirBuilder::new()
+            .mode(mode)
+            .create(path)
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<Metadata> {
+        path.symlink_metadata()
+    }
+
+    fn set_metadata(&self, _path: &Path, infd: &File, outfd: &File, attr: Attribute) -> Result<()> {
+        match attr {
+            Attribute::Owner => copy_owner(infd, outfd),
+            Attribute::Mode { setid } => copy_mode(infd, outfd, setid),
+            Attribute::Timestamps => copy_timestamps(infd, outfd),
+            Attribute::Flags => copy_flags(infd, outfd),
+        }
+    }
+
+    fn truncate(&self, _path: &Path, fd: &File, len: u64) -> io::Result<()> {
+        fd.set_len(len)
+    }
+}
+
+#[derive(Debug)]
+struct Fault {
+    op: FsOp,
+    path: PathBuf,
+    errno: i32,
+    /// Matching calls to allow before failing.
+    after: usize,
+    /// Only fail reads overlapping these bytes of the file.
+    range: Option<Range<u64>>,
+}
+
+/// Wraps another [FsOps], failing selected operations with an OS
+/// error. A fault applies to a path and everything beneath it; for
+/// [FsOp::CopyBytes] and [FsOp::SetMetadata] this is the destination
+/// path, and otherwise the path the operation is given. Intended for
+/// testing.
+#[derive(Debug)]
+pub struct FaultInjectingFs {
+    inner: Arc<dyn FsOps>,
+    faults: Mutex<Vec<Fault>>,
+    calls: Mutex<HashMap<FsOp, usize>>,
+    delays: Mutex<HashMap<FsOp, Duration>>,
+    unknown_types: AtomicBool,
+}
+
+impl Default for FaultInjectingFs {
+    fn default() -> Self {
+        FaultInjectingFs::new()
+    }
+}
+
+impl FaultInjectingFs {
+    /// Wrap the real filesystem.
+    pub fn new() -> FaultInjectingFs {
+        FaultInjectingFs::wrap(Arc::new(RealFs))
+    }
+
+    pub fn wrap(inner: Arc<dyn FsOps>) -> FaultInjectingFs {
+        FaultInjectingFs {
+            inner,
+            faults: Mutex::new(Vec::new()),
+            calls: Mutex::new(HashMap::new()),
+            delays: Mutex::new(HashMap::new()),
+            unknown_types: AtomicBool::new(false),
+        }
+    }
+
+    /// Fail every `op` on `path` or beneath it with `errno`.
+    pub fn fail(&self, op: FsOp, path: impl Into<PathBuf>, errno: i32) -> &Self {
+        self.fail_after(op, path, errno, 0)
+    }
+
+    /// As [FaultInjectingFs::fail], but only once `after` matching
+    /// calls have succeeded; e.g. to fail part-way through copying a
+    /// file.
+    pub fn fail_after(&self, op: FsOp, path: impl Into<PathBuf>, errno: i32, after: usize) -> &Self {
+        self.faults.lock().unwrap()
+            .push(Fault { op, path: path.into(), errno, after, range: None });
+        self
+    }
+
+    /// As [FaultInjectingFs::fail] for [FsOp::ReadAt], but only reads
+    /// overlapping `range` of the file fail; e.g. to simulate
+    /// unreadable sectors.
+    pub fn fail_range(&self, path: impl Into<PathBuf>, range: Range<u64>, errno: i32) -> &Self {
+        self.faults.lock().unwrap()
+            .push(Fault { op: FsOp::ReadAt, path: path.into(), errno, after: 0, range: Some(range) });
+        self
+    }
+
+    /// Sleep for `delay` before every `op`, on any path; e.g. to
+    /// simulate a filesystem with slow metadata updates.
+    pub fn delay(&self, op: FsOp, delay: Duration) -> &Self {
+        self.delays.lock().unwrap().insert(op, delay);
+        self
+    }
+
+    /// Report the type of every directory entry as unknown, as a
+    /// filesystem returning `DT_UNKNOWN` from readdir does.
+    pub fn hide_dirent_types(&self) -> &Self {
+        self.unknown_types.store(true, Ordering::Relaxed);
+        self
+    }
+
+    /// Remove all faults, e.g. to simulate the condition clearing.
+    pub fn clear(&self) {
+        self.faults.lock().unwrap().clear();
+    }
+
+    /// The number of times `op` has been called, including those
+    /// that failed.
+    pub fn calls(&self, op: FsOp) -> usize {
+        self.calls.lock().unwrap()
+            .get(&op)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn check(&self, op: FsOp, path: &Path) -> io::Result<()> {
+        self.check_at(op, path, None)
+    }
+
+    // As check, for an operation on `span` of the file.
+    fn check_at(&self, op: FsOp, path: &Path, span: Option<Range<u64>>) -> io::Result<()> {
+        *self.calls.lock().unwrap().entry(op).or_default() += 1;
+        let delay = self.delays.lock().unwrap().get(&op).copied();
+        if let Some(delay) = delay {
+            thread::sleep(delay);
+        }
+        let overlaps = |range: &Range<u64>| span.as_ref()
+            .is_some_and(|s| s.start < range.end && range.start < s.end);
+        let mut faults = self.faults.lock().unwrap();
+        let fault = faults.iter_mut()
+            .find(|f| f.op == op && path.starts_with(&f.path) && f.range.as_ref().is_none_or(overlaps));
+        match fault {
+            Some(f) if f.after > 0 => {
+                f.after -= 1;
+                Ok(())
+            }
+            Some(f) => Err(io::Error::from_raw_os_error(f.errno)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FsOps for FaultInjectingFs {
+    fn open_source(&self, path: &Path) -> io::Result<File> {
+        self.check(FsOp::OpenSource, path)?;
+        self.inner.open_source(path)
+    }
+
+    fn create_dest(&self, path: &Path, options: &OpenOptions) -> io::Result<File> {
+        self.check(FsOp::CreateDest, path)?;
+        self.inner.create_dest(path, options)
+    }
+
+    fn copy_bytes(
+        &self,
+        to: &Path,
+        infd: &File,
+        outfd: &File,
+        len: u64,
+        observer: Option<Observer<'_>>,
+    ) -> Result<u64> {
+        self.check(FsOp::CopyBytes, to)?;
+        self.inner.copy_bytes(to, infd, outfd, len, observer)
+    }
+
+    fn copy_bytes_at(&self, to: &Path, infd: &File, outfd: &File, len: u64, off: u64) -> Result<u64> {
+        self.check(FsOp::CopyBytes, to)?;
+        self.inner.copy_bytes_at(to, infd, outfd, len, off)
+    }
+
+    fn read_at(&self, path: &Path, fd: &File, buf: &mut [u8], off: u64) -> io::Result<usize> {
+        self.check_at(FsOp::ReadAt, path, Some(off..off + buf.len() as u64))?;
+        self.inner.read_at(path, fd, buf, off)
+    }
+
+    fn mkdir(&self, path: &Path, mode: u32) -> io::Result<()> {
+        self.check(FsOp::Mkdir, path)?;
+        self.inner.mkdir(path, mode)
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<Metadata> {
+        self.check(FsOp::Stat, path)?;
+        self.inner.stat(path)
+    }
+
+    fn set_metadata(&self, path: &Path, infd: &File, outfd: &File, attr: Attribute) -> Result<()> {
+        self.check(FsOp::SetMetadata, path)?;
+        self.inner.set_metadata(path, infd, outfd, attr)
+    }
+
+    fn truncate(&self, path: &Path, fd: &File, len: u64) -> io::Result<()> {
+        self.check(FsOp::Truncate, path)?;
+        self.inner.truncate(path, fd, len)
+    }
+
+    fn dirent_type(&self, path: &Path, reported: fs::FileType) -> Option<fs::FileType> {
+        if self.unknown_types.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.inner.dirent_type(path, reported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::is_no_space;
+    use std::fs::write;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_fault_paths() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sub = dir.path().join("sub");
+        let fs = FaultInjectingFs::new();
+        fs.fail(FsOp::Mkdir, &sub, libc::EACCES);
+
+        let err = fs.mkdir(&sub.join("deep"), 0o777).unwrap_err();
+        assert_eq!(Some(libc::EACCES), err.raw_os_error());
+        assert!(fs.mkdir(&sub, 0o777).is_err());
+        // Only the given operation and paths are affected.
+        fs.mkdir(&dir.path().join("other"), 0o777)?;
+        assert!(fs.stat(&dir.path().join("other"))?.is_dir());
+        assert_eq!(3, fs.calls(FsOp::Mkdir));
+
+        fs.clear();
+        fs.mkdir(&sub, 0o777)?;
+        assert!(sub.is_dir());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fault_after() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from");
+        let to = dir.path().join("to");
+        write(&from, "0123456789")?;
+
+        let fs = FaultInjectingFs::new();
+        fs.fail_after(FsOp::CopyBytes, &to, libc::ENOSPC, 2);
+        let infd = fs.open_source(&from)?;
+        let outfd = fs.create_dest(&to, OpenOptions::new().write(true).create(true))?;
+        assert_eq!(4, fs.copy_bytes(&to, &infd, &outfd, 4, None)?);
+        assert_eq!(4, fs.copy_bytes_at(&to, &infd, &outfd, 4, 4)?);
+        let err = fs.copy_bytes_at(&to, &infd, &outfd, 2, 8).unwrap_err();
+        assert!(is_no_space(&err));
+        assert_eq!(8, to.metadata()?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fault_range() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from");
+        write(&from, "0123456789")?;
+
+        let fs = FaultInjectingFs::new();
+        fs.fail_range(&from, 4..6, libc::EIO);
+        let infd = fs.open_source(&from)?;
+        let mut buf = [0; 4];
+        assert_eq!(4, fs.read_at(&from, &infd, &mut buf, 0)?);
+        assert_eq!(b"0123", &buf);
+        assert!(fs.read_at(&from, &infd, &mut buf, 2).is_err());
+        assert!(fs.read_at(&from, &infd, &mut buf[..1], 5).is_err());
+        assert_eq!(4, fs.read_at(&from, &infd, &mut buf, 6)?);
+        // Other operations on the file are unaffected.
+        fs.stat(&from)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_delay() -> Result<()> {
+        let dir = TempDir::new()?;
+        let fs = FaultInjectingFs::new();
+        fs.delay(FsOp::Stat, Duration::from_millis(50));
+        let start = std::time::Instant::now();
+        fs.stat(dir.path())?;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        Ok(())
+    }
+
+    #[test]
+    fn test_classify_entry() -> Result<()> {
+        let dir = TempDir::new()?;
+        write(dir.path().join("file"), "data")?;
+        fs::create_dir(dir.path().join("dir"))?;
+        std::os::unix::fs::symlink("dir", dir.path().join("link"))?;
+        let expected = [("dir", FileType::Dir), ("file", FileType::File), ("link", FileType::Symlink)];
+        let entries = || -> io::Result<Vec<(PathBuf, fs::FileType)>> {
+            let mut entries = fs::read_dir(dir.path())?
+                .map(|e| e.and_then(|e| Ok((e.path(), e.file_type()?))))
+                .collect::<io::Result<Vec<_>>>()?;
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            Ok(entries)
+        };
+
+        // A reported type is used without a stat.
+        let fs = FaultInjectingFs::new();
+        fs.fail(FsOp::Stat, dir.path(), libc::EIO);
+        for ((path, reported), (name, ft)) in entries()?.into_iter().zip(expected) {
+            assert_eq!(dir.path().join(name), path);
+            assert_eq!(ft, classify_entry(&fs, &path, reported)?);
+        }
+        assert_eq!(0, fs.calls(FsOp::Stat));
+
+        // Unknown types are statted.
+        fs.hide_dirent_types();
+        let (path, reported) = entries()?.remove(0);
+        assert!(classify_entry(&fs, &path, reported).is_err());
+        fs.clear();
+        for ((path, reported), (_, ft)) in entries()?.into_iter().zip(expected) {
+            assert_eq!(ft, classify_entry(&fs, &path, reported)?);
+        }
+        assert_eq!(4, fs.calls(FsOp::Stat));
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Print what copying the sources to a destination would do, and how
+//! much data it would copy, without copying anything:
+//!
+//!     cargo run -p libxcp --example plan -- SOURCE... DEST
+//!
+//! The plan is walked on its own thread, while this one reports it.
+
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::sync::mpsc;
+use std::thread;
+
+use libxcp::config::Config;
+use libxcp::errors::Result;
+use libxcp::plan::{copy_plan, PlanEntry};
+
+fn main() -> Result<()> {
+    let mut paths = env::args_os().skip(1).map(PathBuf::from).collect::<Vec<_>>();
+    if paths.len() < 2 {
+        eprintln!("Usage: plan SOURCE... DEST");
+        process::exit(2);
+    }
+    let dest = paths.pop().unwrap_or_default();
+
+    let (tx, rx) = mpsc::sync_channel(1024);
+    let walker = thread::spawn(move || {
+        for entry in copy_plan(paths, &dest, &Config::default()) {
+            if tx.send(entry).is_err() {
+                break;
+            }
+        }
+    });
+
+    let (mut files, mut bytes) = (0, 0);
+    for entry in rx {
+        match entry? {
+            PlanEntry::CreateDir { dest, .. } => println!("mkdir   {}", dest.display()),
+            PlanEntry::CopyFile { dest, size, .. } => {
+                println!("copy    {} ({} bytes)", dest.display(), size);
+                files += 1;
+                bytes += size;
+            }
+            PlanEntry::CreateSymlink { dest, .. } => println!("symlink {}", dest.display()),
+            PlanEntry::CreateSpecial { dest, .. } => println!("special {}", dest.display()),
+            PlanEntry::HardLink { existing, dest, .. } => {
+                println!("link    {} => {}", dest.display(), existing.display());
+            }
+            PlanEntry::Delete { dest } => println!("delete  {}", dest.display()),
+            PlanEntry::Skip { dest, reason, .. } => println!("skip    {} ({:?})", dest.display(), reason),
+        }
+    }
+    walker.join().expect("plan thread panicked");
+
+    println!("{} files, {} bytes to copy", files, bytes);
+    Ok(())
+}
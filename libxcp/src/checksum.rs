@@ -0,0 +1,193 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Support for checksumming copied files.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+
+use crate::errors::{unexpected_value, Result, XcpError};
+
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Enum defining the supported checksum algorithms. [FromStr] is
+/// supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumType {
+    /// [BLAKE3](https://github.com/BLAKE3-team/BLAKE3); the default.
+    #[default]
+    Blake3,
+    /// SHA-256
+    Sha256,
+}
+
+impl FromStr for ChecksumType {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "blake3" => Ok(ChecksumType::Blake3),
+            "sha256" => Ok(ChecksumType::Sha256),
+            _ => Err(unexpected_value("checksum", s, &["blake3", "sha256"])),
+        }
+    }
+}
+
+impl fmt::Display for ChecksumType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChecksumType::Blake3 => write!(f, "blake3"),
+            ChecksumType::Sha256 => write!(f, "sha256"),
+        }
+    }
+}
+
+/// Incremental hasher for the supported [ChecksumType]s.
+pub enum Hasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    pub fn new(ctype: ChecksumType) -> Hasher {
+        match ctype {
+            ChecksumType::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            ChecksumType::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Blake3(h) => { h.update(data); }
+            Hasher::Sha256(h) => h.update(data),
+        }
+    }
+
+    /// Consume the hasher and return the digest as a lowercase hex
+    /// string.
+    pub fn finalize(self) -> String {
+        match self {
+            Hasher::Blake3(h) => h.finalize().to_hex().to_string(),
+            Hasher::Sha256(h) => h.finalize()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+        }
+    }
+}
+
+/// Read a file in full and return its checksum as a hex string.
+pub fn checksum_file(path: &Path, ctype: ChecksumType) -> Result<String> {
+    checksum_file_with(path, ctype, HASH_BUFFER_SIZE, &mut |_| Ok(()))
+}
+
+/// As [checksum_file], reading `buffer_size` bytes at a time and
+/// calling `progress` with the length of each read.
+pub fn checksum_file_with(path: &Path, ctype: ChecksumType, buffer_size: usize,
+                          progress: &mut dyn FnMut(u64) -> Result<()>) -> Result<String>
+{
+    let mut fd = File::open(path)?;
+    let mut hasher = Hasher::new(ctype);
+    let mut buf = vec![0; buffer_size.max(1)];
+    loop {
+        match fd.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => {
+                hasher.update(&buf[..len]);
+                progress(len as u64)?;
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(hasher.finalize())
+}
+
+/// The checksum of a copied file, along with the metadata needed to
+/// later verify it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileChecksum {
+    /// The path of the file. When sent as a status update this is
+    /// the full destination path; in a manifest it is relative to
+    /// the manifest root.
+    pub path: PathBuf,
+    /// File size in bytes.
+    pub size: u64,
+    /// Modification time, seconds since the epoch.
+    pub mtime: i64,
+    /// Nanosecond component of the modification time.
+    pub mtime_nsec: i64,
+    /// Hex-encoded checksum of the file contents.
+    pub checksum: String,
+}
+
+impl FileChecksum {
+    /// Checksum a file on disk.
+    pub fn from_file(path: &Path, ctype: ChecksumType) -> Result<FileChecksum> {
+        let checksum = checksum_file(path, ctype)?;
+        FileChecksum::from_digest(path, checksum)
+    }
+
+    /// Create an entry for a file from an already-calculated digest,
+    /// e.g. one computed while the file was being copied.
+    pub fn from_digest(path: &Path, checksum: String) -> Result<FileChecksum> {
+        let meta = path.metadata()?;
+        Ok(FileChecksum {
+            path: path.to_path_buf(),
+            size: meta.len(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            checksum,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_checksum_type_parse() {
+        assert_eq!(ChecksumType::Blake3, "blake3".parse().unwrap());
+        assert_eq!(ChecksumType::Sha256, "SHA256".parse().unwrap());
+        assert!("md5".parse::<ChecksumType>().is_err());
+    }
+
+    #[test]
+    fn test_known_digests() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let file = tdir.path().join("file.txt");
+        write(&file, "abc")?;
+
+        assert_eq!("6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85",
+                   checksum_file(&file, ChecksumType::Blake3)?);
+        assert_eq!("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+                   checksum_file(&file, ChecksumType::Sha256)?);
+
+        Ok(())
+    }
+}
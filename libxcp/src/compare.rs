@@ -0,0 +1,487 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Comparison of source entries against an existing destination.
+//!
+//! Each difference is reported as an [Item], which is sent as a
+//! [StatusUpdate::Item] when [Config::itemize] is set. The display
+//! format of an item is a compact change vector similar to `rsync
+//! --itemize-changes`:
+//!
+//! * `>f+++` a new file
+//! * `cd+++` a new directory (`cL` symlink, `cS` special file)
+//! * `>fstp` an existing file; `s`, `t` and `p` mark a size,
+//!   modification time or mode difference, `.` an unchanged attribute
+//! * `*deleting` an extraneous destination entry (with [Config::delete])
+//!
+//! [compare_trees] compares two trees without copying, and also
+//! reports:
+//!
+//! * `>fc..` a file of the same size whose contents differ (with
+//!   [Config::compare_checksum])
+//! * `*extra` an entry that only exists in the destination
+//!
+//! [StatusUpdate::Item]: crate::feedback::StatusUpdate::Item
+//! [Config::itemize]: crate::config::Config::itemize
+//! [Config::delete]: crate::config::Config::delete
+//! [Config::compare_checksum]: crate::config::Config::compare_checksum
+
+use std::fmt;
+use std::fs::{canonicalize, read_link, Metadata};
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use ignore::gitignore::Gitignore;
+use log::debug;
+use serde::Serialize;
+use walkdir::WalkDir;
+
+use crate::checksum::{checksum_file_with, ChecksumType};
+use crate::config::{Config, PreserveSet};
+use crate::errors::{Result, XcpError};
+use crate::feedback::{StatusUpdate, StatusUpdater};
+use crate::filter::Filter;
+use crate::paths::{ignore_filter, parse_ignore, pattern_filter};
+use crate::timestamps::{is_newer, Granularities};
+
+/// Upper limit on the read buffer when comparing contents.
+const MAX_COMPARE_BUFFER: u64 = 16 * 1024 * 1024;
+
+/// The type of an itemized entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    Special,
+}
+
+impl EntryKind {
+    pub(crate) fn from_meta(meta: &Metadata) -> EntryKind {
+        let ft = meta.file_type();
+        if ft.is_file() {
+            EntryKind::File
+        } else if ft.is_dir() {
+            EntryKind::Dir
+        } else if ft.is_symlink() {
+            EntryKind::Symlink
+        } else {
+            EntryKind::Special
+        }
+    }
+
+    fn code(self) -> char {
+        match self {
+            EntryKind::File => 'f',
+            EntryKind::Dir => 'd',
+            EntryKind::Symlink => 'L',
+            EntryKind::Special => 'S',
+        }
+    }
+}
+
+/// The difference between a source entry and the destination.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "change", rename_all = "lowercase")]
+pub enum Change {
+    /// The entry is missing at the destination, or is of a different
+    /// type and will be replaced.
+    New,
+    /// The entry exists at the destination but some attributes
+    /// differ. Only attributes that would be copied are compared.
+    Changed {
+        size: bool,
+        mtime: bool,
+        mode: bool,
+    },
+    /// The entry exists only at the destination, and will be removed.
+    Deleted,
+    /// A file of the same size at the destination has different
+    /// contents.
+    Content,
+    /// The entry exists only at the destination; see [compare_trees].
+    Extra,
+}
+
+/// A single itemized change, keyed by the destination path.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Item {
+    pub path: PathBuf,
+    pub kind: EntryKind,
+    #[serde(flatten)]
+    pub change: Change,
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |set, c| if set { c } else { '.' };
+        let vector = match self.change {
+            Change::Deleted => "*deleting".to_string(),
+            Change::Extra => "*extra".to_string(),
+            Change::Content => format!(">{}c..", self.kind.code()),
+            Change::New => {
+                let op = if self.kind == EntryKind::File { '>' } else { 'c' };
+                format!("{}{}+++", op, self.kind.code())
+            }
+            Change::Changed { size, mtime, mode } => {
+                let op = match self.kind {
+                    EntryKind::File if size || mtime => '>',
+                    _ => '.',
+                };
+                format!("{}{}{}{}{}", op, self.kind.code(),
+                        flag(size, 's'), flag(mtime, 't'), flag(mode, 'p'))
+            }
+        };
+        write!(f, "{} {}", vector, self.path.display())
+    }
+}
+
+/// Compare a source entry with its destination path. Returns `None`
+/// if the destination is up to date.
+pub(crate) fn compare_entry(
+    from: &Path,
+    meta: &Metadata,
+    target: &Path,
+    config: &Config,
+    granularities: &mut Granularities,
+) -> Result<Option<Item>> {
+    let kind = EntryKind::from_meta(meta);
+    let item = |change| Some(Item { path: target.to_path_buf(), kind, change });
+
+    let tmeta = match target.symlink_metadata() {
+        Ok(m) => m,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(item(Change::New)),
+        Err(e) => return Err(e.into()),
+    };
+    if EntryKind::from_meta(&tmeta) != kind {
+        return Ok(item(Change::New));
+    }
+
+    let change = match kind {
+        EntryKind::Symlink => {
+            if read_link(from)? != read_link(target)? {
+                return Ok(item(Change::New));
+            }
+            return Ok(None);
+        }
+        EntryKind::File => {
+            let size = meta.len() != tmeta.len();
+            let mtime = config.preserve.contains(PreserveSet::TIMESTAMPS) && {
+                let tdir = target.parent()
+                    .filter(|p| !p.as_os_str().is_empty())
+                    .unwrap_or(Path::new("."));
+                let gran = granularities.get(tdir)?;
+                let (smod, tmod) = (meta.modified()?, tmeta.modified()?);
+                is_newer(smod, tmod, gran) || is_newer(tmod, smod, gran)
+            };
+            let mode = config.preserve.contains(PreserveSet::MODE) && modes_differ(meta, &tmeta);
+            Change::Changed { size, mtime, mode }
+        }
+        // Directory timestamps change as entries are added, so are
+        // not compared.
+        EntryKind::Dir | EntryKind::Special => Change::Changed {
+            size: false,
+            mtime: false,
+            mode: config.preserve.contains(PreserveSet::MODE) && modes_differ(meta, &tmeta),
+        },
+    };
+
+    match change {
+        Change::Changed { size: false, mtime: false, mode: false } => Ok(None),
+        c => Ok(item(c)),
+    }
+}
+
+fn modes_differ(a: &Metadata, b: &Metadata) -> bool {
+    (a.mode() & 0o7777) != (b.mode() & 0o7777)
+}
+
+/// Compare the tree at `source` with `dest` without copying, returning
+/// the differences sorted by path. The source is walked as for a copy,
+/// and each entry compared with [compare_entry]. With
+/// [Config::compare_checksum] file contents are compared instead of
+/// modification times; files of the same size are then read from both
+/// trees at once by the worker threads, sending
+/// [StatusUpdate::Size] and [StatusUpdate::Copied] as they go. Entries
+/// that only exist under `dest` are reported as [Change::Extra].
+///
+/// [Config::compare_checksum]: crate::config::Config::compare_checksum
+pub fn compare_trees(
+    source: &Path,
+    dest: &Path,
+    config: &Config,
+    updates: &Arc<dyn StatusUpdater>,
+) -> Result<Vec<Item>> {
+    // The contents decide whether files match when checksumming.
+    let meta_config = Config {
+        preserve: match config.compare_checksum {
+            Some(_) => config.preserve.difference(PreserveSet::TIMESTAMPS),
+            None => config.preserve,
+        },
+        ..config.clone()
+    };
+    let mut granularities = Granularities::default();
+    let mut items = Vec::new();
+    // Files of the same size to checksum, with any other difference.
+    let mut pending = Vec::new();
+    let gitignore = parse_ignore(source, config)?;
+
+    for entry in WalkDir::new(source)
+        .into_iter()
+        .filter_entry(|e| ignore_filter(e, &gitignore) && pattern_filter(e, source, &config.filter))
+    {
+        let entry = entry?;
+        let from = if config.dereference {
+            canonicalize(entry.path())?
+        } else {
+            entry.path().to_path_buf()
+        };
+        let meta = from.symlink_metadata()?;
+        let rel = entry.path().strip_prefix(source)?;
+        let target = if rel.as_os_str().is_empty() {
+            dest.to_path_buf()
+        } else {
+            dest.join(rel)
+        };
+
+        let item = compare_entry(&from, &meta, &target, &meta_config, &mut granularities)?;
+        let same_size = match item {
+            None => true,
+            Some(Item { change: Change::Changed { size, .. }, .. }) => !size,
+            Some(_) => false,
+        };
+        match config.compare_checksum {
+            Some(_) if meta.is_file() && same_size => pending.push((from, target, meta.len(), item)),
+            _ => items.extend(item),
+        }
+    }
+
+    if let Some(ctype) = config.compare_checksum {
+        items.extend(compare_contents(pending, ctype, config, updates)?);
+    }
+    if source.is_dir() && dest.is_dir() {
+        items.extend(extra_entries(source, dest, &gitignore, config.filter.as_deref())?);
+    }
+
+    items.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(items)
+}
+
+// Checksum the pending files in parallel, returning those whose
+// contents differ and any other differences found.
+fn compare_contents(
+    pending: Vec<(PathBuf, PathBuf, u64, Option<Item>)>,
+    ctype: ChecksumType,
+    config: &Config,
+    updates: &Arc<dyn StatusUpdater>,
+) -> Result<Vec<Item>> {
+    // Both sides are read.
+    let total = pending.iter().map(|(_, _, len, _)| len * 2).sum();
+    updates.send(StatusUpdate::Size(total))?;
+
+    let buffer_size = config.block_size.min(MAX_COMPARE_BUFFER) as usize;
+    let next = AtomicUsize::new(0);
+    let items = Mutex::new(Vec::new());
+    let workers = config.num_workers().min(pending.len()).max(1);
+
+    thread::scope(|s| -> Result<()> {
+        let handles = (0..workers).map(|_| s.spawn(|| -> Result<()> {
+            loop {
+                let id = next.fetch_add(1, Ordering::Relaxed);
+                let Some((from, target, _, item)) = pending.get(id) else {
+                    return Ok(());
+                };
+                updates.send(StatusUpdate::FileStarted(id as u64, from.clone()))?;
+                let read = AtomicU64::new(0);
+                let differ = contents_differ(from, target, ctype, buffer_size, &read, updates);
+                updates.send(StatusUpdate::FileCompleted(id as u64, read.into_inner()))?;
+                let item = match differ? {
+                    true => Some(Item { path: target.clone(), kind: EntryKind::File, change: Change::Content }),
+                    false => item.clone(),
+                };
+                items.lock().unwrap().extend(item);
+            }
+        })).collect::<Vec<_>>();
+
+        for handle in handles {
+            handle.join()
+                .map_err(|_| XcpError::CopyError("Error during comparison".to_string()))??;
+        }
+        Ok(())
+    })?;
+
+    Ok(items.into_inner().unwrap())
+}
+
+// Checksum a file in both trees, reading both at once. The bytes
+// read from both are added to `read`.
+fn contents_differ(
+    from: &Path,
+    to: &Path,
+    ctype: ChecksumType,
+    buffer_size: usize,
+    read: &AtomicU64,
+    updates: &Arc<dyn StatusUpdater>,
+) -> Result<bool> {
+    debug!("Comparing contents of {:?} and {:?}", from, to);
+    let checksum = |path: &Path| {
+        checksum_file_with(path, ctype, buffer_size, &mut |len| {
+            read.fetch_add(len, Ordering::Relaxed);
+            updates.send(StatusUpdate::Copied(len))
+        })
+    };
+    let (from_sum, to_sum) = thread::scope(|s| {
+        let to_sum = s.spawn(|| checksum(to));
+        (checksum(from), to_sum.join())
+    });
+    let to_sum = to_sum.map_err(|_| XcpError::CopyError("Error during comparison".to_string()))?;
+    Ok(from_sum? != to_sum?)
+}
+
+// Entries under `dest` with no counterpart under `source`. Entries
+// that would be ignored or excluded in the source are not reported.
+fn extra_entries(source: &Path, dest: &Path, gitignore: &Option<Gitignore>, filter: Option<&Filter>) -> Result<Vec<Item>> {
+    let mut items = Vec::new();
+    let mut it = WalkDir::new(dest).min_depth(1).into_iter();
+    while let Some(entry) = it.next() {
+        let entry = entry?;
+        let rel = entry.path().strip_prefix(dest)?;
+        let from = source.join(rel);
+        let meta = entry.path().symlink_metadata()?;
+        let kind = EntryKind::from_meta(&meta);
+        if kind == EntryKind::Dir && from.symlink_metadata().is_err() {
+            it.skip_current_dir();
+        }
+        let ignored = gitignore.as_ref()
+            .is_some_and(|gi| gi.matched(&from, kind == EntryKind::Dir).is_ignore())
+            || filter.is_some_and(|f| f.excludes(rel, kind == EntryKind::Dir));
+        if ignored || from.symlink_metadata().is_ok() {
+            continue;
+        }
+        items.push(Item { path: entry.path().to_path_buf(), kind, change: Change::Extra });
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    fn item(path: &str, kind: EntryKind, change: Change) -> Item {
+        Item { path: PathBuf::from(path), kind, change }
+    }
+
+    #[test]
+    fn test_item_display() {
+        let changed = |size, mtime, mode| Change::Changed { size, mtime, mode };
+        assert_eq!(">f+++ a/b", item("a/b", EntryKind::File, Change::New).to_string());
+        assert_eq!("cd+++ a", item("a", EntryKind::Dir, Change::New).to_string());
+        assert_eq!(">fs.. a", item("a", EntryKind::File, changed(true, false, false)).to_string());
+        assert_eq!(">f.tp a", item("a", EntryKind::File, changed(false, true, true)).to_string());
+        assert_eq!(".f..p a", item("a", EntryKind::File, changed(false, false, true)).to_string());
+        assert_eq!(".d..p a", item("a", EntryKind::Dir, changed(false, false, true)).to_string());
+        assert_eq!("*deleting a", item("a", EntryKind::File, Change::Deleted).to_string());
+        assert_eq!(">fc.. a", item("a", EntryKind::File, Change::Content).to_string());
+        assert_eq!("*extra a", item("a", EntryKind::Dir, Change::Extra).to_string());
+    }
+
+    #[test]
+    fn test_compare_entry() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let from = tdir.path().join("from.txt");
+        let to = tdir.path().join("to.txt");
+        fs::write(&from, "data")?;
+        let meta = from.symlink_metadata()?;
+        let config = Config::default();
+        let mut grans = Granularities::default();
+
+        let r = compare_entry(&from, &meta, &to, &config, &mut grans)?;
+        assert_eq!(Some(Change::New), r.map(|i| i.change));
+
+        fs::copy(&from, &to)?;
+        File::options().write(true).open(&to)?.set_modified(meta.modified()?)?;
+        assert_eq!(None, compare_entry(&from, &meta, &to, &config, &mut grans)?);
+
+        fs::write(&to, "longer data")?;
+        fs::set_permissions(&to, fs::Permissions::from_mode(0o600))?;
+        fs::set_permissions(&from, fs::Permissions::from_mode(0o644))?;
+        let meta = from.symlink_metadata()?;
+        let r = compare_entry(&from, &meta, &to, &config, &mut grans)?;
+        assert_eq!(Some(Change::Changed { size: true, mtime: true, mode: true }), r.map(|i| i.change));
+
+        let config = Config { preserve: PreserveSet::NONE, ..Config::default() };
+        let r = compare_entry(&from, &meta, &to, &config, &mut grans)?;
+        assert_eq!(Some(Change::Changed { size: true, mtime: false, mode: false }), r.map(|i| i.change));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare_trees() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let (src, dest) = (tdir.path().join("src"), tdir.path().join("dest"));
+        for dir in [&src, &dest] {
+            fs::create_dir_all(dir.join("sub"))?;
+            fs::write(dir.join("same.txt"), "same")?;
+            fs::write(dir.join("sub/content.txt"), "abcd")?;
+        }
+        fs::write(src.join("size.txt"), "short")?;
+        fs::write(dest.join("size.txt"), "longer")?;
+        fs::write(src.join("missing.txt"), "missing")?;
+        fs::create_dir_all(dest.join("extra/sub"))?;
+        fs::write(dest.join("extra/sub/file.txt"), "extra")?;
+        fs::write(dest.join("sub/content.txt"), "abce")?;
+        for name in ["same.txt", "size.txt", "sub/content.txt"] {
+            let mtime = src.join(name).metadata()?.modified()?;
+            File::options().write(true).open(dest.join(name))?.set_modified(mtime)?;
+        }
+        let updates: Arc<dyn StatusUpdater> = Arc::new(crate::feedback::NoopUpdater);
+
+        let changes = |config: &Config| -> Result<Vec<(String, Change)>> {
+            Ok(compare_trees(&src, &dest, config, &updates)?.into_iter()
+               .map(|i| (i.path.strip_prefix(&dest).unwrap().to_string_lossy().to_string(), i.change))
+               .collect())
+        };
+        let changed = |size, mtime, mode| Change::Changed { size, mtime, mode };
+
+        // Same size and mtime, so only the checksum finds the content
+        // difference.
+        let config = Config { preserve: PreserveSet::DEFAULT.difference(PreserveSet::MODE), ..Config::default() };
+        assert_eq!(vec![
+            ("extra".to_string(), Change::Extra),
+            ("missing.txt".to_string(), Change::New),
+            ("size.txt".to_string(), changed(true, false, false)),
+        ], changes(&config)?);
+
+        let config = Config { preserve: PreserveSet::DEFAULT.difference(PreserveSet::MODE), compare_checksum: Some(ChecksumType::Blake3), ..Config::default() };
+        assert_eq!(vec![
+            ("extra".to_string(), Change::Extra),
+            ("missing.txt".to_string(), Change::New),
+            ("size.txt".to_string(), changed(true, false, false)),
+            ("sub/content.txt".to_string(), Change::Content),
+        ], changes(&config)?);
+
+        Ok(())
+    }
+}
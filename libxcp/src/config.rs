@@ -16,10 +16,22 @@
 
 //! Driver configuration support.
 
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
 use std::result;
 use std::str::FromStr;
 
-use crate::errors::XcpError;
+use libfs::{preferred_io_size, FsOps, RealFs, MAX_IO_SIZE};
+
+use crate::checksum::ChecksumType;
+use crate::conflict::ConflictTemplate;
+use crate::errors::{unexpected_value, XcpError};
+use crate::executor::Executor;
+use crate::filter::Filter;
+use crate::names::NameProfile;
 
 /// Enum defining configuration options for handling
 /// [reflinks](https://btrfs.readthedocs.io/en/latest/Reflink.html). [FromStr]
@@ -48,7 +60,7 @@ impl FromStr for Reflink {
             "always" => Ok(Reflink::Always),
             "auto" => Ok(Reflink::Auto),
             "never" => Ok(Reflink::Never),
-            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'reflink': {}", s))),
+            _ => Err(unexpected_value("reflink", s, &["auto", "always", "never"])),
         }
     }
 }
@@ -63,6 +75,9 @@ pub enum Backup {
     Auto,
     /// Create numbered backups. Numbered backups follow the semantics
     /// of `cp` numbered backups (e.g. `file.txt.~123~`).
+    ///
+    /// `cp`'s other controls parse to this, as simple backups
+    /// (`file.txt~`) are not supported.
     Numbered,
 }
 
@@ -73,12 +88,390 @@ impl FromStr for Backup {
         match s.to_lowercase().as_str() {
             "none" | "off" => Ok(Backup::None),
             "auto" => Ok(Backup::Auto),
-            "numbered" => Ok(Backup::Numbered),
-            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'backup': {}", s))),
+            "numbered" | "t" | "existing" | "nil" | "simple" | "never" => Ok(Backup::Numbered),
+            _ => Err(unexpected_value("backup", s, &["none", "off", "auto", "numbered", "existing", "simple"])),
+        }
+    }
+}
+
+/// Enum defining how existing destination files are handled when
+/// [Config::no_clobber] is set. [FromStr] is supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NoClobber {
+    /// Leave existing destination files untouched and copy the rest;
+    /// a [StatusUpdate::Skipped] is sent for each file skipped.
+    /// Existing directories are merged into.
+    ///
+    /// [StatusUpdate::Skipped]: crate::feedback::StatusUpdate::Skipped
+    #[default]
+    Skip,
+    /// Abort the copy if any destination entry exists.
+    Fail,
+    /// Keep both: copy files, symlinks and special files that would
+    /// overwrite an existing entry under a new name, generated from
+    /// [Config::conflict_suffix]; see [crate::conflict]. A
+    /// [StatusUpdate::KeptBoth] is sent for each. Existing
+    /// directories are merged into.
+    ///
+    /// [StatusUpdate::KeptBoth]: crate::feedback::StatusUpdate::KeptBoth
+    Rename,
+}
+
+impl FromStr for NoClobber {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "skip" => Ok(NoClobber::Skip),
+            "fail" => Ok(NoClobber::Fail),
+            "rename" => Ok(NoClobber::Rename),
+            _ => Err(unexpected_value("no-clobber", s, &["skip", "fail", "rename"])),
+        }
+    }
+}
+
+/// Enum defining how an existing destination file is found to have
+/// the same contents as its source, so that it isn't copied again;
+/// see [Config::skip_same]. [FromStr] is supported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SkipSame {
+    /// Files of the same size are read in full and compared by
+    /// checksum.
+    Checksum,
+    /// As [SkipSame::Checksum], but a current [Stamp] on the
+    /// destination is trusted rather than reading either file: the
+    /// file is the same if the source still has the stamped
+    /// modification time, and otherwise copied. See [crate::stamp].
+    ///
+    /// [Stamp]: crate::stamp::Stamp
+    Stamp,
+}
+
+impl FromStr for SkipSame {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "checksum" => Ok(SkipSame::Checksum),
+            "stamp" => Ok(SkipSame::Stamp),
+            _ => Err(unexpected_value("skip-same", s, &["checksum", "stamp"])),
+        }
+    }
+}
+
+/// Enum defining how source metadata is applied to destination
+/// directories. [FromStr] is supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DirMode {
+    /// Directories that already exist at the destination keep their
+    /// mode, ownership and xattrs; only newly created directories
+    /// receive the source metadata.
+    #[default]
+    PreserveExisting,
+    /// Apply the source metadata to all destination directories.
+    Overwrite,
+}
+
+impl FromStr for DirMode {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "preserve-existing" => Ok(DirMode::PreserveExisting),
+            "overwrite" => Ok(DirMode::Overwrite),
+            _ => Err(unexpected_value("dir-mode", s, &["preserve-existing", "overwrite"])),
+        }
+    }
+}
+
+/// Enum defining how source names that the destination filesystem
+/// cannot represent are handled; see [crate::names]. [FromStr] is
+/// supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InvalidName {
+    /// Report an error for the entry. The copy stops unless
+    /// [Config::continue_on_error] is set.
+    #[default]
+    Error,
+    /// Skip the entry, and anything under it. A
+    /// [StatusUpdate::NameSkipped] is sent for each skipped entry.
+    ///
+    /// [StatusUpdate::NameSkipped]: crate::feedback::StatusUpdate::NameSkipped
+    Skip,
+    /// Replace invalid characters with `_`, adding a numeric suffix
+    /// if the result collides with another name. A
+    /// [StatusUpdate::Renamed] is sent for each renamed entry.
+    ///
+    /// [StatusUpdate::Renamed]: crate::feedback::StatusUpdate::Renamed
+    Sanitize,
+}
+
+impl FromStr for InvalidName {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(InvalidName::Error),
+            "skip" => Ok(InvalidName::Skip),
+            "sanitize" => Ok(InvalidName::Sanitize),
+            _ => Err(unexpected_value("invalid-name", s, &["error", "skip", "sanitize"])),
         }
     }
 }
 
+/// Enum defining the order in which files are dispatched to the
+/// copy workers. [FromStr] is supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Order {
+    /// The order the source tree is scanned in.
+    #[default]
+    Scan,
+    /// Largest files first, so that they overlap with the copying of
+    /// smaller files rather than finishing alone at the end. Files
+    /// are held until the scan completes, except for very large ones
+    /// which are dispatched as soon as they are found.
+    LargestFirst,
+    /// Smallest files first. Files are held until the scan completes.
+    SmallestFirst,
+}
+
+impl FromStr for Order {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "scan" => Ok(Order::Scan),
+            "largest-first" => Ok(Order::LargestFirst),
+            "smallest-first" => Ok(Order::SmallestFirst),
+            _ => Err(unexpected_value("order", s, &["scan", "largest-first", "smallest-first"])),
+        }
+    }
+}
+
+/// The entries a [ModeClause] applies to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModeTarget {
+    All,
+    Dirs,
+    Files,
+}
+
+/// The operator of a symbolic mode change.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModeOp {
+    Add,
+    Remove,
+    Set,
+}
+
+/// A single mode change.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModeChange {
+    /// An absolute mode, e.g. `644`.
+    Absolute(u32),
+    /// A symbolic change, e.g. `u+rwX`. `who` and `perms` are masks
+    /// of the affected mode bits. If `exec_if_any` is set the execute
+    /// bits are also added for directories, or files that are already
+    /// executable by someone (the `X` permission).
+    Symbolic {
+        who: u32,
+        op: ModeOp,
+        perms: u32,
+        exec_if_any: bool,
+    },
+}
+
+/// A mode change and the entries it applies to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ModeClause {
+    pub target: ModeTarget,
+    pub change: ModeChange,
+}
+
+/// A list of mode changes applied in order, as given to `--chmod`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chmod(pub Vec<ModeClause>);
+
+impl Chmod {
+    /// Apply the changes to a mode.
+    pub fn apply(&self, mut mode: u32, is_dir: bool) -> u32 {
+        for clause in &self.0 {
+            match clause.target {
+                ModeTarget::Dirs if !is_dir => continue,
+                ModeTarget::Files if is_dir => continue,
+                _ => {}
+            }
+            mode = match clause.change {
+                ModeChange::Absolute(m) => (mode & !0o7777) | m,
+                ModeChange::Symbolic { who, op, perms, exec_if_any } => {
+                    let mut bits = perms & who;
+                    if exec_if_any && (is_dir || mode & 0o111 != 0) {
+                        bits |= 0o111 & who;
+                    }
+                    match op {
+                        ModeOp::Add => mode | bits,
+                        ModeOp::Remove => mode & !bits,
+                        ModeOp::Set => (mode & !who) | bits,
+                    }
+                }
+            };
+        }
+        mode
+    }
+}
+
+/// Ownership applied to copied files, as given to `--chown`. `None`
+/// leaves the id unchanged.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Chown {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+/// The file attributes copied from the source, as given to
+/// `--preserve` and `--no-preserve`. [FromStr] parses a
+/// comma-separated list of attribute names, and [fmt::Display] writes
+/// one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PreserveSet(u8);
+
+impl PreserveSet {
+    /// The file mode, including the setuid and setgid bits when the
+    /// ownership matches; see [Config::preserve_mode].
+    pub const MODE: PreserveSet = PreserveSet(1);
+    /// The user and group. This requires root permissions or
+    /// appropriate capabilities; if the attempt fails a warning is
+    /// issued but the operation continues.
+    pub const OWNERSHIP: PreserveSet = PreserveSet(1 << 1);
+    /// The access and modification times.
+    pub const TIMESTAMPS: PreserveSet = PreserveSet(1 << 2);
+    /// Hard-links within the copied files. Files with multiple links
+    /// are copied once and the other links recreated once the copy is
+    /// complete; they do not count towards the size of the copy.
+    pub const LINKS: PreserveSet = PreserveSet(1 << 3);
+    /// The SELinux security context.
+    pub const CONTEXT: PreserveSet = PreserveSet(1 << 4);
+    /// Extended attributes, other than the security context.
+    pub const XATTR: PreserveSet = PreserveSet(1 << 5);
+    /// The inode flags shown by `lsattr(1)`, such as immutable and
+    /// append-only. These are set after everything else, including
+    /// any checksum; an existing destination with either flag is only
+    /// rewritten if they are preserved, when they are cleared first.
+    /// Setting them requires root permissions or
+    /// `CAP_LINUX_IMMUTABLE`; if the attempt fails a warning is issued
+    /// but the operation continues.
+    pub const FLAGS: PreserveSet = PreserveSet(1 << 6);
+
+    /// No attributes.
+    pub const NONE: PreserveSet = PreserveSet(0);
+    /// All attributes.
+    pub const ALL: PreserveSet = PreserveSet((1 << 7) - 1);
+    /// The attributes copied by default.
+    pub const DEFAULT: PreserveSet = Self::MODE.union(Self::TIMESTAMPS).union(Self::CONTEXT).union(Self::XATTR);
+    /// The attributes added by `--preserve` or `-p` without a list, as
+    /// with `cp`.
+    pub const BASIC: PreserveSet = Self::MODE.union(Self::OWNERSHIP).union(Self::TIMESTAMPS);
+
+    // Attribute names, in display order.
+    const NAMES: [(&'static str, PreserveSet); 7] = [
+        ("mode", Self::MODE),
+        ("ownership", Self::OWNERSHIP),
+        ("timestamps", Self::TIMESTAMPS),
+        ("links", Self::LINKS),
+        ("context", Self::CONTEXT),
+        ("xattr", Self::XATTR),
+        ("flags", Self::FLAGS),
+    ];
+
+    /// The attributes in either set.
+    pub const fn union(self, other: PreserveSet) -> PreserveSet {
+        PreserveSet(self.0 | other.0)
+    }
+
+    /// The attributes in this set but not `other`.
+    pub const fn difference(self, other: PreserveSet) -> PreserveSet {
+        PreserveSet(self.0 & !other.0)
+    }
+
+    /// Whether all the attributes in `other` are in this set.
+    pub const fn contains(self, other: PreserveSet) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Whether any attribute is in both sets.
+    pub const fn intersects(self, other: PreserveSet) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    /// Whether the set has no attributes.
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl Default for PreserveSet {
+    fn default() -> Self {
+        PreserveSet::DEFAULT
+    }
+}
+
+impl FromStr for PreserveSet {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        s.split(',').try_fold(PreserveSet::NONE, |set, name| {
+            let name = name.to_lowercase();
+            let attr = match name.as_str() {
+                "all" => PreserveSet::ALL,
+                _ => PreserveSet::NAMES.iter()
+                    .find(|(n, _)| *n == name)
+                    .map(|(_, attr)| *attr)
+                    .ok_or_else(|| unexpected_value("preserve", &name,
+                        &["mode", "ownership", "timestamps", "links", "context", "xattr", "flags", "all"]))?,
+            };
+            Ok(set.union(attr))
+        })
+    }
+}
+
+impl fmt::Display for PreserveSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = PreserveSet::NAMES.iter()
+            .filter(|(_, attr)| self.contains(*attr))
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+/// The default for [Config::max_buffer_memory].
+pub const DEFAULT_MAX_BUFFER_MEMORY: u64 = 256 * 1024 * 1024;
+
+// Buffers are not made smaller than this unless the cap is; fewer
+// workers are used instead.
+const MIN_BUFFER_SIZE: u64 = 64 * 1024;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// How the copy buffers are sized to keep them within
+/// [Config::max_buffer_memory]; see [Config::buffer_plan].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BufferPlan {
+    /// The largest block read or written at once, and so the largest
+    /// buffer each worker holds. At most [Config::block_size].
+    pub block_size: u64,
+    /// The number of workers copying at once. At most the configured
+    /// number, and only fewer if the cap is below 64KiB per worker.
+    pub workers: usize,
+}
+
+impl BufferPlan {
+    /// The most memory the buffers can use at once.
+    pub fn total(&self) -> u64 {
+        self.block_size.saturating_mul(self.workers as u64)
+    }
+}
+
 /// A structure defining the runtime options for copy-drivers. This
 /// would normally be passed to `load_driver()`.
 #[derive(Clone, Debug)]
@@ -87,10 +480,39 @@ pub struct Config {
     /// CPUs (the default).
     pub workers: usize,
 
+    /// The maximum number of files read at once from each source
+    /// device. If unset this is 2 for rotational disks, where
+    /// parallel reads cause seeking between files, and otherwise
+    /// unlimited. Writes are not limited. Default is `None`.
+    pub readers_per_device: Option<usize>,
+
+    /// Apply the metadata of copied files (permissions, timestamps,
+    /// xattrs, and any fsync) on a separate pool of this many
+    /// workers, so the copy workers carry on copying data meanwhile.
+    /// This helps where metadata operations are slow, such as on
+    /// network filesystems. Files are still only reported complete
+    /// once their metadata is applied. If unset each file's metadata
+    /// is applied by the worker that copied it. Default is `None`.
+    pub metadata_workers: Option<usize>,
+
     /// Block size for operations. Defaults to the full file size. Use
     /// a smaller value for finer-grained feedback.
     pub block_size: u64,
 
+    /// The most memory used by copy buffers across all workers.
+    /// Copies through userspace hold a buffer of up to a block per
+    /// worker, so the block size, and failing that the number of
+    /// workers, is reduced to fit; see [Config::buffer_plan]. Default
+    /// is `None`, which limits the buffers to
+    /// [DEFAULT_MAX_BUFFER_MEMORY].
+    pub max_buffer_memory: Option<u64>,
+
+    /// Choose the block size of each file from the preferred IO size
+    /// (`st_blksize`) of its source and destination, with
+    /// [Config::block_size] as the minimum; see
+    /// [Config::io_block_size]. Default is `false`.
+    pub auto_block_size: bool,
+
     /// Use .gitignore if present.
     ///
     /// NOTE: This is fairly basic at the moment, and only honours a
@@ -99,26 +521,108 @@ pub struct Config {
     /// `false`.
     pub gitignore: bool,
 
-    /// Do not overwrite existing files. Default is `false`.
-    pub no_clobber: bool,
+    /// Exclude and include patterns, matched against the path of each
+    /// entry relative to its source; see [Filter]. Excluded entries
+    /// are neither copied nor, with [Config::delete], deleted from
+    /// the destination. Default is `None`.
+    pub filter: Option<Arc<Filter>>,
 
-    /// Do not copy the file permissions. Default is `false`.
-    pub no_perms: bool,
+    /// Do not overwrite existing files, and how to handle them if
+    /// found; see [NoClobber]. Default is `None`.
+    pub no_clobber: Option<NoClobber>,
 
-    /// Do not copy the file permissions. Default is `false`.
-    pub no_timestamps: bool,
+    /// The name given to a copy kept alongside an existing
+    /// destination with [NoClobber::Rename]. Default is
+    /// `{name} ({n}).{ext}`.
+    pub conflict_suffix: ConflictTemplate,
 
-    /// Copy ownership.
+    /// Only copy files that are newer than the destination, or where
+    /// the destination is missing. Modification times are compared
+    /// to the resolution of the destination filesystem. Default is
+    /// `false`.
+    pub update: bool,
+
+    /// Refuse to overwrite destination files that are newer than
+    /// their source, reporting an error for each instead. Otherwise
+    /// overwriting them is a warning, and a
+    /// [StatusUpdate::OverwritingNewer] is sent. Not checked with
+    /// `update`, which skips these files. Default is `false`.
     ///
-    /// Whether to copy ownship (user/group).  This option requires
-    /// root permissions or appropriate capabilities; if the attempt
-    /// to copy ownership fails a warning is issued but the operation
-    /// continues.
-    pub ownership: bool,
+    /// [StatusUpdate::OverwritingNewer]: crate::feedback::StatusUpdate::OverwritingNewer
+    pub forbid_overwrite_newer: bool,
+
+    /// The attributes copied from the source files and directories;
+    /// see [PreserveSet]. Default is [PreserveSet::DEFAULT].
+    pub preserve: PreserveSet,
+
+    /// Do not copy directory timestamps, only those of files. Each
+    /// directory's timestamps are otherwise set once all of its
+    /// entries are complete. Implied by not preserving
+    /// [PreserveSet::TIMESTAMPS]. Default is `false`.
+    pub no_dir_timestamps: bool,
+
+    /// Recreate btrfs subvolumes found in the source as subvolumes in
+    /// the destination, rather than plain directories. Existing
+    /// destination directories are copied into unchanged. On other
+    /// destination filesystems a plain directory is created with a
+    /// warning, and reported as an [Action::MetadataDegraded]. Only
+    /// supported with the `btrfs` feature. Default is `false`.
+    ///
+    /// [Action::MetadataDegraded]: crate::feedback::Action::MetadataDegraded
+    pub preserve_subvolumes: bool,
+
+    /// Write through a destination symlink whose target doesn't
+    /// exist, creating the target. By default this is an error, as the
+    /// file would be created wherever the link points. Default is
+    /// `false`.
+    pub follow_dest_symlinks: bool,
+
+    /// With `follow_dest_symlinks`, also create any missing parent
+    /// directories of a dangling symlink's target. Default is `false`.
+    pub mkdir_parents: bool,
+
+    /// Remove a destination symlink and replace it with a regular
+    /// file, rather than writing through it. Default is `false`.
+    pub remove_destination: bool,
+
+    /// Always copy the setuid and setgid bits. Otherwise they are
+    /// cleared when the destination does not have the same owner or
+    /// group as the source. The CLI sets this when the mode is
+    /// explicitly preserved, as with `cp --preserve=mode`. Default is
+    /// `false`.
+    pub preserve_mode: bool,
+
+    /// Skip xattrs with values larger than this many bytes, with a
+    /// warning, rather than reading them into memory. `None` copies
+    /// xattrs of any size. Default is 64MiB.
+    pub xattr_value_limit: Option<u64>,
+
+    /// If set, this is set once the destination rejects xattrs as
+    /// unsupported, and no more are tried, rather than failing (and
+    /// warning) for every file; see [crate::profile]. Default is
+    /// `None`, trying for every file.
+    pub xattrs_unsupported: Option<Arc<AtomicBool>>,
+
+    /// When not preserving hard-links, copy further links to a source
+    /// file from its first destination copy rather than re-reading
+    /// the source. These copies are made by the workers once all other
+    /// files are complete, and only if the first copy completed and
+    /// the source is unchanged. Default is `false`.
+    pub cache_linked_sources: bool,
 
     /// Dereference symlinks. Default is `false`.
     pub dereference: bool,
 
+    /// Follow symlinks given as sources, while copying those found
+    /// beneath them as symlinks; see `cp -H`. Implied by
+    /// `dereference`. Default is `false`.
+    pub dereference_sources: bool,
+
+    /// Don't descend into directories on other filesystems than their
+    /// source, as `cp -x`; the directories themselves are copied.
+    /// Default is `false`.
+    pub one_file_system: bool,
+
     /// Target should not be a directory.
     ///
     /// Analogous to cp's no-target-directory. Expected behavior is that when
@@ -126,9 +630,65 @@ pub struct Config {
     /// in target, overwrite target. Default is 'false`.
     pub no_target_directory: bool,
 
+    /// Copy each source into a subdirectory of the destination named
+    /// after it, creating the destination if necessary. Sources with
+    /// the same basename are named with their parent directories;
+    /// see [crate::paths::dest_names]. Default is `false`.
+    pub dest_subdir_from_source: bool,
+
+    /// Do not preallocate destination files, and write them
+    /// sequentially. Preallocation is skipped automatically on
+    /// filesystems that don't support it; this forces it for targets
+    /// that misbehave instead. Default is `false`.
+    pub no_fallocate: bool,
+
+    /// With the parblock driver, write blocks out of order even to
+    /// destinations that handle it poorly: compressed files on btrfs,
+    /// which lose compression, and FUSE filesystems, some of which
+    /// reject concurrent writers. Otherwise blocks are still read in
+    /// parallel, but written in order. Default is `false`.
+    pub force_parblock: bool,
+
+    /// Create small files a directory at a time: a worker creates a
+    /// batch of files in one destination directory, and then fills
+    /// them. On some filesystems this reduces contention for the
+    /// directory lock when copying many small files. Default is
+    /// `false`.
+    pub batch_dirents: bool,
+
+    /// Read files of up to 512 bytes while walking the source, and
+    /// have the workers write each in a single call without
+    /// preallocating it; empty files have no data to copy. This skips
+    /// most of the per-file cost of copying many tiny files, e.g. a
+    /// `node_modules` tree. Their metadata is copied as for any
+    /// other file. Default is `true`.
+    pub tiny_file_fastpath: bool,
+
+    /// When imaging a block device, write blocks of zeros as holes
+    /// rather than preallocating the destination, creating a sparse
+    /// image. Default is `false`.
+    pub sparse: bool,
+
+    /// Do not read block devices with `O_DIRECT`. Reading with
+    /// `O_DIRECT` avoids filling the page cache with the image.
+    /// Default is `false`.
+    pub no_direct_io: bool,
+
+    /// The order files are dispatched to the workers in. Default is
+    /// [Order::Scan].
+    pub order: Order,
+
     /// Sync each file to disk after writing. Default is `false`.
     pub fsync: bool,
 
+    /// Write each file, including its metadata, under this directory
+    /// and move it into place once complete and synced to disk, so a
+    /// destination file is never seen partly written. The file is
+    /// renamed if it is on the same filesystem, otherwise copied
+    /// again. Leftovers from runs that crashed are removed. Default is
+    /// `None`.
+    pub staging_dir: Option<PathBuf>,
+
     /// Reflink options.
     ///
     /// Whether and how to use reflinks. 'auto' (the default) will
@@ -144,6 +704,204 @@ pub struct Config {
     /// semantics of `cp` numbered backups
     /// (e.g. `file.txt.~123~`). Default is `None`.
     pub backup: Backup,
+
+    /// Directory metadata options.
+    ///
+    /// Whether source permissions, ownership and xattrs are applied
+    /// to destination directories that already exist. Directories
+    /// created by the copy always receive them. Default is
+    /// [DirMode::PreserveExisting].
+    pub dir_mode: DirMode,
+
+    /// The mode of directories created without a source counterpart,
+    /// such as missing parents of the destination. As with
+    /// mkdir(2) it is filtered by the process umask; directories
+    /// copied from the source follow [Config::dir_mode] instead.
+    /// Default is `0o777`.
+    pub new_dir_mode: u32,
+
+    /// Only create destination directories that receive at least one
+    /// entry once filters are applied, so directories whose contents
+    /// are all excluded or skipped aren't left empty. Each is created
+    /// when the first entry to be copied into it is found. The root of
+    /// the copy is always created. Default is `false`.
+    pub prune_empty_dirs: bool,
+
+    /// Continue on errors.
+    ///
+    /// Errors copying individual files, or reading source
+    /// directories, are reported via [StatusUpdate::Error] and the
+    /// copy continues with the remaining files. Default is `false`.
+    ///
+    /// [StatusUpdate::Error]: crate::feedback::StatusUpdate::Error
+    pub continue_on_error: bool,
+
+    /// Stop a copy with [Config::continue_on_error] once this many
+    /// errors have been reported.
+    ///
+    /// Work stops as with any other abort, and the copy returns
+    /// [XcpError::TooManyErrors]. Default is `None`, for no limit.
+    ///
+    /// [XcpError::TooManyErrors]: crate::errors::XcpError::TooManyErrors
+    pub max_errors: Option<u64>,
+
+    /// Stop a copy with [Config::continue_on_error] once more than
+    /// this many errors have been reported among the last 1000 files.
+    ///
+    /// Unlike [Config::max_errors] this catches a copy in which
+    /// everything has started to fail, e.g. because the destination
+    /// has gone away, however long it has run. Each completed file
+    /// and each error counts towards the 1000. The copy returns
+    /// [XcpError::ErrorRateExceeded]. Default is `None`.
+    ///
+    /// [XcpError::ErrorRateExceeded]: crate::errors::XcpError::ErrorRateExceeded
+    pub max_error_rate: Option<u64>,
+
+    /// Stop the copy once it has run for this long.
+    ///
+    /// Work stops as with any other abort; files being copied are
+    /// removed and the copy returns [XcpError::TimedOut]. Progress is
+    /// checked between blocks, so the copy can overrun by about one
+    /// block of IO. Default is `None`.
+    ///
+    /// [XcpError::TimedOut]: crate::errors::XcpError::TimedOut
+    pub timeout: Option<Duration>,
+
+    /// Abandon any single file that takes longer than this to copy,
+    /// measured from when it is opened.
+    ///
+    /// The partial file is removed and
+    /// [XcpError::FileTimedOut] reported for it; the copy continues
+    /// if [Config::continue_on_error] is set. As with
+    /// [Config::timeout] this is checked between blocks, so a read or
+    /// write that never returns, e.g. on a hung network filesystem,
+    /// can't be interrupted. Default is `None`.
+    ///
+    /// [XcpError::FileTimedOut]: crate::errors::XcpError::FileTimedOut
+    pub file_timeout: Option<Duration>,
+
+    /// Continue on a full destination.
+    ///
+    /// By default running out of space on the destination stops the
+    /// copy, even if [Config::continue_on_error] is set. If this is
+    /// also set the copy continues with the remaining files. Partial
+    /// files are removed in either case. Default is `false`.
+    pub really_continue_on_enospc: bool,
+
+    /// Retry a source read that fails with `EIO` this many times.
+    ///
+    /// A block that can't be read is retried, and then split in
+    /// halves down to the sector size so that as much as possible is
+    /// recovered from a failing device. An unreadable sector fails
+    /// the file with [XcpError::ReadFailed], unless
+    /// [Config::fill_errors] is set. Default is `0`.
+    ///
+    /// [XcpError::ReadFailed]: crate::errors::XcpError::ReadFailed
+    pub read_retries: u32,
+
+    /// Write zeros for unreadable parts of a source file, rather than
+    /// failing it; see [Config::read_retries].
+    ///
+    /// The unreadable ranges are sent in a [StatusUpdate::BadRanges],
+    /// and recorded in the destination's
+    /// [BAD_RANGES_XATTR](crate::rescue::BAD_RANGES_XATTR) xattr where
+    /// supported. Default is `false`.
+    ///
+    /// [StatusUpdate::BadRanges]: crate::feedback::StatusUpdate::BadRanges
+    pub fill_errors: bool,
+
+    /// Checksum each file after it has been copied.
+    ///
+    /// The data is hashed as it is copied where possible, otherwise
+    /// (e.g. after a reflink) the destination file is read back once
+    /// it is complete. The result is sent as a
+    /// [StatusUpdate::Checksum]. Default is `None`.
+    ///
+    /// [StatusUpdate::Checksum]: crate::feedback::StatusUpdate::Checksum
+    pub checksum: Option<ChecksumType>,
+
+    /// Record the BLAKE3 digest of each copied file, and the
+    /// modification time of its source, in xattrs of the copy; see
+    /// [crate::stamp]. The digest is computed as for
+    /// [Config::checksum]. Destinations without xattr support are
+    /// copied without stamps. Default is `false`.
+    pub stamp_checksum: bool,
+
+    /// Skip files whose destination has the same size and contents
+    /// as the source; see [SkipSame]. As with [Config::update] an
+    /// [Action::UpToDate] is reported for each. Default is `None`.
+    ///
+    /// [Action::UpToDate]: crate::feedback::Action::UpToDate
+    pub skip_same: Option<SkipSame>,
+
+    /// Walk the sources but do not modify the destination. Combine
+    /// with [Config::itemize] to see what would be changed. Default
+    /// is `false`.
+    pub dry_run: bool,
+
+    /// Compare each source entry with the destination and report the
+    /// differences as [StatusUpdate::Item]. See [crate::compare] for
+    /// the criteria used. Default is `false`.
+    ///
+    /// [StatusUpdate::Item]: crate::feedback::StatusUpdate::Item
+    pub itemize: bool,
+
+    /// Remove destination entries that do not exist in the source
+    /// directories. Default is `false`.
+    pub delete: bool,
+
+    /// Report each change made to the destination as a
+    /// [StatusUpdate::Action], e.g. for an audit log. Default is
+    /// `false`.
+    ///
+    /// [StatusUpdate::Action]: crate::feedback::StatusUpdate::Action
+    pub report_actions: bool,
+
+    /// Keep a [FileResult] for each file copied in the [CopyStats]
+    /// returned by the driver. This is off by default, as for large
+    /// copies it holds an entry per file until the copy is complete;
+    /// the totals are always returned.
+    ///
+    /// [FileResult]: crate::results::FileResult
+    /// [CopyStats]: crate::results::CopyStats
+    pub collect_results: bool,
+
+    /// Compare file contents with this checksum algorithm in
+    /// [compare_trees], rather than by size and modification time.
+    /// Default is `None`.
+    ///
+    /// [compare_trees]: crate::compare::compare_trees
+    pub compare_checksum: Option<ChecksumType>,
+
+    /// Mode changes applied to copied files and created directories,
+    /// after any source permissions. Default is `None`.
+    pub chmod: Option<Chmod>,
+
+    /// Ownership applied to copied files and created directories,
+    /// overriding [PreserveSet::OWNERSHIP]. As with that attribute a
+    /// failure to change ownership is a warning. Default is `None`.
+    pub chown: Option<Chown>,
+
+    /// How to handle source names that the destination filesystem
+    /// cannot represent, or that collide on a case-insensitive
+    /// destination. Default is [InvalidName::Error].
+    pub invalid_name: InvalidName,
+
+    /// The filename restrictions of the destination. If `None` (the
+    /// default) they are determined from the destination filesystem;
+    /// see [NameProfile::probe].
+    pub name_profile: Option<NameProfile>,
+
+    /// The filesystem operations used to copy each file and create
+    /// directories; see [FsOps]. This can be replaced with e.g. a
+    /// [FaultInjectingFs](libfs::FaultInjectingFs) to test error
+    /// handling. Default is [RealFs].
+    pub fs: Arc<dyn FsOps>,
+
+    /// How the drivers run the walker, the copy workers and the
+    /// blocks of files; see [Executor]. Default is
+    /// [Executor::Threads].
+    pub executor: Executor,
 }
 
 impl Config {
@@ -154,23 +912,246 @@ impl Config {
             self.workers
         }
     }
+
+    /// The checksum computed as each file is copied, if any: that of
+    /// [Config::checksum], or BLAKE3 for [Config::stamp_checksum].
+    pub(crate) fn hash_type(&self) -> Option<ChecksumType> {
+        self.checksum.or(self.stamp_checksum.then_some(ChecksumType::Blake3))
+    }
+
+    /// The block size and number of workers to use, keeping the
+    /// buffers within [Config::max_buffer_memory].
+    pub fn buffer_plan(&self) -> BufferPlan {
+        self.plan_blocks(self.block_size)
+    }
+
+    /// The block size to copy between files with the given preferred
+    /// IO sizes. Unless [Config::auto_block_size] is set this is the
+    /// [Config::buffer_plan] block size.
+    pub fn io_block_size(&self, src_blksize: u64, dest_blksize: u64) -> u64 {
+        if !self.auto_block_size {
+            return self.buffer_plan().block_size;
+        }
+        let limit = self.plan_blocks(MAX_IO_SIZE).block_size;
+        preferred_io_size(src_blksize, dest_blksize, self.block_size, limit)
+    }
+
+    fn plan_blocks(&self, block_size: u64) -> BufferPlan {
+        let cap = self.max_buffer_memory.unwrap_or(DEFAULT_MAX_BUFFER_MEMORY).max(1);
+        let workers = self.num_workers().min((cap / MIN_BUFFER_SIZE).max(1) as usize);
+        let share = cap / workers as u64;
+        let share = if share >= PAGE_SIZE {
+            share - share % PAGE_SIZE
+        } else {
+            share
+        };
+        BufferPlan {
+            block_size: block_size.min(share).max(1),
+            workers,
+        }
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             workers: num_cpus::get(),
+            readers_per_device: None,
+            metadata_workers: None,
             block_size: u64::MAX,
+            max_buffer_memory: None,
+            auto_block_size: false,
             gitignore: false,
-            no_clobber: false,
-            no_perms: false,
-            no_timestamps: false,
-            ownership: false,
+            filter: None,
+            no_clobber: None,
+            conflict_suffix: ConflictTemplate::default(),
+            update: false,
+            forbid_overwrite_newer: false,
+            preserve: PreserveSet::DEFAULT,
+            no_dir_timestamps: false,
+            preserve_subvolumes: false,
+            follow_dest_symlinks: false,
+            mkdir_parents: false,
+            remove_destination: false,
+            preserve_mode: false,
+            xattr_value_limit: Some(64 * 1024 * 1024),
+            xattrs_unsupported: None,
+            cache_linked_sources: false,
             dereference: false,
+            dereference_sources: false,
+            one_file_system: false,
             no_target_directory: false,
+            dest_subdir_from_source: false,
+            no_fallocate: false,
+            force_parblock: false,
+            batch_dirents: false,
+            tiny_file_fastpath: true,
+            sparse: false,
+            no_direct_io: false,
+            order: Order::Scan,
             fsync: false,
+            staging_dir: None,
             reflink: Reflink::Auto,
             backup: Backup::None,
+            dir_mode: DirMode::PreserveExisting,
+            new_dir_mode: 0o777,
+            prune_empty_dirs: false,
+            continue_on_error: false,
+            max_errors: None,
+            max_error_rate: None,
+            timeout: None,
+            file_timeout: None,
+            really_continue_on_enospc: false,
+            read_retries: 0,
+            fill_errors: false,
+            checksum: None,
+            stamp_checksum: false,
+            skip_same: None,
+            dry_run: false,
+            itemize: false,
+            delete: false,
+            report_actions: false,
+            collect_results: false,
+            compare_checksum: None,
+            chmod: None,
+            chown: None,
+            invalid_name: InvalidName::Error,
+            name_profile: None,
+            fs: Arc::new(RealFs),
+            executor: Executor::Threads,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbolic(target: ModeTarget, who: u32, op: ModeOp, perms: u32, exec_if_any: bool) -> ModeClause {
+        ModeClause { target, change: ModeChange::Symbolic { who, op, perms, exec_if_any } }
+    }
+
+    #[test]
+    fn test_chmod_dir_file_split() {
+        let chmod = Chmod(vec![
+            ModeClause { target: ModeTarget::Dirs, change: ModeChange::Absolute(0o755) },
+            ModeClause { target: ModeTarget::Files, change: ModeChange::Absolute(0o644) },
+        ]);
+        assert_eq!(0o40755, chmod.apply(0o40700, true));
+        assert_eq!(0o100644, chmod.apply(0o100777, false));
+    }
+
+    #[test]
+    fn test_chmod_symbolic() {
+        // u+rwX,go-w
+        let chmod = Chmod(vec![
+            symbolic(ModeTarget::All, 0o4700, ModeOp::Add, 0o666, true),
+            symbolic(ModeTarget::All, 0o3077, ModeOp::Remove, 0o222, false),
+        ]);
+        assert_eq!(0o600, chmod.apply(0o022, false));
+        assert_eq!(0o744, chmod.apply(0o166, false));
+        assert_eq!(0o755, chmod.apply(0o077, true));
+
+        // o=r
+        let chmod = Chmod(vec![symbolic(ModeTarget::All, 0o1007, ModeOp::Set, 0o444, false)]);
+        assert_eq!(0o774, chmod.apply(0o777, false));
+    }
+
+    #[test]
+    fn test_preserve_round_trip() {
+        for bits in 1..=PreserveSet::ALL.0 {
+            let set = PreserveSet(bits);
+            let parsed = set.to_string().parse::<PreserveSet>().unwrap();
+            assert_eq!(set, parsed, "{}", set);
         }
+        assert_eq!("mode,ownership,timestamps,links,context,xattr,flags", PreserveSet::ALL.to_string());
+        assert_eq!("", PreserveSet::NONE.to_string());
+    }
+
+    #[test]
+    fn test_preserve_parse() {
+        let parse = |s: &str| s.parse::<PreserveSet>().unwrap();
+        assert_eq!(PreserveSet::ALL, parse("all"));
+        assert_eq!(PreserveSet::ALL, parse("mode,all"));
+        assert_eq!(PreserveSet::BASIC, parse("timestamps,Mode,ownership,mode"));
+        assert_eq!(PreserveSet::LINKS.union(PreserveSet::XATTR), parse("xattr,links"));
+        assert_eq!(PreserveSet::FLAGS, parse("flags"));
+        assert_eq!(PreserveSet::DEFAULT, parse(&PreserveSet::DEFAULT.to_string()));
+
+        for bad in ["", "mode,", "perms", "mode,timestamp", "all,none"] {
+            assert!(bad.parse::<PreserveSet>().is_err(), "{:?}", bad);
+        }
+        let err = "ownrship".parse::<PreserveSet>().unwrap_err().to_string();
+        assert!(err.contains("did you mean 'ownership'?"), "{}", err);
+    }
+
+    #[test]
+    fn test_preserve_ops() {
+        let set = PreserveSet::ALL.difference(PreserveSet::BASIC);
+        assert_eq!("links,context,xattr,flags", set.to_string());
+        assert!(set.contains(PreserveSet::LINKS.union(PreserveSet::XATTR)));
+        assert!(!set.contains(PreserveSet::LINKS.union(PreserveSet::MODE)));
+        assert!(set.intersects(PreserveSet::LINKS.union(PreserveSet::MODE)));
+        assert!(!set.intersects(PreserveSet::BASIC));
+        assert!(set.difference(PreserveSet::ALL).is_empty());
+        assert_eq!(PreserveSet::ALL, set.union(PreserveSet::BASIC));
+    }
+
+    #[test]
+    fn test_buffer_plan() {
+        const K: u64 = 1024;
+        const M: u64 = 1024 * K;
+        let plan = |workers, block_size, max| Config {
+            workers,
+            block_size,
+            max_buffer_memory: max,
+            ..Config::default()
+        }.buffer_plan();
+        let bp = |block_size, workers| BufferPlan { block_size, workers };
+
+        // Within the cap.
+        assert_eq!(bp(M, 8), plan(8, M, None));
+        assert_eq!(bp(8 * M, 4), plan(4, 8 * M, Some(32 * M)));
+        // Whole-file blocks are split between the workers.
+        assert_eq!(bp(4 * M, 64), plan(64, u64::MAX, None));
+        assert_eq!(bp(2 * M, 64), plan(64, 8 * M, Some(128 * M)));
+        // Rounded down to a page.
+        assert_eq!(bp(340 * K, 3), plan(3, M, Some(M)));
+        // Fewer workers rather than tiny blocks.
+        assert_eq!(bp(64 * K, 2), plan(64, M, Some(128 * K)));
+        assert_eq!(bp(1000, 1), plan(4, M, Some(1000)));
+
+        for workers in [1, 3, 8, 64, 1000] {
+            for block_size in [1, 4 * K, M, 8 * M, u64::MAX] {
+                for max in [1, 4095, 64 * K, M, 100 * M + 1, 256 * M, u64::MAX] {
+                    let p = plan(workers, block_size, Some(max));
+                    assert!(p.total() <= max, "{:?} exceeds {}", p, max);
+                    assert!(p.block_size >= 1 && p.block_size <= block_size, "{:?}", p);
+                    assert!(p.workers >= 1 && p.workers <= workers, "{:?}", p);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_io_block_size() {
+        const K: u64 = 1024;
+        const M: u64 = 1024 * K;
+        let config = |auto, max| Config {
+            workers: 4,
+            block_size: M,
+            max_buffer_memory: max,
+            auto_block_size: auto,
+            ..Config::default()
+        };
+
+        assert_eq!(M, config(false, None).io_block_size(4 * K, 8 * M));
+        assert_eq!(M, config(true, None).io_block_size(4 * K, 4 * K));
+        assert_eq!(8 * M, config(true, None).io_block_size(4 * K, 8 * M));
+        assert_eq!(MAX_IO_SIZE, config(true, None).io_block_size(4 * K, 1024 * M));
+        // The buffer memory cap still applies, keeping to whole
+        // stripes where possible.
+        assert_eq!(7 * M, config(true, Some(28 * M)).io_block_size(8 * M, 4 * K));
+        assert_eq!(768 * K, config(true, Some(4 * M)).io_block_size(768 * K, 4 * K));
     }
 }
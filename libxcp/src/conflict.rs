@@ -0,0 +1,297 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Names for copies that would overwrite an existing destination,
+//! with [NoClobber::Rename].
+//!
+//! The new name is generated from a [ConflictTemplate], trying
+//! `{n}` = 1, 2, ... until an unused name is found. Each candidate is
+//! created exclusively, so a name taken by another process in the
+//! meantime is never overwritten.
+//!
+//! [NoClobber::Rename]: crate::config::NoClobber::Rename
+
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::debug;
+
+use crate::errors::XcpError;
+
+/// The default template, e.g. `report (1).pdf`.
+pub const DEFAULT_TEMPLATE: &str = "{name} ({n}).{ext}";
+
+/// The most names tried before giving up.
+const MAX_ATTEMPTS: u64 = 100_000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Name,
+    Ext,
+    N,
+    Date,
+}
+
+/// A template for the name of a copy kept alongside an existing
+/// destination. `{name}` is the original name without its
+/// extension, `{ext}` the extension, `{n}` a number counting from 1,
+/// and `{date}` the current date as `YYYY-MM-DD` (UTC); `{{` and `}}`
+/// are literal braces. A dot immediately before `{ext}` is dropped
+/// when the name has no extension. [FromStr] is supported; the
+/// template must contain `{n}` and no `/`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConflictTemplate {
+    parts: Vec<Part>,
+}
+
+impl Default for ConflictTemplate {
+    fn default() -> Self {
+        // Fixed template, so should never error.
+        DEFAULT_TEMPLATE.parse().unwrap()
+    }
+}
+
+impl FromStr for ConflictTemplate {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let invalid = |reason: &str| XcpError::InvalidArguments(
+            format!("Invalid conflict suffix template {:?}: {}", s, reason));
+        if s.contains('/') {
+            return Err(invalid("names may not contain '/'"));
+        }
+
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut token = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => token.push(c),
+                            None => return Err(invalid("unmatched '{'")),
+                        }
+                    }
+                    let part = match token.as_str() {
+                        "name" => Part::Name,
+                        "ext" => Part::Ext,
+                        "n" => Part::N,
+                        "date" => Part::Date,
+                        _ => return Err(invalid(&format!("unknown token {{{}}}", token))),
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(part);
+                }
+                '}' => return Err(invalid("unmatched '}'")),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        if !parts.contains(&Part::N) {
+            return Err(invalid("the template must contain {n}"));
+        }
+        Ok(ConflictTemplate { parts })
+    }
+}
+
+impl fmt::Display for ConflictTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for part in &self.parts {
+            match part {
+                Part::Literal(s) => write!(f, "{}", s.replace('{', "{{").replace('}', "}}"))?,
+                Part::Name => write!(f, "{{name}}")?,
+                Part::Ext => write!(f, "{{ext}}")?,
+                Part::N => write!(f, "{{n}}")?,
+                Part::Date => write!(f, "{{date}}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ConflictTemplate {
+    /// The name of copy `n` of `name`, dated `date`.
+    pub fn render(&self, name: &OsStr, n: u64, date: &str) -> OsString {
+        let (stem, ext) = split_ext(name);
+        let mut out = OsString::new();
+        for (i, part) in self.parts.iter().enumerate() {
+            match part {
+                Part::Literal(s) if ext.is_none() && self.parts.get(i + 1) == Some(&Part::Ext) => {
+                    out.push(s.strip_suffix('.').unwrap_or(s));
+                }
+                Part::Literal(s) => out.push(s),
+                Part::Name => out.push(stem),
+                Part::Ext => out.push(ext.unwrap_or_default()),
+                Part::N => out.push(n.to_string()),
+                Part::Date => out.push(date),
+            }
+        }
+        out
+    }
+}
+
+// Split a name into its stem and extension. A leading dot doesn't
+// start an extension, so `.bashrc` has none, and compressed tar
+// archives keep both parts, e.g. `tar.gz`.
+fn split_ext(name: &OsStr) -> (&OsStr, Option<&OsStr>) {
+    let path = Path::new(name);
+    let (Some(stem), Some(ext)) = (path.file_stem(), path.extension()) else {
+        return (name, None);
+    };
+    let inner = Path::new(stem);
+    match (inner.file_stem(), inner.extension()) {
+        (Some(base), Some(tar)) if tar.eq_ignore_ascii_case("tar") => {
+            let bytes = name.as_bytes();
+            (OsStr::from_bytes(&bytes[..base.len()]), Some(OsStr::from_bytes(&bytes[base.len() + 1..])))
+        }
+        _ => (stem, Some(ext)),
+    }
+}
+
+// Today's date as `YYYY-MM-DD`, in UTC.
+fn today() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() / 86400) as i64;
+    // Howard Hinnant's days_from_civil, inverted.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Create a copy of `to` under the lowest unused name generated by
+/// `template`, by calling `create` on each candidate until it doesn't
+/// fail with [ErrorKind::AlreadyExists]. `create` must fail if the
+/// path exists, e.g. by opening with `O_EXCL`. Returns its result and
+/// the name used.
+pub(crate) fn create_renamed<T>(
+    to: &Path,
+    template: &ConflictTemplate,
+    mut create: impl FnMut(&Path) -> io::Result<T>,
+) -> io::Result<(T, PathBuf)> {
+    let name = to.file_name().unwrap_or_default();
+    let date = today();
+    for n in 1..=MAX_ATTEMPTS {
+        let candidate = to.with_file_name(template.render(name, n, &date));
+        match create(&candidate) {
+            Ok(created) => {
+                debug!("Copying to {:?} as {:?} exists", candidate, to);
+                return Ok((created, candidate));
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(ErrorKind::AlreadyExists,
+                       format!("No unused name for a copy of {:?} after {} attempts", to, MAX_ATTEMPTS)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{write, File};
+    use tempfile::TempDir;
+
+    fn render(template: &str, name: &str, n: u64) -> String {
+        let template = template.parse::<ConflictTemplate>().unwrap();
+        template.render(OsStr::new(name), n, "2024-06-01").into_string().unwrap()
+    }
+
+    #[test]
+    fn test_render_default() {
+        assert_eq!("report (1).pdf", render(DEFAULT_TEMPLATE, "report.pdf", 1));
+        assert_eq!("Makefile (2)", render(DEFAULT_TEMPLATE, "Makefile", 2));
+        assert_eq!(".bashrc (1)", render(DEFAULT_TEMPLATE, ".bashrc", 1));
+        assert_eq!(".bashrc (1).bak", render(DEFAULT_TEMPLATE, ".bashrc.bak", 1));
+        assert_eq!("my.notes.v2 (1).txt", render(DEFAULT_TEMPLATE, "my.notes.v2.txt", 1));
+        assert_eq!("src (3).tar.gz", render(DEFAULT_TEMPLATE, "src.tar.gz", 3));
+        assert_eq!("src (3).TAR.XZ", render(DEFAULT_TEMPLATE, "src.TAR.XZ", 3));
+    }
+
+    #[test]
+    fn test_render_tokens() {
+        assert_eq!("a-2024-06-01-7.txt", render("{name}-{date}-{n}.{ext}", "a.txt", 7));
+        assert_eq!("{a}_1", render("{{{name}}}_{n}", "a", 1));
+        assert_eq!("1.a.txt", render("{n}.{name}.{ext}", "a.txt", 1));
+        assert_eq!("1.a", render("{n}.{name}.{ext}", "a", 1));
+    }
+
+    #[test]
+    fn test_parse_template() {
+        for bad in ["{name}.{ext}", "{n}/{name}", "{name}-{m}", "{n}}", "{n"] {
+            assert!(bad.parse::<ConflictTemplate>().is_err(), "{}", bad);
+        }
+        for good in [DEFAULT_TEMPLATE, "{{{n}}}", "{name}_{date}_{n}.{ext}"] {
+            assert_eq!(good, good.parse::<ConflictTemplate>().unwrap().to_string());
+        }
+    }
+
+    #[test]
+    fn test_today() {
+        let date = today();
+        assert_eq!(10, date.len());
+        assert!(date.starts_with("20"));
+        assert_eq!(Some('-'), date.chars().nth(4));
+    }
+
+    #[test]
+    fn test_create_renamed() {
+        let dir = TempDir::new().unwrap();
+        let to = dir.path().join("a.txt");
+        write(&to, "a").unwrap();
+        write(dir.path().join("a (1).txt"), "a").unwrap();
+        write(dir.path().join("a (3).txt"), "a").unwrap();
+
+        let create = |p: &Path| File::options().write(true).create_new(true).open(p);
+        let template = ConflictTemplate::default();
+        let (_, path) = create_renamed(&to, &template, create).unwrap();
+        assert_eq!(dir.path().join("a (2).txt"), path);
+        let (_, path) = create_renamed(&to, &template, create).unwrap();
+        assert_eq!(dir.path().join("a (4).txt"), path);
+
+        let err = create_renamed(&to, &template, |_| Err::<(), _>(io::Error::from(ErrorKind::PermissionDenied)));
+        assert_eq!(ErrorKind::PermissionDenied, err.unwrap_err().kind());
+    }
+}
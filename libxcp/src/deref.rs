@@ -0,0 +1,179 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Tracking of the source directories reached when following
+//! symlinks with [Config::dereference].
+//!
+//! Loops, where a symlink leads back to a directory the walk is
+//! already within, are refused by `walkdir`, which compares each
+//! followed directory against those on the current descent path by
+//! device and inode. [DerefTracker] keeps the same descent path so the
+//! loop can be reported in full.
+//!
+//! A directory reached by more than one path, e.g. via two symlinks,
+//! is not a loop and is copied under each name as `cp -L` does. The
+//! additional copies are tracked so the extra bytes can be reported.
+//!
+//! [Config::dereference]: crate::config::Config::dereference
+
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// A directory copied again as it was reached by a second path, and
+/// the bytes of the files copied beneath it.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Duplicate {
+    pub depth: usize,
+    pub path: PathBuf,
+    pub original: PathBuf,
+    pub bytes: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct DerefTracker {
+    // The directories the walk is within, with their depth.
+    open: Vec<(usize, PathBuf)>,
+    // The first path each directory was reached by, by (dev, inode).
+    seen: HashMap<(u64, u64), PathBuf>,
+    // The outermost duplicate the walk is within.
+    duplicate: Option<Duplicate>,
+}
+
+impl DerefTracker {
+    pub(crate) fn new() -> DerefTracker {
+        DerefTracker::default()
+    }
+
+    /// Record that the walk has entered the directory at `path`.
+    /// Returns the path it was first reached by if this is the start
+    /// of a duplicate.
+    pub(crate) fn enter(&mut self, depth: usize, path: &Path, meta: &Metadata) -> Option<PathBuf> {
+        self.open.push((depth, path.to_path_buf()));
+        let original = match self.seen.get(&(meta.dev(), meta.ino())) {
+            Some(original) => original.clone(),
+            None => {
+                self.seen.insert((meta.dev(), meta.ino()), path.to_path_buf());
+                return None;
+            }
+        };
+        // Everything beneath a duplicate has been seen too; only the
+        // outermost is reported.
+        if self.duplicate.is_some() {
+            return None;
+        }
+        self.duplicate = Some(Duplicate {
+            depth,
+            path: path.to_path_buf(),
+            original: original.clone(),
+            bytes: 0,
+        });
+        Some(original)
+    }
+
+    /// Record that the walk has moved on to an entry at `depth`,
+    /// leaving any directories at or below it. Returns the duplicate
+    /// that has been left, if any.
+    pub(crate) fn leave(&mut self, depth: usize) -> Option<Duplicate> {
+        let keep = self.open.iter().take_while(|(d, _)| *d < depth).count();
+        self.open.truncate(keep);
+        match self.duplicate {
+            Some(ref dup) if dup.depth >= depth => self.duplicate.take(),
+            _ => None,
+        }
+    }
+
+    /// Count a file copied at the current position in the walk.
+    pub(crate) fn file(&mut self, len: u64) {
+        if let Some(ref mut dup) = self.duplicate {
+            dup.bytes += len;
+        }
+    }
+
+    /// The loop formed by the symlink at `link` leading back to
+    /// `ancestor`, from the ancestor through the open directories to
+    /// the link and back.
+    pub(crate) fn loop_chain(&self, ancestor: &Path, link: &Path) -> Vec<PathBuf> {
+        let mut chain = self.open.iter()
+            .map(|(_, p)| p)
+            .skip_while(|p| *p != ancestor)
+            .cloned()
+            .collect::<Vec<PathBuf>>();
+        if chain.is_empty() {
+            chain.push(ancestor.to_path_buf());
+        }
+        chain.push(link.to_path_buf());
+        chain.push(ancestor.to_path_buf());
+        chain
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::create_dir_all;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_loop_chain() {
+        let mut tracker = DerefTracker::new();
+        let dir = TempDir::new().unwrap();
+        for (depth, p) in ["a", "a/b", "a/b/c"].iter().enumerate() {
+            let path = dir.path().join(p);
+            create_dir_all(&path).unwrap();
+            assert_eq!(None, tracker.enter(depth, &path, &path.metadata().unwrap()));
+        }
+        let chain = tracker.loop_chain(&dir.path().join("a/b"), &dir.path().join("a/b/c/up"));
+        assert_eq!(vec![dir.path().join("a/b"), dir.path().join("a/b/c"),
+                        dir.path().join("a/b/c/up"), dir.path().join("a/b")],
+                   chain);
+
+        // Leaving 'b' closes 'c' too.
+        assert_eq!(None, tracker.leave(1));
+        let chain = tracker.loop_chain(&dir.path().join("a"), &dir.path().join("a/up"));
+        assert_eq!(vec![dir.path().join("a"), dir.path().join("a/up"), dir.path().join("a")],
+                   chain);
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let mut tracker = DerefTracker::new();
+        let dir = TempDir::new().unwrap();
+        let real = dir.path().join("real");
+        let sub = real.join("sub");
+        create_dir_all(&sub).unwrap();
+        let (real_meta, sub_meta) = (real.metadata().unwrap(), sub.metadata().unwrap());
+
+        assert_eq!(None, tracker.enter(0, dir.path(), &dir.path().metadata().unwrap()));
+        assert_eq!(None, tracker.enter(1, &real, &real_meta));
+        assert_eq!(None, tracker.enter(2, &sub, &sub_meta));
+        tracker.file(10);
+        assert_eq!(None, tracker.leave(1));
+
+        // The same directory via a link; only the outermost is
+        // reported.
+        let link = dir.path().join("link");
+        assert_eq!(Some(real.clone()), tracker.enter(1, &link, &real_meta));
+        tracker.file(5);
+        assert_eq!(None, tracker.enter(2, &link.join("sub"), &sub_meta));
+        tracker.file(7);
+        assert_eq!(None, tracker.leave(2));
+        assert_eq!(Some(Duplicate { depth: 1, path: link, original: real, bytes: 12 }),
+                   tracker.leave(1));
+        assert_eq!(None, tracker.leave(0));
+    }
+}
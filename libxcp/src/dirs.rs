@@ -0,0 +1,469 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Creation and completion of destination directories.
+//!
+//! [DirCache] remembers which directories have already been created
+//! or found to exist, so each is only created once however many
+//! times it is requested. Concurrent requests for the same directory
+//! are coalesced; one thread creates it while the others wait for the
+//! result.
+//!
+//! [DirTracker] counts the outstanding entries of each destination
+//! directory. Once a directory has been fully walked and its last
+//! entry completed its source metadata is applied, while the rest of
+//! the copy continues.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::Instant;
+
+use libfs::FsOps;
+use log::debug;
+
+use crate::config::Config;
+use crate::feedback::{StatusUpdate, StatusUpdater, BATCH_FLUSH_INTERVAL};
+use crate::operations::apply_dir_metadata;
+
+// The mode std::fs::create_dir uses, before the umask.
+const DEFAULT_DIR_MODE: u32 = 0o777;
+
+/// The most finalised directories counted in a single
+/// [StatusUpdate::MetadataDone].
+const METADATA_BATCH: u64 = 1024;
+
+// The outcome of a creation, shared with any waiting threads.
+type Flight = Arc<OnceLock<Result<bool, (ErrorKind, String)>>>;
+
+pub(crate) struct DirCache {
+    fs: Arc<dyn FsOps>,
+    /// The mode of directories without a source counterpart; see
+    /// [Config::new_dir_mode].
+    new_dir_mode: u32,
+    /// Directories known to exist. This is read far more often than
+    /// written.
+    ensured: RwLock<HashSet<PathBuf>>,
+    /// Directories currently being created.
+    inflight: Mutex<HashMap<PathBuf, Flight>>,
+}
+
+impl DirCache {
+    pub(crate) fn new(config: &Config) -> DirCache {
+        DirCache {
+            fs: config.fs.clone(),
+            new_dir_mode: config.new_dir_mode,
+            ensured: RwLock::default(),
+            inflight: Mutex::default(),
+        }
+    }
+
+    /// Ensure a directory copied from the source and its parents
+    /// exist, as with [std::fs::create_dir_all]. Returns true if this call
+    /// created `dir`, or false if it already existed. Missing parents
+    /// are created with [Config::new_dir_mode].
+    pub(crate) fn ensure(&self, dir: &Path) -> io::Result<bool> {
+        self.ensure_mode(dir, DEFAULT_DIR_MODE)
+    }
+
+    /// As [DirCache::ensure], for a directory without a source
+    /// counterpart, which is also created with
+    /// [Config::new_dir_mode].
+    pub(crate) fn ensure_new(&self, dir: &Path) -> io::Result<bool> {
+        self.ensure_mode(dir, self.new_dir_mode)
+    }
+
+    /// Record that `dir` exists, having been created other than by
+    /// [DirCache::ensure], e.g. as a btrfs subvolume.
+    #[cfg(all(target_os = "linux", feature = "use_linux", feature = "btrfs"))]
+    pub(crate) fn insert(&self, dir: &Path) {
+        self.ensured.write().unwrap().insert(normalize(dir));
+    }
+
+    fn ensure_mode(&self, dir: &Path, mode: u32) -> io::Result<bool> {
+        let dir = normalize(dir);
+        if self.ensured.read().unwrap().contains(&dir) {
+            return Ok(false);
+        }
+
+        let flight = self.inflight.lock().unwrap()
+            .entry(dir.clone())
+            .or_default()
+            .clone();
+        let mut first = false;
+        let result = flight.get_or_init(|| {
+            first = true;
+            self.create(&dir, mode).map_err(|e| (e.kind(), e.to_string()))
+        });
+        if first {
+            if result.is_ok() {
+                self.ensured.write().unwrap().insert(dir.clone());
+            }
+            // Failures are not cached, so later calls retry.
+            self.inflight.lock().unwrap().remove(&dir);
+        }
+
+        match result {
+            Ok(created) => Ok(*created && first),
+            Err((kind, msg)) => Err(io::Error::new(*kind, msg.clone())),
+        }
+    }
+
+    fn create(&self, dir: &Path, mode: u32) -> io::Result<bool> {
+        match self.fs.mkdir(dir, mode) {
+            Ok(()) => {
+                debug!("Created directory {:?}", dir);
+                Ok(true)
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists && dir.is_dir() => Ok(false),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                match dir.parent() {
+                    Some(parent) if !parent.as_os_str().is_empty() => self.ensure_new(parent)?,
+                    _ => return Err(e),
+                };
+                match self.fs.mkdir(dir, mode) {
+                    Ok(()) => Ok(true),
+                    Err(e) if e.kind() == ErrorKind::AlreadyExists && dir.is_dir() => Ok(false),
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// A directory awaiting its metadata.
+struct PendingDir {
+    from: PathBuf,
+    /// Entries dispatched but not yet completed, including
+    /// subdirectories not yet finalised.
+    outstanding: usize,
+    /// All entries have been found by the walk.
+    walked: bool,
+    /// The parent, if it is also pending.
+    parent: Option<PathBuf>,
+}
+
+/// Tracks destination directories that need source metadata applied;
+/// see [apply_dir_metadata]. Each dispatched entry holds a
+/// [ChildGuard] on its parent directory, which is released when the
+/// entry is complete, successfully or otherwise. A directory is
+/// finalised when it has been marked as walked and has no outstanding
+/// entries, which in turn releases its own parent.
+pub(crate) struct DirTracker {
+    config: Arc<Config>,
+    stats: Arc<dyn StatusUpdater>,
+    pending: Mutex<HashMap<PathBuf, PendingDir>>,
+    /// Directories finalised but not yet reported, and when they were
+    /// last reported; see [StatusUpdate::MetadataDone].
+    done: Mutex<(u64, Instant)>,
+}
+
+impl DirTracker {
+    pub(crate) fn new(config: &Arc<Config>, stats: &Arc<dyn StatusUpdater>) -> Arc<DirTracker> {
+        Arc::new(DirTracker {
+            config: config.clone(),
+            stats: stats.clone(),
+            pending: Mutex::new(HashMap::new()),
+            done: Mutex::new((0, Instant::now())),
+        })
+    }
+
+    /// Start tracking a destination directory. If its parent is being
+    /// tracked the directory counts as one of its entries until
+    /// finalised.
+    pub(crate) fn add(&self, from: PathBuf, to: PathBuf) {
+        let mut pending = self.pending.lock().unwrap();
+        let parent = to.parent()
+            .and_then(|p| pending.get_mut(p).map(|d| (p.to_path_buf(), d)))
+            .map(|(path, dir)| {
+                dir.outstanding += 1;
+                path
+            });
+        pending.insert(to, PendingDir { from, outstanding: 0, walked: false, parent });
+        let _ = self.stats.send(StatusUpdate::MetadataPending(1));
+    }
+
+    /// Record an entry to be written at `target`, returning a guard
+    /// to be dropped once it is complete.
+    pub(crate) fn child(self: &Arc<Self>, target: &Path) -> ChildGuard {
+        let parent = target.parent()
+            .filter(|p| match self.pending.lock().unwrap().get_mut(*p) {
+                Some(dir) => {
+                    dir.outstanding += 1;
+                    true
+                }
+                None => false,
+            })
+            .map(|p| (self.clone(), p.to_path_buf()));
+        ChildGuard(parent)
+    }
+
+    /// All entries of `dir` have been found; it is finalised once
+    /// they are complete.
+    pub(crate) fn walked(&self, dir: &Path) {
+        let ready = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get_mut(dir) {
+                Some(d) if d.outstanding == 0 => pending.remove(dir),
+                Some(d) => {
+                    d.walked = true;
+                    None
+                }
+                None => None,
+            }
+        };
+        if let Some(d) = ready {
+            self.finalise(dir, d);
+        }
+    }
+
+    // An entry of `dir` is complete.
+    fn release(&self, dir: &Path) {
+        let ready = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get_mut(dir) {
+                Some(d) => {
+                    d.outstanding -= 1;
+                    if d.outstanding == 0 && d.walked {
+                        pending.remove(dir)
+                    } else {
+                        None
+                    }
+                }
+                None => None,
+            }
+        };
+        if let Some(d) = ready {
+            self.finalise(dir, d);
+        }
+    }
+
+    fn finalise(&self, dir: &Path, pending: PendingDir) {
+        debug!("Directory {:?} is complete", dir);
+        apply_dir_metadata(&pending.from, dir, &self.config);
+        self.finalised();
+        if let Some(parent) = pending.parent {
+            self.release(&parent);
+        }
+    }
+
+    // Count a finalised directory. The count is reported in batches
+    // of up to [METADATA_BATCH], and at least every
+    // [BATCH_FLUSH_INTERVAL] while directories are being finalised.
+    fn finalised(&self) {
+        let mut done = self.done.lock().unwrap();
+        done.0 += 1;
+        if done.0 >= METADATA_BATCH || done.1.elapsed() >= BATCH_FLUSH_INTERVAL {
+            self.report_done(&mut done);
+        }
+    }
+
+    fn report_done(&self, done: &mut (u64, Instant)) {
+        if done.0 > 0 {
+            // The receiver may have gone away if the copy was aborted.
+            let _ = self.stats.send(StatusUpdate::MetadataDone(done.0));
+        }
+        *done = (0, Instant::now());
+    }
+
+    /// Finalise any remaining directories, e.g. those not fully
+    /// walked because the copy was aborted. Children are finalised
+    /// before their parents.
+    pub(crate) fn finish(&self) {
+        let mut remaining = self.pending.lock().unwrap()
+            .drain()
+            .collect::<Vec<_>>();
+        remaining.sort_by(|a, b| b.0.cmp(&a.0));
+        for (dir, pending) in remaining {
+            apply_dir_metadata(&pending.from, &dir, &self.config);
+            self.finalised();
+        }
+        self.report_done(&mut self.done.lock().unwrap());
+    }
+}
+
+/// An outstanding entry of a directory held by a [DirTracker]. The
+/// entry is complete when this is dropped. The default guard is not
+/// tracked.
+#[derive(Default)]
+pub(crate) struct ChildGuard(Option<(Arc<DirTracker>, PathBuf)>);
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        if let Some((tracker, parent)) = self.0.take() {
+            tracker.release(&parent);
+        }
+    }
+}
+
+impl fmt::Debug for ChildGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ChildGuard")
+            .field(&self.0.as_ref().map(|(_, parent)| parent))
+            .finish()
+    }
+}
+
+// Lexically normalize a path, so that e.g. `a//b` and `a/./b` share
+// an entry. `..` is left as-is, as it can't be resolved without
+// following symlinks.
+fn normalize(path: &Path) -> PathBuf {
+    path.components().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PreserveSet;
+    use crate::feedback::ChannelUpdater;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(PathBuf::from("a/b"), normalize(Path::new("a//b")));
+        assert_eq!(PathBuf::from("a/b"), normalize(Path::new("a/./b/")));
+        assert_eq!(PathBuf::from("/a/../b"), normalize(Path::new("/a/../b")));
+    }
+
+    #[test]
+    fn test_ensure() -> io::Result<()> {
+        let tdir = TempDir::new()?;
+        let cache = DirCache::new(&Config::default());
+        let deep = tdir.path().join("a/b/c");
+
+        assert!(cache.ensure(&deep)?);
+        assert!(deep.is_dir());
+        assert!(!cache.ensure(&deep)?);
+        assert!(!cache.ensure(&tdir.path().join("a//b/./c"))?);
+        assert!(!cache.ensure(&tdir.path().join("a"))?);
+
+        // A file in the way is an error, and is not cached.
+        let file = tdir.path().join("file");
+        fs::write(&file, "")?;
+        assert!(cache.ensure(&file).is_err());
+        fs::remove_file(&file)?;
+        assert!(cache.ensure(&file)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ensure_new_dir_mode() -> io::Result<()> {
+        let tdir = TempDir::new()?;
+        let cache = DirCache::new(&Config { new_dir_mode: 0o700, ..Config::default() });
+        let mode = |p: &str| tdir.path().join(p).metadata().map(|m| m.permissions().mode() & 0o777);
+
+        // Only the missing parents have no source counterpart. The
+        // umask can only remove permissions, so check those.
+        assert!(cache.ensure(&tdir.path().join("a/b"))?);
+        assert_eq!(0o700, mode("a")?);
+        assert!(cache.ensure_new(&tdir.path().join("c/d"))?);
+        assert_eq!(0o700, mode("c")?);
+        assert_eq!(0o700, mode("c/d")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dir_tracker() -> io::Result<()> {
+        use std::time::{Duration, SystemTime};
+
+        let tdir = TempDir::new()?;
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let (src, dest) = (tdir.path().join("src"), tdir.path().join("dest"));
+        for d in [&src, &dest] {
+            fs::create_dir_all(d.join("sub"))?;
+        }
+        for d in [src.join("sub"), src.clone()] {
+            fs::File::open(d)?.set_modified(mtime)?;
+        }
+        let modified = |d: &Path| d.metadata().unwrap().modified().unwrap();
+
+        let config = Arc::new(Config { preserve: PreserveSet::DEFAULT.difference(PreserveSet::MODE), ..Config::default() });
+        let updater = ChannelUpdater::new(&config);
+        let rx = updater.rx_channel();
+        let tracker = DirTracker::new(&config, &(Arc::new(updater) as Arc<dyn StatusUpdater>));
+        tracker.add(src.clone(), dest.clone());
+        let file = tracker.child(&dest.join("file"));
+        tracker.add(src.join("sub"), dest.join("sub"));
+        let subfile = tracker.child(&dest.join("sub/file"));
+        // Untracked parents are ignored.
+        drop(tracker.child(&tdir.path().join("other")));
+
+        // Neither is complete until walked.
+        drop(subfile);
+        assert_ne!(mtime, modified(&dest.join("sub")));
+        tracker.walked(&dest.join("sub"));
+        assert_eq!(mtime, modified(&dest.join("sub")));
+
+        tracker.walked(&dest);
+        assert_ne!(mtime, modified(&dest));
+        drop(file);
+        assert_eq!(mtime, modified(&dest));
+        assert!(tracker.pending.lock().unwrap().is_empty());
+
+        // Both are counted; the finalised count is reported in
+        // batches, and in full by the end.
+        tracker.finish();
+        drop(tracker);
+        let (pending, done) = rx.iter().fold((0, 0), |(p, d), u| match u {
+            StatusUpdate::MetadataPending(n) => (p + n, d),
+            StatusUpdate::MetadataDone(n) => (p, d + n),
+            _ => (p, d),
+        });
+        assert_eq!((2, 2), (pending, done));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ensure_concurrent() -> io::Result<()> {
+        let tdir = TempDir::new()?;
+        let cache = Arc::new(DirCache::new(&Config::default()));
+        let created = Arc::new(AtomicUsize::new(0));
+
+        let threads = (0..8).map(|_| {
+            let cache = cache.clone();
+            let created = created.clone();
+            let base = tdir.path().to_path_buf();
+            thread::spawn(move || {
+                for d in 0..20 {
+                    let dir = base.join(format!("shared/{}/deep/dir", d % 5));
+                    if cache.ensure(&dir).unwrap() {
+                        created.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            })
+        }).collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        // Each leaf is reported created exactly once.
+        assert_eq!(5, created.load(Ordering::Relaxed));
+        for d in 0..5 {
+            assert!(tdir.path().join(format!("shared/{}/deep/dir", d)).is_dir());
+        }
+        Ok(())
+    }
+}
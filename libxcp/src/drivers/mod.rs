@@ -40,9 +40,13 @@ use std::result;
 use std::str::FromStr;
 use std::sync::Arc;
 
+use log::warn;
+
 use crate::config::Config;
 use crate::errors::{Result, XcpError};
 use crate::feedback::StatusUpdater;
+use crate::metrics::Metrics;
+use crate::results::CopyStats;
 
 /// The trait specifying driver operations; drivers should implement
 /// this.
@@ -53,7 +57,14 @@ pub trait CopyDriver {
     /// `StatusUpdate` objects depending on the driver configuration.
     /// `copy()` itself will block until all work is complete, so
     /// should be run in a thread if real-time updates are required.
-    fn copy(&self, sources: Vec<PathBuf>, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<()>;
+    /// The files copied are totalled in the returned [CopyStats],
+    /// with the result of each if [Config::collect_results] is set.
+    fn copy(&self, sources: Vec<PathBuf>, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<CopyStats>;
+
+    /// The runtime metrics of the copy in progress, or of the last
+    /// one; see [crate::metrics]. These can be sampled from another
+    /// thread while `copy()` runs.
+    fn metrics(&self) -> Arc<Metrics>;
 }
 
 /// An enum specifing the driver to use. This is just a helper for
@@ -82,6 +93,16 @@ impl FromStr for Drivers {
 
 /// Load and configure the given driver.
 pub fn load_driver(driver: Drivers, config: &Arc<Config>) -> Result<Box<dyn CopyDriver + Send>> {
+    let plan = config.buffer_plan();
+    // A block size of u64::MAX just means whole files, so only an
+    // explicit one is worth a warning.
+    if plan.block_size < config.block_size && config.block_size != u64::MAX {
+        warn!("Buffer memory limit reduces the block size from {} to {} bytes", config.block_size, plan.block_size);
+    }
+    if plan.workers < config.num_workers() {
+        warn!("Buffer memory limit reduces the number of workers from {} to {}", config.num_workers(), plan.workers);
+    }
+
     let driver_impl: Box<dyn CopyDriver + Send> = match driver {
         Drivers::ParFile => Box::new(parfile::Driver::new(config.clone())?),
         #[cfg(feature = "parblock")]
@@ -18,26 +18,30 @@
 //! configurable. This can have better performance for large files,
 //! but has a higher overhead.
 
-use std::cmp;
-use std::fs::remove_file;
+use std::collections::BTreeSet;
+use std::fs::File;
 use std::ops::Range;
-use std::os::unix::fs::symlink;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
 
 use cfg_if::cfg_if;
 use crossbeam_channel as cbc;
-use libfs::copy_node;
 use log::{error, info};
-use blocking_threadpool::{Builder, ThreadPool};
 
-use crate::config::Config;
+use crate::config::{Config, Reflink};
 use crate::drivers::CopyDriver;
-use crate::errors::{Result, XcpError};
-use crate::feedback::{StatusUpdate, StatusUpdater};
-use crate::operations::{CopyHandle, Operation, tree_walker};
-use libfs::{copy_file_offset, map_extents, merge_extents, probably_sparse};
+use crate::errlimit::limit_errors;
+use crate::errors::{copy_error, is_early_shutdown, Result, XcpError};
+use crate::executor::{Pool, Task};
+use crate::feedback::{Action, CopyMethod, StatusUpdate, StatusUpdater};
+use crate::metastage::MetadataStage;
+use crate::metrics::Metrics;
+use crate::results::{CopyStats, Results};
+use crate::operations::{copy_special, copy_symlink, queue_file_range, send_action, skip_existing, Abort, BlockFiles, CopyHandle, Operation, WriteOrder, tree_walker};
+use crate::staging::Staging;
+use libfs::{fs_type, is_compressed, map_extents, merge_extents, probably_sparse, FsType};
 
 // ********************************************************************** //
 
@@ -62,6 +66,7 @@ const fn supported_platform() -> bool {
 
 pub struct Driver {
     config: Arc<Config>,
+    metrics: Arc<Metrics>,
 }
 
 impl Driver {
@@ -74,91 +79,153 @@ impl Driver {
 
         Ok(Self {
             config,
+            metrics: Arc::default(),
         })
     }
+
+    // Start the (single) dispatch worker, which consumes files until
+    // the queue is closed.
+    fn start_dispatcher(
+        &self,
+        file_rx: cbc::Receiver<Operation>,
+        stats: &Arc<dyn StatusUpdater>,
+        abort: &Arc<Abort>,
+        staging: Option<&Arc<Staging>>,
+        results: &Arc<Results>,
+        stage: Option<&Arc<MetadataStage>>,
+    ) -> Task<Result<()>> {
+        let q_config = self.config.clone();
+        let st = stats.clone();
+        let a = abort.clone();
+        let sg = staging.cloned();
+        let r = results.clone();
+        let ms = stage.cloned();
+        let m = self.metrics.clone();
+        self.config.executor.spawn(move || dispatch_worker(file_rx, &st, q_config, &a, sg.as_ref(), &r, ms.as_ref(), &m))
+    }
+}
+
+fn join_dispatcher(dispatcher: Task<Result<()>>) -> Result<()> {
+    dispatcher.join()
+        .map_err(|_| XcpError::CopyError("Error dispatching copy operation".to_string()))?
 }
 
 impl CopyDriver for Driver {
-    fn copy(&self, sources: Vec<PathBuf>, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<()> {
+    fn copy(&self, sources: Vec<PathBuf>, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<CopyStats> {
         let (file_tx, file_rx) = cbc::unbounded::<Operation>();
-
-        // Start (single) dispatch worker
-        let dispatcher = {
-            let q_config = self.config.clone();
-            let st = stats.clone();
-            thread::spawn(move || dispatch_worker(file_rx, &st, q_config))
-        };
+        let abort = Arc::new(Abort::new(&self.config));
+        let stats = limit_errors(stats, &abort);
+        let staging = Staging::new(&self.config)?.map(Arc::new);
+        let results = Arc::new(Results::new(&self.config));
+        self.metrics.reset();
+        let stage = MetadataStage::new(&self.config, &self.metrics).map(Arc::new);
 
         // Thread which walks the file tree and sends jobs to the
         // workers. The worker tx channel is moved to the walker so it is
         // closed, which will cause the workers to shutdown on completion.
+        // Started first, so a sequential executor has walked the tree
+        // before dispatching.
         let walk_worker = {
             let sc = stats.clone();
             let d = dest.to_path_buf();
             let c = self.config.clone();
-            thread::spawn(move || tree_walker(sources, &d, &c, file_tx, sc))
+            let a = abort.clone();
+            let r = results.clone();
+            let m = self.metrics.clone();
+            self.config.executor.spawn(move || tree_walker(sources, &d, &c, file_tx, sc, &a, &r, &m))
         };
 
-        walk_worker.join()
-            .map_err(|_| XcpError::CopyError("Error walking copy tree".to_string()))??;
-        dispatcher.join()
-            .map_err(|_| XcpError::CopyError("Error dispatching copy operation".to_string()))??;
+        let dispatcher = self.start_dispatcher(file_rx, &stats, &abort, staging.as_ref(), &results, stage.as_ref());
+
+        let walked = walk_worker.join()
+            .map_err(|_| XcpError::CopyError("Error walking copy tree".to_string()))?;
+        let dispatched = join_dispatcher(dispatcher);
+        if let Some(stage) = &stage {
+            stage.join();
+        }
+        if walked.is_err() || dispatched.is_err() {
+            // Stopping at the error limit can fail the walk as the
+            // dispatcher exits; the limit is the cause.
+            abort.check_errors()?;
+        }
+        let mut walked = walked?;
+        dispatched?;
+        // Further links to a source are copied once the first copy is
+        // complete, by a second dispatcher.
+        if walked.has_copies() && !abort.is_set() {
+            let (file_tx, file_rx) = cbc::unbounded::<Operation>();
+            // Queued first, as the sequential executor runs the
+            // dispatcher when it is started.
+            let sent = walked.send_copies(&self.config, file_tx, &results, &self.metrics);
+            let dispatcher = self.start_dispatcher(file_rx, &stats, &abort, staging.as_ref(), &results, stage.as_ref());
+            let dispatched = join_dispatcher(dispatcher);
+            if let Some(stage) = &stage {
+                stage.join();
+            }
+            if sent.is_err() || dispatched.is_err() {
+                abort.check_errors()?;
+            }
+            sent?;
+            dispatched?;
+        }
+        walked.finish(&self.config, &stats)?;
+        abort.check_timeout()?;
+        abort.check_errors()?;
+
+        Ok(results.take())
+    }
 
-        Ok(())
+    fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
     }
 }
 
 // ********************************************************************** //
 
-fn queue_file_range(
-    handle: &Arc<CopyHandle>,
-    range: Range<u64>,
-    pool: &ThreadPool,
+// The file is complete once the last block has been copied and the
+// handle dropped, so it should hold the guard of the destination.
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(bytes = handle.len)))]
+fn queue_file_blocks(
+    handle: CopyHandle,
+    pool: &Pool,
     status_channel: &Arc<dyn StatusUpdater>,
+    config: &Arc<Config>,
+    metrics: &Arc<Metrics>,
 ) -> Result<u64> {
-    let len = range.end - range.start;
-    let bsize = handle.config.block_size;
-    let blocks = (len / bsize) + (if len % bsize > 0 { 1 } else { 0 });
+    let len = handle.len;
 
-    for blkn in 0..blocks {
-        let harc = handle.clone();
+    if config.hash_type().is_some() || config.reflink == Reflink::Always || handle.sequential || handle.offload || handle.device
+        || handle.inline.is_some()
+    {
+        // Hashing must be done in order, so copy the file
+        // sequentially as a single job. Clones are a single ioctl,
+        // so are also done as one job rather than serialising them
+        // in the dispatcher, as are server-side copies, device
+        // images and tiny files read by the walker. Destinations
+        // that can't be preallocated often also fail with
+        // out-of-order writes.
         let stat_tx = status_channel.clone();
-        let bytes = cmp::min(len - (blkn * bsize), bsize);
-        let off = range.start + (blkn * bsize);
-
+        let metrics = metrics.clone();
         pool.execute(move || {
-            let copy_result = copy_file_offset(&harc.infd, &harc.outfd, bytes, off as i64);
-            let stat_result = match copy_result {
-                Ok(bytes) => {
-                    stat_tx.send(StatusUpdate::Copied(bytes as u64))
-                }
-                Err(e) => {
+            let _worker = metrics.enter();
+            if let Err(e) = handle.copy_file(&stat_tx) {
+                if !is_early_shutdown(&e) {
                     error!("Error copying: aborting.");
-                    stat_tx.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())))
+                    if let Err(e) = stat_tx.send(StatusUpdate::Error(copy_error(&e, &handle.from, &handle.to))) {
+                        let msg = format!("Failed to send status update message. This should not happen; aborting. Error: {}", e);
+                        error!("{}", msg);
+                        panic!("{}", msg);
+                    }
                 }
-            };
-            if let Err(e) = stat_result {
-                let msg = format!("Failed to send status update message. This should not happen; aborting. Error: {}", e);
-                error!("{}", msg);
-                panic!("{}", msg);
             }
+            handle.release();
         });
+        return Ok(len);
     }
-    Ok(len)
-}
 
-fn queue_file_blocks(
-    source: &Path,
-    dest: &Path,
-    pool: &ThreadPool,
-    status_channel: &Arc<dyn StatusUpdater>,
-    config: &Arc<Config>,
-) -> Result<u64> {
-    let handle = CopyHandle::new(source, dest, config)?;
-    let len = handle.metadata.len();
-
-    if handle.try_reflink()? {
+    if handle.try_reflink().map_err(|e| { handle.mark_failed(); e })? {
         info!("Reflinked, skipping rest of copy");
+        handle.release();
         return Ok(len);
     }
 
@@ -167,71 +234,171 @@ fn queue_file_blocks(
     // consumed, then close them. (This may be overkill; opening the
     // files in the workers would also be valid.)
     let harc = Arc::new(handle);
+    harc.set_method(CopyMethod::Blocks);
 
-    let queue_whole_file = || {
-        queue_file_range(&harc, 0..len, pool, status_channel)
+    let queue_all = || {
+        let ranges = file_ranges(&harc.infd, len)?;
+        let order = ordered_writes(&harc.outfd, &harc.to, config)?
+            .then(|| Arc::new(WriteOrder::default()));
+        let mut queued = 0;
+        for range in ranges {
+            queued += queue_file_range(&harc, range, harc.block_size, order.as_ref(), pool, status_channel, Some(metrics))?;
+        }
+        Ok(queued)
     };
 
-    if probably_sparse(&harc.infd)? {
-        if let Some(extents) = map_extents(&harc.infd)? {
-            let sparse_map = merge_extents(extents)?;
-            let mut queued = 0;
-            for ext in sparse_map {
-                queued += queue_file_range(&harc, ext.into(), pool, status_channel)?;
-            }
-            Ok(queued)
-        } else {
-            queue_whole_file()
+    let queued = queue_all();
+    if queued.is_err() {
+        harc.mark_failed();
+    }
+    // The last block may already be done.
+    BlockFiles::release(harc);
+    queued
+}
+
+// Handle a file that failed to be queued. Returns the error if the
+// copy should stop.
+fn copy_failed(
+    e: anyhow::Error,
+    from: &Path,
+    to: &Path,
+    config: &Config,
+    stats: &Arc<dyn StatusUpdater>,
+) -> Result<()> {
+    if skip_existing(&e, from, to, config, stats)? {
+        return Ok(());
+    }
+    stats.send(StatusUpdate::Error(copy_error(&e, from, to)))?;
+    error!("Dispatcher: Error copying {:?} -> {:?}.", from, to);
+    if config.continue_on_error {
+        return Ok(());
+    }
+    Err(e)
+}
+
+/// Destination devices that blocks have been written in order to, so
+/// the decision is only logged once for each.
+static ORDERED_DEVS: Mutex<BTreeSet<u64>> = Mutex::new(BTreeSet::new());
+
+// Whether blocks must be written to the destination in order; see
+// [Config::force_parblock].
+fn ordered_writes(outfd: &File, to: &Path, config: &Config) -> Result<bool> {
+    if config.force_parblock {
+        return Ok(false);
+    }
+    let reason = match fs_type(outfd)? {
+        FsType::Btrfs if is_compressed(outfd) => "is compressed",
+        FsType::Fuse => "is on a FUSE filesystem",
+        _ => return Ok(false),
+    };
+    if ORDERED_DEVS.lock().unwrap().insert(outfd.metadata()?.dev()) {
+        info!("Destination {:?} {}; writing blocks in order (use --force-parblock to override)", to, reason);
+    }
+    Ok(true)
+}
+
+// The ranges of a file that contain data; the extents if the file is
+// sparse, otherwise the whole file.
+fn file_ranges(infd: &File, len: u64) -> Result<Vec<Range<u64>>> {
+    if probably_sparse(infd)? {
+        if let Some(extents) = map_extents(infd)? {
+            let ranges = merge_extents(extents)?
+                .into_iter()
+                .map(Range::from)
+                .collect();
+            return Ok(ranges);
         }
-    } else {
-        queue_whole_file()
     }
+    Ok(std::iter::once(0..len).collect())
 }
 
 // Dispatch worker; receives queued files and hands them to
 // queue_file_blocks() which splits them onto the copy-pool.
-fn dispatch_worker(file_q: cbc::Receiver<Operation>, stats: &Arc<dyn StatusUpdater>, config: Arc<Config>) -> Result<()> {
-    let nworkers = config.num_workers();
-    let copy_pool = Builder::new()
-        .num_threads(nworkers)
-        // Use bounded queue for backpressure; this limits open
-        // files in-flight so we don't run out of file handles.
-        // FIXME: Number is arbitrary ATM, we should be able to
-        // calculate it from ulimits.
-        .queue_len(128)
-        .build();
+#[allow(clippy::too_many_arguments)]
+fn dispatch_worker(
+    file_q: cbc::Receiver<Operation>,
+    stats: &Arc<dyn StatusUpdater>,
+    config: Arc<Config>,
+    abort: &Arc<Abort>,
+    staging: Option<&Arc<Staging>>,
+    results: &Arc<Results>,
+    stage: Option<&Arc<MetadataStage>>,
+    metrics: &Arc<Metrics>,
+) -> Result<()> {
+    let nworkers = config.buffer_plan().workers;
+    // Use bounded queue for backpressure; this limits open
+    // files in-flight so we don't run out of file handles.
+    // FIXME: Number is arbitrary ATM, we should be able to
+    // calculate it from ulimits.
+    let copy_pool = config.executor.pool(nworkers, Some(128));
     for op in file_q {
+        metrics.dequeued();
+        if abort.is_set() {
+            info!("Copy aborted, stopping dispatch");
+            break;
+        }
         match op {
-            Operation::Copy(from, to) => {
+            Operation::Copy(from, to, len, guard) => {
                 info!("Dispatch[{:?}]: Copy {:?} -> {:?}", thread::current().id(), from, to);
-                let r = queue_file_blocks(&from, &to, &copy_pool, stats, &config);
+                let r = CopyHandle::walked(&from, &to, len, &config, stats, abort, staging)
+                    .and_then(|h| queue_file_blocks(h.with_guard(guard).with_results(results).with_stage(stage), &copy_pool, stats, &config, metrics));
                 if let Err(e) = r {
-                    stats.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())))?;
-                    error!("Dispatcher: Error copying {:?} -> {:?}.", from, to);
-                    return Err(e)
+                    copy_failed(e, &from, &to, &config, stats)?;
                 }
             }
 
+            // Create the files, and then queue them to be filled. If
+            // creating a file fails the rest are still queued, so none
+            // are left empty.
+            Operation::Batch(batch) => {
+                info!("Dispatch[{:?}]: Batch of {} files", thread::current().id(), batch.len());
+                let mut handles = Vec::with_capacity(batch.len());
+                let mut reader = None;
+                let mut failed = Ok(());
+                for file in batch {
+                    match CopyHandle::batched(&file, &mut reader, &config, stats, abort, staging) {
+                        Ok(h) => handles.push(h.with_guard(file.guard).with_results(results).with_stage(stage)),
+                        Err(e) => {
+                            failed = copy_failed(e, &file.from, &file.to, &config, stats);
+                            if failed.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+                for h in handles {
+                    let (from, to) = (h.from.clone(), h.to.clone());
+                    if let Err(e) = queue_file_blocks(h, &copy_pool, stats, &config, metrics) {
+                        copy_failed(e, &from, &to, &config, stats)?;
+                    }
+                }
+                failed?;
+            }
+
             // Inline the following operations as the should be near-instant.
-            Operation::Link(from, to) => {
+            Operation::Link(from, to, _guard) => {
                 info!("Dispatch[{:?}]: Symlink {:?} -> {:?}", thread::current().id(), from, to);
-                let r = symlink(&from, &to);
+                let r = copy_symlink(&from, &to, &config)
+                    .and_then(|_| send_action(stats, &config, Action::SymlinkCreated { from: from.clone(), to: to.clone() }));
                 if let Err(e) = r {
-                    stats.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())))?;
+                    stats.send(StatusUpdate::Error(copy_error(&e, &from, &to)))?;
+                    if config.continue_on_error {
+                        error!("Error symlinking: {:?} -> {:?}; continuing.", from, to);
+                        continue;
+                    }
                     error!("Error symlinking: {:?} -> {:?}; aborting.", from, to);
-                    return Err(e.into())
+                    return Err(e)
                 }
             }
 
-            Operation::Special(from, to) => {
+            Operation::Special(from, to, _guard) => {
                 info!("Dispatch[{:?}]: Special file {:?} -> {:?}", thread::current().id(), from, to);
-                if to.exists() {
-                    if config.no_clobber {
-                        return Err(XcpError::DestinationExists("Destination file exists and --no-clobber is set.", to).into());
+                match copy_special(&from, &to, &config) {
+                    Ok(()) => send_action(stats, &config, Action::SpecialCreated { from, to })?,
+                    Err(e) => if !skip_existing(&e, &from, &to, &config, stats)? {
+                        return Err(e);
                     }
-                    remove_file(&to)?;
                 }
-                copy_node(&from, &to)?;
             }
         }
     }
@@ -19,36 +19,82 @@
 
 use crossbeam_channel as cbc;
 use log::{debug, error, info};
-use libfs::copy_node;
-use std::fs::remove_file;
-use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::thread;
 
 use crate::config::Config;
 use crate::drivers::CopyDriver;
-use crate::errors::{Result, XcpError};
-use crate::feedback::{StatusUpdate, StatusUpdater};
-use crate::operations::{CopyHandle, Operation, tree_walker};
+use crate::errlimit::limit_errors;
+use crate::errors::{copy_error, is_destination_full, is_early_shutdown, Result, XcpError};
+use crate::executor::Task;
+use crate::feedback::{Action, BatchedUpdater, StatusUpdate, StatusUpdater};
+use crate::metastage::MetadataStage;
+use crate::metrics::{self, Metrics, WorkerState};
+use crate::results::{CopyStats, Results};
+use crate::operations::{copy_special, copy_symlink, send_action, skip_existing, Abort, BatchedCopy, CopyHandle, Operation, tree_walker};
+use crate::staging::Staging;
 
 // ********************************************************************** //
 
 pub struct Driver {
     config: Arc<Config>,
+    metrics: Arc<Metrics>,
 }
 
 impl Driver {
     pub fn new(config: Arc<Config>) -> Result<Self> {
         Ok(Self {
             config,
+            metrics: Arc::default(),
         })
     }
+
+    // Start the worker threads, which consume work until the queue is
+    // closed.
+    fn start_workers(
+        &self,
+        work_rx: cbc::Receiver<Operation>,
+        stats: &Arc<dyn StatusUpdater>,
+        abort: &Arc<Abort>,
+        staging: Option<&Arc<Staging>>,
+        results: &Arc<Results>,
+        stage: Option<&Arc<MetadataStage>>,
+    ) -> Vec<Task<Result<()>>> {
+        let nworkers = self.config.buffer_plan().workers;
+        let mut joins = Vec::with_capacity(nworkers);
+        for _ in 0..nworkers {
+            let copy_worker = {
+                let wrx = work_rx.clone();
+                let sc = stats.clone();
+                let conf = self.config.clone();
+                let a = abort.clone();
+                let st = staging.cloned();
+                let r = results.clone();
+                let ms = stage.cloned();
+                let m = self.metrics.clone();
+                self.config.executor.spawn(move || copy_worker(wrx, &conf, sc, &a, st.as_ref(), &r, ms.as_ref(), &m))
+            };
+            joins.push(copy_worker);
+        }
+        joins
+    }
+}
+
+fn join_workers(joins: Vec<Task<Result<()>>>) -> Result<()> {
+    joins.into_iter().try_for_each(|handle| handle.join()
+        .map_err(|_| XcpError::CopyError("Error during copy operation".to_string()))?)
 }
 
 impl CopyDriver for Driver {
-    fn copy(&self, sources: Vec<PathBuf>, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<()> {
+    fn copy(&self, sources: Vec<PathBuf>, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<CopyStats> {
         let (work_tx, work_rx) = cbc::unbounded();
+        let abort = Arc::new(Abort::new(&self.config));
+        let stats = limit_errors(stats, &abort);
+        let staging = Staging::new(&self.config)?.map(Arc::new);
+        let results = Arc::new(Results::new(&self.config));
+        self.metrics.reset();
+        let stage = MetadataStage::new(&self.config, &self.metrics).map(Arc::new);
 
         // Thread which walks the file tree and sends jobs to the
         // workers. The worker tx channel is moved to the walker so it is
@@ -57,75 +103,214 @@ impl CopyDriver for Driver {
             let sc = stats.clone();
             let d = dest.to_path_buf();
             let o = self.config.clone();
-            thread::spawn(move || tree_walker(sources, &d, &o, work_tx, sc))
+            let a = abort.clone();
+            let r = results.clone();
+            let m = self.metrics.clone();
+            self.config.executor.spawn(move || tree_walker(sources, &d, &o, work_tx, sc, &a, &r, &m))
         };
 
         // Worker threads. Will consume work and then shutdown once the
         // queue is closed by the walker.
-        let nworkers = self.config.num_workers();
-        let mut joins = Vec::with_capacity(nworkers);
-        for _ in 0..nworkers {
-            let copy_worker = {
-                let wrx = work_rx.clone();
-                let sc = stats.clone();
-                let conf = self.config.clone();
-                thread::spawn(move || copy_worker(wrx, &conf, sc))
-            };
-            joins.push(copy_worker);
-        }
+        let joins = self.start_workers(work_rx, &stats, &abort, staging.as_ref(), &results, stage.as_ref());
 
-        walk_worker.join()
-            .map_err(|_| XcpError::CopyError("Error walking copy tree".to_string()))??;
-        for handle in joins {
-            handle.join()
-                .map_err(|_| XcpError::CopyError("Error during copy operation".to_string()))??;
+        let walked = walk_worker.join()
+            .map_err(|_| XcpError::CopyError("Error walking copy tree".to_string()))?;
+        let worked = join_workers(joins);
+        if let Some(stage) = &stage {
+            stage.join();
+        }
+        if walked.is_err() || worked.is_err() {
+            // Stopping at the error limit can fail the walk as the
+            // workers exit; the limit is the cause.
+            abort.check_errors()?;
+        }
+        let mut walked = walked?;
+        worked?;
+        // Further links to a source are copied once the first copy is
+        // complete, by a second round of workers.
+        if walked.has_copies() && !abort.is_set() {
+            let (work_tx, work_rx) = cbc::unbounded();
+            // Queued first, as the sequential executor runs the
+            // workers when they are started.
+            let sent = walked.send_copies(&self.config, work_tx, &results, &self.metrics);
+            let joins = self.start_workers(work_rx, &stats, &abort, staging.as_ref(), &results, stage.as_ref());
+            let worked = join_workers(joins);
+            if let Some(stage) = &stage {
+                stage.join();
+            }
+            if sent.is_err() || worked.is_err() {
+                abort.check_errors()?;
+            }
+            sent?;
+            worked?;
         }
+        walked.finish(&self.config, &stats)?;
+        abort.check_timeout()?;
+        abort.check_errors()?;
 
-        Ok(())
+        Ok(results.take())
     }
 
+    fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
 }
 
 // ********************************************************************** //
 
-fn copy_worker(work: cbc::Receiver<Operation>, config: &Arc<Config>, updates: Arc<dyn StatusUpdater>) -> Result<()> {
+#[allow(clippy::too_many_arguments)]
+fn copy_worker(
+    work: cbc::Receiver<Operation>,
+    config: &Arc<Config>,
+    updates: Arc<dyn StatusUpdater>,
+    abort: &Arc<Abort>,
+    staging: Option<&Arc<Staging>>,
+    results: &Arc<Results>,
+    stage: Option<&Arc<MetadataStage>>,
+    metrics: &Metrics,
+) -> Result<()> {
     debug!("Starting copy worker {:?}", thread::current().id());
+    let _worker = metrics.enter();
+    // Batch this worker's progress updates; they are flushed when it
+    // exits.
+    let updates: Arc<dyn StatusUpdater> = Arc::new(BatchedUpdater::new(updates, config.block_size));
     for op in work {
+        metrics.dequeued();
+        if abort.is_set() {
+            debug!("Copy aborted, worker {:?} shutting down", thread::current().id());
+            break;
+        }
         debug!("Received operation {:?}", op);
 
         match op {
-            Operation::Copy(from, to) => {
+            Operation::Copy(from, to, len, _guard) => {
                 info!("Worker[{:?}]: Copy {:?} -> {:?}", thread::current().id(), from, to);
                 // copy_file() sends back its own updates, but we should
                 // send back any errors as they may have occurred
                 // before the copy started..
-                let r = CopyHandle::new(&from, &to, config)
-                    .and_then(|hdl| hdl.copy_file(&updates));
+                let r = CopyHandle::walked(&from, &to, len, config, &updates, abort, staging)
+                    .and_then(|hdl| {
+                        let hdl = hdl.with_results(results).with_stage(stage);
+                        let copied = hdl.copy_file(&updates);
+                        hdl.release();
+                        copied
+                    });
                 if let Err(e) = r {
-                    updates.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())))?;
-                    error!("Error copying: {:?} -> {:?}; aborting.", from, to);
-                    return Err(e)
+                    if !copy_failed(e, &from, &to, config, &updates)? {
+                        break;
+                    }
                 }
             }
 
-            Operation::Link(from, to) => {
+            Operation::Batch(batch) => {
+                info!("Worker[{:?}]: Batch of {} files", thread::current().id(), batch.len());
+                if !copy_batch(batch, config, &updates, abort, staging, results, stage)? {
+                    break;
+                }
+            }
+
+            Operation::Link(from, to, _guard) => {
                 info!("Worker[{:?}]: Symlink {:?} -> {:?}", thread::current().id(), from, to);
-                let _r = symlink(&from, &to);
+                metrics::set_state(WorkerState::Metadata);
+                let r = copy_symlink(&from, &to, config)
+                    .and_then(|_| send_action(&updates, config, Action::SymlinkCreated { from: from.clone(), to: to.clone() }));
+                if let Err(e) = r {
+                    updates.send(StatusUpdate::Error(copy_error(&e, &from, &to)))?;
+                    if !config.continue_on_error {
+                        error!("Error symlinking: {:?} -> {:?}; aborting.", from, to);
+                        return Err(e);
+                    }
+                    error!("Error symlinking: {:?} -> {:?}; continuing.", from, to);
+                }
             }
 
-            Operation::Special(from, to) => {
+            Operation::Special(from, to, _guard) => {
                 info!("Worker[{:?}]: Special file {:?} -> {:?}", thread::current().id(), from, to);
-                if to.exists() {
-                    if config.no_clobber {
-                        return Err(XcpError::DestinationExists("Destination file exists and --no-clobber is set.", to).into());
+                metrics::set_state(WorkerState::Metadata);
+                match copy_special(&from, &to, config) {
+                    Ok(()) => send_action(&updates, config, Action::SpecialCreated { from, to })?,
+                    Err(e) => if !skip_existing(&e, &from, &to, config, &updates)? {
+                        return Err(e);
                     }
-                    remove_file(&to)?;
                 }
-                copy_node(&from, &to)?;
             }
 
         }
+        metrics::set_state(WorkerState::Idle);
     }
     debug!("Copy worker {:?} shutting down", thread::current().id());
     Ok(())
 }
+
+// Handle a failed copy. Returns whether the worker should carry on,
+// or the error if the copy should stop.
+fn copy_failed(
+    e: anyhow::Error,
+    from: &Path,
+    to: &Path,
+    config: &Config,
+    updates: &Arc<dyn StatusUpdater>,
+) -> Result<bool> {
+    if skip_existing(&e, from, to, config, updates)? {
+        return Ok(true);
+    }
+    if is_early_shutdown(&e) {
+        // Caused by an error reported elsewhere.
+        debug!("Worker[{:?}]: Copy aborted", thread::current().id());
+        return Ok(false);
+    }
+    updates.send(StatusUpdate::Error(copy_error(&e, from, to)))?;
+    if config.continue_on_error && (!is_destination_full(&e) || config.really_continue_on_enospc) {
+        error!("Error copying: {:?} -> {:?}; continuing.", from, to);
+        return Ok(true);
+    }
+    error!("Error copying: {:?} -> {:?}; aborting.", from, to);
+    Err(e)
+}
+
+// Create all the files of a batch, and then fill them; see
+// [Config::batch_dirents]. If creating a file stops the copy, those
+// already created are still filled, so none are left empty. Returns
+// whether the worker should carry on.
+fn copy_batch(
+    batch: Vec<BatchedCopy>,
+    config: &Arc<Config>,
+    updates: &Arc<dyn StatusUpdater>,
+    abort: &Arc<Abort>,
+    staging: Option<&Arc<Staging>>,
+    results: &Arc<Results>,
+    stage: Option<&Arc<MetadataStage>>,
+) -> Result<bool> {
+    let mut handles = Vec::with_capacity(batch.len());
+    let mut reader = None;
+    let mut stopped = Ok(true);
+    for file in batch {
+        match CopyHandle::batched(&file, &mut reader, config, updates, abort, staging) {
+            Ok(handle) => handles.push(handle.with_guard(file.guard).with_results(results).with_stage(stage)),
+            Err(e) => {
+                stopped = copy_failed(e, &file.from, &file.to, config, updates);
+                if !matches!(stopped, Ok(true)) {
+                    break;
+                }
+            }
+        }
+    }
+
+    let mut handles = handles.into_iter();
+    for handle in handles.by_ref() {
+        let carry_on = match handle.copy_file(updates) {
+            Ok(_) => Ok(true),
+            Err(e) => copy_failed(e, &handle.from, &handle.to, config, updates),
+        };
+        handle.release();
+        if !matches!(carry_on, Ok(true)) {
+            stopped = carry_on;
+            break;
+        }
+    }
+    // Not filled as the copy has stopped.
+    for handle in handles {
+        handle.abandon();
+    }
+    stopped
+}
@@ -0,0 +1,189 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Limits on the errors a copy with [Config::continue_on_error] will
+//! tolerate; see [Config::max_errors] and [Config::max_error_rate].
+//! Errors are counted as they are sent to the [StatusUpdater], and a
+//! copy over either limit is stopped through its [Abort] flag, as with
+//! any other abort.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use log::warn;
+
+use crate::config::Config;
+use crate::errors::{Result, XcpError};
+use crate::feedback::{StatusUpdate, StatusUpdater};
+use crate::operations::Abort;
+
+/// The number of files over which [Config::max_error_rate] is
+/// measured.
+pub(crate) const ERROR_RATE_WINDOW: usize = 1000;
+
+/// The error counts of a copy, and the limit exceeded, if any.
+#[derive(Default)]
+pub(crate) struct ErrorLimit {
+    max_errors: Option<u64>,
+    max_rate: Option<u64>,
+    errors: AtomicU64,
+    /// Whether each of the last [ERROR_RATE_WINDOW] outcomes was an
+    /// error, and how many were; only kept with a rate limit.
+    window: Mutex<(VecDeque<bool>, u64)>,
+    exceeded: OnceLock<XcpError>,
+}
+
+impl ErrorLimit {
+    /// The limits of a copy, or `None` if it has none.
+    pub(crate) fn new(config: &Config) -> Option<ErrorLimit> {
+        if config.max_errors.is_none() && config.max_error_rate.is_none() {
+            return None;
+        }
+        Some(ErrorLimit {
+            max_errors: config.max_errors,
+            max_rate: config.max_error_rate,
+            ..ErrorLimit::default()
+        })
+    }
+
+    /// Count an update. Returns whether it took the copy over a
+    /// limit; this is only returned once.
+    pub(crate) fn record(&self, update: &StatusUpdate) -> bool {
+        let error = match update {
+            StatusUpdate::Error(_) => true,
+            StatusUpdate::FileCompleted(..) => false,
+            _ => return false,
+        };
+        let exceeded = if error {
+            let errors = self.errors.fetch_add(1, Ordering::Relaxed) + 1;
+            self.max_errors
+                .filter(|max| errors >= *max)
+                .map(XcpError::TooManyErrors)
+        } else {
+            None
+        };
+        let exceeded = exceeded.or_else(|| self.record_rate(error));
+        match exceeded {
+            Some(e) => {
+                let first = self.exceeded.set(e).is_ok();
+                if first {
+                    warn!("Error limit reached; stopping the copy");
+                }
+                first
+            }
+            None => false,
+        }
+    }
+
+    // Add an outcome to the window, returning the error if it is now
+    // over the rate limit.
+    fn record_rate(&self, error: bool) -> Option<XcpError> {
+        let max = self.max_rate?;
+        let (ref mut window, ref mut errors) = *self.window.lock().unwrap();
+        if window.len() == ERROR_RATE_WINDOW && window.pop_front() == Some(true) {
+            *errors -= 1;
+        }
+        window.push_back(error);
+        *errors += error as u64;
+        (*errors > max).then_some(XcpError::ErrorRateExceeded { errors: *errors, window: window.len() })
+    }
+
+    /// Returns the limit exceeded, if the copy was stopped by one.
+    pub(crate) fn check(&self) -> Result<()> {
+        match self.exceeded.get() {
+            Some(XcpError::TooManyErrors(n)) => Err(XcpError::TooManyErrors(*n).into()),
+            Some(XcpError::ErrorRateExceeded { errors, window }) =>
+                Err(XcpError::ErrorRateExceeded { errors: *errors, window: *window }.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A [StatusUpdater] that counts the errors passing through it
+/// against the limits of the copy, and stops it once one is exceeded.
+struct LimitedUpdater {
+    inner: Arc<dyn StatusUpdater>,
+    abort: Arc<Abort>,
+}
+
+impl StatusUpdater for LimitedUpdater {
+    fn send(&self, update: StatusUpdate) -> Result<()> {
+        let exceeded = self.abort.record(&update);
+        // The error that exceeds the limit is still reported.
+        self.inner.send(update)?;
+        if exceeded {
+            self.abort.set();
+        }
+        Ok(())
+    }
+}
+
+/// Wrap the updater of a copy to enforce its error limits, if it has
+/// any.
+pub(crate) fn limit_errors(updates: Arc<dyn StatusUpdater>, abort: &Arc<Abort>) -> Arc<dyn StatusUpdater> {
+    if !abort.has_error_limit() {
+        return updates;
+    }
+    Arc::new(LimitedUpdater { inner: updates, abort: abort.clone() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn error() -> StatusUpdate {
+        StatusUpdate::Error(XcpError::UnknownFileType(PathBuf::from("file")))
+    }
+
+    #[test]
+    fn test_max_errors() {
+        let limit = ErrorLimit::new(&Config { max_errors: Some(3), ..Config::default() }).unwrap();
+        assert!(!limit.record(&error()));
+        assert!(!limit.record(&StatusUpdate::FileCompleted(0, 0)));
+        assert!(!limit.record(&error()));
+        assert!(limit.check().is_ok());
+        assert!(limit.record(&error()));
+        // Only reported once.
+        assert!(!limit.record(&error()));
+        let err = limit.check().unwrap_err();
+        assert!(matches!(err.downcast_ref::<XcpError>(), Some(XcpError::TooManyErrors(3))));
+
+        assert!(ErrorLimit::new(&Config::default()).is_none());
+    }
+
+    #[test]
+    fn test_max_error_rate() {
+        let limit = ErrorLimit::new(&Config { max_error_rate: Some(10), ..Config::default() }).unwrap();
+        // Errors spread over more than the window never exceed it.
+        for i in 0..ERROR_RATE_WINDOW * 3 {
+            let update = if i % 100 == 0 { error() } else { StatusUpdate::FileCompleted(0, 0) };
+            assert!(!limit.record(&update));
+        }
+        for _ in 0..ERROR_RATE_WINDOW {
+            assert!(!limit.record(&StatusUpdate::FileCompleted(0, 0)));
+        }
+        for _ in 0..10 {
+            assert!(!limit.record(&error()));
+        }
+        assert!(limit.check().is_ok());
+        assert!(limit.record(&error()));
+        let err = limit.check().unwrap_err();
+        assert!(matches!(err.downcast_ref::<XcpError>(),
+                         Some(XcpError::ErrorRateExceeded { errors: 11, window: ERROR_RATE_WINDOW })), "{:?}", err);
+    }
+}
@@ -15,33 +15,169 @@
  */
 
 //! Custom error types.
+//!
+//! Errors are returned as [anyhow::Error]; those raised by xcp itself
+//! are an [XcpError], which can be recovered with `downcast_ref` and
+//! matched on. [error_kind] classifies any error, including I/O
+//! errors from the OS, for callers that only need to know the broad
+//! cause.
 
-use std::path::PathBuf;
+use std::cmp;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use libfs::{is_dir_error, is_exists, is_io_error, is_no_space, is_not_dir, is_unsupported};
 
 pub use anyhow::Result;
 
 #[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
 pub enum XcpError {
+    #[error("{0} and {1} cannot be used together: {2}")]
+    ConflictingOptions(&'static str, &'static str, &'static str),
+
     #[error("Error during copy: {0}")]
     CopyError(String),
 
-    #[error("Destination Exists: {0}, {1}")]
-    DestinationExists(&'static str, PathBuf),
+    /// Copying a file failed with an I/O error. The message is that
+    /// of the error returned, which may add context to the source.
+    #[error("Error copying {from:?}: {message}")]
+    CopyFailed {
+        from: PathBuf,
+        to: PathBuf,
+        message: String,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("Copy stalled for {1}s on {0:?}")]
+    CopyStalled(PathBuf, u64),
+
+    #[error("Destination Exists: {reason}, {path}")]
+    DestinationExists {
+        path: PathBuf,
+        reason: &'static str,
+    },
+
+    #[error("Destination {0:?} is a symlink to {1:?}, which does not exist")]
+    DanglingDestination(PathBuf, PathBuf),
+
+    #[error("Sources {0:?} and {1:?} would both be copied to {2:?}")]
+    DestinationCollision(PathBuf, PathBuf, PathBuf),
+
+    #[error("Destination {0:?} is newer than the source; not overwriting")]
+    DestinationNewer(PathBuf),
+
+    #[error("Destination {path:?} is locked by another copy{}", display_pid(.pid))]
+    DestinationLocked {
+        path: PathBuf,
+        /// The holder of the lock, if known.
+        pid: Option<u32>,
+    },
+
+    #[error("Destination full copying to {path:?}: {written} of {needed} bytes written")]
+    DestinationFull {
+        path: PathBuf,
+        written: u64,
+        needed: u64,
+    },
 
     #[error("Early shutdown: {0}")]
     EarlyShutdown(&'static str),
 
+    /// The copy was stopped by [Config::max_error_rate].
+    ///
+    /// [Config::max_error_rate]: crate::config::Config::max_error_rate
+    #[error("Copy stopped after {errors} errors in the last {window} files")]
+    ErrorRateExceeded {
+        errors: u64,
+        window: usize,
+    },
+
+    /// A destination entry that is immutable or append-only, or is
+    /// in such a directory, can't be changed as needed; see
+    /// [PreserveSet::FLAGS].
+    ///
+    /// [PreserveSet::FLAGS]: crate::config::PreserveSet::FLAGS
+    #[error("Destination {path:?} is immutable or append-only: {reason}")]
+    ImmutableDestination {
+        path: PathBuf,
+        reason: &'static str,
+    },
+
     #[error("Invalid arguments: {0}")]
     InvalidArguments(String),
 
-    #[error("Invalid destination: {0}")]
-    InvalidDestination(&'static str),
+    #[error("Cannot copy {0:?} to the destination filesystem: {1}")]
+    InvalidName(PathBuf, &'static str),
+
+    #[error("Invalid destination: {reason}")]
+    InvalidDestination {
+        path: PathBuf,
+        reason: &'static str,
+    },
+
+    #[error("Invalid source: {reason}")]
+    InvalidSource {
+        path: PathBuf,
+        reason: &'static str,
+    },
+
+    #[error("Invalid source: No source files found.")]
+    NoSources,
+
+    #[error("Invalid source: Source does not exist.")]
+    SourceNotFound(PathBuf),
+
+    #[error("Copy not confirmed: {0}")]
+    NotConfirmed(String),
+
+    #[error("Destination {1:?} is the same directory as source {0:?}, possibly via a bind mount")]
+    OverlappingDestination(PathBuf, PathBuf),
+
+    #[error("Failed to reflink file and 'always' was specified: {from:?} -> {to:?}: {source}")]
+    ReflinkFailed {
+        from: PathBuf,
+        to: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("Failed to read {path:?} at offset {offset}: {source}")]
+    ReadFailed {
+        path: PathBuf,
+        offset: u64,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("Source {path:?} ended prematurely at offset {offset}")]
+    SourceTruncated {
+        path: PathBuf,
+        offset: u64,
+    },
+
+    #[error("Trees differ: {differing} differing, {missing} missing and {extra} extra paths")]
+    TreesDiffer {
+        differing: usize,
+        missing: usize,
+        extra: usize,
+    },
+
+    #[error("Symlink loop: {}", display_chain(.0))]
+    SymlinkLoop(Vec<PathBuf>),
 
-    #[error("Invalid source: {0}")]
-    InvalidSource(&'static str),
+    #[error("Copy timed out after {0}s")]
+    TimedOut(u64),
 
-    #[error("Failed to reflink file and 'always' was specified: {0}")]
-    ReflinkFailed(String),
+    #[error("Copying {0:?} timed out after {1}s")]
+    FileTimedOut(PathBuf, u64),
+
+    /// The copy was stopped by [Config::max_errors].
+    ///
+    /// [Config::max_errors]: crate::config::Config::max_errors
+    #[error("Copy stopped after {0} errors")]
+    TooManyErrors(u64),
 
     #[error("Unknown driver: {0}")]
     UnknownDriver(String),
@@ -49,6 +185,576 @@ pub enum XcpError {
     #[error("Unknown file-type: {0}")]
     UnknownFileType(PathBuf),
 
+    #[error("Unreadable directory skipped: {path}: {source}")]
+    UnreadableDirectory {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
     #[error("Unsupported OS")]
     UnsupportedOS(&'static str),
+
+    #[error("Verification failed: {mismatched} of {total} files do not match the manifest")]
+    VerificationFailed {
+        mismatched: usize,
+        total: usize,
+    },
+}
+
+/// The broad cause of an error, for callers that handle errors
+/// programmatically without matching every [XcpError]; see
+/// [XcpError::kind] and [error_kind].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// Invalid or conflicting options.
+    Usage,
+    /// A source can't be copied as given.
+    InvalidSource,
+    /// The destination conflicts with a source or an existing file.
+    DestinationConflict,
+    /// The destination filesystem is full.
+    DestinationFull,
+    PermissionDenied,
+    NotFound,
+    /// The copy doesn't match its source or manifest.
+    Verification,
+    TimedOut,
+    /// The operation isn't supported by the OS or filesystem.
+    Unsupported,
+    /// The copy was stopped, e.g. by another error that has already
+    /// been reported.
+    Aborted,
+    Other,
+}
+
+impl XcpError {
+    /// A stable identifier for the error, for callers that branch on
+    /// it, e.g. from '--progress=json' events or journal records.
+    /// Codes are snake_case and never reused or renamed; each variant
+    /// has its own. See also [XcpError::sub_code].
+    pub fn code(&self) -> &'static str {
+        match self {
+            XcpError::ConflictingOptions(..) => "conflicting_options",
+            XcpError::CopyError(_) => "copy_error",
+            XcpError::CopyFailed { .. } => "copy_failed",
+            XcpError::CopyStalled(..) => "copy_stalled",
+            XcpError::DanglingDestination(..) => "dangling_destination",
+            XcpError::DestinationCollision(..) => "destination_collision",
+            XcpError::DestinationExists { .. } => "destination_exists",
+            XcpError::DestinationNewer(_) => "destination_newer",
+            XcpError::DestinationLocked { .. } => "destination_locked",
+            XcpError::DestinationFull { .. } => "insufficient_space",
+            XcpError::EarlyShutdown(_) => "early_shutdown",
+            XcpError::ErrorRateExceeded { .. } => "error_rate_exceeded",
+            XcpError::ImmutableDestination { .. } => "immutable_destination",
+            XcpError::InvalidArguments(_) => "invalid_arguments",
+            XcpError::InvalidName(..) => "invalid_name",
+            XcpError::InvalidDestination { .. } => "invalid_destination",
+            XcpError::InvalidSource { .. } => "invalid_source",
+            XcpError::NoSources => "no_sources",
+            XcpError::NotConfirmed(_) => "not_confirmed",
+            XcpError::OverlappingDestination(..) => "overlapping_destination",
+            XcpError::ReadFailed { .. } => "read_failed",
+            XcpError::ReflinkFailed { .. } => "reflink_failed",
+            XcpError::SourceNotFound(_) => "source_not_found",
+            XcpError::SourceTruncated { .. } => "source_truncated",
+            XcpError::SymlinkLoop(_) => "symlink_loop",
+            XcpError::TimedOut(_) => "timed_out",
+            XcpError::FileTimedOut(..) => "file_timed_out",
+            XcpError::TooManyErrors(_) => "too_many_errors",
+            XcpError::TreesDiffer { .. } => "trees_differ",
+            XcpError::UnknownDriver(_) => "unknown_driver",
+            XcpError::UnknownFileType(_) => "unknown_file_type",
+            XcpError::UnreadableDirectory { .. } => "unreadable_directory",
+            XcpError::UnsupportedOS(_) => "unsupported_os",
+            XcpError::VerificationFailed { .. } => "verification_failed",
+        }
+    }
+
+    /// A stable identifier for the I/O error underlying this one, if
+    /// any and it is one we distinguish, e.g. `permission_denied` for
+    /// a [XcpError::CopyFailed].
+    pub fn sub_code(&self) -> Option<&'static str> {
+        match self {
+            XcpError::CopyFailed { source, .. }
+                | XcpError::ReadFailed { source, .. }
+                | XcpError::ReflinkFailed { source, .. }
+                | XcpError::UnreadableDirectory { source, .. } => io_sub_code(source),
+            _ => None,
+        }
+    }
+
+    /// The broad cause of the error. Errors wrapping an I/O error are
+    /// classified by it, e.g. as [ErrorKind::PermissionDenied].
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            _ if self.is_usage() => ErrorKind::Usage,
+            XcpError::CopyFailed { source, .. }
+                | XcpError::ReadFailed { source, .. }
+                | XcpError::UnreadableDirectory { source, .. } => io_kind(source),
+            XcpError::InvalidSource { .. }
+                | XcpError::NoSources
+                | XcpError::SourceNotFound(_)
+                | XcpError::InvalidName(..)
+                | XcpError::SourceTruncated { .. }
+                | XcpError::SymlinkLoop(_)
+                | XcpError::UnknownFileType(_) => ErrorKind::InvalidSource,
+            XcpError::DanglingDestination(..)
+                | XcpError::DestinationCollision(..)
+                | XcpError::DestinationExists { .. }
+                | XcpError::DestinationNewer(_)
+                | XcpError::DestinationLocked { .. }
+                | XcpError::InvalidDestination { .. }
+                | XcpError::OverlappingDestination(..) => ErrorKind::DestinationConflict,
+            XcpError::DestinationFull { .. } => ErrorKind::DestinationFull,
+            XcpError::ImmutableDestination { .. } => ErrorKind::PermissionDenied,
+            XcpError::TreesDiffer { .. }
+                | XcpError::VerificationFailed { .. } => ErrorKind::Verification,
+            XcpError::CopyStalled(..)
+                | XcpError::TimedOut(_)
+                | XcpError::FileTimedOut(..) => ErrorKind::TimedOut,
+            XcpError::ReflinkFailed { .. }
+                | XcpError::UnsupportedOS(_) => ErrorKind::Unsupported,
+            XcpError::EarlyShutdown(_)
+                | XcpError::ErrorRateExceeded { .. }
+                | XcpError::NotConfirmed(_)
+                | XcpError::TooManyErrors(_) => ErrorKind::Aborted,
+            _ => ErrorKind::Other,
+        }
+    }
+
+    /// Whether the error is due to invalid command-line arguments,
+    /// rather than a failure of the copy.
+    pub fn is_usage(&self) -> bool {
+        matches!(self,
+                 XcpError::ConflictingOptions(..)
+                 | XcpError::InvalidArguments(_)
+                 | XcpError::UnknownDriver(_))
+    }
+
+    /// The source path the error relates to, if known.
+    pub fn source_path(&self) -> Option<&Path> {
+        match self {
+            XcpError::OverlappingDestination(source, _)
+                | XcpError::CopyFailed { from: source, .. }
+                | XcpError::CopyStalled(source, _)
+                | XcpError::FileTimedOut(source, _)
+                | XcpError::DestinationCollision(source, ..)
+                | XcpError::InvalidName(source, _)
+                | XcpError::InvalidSource { path: source, .. }
+                | XcpError::SourceNotFound(source)
+                | XcpError::ReadFailed { path: source, .. }
+                | XcpError::ReflinkFailed { from: source, .. }
+                | XcpError::SourceTruncated { path: source, .. }
+                | XcpError::UnknownFileType(source)
+                | XcpError::UnreadableDirectory { path: source, .. } => Some(source),
+            XcpError::SymlinkLoop(chain) => chain.first().map(PathBuf::as_path),
+            _ => None,
+        }
+    }
+
+    /// The destination path the error relates to, if known.
+    pub fn dest_path(&self) -> Option<&Path> {
+        match self {
+            XcpError::DanglingDestination(dest, _)
+                | XcpError::CopyFailed { to: dest, .. }
+                | XcpError::DestinationExists { path: dest, .. }
+                | XcpError::InvalidDestination { path: dest, .. }
+                | XcpError::ReflinkFailed { to: dest, .. }
+                | XcpError::DestinationCollision(_, _, dest)
+                | XcpError::DestinationNewer(dest)
+                | XcpError::DestinationLocked { path: dest, .. }
+                | XcpError::DestinationFull { path: dest, .. }
+                | XcpError::ImmutableDestination { path: dest, .. }
+                | XcpError::OverlappingDestination(_, dest) => Some(dest),
+            _ => None,
+        }
+    }
+}
+
+fn display_pid(pid: &Option<u32>) -> String {
+    pid.map_or_else(String::new, |pid| format!(" (pid {})", pid))
+}
+
+// The paths of a symlink loop, in order.
+fn display_chain(chain: &[PathBuf]) -> String {
+    chain.iter()
+        .map(|p| format!("{:?}", p))
+        .collect::<Vec<String>>()
+        .join(" -> ")
+}
+
+/// An [XcpError::InvalidArguments] for an unrecognised option value,
+/// listing the `expected` values and suggesting the closest if it is
+/// likely to be a typo.
+pub fn unexpected_value(option: &str, value: &str, expected: &[&str]) -> XcpError {
+    let mut msg = format!("Unexpected value for '{}': {}; expected one of {}", option, value, expected.join(", "));
+    if let Some(close) = closest(value, expected) {
+        msg.push_str(&format!(" (did you mean '{}'?)", close));
+    }
+    XcpError::InvalidArguments(msg)
+}
+
+// The candidate closest to `value` by edit distance, if it is close
+// enough to be a plausible typo.
+fn closest<'a>(value: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    let value = value.to_lowercase();
+    candidates.iter()
+        .map(|c| (edit_distance(&value, c), *c))
+        .filter(|(d, c)| *d > 0 && *d <= cmp::max(1, c.len() / 3))
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, c)| c)
+}
+
+// Levenshtein distance between two strings, by character.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<char>>();
+    let mut prev = (0..=b.len()).collect::<Vec<usize>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut row = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let subst = prev[j] + usize::from(ca != *cb);
+            row[j + 1] = cmp::min(subst, cmp::min(prev[j + 1], row[j]) + 1);
+        }
+        prev = row;
+    }
+    prev[b.len()]
+}
+
+/// The broad cause of any error; see [XcpError::kind]. Errors that
+/// aren't an [XcpError] are classified by the first I/O error in
+/// their chain, if any.
+pub fn error_kind(err: &anyhow::Error) -> ErrorKind {
+    if let Some(e) = err.downcast_ref::<XcpError>() {
+        return e.kind();
+    }
+    io_cause(err).map_or(ErrorKind::Other, |e| io_kind(&e))
+}
+
+/// The code of any error; see [XcpError::code]. Errors that aren't an
+/// [XcpError] are `io_error` if caused by an I/O error, and `other`
+/// otherwise.
+pub fn error_code(err: &anyhow::Error) -> &'static str {
+    match err.downcast_ref::<XcpError>() {
+        Some(e) => e.code(),
+        None if io_cause(err).is_some() => "io_error",
+        None => "other",
+    }
+}
+
+/// The sub-code of any error; see [XcpError::sub_code]. Errors that
+/// aren't an [XcpError] take it from the first I/O error in their
+/// chain.
+pub fn error_sub_code(err: &anyhow::Error) -> Option<&'static str> {
+    match err.downcast_ref::<XcpError>() {
+        Some(e) => e.sub_code(),
+        None => io_cause(err).as_ref().and_then(io_sub_code),
+    }
+}
+
+fn io_sub_code(err: &io::Error) -> Option<&'static str> {
+    let code = match err.kind() {
+        _ if is_no_space(err) => "no_space",
+        _ if is_exists(err) => "already_exists",
+        _ if is_not_dir(err) => "not_a_directory",
+        _ if is_dir_error(err) => "is_a_directory",
+        _ if is_io_error(err) => "io_error",
+        _ if is_unsupported(err) => "unsupported",
+        io::ErrorKind::PermissionDenied => "permission_denied",
+        io::ErrorKind::NotFound => "not_found",
+        io::ErrorKind::TimedOut => "timed_out",
+        io::ErrorKind::Unsupported => "unsupported",
+        io::ErrorKind::Interrupted => "interrupted",
+        _ => return None,
+    };
+    Some(code)
+}
+
+fn io_kind(err: &io::Error) -> ErrorKind {
+    match err.kind() {
+        _ if is_no_space(err) => ErrorKind::DestinationFull,
+        _ if is_exists(err) => ErrorKind::DestinationConflict,
+        io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+        io::ErrorKind::NotFound => ErrorKind::NotFound,
+        io::ErrorKind::TimedOut => ErrorKind::TimedOut,
+        io::ErrorKind::Unsupported => ErrorKind::Unsupported,
+        _ => ErrorKind::Other,
+    }
+}
+
+// A copy of the first I/O error in the chain, including the errors
+// wrapped by libfs. OS errors are copied exactly; others keep their
+// kind and message.
+fn io_cause(err: &anyhow::Error) -> Option<io::Error> {
+    err.chain().find_map(|e| {
+        if let Some(ioe) = e.downcast_ref::<io::Error>() {
+            return Some(copy_io_error(ioe));
+        }
+        match e.downcast_ref::<libfs::Error>() {
+            Some(libfs::Error::IOError(ioe)) => Some(copy_io_error(ioe)),
+            Some(libfs::Error::OSError(errno)) => Some(io::Error::from_raw_os_error(errno.raw_os_error())),
+            _ => None,
+        }
+    })
+}
+
+fn copy_io_error(err: &io::Error) -> io::Error {
+    match err.raw_os_error() {
+        Some(errno) => io::Error::from_raw_os_error(errno),
+        None => io::Error::new(err.kind(), err.to_string()),
+    }
+}
+
+/// Convert a copy error into an [XcpError] for sending as a status
+/// update, preserving the details of errors we handle specifically.
+pub(crate) fn status_error(err: &anyhow::Error) -> XcpError {
+    match err.downcast_ref::<XcpError>() {
+        Some(XcpError::DestinationFull { path, written, needed }) => XcpError::DestinationFull {
+            path: path.clone(),
+            written: *written,
+            needed: *needed,
+        },
+        Some(XcpError::FileTimedOut(path, secs)) => XcpError::FileTimedOut(path.clone(), *secs),
+        Some(XcpError::InvalidSource { path, reason }) => XcpError::InvalidSource { path: path.clone(), reason },
+        Some(XcpError::SourceNotFound(path)) => XcpError::SourceNotFound(path.clone()),
+        Some(XcpError::InvalidDestination { path, reason }) => XcpError::InvalidDestination { path: path.clone(), reason },
+        Some(XcpError::ImmutableDestination { path, reason }) => XcpError::ImmutableDestination { path: path.clone(), reason },
+        Some(XcpError::ReadFailed { path, offset, source }) => XcpError::ReadFailed {
+            path: path.clone(),
+            offset: *offset,
+            source: copy_io_error(source),
+        },
+        _ => XcpError::CopyError(err.to_string()),
+    }
+}
+
+/// As [status_error], for an error copying `from` to `to`. An I/O
+/// error is reported as [XcpError::CopyFailed], keeping the paths
+/// and the original error.
+pub(crate) fn copy_error(err: &anyhow::Error, from: &Path, to: &Path) -> XcpError {
+    if err.downcast_ref::<XcpError>().is_some() {
+        return status_error(err);
+    }
+    match io_cause(err) {
+        Some(source) => XcpError::CopyFailed {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+            message: err.to_string(),
+            source,
+        },
+        None => status_error(err),
+    }
+}
+
+/// Whether the error is [XcpError::DestinationFull].
+pub(crate) fn is_destination_full(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<XcpError>(), Some(XcpError::DestinationFull { .. }))
+}
+
+/// Whether the error is caused by a lack of permission, including
+/// `EPERM` from changing an immutable file.
+pub(crate) fn is_permission_denied(err: &anyhow::Error) -> bool {
+    error_kind(err) == ErrorKind::PermissionDenied
+}
+
+/// Whether the error is [XcpError::DestinationExists].
+pub(crate) fn is_destination_exists(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<XcpError>(), Some(XcpError::DestinationExists { .. }))
+}
+
+/// Whether the error is [XcpError::EarlyShutdown]; these are caused by
+/// another error that has already been reported.
+pub(crate) fn is_early_shutdown(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<XcpError>(), Some(XcpError::EarlyShutdown(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(0, edit_distance("auto", "auto"));
+        assert_eq!(1, edit_distance("alwys", "always"));
+        assert_eq!(2, edit_distance("nevre", "never"));
+        assert_eq!(4, edit_distance("", "skip"));
+        assert_eq!(3, edit_distance("kitten", "sitting"));
+    }
+
+    #[test]
+    fn test_unexpected_value() {
+        let err = unexpected_value("reflink", "Alwys", &["auto", "always", "never"]);
+        assert_eq!("Invalid arguments: Unexpected value for 'reflink': Alwys; expected one of auto, always, never (did you mean 'always'?)",
+                   err.to_string());
+        let err = unexpected_value("reflink", "sometimes", &["auto", "always", "never"]);
+        assert_eq!("Invalid arguments: Unexpected value for 'reflink': sometimes; expected one of auto, always, never",
+                   err.to_string());
+        assert!(err.is_usage());
+        assert_eq!(ErrorKind::Usage, err.kind());
+    }
+
+    // EIO, EACCES and ENOSPC on Linux and the BSDs.
+    const EIO: i32 = 5;
+    const EACCES: i32 = 13;
+    const ENOSPC: i32 = 28;
+
+    #[test]
+    fn test_error_kind() {
+        let denied = || io::Error::from_raw_os_error(EACCES);
+        assert_eq!(ErrorKind::PermissionDenied, error_kind(&denied().into()));
+        assert_eq!(ErrorKind::NotFound, error_kind(&io::Error::from(io::ErrorKind::NotFound).into()));
+        // Including when wrapped by libfs or with context.
+        let nospc = anyhow::Error::from(libfs::Error::IOError(io::Error::from_raw_os_error(ENOSPC)));
+        assert_eq!(ErrorKind::DestinationFull, error_kind(&nospc));
+        assert_eq!(ErrorKind::PermissionDenied, error_kind(&anyhow::Error::from(denied()).context("opening")));
+        assert_eq!(ErrorKind::Other, error_kind(&anyhow::anyhow!("other")));
+
+        let err = anyhow::Error::from(XcpError::InvalidSource {
+            path: PathBuf::from("/src"),
+            reason: "Source does not exist.",
+        });
+        assert_eq!(ErrorKind::InvalidSource, error_kind(&err));
+        match err.downcast_ref::<XcpError>() {
+            Some(XcpError::InvalidSource { path, .. }) => assert_eq!(Path::new("/src"), path),
+            e => panic!("Unexpected error {:?}", e),
+        }
+        assert_eq!("Invalid source: Source does not exist.", err.to_string());
+    }
+
+    #[test]
+    fn test_copy_error_source() {
+        use std::error::Error;
+
+        let (from, to) = (Path::new("/from"), Path::new("/to"));
+        let err = anyhow::Error::from(libfs::Error::IOError(io::Error::from_raw_os_error(EACCES)));
+        let copy = copy_error(&err, from, to);
+        assert!(matches!(&copy, XcpError::CopyFailed { source, .. }
+                         if source.kind() == io::ErrorKind::PermissionDenied));
+        assert_eq!(ErrorKind::PermissionDenied, copy.kind());
+        assert_eq!(Some(from), copy.source_path());
+        assert_eq!(Some(to), copy.dest_path());
+        let source = copy.source().and_then(|e| e.downcast_ref::<io::Error>());
+        assert_eq!(Some(EACCES), source.and_then(io::Error::raw_os_error));
+        assert!(copy.to_string().starts_with("Error copying \"/from\": Permission denied"), "{}", copy);
+
+        // Errors we handle specifically are kept.
+        let full = anyhow::Error::from(XcpError::DestinationFull { path: to.to_path_buf(), written: 1, needed: 2 });
+        assert!(matches!(copy_error(&full, from, to), XcpError::DestinationFull { written: 1, .. }));
+        let read = anyhow::Error::from(XcpError::ReadFailed {
+            path: from.to_path_buf(),
+            offset: 512,
+            source: io::Error::from_raw_os_error(EIO),
+        });
+        let read = copy_error(&read, from, to);
+        assert!(matches!(read, XcpError::ReadFailed { offset: 512, .. }));
+        assert_eq!(Some(from), read.source_path());
+        assert_eq!("read_failed", read.code());
+        assert_eq!(Some("io_error"), read.sub_code());
+        let replaced = anyhow::Error::from(XcpError::InvalidSource { path: from.to_path_buf(), reason: "replaced" });
+        let replaced = copy_error(&replaced, from, to);
+        assert!(matches!(replaced, XcpError::InvalidSource { reason: "replaced", .. }));
+        assert_eq!(ErrorKind::InvalidSource, replaced.kind());
+        let other = copy_error(&anyhow::anyhow!("other"), from, to);
+        assert!(matches!(other, XcpError::CopyError(_)));
+        assert!(other.source().is_none());
+    }
+
+    // One of each variant.
+    fn all_errors() -> Vec<XcpError> {
+        let path = PathBuf::from;
+        let io = || io::Error::from_raw_os_error(EACCES);
+        vec![
+            XcpError::ConflictingOptions("-a", "-b", "reason"),
+            XcpError::CopyError("error".to_string()),
+            XcpError::CopyFailed { from: path("a"), to: path("b"), message: "error".to_string(), source: io() },
+            XcpError::CopyStalled(path("a"), 1),
+            XcpError::DestinationExists { path: path("b"), reason: "reason" },
+            XcpError::DanglingDestination(path("b"), path("c")),
+            XcpError::DestinationCollision(path("a"), path("c"), path("b")),
+            XcpError::DestinationNewer(path("b")),
+            XcpError::DestinationLocked { path: path("b"), pid: None },
+            XcpError::DestinationFull { path: path("b"), written: 1, needed: 2 },
+            XcpError::EarlyShutdown("reason"),
+            XcpError::ErrorRateExceeded { errors: 1, window: 2 },
+            XcpError::ImmutableDestination { path: path("b"), reason: "reason" },
+            XcpError::InvalidArguments("args".to_string()),
+            XcpError::InvalidName(path("a"), "reason"),
+            XcpError::InvalidDestination { path: path("b"), reason: "reason" },
+            XcpError::InvalidSource { path: path("a"), reason: "reason" },
+            XcpError::NoSources,
+            XcpError::SourceNotFound(path("a")),
+            XcpError::NotConfirmed("reason".to_string()),
+            XcpError::OverlappingDestination(path("a"), path("b")),
+            XcpError::ReflinkFailed { from: path("a"), to: path("b"), source: io() },
+            XcpError::ReadFailed { path: path("a"), offset: 0, source: io() },
+            XcpError::SourceTruncated { path: path("a"), offset: 0 },
+            XcpError::TreesDiffer { differing: 1, missing: 0, extra: 0 },
+            XcpError::SymlinkLoop(vec![path("a"), path("a")]),
+            XcpError::TimedOut(1),
+            XcpError::FileTimedOut(path("a"), 1),
+            XcpError::TooManyErrors(1),
+            XcpError::UnknownDriver("driver".to_string()),
+            XcpError::UnknownFileType(path("a")),
+            XcpError::UnreadableDirectory { path: path("a"), source: io() },
+            XcpError::UnsupportedOS("os"),
+            XcpError::VerificationFailed { mismatched: 1, total: 2 },
+        ]
+    }
+
+    #[test]
+    fn test_codes() {
+        let errors = all_errors();
+        let codes = errors.iter().map(XcpError::code).collect::<std::collections::BTreeSet<_>>();
+        assert_eq!(errors.len(), codes.len(), "codes are not unique");
+        for code in &codes {
+            assert!(code.chars().all(|c| c.is_ascii_lowercase() || c == '_'), "{}", code);
+        }
+
+        // Every code, and only these, is documented.
+        let readme = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/../README.md")).unwrap();
+        let documented = readme.lines()
+            .skip_while(|l| *l != "### Error codes")
+            .skip(1)
+            .take_while(|l| !l.starts_with('#'))
+            .filter_map(|l| l.strip_prefix("| `"))
+            .filter_map(|l| l.split('`').next())
+            .collect::<Vec<_>>();
+        let generic = ["io_error", "other"];
+        let subs = ["already_exists", "interrupted", "io_error", "is_a_directory", "no_space", "not_a_directory",
+                    "not_found", "permission_denied", "timed_out", "unsupported"];
+        let mut expected = codes.into_iter().chain(generic).chain(subs).collect::<Vec<_>>();
+        expected.sort();
+        expected.dedup();
+        let mut documented = documented;
+        documented.sort();
+        documented.dedup();
+        assert_eq!(expected, documented);
+    }
+
+    #[test]
+    fn test_sub_codes() {
+        let copy = |errno| XcpError::CopyFailed {
+            from: PathBuf::from("a"),
+            to: PathBuf::from("b"),
+            message: "error".to_string(),
+            source: io::Error::from_raw_os_error(errno),
+        };
+        assert_eq!(Some("permission_denied"), copy(EACCES).sub_code());
+        assert_eq!(Some("no_space"), copy(ENOSPC).sub_code());
+        assert_eq!(Some("io_error"), copy(EIO).sub_code());
+        assert_eq!(None, XcpError::NoSources.sub_code());
+
+        // Other errors are classified by their I/O error, if any.
+        let denied = anyhow::Error::from(io::Error::from_raw_os_error(EACCES)).context("opening");
+        assert_eq!("io_error", error_code(&denied));
+        assert_eq!(Some("permission_denied"), error_sub_code(&denied));
+        let missing = anyhow::Error::from(io::Error::from(io::ErrorKind::NotFound));
+        assert_eq!(Some("not_found"), error_sub_code(&missing));
+        assert_eq!("other", error_code(&anyhow::anyhow!("other")));
+        assert_eq!(None, error_sub_code(&anyhow::anyhow!("other")));
+        let full = anyhow::Error::from(XcpError::DestinationFull { path: PathBuf::from("b"), written: 1, needed: 2 });
+        assert_eq!("insufficient_space", error_code(&full));
+    }
 }
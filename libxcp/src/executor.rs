@@ -0,0 +1,385 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! How the drivers run their work; see [Config::executor].
+//!
+//! A copy runs a tree walker, copy workers or a dispatcher, and with
+//! the `parblock` driver a pool copying blocks. By default each of
+//! these is a thread of its own, started for the copy. Applications
+//! that manage their threads themselves can instead run them on a
+//! [rayon] pool, with the `rayon` feature, and [Executor::Sequential]
+//! runs everything on the calling thread in a fixed order, which is
+//! useful for reproducing bugs that depend on timing.
+//!
+//! Work is passed to the executor as `Send + 'static` closures, so
+//! everything shared with it is held in an [Arc]; the [Config],
+//! including the executor, must be `Send + Sync` for the same reason.
+//!
+//! [Config]: crate::config::Config
+//! [Config::executor]: crate::config::Config::executor
+//! [Arc]: std::sync::Arc
+
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::result;
+use std::str::FromStr;
+use std::thread::{self, JoinHandle};
+
+use blocking_threadpool::{Builder, ThreadPool};
+#[cfg(feature = "rayon")]
+use crossbeam_channel as cbc;
+#[cfg(feature = "rayon")]
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use crate::errors::{unexpected_value, XcpError};
+
+/// How the drivers run their work; see the [module
+/// documentation](self).
+#[derive(Clone, Default)]
+pub enum Executor {
+    /// Start a thread for each task, and a pool of threads for
+    /// blocks. This is the default.
+    #[default]
+    Threads,
+    /// Run tasks and blocks on an existing rayon pool, so the number
+    /// of threads used across the process is bounded by it. The
+    /// copy can be started from one of the pool's threads; it runs
+    /// queued work while it waits. Tasks such as copy workers
+    /// occupy a pool thread until the copy completes, so a pool
+    /// with fewer threads than [Config::workers] limits the
+    /// parallelism of the copy.
+    ///
+    /// [Config::workers]: crate::config::Config::workers
+    #[cfg(feature = "rayon")]
+    Rayon(Arc<rayon::ThreadPool>),
+    /// Run everything on the calling thread: the whole source tree
+    /// is walked before anything is copied, and then each file and
+    /// each block is copied in turn. This is slow, but the order of
+    /// operations doesn't vary between runs.
+    Sequential,
+}
+
+impl fmt::Debug for Executor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Executor::Threads => write!(f, "Threads"),
+            #[cfg(feature = "rayon")]
+            Executor::Rayon(pool) => write!(f, "Rayon({} threads)", pool.current_num_threads()),
+            Executor::Sequential => write!(f, "Sequential"),
+        }
+    }
+}
+
+impl FromStr for Executor {
+    type Err = XcpError;
+
+    /// Parses `threads` and `sequential`, and with the `rayon`
+    /// feature `rayon`, which creates a pool of the default size.
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "threads" => Ok(Executor::Threads),
+            "sequential" => Ok(Executor::Sequential),
+            #[cfg(feature = "rayon")]
+            "rayon" => rayon::ThreadPoolBuilder::new()
+                .build()
+                .map(|pool| Executor::Rayon(Arc::new(pool)))
+                .map_err(|e| XcpError::InvalidArguments(format!("Failed to create rayon pool: {}", e))),
+            _ => Err(unexpected_value("executor", s, &["threads", "sequential", #[cfg(feature = "rayon")] "rayon"])),
+        }
+    }
+}
+
+impl Executor {
+    /// Start `task`, returning a handle to wait for its result. With
+    /// [Executor::Sequential] it is run before returning.
+    pub(crate) fn spawn<T, F>(&self, task: F) -> Task<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        match self {
+            Executor::Threads => Task::Thread(thread::spawn(task)),
+            #[cfg(feature = "rayon")]
+            Executor::Rayon(pool) => {
+                let (tx, rx) = cbc::bounded(1);
+                // FIFO, so a task waiting on those started before it
+                // can't be run ahead of them.
+                pool.spawn_fifo(move || {
+                    let _ = tx.send(panic::catch_unwind(AssertUnwindSafe(task)));
+                });
+                Task::Pool(pool.clone(), rx)
+            }
+            Executor::Sequential => Task::Done(panic::catch_unwind(AssertUnwindSafe(task))),
+        }
+    }
+
+    /// A pool to run `workers` jobs at once, queueing at most
+    /// `queue_len` more before [Pool::execute] blocks.
+    pub(crate) fn pool(&self, workers: usize, queue_len: Option<usize>) -> Pool {
+        match self {
+            Executor::Threads => {
+                let mut builder = Builder::new().num_threads(workers);
+                if let Some(len) = queue_len {
+                    builder = builder.queue_len(len);
+                }
+                Pool::Threads(builder.build())
+            }
+            #[cfg(feature = "rayon")]
+            Executor::Rayon(pool) => Pool::Rayon {
+                pool: pool.clone(),
+                limit: workers + queue_len.unwrap_or(usize::MAX - workers),
+                pending: Arc::new((Mutex::new(0), Condvar::new())),
+            },
+            Executor::Sequential => Pool::Sequential,
+        }
+    }
+
+    /// Whether this is called from a thread of the pool the work is
+    /// run on.
+    pub(crate) fn on_pool(&self) -> bool {
+        match self {
+            #[cfg(feature = "rayon")]
+            Executor::Rayon(pool) => pool.current_thread_index().is_some(),
+            _ => false,
+        }
+    }
+
+    /// Run a queued job of the pool, as work a caller on one of its
+    /// threads is waiting for may be queued behind it. Returns whether
+    /// a job was run.
+    pub(crate) fn yield_now(&self) -> bool {
+        match self {
+            #[cfg(feature = "rayon")]
+            Executor::Rayon(pool) => matches!(pool.yield_now(), Some(rayon::Yield::Executed)),
+            _ => false,
+        }
+    }
+}
+
+/// A task started by [Executor::spawn].
+pub(crate) enum Task<T> {
+    Thread(JoinHandle<T>),
+    #[cfg(feature = "rayon")]
+    Pool(Arc<rayon::ThreadPool>, cbc::Receiver<thread::Result<T>>),
+    Done(thread::Result<T>),
+}
+
+impl<T> Task<T> {
+    /// Wait for the task to complete. As with [JoinHandle::join] this
+    /// fails if the task panicked.
+    pub(crate) fn join(self) -> thread::Result<T> {
+        match self {
+            Task::Thread(handle) => handle.join(),
+            #[cfg(feature = "rayon")]
+            Task::Pool(pool, rx) => {
+                let dropped = || -> thread::Result<T> { Err(Box::new("Task dropped without completing")) };
+                wait_in(&pool, || match rx.try_recv() {
+                    Ok(result) => Some(result),
+                    Err(e) if e.is_empty() => None,
+                    Err(_) => Some(dropped()),
+                }, |timeout| match rx.recv_timeout(timeout) {
+                    Ok(result) => Some(result),
+                    Err(e) if e.is_timeout() => None,
+                    Err(_) => Some(dropped()),
+                })
+            }
+            Task::Done(result) => result,
+        }
+    }
+}
+
+/// Runs the blocks of files; see [Executor::pool].
+pub(crate) enum Pool {
+    Threads(ThreadPool),
+    #[cfg(feature = "rayon")]
+    Rayon {
+        pool: Arc<rayon::ThreadPool>,
+        limit: usize,
+        /// The number of jobs queued or running.
+        pending: Arc<(Mutex<usize>, Condvar)>,
+    },
+    Sequential,
+}
+
+impl Pool {
+    /// Run `job` on the pool, or with [Executor::Sequential] before
+    /// returning. Jobs are started in the order they are passed.
+    pub(crate) fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+        match self {
+            Pool::Threads(pool) => pool.execute(job),
+            #[cfg(feature = "rayon")]
+            Pool::Rayon { pool, limit, pending } => {
+                let (count, changed) = &**pending;
+                wait_in(pool, || {
+                    let mut n = count.lock().unwrap();
+                    (*n < *limit).then(|| *n += 1)
+                }, |timeout| {
+                    let mut n = changed.wait_timeout(count.lock().unwrap(), timeout).unwrap().0;
+                    (*n < *limit).then(|| *n += 1)
+                });
+                let done = Completion(pending.clone());
+                pool.spawn_fifo(move || {
+                    let _done = done;
+                    job();
+                });
+            }
+            Pool::Sequential => job(),
+        }
+    }
+
+    /// Wait for all jobs to complete.
+    pub(crate) fn join(&self) {
+        match self {
+            Pool::Threads(pool) => pool.join(),
+            #[cfg(feature = "rayon")]
+            Pool::Rayon { pool, pending, .. } => {
+                let (count, changed) = &**pending;
+                wait_in(pool, || (*count.lock().unwrap() == 0).then_some(()), |timeout| {
+                    let n = changed.wait_timeout(count.lock().unwrap(), timeout).unwrap().0;
+                    (*n == 0).then_some(())
+                });
+            }
+            Pool::Sequential => {}
+        }
+    }
+}
+
+// Marks a job of a rayon pool complete when dropped, including if it
+// panics.
+#[cfg(feature = "rayon")]
+struct Completion(Arc<(Mutex<usize>, Condvar)>);
+
+#[cfg(feature = "rayon")]
+impl Drop for Completion {
+    fn drop(&mut self) {
+        let (count, changed) = &*self.0;
+        *count.lock().unwrap() -= 1;
+        changed.notify_all();
+    }
+}
+
+/// How long to block waiting on a rayon pool before checking for
+/// queued work again.
+pub(crate) const POOL_WAIT: Duration = Duration::from_millis(10);
+
+// Wait until `ready` returns a value. On a thread of `pool` queued
+// work is run while waiting, as the work waited for may be queued
+// behind it; otherwise this blocks in `wait`, which should give up
+// after the timeout it is passed.
+#[cfg(feature = "rayon")]
+fn wait_in<R>(
+    pool: &rayon::ThreadPool,
+    mut ready: impl FnMut() -> Option<R>,
+    mut wait: impl FnMut(Duration) -> Option<R>,
+) -> R {
+    loop {
+        if let Some(r) = ready() {
+            return r;
+        }
+        let ran = pool.current_thread_index().is_some()
+            && matches!(pool.yield_now(), Some(rayon::Yield::Executed));
+        if !ran {
+            if let Some(r) = wait(POOL_WAIT) {
+                return r;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    fn executors() -> Vec<Executor> {
+        vec![
+            Executor::Threads,
+            #[cfg(feature = "rayon")]
+            Executor::Rayon(Arc::new(rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap())),
+            Executor::Sequential,
+        ]
+    }
+
+    #[test]
+    fn test_spawn_join() {
+        for executor in executors() {
+            let task = executor.spawn(|| 42);
+            assert_eq!(42, task.join().unwrap());
+            let task = executor.spawn(|| -> u32 { panic!("task failed") });
+            assert!(task.join().is_err(), "{:?}", executor);
+        }
+    }
+
+    #[test]
+    fn test_pool_order() {
+        for executor in executors() {
+            let pool = executor.pool(1, Some(2));
+            let order = Arc::new(Mutex::new(Vec::new()));
+            for i in 0..20 {
+                let order = order.clone();
+                pool.execute(move || order.lock().unwrap().push(i));
+            }
+            pool.join();
+            let mut order = order.lock().unwrap().clone();
+            // Only a sequential pool is guaranteed to finish the jobs
+            // in order.
+            if !matches!(executor, Executor::Sequential) {
+                order.sort();
+            }
+            assert_eq!((0..20).collect::<Vec<_>>(), order, "{:?}", executor);
+        }
+    }
+
+    #[test]
+    fn test_pool_join() {
+        for executor in executors() {
+            let pool = executor.pool(4, None);
+            let done = Arc::new(AtomicUsize::new(0));
+            for _ in 0..100 {
+                let done = done.clone();
+                pool.execute(move || {
+                    thread::yield_now();
+                    done.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+            pool.join();
+            assert_eq!(100, done.load(Ordering::Relaxed), "{:?}", executor);
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_rayon_nested() {
+        // A single thread, which is also the caller; waiting must run
+        // the queued work rather than deadlocking.
+        let rpool = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+        let executor = Executor::Rayon(rpool.clone());
+        let total = rpool.install(|| {
+            let pool = executor.pool(1, Some(1));
+            let done = Arc::new(AtomicUsize::new(0));
+            for _ in 0..10 {
+                let done = done.clone();
+                pool.execute(move || { done.fetch_add(1, Ordering::Relaxed); });
+            }
+            pool.join();
+            let task = executor.spawn(move || done.load(Ordering::Relaxed));
+            task.join().unwrap()
+        });
+        assert_eq!(10, total);
+    }
+}
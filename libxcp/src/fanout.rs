@@ -0,0 +1,537 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Copying a source to several destinations at once, reading each
+//! file only once. Each block read is queued to a writer for every
+//! destination, and its buffer is released once all of them have
+//! written it.
+//!
+//! Destinations fail independently. An error writing to one is
+//! reported with [StatusUpdate::DestError] and, unless
+//! [Config::continue_on_error] is set, nothing more is written to it;
+//! the other destinations are still completed. Files are always copied
+//! through userspace, so reflinks and sparse files are not preserved.
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use crossbeam_channel as cbc;
+use libfs::{classify_entry, sync, FileType};
+use log::{debug, error, warn};
+use walkdir::WalkDir;
+
+use crate::config::{Config, DirMode};
+use crate::dirs::DirCache;
+use crate::errors::{copy_error, is_early_shutdown, status_error, Result, XcpError};
+use crate::feedback::{StatusUpdate, StatusUpdater};
+use crate::immutable::copy_flags_last;
+use crate::operations::{
+    apply_dir_metadata, apply_overrides, copy_metadata, copy_special, copy_symlink, create_dest,
+    skip_existing, Abort, NEXT_FILE_ID,
+};
+use crate::paths::{dest_names, ignore_filter, parse_ignore, pattern_filter};
+
+/// The number of blocks that may be queued for each destination, so
+/// that a slow destination only briefly holds up the others.
+const QUEUE_BLOCKS: u64 = 2;
+
+// Directories created by the walk, with their target in each
+// destination by index.
+type CreatedDirs = Vec<(PathBuf, Vec<(usize, PathBuf)>)>;
+
+// A file to copy, and its path relative to the source.
+struct Job {
+    from: PathBuf,
+    rel: PathBuf,
+}
+
+// A destination file being written.
+struct Output {
+    dest: usize,
+    to: PathBuf,
+    fd: File,
+}
+
+struct Fanout {
+    config: Arc<Config>,
+    updater: Arc<dyn StatusUpdater>,
+    abort: Abort,
+    /// The path the source is copied to in each destination.
+    targets: Vec<PathBuf>,
+    /// Destinations that are no longer written to.
+    failed: Vec<AtomicBool>,
+    /// The read size. Each worker holds up to [QUEUE_BLOCKS] per
+    /// destination, plus the block being read and those being
+    /// written.
+    block_size: u64,
+}
+
+/// Copy `source` to each of `dests`, reading it once. As with the
+/// drivers, `source` is copied under its basename if a destination is
+/// an existing directory; see [dest_names].
+///
+/// A [StatusUpdate::Size] is sent once for each file, rather than for
+/// each destination, and [StatusUpdate::DestCopied] as each block is
+/// written to a destination. Errors reading the source apply to every
+/// destination, and are reported with [StatusUpdate::Error]; unless
+/// [Config::continue_on_error] is set they stop the copy.
+pub fn copy_fanout(
+    source: &Path,
+    dests: &[PathBuf],
+    config: &Arc<Config>,
+    updater: &Arc<dyn StatusUpdater>,
+) -> Result<()> {
+    let mut targets = Vec::with_capacity(dests.len());
+    for dest in dests {
+        let name = dest_names(&[source.to_path_buf()], dest, config)?.pop().flatten();
+        targets.push(name.map_or_else(|| dest.clone(), |n| dest.join(n)));
+    }
+    let plan = config.buffer_plan();
+    let per_file = QUEUE_BLOCKS * dests.len() as u64 + 2;
+    let fanout = Arc::new(Fanout {
+        config: config.clone(),
+        updater: updater.clone(),
+        abort: Abort::new(config),
+        targets,
+        failed: dests.iter().map(|_| AtomicBool::new(false)).collect(),
+        block_size: (plan.block_size / per_file).max(1),
+    });
+
+    let (work_tx, work_rx) = cbc::bounded(plan.workers * 2);
+    let workers = (0..plan.workers)
+        .map(|_| {
+            let fanout = fanout.clone();
+            let work_rx = work_rx.clone();
+            thread::spawn(move || fanout.worker(work_rx))
+        })
+        .collect::<Vec<_>>();
+    drop(work_rx);
+
+    let mut result = fanout.walk(source, work_tx);
+    for worker in workers {
+        let r = worker.join()
+            .map_err(|_| XcpError::CopyError("Error during fan-out copy".to_string()))?;
+        if let Err(e) = r {
+            // The walk stops early when a worker fails; report the
+            // cause.
+            if result.as_ref().map_or_else(is_early_shutdown, |_| true) {
+                result = Err(e);
+            }
+        }
+    }
+    fanout.abort.check_timeout()?;
+    let dirs = result?;
+
+    // As with the drivers, directory metadata is applied once their
+    // contents are complete.
+    for (from, targets) in dirs.iter().rev() {
+        for (dest, to) in targets {
+            if !fanout.is_failed(*dest) {
+                apply_dir_metadata(from, to, config);
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Fanout {
+    fn is_failed(&self, dest: usize) -> bool {
+        self.failed[dest].load(Ordering::Relaxed)
+    }
+
+    // The destinations still being written to, and the path of `rel`
+    // in each.
+    fn live(&self, rel: &Path) -> Vec<(usize, PathBuf)> {
+        self.targets.iter().enumerate()
+            .filter(|(dest, _)| !self.is_failed(*dest))
+            .map(|(dest, target)| {
+                let to = if rel.as_os_str().is_empty() { target.clone() } else { target.join(rel) };
+                (dest, to)
+            })
+            .collect()
+    }
+
+    // Report an error writing to a destination. Unless continuing
+    // after errors, nothing more is written to it.
+    fn dest_error(&self, dest: usize, from: &Path, to: &Path, err: &anyhow::Error) -> Result<()> {
+        if skip_existing(err, from, to, &self.config, &self.updater)? {
+            return Ok(());
+        }
+        if !self.config.continue_on_error && self.failed[dest].swap(true, Ordering::Relaxed) {
+            // Already reported.
+            debug!("Ignoring error from failed destination {:?}: {}", self.targets[dest], err);
+            return Ok(());
+        }
+        error!("Error copying {:?} to {:?}: {}", from, to, err);
+        self.updater.send(StatusUpdate::DestError { dest, error: copy_error(err, from, to) })
+    }
+
+    // Report an error with the source. Unless continuing after
+    // errors, the copy is stopped.
+    fn source_error(&self, err: anyhow::Error) -> Result<()> {
+        self.updater.send(StatusUpdate::Error(status_error(&err)))?;
+        if self.config.continue_on_error {
+            return Ok(());
+        }
+        self.abort.set();
+        Err(err)
+    }
+
+    // Walk the source, creating directories, links and special files
+    // in each destination and sending files to the workers. Returns the
+    // directories created, with their targets by destination.
+    fn walk(&self, source: &Path, work_tx: cbc::Sender<Job>) -> Result<CreatedDirs> {
+        let gitignore = parse_ignore(source, &self.config)?;
+        let dircache = DirCache::new(&self.config);
+        let mut dirs = Vec::new();
+
+        for entry in WalkDir::new(source)
+            .follow_links(self.config.dereference)
+            .follow_root_links(self.config.dereference || self.config.dereference_sources)
+            .same_file_system(self.config.one_file_system)
+            .into_iter()
+            .filter_entry(|e| ignore_filter(e, &gitignore) && pattern_filter(e, source, &self.config.filter))
+        {
+            if self.abort.is_set() {
+                debug!("Copy aborted, stopping walk");
+                return Err(XcpError::EarlyShutdown("fan-out copy aborted").into());
+            }
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    let path = err.path().map_or_else(|| source.to_path_buf(), Path::to_path_buf);
+                    self.source_error(XcpError::UnreadableDirectory { path, source: err.into() }.into())?;
+                    continue;
+                }
+            };
+            let from = entry.path().to_path_buf();
+            let rel = from.strip_prefix(source)?.to_path_buf();
+
+            // Entries of unknown type, on filesystems that don't
+            // record it, are statted.
+            match classify_entry(&*self.config.fs, &from, entry.file_type())? {
+                FileType::File => {
+                    let len = entry.metadata()?.len();
+                    self.updater.send(StatusUpdate::Size(len))?;
+                    if work_tx.send(Job { from, rel }).is_err() {
+                        // The workers have stopped after an error.
+                        return Err(XcpError::EarlyShutdown("fan-out workers stopped").into());
+                    }
+                }
+                FileType::Dir => {
+                    let mut created = Vec::new();
+                    for (dest, to) in self.live(&rel) {
+                        debug!("Creating target directory {:?}", to);
+                        match dircache.ensure(&to) {
+                            Ok(true) => created.push((dest, to)),
+                            Ok(false) if self.config.dir_mode == DirMode::Overwrite => created.push((dest, to)),
+                            Ok(false) => {}
+                            Err(e) => self.dest_error(dest, &from, &to, &e.into())?,
+                        }
+                    }
+                    dirs.push((from, created));
+                }
+                FileType::Symlink => {
+                    for (dest, to) in self.live(&rel) {
+                        if let Err(e) = copy_symlink(&from, &to, &self.config) {
+                            self.dest_error(dest, &from, &to, &e)?;
+                        }
+                    }
+                }
+                FileType::Socket | FileType::Char | FileType::Fifo => {
+                    for (dest, to) in self.live(&rel) {
+                        if let Err(e) = copy_special(&from, &to, &self.config) {
+                            self.dest_error(dest, &from, &to, &e)?;
+                        }
+                    }
+                }
+                FileType::Block | FileType::Other => {
+                    self.source_error(XcpError::UnknownFileType(from).into())?;
+                }
+            }
+        }
+        Ok(dirs)
+    }
+
+    fn worker(&self, work_rx: cbc::Receiver<Job>) -> Result<()> {
+        for job in work_rx {
+            if self.abort.is_set() {
+                break;
+            }
+            let id = NEXT_FILE_ID.fetch_add(1, Ordering::Relaxed);
+            self.updater.send(StatusUpdate::FileStarted(id, job.from.clone()))?;
+            let result = self.copy_file(&job);
+            // Progress is reported per destination, as
+            // [StatusUpdate::DestCopied], so none of it is copied bytes.
+            self.updater.send(StatusUpdate::FileCompleted(id, 0))?;
+            match result {
+                Err(e) if is_early_shutdown(&e) => break,
+                Err(e) => self.source_error(e)?,
+                Ok(()) => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Copy a file to each destination. Only errors with the source
+    // are returned; those with a destination are reported with
+    // [Fanout::dest_error].
+    fn copy_file(&self, job: &Job) -> Result<()> {
+        let infd = File::open(&job.from)?;
+        let meta = infd.metadata()?;
+        let len = meta.len();
+
+        let mut outs = Vec::new();
+        for (dest, to) in self.live(&job.rel) {
+            match create_dest(&to, &self.config, None, Some(&meta)) {
+                Ok((fd, _)) => outs.push(Output { dest, to, fd }),
+                Err(e) => self.dest_error(dest, &job.from, &to, &e)?,
+            }
+        }
+        if outs.is_empty() {
+            return Ok(());
+        }
+
+        let (read, written) = if len <= self.block_size {
+            self.write_whole(&infd, &outs)
+        } else {
+            self.write_blocks(&infd, &outs)
+        };
+
+        for (out, written) in outs.iter().zip(written) {
+            // A read error applies to every destination, and is
+            // returned rather than reported for each.
+            let result = match read {
+                Ok(()) => written.and_then(|_| self.finalise(&infd, out)),
+                Err(_) => Err(XcpError::EarlyShutdown("source read failed").into()),
+            };
+            if let Err(e) = result {
+                debug!("Removing partial file {:?}", out.to);
+                if let Err(e) = fs::remove_file(&out.to) {
+                    warn!("Failed to remove partial file {:?}: {}", out.to, e);
+                }
+                if read.is_ok() {
+                    self.dest_error(out.dest, &job.from, &out.to, &e)?;
+                }
+            }
+        }
+        read
+    }
+
+    // Copy a file that fits in a single block, writing it to each
+    // destination in turn. Returns the result of reading the source,
+    // and of writing each destination.
+    fn write_whole(&self, infd: &File, outs: &[Output]) -> (Result<()>, Vec<Result<()>>) {
+        let mut buf = Vec::new();
+        if let Err(e) = infd.take(self.block_size).read_to_end(&mut buf) {
+            return (Err(e.into()), outs.iter().map(|_| Ok(())).collect());
+        }
+        let written = outs.iter()
+            .map(|out| {
+                (&out.fd).write_all(&buf)?;
+                self.updater.send(StatusUpdate::DestCopied { dest: out.dest, bytes: buf.len() as u64 })
+            })
+            .collect();
+        (Ok(()), written)
+    }
+
+    // Read a file a block at a time, queueing each block to a writer
+    // for every destination. A destination that fails is dropped and
+    // the rest continue. Returns as [Fanout::write_whole].
+    fn write_blocks(&self, infd: &File, outs: &[Output]) -> (Result<()>, Vec<Result<()>>) {
+        thread::scope(|s| {
+            let mut queues = Vec::with_capacity(outs.len());
+            let mut writers = Vec::with_capacity(outs.len());
+            for out in outs {
+                let (tx, rx) = cbc::bounded::<Arc<Vec<u8>>>(QUEUE_BLOCKS as usize);
+                queues.push(Some(tx));
+                writers.push(s.spawn(move || -> Result<()> {
+                    for block in rx {
+                        (&out.fd).write_all(&block)?;
+                        self.updater.send(StatusUpdate::DestCopied { dest: out.dest, bytes: block.len() as u64 })?;
+                    }
+                    Ok(())
+                }));
+            }
+
+            let read = (|| -> Result<()> {
+                loop {
+                    if self.abort.is_set() {
+                        return Err(XcpError::EarlyShutdown("fan-out copy aborted").into());
+                    }
+                    let mut buf = Vec::with_capacity(self.block_size as usize);
+                    if infd.take(self.block_size).read_to_end(&mut buf)? == 0 {
+                        return Ok(());
+                    }
+                    let block = Arc::new(buf);
+                    for queue in queues.iter_mut() {
+                        // A writer that has stopped has failed; its
+                        // error is returned when it is joined.
+                        if queue.as_ref().is_some_and(|tx| tx.send(block.clone()).is_err()) {
+                            *queue = None;
+                        }
+                    }
+                    if queues.iter().all(Option::is_none) {
+                        return Ok(());
+                    }
+                }
+            })();
+            drop(queues);
+
+            let written = writers.into_iter()
+                .map(|w| w.join().unwrap_or_else(|_| Err(XcpError::CopyError("Writer thread failed".to_string()).into())))
+                .collect();
+            (read, written)
+        })
+    }
+
+    // Copy the metadata of a completed file.
+    fn finalise(&self, infd: &File, out: &Output) -> Result<()> {
+        copy_metadata(&out.to, infd, &out.fd, &self.config)?;
+        apply_overrides(&out.to, &out.fd, false, &self.config)?;
+        if self.config.fsync {
+            debug!("Syncing file {:?}", out.to);
+            sync(&out.fd)?;
+        }
+        copy_flags_last(&out.to, infd, &out.fd, &self.config);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libfs::{FaultInjectingFs, FsOp};
+    use std::sync::Mutex;
+    use tempfile::TempDir;
+
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<StatusUpdate>>);
+
+    impl StatusUpdater for Collect {
+        fn send(&self, update: StatusUpdate) -> Result<()> {
+            self.0.lock().unwrap().push(update);
+            Ok(())
+        }
+    }
+
+    fn copied(updates: &[StatusUpdate], dest: usize) -> u64 {
+        updates.iter()
+            .filter_map(|u| match u {
+                StatusUpdate::DestCopied { dest: d, bytes } if *d == dest => Some(*bytes),
+                _ => None,
+            })
+            .sum()
+    }
+
+    #[test]
+    fn test_copy_fanout() -> Result<()> {
+        let dir = TempDir::new()?;
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub"))?;
+        let big = (0..200_000u32).flat_map(u32::to_le_bytes).collect::<Vec<u8>>();
+        fs::write(source.join("small.txt"), "small")?;
+        fs::write(source.join("sub/big.bin"), &big)?;
+        std::os::unix::fs::symlink("small.txt", source.join("link"))?;
+
+        let dests = (0..3).map(|i| dir.path().join(format!("dest{}", i))).collect::<Vec<_>>();
+        let config = Arc::new(Config {
+            block_size: 64 * 1024,
+            ..Config::default()
+        });
+        let collect = Arc::new(Collect::default());
+        let updater: Arc<dyn StatusUpdater> = collect.clone();
+        copy_fanout(&source, &dests, &config, &updater)?;
+
+        for dest in &dests {
+            assert_eq!("small", fs::read_to_string(dest.join("small.txt"))?);
+            assert_eq!(big, fs::read(dest.join("sub/big.bin"))?);
+            assert_eq!(Path::new("small.txt"), fs::read_link(dest.join("link"))?);
+        }
+        let updates = collect.0.lock().unwrap();
+        for i in 0..dests.len() {
+            assert_eq!(big.len() as u64 + 5, copied(&updates, i));
+        }
+        let size = updates.iter()
+            .filter_map(|u| match u { StatusUpdate::Size(s) => Some(*s), _ => None })
+            .sum::<u64>();
+        assert_eq!(big.len() as u64 + 5, size);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fanout_unknown_dirent_types() -> Result<()> {
+        let dir = TempDir::new()?;
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub/deeper"))?;
+        fs::write(source.join("top.txt"), "top")?;
+        fs::write(source.join("sub/deeper/file.txt"), "deep")?;
+        std::os::unix::fs::symlink("sub", source.join("link"))?;
+
+        let faults = Arc::new(FaultInjectingFs::new());
+        faults.hide_dirent_types();
+        let config = Arc::new(Config {
+            fs: faults.clone(),
+            ..Config::default()
+        });
+        let dests = vec![dir.path().join("dest0"), dir.path().join("dest1")];
+        let updater: Arc<dyn StatusUpdater> = Arc::new(Collect::default());
+        copy_fanout(&source, &dests, &config, &updater)?;
+
+        for dest in &dests {
+            assert_eq!("top", fs::read_to_string(dest.join("top.txt"))?);
+            assert_eq!("deep", fs::read_to_string(dest.join("sub/deeper/file.txt"))?);
+            assert_eq!(Path::new("sub"), fs::read_link(dest.join("link"))?);
+        }
+        // Each entry, including the source itself, was statted.
+        assert_eq!(6, faults.calls(FsOp::Stat));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fanout_failed_dest() -> Result<()> {
+        let dir = TempDir::new()?;
+        let source = dir.path().join("source");
+        fs::create_dir_all(&source)?;
+        for i in 0..4 {
+            fs::write(source.join(format!("file{}.txt", i)), "data")?;
+        }
+        // The second destination can't be created, as its parent is a
+        // file.
+        fs::write(dir.path().join("blocker"), "")?;
+        let dests = vec![dir.path().join("good"), dir.path().join("blocker/bad")];
+
+        let collect = Arc::new(Collect::default());
+        let updater: Arc<dyn StatusUpdater> = collect.clone();
+        copy_fanout(&source, &dests, &Arc::new(Config::default()), &updater)?;
+
+        for i in 0..4 {
+            assert_eq!("data", fs::read_to_string(dests[0].join(format!("file{}.txt", i)))?);
+        }
+        let updates = collect.0.lock().unwrap();
+        let errors = updates.iter()
+            .filter(|u| matches!(u, StatusUpdate::DestError { dest: 1, .. }))
+            .count();
+        assert_eq!(1, errors);
+        assert!(!updates.iter().any(|u| matches!(u, StatusUpdate::Error(_) | StatusUpdate::DestError { dest: 0, .. })));
+        assert_eq!(0, copied(&updates, 1));
+        Ok(())
+    }
+}
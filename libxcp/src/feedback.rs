@@ -23,11 +23,21 @@
 //!
 //! * [NoopUpdater]
 //! * [ChannelUpdater]
+//!
+//! [BatchedUpdater] can wrap either of these to reduce the number of
+//! updates sent by each copy worker.
 
+use std::fmt;
+use std::ops::Range;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 use crossbeam_channel as cbc;
+use serde::Serialize;
 
+use crate::checksum::FileChecksum;
+use crate::compare::Item;
 use crate::config::Config;
 use crate::errors::{Result, XcpError};
 
@@ -39,10 +49,238 @@ pub enum StatusUpdate {
     Copied(u64),
     /// An update representing that this number of bytes will need to be copied.
     Size(u64),
+    /// The number of bytes to be copied, previously sent as
+    /// [StatusUpdate::Size], has changed by this amount. This is
+    /// negative for files that are skipped or fail, and for holes in
+    /// sparse files, so the copied total still reaches the size.
+    TotalAdjust(i64),
+    /// Copying of a file has started. The first value is an id that
+    /// is unique for the lifetime of the process, and will be
+    /// repeated in the matching [StatusUpdate::FileCompleted]. The
+    /// path is the source file.
+    FileStarted(u64, PathBuf),
+    /// A file of this size was reflinked rather than copied. A
+    /// matching [StatusUpdate::Copied] is also sent.
+    Reflinked(u64),
+    /// This many bytes of a range copy were reflinked rather than
+    /// copied. A matching [StatusUpdate::Copied] is also sent.
+    RangeCloned(u64),
+    /// A file of this size appears to have been copied server-side
+    /// on a network filesystem. This is inferred from a single
+    /// `copy_file_range` call completing faster than the data could
+    /// have crossed the network. [StatusUpdate::Copied] is sent
+    /// separately.
+    Offloaded(u64),
+    /// The bytes written for a file, and the time taken, by source
+    /// and destination device (`st_dev`). Sent once per file,
+    /// before [StatusUpdate::FileCompleted].
+    DeviceCopied {
+        source: u64,
+        dest: u64,
+        bytes: u64,
+        elapsed: Duration,
+    },
+    /// Copying of a file has finished, successfully or otherwise.
+    /// The first value is the id from [StatusUpdate::FileStarted],
+    /// and the second the number of bytes of the file sent in
+    /// [StatusUpdate::Copied]. Bytes copied more than once, e.g. when
+    /// a failed block is copied again another way, are only counted
+    /// once in both, so the sum of these should always match the sum
+    /// of the copied bytes.
+    ///
+    /// For a file copied successfully this is only sent once the
+    /// destination is complete: its data has been written, its
+    /// metadata applied, it has been synced if configured and moved
+    /// into place if staged, and it has been closed. It can be read
+    /// as soon as this is received, and the [StatusUpdate::Copied]
+    /// bytes for it have been sent before it. A file that fails,
+    /// including while its metadata is applied or it is synced, has
+    /// a [StatusUpdate::Error] sent before this instead.
+    FileCompleted(u64, u64),
+    /// This many destination directories will have their source
+    /// metadata applied once their entries are complete. The
+    /// metadata of a file is applied before its
+    /// [StatusUpdate::FileCompleted].
+    MetadataPending(u64),
+    /// The metadata of this many directories, previously counted in a
+    /// [StatusUpdate::MetadataPending], has been applied. These are
+    /// sent in batches. Once all data has been copied this may be the
+    /// only progress, e.g. for large trees.
+    MetadataDone(u64),
+    /// The walk of the source tree is complete; every file to be
+    /// copied has been included in a [StatusUpdate::Size], and every
+    /// directory in a [StatusUpdate::MetadataPending].
+    WalkCompleted,
+    /// The checksum of a completed file; only sent if
+    /// [Config::checksum] is set.
+    Checksum(FileChecksum),
+    /// A difference between a source entry and the destination; only
+    /// sent if [Config::itemize] is set.
+    Item(Item),
+    /// An existing destination was left untouched; only sent with
+    /// [NoClobber::Skip]. `bytes` is the size of the source file, which
+    /// was included in [StatusUpdate::Size] but will not be copied; a
+    /// matching [StatusUpdate::TotalAdjust] is also sent. `source` is
+    /// the entry that would have been copied; it isn't known for
+    /// hard-links, which are made from the first copy.
+    ///
+    /// [NoClobber::Skip]: crate::config::NoClobber::Skip
+    Skipped {
+        path: PathBuf,
+        bytes: u64,
+        source: Option<PathBuf>,
+    },
+    /// An existing destination file with a newer modification time
+    /// than its source will be overwritten. Not sent with
+    /// [Config::update], which skips these files, or with
+    /// [Config::forbid_overwrite_newer], which reports an error
+    /// instead.
+    OverwritingNewer {
+        path: PathBuf,
+        source_mtime: SystemTime,
+        dest_mtime: SystemTime,
+    },
+    /// An entry was given a different destination name, as the
+    /// original could not be represented; only sent with
+    /// [InvalidName::Sanitize]. `from` is the destination path the
+    /// entry would have had.
+    ///
+    /// [InvalidName::Sanitize]: crate::config::InvalidName::Sanitize
+    Renamed {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// An entry was copied under a new name as `from` exists; only
+    /// sent with [NoClobber::Rename].
+    ///
+    /// [NoClobber::Rename]: crate::config::NoClobber::Rename
+    KeptBoth {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// A source entry was skipped, as its name could not be
+    /// represented or collides with another in the destination
+    /// directory; only sent with [InvalidName::Skip].
+    ///
+    /// [InvalidName::Skip]: crate::config::InvalidName::Skip
+    NameSkipped(PathBuf),
+    /// A source directory was reached by a second path while
+    /// following symlinks, so was copied again; only sent with
+    /// [Config::dereference]. Sent once the walk has left it, with the
+    /// bytes of the files copied again beneath it.
+    Duplicated {
+        path: PathBuf,
+        original: PathBuf,
+        bytes: u64,
+    },
+    /// Bytes written to one of the destinations of a fan-out copy, by
+    /// index; see [crate::fanout].
+    DestCopied {
+        dest: usize,
+        bytes: u64,
+    },
+    /// An error writing to one of the destinations of a fan-out copy.
+    /// Unless [Config::continue_on_error] is set, nothing more is
+    /// written to that destination.
+    DestError {
+        dest: usize,
+        error: XcpError,
+    },
+    /// Parts of a source file could not be read, and were written to
+    /// its copy at `path` as zeros; only sent with
+    /// [Config::fill_errors]. The ranges are sorted byte offsets in
+    /// the file. Sent before the matching
+    /// [StatusUpdate::FileCompleted].
+    BadRanges {
+        path: PathBuf,
+        ranges: Vec<Range<u64>>,
+    },
+    /// A change made to the destination; only sent if
+    /// [Config::report_actions] is set.
+    Action(Action),
     /// An error during a copy operation.
     Error(XcpError)
 }
 
+/// A change made to the destination, for auditing; see
+/// [StatusUpdate::Action]. Skipped entries and errors are reported
+/// by their own [StatusUpdate] variants.
+#[derive(Debug)]
+pub enum Action {
+    /// A file was copied in full, and its metadata applied. `bytes`
+    /// is the number written, which excludes any holes in a sparse
+    /// file. Sent before the matching [StatusUpdate::FileCompleted].
+    FileCopied {
+        from: PathBuf,
+        to: PathBuf,
+        bytes: u64,
+        method: CopyMethod,
+    },
+    /// A destination directory was created.
+    DirCreated(PathBuf),
+    /// A symlink was created from the source symlink.
+    SymlinkCreated {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// A socket, FIFO or device node was created.
+    SpecialCreated {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// A file was not copied as the destination is up to date; see
+    /// [Config::update] and [Config::skip_same].
+    UpToDate(PathBuf),
+    /// Some of the source metadata could not be applied to a copied
+    /// file; the copy is otherwise complete.
+    MetadataDegraded {
+        path: PathBuf,
+        detail: String,
+    },
+    /// An extraneous destination entry was removed; see
+    /// [Config::delete].
+    Deleted(PathBuf),
+}
+
+/// How the data of a file was copied; see [Action::FileCopied].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CopyMethod {
+    /// Cloned with a reflink, sharing the source blocks.
+    Reflink,
+    /// Copied by the server of a network filesystem.
+    ServerSide,
+    /// Copied by the kernel, e.g. with `copy_file_range`.
+    Kernel,
+    /// Copied in parallel blocks by the kernel.
+    Blocks,
+    /// Copied by the kernel, skipping holes in the source.
+    Sparse,
+    /// Read and written through userspace, to hash the data.
+    Userspace,
+    /// A block device imaged sequentially.
+    Image,
+    /// A tiny file read during the walk, and written in a single
+    /// call; see [Config::tiny_file_fastpath].
+    Inline,
+}
+
+impl fmt::Display for CopyMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            CopyMethod::Reflink => "reflink",
+            CopyMethod::ServerSide => "server-side",
+            CopyMethod::Kernel => "kernel",
+            CopyMethod::Blocks => "blocks",
+            CopyMethod::Sparse => "sparse",
+            CopyMethod::Userspace => "userspace",
+            CopyMethod::Image => "image",
+            CopyMethod::Inline => "inline",
+        };
+        f.write_str(s)
+    }
+}
+
 pub trait StatusUpdater: Sync + Send {
     fn send(&self, update: StatusUpdate) -> Result<()>;
 }
@@ -57,6 +295,9 @@ pub struct ChannelUpdater {
     chan_rx: cbc::Receiver<StatusUpdate>,
     config: Arc<Config>,
     sent: AtomicU64,
+    /// Copied bytes not yet sent; these are sent in at least
+    /// block-sized updates, and on drop.
+    pending: AtomicU64,
 }
 
 impl ChannelUpdater {
@@ -68,6 +309,7 @@ impl ChannelUpdater {
             chan_rx,
             config: config.clone(),
             sent: AtomicU64::new(0),
+            pending: AtomicU64::new(0),
         }
     }
 
@@ -94,17 +336,119 @@ impl StatusUpdater for ChannelUpdater {
         if let StatusUpdate::Copied(bytes) = update {
             // Avoid saturating the queue with small writes
             let bsize = self.config.block_size;
+            self.pending.fetch_add(bytes, Ordering::Relaxed);
             let prev_written = self.sent.fetch_add(bytes, Ordering::Relaxed);
             if ((prev_written + bytes) / bsize) > (prev_written / bsize) {
-                self.chan_tx.send(update)?;
+                let bytes = self.pending.swap(0, Ordering::Relaxed);
+                if bytes > 0 {
+                    self.chan_tx.send(StatusUpdate::Copied(bytes))?;
+                }
             }
         } else {
+            // Progress shouldn't lag behind the file lifecycle.
+            let bytes = self.pending.swap(0, Ordering::Relaxed);
+            if bytes > 0 {
+                self.chan_tx.send(StatusUpdate::Copied(bytes))?;
+            }
             self.chan_tx.send(update)?;
         }
         Ok(())
     }
 }
 
+impl Drop for ChannelUpdater {
+    fn drop(&mut self) {
+        let bytes = self.pending.swap(0, Ordering::Relaxed);
+        if bytes > 0 {
+            let _ = self.chan_tx.send(StatusUpdate::Copied(bytes));
+        }
+    }
+}
+
+/// How long a [BatchedUpdater] holds copied bytes before passing
+/// them on.
+pub const BATCH_FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+const CLOCK_CHECK_UPDATES: u64 = 16;
+
+/// A [StatusUpdater] for a single copy worker, which accumulates
+/// [StatusUpdate::Copied] bytes locally and passes them to the
+/// wrapped updater in batches. This avoids every worker sending, and
+/// contending on the shared updater, for each block.
+///
+/// Pending bytes are flushed when they reach `flush_bytes`, when
+/// [BATCH_FLUSH_INTERVAL] has passed since the last flush, before any
+/// other update, such as file start and completion, and on drop. The
+/// bytes copied for a file therefore always arrive before its
+/// completion.
+pub struct BatchedUpdater {
+    inner: Arc<dyn StatusUpdater>,
+    flush_bytes: u64,
+    pending: AtomicU64,
+    /// Copied updates since the last flush; the clock is only checked
+    /// every [CLOCK_CHECK_UPDATES] to keep small updates cheap.
+    updates: AtomicU64,
+    start: Instant,
+    /// Nanoseconds from `start` to the last flush.
+    last_flush: AtomicU64,
+}
+
+impl BatchedUpdater {
+    pub fn new(inner: Arc<dyn StatusUpdater>, flush_bytes: u64) -> BatchedUpdater {
+        BatchedUpdater {
+            inner,
+            flush_bytes,
+            pending: AtomicU64::new(0),
+            updates: AtomicU64::new(0),
+            start: Instant::now(),
+            last_flush: AtomicU64::new(0),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        u64::try_from(self.start.elapsed().as_nanos()).unwrap_or(u64::MAX)
+    }
+
+    fn interval_passed(&self) -> bool {
+        let updates = self.updates.fetch_add(1, Ordering::Relaxed) + 1;
+        updates % CLOCK_CHECK_UPDATES == 0
+            && self.now().saturating_sub(self.last_flush.load(Ordering::Relaxed)) >= BATCH_FLUSH_INTERVAL.as_nanos() as u64
+    }
+
+    /// Send any pending bytes.
+    pub fn flush(&self) -> Result<()> {
+        self.updates.store(0, Ordering::Relaxed);
+        self.last_flush.store(self.now(), Ordering::Relaxed);
+        let bytes = self.pending.swap(0, Ordering::Relaxed);
+        if bytes > 0 {
+            self.inner.send(StatusUpdate::Copied(bytes))?;
+        }
+        Ok(())
+    }
+}
+
+impl StatusUpdater for BatchedUpdater {
+    fn send(&self, update: StatusUpdate) -> Result<()> {
+        if let StatusUpdate::Copied(bytes) = update {
+            let pending = self.pending.fetch_add(bytes, Ordering::Relaxed) + bytes;
+            if pending >= self.flush_bytes || self.interval_passed() {
+                self.flush()?;
+            }
+            Ok(())
+        } else {
+            self.flush()?;
+            self.inner.send(update)
+        }
+    }
+}
+
+impl Drop for BatchedUpdater {
+    fn drop(&mut self) {
+        // The receiver may have gone away if the copy was aborted.
+        let _ = self.flush();
+    }
+}
+
 /// A null updater for when no feedback is required.
 pub struct NoopUpdater;
 
@@ -113,3 +457,146 @@ impl StatusUpdater for NoopUpdater {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::thread;
+
+    // Records updates for inspection.
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<StatusUpdate>>);
+
+    impl StatusUpdater for Recorder {
+        fn send(&self, update: StatusUpdate) -> Result<()> {
+            self.0.lock().unwrap().push(update);
+            Ok(())
+        }
+    }
+
+    fn copied(updates: &[StatusUpdate]) -> Vec<u64> {
+        updates.iter()
+            .filter_map(|u| match u {
+                StatusUpdate::Copied(b) => Some(*b),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_channel_updater_totals() -> Result<()> {
+        let config = Arc::new(Config { block_size: 1000, ..Config::default() });
+        let updater = ChannelUpdater::new(&config);
+        let rx = updater.rx_channel();
+        for _ in 0..25 {
+            updater.send(StatusUpdate::Copied(30))?;
+        }
+        updater.send(StatusUpdate::FileCompleted(1, 750))?;
+        drop(updater);
+
+        let updates = rx.iter().collect::<Vec<_>>();
+        let sent = copied(&updates);
+        assert_eq!(750, sent.iter().sum::<u64>());
+        assert!(sent.len() < 25);
+        // All the bytes arrive before the completion.
+        assert!(matches!(updates.last(), Some(StatusUpdate::FileCompleted(1, 750))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_batched_updater() -> Result<()> {
+        let recorder = Arc::new(Recorder::default());
+        let batched = BatchedUpdater::new(recorder.clone(), 1000);
+
+        for _ in 0..25 {
+            batched.send(StatusUpdate::Copied(100))?;
+        }
+        assert_eq!(vec![1000, 1000], copied(&recorder.0.lock().unwrap()));
+
+        // Other updates aren't delayed, and flush the pending bytes
+        // first.
+        batched.send(StatusUpdate::FileCompleted(1, 2500))?;
+        {
+            let updates = recorder.0.lock().unwrap();
+            assert_eq!(vec![1000, 1000, 500], copied(&updates));
+            assert!(matches!(updates.last(), Some(StatusUpdate::FileCompleted(1, 2500))));
+        }
+        batched.send(StatusUpdate::FileStarted(2, PathBuf::from("file")))?;
+        assert_eq!(vec![1000, 1000, 500], copied(&recorder.0.lock().unwrap()));
+
+        // Pending bytes are sent on drop.
+        batched.send(StatusUpdate::Copied(10))?;
+        drop(batched);
+        assert_eq!(vec![1000, 1000, 500, 10], copied(&recorder.0.lock().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_batched_updater_interval() -> Result<()> {
+        let recorder = Arc::new(Recorder::default());
+        let batched = BatchedUpdater::new(recorder.clone(), u64::MAX);
+
+        for _ in 0..CLOCK_CHECK_UPDATES {
+            batched.send(StatusUpdate::Copied(10))?;
+        }
+        assert!(copied(&recorder.0.lock().unwrap()).is_empty());
+        thread::sleep(BATCH_FLUSH_INTERVAL);
+        for _ in 0..CLOCK_CHECK_UPDATES {
+            batched.send(StatusUpdate::Copied(10))?;
+        }
+        assert_eq!(vec![10 * 2 * CLOCK_CHECK_UPDATES], copied(&recorder.0.lock().unwrap()));
+        Ok(())
+    }
+
+    // Compare update throughput from many workers, with and without
+    // batching. Run with:
+    //
+    //     cargo test --release -p libxcp bench_status_updates -- --ignored --nocapture
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_status_updates() -> Result<()> {
+        const WORKERS: usize = 64;
+        const UPDATES: usize = 100_000;
+
+        let config = Arc::new(Config { block_size: 1024 * 1024, ..Config::default() });
+        for batched in [false, true] {
+            let updater = ChannelUpdater::new(&config);
+            let rx = updater.rx_channel();
+            let updater: Arc<dyn StatusUpdater> = Arc::new(updater);
+            let drain = thread::spawn(move || rx.iter().count());
+
+            let start = Instant::now();
+            let workers = (0..WORKERS).map(|_| {
+                let updater = updater.clone();
+                let config = config.clone();
+                thread::spawn(move || -> Result<()> {
+                    let updates: Arc<dyn StatusUpdater> = if batched {
+                        Arc::new(BatchedUpdater::new(updater, config.block_size))
+                    } else {
+                        updater
+                    };
+                    for i in 0..UPDATES {
+                        // Small files; a block of data and its lifecycle.
+                        updates.send(StatusUpdate::Copied(4096))?;
+                        if i % 16 == 0 {
+                            updates.send(StatusUpdate::FileCompleted(i as u64, 4096 * 16))?;
+                        }
+                    }
+                    Ok(())
+                })
+            }).collect::<Vec<_>>();
+            for w in workers {
+                w.join().unwrap()?;
+            }
+            let elapsed = start.elapsed();
+            drop(updater);
+            let received = drain.join().unwrap();
+
+            println!("{}: {:.0} updates/s from {} workers; {} received",
+                     if batched { "Batched" } else { "Unbatched" },
+                     (WORKERS * UPDATES) as f64 / elapsed.as_secs_f64(), WORKERS, received);
+        }
+        Ok(())
+    }
+}
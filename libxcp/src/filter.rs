@@ -0,0 +1,268 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Exclude and include patterns; see [Config::filter].
+//!
+//! Patterns are matched against the path of each entry relative to
+//! the root of the source it was found under, so with several sources
+//! the same patterns apply within each of them. The syntax follows
+//! rsync:
+//!
+//! * A pattern without a `/`, other than a trailing one, matches the
+//!   name of an entry at any depth, e.g. `*.o`.
+//! * Other patterns match the end of the relative path, e.g.
+//!   `src/*.rs` matches both `src/main.rs` and `lib/src/lib.rs`.
+//! * A leading `/` anchors the pattern to the source root, e.g.
+//!   `/build` only matches `build` directly under it.
+//! * A trailing `/` only matches directories.
+//! * `*` and `?` don't match a `/`, while `**` matches any number of
+//!   path components, e.g. `/docs/**/*.html`. `[...]` matches any of
+//!   the characters listed, and `\` escapes a special character.
+//! * `{a,b}` matches either alternative, each treated as a pattern of
+//!   its own, so `{/build/,*.o}` excludes both.
+//!
+//! This is the language of `--glob` sources; see [crate::pattern].
+//!
+//! An entry is excluded if it matches an exclude pattern and no
+//! include pattern. An excluded directory isn't walked, so nothing
+//! under it can be included again.
+//!
+//! [Config::filter]: crate::config::Config::filter
+
+use std::fs::read_to_string;
+use std::path::Path;
+
+use globset::{Candidate, GlobSet, GlobSetBuilder};
+
+use crate::errors::{Result, XcpError};
+use crate::pattern;
+
+/// Compiled exclude and include patterns.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    exclude: Matcher,
+    include: Matcher,
+}
+
+impl Filter {
+    /// Compile the exclude and include patterns. An invalid pattern
+    /// is returned as [XcpError::InvalidArguments].
+    pub fn new(exclude: &[String], include: &[String]) -> Result<Filter> {
+        Ok(Filter {
+            exclude: Matcher::new(exclude)?,
+            include: Matcher::new(include)?,
+        })
+    }
+
+    /// Whether the entry at `rel`, relative to the root of its source,
+    /// is excluded. The root itself never is.
+    pub fn excludes(&self, rel: &Path, is_dir: bool) -> bool {
+        if rel.as_os_str().is_empty() {
+            return false;
+        }
+        let candidate = Candidate::new(rel);
+        self.exclude.is_match(&candidate, is_dir) && !self.include.is_match(&candidate, is_dir)
+    }
+}
+
+// The patterns of one kind, split so that a match needs no
+// allocation.
+#[derive(Clone, Debug, Default)]
+struct Matcher {
+    any: GlobSet,
+    dirs: GlobSet,
+}
+
+impl Matcher {
+    fn new(patterns: &[String]) -> Result<Matcher> {
+        let (mut any, mut dirs) = (GlobSetBuilder::new(), GlobSetBuilder::new());
+        for pattern in patterns {
+            let mut empty = true;
+            for alternative in pattern::parse(pattern)? {
+                let glob = alternative.glob();
+                let dir_only = glob.ends_with('/');
+                let trimmed = glob.trim_end_matches('/');
+                if trimmed.trim_start_matches('/').is_empty() {
+                    continue;
+                }
+                empty = false;
+                let glob = match trimmed.strip_prefix('/') {
+                    Some(anchored) => anchored.to_string(),
+                    None => format!("**/{}", trimmed),
+                };
+                let glob = alternative.build(&glob)?;
+                if dir_only {
+                    dirs.add(glob);
+                } else {
+                    any.add(glob);
+                }
+            }
+            if empty {
+                return Err(XcpError::InvalidArguments(format!("Invalid pattern {:?}: the pattern is empty", pattern)).into());
+            }
+        }
+        let build = |set: GlobSetBuilder| set.build()
+            .map_err(|e| XcpError::InvalidArguments(format!("Invalid patterns: {}", e)));
+        Ok(Matcher { any: build(any)?, dirs: build(dirs)? })
+    }
+
+    fn is_match(&self, candidate: &Candidate, is_dir: bool) -> bool {
+        self.any.is_match_candidate(candidate) || (is_dir && self.dirs.is_match_candidate(candidate))
+    }
+}
+
+/// Read patterns from a file, one per line. Blank lines and lines
+/// starting with `#` are ignored.
+pub fn read_patterns(path: &Path) -> Result<Vec<String>> {
+    let patterns = read_to_string(path)?
+        .lines()
+        .filter(|l| !l.trim().is_empty() && !l.starts_with('#'))
+        .map(str::to_string)
+        .collect();
+    Ok(patterns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use tempfile::TempDir;
+
+    fn patterns(p: &[&str]) -> Vec<String> {
+        p.iter().map(|s| s.to_string()).collect()
+    }
+
+    // (pattern, path, is_dir, excluded)
+    const CASES: &[(&str, &str, bool, bool)] = &[
+        // A name matches at any depth.
+        ("build", "build", true, true),
+        ("build", "build", false, true),
+        ("build", "src/build", true, true),
+        ("build", "a/b/c/build", false, true),
+        ("build", "builder", true, false),
+        ("build", "rebuild", false, false),
+        ("build", "build/file", false, false),
+        // Wildcards don't cross directories.
+        ("*.o", "main.o", false, true),
+        ("*.o", "src/obj/main.o", false, true),
+        ("*.o", "main.obj", false, false),
+        ("*.o", "main.o/file", false, false),
+        ("?.txt", "a.txt", false, true),
+        ("?.txt", "ab.txt", false, false),
+        ("[ab].txt", "b.txt", false, true),
+        ("[ab].txt", "c.txt", false, false),
+        ("[!ab].txt", "c.txt", false, true),
+        ("\\*.txt", "*.txt", false, true),
+        ("\\*.txt", "a.txt", false, false),
+        // A trailing slash only matches directories.
+        ("build/", "build", true, true),
+        ("build/", "build", false, false),
+        ("build/", "src/build", true, true),
+        ("build//", "build", true, true),
+        ("*/", "anydir", true, true),
+        ("*/", "file", false, false),
+        // A leading slash anchors to the source root.
+        ("/build", "build", true, true),
+        ("/build", "src/build", true, false),
+        ("/build/", "build", false, false),
+        ("/*.txt", "a.txt", false, true),
+        ("/*.txt", "sub/a.txt", false, false),
+        ("/src/gen", "src/gen", true, true),
+        ("/src/gen", "lib/src/gen", true, false),
+        // Otherwise a path matches the end of the relative path.
+        ("src/*.rs", "src/main.rs", false, true),
+        ("src/*.rs", "lib/src/lib.rs", false, true),
+        ("src/*.rs", "src/sub/lib.rs", false, false),
+        ("src/*.rs", "mysrc/main.rs", false, false),
+        ("a/b", "a/b", false, true),
+        ("a/b", "x/a/b", false, true),
+        ("a/b", "a/x/b", false, false),
+        // '**' crosses directories.
+        ("**/*.rs", "main.rs", false, true),
+        ("**/*.rs", "a/b/main.rs", false, true),
+        ("/docs/**/*.html", "docs/index.html", false, true),
+        ("/docs/**/*.html", "docs/a/b/index.html", false, true),
+        ("/docs/**/*.html", "other/docs/index.html", false, false),
+        ("/docs/**", "docs/a", false, true),
+        ("/docs/**", "docs/a/b", true, true),
+        ("a/**/b", "a/b", false, true),
+        ("a/**/b", "a/x/y/b", false, true),
+        ("a/**/b", "a/x/y/c", false, false),
+        ("logs/**", "var/logs/today", false, true),
+        // Names with dots and spaces are literal.
+        (".git", ".git", true, true),
+        (".git", "sub/.git", true, true),
+        (".*", ".hidden", false, true),
+        (".*", "visible", false, false),
+        ("my file", "dir/my file", false, true),
+        // Case matters.
+        ("*.TXT", "a.txt", false, false),
+    ];
+
+    #[test]
+    fn test_pattern_table() -> Result<()> {
+        for (pattern, path, is_dir, excluded) in CASES {
+            let filter = Filter::new(&patterns(&[pattern]), &[])?;
+            assert_eq!(*excluded, filter.excludes(Path::new(path), *is_dir),
+                       "{:?} against {:?} (dir: {})", pattern, path, is_dir);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_root_never_excluded() -> Result<()> {
+        let filter = Filter::new(&patterns(&["*", "**"]), &[])?;
+        assert!(!filter.excludes(Path::new(""), true));
+        assert!(filter.excludes(Path::new("a"), true));
+        Ok(())
+    }
+
+    #[test]
+    fn test_include_overrides() -> Result<()> {
+        let filter = Filter::new(&patterns(&["*.log", "/tmp/"]), &patterns(&["keep.log", "/tmp/"]))?;
+        assert!(filter.excludes(Path::new("a.log"), false));
+        assert!(filter.excludes(Path::new("sub/b.log"), false));
+        assert!(!filter.excludes(Path::new("keep.log"), false));
+        assert!(!filter.excludes(Path::new("sub/keep.log"), false));
+        assert!(!filter.excludes(Path::new("tmp"), true));
+        assert!(!filter.excludes(Path::new("a.txt"), false));
+
+        // Includes alone exclude nothing.
+        let filter = Filter::new(&[], &patterns(&["*.rs"]))?;
+        assert!(!filter.excludes(Path::new("a.txt"), false));
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        for pattern in ["", "/", "//", "[a", "a[", "{a,b"] {
+            let err = Filter::new(&patterns(&[pattern]), &[]).unwrap_err();
+            assert!(matches!(err.downcast_ref::<XcpError>(), Some(XcpError::InvalidArguments(_))), "{:?}", pattern);
+        }
+        assert!(Filter::new(&[], &patterns(&["[a"])).is_err());
+    }
+
+    #[test]
+    fn test_read_patterns() -> Result<()> {
+        let dir = TempDir::new()?;
+        let file = dir.path().join("excludes");
+        write(&file, "# Build output\n*.o\n\n/target/\n  \n #x\nmy file\n")?;
+        let read = read_patterns(&file)?;
+        assert_eq!(patterns(&["*.o", "/target/", " #x", "my file"]), read);
+        assert!(read_patterns(&dir.path().join("missing")).is_err());
+        Ok(())
+    }
+}
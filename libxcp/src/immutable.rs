@@ -0,0 +1,116 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Immutable and append-only destinations; see [PreserveSet::FLAGS].
+//!
+//! Nothing about a file with either flag can be changed, so the flags
+//! of a copy are set after everything else. An existing destination
+//! with them fails to be rewritten, renamed or removed with a
+//! permission error, which is only then checked for and reported as
+//! [XcpError::ImmutableDestination] naming the protected entry.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use libfs::{clear_immutable, is_immutable, warn_repeated, Attribute};
+use log::info;
+use walkdir::WalkDir;
+
+use crate::config::{Config, PreserveSet};
+use crate::errors::{is_permission_denied, Result, XcpError};
+use crate::operations::dev_of;
+
+const REWRITE_MSG: &str = "clear the flags with 'chattr -ia', or copy with --preserve=flags to replace them";
+const CLEAR_MSG: &str = "clearing the flags to rewrite it needs root or CAP_LINUX_IMMUTABLE";
+const REMOVE_MSG: &str = "it can't be removed; clear the flags with 'chattr -ia'";
+const DIR_MSG: &str = "entries can't be replaced or removed in it; clear the flags with 'chattr -ia'";
+
+// Whether the file or directory at `path` is immutable or
+// append-only. Symlinks have no flags, and other files aren't opened.
+fn protected(path: &Path) -> bool {
+    path.symlink_metadata()
+        .is_ok_and(|m| (m.is_file() || m.is_dir()) && File::open(path).is_ok_and(|fd| is_immutable(&fd)))
+}
+
+// The protected entry stopping `path` being replaced or removed, if
+// any; this may be its directory or, for a directory, anything in it.
+fn find_protected(path: &Path) -> Option<(PathBuf, &'static str)> {
+    let parent = path.parent().filter(|p| !p.as_os_str().is_empty());
+    if let Some(parent) = parent.filter(|p| protected(p)) {
+        return Some((parent.to_path_buf(), DIR_MSG));
+    }
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .find(|e| protected(e.path()))
+        .map(|e| (e.into_path(), REMOVE_MSG))
+}
+
+/// Report a failure to replace or remove the destination entry
+/// `path` as [XcpError::ImmutableDestination] if it, or its
+/// directory, is protected.
+pub(crate) fn protected_error(path: &Path, err: anyhow::Error) -> anyhow::Error {
+    if !is_permission_denied(&err) {
+        return err;
+    }
+    match find_protected(path) {
+        Some((path, reason)) => XcpError::ImmutableDestination { path, reason }.into(),
+        None => err,
+    }
+}
+
+/// Run `change`, which rewrites the existing destination file `to`.
+/// If it fails because `to` is immutable or append-only, with
+/// [PreserveSet::FLAGS] the flags are cleared and `change` is run
+/// again; the flags of the source are set once the copy is complete.
+/// Otherwise the failure is reported as
+/// [XcpError::ImmutableDestination].
+pub(crate) fn unprotected<T>(to: &Path, config: &Config, mut change: impl FnMut() -> Result<T>) -> Result<T> {
+    let err = match change() {
+        Err(e) if is_permission_denied(&e) => e,
+        done => return done,
+    };
+    // Files are written through a destination symlink.
+    let immutable = File::open(to).ok().filter(is_immutable);
+    let Some(fd) = immutable else {
+        return Err(protected_error(to, err));
+    };
+    if !config.preserve.contains(PreserveSet::FLAGS) {
+        return Err(XcpError::ImmutableDestination { path: to.to_path_buf(), reason: REWRITE_MSG }.into());
+    }
+    info!("Clearing the immutable and append-only flags of {:?} to rewrite it", to);
+    if let Err(e) = clear_immutable(&fd) {
+        let e = anyhow::Error::from(e);
+        return match is_permission_denied(&e) {
+            true => Err(XcpError::ImmutableDestination { path: to.to_path_buf(), reason: CLEAR_MSG }.into()),
+            false => Err(e),
+        };
+    }
+    change()
+}
+
+/// Copy the inode flags from `infd` to `outfd`, the file at `to`,
+/// with [PreserveSet::FLAGS]. This must be the last change made to
+/// the destination. Failing is a warning, and is returned as a
+/// description of what was not copied.
+pub(crate) fn copy_flags_last(to: &Path, infd: &File, outfd: &File, config: &Config) -> Option<String> {
+    if !config.preserve.contains(PreserveSet::FLAGS) {
+        return None;
+    }
+    let err = config.fs.set_metadata(to, infd, outfd, Attribute::Flags).err()?;
+    warn_repeated!(dev_of(outfd.metadata()), "Failed to copy the flags of {:?}: {}", to, err);
+    Some(format!("flags not copied: {}", err))
+}
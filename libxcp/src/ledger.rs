@@ -0,0 +1,121 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Tracking of the bytes of a file already reported as copied.
+//!
+//! A copy method can fail part-way through a file and the rest be
+//! copied another way, e.g. a block recovered through userspace after
+//! `copy_file_range` fails with `EIO`, or a reflink succeeding after
+//! some data was written. The replacement may cover bytes that were
+//! already copied and reported. Recording the ranges acknowledged
+//! means only new bytes are passed on as [StatusUpdate::Copied], so
+//! progress can't exceed the total.
+//!
+//! [StatusUpdate::Copied]: crate::feedback::StatusUpdate::Copied
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// The byte ranges of a file acknowledged as copied.
+#[derive(Debug, Default)]
+pub(crate) struct Ledger {
+    /// Disjoint, non-adjacent ranges, by start.
+    ranges: BTreeMap<u64, u64>,
+    total: u64,
+}
+
+impl Ledger {
+    /// Acknowledge a range as copied, returning the number of its
+    /// bytes not already acknowledged.
+    pub(crate) fn add(&mut self, range: Range<u64>) -> u64 {
+        if range.is_empty() {
+            return 0;
+        }
+        let (mut start, mut end) = (range.start, range.end);
+        let mut covered = 0;
+        // Absorb the ranges overlapping or adjacent to this one; at
+        // most one can start before it.
+        let first = self.ranges.range(..=start).next_back()
+            .filter(|(_, e)| **e >= start)
+            .map_or(start, |(s, _)| *s);
+        let absorbed = self.ranges.range(first..=end)
+            .map(|(s, e)| (*s, *e))
+            .collect::<Vec<_>>();
+        for (s, e) in absorbed {
+            self.ranges.remove(&s);
+            covered += e.min(range.end).saturating_sub(s.max(range.start));
+            start = start.min(s);
+            end = end.max(e);
+        }
+        self.ranges.insert(start, end);
+        let added = (range.end - range.start) - covered;
+        self.total += added;
+        added
+    }
+
+    /// The number of bytes acknowledged.
+    pub(crate) fn total(&self) -> u64 {
+        self.total
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+
+    fn ranges(ledger: &Ledger) -> Vec<Range<u64>> {
+        ledger.ranges.iter().map(|(s, e)| *s..*e).collect()
+    }
+
+    #[test]
+    fn test_ledger_disjoint() {
+        let mut ledger = Ledger::default();
+        assert_eq!(100, ledger.add(0..100));
+        assert_eq!(100, ledger.add(200..300));
+        assert_eq!(0, ledger.add(50..50));
+        assert_eq!(vec![0..100, 200..300], ranges(&ledger));
+        assert_eq!(200, ledger.total());
+    }
+
+    #[test]
+    fn test_ledger_adjacent() {
+        let mut ledger = Ledger::default();
+        assert_eq!(100, ledger.add(100..200));
+        assert_eq!(100, ledger.add(0..100));
+        assert_eq!(100, ledger.add(200..300));
+        assert_eq!(vec![0..300], ranges(&ledger));
+        assert_eq!(300, ledger.total());
+    }
+
+    #[test]
+    fn test_ledger_overlapping() {
+        let mut ledger = Ledger::default();
+        ledger.add(0..100);
+        ledger.add(200..300);
+        ledger.add(400..500);
+        // Repeats add nothing.
+        assert_eq!(0, ledger.add(0..100));
+        assert_eq!(0, ledger.add(210..290));
+        // Only the gaps are new.
+        assert_eq!(50, ledger.add(50..150));
+        assert_eq!(150, ledger.add(120..450));
+        assert_eq!(vec![0..500], ranges(&ledger));
+        assert_eq!(500, ledger.total());
+        assert_eq!(100, ledger.add(0..600));
+        assert_eq!(600, ledger.total());
+    }
+}
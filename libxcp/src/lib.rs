@@ -60,44 +60,144 @@
 //!             StatusUpdate::Size(v) => {
 //!                 println!("Size update: {}", v);
 //!             },
+//!             StatusUpdate::FileStarted(_id, path) => {
+//!                 println!("Copying {:?}", path);
+//!             },
+//!             StatusUpdate::FileCompleted(_id, _bytes) => {},
+//!             StatusUpdate::MetadataPending(n) => {
+//!                 println!("{} directories awaiting metadata", n);
+//!             },
+//!             StatusUpdate::MetadataDone(n) => {
+//!                 println!("Applied metadata to {} directories", n);
+//!             },
+//!             StatusUpdate::WalkCompleted => {
+//!                 println!("All files found");
+//!             },
+//!             StatusUpdate::Reflinked(v) => {
+//!                 println!("Reflinked {} bytes", v);
+//!             },
+//!             StatusUpdate::RangeCloned(v) => {
+//!                 println!("Reflinked {} bytes of range", v);
+//!             },
+//!             StatusUpdate::Offloaded(v) => {
+//!                 println!("Server-side copied {} bytes", v);
+//!             },
+//!             StatusUpdate::DeviceCopied { dest, bytes, .. } => {
+//!                 println!("Wrote {} bytes to device {}", bytes, dest);
+//!             },
+//!             StatusUpdate::Checksum(c) => {
+//!                 println!("Checksum of {:?}: {}", c.path, c.checksum);
+//!             },
+//!             StatusUpdate::Item(i) => {
+//!                 println!("{}", i);
+//!             },
+//!             StatusUpdate::TotalAdjust(v) => {
+//!                 println!("Size adjusted by {} bytes", v);
+//!             },
+//!             StatusUpdate::Skipped { path, .. } => {
+//!                 println!("Skipped existing {:?}", path);
+//!             },
+//!             StatusUpdate::OverwritingNewer { path, .. } => {
+//!                 println!("Overwriting newer {:?}", path);
+//!             },
+//!             StatusUpdate::Renamed { from, to } => {
+//!                 println!("Renamed {:?} to {:?}", from, to);
+//!             },
+//!             StatusUpdate::KeptBoth { from, to } => {
+//!                 println!("Copied to {:?} as {:?} exists", to, from);
+//!             },
+//!             StatusUpdate::NameSkipped(path) => {
+//!                 println!("Skipped invalid name {:?}", path);
+//!             },
+//!             StatusUpdate::Duplicated { path, original, .. } => {
+//!                 println!("Copied {:?} again as {:?}", original, path);
+//!             },
+//!             StatusUpdate::DestCopied { dest, bytes } => {
+//!                 println!("Wrote {} bytes to destination {}", bytes, dest);
+//!             },
+//!             StatusUpdate::DestError { dest, error } => {
+//!                 println!("Error writing destination {}: {}", dest, error);
+//!             },
+//!             StatusUpdate::BadRanges { path, ranges } => {
+//!                 println!("Filled {} unreadable ranges of {:?}", ranges.len(), path);
+//!             },
+//!             StatusUpdate::Action(a) => {
+//!                 println!("Action: {:?}", a);
+//!             },
 //!             StatusUpdate::Error(e) => {
 //!                 panic!("Error during copy: {}", e);
 //!             }
 //!         }
 //!     }
 //!
-//!     handle.join()
+//!     // The driver returns the totals of the files copied.
+//!     let copied = handle.join()
 //!         .map_err(|_| XcpError::CopyError("Error during copy operation".to_string()))??;
 //!
-//!     println!("Copy complete");
+//!     println!("Copy complete: {} files, {} bytes", copied.files, copied.bytes);
 //!
 //!     # Ok(())
 //!     # }
 //!
 //! [xcp]: https://crates.io/crates/xcp/
 
+pub mod checksum;
+pub mod compare;
 pub mod config;
+pub mod conflict;
 pub mod drivers;
 pub mod errors;
+pub mod executor;
+pub mod fanout;
+pub mod filter;
+pub mod lock;
 pub mod feedback;
+pub mod manifest;
+pub mod metrics;
+pub mod names;
+pub mod operations;
+pub mod paths;
+pub mod pattern;
+pub mod plan;
+pub mod profile;
+pub mod rescue;
+pub mod results;
+pub mod space;
+pub mod stamp;
 
 // Internal
 mod backup;
-mod operations;
-mod paths;
+mod deref;
+mod dirs;
+mod errlimit;
+mod immutable;
+mod ledger;
+mod metastage;
+mod readers;
+mod staging;
+mod timestamps;
 
 #[cfg(test)]
 #[allow(unused)]
 mod tests {
-    use std::path::PathBuf;
-    use std::sync::Arc;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
     use std::thread;
+    use std::time::{Duration, Instant};
 
+    use libfs::{FaultInjectingFs, FsOp};
     use tempfile::TempDir;
+    use walkdir::WalkDir;
 
+    use crate::checksum::{checksum_file, ChecksumType};
     use crate::errors::{Result, XcpError};
-    use crate::config::Config;
-    use crate::feedback::{ChannelUpdater, StatusUpdater, StatusUpdate};
+    use crate::config::{Config, NoClobber, Reflink};
+    use crate::executor::Executor;
+    use crate::feedback::{ChannelUpdater, NoopUpdater, StatusUpdater, StatusUpdate};
     use crate::drivers::{Drivers, load_driver};
 
     #[test]
@@ -118,6 +218,8 @@ mod tests {
 
         // Gather the results as we go; our end of the channel has been
         // moved to the driver call and will end when drained.
+        let (mut started, mut completed) = (0, 0);
+        let (mut dirs_pending, mut dirs_done, mut walked) = (0, 0, false);
         for stat in stat_rx {
             match stat {
                 StatusUpdate::Copied(v) => {
@@ -126,6 +228,73 @@ mod tests {
                 StatusUpdate::Size(v) => {
                     println!("Size update: {}", v);
                 },
+                StatusUpdate::FileStarted(_id, path) => {
+                    println!("Copying {:?}", path);
+                    started += 1;
+                },
+                StatusUpdate::FileCompleted(_id, _bytes) => {
+                    completed += 1;
+                },
+                StatusUpdate::MetadataPending(n) => {
+                    dirs_pending += n;
+                },
+                StatusUpdate::MetadataDone(n) => {
+                    dirs_done += n;
+                },
+                StatusUpdate::WalkCompleted => {
+                    walked = true;
+                },
+                StatusUpdate::Reflinked(v) => {
+                    println!("Reflinked {} bytes", v);
+                },
+                StatusUpdate::RangeCloned(v) => {
+                    println!("Reflinked {} bytes of range", v);
+                },
+                StatusUpdate::Offloaded(v) => {
+                    println!("Server-side copied {} bytes", v);
+                },
+                StatusUpdate::DeviceCopied { dest, bytes, .. } => {
+                    println!("Wrote {} bytes to device {}", bytes, dest);
+                },
+                StatusUpdate::Checksum(c) => {
+                    println!("Checksum of {:?}: {}", c.path, c.checksum);
+                },
+                StatusUpdate::Item(i) => {
+                    println!("{}", i);
+                },
+                StatusUpdate::TotalAdjust(v) => {
+                    println!("Size adjusted by {} bytes", v);
+                },
+                StatusUpdate::Skipped { path, .. } => {
+                    println!("Skipped existing {:?}", path);
+                },
+                StatusUpdate::OverwritingNewer { path, .. } => {
+                    println!("Overwriting newer {:?}", path);
+                },
+                StatusUpdate::Renamed { from, to } => {
+                    println!("Renamed {:?} to {:?}", from, to);
+                },
+                StatusUpdate::KeptBoth { from, to } => {
+                    println!("Copied to {:?} as {:?} exists", to, from);
+                },
+                StatusUpdate::NameSkipped(path) => {
+                    println!("Skipped invalid name {:?}", path);
+                },
+                StatusUpdate::Duplicated { path, original, .. } => {
+                    println!("Copied {:?} again as {:?}", original, path);
+                },
+                StatusUpdate::DestCopied { dest, bytes } => {
+                    println!("Wrote {} bytes to destination {}", bytes, dest);
+                },
+                StatusUpdate::DestError { dest, error } => {
+                    println!("Error writing destination {}: {}", dest, error);
+                },
+                StatusUpdate::BadRanges { path, ranges } => {
+                    println!("Filled {} unreadable ranges of {:?}", ranges.len(), path);
+                },
+                StatusUpdate::Action(a) => {
+                    println!("Action: {:?}", a);
+                },
                 StatusUpdate::Error(e) => {
                     println!("Error during copy: {}", e);
                     return Err(e.into());
@@ -137,7 +306,317 @@ mod tests {
             .map_err(|_| XcpError::CopyError("Error during copy operation".to_string()))??;
 
         println!("Copy complete");
+        assert!(started > 0);
+        assert_eq!(started, completed);
+        assert!(walked);
+        assert!(dirs_pending > 0);
+        assert_eq!(dirs_pending, dirs_done);
+
+        Ok(())
+    }
+
+    fn executors() -> Vec<Executor> {
+        vec![
+            Executor::Threads,
+            #[cfg(feature = "rayon")]
+            Executor::Rayon(Arc::new(rayon::ThreadPoolBuilder::new().num_threads(3).build().unwrap())),
+            Executor::Sequential,
+        ]
+    }
+
+    // Copy a tree of small and large files with each driver, returning
+    // the copied and completed bytes reported, and the bytes returned.
+    fn copy_tree(source: &Path, dest: &Path, driver: Drivers, executor: Executor) -> Result<(u64, u64, u64)> {
+        let config = Arc::new(Config {
+            block_size: 4096,
+            workers: 4,
+            executor,
+            ..Config::default()
+        });
+        let updater = ChannelUpdater::new(&config);
+        let rx = updater.rx_channel();
+        let stats = load_driver(driver, &config)?.copy(vec![source.to_path_buf()], dest, Arc::new(updater))?;
+        assert!(stats.results.is_empty());
+        let (mut copied, mut completed) = (0, 0);
+        for update in rx {
+            match update {
+                StatusUpdate::Copied(b) => copied += b,
+                StatusUpdate::FileCompleted(_, b) => completed += b,
+                StatusUpdate::Error(e) => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok((copied, completed, stats.bytes))
+    }
+
+    #[test]
+    fn executor_drivers_test() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = tdir.path().join("src");
+        let mut total = 0;
+        for d in 0..4 {
+            let dir = source.join(format!("dir{}/sub", d));
+            fs::create_dir_all(&dir)?;
+            for f in 0..10 {
+                let data = vec![(d * 10 + f) as u8; f * 1000];
+                total += data.len() as u64;
+                fs::write(dir.join(format!("file{}", f)), data)?;
+            }
+        }
+        let large = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        total += large.len() as u64;
+        fs::write(source.join("large"), &large)?;
+        symlink("large", source.join("link"))?;
+
+        for driver in [Drivers::ParFile, #[cfg(feature = "parblock")] Drivers::ParBlock] {
+            for executor in executors() {
+                let dest = tdir.path().join(format!("{:?}-{:?}", driver, executor));
+                let totals = copy_tree(&source, &dest, driver, executor.clone())?;
+                assert_eq!((total, total, total), totals, "{:?} {:?}", driver, executor);
+                for entry in WalkDir::new(&source) {
+                    let entry = entry?;
+                    let to = dest.join(entry.path().strip_prefix(&source)?);
+                    let meta = entry.path().symlink_metadata()?;
+                    if meta.is_file() {
+                        assert_eq!(fs::read(entry.path())?, fs::read(&to)?, "{:?}", to);
+                    } else if meta.is_symlink() {
+                        assert_eq!(fs::read_link(entry.path())?, fs::read_link(&to)?);
+                    } else {
+                        assert!(to.is_dir());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn executor_rayon_nested_test() -> Result<()> {
+        // The copy is started from the pool's only thread, which must
+        // run the walker and workers itself while it waits.
+        let tdir = TempDir::new()?;
+        let source = tdir.path().join("src");
+        fs::create_dir_all(source.join("sub"))?;
+        fs::write(source.join("sub/a"), vec![1; 50_000])?;
+        fs::write(source.join("b"), vec![2; 10])?;
+
+        let pool = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+        for driver in [Drivers::ParFile, #[cfg(feature = "parblock")] Drivers::ParBlock] {
+            let dest = tdir.path().join(format!("{:?}", driver));
+            let totals = pool.install(|| copy_tree(&source, &dest, driver, Executor::Rayon(pool.clone())))?;
+            assert_eq!((50_010, 50_010, 50_010), totals);
+            assert_eq!(vec![1; 50_000], fs::read(dest.join("sub/a"))?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn file_results_test() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = tdir.path().join("file");
+        fs::write(&source, vec![7; 20_000])?;
+
+        for driver in [Drivers::ParFile, #[cfg(feature = "parblock")] Drivers::ParBlock] {
+            let dest = tdir.path().join(format!("{:?}", driver));
+            fs::write(&dest, "existing")?;
+            let config = Arc::new(Config {
+                collect_results: true,
+                no_clobber: Some(NoClobber::Rename),
+                ..Config::default()
+            });
+            let stats = load_driver(driver, &config)?.copy(vec![source.clone()], &dest, Arc::new(NoopUpdater))?;
+            assert_eq!((1, 20_000), (stats.files, stats.bytes));
+
+            // Written alongside the existing file.
+            let [result] = stats.results.as_slice() else {
+                panic!("{:?}", stats.results);
+            };
+            assert_eq!(source, result.from);
+            assert_ne!(dest, result.to);
+            assert_eq!(vec![7; 20_000], fs::read(&result.to)?);
+            assert_eq!(b"existing", fs::read(&dest)?.as_slice());
+            assert_eq!(20_000, result.bytes);
+            assert!(result.metadata_preserved());
+        }
+        Ok(())
+    }
+
+    // Checks each destination file as soon as it is reported complete,
+    // from the worker that completed it.
+    struct CompletionChecker {
+        source: PathBuf,
+        dest: PathBuf,
+        started: Mutex<HashMap<u64, PathBuf>>,
+        checked: AtomicU64,
+        failures: Mutex<Vec<String>>,
+    }
+
+    impl CompletionChecker {
+        fn check(&self, from: &Path) -> std::result::Result<(), String> {
+            let to = self.dest.join(from.strip_prefix(&self.source).unwrap());
+            let (smeta, dmeta) = (from.metadata().unwrap(), to.metadata().map_err(|e| e.to_string())?);
+            if fs::read(from).unwrap() != fs::read(&to).map_err(|e| e.to_string())? {
+                return Err("contents differ".to_string());
+            }
+            if (smeta.mode(), smeta.mtime(), smeta.mtime_nsec()) != (dmeta.mode(), dmeta.mtime(), dmeta.mtime_nsec()) {
+                return Err("metadata not applied".to_string());
+            }
+            for fd in fs::read_dir("/proc/self/fd").unwrap().flatten() {
+                if fs::read_link(fd.path()).is_ok_and(|p| p == to) {
+                    return Err("still open".to_string());
+                }
+            }
+            Ok(())
+        }
+    }
 
+    impl StatusUpdater for CompletionChecker {
+        fn send(&self, update: StatusUpdate) -> Result<()> {
+            match update {
+                StatusUpdate::FileStarted(id, from) => {
+                    self.started.lock().unwrap().insert(id, from);
+                }
+                StatusUpdate::FileCompleted(id, _) => {
+                    let from = self.started.lock().unwrap().remove(&id).unwrap();
+                    if let Err(e) = self.check(&from) {
+                        self.failures.lock().unwrap().push(format!("{:?}: {}", from, e));
+                    }
+                    self.checked.fetch_add(1, Ordering::Relaxed);
+                }
+                StatusUpdate::Error(e) => return Err(e.into()),
+                _ => {}
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn completed_files_test() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = tdir.path().join("src");
+        for d in 0..4 {
+            let dir = source.join(format!("dir{}", d));
+            fs::create_dir_all(&dir)?;
+            for f in 0..50 {
+                let data = (0..(d * 50 + f) * 500).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+                let file = dir.join(format!("file{}", f));
+                fs::write(&file, data)?;
+                fs::set_permissions(&file, fs::Permissions::from_mode(0o600 + (f as u32 % 8) * 0o10))?;
+            }
+        }
+
+        for driver in [Drivers::ParFile, #[cfg(feature = "parblock")] Drivers::ParBlock] {
+            for (executor, metadata_workers) in executors().into_iter().flat_map(|e| [(e.clone(), None), (e, Some(2))]) {
+                let dest = tdir.path().join(format!("{:?}-{:?}-{:?}", driver, executor, metadata_workers));
+                let config = Arc::new(Config {
+                    block_size: 4096,
+                    workers: 8,
+                    executor: executor.clone(),
+                    metadata_workers,
+                    ..Config::default()
+                });
+                let checker = Arc::new(CompletionChecker {
+                    source: source.clone(),
+                    dest: dest.clone(),
+                    started: Mutex::default(),
+                    checked: AtomicU64::default(),
+                    failures: Mutex::default(),
+                });
+                load_driver(driver, &config)?.copy(vec![source.clone()], &dest, checker.clone())?;
+                let context = format!("{:?} {:?} {:?}", driver, executor, metadata_workers);
+                assert_eq!(200, checker.checked.load(Ordering::Relaxed), "{}", context);
+                assert_eq!(Vec::<String>::new(), *checker.failures.lock().unwrap(), "{}", context);
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn metadata_workers_test() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = tdir.path().join("src");
+        fs::create_dir_all(&source)?;
+        for f in 0..24 {
+            fs::write(source.join(format!("file{}", f)), vec![f as u8; 10_000])?;
+        }
+
+        // Slow metadata updates with a single copy worker; the
+        // metadata stage overlaps them.
+        let fs = Arc::new(FaultInjectingFs::new());
+        fs.delay(FsOp::SetMetadata, Duration::from_millis(20));
+        for driver in [Drivers::ParFile, #[cfg(feature = "parblock")] Drivers::ParBlock] {
+            let mut elapsed = Vec::new();
+            for metadata_workers in [None, Some(8)] {
+                let dest = tdir.path().join(format!("{:?}-{:?}", driver, metadata_workers));
+                let config = Arc::new(Config {
+                    workers: 1,
+                    executor: Executor::Threads,
+                    metadata_workers,
+                    fs: fs.clone(),
+                    ..Config::default()
+                });
+                let checker = Arc::new(CompletionChecker {
+                    source: source.clone(),
+                    dest: dest.clone(),
+                    started: Mutex::default(),
+                    checked: AtomicU64::default(),
+                    failures: Mutex::default(),
+                });
+                let start = Instant::now();
+                load_driver(driver, &config)?.copy(vec![source.clone()], &dest, checker.clone())?;
+                elapsed.push(start.elapsed());
+                assert_eq!(24, checker.checked.load(Ordering::Relaxed));
+                assert_eq!(Vec::<String>::new(), *checker.failures.lock().unwrap());
+            }
+            assert!(elapsed[1] * 2 < elapsed[0], "{:?}: {:?}", driver, elapsed);
+        }
+        Ok(())
+    }
+
+    // Compares the throughput of copying a file unverified, verified
+    // with the data hashed as it is copied, and verified by reading
+    // the copy back afterwards; the best of several rounds of each.
+    // The source is in the page cache, so this measures the cost of
+    // hashing rather than of the disk. Run
+    // with:
+    //
+    //     cargo test --release -p libxcp bench_verified_copy -- --ignored --nocapture
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_verified_copy() -> Result<()> {
+        const SIZE: usize = 256 * 1024 * 1024;
+        const ROUNDS: usize = 5;
+
+        let tdir = TempDir::new()?;
+        let source = tdir.path().join("source");
+        fs::write(&source, (0..SIZE).map(|i| (i % 251) as u8).collect::<Vec<u8>>())?;
+        for (name, checksum, reread) in [
+            ("Unverified", None, false),
+            ("Verified, hashed while copying", Some(ChecksumType::Blake3), false),
+            ("Verified, read back", None, true),
+        ] {
+            let dest = tdir.path().join("dest");
+            let config = Arc::new(Config {
+                checksum,
+                reflink: Reflink::Never,
+                ..Config::default()
+            });
+            let mut best = Duration::MAX;
+            for _ in 0..ROUNDS {
+                let start = Instant::now();
+                load_driver(Drivers::ParFile, &config)?.copy(vec![source.clone()], &dest, Arc::new(NoopUpdater))?;
+                if reread {
+                    checksum_file(&dest, ChecksumType::Blake3)?;
+                }
+                best = best.min(start.elapsed());
+                fs::remove_file(&dest)?;
+            }
+            println!("{}: {:.0} MB/s", name, SIZE as f64 / best.as_secs_f64() / 1_000_000.0);
+        }
         Ok(())
     }
 }
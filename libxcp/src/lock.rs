@@ -0,0 +1,351 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! A lock held on a destination for the duration of a copy, so that
+//! two copies can't write the same tree at once.
+//!
+//! A directory is locked with `flock(2)` on `.xcp-lock` inside it;
+//! other destinations, including directories that don't exist yet
+//! and symlinks to directories that are to be replaced, with
+//! `.xcp-lock-NAME` in their parent. The file records the pid and
+//! start time of the holder, and is removed when the lock is
+//! dropped. A lock file left by a copy that crashed is no longer
+//! locked, and one whose holder has exited is stale; both are taken
+//! over with a warning.
+
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, FileTimes, Metadata};
+use std::io::{self, ErrorKind, Read, Seek, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use libfs::{process_exists, try_lock_file};
+use log::{debug, info, warn};
+
+use crate::errors::{Result, XcpError};
+
+/// The name of the lock file in a locked directory, and the prefix of
+/// those for other destinations.
+pub const LOCK_NAME: &str = ".xcp-lock";
+
+/// How often a held lock is retried when waiting for it.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Whether `name` is that of a lock file, which e.g. '--delete'
+/// leaves in place.
+pub fn is_lock_file(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(LOCK_NAME.as_bytes())
+}
+
+/// The process recorded in a lock file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Holder {
+    pid: u32,
+    /// Seconds since the Unix epoch.
+    started: u64,
+}
+
+impl Holder {
+    fn current() -> Holder {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Holder { pid: process::id(), started }
+    }
+
+    fn parse(record: &str) -> Option<Holder> {
+        let mut fields = record.split_whitespace().map(str::parse::<u64>);
+        let pid = fields.next()?.ok()?;
+        let started = fields.next()?.ok()?;
+        Some(Holder { pid: u32::try_from(pid).ok()?, started })
+    }
+}
+
+enum Attempt {
+    Taken(DestLock),
+    /// Held by a live process, recorded in the file if it has been
+    /// written.
+    Held(Option<Holder>),
+}
+
+// Whether `dest` is locked from inside. A symlink given as the
+// destination is itself replaced, so is locked by name, even if it
+// points at a directory.
+fn is_locked_dir(dest: &Path) -> bool {
+    dest.is_dir() && !dest.is_symlink()
+}
+
+/// A lock on a destination, released and removed when dropped.
+#[derive(Debug)]
+pub struct DestLock {
+    path: PathBuf,
+    // Locked for the lifetime of the copy.
+    _file: File,
+}
+
+impl DestLock {
+    /// The lock file for `dest`; see the [module](self) docs.
+    pub fn path(dest: &Path) -> PathBuf {
+        if is_locked_dir(dest) {
+            dest.join(LOCK_NAME)
+        } else {
+            named_path(dest)
+        }
+    }
+
+    /// Lock `dest`, waiting up to `wait` for another copy holding it
+    /// to finish; [Duration::ZERO] fails at once, and [Duration::MAX]
+    /// waits indefinitely. A directory is also treated as held while
+    /// a copy that created it holds the lock for its name in the
+    /// parent.
+    pub fn acquire(dest: &Path, wait: Duration) -> Result<DestLock> {
+        let path = DestLock::path(dest);
+        let created = (is_locked_dir(dest) && dest.file_name().is_some()).then(|| named_path(dest));
+        let deadline = Instant::now().checked_add(wait);
+        let mut waiting = false;
+        loop {
+            let holder = match attempt(&path)? {
+                Attempt::Taken(lock) => match created.as_deref().map(held).transpose()?.flatten() {
+                    // Dropping our lock removes it again.
+                    Some(holder) => holder,
+                    None => return Ok(lock),
+                },
+                Attempt::Held(holder) => holder,
+            };
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                return Err(XcpError::DestinationLocked {
+                    path: dest.to_path_buf(),
+                    pid: holder.map(|h| h.pid),
+                }.into());
+            }
+            if !waiting {
+                info!("Waiting for the lock on {:?}{}", dest, describe(holder));
+                waiting = true;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+impl Drop for DestLock {
+    fn drop(&mut self) {
+        // Removed while still locked, so a waiting copy can't lock
+        // the old file after we release it; see [attempt].
+        debug!("Removing lock {:?}", self.path);
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        let times = dir.metadata();
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove lock {:?}: {}", self.path, e);
+        }
+        // The directory may be the destination, with timestamps copied
+        // from the source.
+        if let Err(e) = times.and_then(|meta| restore_times(dir, &meta)) {
+            debug!("Failed to restore timestamps of {:?}: {}", dir, e);
+        }
+    }
+}
+
+fn restore_times(dir: &Path, meta: &Metadata) -> io::Result<()> {
+    let times = FileTimes::new()
+        .set_accessed(meta.accessed()?)
+        .set_modified(meta.modified()?);
+    File::open(dir)?.set_times(times)
+}
+
+// The lock file for `dest` in its parent directory.
+fn named_path(dest: &Path) -> PathBuf {
+    let parent = dest.parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let mut name = OsString::from(format!("{}-", LOCK_NAME));
+    name.push(dest.file_name().unwrap_or_default());
+    parent.join(name)
+}
+
+fn describe(holder: Option<Holder>) -> String {
+    match holder {
+        Some(h) => format!(", held by pid {} since {}", h.pid, h.started),
+        None => String::new(),
+    }
+}
+
+fn read_holder(mut file: &File) -> Result<Option<Holder>> {
+    let mut record = String::new();
+    file.rewind()?;
+    file.read_to_string(&mut record)?;
+    Ok(Holder::parse(&record))
+}
+
+// Try to take the lock at `path`, breaking it if the holder has
+// exited.
+fn attempt(path: &Path) -> Result<Attempt> {
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    if !try_lock_file(&file)? {
+        let holder = read_holder(&file)?;
+        return match holder {
+            Some(h) if !process_exists(h.pid) => {
+                warn!("Breaking stale lock {:?}{}, which has exited", path, describe(holder));
+                remove_lock(path)?;
+                attempt(path)
+            }
+            _ => Ok(Attempt::Held(holder)),
+        };
+    }
+
+    // The previous holder removes the file before releasing it, so
+    // if we locked a removed file try again with a new one.
+    let current = match path.symlink_metadata() {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == ErrorKind::NotFound => return attempt(path),
+        Err(e) => return Err(e.into()),
+    };
+    let ours = file.metadata()?;
+    if (current.dev(), current.ino()) != (ours.dev(), ours.ino()) {
+        return attempt(path);
+    }
+
+    if let Some(stale) = read_holder(&file)? {
+        warn!("Breaking stale lock {:?}{}, which did not exit cleanly", path, describe(Some(stale)));
+    }
+    let holder = Holder::current();
+    file.set_len(0)?;
+    file.rewind()?;
+    writeln!(file, "{} {}", holder.pid, holder.started)?;
+    debug!("Locked {:?}", path);
+    Ok(Attempt::Taken(DestLock { path: path.to_path_buf(), _file: file }))
+}
+
+// Whether the lock at `path` exists and is held by a live process,
+// with the holder if it has been recorded. The file isn't created if
+// it doesn't exist.
+fn held(path: &Path) -> Result<Option<Option<Holder>>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if try_lock_file(&file)? {
+        return Ok(None);
+    }
+    let holder = read_holder(&file)?;
+    Ok(match holder {
+        Some(h) if !process_exists(h.pid) => None,
+        _ => Some(holder),
+    })
+}
+
+fn remove_lock(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir, read_to_string, write};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_lock_paths() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(dir.path().join(LOCK_NAME), DestLock::path(dir.path()));
+        let file = dir.path().join("file");
+        assert_eq!(dir.path().join(".xcp-lock-file"), DestLock::path(&file));
+        assert_eq!(PathBuf::from("./.xcp-lock-new"), DestLock::path(Path::new("new")));
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(dir.path(), &link).unwrap();
+        assert_eq!(dir.path().join(".xcp-lock-link"), DestLock::path(&link));
+        assert!(is_lock_file(OsStr::new(".xcp-lock-file")));
+        assert!(!is_lock_file(OsStr::new("xcp-lock")));
+    }
+
+    #[test]
+    fn test_lock_held() -> Result<()> {
+        let dir = TempDir::new()?;
+        let lock = DestLock::acquire(dir.path(), Duration::ZERO)?;
+        let record = read_to_string(dir.path().join(LOCK_NAME))?;
+        assert_eq!(Some(process::id()), Holder::parse(&record).map(|h| h.pid));
+
+        let err = DestLock::acquire(dir.path(), Duration::from_millis(200)).unwrap_err();
+        match err.downcast_ref::<XcpError>() {
+            Some(XcpError::DestinationLocked { pid, .. }) => assert_eq!(Some(process::id()), *pid),
+            e => panic!("Unexpected error {:?}", e),
+        }
+
+        drop(lock);
+        assert!(!dir.path().join(LOCK_NAME).exists());
+        let _lock = DestLock::acquire(dir.path(), Duration::ZERO)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_wait() -> Result<()> {
+        let dir = TempDir::new()?;
+        let lock = DestLock::acquire(dir.path(), Duration::ZERO)?;
+        let released = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            drop(lock);
+        });
+        let start = Instant::now();
+        let _lock = DestLock::acquire(dir.path(), Duration::MAX)?;
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        released.join().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_stale() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join(LOCK_NAME);
+        // Left by a crashed copy; not locked.
+        write(&path, "1 0\n")?;
+        let lock = DestLock::acquire(dir.path(), Duration::ZERO)?;
+        drop(lock);
+
+        // Held by an exited process.
+        write(&path, format!("{} 0\n", i32::MAX))?;
+        let file = File::open(&path)?;
+        assert!(try_lock_file(&file)?);
+        let _lock = DestLock::acquire(dir.path(), Duration::ZERO)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_created_dir() -> Result<()> {
+        let dir = TempDir::new()?;
+        let dest = dir.path().join("dest");
+        // Held by a copy that is creating the directory.
+        let creating = DestLock::acquire(&dest, Duration::ZERO)?;
+        assert!(dir.path().join(".xcp-lock-dest").exists());
+        create_dir(&dest)?;
+        assert!(DestLock::acquire(&dest, Duration::ZERO).is_err());
+        assert!(!dest.join(LOCK_NAME).exists());
+        drop(creating);
+        let _lock = DestLock::acquire(&dest, Duration::ZERO)?;
+        Ok(())
+    }
+}
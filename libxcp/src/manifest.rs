@@ -0,0 +1,354 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Manifests of copied files.
+//!
+//! A manifest is a JSON document listing every copied file relative
+//! to a root directory, along with its size, modification time and
+//! checksum. Entries are sorted by path so that manifests of
+//! identical trees are identical. Entries that were renamed for the
+//! destination filesystem are also listed; see [crate::names].
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+
+use crate::checksum::{checksum_file_with, ChecksumType, FileChecksum};
+use crate::config::Config;
+use crate::errors::{Result, XcpError};
+use crate::feedback::{StatusUpdate, StatusUpdater};
+
+// Upper limit on the read size when verifying, as the configured
+// block size is unbounded when progress is disabled.
+const MAX_VERIFY_BUFFER: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The directory the file paths are relative to.
+    pub root: PathBuf,
+    /// The algorithm used for the file checksums.
+    pub algorithm: ChecksumType,
+    /// The copied files.
+    pub files: Vec<FileChecksum>,
+    /// Entries given a different name at the destination.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub renamed: Vec<Renamed>,
+}
+
+/// An entry given a different name at the destination. `from` is the
+/// path it would have had.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Renamed {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+/// A file that doesn't match its manifest entry; see
+/// [Manifest::verify]. Paths are relative to the manifest root.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    /// The file no longer exists.
+    Missing(PathBuf),
+    /// The file could not be read.
+    Unreadable(PathBuf, String),
+    /// The file size differs; the checksum is not calculated.
+    Size {
+        path: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+    /// The file contents differ.
+    Checksum {
+        path: PathBuf,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl Mismatch {
+    pub fn path(&self) -> &Path {
+        match self {
+            Mismatch::Missing(path)
+                | Mismatch::Unreadable(path, _)
+                | Mismatch::Size { path, .. }
+                | Mismatch::Checksum { path, .. } => path,
+        }
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::Missing(path) => write!(f, "{:?}: missing", path),
+            Mismatch::Unreadable(path, err) => write!(f, "{:?}: unreadable: {}", path, err),
+            Mismatch::Size { path, expected, actual } =>
+                write!(f, "{:?}: size is {}, expected {}", path, actual, expected),
+            Mismatch::Checksum { path, expected, actual } =>
+                write!(f, "{:?}: checksum is {}, expected {}", path, actual, expected),
+        }
+    }
+}
+
+impl Manifest {
+    pub fn new(root: &Path, algorithm: ChecksumType) -> Manifest {
+        Manifest {
+            root: root.to_path_buf(),
+            algorithm,
+            files: Vec::new(),
+            renamed: Vec::new(),
+        }
+    }
+
+    // Make a path relative to the manifest root where possible.
+    fn relative(&self, path: PathBuf) -> PathBuf {
+        match path.strip_prefix(&self.root) {
+            Ok(rel) => rel.to_path_buf(),
+            Err(_) => path,
+        }
+    }
+
+    /// Add a checksum to the manifest. The path is made relative to
+    /// the manifest root where possible.
+    pub fn add(&mut self, mut sum: FileChecksum) {
+        sum.path = self.relative(sum.path);
+        self.files.push(sum);
+    }
+
+    /// Record a renamed entry. The paths are made relative to the
+    /// manifest root where possible.
+    pub fn add_renamed(&mut self, from: PathBuf, to: PathBuf) {
+        let renamed = Renamed {
+            from: self.relative(from),
+            to: self.relative(to),
+        };
+        self.renamed.push(renamed);
+    }
+
+    /// Write the manifest to a file. The file is written to a
+    /// temporary location and renamed into place, so an existing
+    /// manifest is never left partially written.
+    pub fn write(&mut self, path: &Path) -> Result<()> {
+        self.files.sort_by(|a, b| a.path.cmp(&b.path));
+        self.renamed.sort_by(|a, b| a.from.cmp(&b.from));
+
+        let mut tmp = path.to_path_buf().into_os_string();
+        tmp.push(format!(".tmp-{}", std::process::id()));
+        let tmp = PathBuf::from(tmp);
+
+        {
+            let fd = File::create(&tmp)?;
+            let mut writer = BufWriter::new(&fd);
+            serde_json::to_writer_pretty(&mut writer, self)?;
+            writer.write_all(b"\n")?;
+            writer.flush()?;
+            drop(writer);
+            fd.sync_all()?;
+        }
+        fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+
+    /// Read a manifest previously written with [Manifest::write].
+    pub fn read(path: &Path) -> Result<Manifest> {
+        let reader = BufReader::new(File::open(path)?);
+        let manifest = serde_json::from_reader(reader)?;
+        Ok(manifest)
+    }
+
+    /// Check the listed files under `root` against their recorded
+    /// sizes and checksums, returning any that differ sorted by
+    /// path. Files are hashed in parallel by [Config::workers]
+    /// threads, reading [Config::block_size] bytes at a time.
+    ///
+    /// Progress is reported as for a copy: a [StatusUpdate::Size]
+    /// of the total manifest bytes, then [StatusUpdate::FileStarted],
+    /// [StatusUpdate::Copied] and [StatusUpdate::FileCompleted] for
+    /// each file. Files that are missing or the wrong size are
+    /// counted as complete so the total stays consistent.
+    pub fn verify(&self, root: &Path, config: &Config, updater: &Arc<dyn StatusUpdater>) -> Result<Vec<Mismatch>> {
+        let total = self.files.iter().map(|f| f.size).sum();
+        updater.send(StatusUpdate::Size(total))?;
+
+        let buffer_size = config.block_size.min(MAX_VERIFY_BUFFER) as usize;
+        let next = AtomicUsize::new(0);
+        let mismatches = Mutex::new(Vec::new());
+        let workers = config.num_workers().min(self.files.len()).max(1);
+
+        thread::scope(|s| -> Result<()> {
+            let handles = (0..workers).map(|_| s.spawn(|| -> Result<()> {
+                loop {
+                    let id = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(entry) = self.files.get(id) else {
+                        return Ok(());
+                    };
+                    let path = root.join(&entry.path);
+                    updater.send(StatusUpdate::FileStarted(id as u64, path.clone()))?;
+                    let result = self.verify_file(entry, &path, buffer_size, updater);
+                    let (mismatch, read) = result?;
+                    updater.send(StatusUpdate::FileCompleted(id as u64, read))?;
+                    if let Some(mismatch) = mismatch {
+                        mismatches.lock().unwrap().push(mismatch);
+                    }
+                }
+            })).collect::<Vec<_>>();
+
+            for handle in handles {
+                handle.join()
+                    .map_err(|_| XcpError::CopyError("Error during verification".to_string()))??;
+            }
+            Ok(())
+        })?;
+
+        let mut mismatches = mismatches.into_inner().unwrap();
+        mismatches.sort_by(|a, b| a.path().cmp(b.path()));
+        Ok(mismatches)
+    }
+
+    // Check a single file, returning `Err` only if a status update
+    // couldn't be sent. Also returns the bytes sent as
+    // [StatusUpdate::Copied].
+    fn verify_file(&self, entry: &FileChecksum, path: &Path, buffer_size: usize,
+                   updater: &Arc<dyn StatusUpdater>) -> Result<(Option<Mismatch>, u64)>
+    {
+        let rel = entry.path.clone();
+        let meta = match path.metadata() {
+            Ok(meta) => meta,
+            Err(e) => {
+                updater.send(StatusUpdate::Copied(entry.size))?;
+                return Ok((Some(match e.kind() {
+                    ErrorKind::NotFound => Mismatch::Missing(rel),
+                    _ => Mismatch::Unreadable(rel, e.to_string()),
+                }), entry.size));
+            }
+        };
+        if meta.len() != entry.size {
+            updater.send(StatusUpdate::Copied(entry.size))?;
+            return Ok((Some(Mismatch::Size { path: rel, expected: entry.size, actual: meta.len() }), entry.size));
+        }
+
+        let mut read = 0;
+        let mut progress = |len| {
+            read += len;
+            updater.send(StatusUpdate::Copied(len))
+        };
+        let sum = checksum_file_with(path, self.algorithm, buffer_size, &mut progress);
+        // Make up any shortfall from a failed or short read.
+        if read < entry.size {
+            updater.send(StatusUpdate::Copied(entry.size - read))?;
+            read = entry.size;
+        }
+        Ok((match sum {
+            Ok(actual) if actual == entry.checksum => None,
+            Ok(actual) => Some(Mismatch::Checksum { path: rel, expected: entry.checksum.clone(), actual }),
+            Err(e) => Some(Mismatch::Unreadable(rel, e.to_string())),
+        }, read))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU64;
+    use tempfile::TempDir;
+
+    fn entry(path: &str) -> FileChecksum {
+        FileChecksum {
+            path: PathBuf::from(path),
+            size: 3,
+            mtime: 1234,
+            mtime_nsec: 5678,
+            checksum: "abcd".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_manifest_roundtrip() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let root = tdir.path().join("dest");
+        let file = tdir.path().join("manifest.json");
+
+        let mut manifest = Manifest::new(&root, ChecksumType::Sha256);
+        manifest.add(entry(root.join("b/file.txt").to_str().unwrap()));
+        manifest.add(entry(root.join("a.txt").to_str().unwrap()));
+        manifest.add_renamed(root.join("c:d"), root.join("c_d"));
+        manifest.write(&file)?;
+
+        let read = Manifest::read(&file)?;
+        assert_eq!(root, read.root);
+        assert_eq!(ChecksumType::Sha256, read.algorithm);
+        assert_eq!(vec![entry("a.txt"), entry("b/file.txt")], read.files);
+        let renamed = Renamed { from: PathBuf::from("c:d"), to: PathBuf::from("c_d") };
+        assert_eq!(vec![renamed], read.renamed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_verify() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let root = tdir.path();
+        let mut manifest = Manifest::new(root, ChecksumType::Blake3);
+        for name in ["same", "changed", "resized", "missing"] {
+            let path = root.join(name);
+            fs::write(&path, "0123456789")?;
+            manifest.add(FileChecksum::from_file(&path, ChecksumType::Blake3)?);
+        }
+        fs::write(root.join("changed"), "9876543210")?;
+        fs::write(root.join("resized"), "0123")?;
+        fs::remove_file(root.join("missing"))?;
+
+        let config = Config { workers: 2, block_size: 4, ..Config::default() };
+        let recorder = Arc::new(Recorder::default());
+        let updater: Arc<dyn StatusUpdater> = recorder.clone();
+        let mismatches = manifest.verify(root, &config, &updater)?;
+
+        let paths = mismatches.iter().map(Mismatch::path).collect::<Vec<_>>();
+        assert_eq!(vec![Path::new("changed"), Path::new("missing"), Path::new("resized")], paths);
+        assert!(matches!(mismatches[0], Mismatch::Checksum { .. }));
+        assert_eq!(Mismatch::Missing(PathBuf::from("missing")), mismatches[1]);
+        assert_eq!(Mismatch::Size { path: PathBuf::from("resized"), expected: 10, actual: 4 }, mismatches[2]);
+
+        // Every manifest byte is accounted for.
+        assert_eq!(40, recorder.size.load(Ordering::Relaxed));
+        assert_eq!(40, recorder.copied.load(Ordering::Relaxed));
+
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        size: AtomicU64,
+        copied: AtomicU64,
+    }
+
+    impl StatusUpdater for Recorder {
+        fn send(&self, update: StatusUpdate) -> Result<()> {
+            match update {
+                StatusUpdate::Size(s) => { self.size.fetch_add(s, Ordering::Relaxed); }
+                StatusUpdate::Copied(c) => { self.copied.fetch_add(c, Ordering::Relaxed); }
+                _ => {}
+            }
+            Ok(())
+        }
+    }
+}
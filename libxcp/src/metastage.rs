@@ -0,0 +1,72 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! A separate stage applying the metadata of copied files; see
+//! [Config::metadata_workers].
+//!
+//! A file's metadata is applied, and its completion reported, when
+//! its [CopyHandle] is dropped. Without a stage that is on whichever
+//! worker copied the last of its data. With one the workers pass the
+//! handle to [MetadataStage::finish] instead, and carry on copying
+//! while it is dropped on the stage's own pool. The pool's queue is
+//! bounded, so the open files waiting for it are too; once it is full
+//! the workers wait.
+//!
+//! [Config::metadata_workers]: crate::config::Config::metadata_workers
+
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::executor::Pool;
+use crate::metrics::Metrics;
+use crate::operations::CopyHandle;
+
+/// The most files waiting for the metadata workers.
+const QUEUE_LEN: usize = 64;
+
+/// A pool of workers applying the metadata of copied files.
+pub(crate) struct MetadataStage {
+    pool: Pool,
+    metrics: Arc<Metrics>,
+}
+
+impl MetadataStage {
+    /// The stage for a copy, or `None` if metadata is applied by the
+    /// copy workers.
+    pub(crate) fn new(config: &Config, metrics: &Arc<Metrics>) -> Option<MetadataStage> {
+        let workers = config.metadata_workers?;
+        Some(MetadataStage {
+            pool: config.executor.pool(workers.max(1), Some(QUEUE_LEN)),
+            metrics: metrics.clone(),
+        })
+    }
+
+    /// Apply the metadata of `handle`, and report it complete, on the
+    /// stage. This waits while the queue is full.
+    pub(crate) fn finish(&self, handle: CopyHandle) {
+        let metrics = self.metrics.clone();
+        self.pool.execute(move || {
+            let _worker = metrics.enter();
+            drop(handle);
+        });
+    }
+
+    /// Wait for the metadata of every file passed to the stage to be
+    /// applied.
+    pub(crate) fn join(&self) {
+        self.pool.join();
+    }
+}
@@ -0,0 +1,268 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Runtime metrics of a copy, for finding out why one is slow: whether
+//! the workers are starved of work by the walk, busy reading or
+//! writing, or applying metadata, and how much each is copying.
+//!
+//! Each worker thread records its [WorkerState] and the bytes it has
+//! copied in relaxed atomics as it goes, and [Metrics::snapshot]
+//! samples them. The metrics of a driver are available from
+//! [CopyDriver::metrics], and can be sampled from another thread
+//! while the copy runs.
+//!
+//! [CopyDriver::metrics]: crate::drivers::CopyDriver::metrics
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, ThreadId};
+use std::time::Instant;
+
+use serde::Serialize;
+
+/// What a worker is doing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum WorkerState {
+    /// Waiting for work.
+    Idle,
+    /// Reading source data in userspace.
+    Reading,
+    /// Copying data to the destination, or waiting to. Copies done
+    /// by the kernel, such as `copy_file_range`, read and write in
+    /// one call, so count as writing.
+    Writing,
+    /// Opening and creating files, or applying their metadata.
+    Metadata,
+}
+
+impl WorkerState {
+    const ALL: [WorkerState; 4] = [WorkerState::Idle, WorkerState::Reading, WorkerState::Writing, WorkerState::Metadata];
+}
+
+// The state of one worker thread.
+#[derive(Default)]
+struct Slot {
+    state: AtomicU8,
+    bytes: AtomicU64,
+}
+
+thread_local! {
+    /// The slot of the worker running on this thread, if any.
+    static CURRENT: RefCell<Option<Arc<Slot>>> = const { RefCell::new(None) };
+}
+
+/// The metrics of the copy in progress; see the [module
+/// documentation](self).
+#[derive(Default)]
+pub struct Metrics {
+    /// By thread, in the order they started work.
+    workers: Mutex<Vec<(ThreadId, Arc<Slot>)>>,
+    /// Operations sent by the walk and not yet received by a worker.
+    queued: AtomicU64,
+}
+
+impl Metrics {
+    /// Take a sample of the metrics.
+    pub fn snapshot(&self) -> Snapshot {
+        let workers = self.workers.lock().unwrap().iter()
+            .map(|(_, slot)| WorkerSample {
+                state: WorkerState::ALL[slot.state.load(Ordering::Relaxed) as usize],
+                bytes: slot.bytes.load(Ordering::Relaxed),
+            })
+            .collect();
+        Snapshot {
+            taken: Instant::now(),
+            workers,
+            queued: self.queued.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Clear the metrics of any previous copy.
+    pub(crate) fn reset(&self) {
+        self.workers.lock().unwrap().clear();
+        self.queued.store(0, Ordering::Relaxed);
+    }
+
+    /// Record the calling thread as a worker until the returned guard
+    /// is dropped, after which it is idle. Work on the same thread is
+    /// counted as one worker, so jobs of a pool are counted by the
+    /// thread they run on.
+    pub(crate) fn enter(&self) -> Worker {
+        let id = thread::current().id();
+        let slot = {
+            let mut workers = self.workers.lock().unwrap();
+            match workers.iter().find(|(t, _)| *t == id) {
+                Some((_, slot)) => slot.clone(),
+                None => {
+                    let slot = Arc::new(Slot::default());
+                    workers.push((id, slot.clone()));
+                    slot
+                }
+            }
+        };
+        Worker { previous: CURRENT.with(|c| c.replace(Some(slot))) }
+    }
+
+    /// Record an operation sent to the workers.
+    pub(crate) fn enqueued(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record an operation received by a worker.
+    pub(crate) fn dequeued(&self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Marks the thread as a worker; see [Metrics::enter]. Work can be
+/// nested on a thread, e.g. a rayon pool runs queued jobs while
+/// waiting, so the previous worker is restored when dropped.
+pub(crate) struct Worker {
+    previous: Option<Arc<Slot>>,
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        set_state(WorkerState::Idle);
+        CURRENT.with(|c| c.replace(self.previous.take()));
+    }
+}
+
+/// Record the state of the worker on this thread, if any.
+pub(crate) fn set_state(state: WorkerState) {
+    CURRENT.with(|c| {
+        if let Some(slot) = &*c.borrow() {
+            slot.state.store(state as u8, Ordering::Relaxed);
+        }
+    });
+}
+
+/// Record bytes copied by the worker on this thread, if any.
+pub(crate) fn add_bytes(bytes: u64) {
+    CURRENT.with(|c| {
+        if let Some(slot) = &*c.borrow() {
+            slot.bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+    });
+}
+
+/// A worker in a [Snapshot].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct WorkerSample {
+    pub state: WorkerState,
+    /// The bytes copied by the worker so far.
+    pub bytes: u64,
+}
+
+/// A sample of [Metrics].
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub taken: Instant,
+    /// The workers, in the order they started work.
+    pub workers: Vec<WorkerSample>,
+    /// The operations waiting for a worker.
+    pub queued: u64,
+}
+
+impl Snapshot {
+    /// The number of workers in a state.
+    pub fn count(&self, state: WorkerState) -> usize {
+        self.workers.iter().filter(|w| w.state == state).count()
+    }
+
+    /// The copy rate of each worker in bytes per second since an
+    /// earlier snapshot of the same copy. Workers that have started
+    /// since are counted from zero.
+    pub fn rates(&self, earlier: &Snapshot) -> Vec<f64> {
+        let secs = self.taken.duration_since(earlier.taken).as_secs_f64();
+        self.workers.iter().enumerate()
+            .map(|(i, w)| {
+                let before = earlier.workers.get(i).map_or(0, |e| e.bytes);
+                if secs > 0.0 {
+                    w.bytes.saturating_sub(before) as f64 / secs
+                } else {
+                    0.0
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_worker_states() {
+        let metrics = Arc::new(Metrics::default());
+        // Not a worker, so nothing is recorded.
+        set_state(WorkerState::Writing);
+        add_bytes(10);
+        assert!(metrics.snapshot().workers.is_empty());
+
+        let worker = metrics.enter();
+        set_state(WorkerState::Reading);
+        add_bytes(100);
+        let m = metrics.clone();
+        thread::spawn(move || {
+            let _worker = m.enter();
+            set_state(WorkerState::Metadata);
+            add_bytes(5);
+            // Nested work on the same thread is the same worker.
+            drop(m.enter());
+        }).join().unwrap();
+
+        let snap = metrics.snapshot();
+        assert_eq!(vec![
+            WorkerSample { state: WorkerState::Reading, bytes: 100 },
+            WorkerSample { state: WorkerState::Idle, bytes: 5 },
+        ], snap.workers);
+        assert_eq!(1, snap.count(WorkerState::Reading));
+        assert_eq!(1, snap.count(WorkerState::Idle));
+
+        drop(worker);
+        assert_eq!(2, metrics.snapshot().count(WorkerState::Idle));
+        metrics.reset();
+        assert!(metrics.snapshot().workers.is_empty());
+    }
+
+    #[test]
+    fn test_queue_depth() {
+        let metrics = Metrics::default();
+        metrics.enqueued();
+        metrics.enqueued();
+        metrics.dequeued();
+        assert_eq!(1, metrics.snapshot().queued);
+    }
+
+    #[test]
+    fn test_rates() {
+        let start = Instant::now();
+        let sample = |secs, bytes: &[u64]| Snapshot {
+            taken: start + Duration::from_secs(secs),
+            workers: bytes.iter().map(|&bytes| WorkerSample { state: WorkerState::Writing, bytes }).collect(),
+            queued: 0,
+        };
+        let earlier = sample(0, &[100]);
+        let later = sample(2, &[300, 50]);
+        assert_eq!(vec![100.0, 25.0], later.rates(&earlier));
+        assert_eq!(vec![0.0], earlier.rates(&earlier));
+    }
+}
@@ -0,0 +1,487 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Destination filename restrictions.
+//!
+//! Some filesystems, notably FAT, exFAT and NTFS, cannot represent
+//! every name that is valid on a Unix filesystem, and may treat names
+//! differing only in case as the same file; on ext4 and F2FS this
+//! can be enabled for individual directories. The restrictions of a
+//! destination are described by a [NameProfile], and source names
+//! that break them are handled according to [InvalidName].
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use libfs::{fs_type, is_casefolded, FsType};
+use log::debug;
+
+use crate::config::{Config, InvalidName};
+use crate::errors::{Result, XcpError};
+use crate::feedback::{StatusUpdate, StatusUpdater};
+
+/// The filename restrictions of a destination filesystem. The
+/// default profile has no restrictions.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NameProfile {
+    /// Characters that may not appear in a name.
+    pub invalid_chars: Vec<char>,
+    /// Names may not end with a dot or a space.
+    pub no_trailing_dot_space: bool,
+    /// Names must be valid UTF-8.
+    pub utf8_only: bool,
+    /// Names differing only in case refer to the same entry.
+    pub case_insensitive: bool,
+    /// Individual directories may be case-insensitive; see
+    /// [libfs::is_casefolded].
+    pub casefold_dirs: bool,
+}
+
+impl NameProfile {
+    /// The restrictions of FAT, exFAT and NTFS, as enforced by
+    /// Windows.
+    pub fn windows() -> NameProfile {
+        NameProfile {
+            invalid_chars: "\\:*?\"<>|".chars()
+                .chain('\u{1}'..='\u{1f}')
+                .collect(),
+            no_trailing_dot_space: true,
+            utf8_only: true,
+            case_insensitive: true,
+            casefold_dirs: false,
+        }
+    }
+
+    /// Determine the restrictions of the filesystem containing
+    /// `dir`. Known restricted filesystems use [NameProfile::windows];
+    /// otherwise a probe file is created in `dir` to check for
+    /// case-insensitivity and, on FUSE, for Windows-style name
+    /// checks. On filesystems supporting casefolding each directory
+    /// is checked as it is used.
+    pub fn probe(dir: &Path) -> Result<NameProfile> {
+        match fs_type(&File::open(dir)?)? {
+            FsType::Fat | FsType::Exfat | FsType::Ntfs => Ok(NameProfile::windows()),
+            // ntfs-3g and exfat-fuse only reject Windows-invalid
+            // names when configured to.
+            FsType::Fuse if !accepts_name(dir, ":")? => Ok(NameProfile::windows()),
+            fstype => Ok(NameProfile {
+                case_insensitive: is_case_insensitive(dir)?,
+                casefold_dirs: matches!(fstype, FsType::Ext4 | FsType::F2fs),
+                ..NameProfile::default()
+            }),
+        }
+    }
+
+    /// Whether any names are restricted.
+    pub fn is_restricted(&self) -> bool {
+        *self != NameProfile::default()
+    }
+
+    // Whether any characters or names are invalid.
+    fn checks_names(&self) -> bool {
+        !self.invalid_chars.is_empty() || self.no_trailing_dot_space || self.utf8_only
+    }
+
+    /// Check whether a name can be represented, returning the reason
+    /// if not. Collisions between names are not checked.
+    pub fn check(&self, name: &OsStr) -> Option<&'static str> {
+        let Some(s) = name.to_str() else {
+            return self.utf8_only.then_some("name is not valid UTF-8");
+        };
+        if s.chars().any(|c| self.invalid_chars.contains(&c)) {
+            return Some("name contains a character the destination does not allow");
+        }
+        if self.no_trailing_dot_space && s.ends_with(['.', ' ']) {
+            return Some("name ends with a dot or space");
+        }
+        None
+    }
+
+    /// Replace the parts of a name that can't be represented with
+    /// `_`.
+    pub fn sanitize(&self, name: &OsStr) -> OsString {
+        let lossy = match name.to_str() {
+            Some(s) => s.to_string(),
+            None if self.utf8_only => name.to_string_lossy().replace('\u{fffd}', "_"),
+            None => return name.to_os_string(),
+        };
+        let mut s = lossy.chars()
+            .map(|c| if self.invalid_chars.contains(&c) { '_' } else { c })
+            .collect::<String>();
+        if self.no_trailing_dot_space {
+            let trimmed = s.trim_end_matches(['.', ' ']).len();
+            let trailing = s.len() - trimmed;
+            s.truncate(trimmed);
+            s.extend(std::iter::repeat('_').take(trailing));
+        }
+        OsString::from(s)
+    }
+}
+
+// The form of a name used to detect collisions.
+fn fold(name: &OsStr, case_insensitive: bool) -> OsString {
+    if !case_insensitive {
+        return name.to_os_string();
+    }
+    match name.to_str() {
+        Some(s) => OsString::from(s.to_lowercase()),
+        None => OsStr::from_bytes(&name.as_bytes().to_ascii_lowercase()).to_os_string(),
+    }
+}
+
+// Whether a probe file with `suffix` in its name can be created in
+// `dir`.
+fn accepts_name(dir: &Path, suffix: &str) -> Result<bool> {
+    let probe = dir.join(format!(".xcp-probe-{}{}", std::process::id(), suffix));
+    match File::options().write(true).create_new(true).open(&probe) {
+        Ok(_) => {
+            fs::remove_file(&probe)?;
+            Ok(true)
+        }
+        Err(e) if e.kind() == ErrorKind::InvalidInput => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn is_case_insensitive(dir: &Path) -> Result<bool> {
+    let probe = dir.join(format!(".xcp-probe-{}", std::process::id()));
+    File::options().write(true).create_new(true).open(&probe)?;
+    let upper = dir.join(format!(".XCP-PROBE-{}", std::process::id()));
+    let insensitive = upper.symlink_metadata().is_ok();
+    fs::remove_file(&probe)?;
+    Ok(insensitive)
+}
+
+// The names used in a destination directory.
+struct DirNames {
+    // Names differing only in case refer to the same entry.
+    case_insensitive: bool,
+    // The names used so far, folded if case-insensitive; `None` if
+    // no names can collide.
+    names: Option<HashSet<OsString>>,
+}
+
+/// Maps source names to destination names during a copy, applying
+/// the [InvalidName] policy and detecting collisions between names
+/// in each destination directory.
+pub(crate) struct NameMapper {
+    profile: NameProfile,
+    policy: InvalidName,
+    continue_on_error: bool,
+    /// The names used in each destination directory.
+    used: HashMap<PathBuf, DirNames>,
+    /// Destination paths of renamed entries.
+    renamed: HashSet<PathBuf>,
+}
+
+impl NameMapper {
+    pub(crate) fn new(profile: NameProfile, config: &Config) -> NameMapper {
+        NameMapper {
+            profile,
+            policy: config.invalid_name,
+            continue_on_error: config.continue_on_error,
+            used: HashMap::new(),
+            renamed: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn is_restricted(&self) -> bool {
+        self.profile.is_restricted()
+    }
+
+    // Whether names in the destination directory `dir` are
+    // case-insensitive. Directories that don't exist yet, e.g. in a
+    // dry run, inherit casefolding from their parent.
+    fn dir_case_insensitive(&self, dir: &Path) -> bool {
+        if self.profile.case_insensitive {
+            return true;
+        }
+        if !self.profile.casefold_dirs {
+            return false;
+        }
+        match File::open(dir) {
+            Ok(fd) => is_casefolded(&fd),
+            Err(_) => dir.parent()
+                .and_then(|p| self.used.get(p))
+                .is_some_and(|d| d.case_insensitive),
+        }
+    }
+
+    /// Whether the destination path is a renamed entry.
+    pub(crate) fn is_renamed(&self, path: &Path) -> bool {
+        self.renamed.contains(path)
+    }
+
+    /// The destination path for the source entry `from`, named `name`
+    /// in the destination directory `dir`. Returns `None` if the
+    /// entry should be skipped.
+    pub(crate) fn target(
+        &mut self,
+        from: &Path,
+        dir: &Path,
+        name: &OsStr,
+        stats: &Arc<dyn StatusUpdater>,
+    ) -> Result<Option<PathBuf>> {
+        if !self.profile.is_restricted() {
+            return Ok(Some(dir.join(name)));
+        }
+        if !self.used.contains_key(dir) {
+            let case_insensitive = self.dir_case_insensitive(dir);
+            if case_insensitive && !self.profile.case_insensitive {
+                debug!("Destination directory {:?} is case-insensitive", dir);
+            }
+            let names = (case_insensitive || self.profile.checks_names()).then(HashSet::new);
+            self.used.insert(dir.to_path_buf(), DirNames { case_insensitive, names });
+        }
+        let Some(DirNames { case_insensitive, names: Some(used) }) = self.used.get_mut(dir) else {
+            return Ok(Some(dir.join(name)));
+        };
+        let case_insensitive = *case_insensitive;
+        let reason = self.profile.check(name).or_else(|| {
+            used.contains(&fold(name, case_insensitive))
+                .then_some("name collides with another entry in the destination directory")
+        });
+        let Some(reason) = reason else {
+            used.insert(fold(name, case_insensitive));
+            return Ok(Some(dir.join(name)));
+        };
+
+        match self.policy {
+            InvalidName::Error => {
+                stats.send(StatusUpdate::Error(XcpError::InvalidName(from.to_path_buf(), reason)))?;
+                if !self.continue_on_error {
+                    return Err(XcpError::EarlyShutdown("Invalid destination name").into());
+                }
+                Ok(None)
+            }
+            InvalidName::Skip => {
+                debug!("Skipping {:?}: {}", from, reason);
+                stats.send(StatusUpdate::NameSkipped(from.to_path_buf()))?;
+                Ok(None)
+            }
+            InvalidName::Sanitize => {
+                let base = self.profile.sanitize(name);
+                let mut candidate = base.clone();
+                let mut n = 1;
+                while used.contains(&fold(&candidate, case_insensitive)) {
+                    candidate = with_suffix(&base, n);
+                    n += 1;
+                }
+                used.insert(fold(&candidate, case_insensitive));
+
+                let (orig, target) = (dir.join(name), dir.join(&candidate));
+                debug!("Renaming {:?} to {:?}: {}", orig, target, reason);
+                self.renamed.insert(target.clone());
+                stats.send(StatusUpdate::Renamed { from: orig, to: target.clone() })?;
+                Ok(Some(target))
+            }
+        }
+    }
+}
+
+// Add a numeric suffix to a name, before any extension.
+fn with_suffix(name: &OsStr, n: usize) -> OsString {
+    let path = Path::new(name);
+    let mut out = path.file_stem().unwrap_or(name).to_os_string();
+    out.push(format!("_{}", n));
+    if let Some(ext) = path.extension() {
+        out.push(".");
+        out.push(ext);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStrExt;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<StatusUpdate>>);
+
+    impl StatusUpdater for Collect {
+        fn send(&self, update: StatusUpdate) -> Result<()> {
+            self.0.lock().unwrap().push(update);
+            Ok(())
+        }
+    }
+
+    fn mapper(policy: InvalidName) -> NameMapper {
+        let config = Config {
+            invalid_name: policy,
+            ..Config::default()
+        };
+        NameMapper::new(NameProfile::windows(), &config)
+    }
+
+    fn target(m: &mut NameMapper, name: &str, stats: &Arc<dyn StatusUpdater>) -> Result<Option<PathBuf>> {
+        m.target(&Path::new("/src").join(name), Path::new("/dest"), OsStr::new(name), stats)
+    }
+
+    #[test]
+    fn test_check() {
+        let p = NameProfile::windows();
+        assert_eq!(None, p.check(OsStr::new("file.txt")));
+        assert!(p.check(OsStr::new("a:b")).is_some());
+        assert!(p.check(OsStr::new("what?")).is_some());
+        assert!(p.check(OsStr::new("trailing.")).is_some());
+        assert!(p.check(OsStr::new("trailing ")).is_some());
+        assert!(p.check(OsStr::from_bytes(b"bad\xff")).is_some());
+
+        let p = NameProfile::default();
+        assert!(!p.is_restricted());
+        assert_eq!(None, p.check(OsStr::new("a:b.")));
+        assert_eq!(None, p.check(OsStr::from_bytes(b"bad\xff")));
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let p = NameProfile::windows();
+        assert_eq!("a_b_c", p.sanitize(OsStr::new("a:b*c")));
+        assert_eq!("name__", p.sanitize(OsStr::new("name. ")));
+        assert_eq!("bad_", p.sanitize(OsStr::from_bytes(b"bad\xff")));
+        assert_eq!("file.txt", p.sanitize(OsStr::new("file.txt")));
+    }
+
+    #[test]
+    fn test_with_suffix() {
+        assert_eq!("a_1.txt", with_suffix(OsStr::new("a.txt"), 1));
+        assert_eq!("a_2", with_suffix(OsStr::new("a"), 2));
+        assert_eq!(".hidden_1", with_suffix(OsStr::new(".hidden"), 1));
+    }
+
+    #[test]
+    fn test_policy_sanitize() -> Result<()> {
+        let collect = Arc::new(Collect::default());
+        let stats: Arc<dyn StatusUpdater> = collect.clone();
+        let mut m = mapper(InvalidName::Sanitize);
+
+        assert_eq!(Some(PathBuf::from("/dest/a_b.txt")), target(&mut m, "a_b.txt", &stats)?);
+        assert_eq!(Some(PathBuf::from("/dest/a_b_1.txt")), target(&mut m, "a:b.txt", &stats)?);
+        assert_eq!(Some(PathBuf::from("/dest/a_b_2.txt")), target(&mut m, "a?b.txt", &stats)?);
+        assert_eq!(Some(PathBuf::from("/dest/Foo")), target(&mut m, "Foo", &stats)?);
+        assert_eq!(Some(PathBuf::from("/dest/foo_1")), target(&mut m, "foo", &stats)?);
+
+        assert!(m.is_renamed(Path::new("/dest/a_b_1.txt")));
+        assert!(!m.is_renamed(Path::new("/dest/a_b.txt")));
+        let updates = collect.0.lock().unwrap();
+        assert_eq!(3, updates.len());
+        assert!(matches!(&updates[0], StatusUpdate::Renamed { from, to }
+                         if from == Path::new("/dest/a:b.txt") && to == Path::new("/dest/a_b_1.txt")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_policy_skip() -> Result<()> {
+        let stats: Arc<dyn StatusUpdater> = Arc::new(Collect::default());
+        let mut m = mapper(InvalidName::Skip);
+
+        assert_eq!(None, target(&mut m, "a:b", &stats)?);
+        assert_eq!(Some(PathBuf::from("/dest/Foo")), target(&mut m, "Foo", &stats)?);
+        assert_eq!(None, target(&mut m, "FOO", &stats)?);
+        // Names are tracked per directory.
+        let other = m.target(Path::new("/src/d/foo"), Path::new("/dest/d"), OsStr::new("foo"), &stats)?;
+        assert_eq!(Some(PathBuf::from("/dest/d/foo")), other);
+        Ok(())
+    }
+
+    #[test]
+    fn test_policy_error() -> Result<()> {
+        let collect = Arc::new(Collect::default());
+        let stats: Arc<dyn StatusUpdater> = collect.clone();
+        let mut m = mapper(InvalidName::Error);
+
+        assert!(target(&mut m, "ok.txt", &stats)?.is_some());
+        assert!(target(&mut m, "a:b", &stats).is_err());
+        assert!(matches!(collect.0.lock().unwrap()[0],
+                         StatusUpdate::Error(XcpError::InvalidName(..))));
+
+        let config = Config {
+            continue_on_error: true,
+            ..Config::default()
+        };
+        let mut m = NameMapper::new(NameProfile::windows(), &config);
+        assert_eq!(None, target(&mut m, "a:b", &stats)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unrestricted() -> Result<()> {
+        let stats: Arc<dyn StatusUpdater> = Arc::new(Collect::default());
+        let mut m = NameMapper::new(NameProfile::default(), &Config::default());
+        assert_eq!(Some(PathBuf::from("/dest/a:b")), target(&mut m, "a:b", &stats)?);
+        assert_eq!(Some(PathBuf::from("/dest/a:b")), target(&mut m, "a:b", &stats)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fold() {
+        assert_eq!("makefile", fold(OsStr::new("Makefile"), true));
+        assert_eq!("Makefile", fold(OsStr::new("Makefile"), false));
+        assert_eq!(OsStr::from_bytes(b"bad\xff"), fold(OsStr::from_bytes(b"BAD\xff"), true));
+    }
+
+    #[test]
+    fn test_casefold_dirs() -> Result<()> {
+        let collect = Arc::new(Collect::default());
+        let stats: Arc<dyn StatusUpdater> = collect.clone();
+        let dir = tempfile::TempDir::new()?;
+        let profile = NameProfile {
+            casefold_dirs: true,
+            ..NameProfile::default()
+        };
+        let config = Config {
+            invalid_name: InvalidName::Skip,
+            ..Config::default()
+        };
+        let mut m = NameMapper::new(profile, &config);
+        assert!(m.is_restricted());
+
+        // An existing directory without casefolding; names aren't
+        // tracked.
+        let t = |m: &mut NameMapper, dir: &Path, name: &str| {
+            m.target(&Path::new("/src").join(name), dir, OsStr::new(name), &stats)
+        };
+        assert_eq!(Some(dir.path().join("Makefile")), t(&mut m, dir.path(), "Makefile")?);
+        assert_eq!(Some(dir.path().join("makefile")), t(&mut m, dir.path(), "makefile")?);
+        assert!(m.used[dir.path()].names.is_none());
+
+        // Directories yet to be created inherit casefolding.
+        let folded = dir.path().join("missing");
+        m.used.insert(folded.clone(), DirNames { case_insensitive: true, names: Some(HashSet::new()) });
+        let sub = folded.join("sub");
+        assert_eq!(Some(sub.join("Makefile")), t(&mut m, &sub, "Makefile")?);
+        assert_eq!(None, t(&mut m, &sub, "makefile")?);
+        assert!(matches!(&collect.0.lock().unwrap()[..],
+                         [StatusUpdate::NameSkipped(p)] if p == Path::new("/src/makefile")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_probe() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let profile = NameProfile::probe(dir.path())?;
+        assert!(profile.invalid_chars.is_empty());
+        assert_eq!(0, fs::read_dir(dir.path())?.count());
+        Ok(())
+    }
+}
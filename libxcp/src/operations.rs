@@ -14,94 +14,853 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+//! Lower-level copy operations.
+//!
+//! Most users will want the drivers; see [crate::drivers]. The
+//! functions here are for applications that need finer control over
+//! how a file is copied, such as [copy_file_blocks].
+
 use std::{cmp, thread};
-use std::fs::{self, canonicalize, create_dir_all, read_link, File, Metadata};
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::mem;
+use std::fs::{self, canonicalize, read_link, DirBuilder, File, Metadata, Permissions};
+use std::os::unix::fs::{fchown, symlink, DirBuilderExt, FileExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::ops::Range;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
 use crossbeam_channel as cbc;
 use libfs::{
-    allocate_file, copy_file_bytes, copy_owner, copy_permissions, copy_timestamps, next_sparse_segments, probably_sparse, reflink, sync, FileType
+    allocate_file, clone_file, clone_range, copy_file_at, copy_node, copy_file_offset, copy_link_xattrs, copy_xattrs, device_size, fs_type, is_exists, is_interrupted, is_io_error, is_no_space, is_not_dir, is_unsupported, next_sparse_segments, open_direct, probably_sparse, reflink, same_inode, set_xattr, sync, try_copy_file_bytes, log_repeated, warn_repeated, Attribute, FsType, SameFile, SELINUX_XATTR
 };
-use log::{debug, error, info, warn};
-use walkdir::WalkDir;
+#[cfg(all(target_os = "linux", feature = "use_linux", feature = "btrfs"))]
+use libfs::{create_subvolume, is_subvolume};
+use log::{debug, error, info, warn, Level};
 
 use crate::backup::{get_backup_path, needs_backup};
-use crate::config::{Config, Reflink};
-use crate::errors::{Result, XcpError};
-use crate::feedback::{StatusUpdate, StatusUpdater};
-use crate::paths::{parse_ignore, ignore_filter};
+use crate::checksum::{checksum_file, ChecksumType, FileChecksum, Hasher};
+use crate::compare::{compare_entry, Change, EntryKind, Item};
+use crate::config::{Config, DirMode, NoClobber, Order, PreserveSet, Reflink};
+use crate::conflict::create_renamed;
+use crate::dirs::{ChildGuard, DirCache, DirTracker};
+use crate::errlimit::ErrorLimit;
+use crate::immutable::{copy_flags_last, protected_error, unprotected};
+use crate::errors::{copy_error, is_destination_exists, is_early_shutdown, status_error, Result, XcpError};
+use crate::executor::Pool;
+use crate::ledger::Ledger;
+use crate::metastage::MetadataStage;
+use crate::metrics::{self, Metrics, WorkerState};
+use crate::feedback::{Action, CopyMethod, StatusUpdate, StatusUpdater};
+use crate::plan::{Event, Plan, PlanEntry, SkipReason, Step};
+use crate::readers::{self, ReadToken};
+use crate::rescue::{copy_rescued, format_ranges, merge_ranges, BAD_RANGES_XATTR};
+use crate::results::{FileResult, Results};
+use crate::stamp::stamp_file;
+use crate::staging::Staging;
+use crate::timestamps::Granularities;
 
-#[derive(Debug)]
-pub struct CopyHandle {
-    pub infd: File,
-    pub outfd: File,
-    pub metadata: Metadata,
-    pub config: Arc<Config>,
+pub(crate) static NEXT_FILE_ID: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) const NO_CLOBBER_MSG: &str = "Destination file exists and --no-clobber is set.";
+const SAME_FILE_MSG: &str = "Source and destination are the same file.";
+
+/// Destination devices that have rejected preallocation; files on
+/// these are written sequentially for the rest of the run.
+static NO_PREALLOC_DEVS: Mutex<BTreeSet<u64>> = Mutex::new(BTreeSet::new());
+
+// Whether to overwrite an existing destination in place rather than
+// truncating it first. This is only done where the lengths are
+// similar, so an interrupted copy leaves a file of roughly the right
+// length that is mostly old or mostly new data.
+fn overwrite_in_place(existing: u64, len: u64) -> bool {
+    existing > 0 && len > 0 && existing / 2 <= len && len / 2 <= existing
+}
+
+// Apply the destination symlink policy before opening `to`. Opening
+// a dangling symlink would create a file wherever it points, so by
+// default this is an error, [XcpError::DanglingDestination]. With
+// [Config::follow_dest_symlinks] the target is created, along with its
+// parent directories if [Config::mkdir_parents] is set. With
+// [Config::remove_destination] any destination symlink is removed and
+// replaced with a regular file.
+fn check_dest_symlink(to: &Path, config: &Config) -> Result<()> {
+    if !to.symlink_metadata().is_ok_and(|m| m.file_type().is_symlink()) {
+        return Ok(());
+    }
+    if config.remove_destination {
+        debug!("Removing destination symlink {:?}", to);
+        fs::remove_file(to).map_err(|e| protected_error(to, e.into()))?;
+        return Ok(());
+    }
+    if to.exists() {
+        return Ok(());
+    }
+
+    let target = read_link(to)?;
+    if !config.follow_dest_symlinks {
+        return Err(XcpError::DanglingDestination(to.to_path_buf(), target).into());
+    }
+    // A relative target is relative to the link's directory.
+    let resolved = to.parent().unwrap_or(Path::new("")).join(&target);
+    match resolved.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() && !parent.exists() => {
+            if !config.mkdir_parents {
+                return Err(XcpError::DanglingDestination(to.to_path_buf(), target).into());
+            }
+            debug!("Creating parents of symlink target {:?}", resolved);
+            DirBuilder::new()
+                .recursive(true)
+                .mode(config.new_dir_mode)
+                .create(parent)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// The path written to when writing through `to`. A staged file is
+// renamed into place, which would replace a symlink rather than
+// writing through it; with [Config::remove_destination] the link has
+// already been removed.
+fn link_target(to: &Path) -> PathBuf {
+    if !to.is_symlink() {
+        return to.to_path_buf();
+    }
+    canonicalize(to)
+        .or_else(|_| read_link(to).map(|t| to.parent().unwrap_or(Path::new("")).join(t)))
+        .unwrap_or_else(|_| to.to_path_buf())
+}
+
+// Create or truncate the destination file. If `in_place_len` is set
+// and the destination is an existing file of a similar length it is
+// opened without truncating, and the second value returned is
+// true. The copy then writes over the old data and truncates at the
+// end. With --no-clobber it is created exclusively (O_CREAT|O_EXCL)
+// rather than checked up-front, so a file created by another process
+// after the source walk is never overwritten.
+//
+// With the metadata of the open `source`, the destination is checked
+// to be a different file once it is open and before anything is
+// truncated, as `to` may have been replaced with a link to the source
+// since any earlier check.
+pub(crate) fn create_dest(to: &Path, config: &Config, in_place_len: Option<u64>, source: Option<&Metadata>) -> Result<(File, bool)> {
+    check_dest_symlink(to, config)?;
+    if config.no_clobber.is_none() {
+        let existing = to.metadata().ok()
+            .filter(|m| m.is_file())
+            .map(|m| m.len());
+        if let (Some(existing), Some(len)) = (existing, in_place_len) {
+            if overwrite_in_place(existing, len) {
+                debug!("Overwriting {:?} in place", to);
+                let outfd = unprotected(to, config, || Ok(config.fs.create_dest(to, File::options().write(true))?))?;
+                check_not_source(to, &outfd, source)?;
+                return Ok((outfd, true));
+            }
+        }
+        let outfd = unprotected(to, config, || Ok(config.fs.create_dest(to, File::options().write(true).create(true))?))?;
+        let meta = check_not_source(to, &outfd, source)?;
+        // Devices can't be truncated, and opening them with O_TRUNC
+        // is ignored anyway.
+        if meta.is_file() {
+            outfd.set_len(0)?;
+        }
+        return Ok((outfd, false));
+    }
+    config.fs.create_dest(to, File::options().write(true).create_new(true))
+        .map(|fd| (fd, false))
+        .map_err(|e| match e.kind() {
+            ErrorKind::AlreadyExists => XcpError::DestinationExists { path: to.to_path_buf(), reason: NO_CLOBBER_MSG }.into(),
+            _ => e.into(),
+        })
+}
+
+// Check that the open destination `outfd` is not the `source` file,
+// returning its metadata.
+fn check_not_source(to: &Path, outfd: &File, source: Option<&Metadata>) -> Result<Metadata> {
+    let meta = outfd.metadata()?;
+    if source.is_some_and(|s| (s.dev(), s.ino()) == (meta.dev(), meta.ino())) {
+        return Err(XcpError::InvalidDestination {
+            path: to.to_path_buf(),
+            reason: SAME_FILE_MSG,
+        }.into());
+    }
+    Ok(meta)
+}
+
+// With [NoClobber::Rename], create the destination file exclusively,
+// or if it exists a file for the copy under a new name; see
+// [crate::conflict]. Returns the file and its path.
+fn create_kept(to: &Path, config: &Config, updates: &Arc<dyn StatusUpdater>) -> Result<(File, PathBuf)> {
+    check_dest_symlink(to, config)?;
+    let create = |path: &Path| config.fs.create_dest(path, File::options().write(true).create_new(true));
+    match create(to) {
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            let (outfd, renamed) = create_renamed(to, &config.conflict_suffix, create)?;
+            updates.send(StatusUpdate::KeptBoth { from: to.to_path_buf(), to: renamed.clone() })?;
+            Ok((outfd, renamed))
+        }
+        created => Ok((created?, to.to_path_buf())),
+    }
+}
+
+/// Copy a special file such as a FIFO or device node, replacing any
+/// existing destination. With --no-clobber an existing destination is
+/// an error; `mknod(2)` fails atomically if it exists.
+pub(crate) fn copy_special(from: &Path, to: &Path, config: &Config) -> Result<()> {
+    if config.no_clobber.is_none() && to.symlink_metadata().is_ok() {
+        fs::remove_file(to)?;
+    }
+    copy_node(from, to)
+        .map_err(|e| match is_exists(&e) && config.no_clobber.is_some() {
+            true => XcpError::DestinationExists { path: to.to_path_buf(), reason: NO_CLOBBER_MSG }.into(),
+            false => e.into(),
+        })
+}
+
+/// Recreate the symlink `from` at `to`, with its xattrs if preserved.
+/// As with files, failing to copy the xattrs is a warning.
+pub(crate) fn copy_symlink(from: &Path, to: &Path, config: &Config) -> Result<()> {
+    let lfile = read_link(from)?;
+    debug!("Symlinking {:?} to {:?}", to, lfile);
+    symlink(&lfile, to)?;
+    if let Some(include) = xattr_filter(config).filter(|_| !xattrs_latched(config)) {
+        if let Err(e) = copy_link_xattrs(from, to, config.xattr_value_limit, &include) {
+            latch_xattrs(config, &e);
+            warn_repeated!(dev_of(to.symlink_metadata()), "Failed to copy xattrs from {:?}: {}", from, e);
+        }
+    }
+    Ok(())
+}
+
+/// Handle an error creating a destination. If the destination exists
+/// and [NoClobber::Skip] is set a [StatusUpdate::Skipped] is sent and
+/// `true` returned; the error should then be ignored.
+pub(crate) fn skip_existing(
+    err: &anyhow::Error,
+    from: &Path,
+    to: &Path,
+    config: &Config,
+    updates: &Arc<dyn StatusUpdater>,
+) -> Result<bool> {
+    if config.no_clobber != Some(NoClobber::Skip) || !is_destination_exists(err) {
+        return Ok(false);
+    }
+    debug!("Skipping existing destination {:?}", to);
+    let bytes = from.metadata().map(|m| m.len()).unwrap_or(0);
+    updates.send(StatusUpdate::Skipped { path: to.to_path_buf(), bytes, source: Some(from.to_path_buf()) })?;
+    Ok(true)
+}
+
+// Create the destination directory `to` of `from`, as a btrfs
+// subvolume if `from` is one; see [Config::preserve_subvolumes].
+// Returns whether it was created.
+#[cfg(all(target_os = "linux", feature = "use_linux", feature = "btrfs"))]
+fn ensure_dir(from: &Path, to: &Path, dirs: &DirCache, config: &Config, stats: &Arc<dyn StatusUpdater>) -> Result<bool> {
+    if !config.preserve_subvolumes || to.symlink_metadata().is_ok() || !is_subvolume(from)? {
+        return Ok(dirs.ensure(to)?);
+    }
+    let parent = to.parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    dirs.ensure_new(parent)?;
+    if fs_type(&File::open(parent)?)? != FsType::Btrfs {
+        warn!("Creating {:?} as a plain directory; the destination is not btrfs", to);
+        send_action(stats, config, Action::MetadataDegraded {
+            path: to.to_path_buf(),
+            detail: "subvolume created as a plain directory: the destination is not btrfs".to_string(),
+        })?;
+        return Ok(dirs.ensure(to)?);
+    }
+    debug!("Creating subvolume {:?}", to);
+    create_subvolume(to)?;
+    dirs.insert(to);
+    Ok(true)
+}
+
+#[cfg(not(all(target_os = "linux", feature = "use_linux", feature = "btrfs")))]
+fn ensure_dir(_from: &Path, to: &Path, dirs: &DirCache, _config: &Config, _stats: &Arc<dyn StatusUpdater>) -> Result<bool> {
+    Ok(dirs.ensure(to)?)
+}
+
+/// A copy faster than this is assumed to have been performed
+/// server-side; it is about the limit of a 10Gb network link.
+const OFFLOAD_MIN_RATE: f64 = 1024.0 * 1024.0 * 1024.0;
+
+// Whether to attempt a server-side copy. This is only possible within
+// a single network mount; across mounts the attempt would be a wasted
+// round-trip per file.
+fn offload_candidate(fstype: FsType, same_dev: bool) -> bool {
+    fstype.is_network() && same_dev
+}
+
+// Whether a copy_file_range call appears to have been offloaded to
+// the server, rather than the kernel copying the data itself. Small
+// files may be missed as their rate is dominated by latency.
+fn offloaded(len: u64, copied: u64, elapsed: Duration) -> bool {
+    copied == len && len as f64 / elapsed.as_secs_f64() >= OFFLOAD_MIN_RATE
+}
+
+// Preallocate the destination file. Preallocation is advisory; if the
+// destination filesystem doesn't support it (e.g. some FUSE mounts)
+// it is skipped for this and later files on the same device. Returns
+// whether the file was preallocated.
+fn preallocate(outfd: &File, dev: u64, to: &Path, len: u64, config: &Config) -> Result<bool> {
+    if config.no_fallocate {
+        return Ok(false);
+    }
+    if NO_PREALLOC_DEVS.lock().unwrap().contains(&dev) {
+        return Ok(false);
+    }
+    match allocate_file(outfd, len) {
+        Ok(()) => Ok(true),
+        Err(e) if is_unsupported(&e) => {
+            debug!("Preallocation of {:?} failed, writing sequentially: {}", to, e);
+            NO_PREALLOC_DEVS.lock().unwrap().insert(dev);
+            Ok(false)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Block devices are imaged sequentially in blocks of this size,
+/// rather than the configured block size.
+const DEVICE_BLOCK_SIZE: usize = 16 * 1024 * 1024;
+
+/// Sparse device images skip runs of zeros at this granularity.
+const SPARSE_CHUNK: usize = 64 * 1024;
+
+/// Buffer alignment for `O_DIRECT` reads; this covers the logical
+/// block size of common devices.
+pub(crate) const DIRECT_IO_ALIGN: usize = 4096;
+
+// Open a file to copy. Block devices are opened with O_DIRECT if
+// configured, falling back to a normal open if it isn't supported.
+// The source was classified by path while walking, so the open file
+// is checked again; it may have been replaced since.
+fn open_source(from: &Path, config: &Config) -> Result<(File, Metadata)> {
+    let infd = config.fs.open_source(from).map_err(|e| match is_not_dir(&e) {
+        true => XcpError::InvalidSource {
+            path: from.to_path_buf(),
+            reason: "A directory above the source was replaced during the copy.",
+        }.into(),
+        false => anyhow::Error::from(e),
+    })?;
+    let metadata = infd.metadata()?;
+    if !metadata.is_file() && !metadata.file_type().is_block_device() {
+        return Err(XcpError::InvalidSource {
+            path: from.to_path_buf(),
+            reason: "Source is no longer a regular file; it was replaced during the copy.",
+        }.into());
+    }
+    if !metadata.file_type().is_block_device() || config.no_direct_io {
+        return Ok((infd, metadata));
+    }
+    match open_direct(from) {
+        Ok(direct) => Ok((direct, metadata)),
+        Err(e) => {
+            debug!("Failed to open {:?} with O_DIRECT, using buffered reads: {}", from, e);
+            Ok((infd, metadata))
+        }
+    }
+}
+
+/// A flag shared between the walker and workers of a copy to signal
+/// that it should stop early, e.g. because the destination is full.
+/// It is also set once [Config::timeout] has passed, or the copy
+/// exceeds its [ErrorLimit].
+#[derive(Default)]
+pub(crate) struct Abort {
+    flag: AtomicBool,
+    /// The timeout and when it expires.
+    timeout: Option<(Duration, Instant)>,
+    expired: AtomicBool,
+    errors: Option<ErrorLimit>,
+}
+
+impl Abort {
+    /// The flag for a copy starting now.
+    pub(crate) fn new(config: &Config) -> Abort {
+        Abort {
+            timeout: config.timeout.map(|t| (t, Instant::now() + t)),
+            errors: ErrorLimit::new(config),
+            ..Abort::default()
+        }
+    }
+
+    /// Set the flag. Returns true if it was not already set.
+    pub(crate) fn set(&self) -> bool {
+        !self.flag.swap(true, Ordering::Relaxed)
+    }
+
+    pub(crate) fn is_set(&self) -> bool {
+        if self.flag.load(Ordering::Relaxed) {
+            return true;
+        }
+        match self.timeout {
+            Some((timeout, deadline)) if Instant::now() >= deadline => {
+                if !self.expired.swap(true, Ordering::Relaxed) {
+                    warn!("Copy timed out after {}s; stopping", timeout.as_secs());
+                }
+                self.set();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns [XcpError::TimedOut] if the copy was stopped by
+    /// [Config::timeout].
+    pub(crate) fn check_timeout(&self) -> Result<()> {
+        match self.timeout {
+            Some((timeout, _)) if self.expired.load(Ordering::Relaxed) =>
+                Err(XcpError::TimedOut(timeout.as_secs()).into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn has_error_limit(&self) -> bool {
+        self.errors.is_some()
+    }
+
+    /// Count an update against the [ErrorLimit]. Returns whether it
+    /// took the copy over the limit.
+    pub(crate) fn record(&self, update: &StatusUpdate) -> bool {
+        self.errors.as_ref().is_some_and(|e| e.record(update))
+    }
+
+    /// Returns the error for the limit exceeded, if the copy was
+    /// stopped by its [ErrorLimit].
+    pub(crate) fn check_errors(&self) -> Result<()> {
+        self.errors.as_ref().map_or(Ok(()), ErrorLimit::check)
+    }
+}
+
+pub(crate) struct CopyHandle {
+    pub(crate) infd: File,
+    pub(crate) outfd: File,
+    pub(crate) metadata: Metadata,
+    pub(crate) config: Arc<Config>,
+    /// The number of bytes to copy; for block devices this is the
+    /// device size.
+    pub(crate) len: u64,
+    /// The source is a block device, which is imaged.
+    pub(crate) device: bool,
+    /// An existing destination is being overwritten in place, and is
+    /// truncated to the source length once the copy is complete.
+    in_place: bool,
+    pub(crate) from: PathBuf,
+    pub(crate) to: PathBuf,
+    updates: Arc<dyn StatusUpdater>,
+    failed: AtomicBool,
+    digest: Mutex<Option<String>>,
+    abort: Arc<Abort>,
+    partial: AtomicBool,
+    /// The ranges of the file reported as copied, so a range copied
+    /// again after a failure isn't counted twice.
+    acknowledged: Mutex<Ledger>,
+    /// The destination was not preallocated, and must be written in
+    /// order without seeking past the end.
+    pub(crate) sequential: bool,
+    /// Source and destination are on the same network mount, so
+    /// the copy may be performed server-side.
+    pub(crate) offload: bool,
+    dest_dev: u64,
+    /// The size of each read and write, or of each parallel block;
+    /// see [Config::io_block_size].
+    pub(crate) block_size: u64,
+    /// How the data was copied, once known.
+    method: OnceLock<CopyMethod>,
+    started: Instant,
+    /// Released once the handle, and so the file, is complete.
+    guard: Option<ChildGuard>,
+    /// The file is written at this path under the staging directory,
+    /// and moved to the destination once complete; see
+    /// [Config::staging_dir].
+    staged: Option<(Arc<Staging>, PathBuf)>,
+    /// The size previously sent for this file in a
+    /// [StatusUpdate::Size]. If a different amount is copied the
+    /// difference is sent as a [StatusUpdate::TotalAdjust].
+    sized: Option<u64>,
+    /// Held while the source is open, and shared by the files of a
+    /// batch; see [Config::readers_per_device].
+    reader: Option<Arc<ReadToken>>,
+    /// Unreadable parts of the source, written as zeros; see
+    /// [Config::fill_errors].
+    bad_ranges: Mutex<Vec<Range<u64>>>,
+    /// The contents of a tiny file read by the walker, written in
+    /// place of copying the data; see [Config::tiny_file_fastpath].
+    pub(crate) inline: Option<Vec<u8>>,
+    /// Where the [FileResult] is recorded once the file is complete.
+    results: Option<Arc<Results>>,
+    /// Applies the metadata once the data is copied, if not done by
+    /// the worker; see [CopyHandle::release].
+    stage: Option<Arc<MetadataStage>>,
+    /// Sends the [StatusUpdate::FileCompleted]. Fields are dropped in
+    /// order after [CopyHandle::drop], so this must be the last: the
+    /// update is only sent once the files are closed.
+    completion: Completion,
+}
+
+// Sends the [StatusUpdate::FileCompleted] of a [CopyHandle] when
+// dropped, with the bytes set by [CopyHandle::drop].
+struct Completion {
+    updates: Arc<dyn StatusUpdater>,
+    id: u64,
+    bytes: u64,
+}
+
+impl Drop for Completion {
+    fn drop(&mut self) {
+        let _ = self.updates.send(StatusUpdate::FileCompleted(self.id, self.bytes));
+    }
 }
 
 impl CopyHandle {
-    pub fn new(from: &Path, to: &Path, config: &Arc<Config>) -> Result<CopyHandle> {
-        let infd = File::open(from)?;
-        let metadata = infd.metadata()?;
+    /// Hold the guard of the destination's directory entry until the
+    /// copy is complete; see [DirTracker].
+    pub(crate) fn with_guard(mut self, guard: ChildGuard) -> CopyHandle {
+        self.guard = Some(guard);
+        self
+    }
+
+    /// Record the outcome of the copy in `results` once it is
+    /// complete; see [FileResult].
+    pub(crate) fn with_results(mut self, results: &Arc<Results>) -> CopyHandle {
+        self.results = Some(results.clone());
+        self
+    }
+
+    /// Apply the metadata on `stage`, if any, once the data is
+    /// copied; see [CopyHandle::release].
+    pub(crate) fn with_stage(mut self, stage: Option<&Arc<MetadataStage>>) -> CopyHandle {
+        self.stage = stage.cloned();
+        self
+    }
+
+    /// Finish with the handle once the data has been copied, or has
+    /// failed to be. The metadata is applied and the file reported
+    /// complete as it is dropped, on the [MetadataStage] if there is
+    /// one and otherwise now.
+    pub(crate) fn release(mut self) {
+        match self.stage.take() {
+            Some(stage) => stage.finish(self),
+            None => drop(self),
+        }
+    }
+
+    pub(crate) fn new(
+        from: &Path,
+        to: &Path,
+        config: &Arc<Config>,
+        updates: &Arc<dyn StatusUpdater>,
+        abort: &Arc<Abort>,
+        staging: Option<&Arc<Staging>>,
+    ) -> Result<CopyHandle> {
+        Self::open(from, to, config, updates, abort, true, staging, None)
+    }
+
+    /// As [CopyHandle::new], for a file whose `len` bytes were sent
+    /// in a [StatusUpdate::Size]. If the file isn't copied in full,
+    /// including when it can't be opened or is skipped, the total is
+    /// adjusted to match.
+    pub(crate) fn walked(
+        from: &Path,
+        to: &Path,
+        len: u64,
+        config: &Arc<Config>,
+        updates: &Arc<dyn StatusUpdater>,
+        abort: &Arc<Abort>,
+        staging: Option<&Arc<Staging>>,
+    ) -> Result<CopyHandle> {
+        Self::sized(Self::new(from, to, config, updates, abort, staging), len, updates)
+    }
+
+    /// As [CopyHandle::walked], for a file of an [Operation::Batch].
+    /// The files of a batch are filled one at a time, so they share
+    /// the read token of their source device; it is taken by the
+    /// first file and kept in `reader`. Holding a token for each would
+    /// deadlock a batch larger than the limit.
+    pub(crate) fn batched(
+        file: &BatchedCopy,
+        reader: &mut Option<Arc<ReadToken>>,
+        config: &Arc<Config>,
+        updates: &Arc<dyn StatusUpdater>,
+        abort: &Arc<Abort>,
+        staging: Option<&Arc<Staging>>,
+    ) -> Result<CopyHandle> {
+        let opened = Self::open(&file.from, &file.to, config, updates, abort, true, staging, reader.as_ref());
+        let mut handle = Self::sized(opened, file.len, updates)?;
+        if reader.is_none() {
+            reader.clone_from(&handle.reader);
+        }
+        // The source may have changed since it was read.
+        handle.inline = file.data.clone()
+            .filter(|data| !handle.device && data.len() as u64 == handle.len);
+        Ok(handle)
+    }
+
+    fn sized(opened: Result<CopyHandle>, len: u64, updates: &Arc<dyn StatusUpdater>) -> Result<CopyHandle> {
+        match opened {
+            Ok(mut handle) => {
+                handle.sized = Some(len);
+                Ok(handle)
+            }
+            Err(e) => {
+                updates.send(StatusUpdate::TotalAdjust(-(len as i64)))?;
+                Err(e)
+            }
+        }
+    }
+
+    /// As [CopyHandle::new], but `in_place` can be false to always
+    /// truncate an existing destination, and a `reader` token already
+    /// held for the source device is shared rather than waiting for
+    /// another.
+    #[allow(clippy::too_many_arguments)]
+    fn open(
+        from: &Path,
+        to: &Path,
+        config: &Arc<Config>,
+        updates: &Arc<dyn StatusUpdater>,
+        abort: &Arc<Abort>,
+        in_place: bool,
+        staging: Option<&Arc<Staging>>,
+        reader: Option<&Arc<ReadToken>>,
+    ) -> Result<CopyHandle> {
+        metrics::set_state(WorkerState::Metadata);
+        let (infd, metadata) = open_source(from, config)?;
+        let reader = match reader {
+            Some(token) if token.dev() == metadata.dev() => Some(token.clone()),
+            _ => readers::acquire(metadata.dev(), config).map(Arc::new),
+        };
+        // The destination is opened through any symlink, so writing
+        // to a link to the source, or a hard link of it, would
+        // destroy it.
+        if same_inode(from, to, true)? == SameFile::Same {
+            return Err(XcpError::InvalidDestination {
+                path: to.to_path_buf(),
+                reason: SAME_FILE_MSG,
+            }.into());
+        }
+        let device = metadata.file_type().is_block_device();
+        let len = if device {
+            device_size(&infd)?
+        } else {
+            metadata.len()
+        };
 
         if needs_backup(to, config)? {
             let backup = get_backup_path(to)?;
             info!("Backup: Rename {:?} to {:?}", to, backup);
-            fs::rename(to, backup)?;
+            unprotected(to, config, || Ok(fs::rename(to, &backup)?))?;
+        }
+
+        // A staged file is always new; the destination is only
+        // replaced once it is complete.
+        let staged = staging.map(|s| (s.clone(), s.path(to)));
+        if staged.is_some() {
+            check_dest_symlink(to, config)?;
+            if config.no_clobber.is_some() && to.symlink_metadata().is_ok() {
+                return Err(XcpError::DestinationExists { path: to.to_path_buf(), reason: NO_CLOBBER_MSG }.into());
+            }
         }
 
-        let outfd = File::create(to)?;
-        allocate_file(&outfd, metadata.len())?;
+        // Holes in a sparse source would leave old data in place, so
+        // those destinations are truncated.
+        let in_place_len = (in_place && !device && !probably_sparse(&infd)?).then_some(len);
+        let (outfd, in_place, to) = match staged {
+            Some((_, ref path)) => {
+                let (outfd, in_place) = create_dest(path, config, None, None)?;
+                (outfd, in_place, to.to_path_buf())
+            }
+            None if config.no_clobber == Some(NoClobber::Rename) => {
+                let (outfd, to) = create_kept(to, config, updates)?;
+                (outfd, false, to)
+            }
+            None => {
+                let (outfd, in_place) = create_dest(to, config, in_place_len, Some(&metadata))?;
+                (outfd, in_place, to.to_path_buf())
+            }
+        };
+        let dest_meta = outfd.metadata()?;
+        let dest_dev = dest_meta.dev();
+        // A clone replaces the destination blocks, so allocating
+        // them first is wasted work. Sparse images leave unwritten
+        // blocks as holes.
+        let sequential = if (config.reflink == Reflink::Always && !device) || (device && config.sparse) {
+            outfd.set_len(len)?;
+            false
+        } else if in_place && dest_meta.len() >= len {
+            // Already allocated; shrinking now would lose old data
+            // early.
+            false
+        } else if config.tiny_file_fastpath && len <= TINY_FILE_LEN {
+            // Written in a single call, so there's nothing to gain.
+            true
+        } else {
+            !preallocate(&outfd, dest_dev, &to, len, config)?
+        };
+        let offload = !device && offload_candidate(fs_type(&infd)?, metadata.dev() == dest_dev);
+        let block_size = config.io_block_size(metadata.blksize(), dest_meta.blksize());
+        debug!("Using block size {} for {:?} (preferred IO sizes {} and {})",
+               block_size, from, metadata.blksize(), dest_meta.blksize());
 
         let handle = CopyHandle {
             infd,
             outfd,
             metadata,
             config: config.clone(),
+            len,
+            device,
+            in_place,
+            from: from.to_path_buf(),
+            to,
+            updates: updates.clone(),
+            failed: AtomicBool::new(false),
+            digest: Mutex::new(None),
+            abort: abort.clone(),
+            partial: AtomicBool::new(false),
+            acknowledged: Mutex::new(Ledger::default()),
+            sequential,
+            offload,
+            dest_dev,
+            block_size,
+            method: OnceLock::new(),
+            started: Instant::now(),
+            guard: None,
+            staged,
+            sized: None,
+            reader,
+            bad_ranges: Mutex::new(Vec::new()),
+            inline: None,
+            results: None,
+            stage: None,
+            completion: Completion {
+                updates: updates.clone(),
+                id: NEXT_FILE_ID.fetch_add(1, Ordering::Relaxed),
+                bytes: 0,
+            },
         };
+        updates.send(StatusUpdate::FileStarted(handle.completion.id, from.to_path_buf()))?;
 
         Ok(handle)
     }
 
-    /// Copy len bytes from wherever the descriptor cursors are set.
-    fn copy_bytes(&self, len: u64, updates: &Arc<dyn StatusUpdater>) -> Result<u64> {
-        let mut written = 0;
-        while written < len {
-            let bytes_to_copy = cmp::min(len - written, self.config.block_size);
-            let bytes = copy_file_bytes(&self.infd, &self.outfd, bytes_to_copy)? as u64;
-            written += bytes;
-            updates.send(StatusUpdate::Copied(bytes))?;
+    /// Copy len bytes from wherever the descriptor cursors are set. If
+    /// a hasher is supplied the data is copied via userspace and
+    /// hashed as it passes through.
+    fn copy_bytes(&self, len: u64, updates: &Arc<dyn StatusUpdater>, mut hasher: Option<&mut Hasher>) -> Result<u64> {
+        // Both cursors are at the same offset, which is tracked in
+        // case a block has to be recovered.
+        let mut pos = (&self.infd).stream_position()?;
+        // Only bytes not already acknowledged are reported.
+        let fresh = Cell::new(0);
+        let mut copy = |bytes_to_copy| -> Result<u64> {
+            self.check_abort()?;
+            let copied = match hasher {
+                Some(ref mut h) => self.config.fs.copy_bytes(&self.to, &self.infd, &self.outfd, bytes_to_copy, Some(&mut |b| h.update(b))),
+                None => self.config.fs.copy_bytes(&self.to, &self.infd, &self.outfd, bytes_to_copy, None),
+            };
+            let bytes = match copied {
+                Ok(bytes) => bytes,
+                Err(e) if is_io_error(&e) => {
+                    // Part of the block may have been copied.
+                    let failed = (&self.infd).stream_position()?;
+                    let end = pos + bytes_to_copy;
+                    self.rescue(failed, end - failed, &mut |b| if let Some(ref mut h) = hasher { h.update(b) })?;
+                    (&self.infd).seek(SeekFrom::Start(end))?;
+                    (&self.outfd).seek(SeekFrom::Start(end))?;
+                    bytes_to_copy
+                }
+                Err(e) => return Err(e.into()),
+            };
+            fresh.set(fresh.get() + self.acknowledge(pos..pos + bytes));
+            pos += bytes;
+            Ok(bytes)
+        };
+        copy_bytes_batched(len, self.block_size, &mut copy, &mut |_| match fresh.take() {
+            0 => Ok(()),
+            bytes => updates.send(StatusUpdate::Copied(bytes)),
+        })
+    }
+
+    /// Record a range of the file as copied, returning the number of
+    /// its bytes not previously recorded. Only these should be sent
+    /// as [StatusUpdate::Copied]; see [Ledger].
+    fn acknowledge(&self, range: Range<u64>) -> u64 {
+        let fresh = self.acknowledged.lock().unwrap().add(range);
+        metrics::add_bytes(fresh);
+        fresh
+    }
+
+    /// As [CopyHandle::acknowledge], sending the new bytes.
+    fn acknowledge_sent(&self, range: Range<u64>, updates: &Arc<dyn StatusUpdater>) -> Result<()> {
+        match self.acknowledge(range) {
+            0 => Ok(()),
+            bytes => updates.send(StatusUpdate::Copied(bytes)),
         }
+    }
+
+    /// The number of bytes of the file copied, counting each once.
+    fn written(&self) -> u64 {
+        self.acknowledged.lock().unwrap().total()
+    }
 
-        Ok(written)
+    /// Copy `len` bytes at `off` in both files after copying them
+    /// failed with `EIO`, recording any unreadable ranges; see
+    /// [crate::rescue].
+    fn rescue(&self, off: u64, len: u64, observer: &mut dyn FnMut(&[u8])) -> Result<()> {
+        metrics::set_state(WorkerState::Reading);
+        let filled = copy_rescued(&self.from, &self.infd, &self.outfd, off, len, &self.config, observer)?;
+        self.bad_ranges.lock().unwrap().extend(filled);
+        Ok(())
     }
 
-    /// Wrapper around copy_bytes that looks for sparse blocks and skips them.
-    fn copy_sparse(&self, updates: &Arc<dyn StatusUpdater>) -> Result<u64> {
-        let len = self.metadata.len();
+    /// Wrapper around copy_bytes that looks for sparse blocks and
+    /// skips them. Holes are fed to the hasher as zeros.
+    fn copy_sparse(&self, updates: &Arc<dyn StatusUpdater>, mut hasher: Option<&mut Hasher>) -> Result<u64> {
+        let len = self.len;
         let mut pos = 0;
 
         while pos < len {
             let (next_data, next_hole) = next_sparse_segments(&self.infd, &self.outfd, pos)?;
 
-            let _written = self.copy_bytes(next_hole - next_data, updates)?;
+            if let Some(ref mut h) = hasher {
+                hash_zeros(h, next_data - pos);
+            }
+            let _written = self.copy_bytes(next_hole - next_data, updates, hasher.as_deref_mut())?;
             pos = next_hole;
         }
 
         Ok(len)
     }
 
-    pub fn try_reflink(&self) -> Result<bool> {
+    pub(crate) fn try_reflink(&self) -> Result<bool> {
         match self.config.reflink {
-            Reflink::Always | Reflink::Auto => {
-                debug!("Attempting reflink from {:?}->{:?}", self.infd, self.outfd);
-                let worked = reflink(&self.infd, &self.outfd)?;
-                if worked {
-                    debug!("Reflink {:?} succeeded", self.outfd);
+            Reflink::Always => {
+                debug!("Attempting reflink from {:?}->{:?}", self.from, self.to);
+                clone_file(&self.infd, &self.outfd)
+                    .map_err(|e| {
+                        // Nothing was copied, so don't leave an empty file.
+                        self.partial.store(true, Ordering::Relaxed);
+                        XcpError::ReflinkFailed { from: self.from.clone(), to: self.to.clone(), source: e }
+                    })?;
+                self.reflinked()?;
+                Ok(true)
+            }
+
+            Reflink::Auto => {
+                debug!("Attempting reflink from {:?}->{:?}", self.from, self.to);
+                if reflink(&self.infd, &self.outfd)? {
+                    self.reflinked()?;
                     Ok(true)
-                } else if self.config.reflink == Reflink::Always {
-                    Err(XcpError::ReflinkFailed(format!("{:?}->{:?}", self.infd, self.outfd)).into())
                 } else {
                     debug!("Failed to reflink, falling back to copy");
                     Ok(false)
@@ -114,148 +873,2705 @@ impl CopyHandle {
         }
     }
 
-    pub fn copy_file(&self, updates: &Arc<dyn StatusUpdater>) -> Result<u64> {
-        if self.try_reflink()? {
-            return Ok(self.metadata.len());
+    /// Attempt to copy the whole file with a single
+    /// `copy_file_range` call, which network filesystems may perform
+    /// server-side. This takes precedence over sparse detection and
+    /// hashing in userspace; checksums are calculated from the
+    /// destination afterwards. Returns `None` if the call isn't
+    /// supported, in which case nothing has been copied.
+    fn try_offload(&self, updates: &Arc<dyn StatusUpdater>) -> Result<Option<u64>> {
+        let len = self.len;
+        debug!("Attempting server-side copy {:?}->{:?}", self.from, self.to);
+        let start = Instant::now();
+        let Some(copied) = try_copy_file_bytes(&self.infd, &self.outfd, len)? else {
+            debug!("Server-side copy not supported, falling back to copy");
+            return Ok(None);
+        };
+        self.acknowledge_sent(0..copied, updates)?;
+        if offloaded(len, copied, start.elapsed()) {
+            debug!("Server-side copy {:?} succeeded", self.to);
+            updates.send(StatusUpdate::Offloaded(len))?;
+            self.set_method(CopyMethod::ServerSide);
+        } else {
+            self.set_method(CopyMethod::Kernel);
+        }
+
+        let rest = self.copy_bytes(len - copied, updates, None)?;
+        Ok(Some(copied + rest))
+    }
+
+    fn reflinked(&self) -> Result<()> {
+        debug!("Reflink {:?} succeeded", self.to);
+        self.set_method(CopyMethod::Reflink);
+        let len = self.len;
+        // Any data already copied is replaced by the clone.
+        self.acknowledge_sent(0..len, &self.updates)?;
+        self.updates.send(StatusUpdate::Reflinked(len))?;
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        name = "copy_file", skip_all, fields(from = ?self.from, bytes = self.len)))]
+    pub(crate) fn copy_file(&self, updates: &Arc<dyn StatusUpdater>) -> Result<u64> {
+        self.copy_data(updates)
+            .map_err(|e| {
+                self.mark_failed();
+                self.copy_error(e)
+            })
+    }
+
+    /// Handle a failed copy. If the destination filesystem is full
+    /// the partial file is removed on completion, and the copy is
+    /// aborted unless configured otherwise. Only the first error
+    /// causing the abort is returned as
+    /// [XcpError::DestinationFull]; later ones become
+    /// [XcpError::EarlyShutdown] and should not be reported.
+    fn copy_error(&self, err: anyhow::Error) -> anyhow::Error {
+        if is_no_space(err.as_ref()) {
+            self.partial.store(true, Ordering::Relaxed);
+            if !self.config.really_continue_on_enospc && !self.abort.set() {
+                return XcpError::EarlyShutdown("Destination full").into();
+            }
+            return XcpError::DestinationFull {
+                path: self.to.clone(),
+                written: self.written(),
+                needed: self.len,
+            }.into();
+        }
+        err
+    }
+
+    /// Check if the copy has been aborted, or this file has taken
+    /// longer than [Config::file_timeout]; if so the destination is
+    /// incomplete and will be removed. This is checked between
+    /// blocks, so a single hung read or write can't be interrupted.
+    /// A file timeout is returned as [XcpError::FileTimedOut] the
+    /// first time, and otherwise [XcpError::EarlyShutdown].
+    fn check_abort(&self) -> Result<()> {
+        if self.abort.is_set() {
+            self.partial.store(true, Ordering::Relaxed);
+            return Err(XcpError::EarlyShutdown("Copy aborted").into());
+        }
+        match self.config.file_timeout {
+            Some(timeout) if self.started.elapsed() >= timeout => {
+                if self.partial.swap(true, Ordering::Relaxed) {
+                    return Err(XcpError::EarlyShutdown("File timed out").into());
+                }
+                Err(XcpError::FileTimedOut(self.from.clone(), timeout.as_secs()).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn copy_data(&self, updates: &Arc<dyn StatusUpdater>) -> Result<u64> {
+        metrics::set_state(WorkerState::Writing);
+        if let Some(data) = &self.inline {
+            return self.write_inline(data, updates);
         }
-        let total = if probably_sparse(&self.infd)? {
-            self.copy_sparse(updates)?
+        if !self.device && self.try_reflink()? {
+            return Ok(self.len);
+        }
+        if self.offload {
+            if let Some(total) = self.try_offload(updates)? {
+                return Ok(total);
+            }
+        }
+
+        // If we need a checksum then copying via userspace lets us
+        // hash the data on the way through rather than re-reading the
+        // destination afterwards.
+        let mut hasher = self.config.hash_type().map(Hasher::new);
+        let total = if self.device {
+            self.set_method(CopyMethod::Image);
+            self.copy_device(updates, hasher.as_mut())?
+        } else if !self.sequential && probably_sparse(&self.infd)? {
+            self.set_method(CopyMethod::Sparse);
+            self.copy_sparse(updates, hasher.as_mut())?
         } else {
-            self.copy_bytes(self.metadata.len(), updates)?
+            self.set_method(if hasher.is_some() { CopyMethod::Userspace } else { CopyMethod::Kernel });
+            self.copy_bytes(self.len, updates, hasher.as_mut())?
         };
 
+        if let Some(h) = hasher {
+            *self.digest.lock().unwrap() = Some(h.finalize());
+        }
+
         Ok(total)
     }
 
-    fn finalise_copy(&self) -> Result<()> {
-        if !self.config.no_perms {
-            copy_permissions(&self.infd, &self.outfd)?;
+    /// Write the contents of a tiny file read by the walker. Empty
+    /// files have nothing to write.
+    fn write_inline(&self, data: &[u8], updates: &Arc<dyn StatusUpdater>) -> Result<u64> {
+        self.set_method(CopyMethod::Inline);
+        self.check_abort()?;
+        let len = data.len() as u64;
+        if len > 0 {
+            self.outfd.write_all_at(data, 0)?;
+            self.acknowledge_sent(0..len, updates)?;
+        }
+        if let Some(ctype) = self.config.hash_type() {
+            let mut hasher = Hasher::new(ctype);
+            hasher.update(data);
+            *self.digest.lock().unwrap() = Some(hasher.finalize());
+        }
+        Ok(len)
+    }
+
+    /// Image a block device sequentially in large blocks, stopping
+    /// exactly at the device size. With [Config::sparse] runs of
+    /// zeros are not written, leaving holes in the destination.
+    fn copy_device(&self, updates: &Arc<dyn StatusUpdater>, mut hasher: Option<&mut Hasher>) -> Result<u64> {
+        // O_DIRECT requires an aligned buffer.
+        let mut raw = vec![0; DEVICE_BLOCK_SIZE + DIRECT_IO_ALIGN];
+        let start = raw.as_ptr().align_offset(DIRECT_IO_ALIGN);
+        let buf = &mut raw[start..start + DEVICE_BLOCK_SIZE];
+
+        let mut pos = 0;
+        while pos < self.len {
+            self.check_abort()?;
+            let block = &mut buf[..cmp::min(self.len - pos, DEVICE_BLOCK_SIZE as u64) as usize];
+            metrics::set_state(WorkerState::Reading);
+            // Retries short reads; the device ending early is an
+            // error as its size is known.
+            match self.infd.read_exact_at(block, pos) {
+                Ok(()) => {}
+                Err(e) if is_io_error(&e) => {
+                    self.rescue(pos, block.len() as u64, &mut |b| if let Some(ref mut h) = hasher { h.update(b) })?;
+                    let bytes = block.len() as u64;
+                    self.acknowledge_sent(pos..pos + bytes, updates)?;
+                    pos += bytes;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            }
+            if let Some(ref mut h) = hasher {
+                h.update(block);
+            }
+            metrics::set_state(WorkerState::Writing);
+            if self.config.sparse {
+                for run in nonzero_runs(block) {
+                    self.outfd.write_all_at(&block[run.clone()], pos + run.start as u64)?;
+                }
+            } else {
+                self.outfd.write_all_at(block, pos)?;
+            }
+            let bytes = block.len() as u64;
+            self.acknowledge_sent(pos..pos + bytes, updates)?;
+            pos += bytes;
         }
-        if !self.config.no_timestamps {
-            copy_timestamps(&self.infd, &self.outfd)?;
+
+        Ok(pos)
+    }
+
+    /// Record how the data is copied; only the first method set is
+    /// kept.
+    pub(crate) fn set_method(&self, method: CopyMethod) {
+        let _ = self.method.set(method);
+    }
+
+    /// Flag that copying the data failed; post-copy steps such as
+    /// checksumming will be skipped.
+    pub(crate) fn mark_failed(&self) {
+        self.failed.store(true, Ordering::Relaxed);
+    }
+
+    /// Discard the handle of a file that was created but won't be
+    /// copied, e.g. when a batch is stopped part-way. The destination
+    /// is removed as a partial copy would be.
+    pub(crate) fn abandon(self) {
+        debug!("Abandoning copy of {:?} to {:?}", self.from, self.to);
+        self.partial.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn has_failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "metadata", skip_all, fields(to = ?self.to)))]
+    fn finalise_copy(&self) -> Result<Vec<String>> {
+        metrics::set_state(WorkerState::Metadata);
+        let mut degraded = Vec::new();
+        if self.in_place {
+            if self.has_failed() {
+                warn!("{:?} has been partly overwritten; use --backup to keep the previous version of overwritten files", self.to);
+            } else {
+                self.config.fs.truncate(&self.to, &self.outfd, self.len)?;
+            }
         }
-        if self.config.ownership && copy_owner(&self.infd, &self.outfd).is_err() {
-            warn!("Failed to copy file ownership: {:?}", self.infd);
+        // The metadata of a device node doesn't apply to an image of
+        // its contents.
+        if !self.device {
+            degraded = copy_metadata(&self.to, &self.infd, &self.outfd, &self.config)?;
         }
-        if self.config.fsync {
+        apply_overrides(&self.to, &self.outfd, false, &self.config)?;
+        self.record_bad_ranges();
+        if self.config.fsync || self.staged.is_some() {
             debug!("Syncing file {:?}", self.outfd);
             sync(&self.outfd)?;
         }
-        Ok(())
+        Ok(degraded)
+    }
+
+    // Merge the unreadable ranges, and record them on the destination
+    // if possible; see [BAD_RANGES_XATTR].
+    fn record_bad_ranges(&self) {
+        let mut bad = self.bad_ranges.lock().unwrap();
+        if bad.is_empty() {
+            return;
+        }
+        *bad = merge_ranges(mem::take(&mut *bad));
+        match set_xattr(&self.outfd, BAD_RANGES_XATTR, format_ranges(&bad).as_bytes()) {
+            Ok(true) => {}
+            Ok(false) => debug!("Xattrs not supported; not recording unreadable ranges of {:?}", self.to),
+            Err(e) => warn!("Failed to record unreadable ranges of {:?}: {}", self.to, e),
+        }
+    }
+
+    // The digest of the copy, as hashed while copying where possible.
+    fn digest(&self, ctype: ChecksumType) -> Result<String> {
+        // The data may have bypassed userspace (e.g. reflink or the
+        // parblock driver), in which case we need to read it back.
+        let hashed = self.digest.lock().unwrap().clone()
+            .filter(|_| self.config.hash_type() == Some(ctype));
+        match hashed {
+            Some(digest) => Ok(digest),
+            None => {
+                debug!("Checksumming {:?}", self.to);
+                checksum_file(&self.to, ctype)
+            }
+        }
+    }
+
+    fn send_checksum(&self, ctype: ChecksumType) -> Result<()> {
+        let sum = FileChecksum::from_digest(&self.to, self.digest(ctype)?)?;
+        self.updates.send(StatusUpdate::Checksum(sum))
     }
 }
 
 impl Drop for CopyHandle {
     fn drop(&mut self) {
+        let written = self.written();
+        self.completion.bytes = written;
+        let _ = self.updates.send(StatusUpdate::DeviceCopied {
+            source: self.metadata.dev(),
+            dest: self.dest_dev,
+            bytes: written,
+            elapsed: self.started.elapsed(),
+        });
+        if let Some(sized) = self.sized {
+            if written != sized {
+                let _ = self.updates.send(StatusUpdate::TotalAdjust(written as i64 - sized as i64));
+            }
+        }
+
+        if self.partial.load(Ordering::Relaxed) {
+            if self.in_place {
+                // Removing it would lose the old data too.
+                warn!("{:?} may have been partly overwritten; use --backup to keep the previous version of overwritten files", self.to);
+            } else {
+                let path = self.staged.as_ref().map_or(&self.to, |(_, p)| p);
+                debug!("Removing partial file {:?}", path);
+                if let Err(e) = fs::remove_file(path) {
+                    warn!("Failed to remove partial file {:?}: {}", path, e);
+                }
+            }
+            return;
+        }
+
         // FIXME: Should we check for panicking() here?
-        if let Err(e) = self.finalise_copy() {
-            error!("Error during finalising copy operation {:?} -> {:?}: {}", self.infd, self.outfd, e);
+        // A file whose metadata, truncation or sync fails has failed
+        // as a whole, and is reported as such before its
+        // [StatusUpdate::FileCompleted].
+        let mut degraded = match self.finalise_copy() {
+            Ok(degraded) => degraded,
+            Err(e) => {
+                error!("Error during finalising copy operation {:?} -> {:?}: {}", self.from, self.to, e);
+                if !self.has_failed() {
+                    self.mark_failed();
+                    let _ = self.updates.send(StatusUpdate::Error(copy_error(&e, &self.from, &self.to)));
+                }
+                Vec::new()
+            }
+        };
+        // Incomplete staged files are left to be removed with the
+        // staging directory.
+        if let Some((staging, path)) = self.staged.as_ref().filter(|_| !self.has_failed()) {
+            let target = link_target(&self.to);
+            let committed = unprotected(&target, &self.config, || staging.commit(path, &target, &self.config));
+            if let Err(e) = committed {
+                error!("Failed to move staged file {:?} to {:?}: {}", path, self.to, e);
+                self.mark_failed();
+                let _ = self.updates.send(StatusUpdate::Error(copy_error(&e, &self.from, &self.to)));
+            }
+        }
+        if let Some(ctype) = self.config.checksum {
+            if !self.has_failed() {
+                if let Err(e) = self.send_checksum(ctype) {
+                    error!("Error checksumming {:?}: {}", self.to, e);
+                    self.mark_failed();
+                    let _ = self.updates.send(StatusUpdate::Error(copy_error(&e, &self.from, &self.to)));
+                }
+            }
+        }
+        if self.config.stamp_checksum && !self.has_failed() {
+            let stamped = self.digest(ChecksumType::Blake3)
+                .and_then(|digest| stamp_file(&self.outfd, &self.to, self.dest_dev, &digest, &self.metadata));
+            if let Err(e) = stamped {
+                warn_repeated!(self.dest_dev, "Failed to stamp the checksum of {:?}: {}", self.to, e);
+            }
+        }
+        // Nothing can be changed once the destination is immutable, so
+        // this comes after the checksum and stamp. A staged file may
+        // have been copied into place rather than renamed.
+        if !self.has_failed() && !self.device && self.config.preserve.contains(PreserveSet::FLAGS) {
+            let flagged = match self.staged {
+                Some(_) => File::open(link_target(&self.to)).map(|fd| copy_flags_last(&self.to, &self.infd, &fd, &self.config)),
+                None => Ok(copy_flags_last(&self.to, &self.infd, &self.outfd, &self.config)),
+            };
+            match flagged {
+                Ok(failed) => degraded.extend(failed),
+                Err(e) => degraded.push(format!("flags not copied: {}", e)),
+            }
+        }
+        let bad = mem::take(self.bad_ranges.get_mut().unwrap());
+        if !bad.is_empty() && !self.has_failed() {
+            let _ = self.updates.send(StatusUpdate::BadRanges { path: self.to.clone(), ranges: bad });
+        }
+        let method = self.method.get().copied().unwrap_or(CopyMethod::Kernel);
+        if self.config.report_actions && !self.has_failed() {
+            for detail in &degraded {
+                let _ = self.updates.send(StatusUpdate::Action(Action::MetadataDegraded {
+                    path: self.to.clone(),
+                    detail: detail.clone(),
+                }));
+            }
+            let _ = self.updates.send(StatusUpdate::Action(Action::FileCopied {
+                from: self.from.clone(),
+                to: self.to.clone(),
+                bytes: written,
+                method,
+            }));
+        }
+        if let Some(results) = self.results.as_ref().filter(|_| !self.has_failed()) {
+            results.record(FileResult {
+                from: self.from.clone(),
+                to: self.to.clone(),
+                bytes: written,
+                method,
+                degraded,
+                duration: self.started.elapsed(),
+            });
         }
     }
 }
 
+/// An entry for the workers to create. The [ChildGuard] should be
+/// held until the entry is complete.
 #[derive(Debug)]
-pub enum Operation {
-    Copy(PathBuf, PathBuf),
-    Link(PathBuf, PathBuf),
-    Special(PathBuf, PathBuf),
+pub(crate) enum Operation {
+    /// A file copy, with the size sent for it in a
+    /// [StatusUpdate::Size].
+    Copy(PathBuf, PathBuf, u64, ChildGuard),
+    /// A symlink copy, from the source symlink; see [copy_symlink].
+    Link(PathBuf, PathBuf, ChildGuard),
+    Special(PathBuf, PathBuf, ChildGuard),
+    /// Small file copies to one directory, to be created together and
+    /// then filled; see [Config::batch_dirents].
+    Batch(Vec<BatchedCopy>),
 }
 
-pub fn tree_walker(
-    sources: Vec<PathBuf>,
-    dest: &Path,
-    config: &Config,
-    work_tx: cbc::Sender<Operation>,
-    stats: Arc<dyn StatusUpdater>,
-) -> Result<()> {
-    debug!("Starting walk worker {:?}", thread::current().id());
+/// A file copy in an [Operation::Batch].
+#[derive(Debug)]
+pub(crate) struct BatchedCopy {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    /// The size sent for the file in a [StatusUpdate::Size].
+    pub len: u64,
+    pub guard: ChildGuard,
+    /// The contents of a tiny file, read by the walker; see
+    /// [Config::tiny_file_fastpath].
+    pub data: Option<Vec<u8>>,
+}
 
-    for source in sources {
-        let sourcedir = source
-            .components()
-            .last()
-            .ok_or(XcpError::InvalidSource("Failed to find source directory name."))?;
+// A further link to an already-copied source file, to be copied from
+// the first destination rather than re-reading the source.
+struct LinkedCopy {
+    from: PathBuf,
+    existing: PathBuf,
+    target: PathBuf,
+    len: u64,
+    mtime: SystemTime,
+    guard: ChildGuard,
+}
 
-        let target_base = if dest.exists() && dest.is_dir() && !config.no_target_directory {
-            dest.join(sourcedir)
-        } else {
-            dest.to_path_buf()
-        };
-        debug!("Target base is {:?}", target_base);
-
-        let gitignore = parse_ignore(&source, config)?;
-
-        for entry in WalkDir::new(&source)
-            .into_iter()
-            .filter_entry(|e| ignore_filter(e, &gitignore))
-        {
-            debug!("Got tree entry {:?}", entry);
-            let epath = entry?.into_path();
-            let from = if config.dereference {
-                let cpath = canonicalize(&epath)?;
-                debug!("Dereferencing {:?} into {:?}", epath, cpath);
-                cpath
-            } else {
-                epath.clone()
+/// Work deferred by [tree_walker] until all files have been copied.
+pub(crate) struct Walked {
+    /// Directories that need source metadata applied.
+    dirs: Arc<DirTracker>,
+    /// Hard-links to create, as (existing, new) destination paths.
+    links: Vec<(PathBuf, PathBuf, ChildGuard)>,
+    /// Copies of multiply-linked sources; see
+    /// [Config::cache_linked_sources].
+    copies: Vec<LinkedCopy>,
+}
+
+impl Walked {
+    /// Whether there are copies of multiply-linked sources to make
+    /// once the first copies are complete; see [Walked::send_copies].
+    pub(crate) fn has_copies(&self) -> bool {
+        !self.copies.is_empty()
+    }
+
+    /// Send the copies of multiply-linked sources to the workers on
+    /// `work_tx`, once all other files have been copied; see
+    /// [Config::cache_linked_sources]. A copy is made from the first
+    /// destination if that completed and the source hasn't changed
+    /// since it was walked, and otherwise from the source.
+    pub(crate) fn send_copies(
+        &mut self,
+        config: &Config,
+        work_tx: cbc::Sender<Operation>,
+        results: &Results,
+        metrics: &Arc<Metrics>,
+    ) -> Result<()> {
+        let mut dispatch = Dispatcher::new(config.order, false, work_tx, metrics.clone());
+        let mut saved = 0;
+        for copy in mem::take(&mut self.copies) {
+            let reusable = results.completed(&copy.existing) && match (copy.from.metadata(), copy.existing.metadata()) {
+                (Ok(smeta), Ok(dmeta)) => smeta.modified().is_ok_and(|m| m == copy.mtime)
+                    && smeta.len() == copy.len
+                    && dmeta.len() == copy.len,
+                _ => false,
             };
-            let meta = from.symlink_metadata()?;
-            let path = epath.strip_prefix(&source)?;
-            let target = if !empty_path(path) {
-                target_base.join(path)
+            let from = if reusable {
+                saved += copy.len;
+                copy.existing
             } else {
-                target_base.clone()
+                debug!("Cannot reuse {:?}, copying from source {:?}", copy.existing, copy.from);
+                copy.from
             };
+            debug!("Send copy of linked source {:?} to {:?}", from, copy.target);
+            dispatch.copy(from, copy.target, copy.guard, copy.len, None)?;
+        }
+        if saved > 0 {
+            info!("Copying {} bytes from existing destination files instead of re-reading the source", saved);
+        }
+        dispatch.flush()
+    }
 
-            if config.no_clobber && target.exists() {
-                let msg = "Destination file exists and --no-clobber is set.";
-                stats.send(StatusUpdate::Error(
-                    XcpError::DestinationExists(msg, target)))?;
-                return Err(XcpError::EarlyShutdown(msg).into());
+    /// Create the hard-links, and then apply the metadata of any
+    /// directories not yet finalised; see [DirTracker]. Failures are
+    /// reported as errors.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub(crate) fn finish(self, config: &Config, stats: &Arc<dyn StatusUpdater>) -> Result<()> {
+        for (existing, link, _guard) in self.links {
+            debug!("Hard-linking {:?} to {:?}", link, existing);
+            let r = match link.symlink_metadata() {
+                Ok(_) if config.no_clobber.is_none() => fs::remove_file(&link),
+                _ => Ok(()),
+            }.and_then(|_| fs::hard_link(&existing, &link));
+            if let Err(e) = r {
+                if e.kind() == ErrorKind::AlreadyExists && config.no_clobber == Some(NoClobber::Skip) {
+                    debug!("Skipping existing destination {:?}", link);
+                    stats.send(StatusUpdate::Skipped { path: link, bytes: 0, source: None })?;
+                    continue;
+                }
+                error!("Failed to hard-link {:?} to {:?}: {}", link, existing, e);
+                stats.send(StatusUpdate::Error(XcpError::CopyError(
+                    format!("Failed to hard-link {:?} to {:?}: {}", link, existing, e))))?;
             }
+        }
 
-            let ft = FileType::from(meta.file_type());
-            match ft {
-                FileType::File => {
-                    debug!("Send copy operation {:?} to {:?}", from, target);
-                    stats.send(StatusUpdate::Size(meta.len()))?;
-                    work_tx.send(Operation::Copy(from, target))?;
-                }
+        self.dirs.finish();
+        Ok(())
+    }
+}
+
+/// With [Order::LargestFirst], files at least this large are
+/// dispatched as soon as they are found rather than after the scan.
+const EARLY_DISPATCH_SIZE: u64 = 64 * 1024 * 1024;
+
+/// With [Config::batch_dirents], files up to this size are batched.
+const BATCH_MAX_LEN: u64 = 64 * 1024;
+
+/// With [Config::tiny_file_fastpath], files up to this size are read
+/// by the walker and written by the workers in a single call.
+pub(crate) const TINY_FILE_LEN: u64 = 512;
+
+/// The most files in a batch. Each holds its source and destination
+/// open from creation until it is filled, so this also bounds the
+/// open files of each worker.
+const BATCH_FILES: usize = 32;
+
+/// Sends operations to the workers, holding back copies until the end
+/// of the walk if they are to be reordered; see [Order]. Small files
+/// may be batched by destination directory; see
+/// [Config::batch_dirents]. Tiny files read by the walker are always
+/// sent as batches, alone if not batching.
+struct Dispatcher {
+    order: Order,
+    work_tx: cbc::Sender<Operation>,
+    /// Held copies, with their size.
+    held: Vec<(u64, Operation)>,
+    batch_dirents: bool,
+    /// The batch being filled, all in one directory.
+    batch: Vec<BatchedCopy>,
+    metrics: Arc<Metrics>,
+}
 
-                FileType::Symlink => {
-                    let lfile = read_link(from)?;
-                    debug!("Send symlink operation {:?} to {:?}", lfile, target);
-                    work_tx.send(Operation::Link(lfile, target))?;
+impl Dispatcher {
+    fn new(order: Order, batch_dirents: bool, work_tx: cbc::Sender<Operation>, metrics: Arc<Metrics>) -> Self {
+        Dispatcher { order, work_tx, held: Vec::new(), batch_dirents, batch: Vec::new(), metrics }
+    }
+
+    fn send(&self, op: Operation) -> Result<()> {
+        // Counted first, so the depth can't be seen below zero.
+        self.metrics.enqueued();
+        Ok(self.work_tx.send(op)?)
+    }
+
+    fn send_batch(&mut self) -> Result<()> {
+        if !self.batch.is_empty() {
+            let batch = std::mem::take(&mut self.batch);
+            self.send(Operation::Batch(batch))?;
+        }
+        Ok(())
+    }
+
+    fn copy(&mut self, from: PathBuf, to: PathBuf, guard: ChildGuard, len: u64, data: Option<Vec<u8>>) -> Result<()> {
+        if self.batch_dirents && len <= BATCH_MAX_LEN {
+            // The walk may leave a directory and return to it, so it
+            // may have several batches.
+            if self.batch.first().is_some_and(|c| c.to.parent() != to.parent()) {
+                self.send_batch()?;
+            }
+            self.batch.push(BatchedCopy { from, to, len, guard, data });
+            if self.batch.len() >= BATCH_FILES {
+                self.send_batch()?;
+            }
+            return Ok(());
+        }
+        let op = match data {
+            Some(data) => Operation::Batch(vec![BatchedCopy { from, to, len, guard, data: Some(data) }]),
+            None => Operation::Copy(from, to, len, guard),
+        };
+        match self.order {
+            Order::Scan => self.send(op),
+            Order::LargestFirst if len >= EARLY_DISPATCH_SIZE => self.send(op),
+            Order::LargestFirst | Order::SmallestFirst => {
+                self.held.push((len, op));
+                Ok(())
+            }
+        }
+    }
+
+    /// Send any batch, and then the held copies in order. The sort is
+    /// stable, so files of the same size stay in scan order.
+    fn flush(&mut self) -> Result<()> {
+        self.send_batch()?;
+        let mut held = std::mem::take(&mut self.held);
+        match self.order {
+            Order::LargestFirst => held.sort_by_key(|(len, ..)| Reverse(*len)),
+            Order::SmallestFirst => held.sort_by_key(|(len, ..)| *len),
+            Order::Scan => {}
+        }
+        debug!("Dispatching {} held copies", held.len());
+        for (_, op) in held {
+            self.send(op)?;
+        }
+        Ok(())
+    }
+}
+
+/// If more than this fraction of the bytes to be copied are extra
+/// hard-links to the same file, suggest `--preserve-hardlinks`.
+const HARDLINK_WARN_RATIO: u64 = 4;
+
+/// Walk the source trees, creating the destination directories and
+/// sending file operations to the workers, as laid out by a
+/// [Plan]. Returns the work to be done once the copy is complete; see
+/// [Walked::send_copies] and [Walked::finish].
+#[cfg_attr(feature = "tracing", tracing::instrument(name = "scan", level = "debug", skip_all))]
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn tree_walker(
+    sources: Vec<PathBuf>,
+    dest: &Path,
+    config: &Arc<Config>,
+    work_tx: cbc::Sender<Operation>,
+    stats: Arc<dyn StatusUpdater>,
+    abort: &Abort,
+    results: &Results,
+    metrics: &Arc<Metrics>,
+) -> Result<Walked> {
+    debug!("Starting walk worker {:?}", thread::current().id());
+
+    let mut granularities = Granularities::default();
+    let mut walked = Walked {
+        dirs: DirTracker::new(config, &stats),
+        links: Vec::new(),
+        copies: Vec::new(),
+    };
+    let (mut total_bytes, mut dup_bytes) = (0, 0);
+    let dirs = DirCache::new(config);
+    let mut plan = Plan::new(sources, dest, config, &stats)?;
+    if config.dest_subdir_from_source && !config.dry_run {
+        dirs.ensure_new(dest)?;
+    }
+    let mut dispatch = Dispatcher::new(config.order, config.batch_dirents, work_tx, metrics.clone());
+    // Tracked directories the walk is still within, by source, with
+    // their depth. Extraneous entries are deleted after the walk, so
+    // with '--delete' none are complete until then.
+    let mut open_dirs: HashMap<usize, Vec<(usize, PathBuf)>> = HashMap::new();
+
+    loop {
+        if abort.is_set() {
+            debug!("Copy aborted, stopping walk");
+            return Ok(walked);
+        }
+        let step = match plan.next_event() {
+            None => break,
+            Some(Ok(Event::Step(step))) => step,
+            Some(Ok(Event::Finished(source))) => {
+                if let Some(mut open) = open_dirs.remove(&source) {
+                    close_dirs(&mut open, 0, &walked.dirs);
+                }
+                continue;
+            }
+            Some(Err(err)) => {
+                if matches!(err.downcast_ref::<XcpError>(), Some(XcpError::OverlappingDestination(..))) {
+                    abort.set();
                 }
+                return Err(err);
+            }
+        };
+        let open = open_dirs.entry(step.source).or_default();
+        if !config.delete {
+            close_dirs(open, step.depth, &walked.dirs);
+        }
+        if config.itemize {
+            if let Some(item) = itemize(&step, config, &mut granularities)? {
+                stats.send(StatusUpdate::Item(item))?;
+            }
+        }
+        if config.dry_run {
+            debug!("Dry run, skipping {:?}", step.entry);
+            continue;
+        }
+
+        match step.entry {
+            PlanEntry::Skip { src, dest, reason: SkipReason::Same } => {
+                debug!("Refreshing metadata of {:?}", dest);
+                refresh_metadata(&src, &dest, config)?;
+            }
+            PlanEntry::Skip { .. } => {}
 
-                FileType::Dir => {
-                    // Create dir tree immediately as we can't
-                    // guarantee a worker will action the creation
-                    // before a subsequent copy operation requires it.
-                    debug!("Creating target directory {:?}", target);
-                    if let Err(err) = create_dir_all(&target) {
-                        let msg = format!("Error creating target directory: {}", err);
-                        error!("{msg}");
-                        return Err(XcpError::CopyError(msg).into())
+            PlanEntry::CreateDir { src, dest } => {
+                open_target_dir(src, dest, step.depth, open, &walked, &dirs, config, &stats)?;
+            }
+
+            PlanEntry::CopyFile { src, dest, size } => match step.linked {
+                Some(existing) if config.cache_linked_sources => {
+                    debug!("Deferring copy of {:?} to {:?} from {:?}", src, dest, existing);
+                    stats.send(StatusUpdate::Size(size))?;
+                    walked.copies.push(LinkedCopy {
+                        from: src,
+                        existing,
+                        guard: walked.dirs.child(&dest),
+                        target: dest,
+                        len: size,
+                        mtime: step.meta.modified()?,
+                    });
+                }
+                linked => {
+                    debug!("Send copy operation {:?} to {:?}", src, dest);
+                    if linked.is_some() {
+                        dup_bytes += size;
+                    } else if config.cache_linked_sources && step.meta.nlink() > 1 {
+                        // Further links are copied from this one only
+                        // if it completes.
+                        results.watch(dest.clone());
                     }
+                    total_bytes += size;
+                    stats.send(StatusUpdate::Size(size))?;
+                    let guard = walked.dirs.child(&dest);
+                    let data = tiny_contents(&src, &step.meta, config);
+                    dispatch.copy(src, dest, guard, size, data)?;
                 }
+            },
 
-                FileType::Socket | FileType::Char | FileType::Fifo => {
-                    debug!("Special file found: {:?} to {:?}", from, target);
-                    work_tx.send(Operation::Special(from, target))?;
+            PlanEntry::CreateSymlink { src, dest } => {
+                debug!("Send symlink operation {:?} to {:?}", src, dest);
+                let guard = walked.dirs.child(&dest);
+                dispatch.send(Operation::Link(src, dest, guard))?;
+            }
+
+            PlanEntry::CreateSpecial { src, dest } => {
+                debug!("Special file found: {:?} to {:?}", src, dest);
+                let guard = walked.dirs.child(&dest);
+                dispatch.send(Operation::Special(src, dest, guard))?;
+            }
+
+            PlanEntry::HardLink { existing, dest, .. } => {
+                debug!("Deferring hard-link {:?} to {:?}", dest, existing);
+                let guard = walked.dirs.child(&dest);
+                walked.links.push((existing, dest, guard));
+            }
+
+            PlanEntry::Delete { dest } => {
+                // Extraneous entries are never touched by the copy
+                // workers, so this is safe while they are still busy.
+                debug!("Deleting extraneous {:?}", dest);
+                let removed = if step.meta.is_dir() {
+                    fs::remove_dir_all(&dest)
+                } else {
+                    fs::remove_file(&dest)
+                };
+                removed.map_err(|e| protected_error(&dest, e.into()))?;
+                send_action(&stats, config, Action::Deleted(dest))?;
+            }
+        }
+    }
+    dispatch.flush()?;
+    stats.send(StatusUpdate::WalkCompleted)?;
+    debug!("Walk-worker finished: {:?}", thread::current().id());
+
+    if dup_bytes > 0 && dup_bytes * HARDLINK_WARN_RATIO > total_bytes {
+        warn!("{} of {} bytes to copy are additional hard-links to the same files; \
+               consider using --preserve-hardlinks", dup_bytes, total_bytes);
+    }
+
+    Ok(walked)
+}
+
+// The change '--itemize' reports for a step of the plan, if any.
+fn itemize(step: &Step, config: &Config, granularities: &mut Granularities) -> Result<Option<Item>> {
+    match &step.entry {
+        PlanEntry::Skip { .. } => Ok(None),
+        PlanEntry::Delete { dest } => Ok(Some(Item {
+            path: dest.clone(),
+            kind: EntryKind::from_meta(&step.meta),
+            change: Change::Deleted,
+        })),
+        PlanEntry::CreateDir { src, dest }
+        | PlanEntry::CopyFile { src, dest, .. }
+        | PlanEntry::CreateSymlink { src, dest }
+        | PlanEntry::CreateSpecial { src, dest }
+        | PlanEntry::HardLink { src, dest, .. } => compare_entry(src, &step.meta, dest, config, granularities),
+    }
+}
+
+// The contents of a tiny source file, read now so the worker can
+// write the destination in one call; see [Config::tiny_file_fastpath].
+// Empty files aren't read. Returns `None` if the file should be copied
+// normally, including if it can't be read here or has changed size.
+fn tiny_contents(src: &Path, meta: &Metadata, config: &Config) -> Option<Vec<u8>> {
+    let len = meta.len();
+    // [Reflink::Always] requires the data to be cloned.
+    if !config.tiny_file_fastpath || !meta.is_file() || len > TINY_FILE_LEN || config.reflink == Reflink::Always {
+        return None;
+    }
+    if len == 0 {
+        return Some(Vec::new());
+    }
+    let mut data = Vec::with_capacity(len as usize);
+    let read = config.fs.open_source(src)
+        .and_then(|infd| infd.take(TINY_FILE_LEN + 1).read_to_end(&mut data));
+    match read {
+        Ok(n) if n as u64 == len => Some(data),
+        Ok(_) => None,
+        Err(e) => {
+            debug!("Failed to read tiny file {:?}, copying normally: {}", src, e);
+            None
+        }
+    }
+}
+
+// Create the target directory of the source directory `from` found
+// at `depth`, and track it until the walk leaves it. Created
+// immediately as we can't guarantee a worker will action the creation
+// before a subsequent copy operation requires it.
+#[allow(clippy::too_many_arguments)]
+fn open_target_dir(
+    from: PathBuf,
+    target: PathBuf,
+    depth: usize,
+    open: &mut Vec<(usize, PathBuf)>,
+    walked: &Walked,
+    dirs: &DirCache,
+    config: &Config,
+    stats: &Arc<dyn StatusUpdater>,
+) -> Result<()> {
+    debug!("Creating target directory {:?}", target);
+    let existed = match ensure_dir(&from, &target, dirs, config, stats) {
+        Ok(created) => !created,
+        // The destination may have been replaced since it was
+        // checked.
+        Err(_) if depth == 0 && target.symlink_metadata().is_ok() && !target.is_dir() => {
+            return Err(XcpError::InvalidDestination {
+                path: target,
+                reason: "Cannot copy a directory to a file.",
+            }.into());
+        }
+        Err(err) => {
+            let msg = format!("Error creating target directory: {}", err);
+            error!("{msg}");
+            return Err(XcpError::CopyError(msg).into())
+        }
+    };
+    if !existed {
+        send_action(stats, config, Action::DirCreated(target.clone()))?;
+    }
+    if !existed || config.dir_mode == DirMode::Overwrite {
+        walked.dirs.add(from, target.clone());
+        open.push((depth, target));
+    }
+    Ok(())
+}
+
+// Mark the open directories at or below `depth` in the walk as fully
+// walked.
+fn close_dirs(open: &mut Vec<(usize, PathBuf)>, depth: usize, tracker: &DirTracker) {
+    let keep = open.iter().take_while(|(d, _)| *d < depth).count();
+    for (_, dir) in open.drain(keep..).rev() {
+        tracker.walked(&dir);
+    }
+}
+
+/// Apply source permissions, ownership, xattrs and timestamps to a
+/// destination directory. This should be called once all of its
+/// entries have been copied, as the source permissions may prevent
+/// writing to the directory and adding entries changes its
+/// timestamps; see [DirTracker]. Failures are logged but are not
+/// fatal.
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(to = ?to)))]
+pub(crate) fn apply_dir_metadata(from: &Path, to: &Path, config: &Config) {
+    debug!("Applying directory metadata {:?} -> {:?}", from, to);
+    let r = File::open(from).and_then(|infd| Ok((infd, File::open(to)?)));
+    let (infd, outfd) = match r {
+        Ok(fds) => fds,
+        Err(e) => {
+            error!("Failed to open directory {:?} for metadata: {}", to, e);
+            return;
+        }
+    };
+    if let Err(e) = copy_attributes(to, &infd, &outfd, config.preserve_mode, config) {
+        log_repeated!(Level::Error, dev_of(outfd.metadata()), "Failed to copy directory permissions {:?}: {}", to, e);
+    }
+    if let Err(e) = apply_overrides(to, &outfd, true, config) {
+        log_repeated!(Level::Error, dev_of(outfd.metadata()), "Failed to apply directory mode {:?}: {}", to, e);
+    }
+    if config.preserve.contains(PreserveSet::TIMESTAMPS) && !config.no_dir_timestamps {
+        if let Err(e) = config.fs.set_metadata(to, &infd, &outfd, Attribute::Timestamps) {
+            log_repeated!(Level::Error, dev_of(outfd.metadata()), "Failed to copy directory timestamps {:?}: {}", to, e);
+        }
+    }
+    copy_flags_last(to, &infd, &outfd, config);
+}
+
+/// Copy the ownership, xattrs and mode from `infd` to `outfd`, the
+/// file at `to`, as selected by [Config::preserve]. Ownership is
+/// copied first, as changing it clears setuid/setgid. Failures to
+/// copy ownership or xattrs are warnings, and are returned as
+/// descriptions of what was not copied.
+pub(crate) fn copy_attributes(to: &Path, infd: &File, outfd: &File, preserve_setid: bool, config: &Config) -> Result<Vec<String>> {
+    let preserve = config.preserve;
+    let mut degraded = Vec::new();
+    if preserve.contains(PreserveSet::OWNERSHIP) {
+        if let Err(e) = config.fs.set_metadata(to, infd, outfd, Attribute::Owner) {
+            warn_repeated!(dev_of(outfd.metadata()), "Failed to copy ownership: {:?}", infd);
+            degraded.push(format!("ownership not copied: {}", e));
+        }
+    }
+    if let Some(include) = xattr_filter(config) {
+        if xattrs_latched(config) {
+            degraded.push("xattrs not copied: not supported by the destination".to_string());
+        } else if let Err(e) = copy_xattrs(infd, outfd, config.xattr_value_limit, &include) {
+            // The destination may not support xattrs.
+            latch_xattrs(config, &e);
+            warn_repeated!(dev_of(outfd.metadata()), "Failed to copy xattrs from {:?}: {}", infd, e);
+            degraded.push(format!("xattrs not copied: {}", e));
+        }
+    }
+    if preserve.contains(PreserveSet::MODE) {
+        config.fs.set_metadata(to, infd, outfd, Attribute::Mode { setid: preserve_setid })?;
+    }
+    Ok(degraded)
+}
+
+// Whether xattrs are no longer tried; see
+// [Config::xattrs_unsupported].
+fn xattrs_latched(config: &Config) -> bool {
+    config.xattrs_unsupported.as_ref()
+        .is_some_and(|latch| latch.load(Ordering::Relaxed))
+}
+
+// Stop trying xattrs if copying them failed as unsupported, and
+// [Config::xattrs_unsupported] is set.
+fn latch_xattrs(config: &Config, err: &libfs::Error) {
+    let Some(latch) = config.xattrs_unsupported.as_ref() else {
+        return;
+    };
+    if is_unsupported(err) && !latch.swap(true, Ordering::Relaxed) {
+        info!("The destination doesn't support xattrs; not copying them for the remaining files");
+    }
+}
+
+// The device of a destination, grouping its repeated warnings; 0 if
+// it can't be read.
+pub(crate) fn dev_of(meta: std::io::Result<Metadata>) -> u64 {
+    meta.map_or(0, |m| m.dev())
+}
+
+/// Send a [StatusUpdate::Action] if [Config::report_actions] is set.
+pub(crate) fn send_action(stats: &Arc<dyn StatusUpdater>, config: &Config, action: Action) -> Result<()> {
+    if config.report_actions {
+        stats.send(StatusUpdate::Action(action))?;
+    }
+    Ok(())
+}
+
+// Which xattrs to copy by name, or None if none are preserved. The
+// security context is itself an xattr.
+fn xattr_filter(config: &Config) -> Option<impl Fn(&OsStr) -> bool> {
+    let preserve = config.preserve;
+    preserve.intersects(PreserveSet::XATTR.union(PreserveSet::CONTEXT))
+        .then_some(move |name: &OsStr| if name == SELINUX_XATTR {
+            preserve.contains(PreserveSet::CONTEXT)
+        } else {
+            preserve.contains(PreserveSet::XATTR)
+        })
+}
+
+// Apply the --chown and --chmod overrides. Ownership is changed first
+// as it may clear setuid/setgid bits.
+/// Copy the attributes and timestamps of a file once its data is in
+/// place. Every copy method ends here, so a destination's metadata
+/// doesn't depend on how its data was copied. Returns what was not
+/// copied, as [copy_attributes].
+pub(crate) fn copy_metadata(to: &Path, infd: &File, outfd: &File, config: &Config) -> Result<Vec<String>> {
+    let degraded = copy_attributes(to, infd, outfd, config.preserve_mode, config)?;
+    if config.preserve.contains(PreserveSet::TIMESTAMPS) {
+        config.fs.set_metadata(to, infd, outfd, Attribute::Timestamps)?;
+    }
+    Ok(degraded)
+}
+
+// Copy the metadata onto an existing destination that already has
+// the source's contents, with [Config::skip_same], as if it had been
+// copied again.
+fn refresh_metadata(from: &Path, to: &Path, config: &Config) -> Result<()> {
+    let infd = File::open(from)?;
+    let outfd = File::open(to)?;
+    unprotected(to, config, || {
+        copy_metadata(to, &infd, &outfd, config)?;
+        apply_overrides(to, &outfd, false, config)
+    })?;
+    copy_flags_last(to, &infd, &outfd, config);
+    Ok(())
+}
+
+pub(crate) fn apply_overrides(path: &Path, outfd: &File, is_dir: bool, config: &Config) -> Result<()> {
+    if let Some(chown) = config.chown {
+        if let Err(e) = fchown(outfd, chown.uid, chown.gid) {
+            warn_repeated!(dev_of(outfd.metadata()), "Failed to change ownership of {:?}: {}", path, e);
+        }
+    }
+    if let Some(ref chmod) = config.chmod {
+        let mode = outfd.metadata()?.permissions().mode();
+        outfd.set_permissions(Permissions::from_mode(chmod.apply(mode, is_dir)))?;
+    }
+    Ok(())
+}
+
+/// A pair of open files that blocks can be copied between. This
+/// allows block copies to be shared between [CopyHandle] and
+/// caller-supplied files.
+pub(crate) trait BlockFiles: Send + Sync + 'static {
+    fn infd(&self) -> &File;
+    fn outfd(&self) -> &File;
+    /// Record a range successfully copied, returning the number of
+    /// its bytes not previously recorded.
+    fn copied(&self, range: Range<u64>) -> u64;
+    /// Record a failed block; returns the error to report, if any.
+    fn block_failed(&self, err: anyhow::Error) -> Option<anyhow::Error>;
+    /// Fails if remaining blocks should be skipped.
+    fn check_abort(&self) -> Result<()>;
+    /// The source and destination paths, if known, for reporting
+    /// errors.
+    fn paths(&self) -> Option<(&Path, &Path)> {
+        None
+    }
+    /// Copy `bytes` at offset `off` in both files.
+    fn copy_block(&self, bytes: u64, off: u64) -> Result<u64> {
+        Ok(copy_file_offset(self.infd(), self.outfd(), bytes, off)?)
+    }
+    /// Drop a reference once a block is done with it; the last may
+    /// finish the file elsewhere, as [CopyHandle::release] does.
+    fn release(self: Arc<Self>) {
+    }
+}
+
+/// Orders the writes of a file's blocks, for destinations that handle
+/// out-of-order writes poorly. Blocks are still read in parallel, and
+/// each is written once those queued before it have been. As the pool
+/// runs blocks in the order they are queued the earliest outstanding
+/// block is always running, so waiting for it can't deadlock.
+#[derive(Default)]
+pub(crate) struct WriteOrder {
+    /// The number of blocks queued.
+    queued: AtomicU64,
+    /// The number of the next block to write.
+    next: Mutex<u64>,
+    written: Condvar,
+}
+
+impl WriteOrder {
+    // Number the next block queued.
+    fn ticket(&self) -> u64 {
+        self.queued.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // Read the block in parallel, and then write it once the earlier
+    // blocks have been written. The turn is passed on even if the
+    // block fails, so later blocks don't wait forever.
+    fn copy_block<F: BlockFiles>(&self, files: &F, seq: u64, bytes: u64, off: u64) -> Result<u64> {
+        let mut buf = vec![0; bytes as usize];
+        metrics::set_state(WorkerState::Reading);
+        let read = files.check_abort().and_then(|_| {
+            let mut n = 0;
+            while n < buf.len() {
+                match files.infd().read_at(&mut buf[n..], off + n as u64)? {
+                    0 => break,
+                    r => n += r,
                 }
+            }
+            Ok(n)
+        });
+        metrics::set_state(WorkerState::Writing);
+        let mut next = self.next.lock().unwrap();
+        while *next != seq {
+            next = self.written.wait(next).unwrap();
+        }
+        let result = read.and_then(|n| {
+            files.outfd().write_all_at(&buf[..n], off)?;
+            Ok(n as u64)
+        });
+        *next += 1;
+        self.written.notify_all();
+        result
+    }
+}
 
-                FileType::Block | FileType::Other => {
-                    error!("Unsupported filetype found: {:?} -> {:?}", target, ft);
-                    return Err(XcpError::UnknownFileType(target).into());
+impl BlockFiles for CopyHandle {
+    fn infd(&self) -> &File {
+        &self.infd
+    }
+    fn outfd(&self) -> &File {
+        &self.outfd
+    }
+    fn copied(&self, range: Range<u64>) -> u64 {
+        self.acknowledge(range)
+    }
+    fn paths(&self) -> Option<(&Path, &Path)> {
+        Some((&self.from, &self.to))
+    }
+    fn block_failed(&self, err: anyhow::Error) -> Option<anyhow::Error> {
+        self.mark_failed();
+        let err = self.copy_error(err);
+        if is_early_shutdown(&err) {
+            None
+        } else {
+            Some(err)
+        }
+    }
+    fn check_abort(&self) -> Result<()> {
+        CopyHandle::check_abort(self)
+    }
+    fn release(self: Arc<Self>) {
+        if let Some(handle) = Arc::into_inner(self) {
+            handle.release();
+        }
+    }
+    fn copy_block(&self, bytes: u64, off: u64) -> Result<u64> {
+        match self.config.fs.copy_bytes_at(&self.to, &self.infd, &self.outfd, bytes, off) {
+            Ok(copied) => Ok(copied),
+            Err(e) if is_io_error(&e) => {
+                self.rescue(off, bytes, &mut |_| {})?;
+                Ok(bytes)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+struct OpenFiles {
+    infd: File,
+    outfd: File,
+    failed: AtomicBool,
+}
+
+impl BlockFiles for OpenFiles {
+    fn infd(&self) -> &File {
+        &self.infd
+    }
+    fn outfd(&self) -> &File {
+        &self.outfd
+    }
+    fn copied(&self, range: Range<u64>) -> u64 {
+        range.end - range.start
+    }
+    fn block_failed(&self, err: anyhow::Error) -> Option<anyhow::Error> {
+        self.failed.store(true, Ordering::Relaxed);
+        Some(err)
+    }
+    fn check_abort(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Queue a range of a file to be copied on the pool, split into
+/// blocks of `block_size`. Block boundaries are at multiples of
+/// `block_size` in the file, so a range starting elsewhere has a
+/// short first block. With a [WriteOrder] the blocks are written in
+/// the order queued, including across ranges. The pool's threads are
+/// recorded as workers in any [Metrics]. Returns the number of bytes
+/// queued.
+pub(crate) fn queue_file_range<F: BlockFiles>(
+    handle: &Arc<F>,
+    range: Range<u64>,
+    block_size: u64,
+    order: Option<&Arc<WriteOrder>>,
+    pool: &Pool,
+    status_channel: &Arc<dyn StatusUpdater>,
+    metrics: Option<&Arc<Metrics>>,
+) -> Result<u64> {
+    let len = range.end - range.start;
+    let bsize = block_size.max(1);
+
+    for (off, bytes) in aligned_blocks(range, bsize) {
+        let harc = handle.clone();
+        let stat_tx = status_channel.clone();
+        let order = order.map(|o| (o.clone(), o.ticket()));
+        let metrics = metrics.cloned();
+
+        pool.execute(move || {
+            let _worker = metrics.as_ref().map(|m| m.enter());
+            metrics::set_state(WorkerState::Writing);
+            let copy_result = match order {
+                Some((order, seq)) => order.copy_block(&*harc, seq, bytes, off),
+                None => harc.check_abort()
+                    .and_then(|_| harc.copy_block(bytes, off)),
+            };
+            let stat_result = match copy_result {
+                Ok(bytes) => match harc.copied(off..off + bytes) {
+                    0 => Ok(()),
+                    fresh => stat_tx.send(StatusUpdate::Copied(fresh)),
+                },
+                Err(e) => match harc.block_failed(e) {
+                    Some(e) => {
+                        error!("Error copying: aborting.");
+                        let error = match harc.paths() {
+                            Some((from, to)) => copy_error(&e, from, to),
+                            None => status_error(&e),
+                        };
+                        stat_tx.send(StatusUpdate::Error(error))
+                    }
+                    None => Ok(()),
                 }
             };
-        }
+            if let Err(e) = stat_result {
+                let msg = format!("Failed to send status update message. This should not happen; aborting. Error: {}", e);
+                error!("{}", msg);
+                panic!("{}", msg);
+            }
+            harc.release();
+        });
     }
-    debug!("Walk-worker finished: {:?}", thread::current().id());
+    Ok(len)
+}
+
+// The offset and length of each block of the range, split at
+// multiples of `bsize`.
+fn aligned_blocks(range: Range<u64>, bsize: u64) -> impl Iterator<Item = (u64, u64)> {
+    let mut off = range.start;
+    std::iter::from_fn(move || {
+        if off >= range.end {
+            return None;
+        }
+        let boundary = (off / bsize).saturating_add(1).saturating_mul(bsize);
+        let bytes = cmp::min(boundary, range.end) - off;
+        let block = (off, bytes);
+        off += bytes;
+        Some(block)
+    })
+}
 
+// Ranges must be non-empty, within the file, and must not overlap.
+fn validate_ranges(ranges: &[Range<u64>], len: u64) -> Result<()> {
+    let mut sorted = ranges.to_vec();
+    sorted.sort_by_key(|r| r.start);
+
+    let mut prev_end = 0;
+    for r in sorted {
+        if r.start >= r.end {
+            return Err(XcpError::InvalidArguments(format!("Empty or inverted range: {:?}", r)).into());
+        }
+        if r.end > len {
+            return Err(XcpError::InvalidArguments(format!("Range {:?} is outside the file length {}", r, len)).into());
+        }
+        if r.start < prev_end {
+            return Err(XcpError::InvalidArguments(format!("Range {:?} overlaps a previous range", r)).into());
+        }
+        prev_end = r.end;
+    }
     Ok(())
 }
 
-fn empty_path(path: &Path) -> bool {
-    *path == PathBuf::new()
+fn run_block_copy<F: BlockFiles>(
+    files: &Arc<F>,
+    ranges: &[Range<u64>],
+    config: &Config,
+    updater: &Arc<dyn StatusUpdater>,
+) -> Result<u64> {
+    let plan = config.buffer_plan();
+    let pool = config.executor.pool(plan.workers, None);
+
+    let mut queued = 0;
+    for r in ranges {
+        queued += queue_file_range(files, r.clone(), plan.block_size, None, &pool, updater, None)?;
+    }
+    pool.join();
+
+    Ok(queued)
+}
+
+/// Copy the given byte ranges of `src` to the same offsets in `dst`,
+/// in parallel using the configured number of workers and
+/// block-size. Ranges are copied in the order given, but blocks may
+/// complete in any order. [StatusUpdate::Copied] is sent for each
+/// block copied.
+///
+/// The destination is created (or truncated) with the same length as
+/// the source, and has the source metadata applied on completion as
+/// per the [Config]. Ranges that are not copied will be holes in the
+/// destination. To copy into an existing file without modifying it
+/// otherwise see [copy_file_blocks_into]. If the destination can't be
+/// preallocated (or [Config::no_fallocate] is set) it only extends to
+/// the end of the last range written.
+///
+/// Returns the number of bytes copied. It is an error for ranges to
+/// be empty, overlap, or extend past the end of the source file.
+pub fn copy_file_blocks(
+    src: &Path,
+    dst: &Path,
+    ranges: &[Range<u64>],
+    config: &Arc<Config>,
+    updater: &Arc<dyn StatusUpdater>,
+) -> Result<u64> {
+    let len = src.metadata()?.len();
+    validate_ranges(ranges, len)?;
+
+    let abort = Arc::new(Abort::default());
+    // Ranges not copied must be holes, so don't overwrite in place.
+    let handle = Arc::new(CopyHandle::open(src, dst, config, updater, &abort, false, None, None)?);
+    let copied = run_block_copy(&handle, ranges, config, updater)?;
+    if handle.has_failed() {
+        return Err(XcpError::CopyError(format!("Failed to copy blocks of {:?}", src)).into());
+    }
+
+    Ok(copied)
+}
+
+/// As [copy_file_blocks], but copies between already open files. The
+/// destination length and metadata are not modified; the caller is
+/// responsible for any preallocation. Ranges are validated against
+/// the source length.
+pub fn copy_file_blocks_into(
+    infd: &File,
+    outfd: &File,
+    ranges: &[Range<u64>],
+    config: &Config,
+    updater: &Arc<dyn StatusUpdater>,
+) -> Result<u64> {
+    validate_ranges(ranges, infd.metadata()?.len())?;
+
+    let files = Arc::new(OpenFiles {
+        infd: infd.try_clone()?,
+        outfd: outfd.try_clone()?,
+        failed: AtomicBool::new(false),
+    });
+    let copied = run_block_copy(&files, ranges, config, updater)?;
+    if files.failed.load(Ordering::Relaxed) {
+        return Err(XcpError::CopyError("Failed to copy blocks".to_string()).into());
+    }
+
+    Ok(copied)
+}
+
+/// A byte range to copy with [copy_byte_range].
+#[derive(Clone, Debug, Default)]
+pub struct ByteRange {
+    /// Offset of the range in the source file.
+    pub offset: u64,
+    /// Length of the range; by default up to the end of the source.
+    pub length: Option<u64>,
+    /// Offset to write the range at in the destination. If set, an
+    /// existing destination is not truncated.
+    pub dest_offset: Option<u64>,
+}
+
+/// Upper limit on the read size of [copy_stream], as the configured
+/// block size is unbounded when progress is disabled.
+const MAX_STREAM_BUFFER: u64 = 16 * 1024 * 1024;
+
+/// Create or truncate a file to write a stream to with
+/// [copy_stream], respecting [Config::no_clobber]. No metadata is
+/// copied to it.
+pub fn create_stream_dest(to: &Path, config: &Config) -> Result<File> {
+    create_dest(to, config, None, None).map(|(fd, _)| fd)
+}
+
+/// Copy a stream of unknown length, such as stdin or to stdout,
+/// reading up to [Config::block_size] bytes at a time. The stream is
+/// reported as a single file named `name`; no [StatusUpdate::Size] is
+/// sent as the total isn't known, only [StatusUpdate::Copied] for each
+/// read. Returns the number of bytes copied.
+pub fn copy_stream(
+    name: &Path,
+    from: &mut dyn Read,
+    to: &mut dyn Write,
+    config: &Config,
+    updater: &Arc<dyn StatusUpdater>,
+) -> Result<u64> {
+    let id = NEXT_FILE_ID.fetch_add(1, Ordering::Relaxed);
+    updater.send(StatusUpdate::FileStarted(id, name.to_path_buf()))?;
+    let mut buf = vec![0; config.buffer_plan().block_size.min(MAX_STREAM_BUFFER) as usize];
+    let mut total = 0;
+    let mut copy = || -> Result<u64> {
+        loop {
+            let len = match from.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            to.write_all(&buf[..len])?;
+            total += len as u64;
+            updater.send(StatusUpdate::Copied(len as u64))?;
+        }
+        to.flush()?;
+        Ok(total)
+    };
+    let result = copy();
+    updater.send(StatusUpdate::FileCompleted(id, total))?;
+    debug!("Streamed {:?} bytes from {:?}", result.as_ref().ok(), name);
+    result
+}
+
+/// Copy a byte range of `src` into `dst`, similar to `dd`. The copy
+/// is sequential, and bypasses the drivers. [StatusUpdate::Size] is
+/// sent with the range length, followed by [StatusUpdate::Copied] for
+/// each block.
+///
+/// Unless [Config::reflink] is [Reflink::Never], the block-aligned
+/// part of the range is reflinked if the filesystem supports it, and
+/// [StatusUpdate::RangeCloned] sent; the rest is copied. Unlike whole
+/// files, failing to reflink is not an error with [Reflink::Always].
+///
+/// Without a [ByteRange::dest_offset] the destination is created or
+/// truncated, and the range written to its start. Otherwise the
+/// destination is created if necessary but not truncated, and is only
+/// extended if the range ends past its current length. No metadata is
+/// copied in either case.
+///
+/// It is an error for the range to extend past the end of the source;
+/// this is checked before the destination is opened. Returns the
+/// number of bytes copied.
+pub fn copy_byte_range(
+    src: &Path,
+    dst: &Path,
+    range: &ByteRange,
+    config: &Config,
+    updater: &Arc<dyn StatusUpdater>,
+) -> Result<u64> {
+    let infd = File::open(src)?;
+    let meta = infd.metadata()?;
+    if !meta.is_file() {
+        return Err(XcpError::InvalidSource {
+            path: src.to_path_buf(),
+            reason: "Range copies require a regular source file.",
+        }.into());
+    }
+    let len = meta.len();
+    let total = range.length.unwrap_or(len.saturating_sub(range.offset));
+    let end = range.offset.checked_add(total).filter(|e| *e <= len)
+        .ok_or_else(|| XcpError::InvalidArguments(format!(
+            "Range of {} bytes at offset {} is outside the source length {}", total, range.offset, len)))?;
+    debug!("Copying bytes {}..{} of {:?} to {:?}", range.offset, end, src, dst);
+
+    check_dest_symlink(dst, config)?;
+    let outfd = match range.dest_offset {
+        Some(_) => File::options().write(true).create(true).truncate(false).open(dst)?,
+        None => {
+            // Truncating the source would destroy the range, so the
+            // open destination is checked first.
+            let outfd = File::options().write(true).create(true).truncate(false).open(dst)?;
+            if check_not_source(dst, &outfd, Some(&meta))?.is_file() {
+                outfd.set_len(0)?;
+            }
+            outfd
+        }
+    };
+    let out_start = range.dest_offset.unwrap_or(0);
+
+    updater.send(StatusUpdate::Size(total))?;
+    let span = match clone_span(range.offset, out_start, total, len, outfd.metadata()?.blksize()) {
+        Some(span) if config.reflink != Reflink::Never => {
+            let cloned = span.end - span.start;
+            debug!("Attempting to reflink {} bytes of {:?}", cloned, src);
+            if clone_range(&infd, &outfd, range.offset + span.start, out_start + span.start, cloned)? {
+                updater.send(StatusUpdate::Copied(cloned))?;
+                updater.send(StatusUpdate::RangeCloned(cloned))?;
+                span
+            } else {
+                debug!("Failed to reflink range, falling back to copy");
+                total..total
+            }
+        }
+        _ => total..total,
+    };
+
+    let block_size = config.buffer_plan().block_size;
+    let mut written = span.end - span.start;
+    for part in [0..span.start, span.end..total] {
+        let mut pos = part.start;
+        while pos < part.end {
+            let bytes = cmp::min(part.end - pos, block_size);
+            let copied = copy_file_at(&infd, range.offset + pos, &outfd, out_start + pos, bytes)?;
+            if copied == 0 {
+                return Err(XcpError::SourceTruncated { path: src.to_path_buf(), offset: range.offset + pos }.into());
+            }
+            pos += copied;
+            written += copied;
+            updater.send(StatusUpdate::Copied(copied))?;
+        }
+    }
+    if config.fsync {
+        sync(&outfd)?;
+    }
+
+    Ok(written)
+}
+
+// The part of a range copy that can be reflinked, relative to the
+// start of the range. Source and destination offsets must share the
+// same alignment to the filesystem block size, and the reflinked part
+// must be whole blocks unless it extends to the end of the source.
+fn clone_span(src_off: u64, dst_off: u64, len: u64, src_len: u64, block: u64) -> Option<Range<u64>> {
+    if block == 0 || src_off % block != dst_off % block {
+        return None;
+    }
+    let start = (block - src_off % block) % block;
+    let end = if src_off + len == src_len {
+        len
+    } else {
+        start + len.saturating_sub(start) / block * block
+    };
+    (start < end).then_some(start..end)
+}
+
+/// Progress is reported in updates of at least this many bytes, other
+/// than the remainder at the end of a copy.
+const MIN_PROGRESS_BYTES: u64 = 64 * 1024;
+
+/// The number of consecutive copies returning no data before giving
+/// up; the source has most likely been truncated during the copy.
+const MAX_ZERO_COPIES: u32 = 3;
+
+// Copy `len` bytes in calls to `copy` of at most `block_size` bytes,
+// which return the number actually copied. Short copies are
+// continued and interrupted calls retried, but repeatedly copying
+// nothing is an error rather than looping forever. Copied bytes are
+// passed to `progress`, including on error.
+fn copy_bytes_batched(
+    len: u64,
+    block_size: u64,
+    copy: &mut dyn FnMut(u64) -> Result<u64>,
+    progress: &mut dyn FnMut(u64) -> Result<()>,
+) -> Result<u64> {
+    let mut written = 0;
+    let mut unreported = 0;
+    let mut zeros = 0;
+    let result = loop {
+        if written >= len {
+            break Ok(written);
+        }
+        let requested = cmp::min(len - written, block_size);
+        let bytes = match copy(requested) {
+            Ok(bytes) => bytes,
+            Err(e) if is_interrupted(e.as_ref()) => continue,
+            Err(e) => break Err(e),
+        };
+        if bytes > requested {
+            break Err(XcpError::CopyError(
+                format!("Copied {} bytes when {} were requested", bytes, requested)).into());
+        }
+        if bytes == 0 {
+            zeros += 1;
+            if zeros >= MAX_ZERO_COPIES {
+                break Err(XcpError::CopyError(
+                    format!("No data copied after {} of {} bytes; the source may have been truncated", written, len)).into());
+            }
+            continue;
+        }
+        zeros = 0;
+        written += bytes;
+        unreported += bytes;
+        if unreported >= MIN_PROGRESS_BYTES {
+            progress(unreported)?;
+            unreported = 0;
+        }
+    };
+    if unreported > 0 {
+        progress(unreported)?;
+    }
+    result
+}
+
+fn hash_zeros(hasher: &mut Hasher, mut len: u64) {
+    static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+    while len > 0 {
+        let n = cmp::min(len, ZEROS.len() as u64) as usize;
+        hasher.update(&ZEROS[..n]);
+        len -= n as u64;
+    }
+}
+
+// The runs of a block containing data, in units of SPARSE_CHUNK.
+// Adjacent non-zero chunks are merged so they are written together.
+fn nonzero_runs(block: &[u8]) -> Vec<Range<usize>> {
+    let mut runs: Vec<Range<usize>> = Vec::new();
+    for (i, chunk) in block.chunks(SPARSE_CHUNK).enumerate() {
+        if chunk.iter().all(|b| *b == 0) {
+            continue;
+        }
+        let start = i * SPARSE_CHUNK;
+        let end = start + chunk.len();
+        match runs.last_mut() {
+            Some(last) if last.end == start => last.end = end,
+            _ => runs.push(start..end),
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+    use std::fs::{read, write};
+    use tempfile::TempDir;
+
+    use libfs::{FaultInjectingFs, FsOp};
+
+    use crate::config::InvalidName;
+    use crate::drivers::{load_driver, Drivers};
+    use crate::executor::Executor;
+    use crate::feedback::{ChannelUpdater, NoopUpdater};
+    use crate::names::NameProfile;
+
+    fn batched(len: u64, block_size: u64, returns: &[Result<u64>]) -> (Result<u64>, Vec<u64>, Vec<u64>) {
+        let mut returns = returns.iter();
+        let mut requests = Vec::new();
+        let mut updates = Vec::new();
+        let result = copy_bytes_batched(len, block_size, &mut |n| {
+            requests.push(n);
+            match returns.next() {
+                Some(Ok(b)) => Ok(*b),
+                Some(Err(e)) => Err(anyhow::anyhow!("{}", e)),
+                None => Ok(n),
+            }
+        }, &mut |b| {
+            updates.push(b);
+            Ok(())
+        });
+        (result, requests, updates)
+    }
+
+    #[test]
+    fn test_copy_bytes_batched_short() {
+        let (result, requests, updates) = batched(100_000, 50_000, &[Ok(10), Ok(49_990)]);
+        assert_eq!(100_000, result.unwrap());
+        assert_eq!(vec![50_000, 50_000, 50_000], requests);
+        // Coalesced into updates of at least MIN_PROGRESS_BYTES.
+        assert_eq!(vec![100_000], updates);
+    }
+
+    #[test]
+    fn test_copy_bytes_batched_zero() {
+        // Occasional zero returns are retried.
+        let (result, _, _) = batched(100, 100, &[Ok(0), Ok(0), Ok(50)]);
+        assert_eq!(100, result.unwrap());
+
+        let (result, requests, updates) = batched(100, 100, &[Ok(40), Ok(0), Ok(0), Ok(0)]);
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("after 40 of 100 bytes"), "{}", err);
+        assert_eq!(4, requests.len());
+        assert_eq!(vec![40], updates);
+    }
+
+    #[test]
+    fn test_copy_bytes_batched_errors() {
+        let (result, _, _) = batched(100, 100, &[Ok(101)]);
+        assert!(result.is_err());
+
+        let (result, requests, updates) = batched(100, 60, &[Ok(60), Err(anyhow::anyhow!("failed"))]);
+        assert!(result.is_err());
+        assert_eq!(2, requests.len());
+        assert_eq!(vec![60], updates);
+    }
+
+    #[test]
+    fn test_copy_bytes_batched_interrupted() {
+        let mut calls = 0;
+        let result = copy_bytes_batched(100, 100, &mut |n| {
+            calls += 1;
+            if calls == 1 {
+                // EINTR is 4 on all supported platforms.
+                return Err(libfs::Error::from(std::io::Error::from_raw_os_error(4)).into());
+            }
+            Ok(n)
+        }, &mut |_| Ok(()));
+        assert_eq!(100, result.unwrap());
+        assert_eq!(2, calls);
+    }
+
+    fn test_config() -> Arc<Config> {
+        Arc::new(Config {
+            workers: 4,
+            block_size: 1000,
+            ..Config::default()
+        })
+    }
+
+    #[test]
+    fn test_offload_candidate() {
+        assert!(offload_candidate(FsType::Nfs, true));
+        assert!(offload_candidate(FsType::Cifs, true));
+        assert!(!offload_candidate(FsType::Nfs, false));
+        assert!(!offload_candidate(FsType::Other, true));
+    }
+
+    #[test]
+    fn test_offloaded() {
+        let gb = 1024 * 1024 * 1024;
+        assert!(offloaded(10 * gb, 10 * gb, Duration::from_millis(50)));
+        assert!(!offloaded(10 * gb, 10 * gb, Duration::from_secs(20)));
+        assert!(!offloaded(10 * gb, gb, Duration::from_millis(50)));
+        assert!(!offloaded(0, 0, Duration::ZERO));
+    }
+
+    #[test]
+    fn test_dispatch_order() -> Result<()> {
+        let sizes = [10, EARLY_DISPATCH_SIZE, 30, 10, 20];
+        let dispatched = |order| -> Result<Vec<String>> {
+            let (tx, rx) = cbc::unbounded();
+            let mut dispatch = Dispatcher::new(order, false, tx, Arc::default());
+            for (i, len) in sizes.iter().enumerate() {
+                dispatch.copy(PathBuf::from(format!("{}", i)), PathBuf::new(), ChildGuard::default(), *len, None)?;
+            }
+            dispatch.send(Operation::Link(PathBuf::from("link"), PathBuf::new(), ChildGuard::default()))?;
+            dispatch.flush()?;
+            drop(dispatch);
+            Ok(rx.iter()
+                .map(|op| match op {
+                    Operation::Copy(from, ..) | Operation::Link(from, ..) | Operation::Special(from, ..) =>
+                        from.to_string_lossy().into_owned(),
+                    Operation::Batch(_) => unreachable!(),
+                })
+                .collect())
+        };
+
+        assert_eq!(["0", "1", "2", "3", "4", "link"], dispatched(Order::Scan)?.as_slice());
+        // Large files are sent immediately, the rest after the scan.
+        assert_eq!(["1", "link", "2", "4", "0", "3"], dispatched(Order::LargestFirst)?.as_slice());
+        assert_eq!(["link", "0", "3", "4", "2", "1"], dispatched(Order::SmallestFirst)?.as_slice());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispatch_tiny() -> Result<()> {
+        let dispatched = |order, batch_dirents| -> Result<Vec<Vec<String>>> {
+            let (tx, rx) = cbc::unbounded();
+            let mut dispatch = Dispatcher::new(order, batch_dirents, tx, Arc::default());
+            for (i, len) in [600, 4, 0, 700].iter().enumerate() {
+                let data = (*len <= TINY_FILE_LEN).then(|| vec![0; *len as usize]);
+                dispatch.copy(PathBuf::from(format!("{}", i)), PathBuf::from("d/f"), ChildGuard::default(), *len, data)?;
+            }
+            dispatch.flush()?;
+            drop(dispatch);
+            Ok(rx.iter()
+                .map(|op| match op {
+                    Operation::Copy(from, ..) => vec![from.to_string_lossy().into_owned()],
+                    Operation::Batch(batch) => batch.iter()
+                        .map(|c| format!("{}={}", c.from.to_string_lossy(), c.data.as_ref().map_or(0, |d| d.len())))
+                        .collect(),
+                    _ => unreachable!(),
+                })
+                .collect())
+        };
+
+        // Tiny files are sent alone in batches, in order.
+        assert_eq!(vec![vec!["0"], vec!["1=4"], vec!["2=0"], vec!["3"]], dispatched(Order::Scan, false)?);
+        assert_eq!(vec![vec!["2=0"], vec!["1=4"], vec!["0"], vec!["3"]], dispatched(Order::SmallestFirst, false)?);
+        // Or with the other files of their directory.
+        assert_eq!(vec![vec!["0=0", "1=4", "2=0", "3=0"]], dispatched(Order::Scan, true)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tiny_contents() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let path = |name| tdir.path().join(name);
+        write(path("empty"), "")?;
+        write(path("tiny"), "tiny")?;
+        write(path("limit"), vec![1; TINY_FILE_LEN as usize])?;
+        write(path("over"), vec![1; TINY_FILE_LEN as usize + 1])?;
+        let contents = |name, config: &Config| -> Result<Option<Vec<u8>>> {
+            Ok(tiny_contents(&path(name), &path(name).metadata()?, config))
+        };
+
+        let config = Config::default();
+        assert_eq!(Some(vec![]), contents("empty", &config)?);
+        assert_eq!(Some(b"tiny".to_vec()), contents("tiny", &config)?);
+        assert_eq!(Some(vec![1; TINY_FILE_LEN as usize]), contents("limit", &config)?);
+        assert_eq!(None, contents("over", &config)?);
+        assert_eq!(None, tiny_contents(tdir.path(), &tdir.path().metadata()?, &config));
+
+        // Changed since it was walked.
+        let meta = path("tiny").metadata()?;
+        write(path("tiny"), "grown")?;
+        assert_eq!(None, tiny_contents(&path("tiny"), &meta, &config));
+
+        for config in [
+            Config { tiny_file_fastpath: false, ..Config::default() },
+            Config { reflink: Reflink::Always, ..Config::default() },
+        ] {
+            assert_eq!(None, contents("empty", &config)?);
+            assert_eq!(None, contents("limit", &config)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_nonzero_runs() {
+        let mut block = vec![0; SPARSE_CHUNK * 6 + 100];
+        assert!(nonzero_runs(&block).is_empty());
+
+        block[0] = 1;
+        block[SPARSE_CHUNK * 3 + 5] = 1;
+        block[SPARSE_CHUNK * 4] = 1;
+        block[SPARSE_CHUNK * 6 + 99] = 1;
+        assert_eq!(vec![
+            0..SPARSE_CHUNK,
+            SPARSE_CHUNK * 3..SPARSE_CHUNK * 5,
+            SPARSE_CHUNK * 6..SPARSE_CHUNK * 6 + 100,
+        ], nonzero_runs(&block));
+    }
+
+    #[test]
+    fn test_aligned_blocks() {
+        let blocks = |range, bsize| aligned_blocks(range, bsize).collect::<Vec<(u64, u64)>>();
+        assert_eq!(vec![(0, 4), (4, 4), (8, 2)], blocks(0..10, 4));
+        assert_eq!(vec![(3, 1), (4, 4), (8, 1)], blocks(3..9, 4));
+        assert_eq!(vec![(5, 2)], blocks(5..7, 4));
+        assert_eq!(vec![(0, 10)], blocks(0..10, u64::MAX));
+        assert!(blocks(4..4, 4).is_empty());
+    }
+
+    #[test]
+    fn test_validate_ranges() {
+        assert!(validate_ranges(&[0..10, 20..30], 30).is_ok());
+        assert!(validate_ranges(&[20..30, 0..10], 30).is_ok());
+        assert!(validate_ranges(&[0..10, 5..15], 30).is_err());
+        assert!(validate_ranges(&[20..31], 30).is_err());
+        assert!(validate_ranges(&[10..10], 30).is_err());
+    }
+
+    #[test]
+    fn test_copy_file_blocks() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let from = tdir.path().join("from.bin");
+        let to = tdir.path().join("to.bin");
+        let data = (0..10000_u32).map(|i| (i % 251) as u8 + 1).collect::<Vec<u8>>();
+        write(&from, &data)?;
+        write(&to, vec![0xff; 20000])?;
+
+        let updater: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+        let copied = copy_file_blocks(&from, &to, &[5000..7500, 0..1500], &test_config(), &updater)?;
+        assert_eq!(4000, copied);
+
+        let out = read(&to)?;
+        assert_eq!(data.len(), out.len());
+        assert_eq!(data[0..1500], out[0..1500]);
+        assert!(out[1500..5000].iter().all(|b| *b == 0));
+        assert_eq!(data[5000..7500], out[5000..7500]);
+        assert!(out[7500..].iter().all(|b| *b == 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_order() -> Result<()> {
+        // Fails the third block read.
+        struct Failing(OpenFiles, AtomicU64);
+        impl BlockFiles for Failing {
+            fn infd(&self) -> &File {
+                self.0.infd()
+            }
+            fn outfd(&self) -> &File {
+                self.0.outfd()
+            }
+            fn copied(&self, range: Range<u64>) -> u64 {
+                self.0.copied(range)
+            }
+            fn block_failed(&self, err: anyhow::Error) -> Option<anyhow::Error> {
+                self.0.block_failed(err)
+            }
+            fn check_abort(&self) -> Result<()> {
+                match self.1.fetch_add(1, Ordering::Relaxed) {
+                    2 => Err(XcpError::EarlyShutdown("test").into()),
+                    _ => Ok(()),
+                }
+            }
+        }
+
+        let tdir = TempDir::new()?;
+        let from = tdir.path().join("from.bin");
+        let data = (0..100_000_u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        write(&from, &data)?;
+        let updater: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+
+        for fail in [false, true] {
+            // Appending ignores the write offset, so the blocks are
+            // only in place if written in order.
+            let to = tdir.path().join(format!("to-{}.bin", fail));
+            let files = OpenFiles {
+                infd: File::open(&from)?,
+                outfd: File::options().create(true).append(true).open(&to)?,
+                failed: AtomicBool::new(false),
+            };
+            let pool = Executor::Threads.pool(8, None);
+            let order = Arc::new(WriteOrder::default());
+            let ranges = [0..40_000, 60_000..100_000];
+            if fail {
+                let files = Arc::new(Failing(files, AtomicU64::new(0)));
+                for r in ranges {
+                    queue_file_range(&files, r, 1000, Some(&order), &pool, &updater, None)?;
+                }
+            } else {
+                let files = Arc::new(files);
+                for r in ranges {
+                    queue_file_range(&files, r, 1000, Some(&order), &pool, &updater, None)?;
+                }
+            }
+            // The failed block doesn't hold up the rest.
+            pool.join();
+
+            let out = read(&to)?;
+            let mut expected = [&data[0..40_000], &data[60_000..]].concat();
+            if fail {
+                expected.drain(2000..3000);
+            }
+            assert_eq!(expected, out);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_file_blocks_into() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let from = tdir.path().join("from.bin");
+        let to = tdir.path().join("to.bin");
+        write(&from, vec![1; 10000])?;
+        write(&to, vec![2; 12000])?;
+
+        let updater: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+        let infd = File::open(&from)?;
+        let outfd = File::options().write(true).open(&to)?;
+        let copied = copy_file_blocks_into(&infd, &outfd, &[2500..4500], &test_config(), &updater)?;
+        assert_eq!(2000, copied);
+        assert!(copy_file_blocks_into(&infd, &outfd, &[9000..10001], &test_config(), &updater).is_err());
+
+        let out = read(&to)?;
+        assert_eq!(12000, out.len());
+        assert!(out[0..2500].iter().all(|b| *b == 2));
+        assert!(out[2500..4500].iter().all(|b| *b == 1));
+        assert!(out[4500..].iter().all(|b| *b == 2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_stream() -> Result<()> {
+        let data = (0..10000_u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let config = Config { block_size: 4096, ..Config::default() };
+        let updater: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+
+        let mut out = Vec::new();
+        let copied = copy_stream(Path::new("-"), &mut data.as_slice(), &mut out, &config, &updater)?;
+        assert_eq!(10000, copied);
+        assert_eq!(data, out);
+
+        let mut out = Vec::new();
+        assert_eq!(0, copy_stream(Path::new("-"), &mut std::io::empty(), &mut out, &config, &updater)?);
+        assert!(out.is_empty());
+
+        // Write errors, e.g. a closed pipe, are returned.
+        let mut full = [0u8; 100];
+        assert!(copy_stream(Path::new("-"), &mut data.as_slice(), &mut full.as_mut_slice(), &config, &updater).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_byte_range() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let from = tdir.path().join("from.bin");
+        let to = tdir.path().join("to.bin");
+        let data = (0..10000_u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        write(&from, &data)?;
+        write(&to, vec![0xff; 20000])?;
+
+        let updater: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+        let range = ByteRange { offset: 2500, length: Some(4000), dest_offset: None };
+        assert_eq!(4000, copy_byte_range(&from, &to, &range, &test_config(), &updater)?);
+        assert_eq!(data[2500..6500], read(&to)?);
+
+        // Default length is the rest of the file.
+        let range = ByteRange { offset: 9000, ..ByteRange::default() };
+        assert_eq!(1000, copy_byte_range(&from, &to, &range, &test_config(), &updater)?);
+        assert_eq!(data[9000..], read(&to)?);
+
+        // Writing at an offset extends but doesn't truncate.
+        write(&to, vec![0xff; 3000])?;
+        let range = ByteRange { offset: 0, length: Some(2000), dest_offset: Some(2000) };
+        assert_eq!(2000, copy_byte_range(&from, &to, &range, &test_config(), &updater)?);
+        let out = read(&to)?;
+        assert_eq!(4000, out.len());
+        assert!(out[0..2000].iter().all(|b| *b == 0xff));
+        assert_eq!(data[0..2000], out[2000..]);
+
+        for range in [
+            ByteRange { offset: 10001, ..ByteRange::default() },
+            ByteRange { offset: 5000, length: Some(5001), dest_offset: None },
+            ByteRange { offset: 1, length: Some(u64::MAX), dest_offset: None },
+        ] {
+            assert!(copy_byte_range(&from, &to, &range, &test_config(), &updater).is_err());
+        }
+        assert_eq!(4000, read(&to)?.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_clone_span() {
+        assert_eq!(Some(0..8192), clone_span(0, 0, 10000, 20000, 4096));
+        assert_eq!(Some(0..10000), clone_span(0, 0, 10000, 10000, 4096));
+        assert_eq!(Some(96..4192), clone_span(4000, 8096, 8000, 20000, 4096));
+        assert_eq!(Some(96..8000), clone_span(4000, 4000, 8000, 12000, 4096));
+        assert_eq!(None, clone_span(4000, 0, 8000, 20000, 4096));
+        assert_eq!(None, clone_span(100, 100, 4000, 20000, 4096));
+        assert_eq!(None, clone_span(0, 0, 10000, 20000, 0));
+    }
+
+    #[test]
+    fn test_overwrite_in_place() {
+        assert!(overwrite_in_place(1000, 1000));
+        assert!(overwrite_in_place(1000, 500));
+        assert!(overwrite_in_place(1000, 2001));
+        assert!(!overwrite_in_place(1000, 499));
+        assert!(!overwrite_in_place(1000, 2002));
+        assert!(!overwrite_in_place(0, 1000));
+        assert!(!overwrite_in_place(1000, 0));
+    }
+
+    #[test]
+    fn test_copy_in_place() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let from = tdir.path().join("from.bin");
+        let to = tdir.path().join("to.bin");
+        let updater: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+        let abort = Arc::new(Abort::default());
+
+        for (old, new) in [(10000, 8000), (8000, 10000), (10000, 100)] {
+            let data = (0..new).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+            write(&from, &data)?;
+            write(&to, vec![0xff; old])?;
+
+            let handle = CopyHandle::new(&from, &to, &test_config(), &updater, &abort, None)?;
+            // Similar lengths are overwritten without truncating
+            // first.
+            let in_place = overwrite_in_place(old as u64, new as u64);
+            assert_eq!(in_place, handle.in_place);
+            assert!(to.metadata()?.len() >= if in_place { old as u64 } else { 0 });
+
+            handle.copy_file(&updater)?;
+            drop(handle);
+            assert_eq!(data, read(&to)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_sanitized_names() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = tdir.path().join("src");
+        let dest = tdir.path().join("dest");
+        fs::create_dir_all(source.join("dir:1"))?;
+        fs::create_dir_all(&dest)?;
+        write(source.join("dir:1/a?.txt"), "a")?;
+        write(source.join("Foo"), "upper")?;
+        write(source.join("foo"), "lower")?;
+
+        let config = Arc::new(Config {
+            invalid_name: InvalidName::Sanitize,
+            name_profile: Some(NameProfile::windows()),
+            delete: true,
+            ..Config::default()
+        });
+        for _ in 0..2 {
+            let driver = load_driver(Drivers::ParFile, &config)?;
+            driver.copy(vec![source.clone()], &dest, Arc::new(NoopUpdater))?;
+        }
+
+        let target = dest.join("src");
+        assert_eq!(b"a", read(target.join("dir_1/a_.txt"))?.as_slice());
+        // Which of the two is renamed depends on the directory order.
+        let mut names = fs::read_dir(&target)?
+            .map(|e| Ok(e?.file_name().into_string().unwrap()))
+            .collect::<Result<Vec<String>>>()?;
+        names.sort();
+        assert!(names == ["Foo", "dir_1", "foo_1"] || names == ["Foo_1", "dir_1", "foo"], "{:?}", names);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_abort_timeout() -> Result<()> {
+        let abort = Abort::new(&Config { timeout: Some(Duration::from_secs(3600)), ..Config::default() });
+        assert!(!abort.is_set());
+        assert!(abort.check_timeout().is_ok());
+        assert!(abort.set());
+        assert!(abort.is_set());
+        // Aborted for another reason.
+        assert!(abort.check_timeout().is_ok());
+
+        let abort = Abort::new(&Config { timeout: Some(Duration::ZERO), ..Config::default() });
+        assert!(abort.is_set());
+        assert!(!abort.set());
+        let err = abort.check_timeout().unwrap_err();
+        assert!(matches!(err.downcast_ref::<XcpError>(), Some(XcpError::TimedOut(0))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_timeouts() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = tdir.path().join("src");
+        fs::create_dir(&source)?;
+        write(source.join("file.txt"), "data")?;
+
+        for driver in [Drivers::ParFile, #[cfg(feature = "parblock")] Drivers::ParBlock] {
+            let dest = tdir.path().join(format!("timeout-{:?}", driver));
+            let config = Arc::new(Config { timeout: Some(Duration::ZERO), ..Config::default() });
+            let err = load_driver(driver, &config)?
+                .copy(vec![source.clone()], &dest, Arc::new(NoopUpdater))
+                .unwrap_err();
+            assert!(matches!(err.downcast_ref::<XcpError>(), Some(XcpError::TimedOut(_))), "{:?}", err);
+            assert!(!dest.join("file.txt").exists());
+
+            let dest = tdir.path().join(format!("file-timeout-{:?}", driver));
+            let config = Arc::new(Config {
+                file_timeout: Some(Duration::ZERO),
+                continue_on_error: true,
+                // A clone is a single call, which can't time out.
+                reflink: Reflink::Never,
+                ..Config::default()
+            });
+            let updater = ChannelUpdater::new(&config);
+            let rx = updater.rx_channel();
+            load_driver(driver, &config)?.copy(vec![source.clone()], &dest, Arc::new(updater))?;
+            let errors = rx.iter()
+                .filter(|u| matches!(u, StatusUpdate::Error(XcpError::FileTimedOut(..))))
+                .count();
+            assert_eq!(1, errors);
+            assert!(dest.is_dir());
+            assert!(!dest.join("file.txt").exists());
+        }
+        Ok(())
+    }
+    // ENOENT, EIO, EACCES, ENOTDIR and ENOSPC are 2, 5, 13, 20 and 28
+    // on all supported platforms.
+    const ENOENT: i32 = 2;
+    const EIO: i32 = 5;
+    const EACCES: i32 = 13;
+    const ENOTDIR: i32 = 20;
+    const ENOSPC: i32 = 28;
+
+    fn fault_tree(tdir: &TempDir) -> Result<PathBuf> {
+        let source = tdir.path().join("src");
+        fs::create_dir_all(source.join("sub"))?;
+        write(source.join("a.txt"), "file a")?;
+        write(source.join("sub/b.txt"), "file b")?;
+        write(source.join("sub/c.txt"), "file c")?;
+        Ok(source)
+    }
+
+    #[test]
+    fn test_fault_continue_on_error() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = fault_tree(&tdir)?;
+
+        for driver in [Drivers::ParFile, #[cfg(feature = "parblock")] Drivers::ParBlock] {
+            let fs = Arc::new(FaultInjectingFs::new());
+            fs.fail(FsOp::OpenSource, source.join("sub/b.txt"), EACCES);
+
+            let dest = tdir.path().join(format!("stop-{:?}", driver));
+            let config = Arc::new(Config { fs: fs.clone(), workers: 1, ..Config::default() });
+            let err = load_driver(driver, &config)?
+                .copy(vec![source.clone()], &dest, Arc::new(NoopUpdater))
+                .unwrap_err();
+            assert!(err.chain().any(|e| e.downcast_ref::<std::io::Error>()
+                                    .is_some_and(|e| e.raw_os_error() == Some(EACCES))), "{:?}", err);
+
+            let dest = tdir.path().join(format!("continue-{:?}", driver));
+            let config = Arc::new(Config { fs: fs.clone(), continue_on_error: true, ..Config::default() });
+            let updater = ChannelUpdater::new(&config);
+            let rx = updater.rx_channel();
+            load_driver(driver, &config)?.copy(vec![source.clone()], &dest, Arc::new(updater))?;
+            let errors = rx.iter()
+                .filter(|u| matches!(u, StatusUpdate::Error(_)))
+                .count();
+            assert_eq!(1, errors);
+            assert_eq!(b"file a", read(dest.join("a.txt"))?.as_slice());
+            assert_eq!(b"file c", read(dest.join("sub/c.txt"))?.as_slice());
+            assert!(!dest.join("sub/b.txt").exists());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fault_delete_unchecked_source() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = fault_tree(&tdir)?;
+
+        for driver in [Drivers::ParFile, #[cfg(feature = "parblock")] Drivers::ParBlock] {
+            let dest = tdir.path().join(format!("dest-{:?}", driver));
+            fs::create_dir_all(dest.join("gone"))?;
+            write(dest.join("gone/data"), "data")?;
+            write(dest.join("extra.txt"), "extra")?;
+            // The source may exist, but can't be checked.
+            let fs = Arc::new(FaultInjectingFs::new());
+            fs.fail(FsOp::Stat, source.join("gone"), EACCES);
+            let config = Arc::new(Config {
+                fs: fs.clone(),
+                delete: true,
+                no_target_directory: true,
+                ..Config::default()
+            });
+            let updater = ChannelUpdater::new(&config);
+            let rx = updater.rx_channel();
+            load_driver(driver, &config)?.copy(vec![source.clone()], &dest, Arc::new(updater))?;
+
+            let errors = rx.iter()
+                .filter(|u| matches!(u, StatusUpdate::Error(_)))
+                .collect::<Vec<_>>();
+            assert_eq!(1, errors.len());
+            assert!(matches!(&errors[0], StatusUpdate::Error(XcpError::CopyFailed { to, source, .. })
+                             if to.ends_with("gone") && source.raw_os_error() == Some(EACCES)), "{:?}", errors);
+            assert_eq!(b"data", read(dest.join("gone/data"))?.as_slice());
+            assert!(!dest.join("extra.txt").exists());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fault_metadata_fails_file() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = fault_tree(&tdir)?;
+
+        for driver in [Drivers::ParFile, #[cfg(feature = "parblock")] Drivers::ParBlock] {
+            for staged in [false, true] {
+                let dest = tdir.path().join(format!("dest-{:?}-{}", driver, staged));
+                let fs = Arc::new(FaultInjectingFs::new());
+                fs.fail(FsOp::SetMetadata, dest.join("sub/b.txt"), EIO);
+                let staging = tdir.path().join(format!("staging-{:?}", driver));
+                fs::create_dir_all(&staging)?;
+                let config = Arc::new(Config {
+                    fs: fs.clone(),
+                    collect_results: true,
+                    staging_dir: staged.then_some(staging),
+                    ..Config::default()
+                });
+                let updater = ChannelUpdater::new(&config);
+                let rx = updater.rx_channel();
+                let stats = load_driver(driver, &config)?.copy(vec![source.clone()], &dest, Arc::new(updater))?;
+
+                // The error is sent before the file is completed, and
+                // it isn't counted as copied.
+                let updates = rx.iter().collect::<Vec<_>>();
+                let id = updates.iter()
+                    .find_map(|u| match u {
+                        StatusUpdate::FileStarted(id, path) if path.ends_with("sub/b.txt") => Some(*id),
+                        _ => None,
+                    })
+                    .unwrap();
+                let error = updates.iter()
+                    .position(|u| matches!(u, StatusUpdate::Error(XcpError::CopyFailed { to, source, .. })
+                                           if to.ends_with("sub/b.txt") && source.raw_os_error() == Some(EIO)))
+                    .unwrap();
+                let completed = updates.iter()
+                    .position(|u| matches!(u, StatusUpdate::FileCompleted(i, _) if *i == id))
+                    .unwrap();
+                assert!(error < completed);
+                assert_eq!(1, updates.iter().filter(|u| matches!(u, StatusUpdate::Error(_))).count());
+                assert_eq!(2, stats.files);
+                assert!(stats.results.iter().all(|r| !r.to.ends_with("sub/b.txt")));
+                assert_eq!(staged, !dest.join("sub/b.txt").exists());
+                assert_eq!(b"file c", read(dest.join("sub/c.txt"))?.as_slice());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_staged_commit_failure() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = fault_tree(&tdir)?;
+        let staging = tdir.path().join("staging");
+        fs::create_dir(&staging)?;
+
+        for driver in [Drivers::ParFile, #[cfg(feature = "parblock")] Drivers::ParBlock] {
+            // A file can't be moved over a non-empty directory.
+            let dest = tdir.path().join(format!("dest-{:?}", driver));
+            fs::create_dir_all(dest.join("src/a.txt/inner"))?;
+            let config = Arc::new(Config {
+                staging_dir: Some(staging.clone()),
+                continue_on_error: true,
+                collect_results: true,
+                ..Config::default()
+            });
+            let updater = ChannelUpdater::new(&config);
+            let rx = updater.rx_channel();
+            let stats = load_driver(driver, &config)?.copy(vec![source.clone()], &dest, Arc::new(updater))?;
+
+            let errors = rx.iter()
+                .filter_map(|u| match u {
+                    StatusUpdate::Error(e) => Some(e),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            let [err] = errors.as_slice() else {
+                panic!("{:?}", errors);
+            };
+            assert_eq!("copy_failed", err.code());
+            assert_eq!(Some(source.join("a.txt").as_path()), err.source_path());
+            assert_eq!(Some(dest.join("src/a.txt").as_path()), err.dest_path());
+            assert_eq!(2, stats.files);
+            assert!(stats.results.iter().all(|r| !r.to.ends_with("a.txt")));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fault_truncate_in_place() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = tdir.path().join("source");
+        write(&source, vec![1; 10_000])?;
+
+        for driver in [Drivers::ParFile, #[cfg(feature = "parblock")] Drivers::ParBlock] {
+            // Long enough to be overwritten in place.
+            let dest = tdir.path().join(format!("dest-{:?}", driver));
+            write(&dest, vec![2; 15_000])?;
+            let fs = Arc::new(FaultInjectingFs::new());
+            fs.fail(FsOp::Truncate, &dest, EIO);
+            let config = Arc::new(Config { fs: fs.clone(), collect_results: true, ..Config::default() });
+            let updater = ChannelUpdater::new(&config);
+            let rx = updater.rx_channel();
+            let stats = load_driver(driver, &config)?.copy(vec![source.clone()], &dest, Arc::new(updater))?;
+
+            let errors = rx.iter()
+                .filter(|u| matches!(u, StatusUpdate::Error(XcpError::CopyFailed { source, .. })
+                                     if source.raw_os_error() == Some(EIO)))
+                .count();
+            assert_eq!(1, errors);
+            assert_eq!(1, fs.calls(FsOp::Truncate));
+            assert_eq!(0, stats.files);
+            assert!(stats.results.is_empty());
+            // The old tail is still there.
+            assert_eq!(15_000, read(&dest)?.len());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fault_error_limits() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = tdir.path().join("src");
+        fs::create_dir(&source)?;
+        let fs = Arc::new(FaultInjectingFs::new());
+        for i in 0..20 {
+            let file = source.join(format!("{:02}.txt", i));
+            write(&file, "data")?;
+            if i % 2 == 1 {
+                fs.fail(FsOp::OpenSource, file, EACCES);
+            }
+        }
+
+        for driver in [Drivers::ParFile, #[cfg(feature = "parblock")] Drivers::ParBlock] {
+            let limits = [
+                (Config { max_errors: Some(3), ..Config::default() }, 3),
+                (Config { max_error_rate: Some(3), ..Config::default() }, 4),
+            ];
+            for (i, (limit, stopped_at)) in limits.into_iter().enumerate() {
+                let dest = tdir.path().join(format!("dest-{:?}-{}", driver, i));
+                let config = Arc::new(Config {
+                    fs: fs.clone(),
+                    workers: 1,
+                    continue_on_error: true,
+                    ..limit
+                });
+                let updater = ChannelUpdater::new(&config);
+                let rx = updater.rx_channel();
+                let err = load_driver(driver, &config)?
+                    .copy(vec![source.clone()], &dest, Arc::new(updater))
+                    .unwrap_err();
+                match err.downcast_ref::<XcpError>() {
+                    Some(XcpError::TooManyErrors(n)) => assert_eq!(stopped_at, *n),
+                    Some(XcpError::ErrorRateExceeded { errors, .. }) => assert_eq!(stopped_at, *errors),
+                    _ => panic!("Unexpected error {:?}", err),
+                }
+                let errors = rx.iter()
+                    .filter(|u| matches!(u, StatusUpdate::Error(_)))
+                    .count() as u64;
+                // Files already being copied may still fail, but the
+                // rest are never attempted.
+                assert!(errors >= stopped_at && errors < 10, "{}", errors);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fault_mkdir() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = fault_tree(&tdir)?;
+
+        for driver in [Drivers::ParFile, #[cfg(feature = "parblock")] Drivers::ParBlock] {
+            let dest = tdir.path().join(format!("dest-{:?}", driver));
+            let fs = Arc::new(FaultInjectingFs::new());
+            fs.fail(FsOp::Mkdir, dest.join("sub"), EACCES);
+            // A directory that can't be created is fatal regardless.
+            let config = Arc::new(Config { fs: fs.clone(), continue_on_error: true, ..Config::default() });
+            let err = load_driver(driver, &config)?
+                .copy(vec![source.clone()], &dest, Arc::new(NoopUpdater))
+                .unwrap_err();
+            assert!(err.to_string().contains("Permission denied"), "{:?}", err);
+            assert!(dest.is_dir());
+            assert!(!dest.join("sub").exists());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fault_no_space() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = fault_tree(&tdir)?;
+        write(source.join("a.txt"), vec![1u8; 4096])?;
+
+        for driver in [Drivers::ParFile, #[cfg(feature = "parblock")] Drivers::ParBlock] {
+            let dest = tdir.path().join(format!("dest-{:?}", driver));
+            let fs = Arc::new(FaultInjectingFs::new());
+            // Fail after the first block.
+            fs.fail_after(FsOp::CopyBytes, dest.join("a.txt"), ENOSPC, 1);
+            let config = Arc::new(Config {
+                fs: fs.clone(),
+                workers: 1,
+                block_size: 1024,
+                reflink: Reflink::Never,
+                ..Config::default()
+            });
+            let updater = ChannelUpdater::new(&config);
+            let rx = updater.rx_channel();
+            // The error is sent as a status update; the copy itself
+            // only fails if the walk was still running.
+            let _ = load_driver(driver, &config)?.copy(vec![source.clone()], &dest, Arc::new(updater));
+            let full = rx.iter()
+                .filter(|u| matches!(u, StatusUpdate::Error(XcpError::DestinationFull { .. })))
+                .count();
+            assert_eq!(1, full);
+            // The partial file is removed.
+            assert!(!dest.join("a.txt").exists());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fault_read_errors() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = fault_tree(&tdir)?;
+        let data = (0..16384u32).map(|i| (i % 251) as u8 + 1).collect::<Vec<u8>>();
+        write(source.join("a.txt"), &data)?;
+
+        for driver in [Drivers::ParFile, #[cfg(feature = "parblock")] Drivers::ParBlock] {
+            let dest = tdir.path().join(format!("fail-{:?}", driver));
+            let fs = Arc::new(FaultInjectingFs::new());
+            // The copy fails, and reading the data back finds the
+            // unreadable sector.
+            fs.fail(FsOp::CopyBytes, dest.join("a.txt"), EIO);
+            fs.fail_range(source.join("a.txt"), 5000..5001, EIO);
+            let config = Arc::new(Config {
+                fs: fs.clone(),
+                block_size: 4096,
+                reflink: Reflink::Never,
+                continue_on_error: true,
+                ..Config::default()
+            });
+            let updater = ChannelUpdater::new(&config);
+            let rx = updater.rx_channel();
+            load_driver(driver, &config)?.copy(vec![source.clone()], &dest, Arc::new(updater))?;
+            let offsets = rx.iter()
+                .filter_map(|u| match u {
+                    StatusUpdate::Error(XcpError::ReadFailed { offset, .. }) => Some(offset),
+                    _ => None,
+                })
+                .collect::<Vec<u64>>();
+            assert_eq!(vec![4608], offsets);
+            assert_eq!(b"file b", read(dest.join("sub/b.txt"))?.as_slice());
+
+            let dest = tdir.path().join(format!("fill-{:?}", driver));
+            fs.fail(FsOp::CopyBytes, dest.join("a.txt"), EIO);
+            let config = Arc::new(Config { fs: fs.clone(), fill_errors: true, ..(*config).clone() });
+            let updater = ChannelUpdater::new(&config);
+            let rx = updater.rx_channel();
+            load_driver(driver, &config)?.copy(vec![source.clone()], &dest, Arc::new(updater))?;
+            let bad = rx.iter()
+                .filter_map(|u| match u {
+                    StatusUpdate::BadRanges { path, ranges } => Some((path, ranges)),
+                    StatusUpdate::Error(e) => panic!("Unexpected error {}", e),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(vec![(dest.join("a.txt"), vec![4608..5120])], bad);
+            let copied = read(dest.join("a.txt"))?;
+            assert_eq!(data.len(), copied.len());
+            assert!(copied[4608..5120].iter().all(|b| *b == 0));
+            assert_eq!(data[..4608], copied[..4608]);
+            assert_eq!(data[5120..], copied[5120..]);
+        }
+        Ok(())
+    }
+
+    // The copied bytes, and the bytes of each completed file, sent
+    // in `updates`.
+    fn totals(updates: &[StatusUpdate]) -> (u64, Vec<u64>) {
+        let copied = updates.iter()
+            .filter_map(|u| match u {
+                StatusUpdate::Copied(b) => Some(*b),
+                _ => None,
+            })
+            .sum();
+        let completed = updates.iter()
+            .filter_map(|u| match u {
+                StatusUpdate::FileCompleted(_, b) => Some(*b),
+                _ => None,
+            })
+            .collect();
+        (copied, completed)
+    }
+
+    #[test]
+    fn test_fault_fallback_totals() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = tdir.path().join("src");
+        fs::create_dir(&source)?;
+        let data = (0..65536u32).map(|i| (i % 251) as u8 + 1).collect::<Vec<u8>>();
+        write(source.join("a.bin"), &data)?;
+
+        for driver in [Drivers::ParFile, #[cfg(feature = "parblock")] Drivers::ParBlock] {
+            let dest = tdir.path().join(format!("dest-{:?}", driver));
+            let fs = Arc::new(FaultInjectingFs::new());
+            // Fail part-way through the file, so the rest of it is
+            // copied again through userspace.
+            fs.fail_after(FsOp::CopyBytes, dest.join("a.bin"), EIO, 3);
+            let config = Arc::new(Config {
+                fs: fs.clone(),
+                workers: 1,
+                block_size: 4096,
+                reflink: Reflink::Never,
+                ..Config::default()
+            });
+            let updater = ChannelUpdater::new(&config);
+            let rx = updater.rx_channel();
+            load_driver(driver, &config)?.copy(vec![source.clone()], &dest, Arc::new(updater))?;
+            let updates = rx.iter().collect::<Vec<_>>();
+            assert!(fs.calls(FsOp::CopyBytes) > 4);
+            assert_eq!((data.len() as u64, vec![data.len() as u64]), totals(&updates));
+            assert_eq!(data, read(dest.join("a.bin"))?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_recopied_totals() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let from = tdir.path().join("from.bin");
+        let to = tdir.path().join("to.bin");
+        let data = (0..10000u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        write(&from, &data)?;
+
+        let config = test_config();
+        let updater = ChannelUpdater::new(&config);
+        let rx = updater.rx_channel();
+        let updater: Arc<dyn StatusUpdater> = Arc::new(updater);
+        let handle = CopyHandle::new(&from, &to, &config, &updater, &Arc::new(Abort::default()), None)?;
+        // Part of the file is copied and then all of it again, as
+        // when a whole-file method succeeds after a partial copy.
+        handle.copy_bytes(4000, &updater, None)?;
+        (&handle.infd).seek(SeekFrom::Start(2000))?;
+        (&handle.outfd).seek(SeekFrom::Start(2000))?;
+        handle.copy_bytes(8000, &updater, None)?;
+        handle.reflinked()?;
+        assert_eq!(10000, handle.written());
+        drop(handle);
+        drop(updater);
+
+        let updates = rx.iter().collect::<Vec<_>>();
+        assert_eq!((10000, vec![10000]), totals(&updates));
+        assert_eq!(data, read(&to)?);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_latch_xattrs() {
+        // ENOSPC and EOPNOTSUPP on Linux.
+        let errno = |n| libfs::Error::from(std::io::Error::from_raw_os_error(n));
+        let config = Config {
+            xattrs_unsupported: Some(Arc::default()),
+            ..Config::default()
+        };
+        latch_xattrs(&config, &errno(28));
+        assert!(!xattrs_latched(&config));
+        latch_xattrs(&config, &errno(95));
+        assert!(xattrs_latched(&config));
+
+        // Without the latch every file keeps trying.
+        let config = Config::default();
+        latch_xattrs(&config, &errno(95));
+        assert!(!xattrs_latched(&config));
+    }
+
+    // Run `swap` on a thread until `copy` has been called `runs`
+    // times.
+    fn race(runs: usize, swap: impl Fn() + Send + 'static, mut copy: impl FnMut(usize)) {
+        let stop = Arc::new(AtomicBool::new(false));
+        let swapping = {
+            let stop = stop.clone();
+            thread::spawn(move || while !stop.load(Ordering::Relaxed) { swap() })
+        };
+        for i in 0..runs {
+            copy(i);
+        }
+        stop.store(true, Ordering::Relaxed);
+        swapping.join().unwrap();
+    }
+
+    #[test]
+    fn test_source_replaced_during_copy() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = fault_tree(&tdir)?;
+        // The source, or a directory in it, is found to have gone or
+        // been replaced with a file once its entries have been read.
+        let cases = [
+            ("", ENOENT, "source_not_found"),
+            ("sub/b.txt", ENOENT, "source_not_found"),
+            ("sub/b.txt", ENOTDIR, "invalid_source"),
+        ];
+
+        for driver in [Drivers::ParFile, #[cfg(feature = "parblock")] Drivers::ParBlock] {
+            for (i, (path, errno, code)) in cases.into_iter().enumerate() {
+                let fs = Arc::new(FaultInjectingFs::new());
+                fs.fail(FsOp::Stat, source.join(path), errno);
+
+                let dest = tdir.path().join(format!("stop-{:?}-{}", driver, i));
+                let config = Arc::new(Config { fs: fs.clone(), ..Config::default() });
+                let err = load_driver(driver, &config)?
+                    .copy(vec![source.clone()], &dest, Arc::new(NoopUpdater))
+                    .unwrap_err();
+                assert_eq!(Some(code), err.downcast_ref::<XcpError>().map(XcpError::code), "{:?}", err);
+
+                let dest = tdir.path().join(format!("continue-{:?}-{}", driver, i));
+                let config = Arc::new(Config { fs: fs.clone(), continue_on_error: true, ..Config::default() });
+                let updater = ChannelUpdater::new(&config);
+                let rx = updater.rx_channel();
+                load_driver(driver, &config)?.copy(vec![source.clone()], &dest, Arc::new(updater))?;
+                let errors = rx.iter()
+                    .filter_map(|u| match u {
+                        StatusUpdate::Error(e) => Some(e.code()),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                assert_eq!(vec![code], errors);
+                if !path.is_empty() {
+                    assert!(!dest.join(path).exists());
+                    assert_eq!(b"file a", read(dest.join("a.txt"))?.as_slice());
+                    assert_eq!(b"file c", read(dest.join("sub/c.txt"))?.as_slice());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_dest_replaced_with_source() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = tdir.path().join("source");
+        let data = (0..64 * 1024).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        write(&source, &data)?;
+        let dest = tdir.path().join("dest");
+
+        for driver in [Drivers::ParFile, #[cfg(feature = "parblock")] Drivers::ParBlock] {
+            let (src, to) = (source.clone(), dest.clone());
+            let (link, other) = (tdir.path().join("link"), tdir.path().join("other"));
+            let config = Arc::new(Config::default());
+            // The destination is in turn a hard link of the source,
+            // and a separate file.
+            race(200, move || {
+                let _ = fs::hard_link(&src, &link).and_then(|_| fs::rename(&link, &to));
+                let _ = write(&other, "other").and_then(|_| fs::rename(&other, &to));
+            }, |_| {
+                let copied = load_driver(driver, &config).unwrap()
+                    .copy(vec![source.clone()], &dest, Arc::new(NoopUpdater));
+                if let Err(err) = copied {
+                    assert!(matches!(err.downcast_ref::<XcpError>(), Some(XcpError::InvalidDestination { .. })),
+                            "Unexpected error {:?}", err);
+                }
+            });
+            assert_eq!(data, read(&source)?);
+        }
+
+        // The window above is narrow, so check the open destination is
+        // compared directly.
+        let link = tdir.path().join("link");
+        fs::hard_link(&source, &link)?;
+        let meta = source.metadata()?;
+        for in_place in [None, Some(data.len() as u64)] {
+            let err = create_dest(&link, &Config::default(), in_place, Some(&meta)).unwrap_err();
+            assert!(matches!(err.downcast_ref::<XcpError>(), Some(XcpError::InvalidDestination { .. })));
+        }
+        assert_eq!(data, read(&source)?);
+        Ok(())
+    }
 }
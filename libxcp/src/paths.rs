@@ -14,13 +14,20 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::path::Path;
+//! Source filtering and destination path handling.
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 use ignore::gitignore::{Gitignore, GitignoreBuilder};
-use log::info;
+use log::{debug, info};
 use walkdir::DirEntry;
 
 use crate::config::Config;
-use crate::errors::Result;
+use crate::errors::{Result, XcpError};
+use crate::filter::Filter;
 
 /// Parse a git ignore file.
 pub fn parse_ignore(source: &Path, config: &Config) -> Result<Option<Gitignore>> {
@@ -49,3 +56,325 @@ pub fn ignore_filter(entry: &DirEntry, ignore: &Option<Gitignore>) -> bool {
         }
     }
 }
+
+/// Filter to return whether a given entry, under the source `root`,
+/// is kept by the patterns of [Config::filter].
+pub fn pattern_filter(entry: &DirEntry, root: &Path, filter: &Option<Arc<Filter>>) -> bool {
+    match filter {
+        None => true,
+        Some(f) => {
+            let rel = entry.path().strip_prefix(root).unwrap_or(entry.path());
+            !f.excludes(rel, entry.file_type().is_dir())
+        }
+    }
+}
+
+/// Normalize a destination path so that safety checks compare the
+/// location that will actually be written to.
+///
+/// The longest existing prefix of `dest` is resolved with
+/// [canonicalize](std::fs::canonicalize), following any symlinks and
+/// `..` components in it. The remaining, non-existent, suffix is
+/// normalized lexically; `.` is dropped and `..` removes the previous
+/// suffix component. A `..` that would climb above the resolved
+/// prefix is rejected with [XcpError::InvalidDestination] unless
+/// `allow_dotdot` is set, in which case it removes a component of the
+/// prefix.
+pub fn normalize_dest(dest: &Path, allow_dotdot: bool) -> Result<PathBuf> {
+    let comps = dest.components().collect::<Vec<Component>>();
+
+    // Find the longest prefix that exists; the empty prefix is the
+    // current directory.
+    let mut resolved = None;
+    for n in (0..=comps.len()).rev() {
+        let prefix = match n {
+            0 => PathBuf::from("."),
+            _ => comps[..n].iter().collect(),
+        };
+        match prefix.canonicalize() {
+            Ok(p) => {
+                resolved = Some((p, n));
+                break;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let (mut normalized, n) = resolved
+        .ok_or_else(|| XcpError::InvalidDestination {
+            path: dest.to_path_buf(),
+            reason: "Failed to resolve destination path.",
+        })?;
+
+    // Number of suffix components currently pushed.
+    let mut depth = 0;
+    for comp in &comps[n..] {
+        match comp {
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => {
+                normalized.pop();
+                depth -= 1;
+            }
+            Component::ParentDir if allow_dotdot => {
+                normalized.pop();
+            }
+            Component::ParentDir => {
+                return Err(XcpError::InvalidDestination {
+                    path: dest.to_path_buf(),
+                    reason: "Destination contains '..' above its existing parent directory.",
+                }.into());
+            }
+            c => {
+                normalized.push(c);
+                depth += 1;
+            }
+        }
+    }
+
+    debug!("Normalized destination {:?} to {:?}", dest, normalized);
+    Ok(normalized)
+}
+
+/// As [normalize_dest], but a symlink as the last component is kept
+/// rather than followed, for a destination that is replaced rather
+/// than copied into; see [Config::no_target_directory]. A trailing
+/// `/` still follows it, as it names the directory.
+pub fn normalize_dest_entry(dest: &Path, allow_dotdot: bool) -> Result<PathBuf> {
+    match (dest.parent(), dest.file_name()) {
+        (Some(parent), Some(name)) if dest.is_symlink() => {
+            let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+            Ok(normalize_dest(parent, allow_dotdot)?.join(name))
+        }
+        _ => normalize_dest(dest, allow_dotdot),
+    }
+}
+
+// The normal components of a source path, for naming its
+// destination subdirectory. The parent is resolved so that relative
+// sources such as `.` and `../data` have meaningful names.
+fn source_components(source: &Path) -> Vec<OsString> {
+    let resolved = match (source.parent(), source.file_name()) {
+        (Some(parent), Some(name)) => {
+            let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+            parent.canonicalize().map(|p| p.join(name))
+        }
+        _ => source.canonicalize(),
+    }.unwrap_or_else(|_| source.to_path_buf());
+    resolved.components()
+        .filter_map(|c| match c {
+            Component::Normal(n) => Some(n.to_os_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+// The last `depth` components joined with '_', e.g. `disk1_data` for
+// `/mnt/disk1/data` at depth 2.
+fn join_tail(comps: &[OsString], depth: usize) -> OsString {
+    let tail = &comps[comps.len().saturating_sub(depth)..];
+    tail.iter().enumerate().fold(OsString::new(), |mut tag, (i, c)| {
+        if i > 0 {
+            tag.push("_");
+        }
+        tag.push(c);
+        tag
+    })
+}
+
+// Subdirectory names for [Config::dest_subdir_from_source]. Sources
+// with the same basename are named with as many parent directories as
+// needed to tell them apart.
+fn source_tags(sources: &[PathBuf]) -> Vec<OsString> {
+    let comps = sources.iter().map(|s| source_components(s)).collect::<Vec<_>>();
+    let mut depths = vec![1; sources.len()];
+    loop {
+        let tags = comps.iter().zip(&depths)
+            .map(|(c, d)| join_tail(c, *d))
+            .collect::<Vec<OsString>>();
+        let mut groups: HashMap<&OsStr, Vec<usize>> = HashMap::new();
+        for (i, tag) in tags.iter().enumerate() {
+            groups.entry(tag).or_default().push(i);
+        }
+        let mut grown = false;
+        for group in groups.values().filter(|g| g.len() > 1) {
+            for &i in group {
+                if depths[i] < comps[i].len() {
+                    depths[i] += 1;
+                    grown = true;
+                }
+            }
+        }
+        if !grown {
+            return tags;
+        }
+    }
+}
+
+/// Map each source to the name of the directory it is copied to under
+/// `dest`, or `None` if it is copied to `dest` itself. Sources are
+/// copied under their basename if `dest` is an existing directory and
+/// [Config::no_target_directory] isn't set. With
+/// [Config::dest_subdir_from_source] they always are, and colliding
+/// basenames are disambiguated with their parent directory names,
+/// e.g. `disk1_data` and `disk2_data`.
+///
+/// Two sources mapping to the same destination would be copied over
+/// each other concurrently, so this is an error,
+/// [XcpError::DestinationCollision].
+pub fn dest_names(sources: &[PathBuf], dest: &Path, config: &Config) -> Result<Vec<Option<OsString>>> {
+    let names = if config.dest_subdir_from_source {
+        source_tags(sources).into_iter()
+            .map(|t| (!t.is_empty()).then_some(t))
+            .collect::<Vec<_>>()
+    } else if dest.is_dir() && !config.no_target_directory {
+        sources.iter()
+            .map(|s| s.components().next_back().map(|c| c.as_os_str().to_os_string()))
+            .collect::<Vec<_>>()
+    } else {
+        vec![None; sources.len()]
+    };
+
+    let mut seen: HashMap<Option<&OsString>, &PathBuf> = HashMap::new();
+    for (source, name) in sources.iter().zip(&names) {
+        if config.dest_subdir_from_source && name.is_none() {
+            return Err(XcpError::InvalidSource {
+                path: source.clone(),
+                reason: "Failed to find source directory name.",
+            }.into());
+        }
+        if let Some(first) = seen.insert(name.as_ref(), source) {
+            let target = name.as_ref().map_or_else(|| dest.to_path_buf(), |n| dest.join(n));
+            return Err(XcpError::DestinationCollision(first.clone(), source.clone(), target).into());
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::create_dir_all;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    fn names(sources: &[&str], dest: &Path, config: &Config) -> Result<Vec<Option<String>>> {
+        let sources = sources.iter().map(PathBuf::from).collect::<Vec<_>>();
+        Ok(dest_names(&sources, dest, config)?.into_iter()
+            .map(|n| n.map(|n| n.into_string().unwrap()))
+            .collect())
+    }
+
+    #[test]
+    fn test_dest_names() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let config = Config::default();
+        let some = |n: &[&str]| n.iter().map(|n| Some(n.to_string())).collect::<Vec<_>>();
+
+        assert_eq!(some(&["a", "b"]), names(&["/x/a", "/y/b"], tdir.path(), &config)?);
+        // A missing destination is the target itself.
+        let missing = tdir.path().join("missing");
+        assert_eq!(vec![None], names(&["/x/a"], &missing, &config)?);
+
+        let config = Config { no_target_directory: true, ..Config::default() };
+        assert_eq!(vec![None], names(&["/x/a"], tdir.path(), &config)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dest_names_collide() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let err = names(&["/mnt/disk1/data", "/mnt/disk2/data"], tdir.path(), &Config::default())
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref::<XcpError>(), Some(XcpError::DestinationCollision(..))));
+
+        let config = Config { no_target_directory: true, ..Config::default() };
+        assert!(names(&["/x/a", "/y/b"], tdir.path(), &config).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dest_names_subdir_from_source() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let missing = tdir.path().join("missing");
+        let config = Config { dest_subdir_from_source: true, ..Config::default() };
+        let some = |n: &[&str]| n.iter().map(|n| Some(n.to_string())).collect::<Vec<_>>();
+
+        assert_eq!(some(&["a"]), names(&["/x/a"], &missing, &config)?);
+        assert_eq!(some(&["disk1_data", "disk2_data", "other"]),
+                   names(&["/mnt/disk1/data", "/mnt/disk2/data", "/mnt/other"], &missing, &config)?);
+        // Only as many parents as needed are added.
+        assert_eq!(some(&["a_b_data", "c_b_data", "x"]),
+                   names(&["/a/b/data", "/c/b/data", "/a/x"], &missing, &config)?);
+        // Identical sources can't be separated.
+        assert!(names(&["/x/a", "/x/a"], &missing, &config).is_err());
+        assert!(names(&["/"], &missing, &config).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_symlinked_parent() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let base = tdir.path().canonicalize()?;
+        create_dir_all(base.join("real/sub"))?;
+        symlink(base.join("real"), base.join("link"))?;
+
+        assert_eq!(base.join("real/sub"), normalize_dest(&base.join("link/sub"), false)?);
+        assert_eq!(base.join("real/new/file"), normalize_dest(&base.join("link/new/file"), false)?);
+        // '..' after a symlink is relative to the link target.
+        assert_eq!(base.join("real"), normalize_dest(&base.join("link/sub/.."), false)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_dest_entry() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let base = tdir.path().canonicalize()?;
+        create_dir_all(base.join("real/sub"))?;
+        symlink(base.join("real"), base.join("link"))?;
+        symlink(base.join("real/sub"), base.join("real/sublink"))?;
+
+        assert_eq!(base.join("link"), normalize_dest_entry(&base.join("link"), false)?);
+        assert_eq!(base.join("real/sublink"), normalize_dest_entry(&base.join("link/sublink"), false)?);
+        assert_eq!(base.join("real"), normalize_dest_entry(&base.join("link/"), false)?);
+        assert_eq!(base.join("real/sub"), normalize_dest_entry(&base.join("link/sub"), false)?);
+        assert_eq!(base.join("new"), normalize_dest_entry(&base.join("new"), false)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_trailing_dot() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let base = tdir.path().canonicalize()?;
+        create_dir_all(base.join("dir"))?;
+
+        assert_eq!(base.join("dir"), normalize_dest(&base.join("dir/."), false)?);
+        assert_eq!(base.join("new"), normalize_dest(&base.join("new/."), false)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_missing_suffix() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let base = tdir.path().canonicalize()?;
+
+        assert_eq!(base.join("a/b/c"), normalize_dest(&base.join("a/b/c"), false)?);
+        assert_eq!(base.join("a/c"), normalize_dest(&base.join("a/./b/../c"), false)?);
+        assert_eq!(base.join("c"), normalize_dest(&base.join("a/b/../../c"), false)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_dotdot_escape() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let base = tdir.path().canonicalize()?;
+        create_dir_all(base.join("dir"))?;
+
+        let dest = base.join("dir/new/../other");
+        assert_eq!(base.join("dir/other"), normalize_dest(&dest, false)?);
+
+        let dest = base.join("dir/new/../../other");
+        assert!(normalize_dest(&dest, false).is_err());
+        assert_eq!(base.join("other"), normalize_dest(&dest, true)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,504 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The pattern language shared by `--glob` sources and the
+//! [Filter](crate::filter::Filter) patterns:
+//!
+//! * `*` matches any characters other than `/`, and `?` any one of
+//!   them. Both match a leading `.`.
+//! * `**` as a whole path component matches any number of components,
+//!   e.g. `a/**/b` or `**/*.rs`; anywhere else it is an error.
+//! * `[...]` matches any of the characters listed, or in ranges such
+//!   as `a-z`, and `[!...]` or `[^...]` any other character. A `]`
+//!   first in the list is literal, as is `\`.
+//! * `{a,b}` matches either alternative. Alternatives may contain `/`
+//!   and further braces, and braces are expanded first, so a pattern
+//!   is a set of [Alternative]s without them.
+//! * `\` escapes the next character.
+//!
+//! A pattern that can't be compiled is reported as
+//! [XcpError::InvalidArguments], naming the pattern and the column of
+//! the problem.
+
+use std::fmt::Display;
+use std::io;
+use std::path::PathBuf;
+
+use globset::{Glob, GlobBuilder};
+use walkdir::WalkDir;
+
+use crate::errors::{Result, XcpError};
+
+/// The most alternatives the braces of a pattern may expand to.
+const MAX_ALTERNATIVES: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Unit {
+    Char(char),
+    Escaped(char),
+    Star,
+    Question,
+    Class(String),
+    Sep,
+}
+
+impl Unit {
+    fn is_literal(&self) -> bool {
+        matches!(self, Unit::Char(_) | Unit::Escaped(_))
+    }
+}
+
+// A unit of a pattern, and its 1-based column for errors.
+#[derive(Clone, Debug)]
+struct Token {
+    unit: Unit,
+    col: usize,
+}
+
+enum Node {
+    Token(Token),
+    Group(Vec<Vec<Node>>),
+}
+
+struct Parser<'a> {
+    pattern: &'a str,
+    chars: Vec<char>,
+    at: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, col: usize, reason: impl Display) -> anyhow::Error {
+        XcpError::InvalidArguments(format!("Invalid pattern {:?} at column {}: {}", self.pattern, col, reason)).into()
+    }
+
+    // The nodes up to the end of the pattern, or to the ',' or '}'
+    // ending an alternative of a group.
+    fn sequence(&mut self, in_group: bool) -> Result<Vec<Node>> {
+        let mut nodes = Vec::new();
+        while let Some(&c) = self.chars.get(self.at) {
+            let col = self.at + 1;
+            let unit = match c {
+                ',' | '}' if in_group => break,
+                '}' => return Err(self.error(col, "unmatched '}'; escape it as '\\}'")),
+                '{' => {
+                    self.at += 1;
+                    nodes.push(Node::Group(self.group(col)?));
+                    continue;
+                }
+                '\\' => match self.chars.get(self.at + 1) {
+                    Some(&escaped) => {
+                        self.at += 1;
+                        Unit::Escaped(escaped)
+                    }
+                    None => return Err(self.error(col, "dangling '\\'")),
+                },
+                '*' => Unit::Star,
+                '?' => Unit::Question,
+                '/' => Unit::Sep,
+                '[' => Unit::Class(self.class(col)?),
+                c => Unit::Char(c),
+            };
+            self.at += 1;
+            nodes.push(Node::Token(Token { unit, col }));
+        }
+        Ok(nodes)
+    }
+
+    // The alternatives of the group opened at column `open`.
+    fn group(&mut self, open: usize) -> Result<Vec<Vec<Node>>> {
+        let mut alternatives = vec![self.sequence(true)?];
+        loop {
+            match self.chars.get(self.at) {
+                Some(',') => {
+                    self.at += 1;
+                    alternatives.push(self.sequence(true)?);
+                }
+                Some('}') => {
+                    self.at += 1;
+                    return Ok(alternatives);
+                }
+                _ => return Err(self.error(open, "unclosed '{'; escape it as '\\{'")),
+            }
+        }
+    }
+
+    // The class opened at column `open`, leaving the parser on its
+    // closing ']'.
+    fn class(&mut self, open: usize) -> Result<String> {
+        let start = self.at;
+        let mut i = start + 1;
+        if matches!(self.chars.get(i), Some('!' | '^')) {
+            i += 1;
+        }
+        if self.chars.get(i) == Some(&']') {
+            i += 1;
+        }
+        while let Some(&c) = self.chars.get(i) {
+            if c == ']' {
+                self.at = i;
+                return Ok(self.chars[start..=i].iter().collect());
+            }
+            match (self.chars.get(i + 1), self.chars.get(i + 2)) {
+                (Some('-'), Some(&end)) if end != ']' => {
+                    if c > end {
+                        return Err(self.error(i + 1, format!("invalid range '{}-{}'", c, end)));
+                    }
+                    i += 3;
+                }
+                _ => i += 1,
+            }
+        }
+        Err(self.error(open, "unclosed '['; escape it as '\\['"))
+    }
+
+    // Fails if `tokens` use '**' other than as a whole component.
+    fn check_recursive(&self, tokens: &[Token]) -> Result<()> {
+        let is_sep = |i: usize| tokens.get(i).map_or(true, |t| t.unit == Unit::Sep);
+        let mut i = 0;
+        while i < tokens.len() {
+            let start = i;
+            while tokens.get(i).is_some_and(|t| t.unit == Unit::Star) {
+                i += 1;
+            }
+            let run = i - start;
+            if run > 1 && !(run == 2 && (start == 0 || is_sep(start - 1)) && is_sep(i)) {
+                return Err(self.error(tokens[start].col, "'**' can only be a whole path component, as in 'a/**/b'"));
+            }
+            i = i.max(start + 1);
+        }
+        Ok(())
+    }
+}
+
+// The number of alternatives `nodes` expand to, up to a limit.
+fn count(nodes: &[Node]) -> usize {
+    nodes.iter()
+        .map(|node| match node {
+            Node::Token(_) => 1,
+            Node::Group(group) => group.iter()
+                .map(|seq| count(seq))
+                .fold(0, usize::saturating_add),
+        })
+        .fold(1, usize::saturating_mul)
+}
+
+fn expand_braces(nodes: &[Node]) -> Vec<Vec<Token>> {
+    let mut expanded = vec![Vec::new()];
+    for node in nodes {
+        match node {
+            Node::Token(token) => expanded.iter_mut().for_each(|tokens| tokens.push(token.clone())),
+            Node::Group(group) => {
+                let options = group.iter()
+                    .flat_map(|seq| expand_braces(seq))
+                    .collect::<Vec<_>>();
+                expanded = expanded.iter()
+                    .flat_map(|tokens| options.iter().map(move |option| [tokens.as_slice(), option].concat()))
+                    .collect();
+            }
+        }
+    }
+    expanded
+}
+
+/// One alternative of a pattern, with its braces expanded.
+#[derive(Clone, Debug)]
+pub struct Alternative {
+    pattern: String,
+    tokens: Vec<Token>,
+}
+
+impl Alternative {
+    /// The alternative in the syntax of [globset].
+    pub fn glob(&self) -> String {
+        self.tokens.iter()
+            .map(|t| match &t.unit {
+                Unit::Char(c) => c.to_string(),
+                Unit::Escaped(c) => format!("\\{}", c),
+                Unit::Star => "*".to_string(),
+                Unit::Question => "?".to_string(),
+                Unit::Class(class) => class.clone(),
+                Unit::Sep => "/".to_string(),
+            })
+            .collect()
+    }
+
+    // The leading path components without wildcards, unescaped, and
+    // the number of components after them.
+    fn literal_prefix(&self) -> (PathBuf, usize) {
+        let components = self.tokens.split(|t| t.unit == Unit::Sep).collect::<Vec<_>>();
+        let literal = components.iter()
+            .take_while(|c| c.iter().all(|t| t.unit.is_literal()))
+            .count();
+        let mut prefix = components[..literal].iter()
+            .map(|c| c.iter()
+                .map(|t| match t.unit {
+                    Unit::Char(c) | Unit::Escaped(c) => c,
+                    _ => unreachable!(),
+                })
+                .collect::<String>())
+            .collect::<Vec<_>>()
+            .join("/");
+        if prefix.is_empty() && self.tokens.first().is_some_and(|t| t.unit == Unit::Sep) {
+            prefix.push('/');
+        }
+        (PathBuf::from(prefix), components.len() - literal)
+    }
+
+    fn recursive(&self) -> bool {
+        self.tokens.windows(2).any(|w| w[0].unit == Unit::Star && w[1].unit == Unit::Star)
+    }
+
+    /// Build `glob`, the alternative's [Alternative::glob] or one
+    /// derived from it, with the options of the pattern language.
+    pub fn build(&self, glob: &str) -> Result<Glob> {
+        let glob = GlobBuilder::new(glob)
+            .literal_separator(true)
+            .backslash_escape(true)
+            .build()
+            .map_err(|e| XcpError::InvalidArguments(format!("Invalid pattern {:?}: {}", self.pattern, e.kind())))?;
+        Ok(glob)
+    }
+
+    // The existing paths matching the alternative, in any order.
+    fn matches(&self) -> Result<Vec<PathBuf>> {
+        let (prefix, wild) = self.literal_prefix();
+        if wild == 0 {
+            return Ok(prefix.symlink_metadata().is_ok().then_some(prefix).into_iter().collect());
+        }
+        let matcher = self.build(&self.glob())?.compile_matcher();
+        let relative = prefix.as_os_str().is_empty();
+        let root = if relative { PathBuf::from(".") } else { prefix };
+        // As with shells, symlinks aren't followed by '**'.
+        let recursive = self.recursive();
+        let walk = WalkDir::new(&root)
+            .min_depth(1)
+            .max_depth(if recursive { usize::MAX } else { wild })
+            .follow_links(!recursive);
+        let mut paths = Vec::new();
+        for entry in walk {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) if e.depth() == 0 && e.io_error().is_some_and(|e| e.kind() == io::ErrorKind::NotFound) => break,
+                Err(e) => return Err(e.into()),
+            };
+            let path = match relative {
+                true => entry.path().strip_prefix(&root).unwrap_or(entry.path()),
+                false => entry.path(),
+            };
+            if matcher.is_match(path) {
+                paths.push(path.to_path_buf());
+            }
+        }
+        Ok(paths)
+    }
+}
+
+/// Parse `pattern` into its alternatives.
+pub fn parse(pattern: &str) -> Result<Vec<Alternative>> {
+    let mut parser = Parser { pattern, chars: pattern.chars().collect(), at: 0 };
+    let nodes = parser.sequence(false)?;
+    if count(&nodes) > MAX_ALTERNATIVES {
+        return Err(XcpError::InvalidArguments(
+            format!("Invalid pattern {:?}: its braces expand to more than {} alternatives", pattern, MAX_ALTERNATIVES)).into());
+    }
+    expand_braces(&nodes).into_iter()
+        .map(|tokens| {
+            parser.check_recursive(&tokens)?;
+            Ok(Alternative { pattern: pattern.to_string(), tokens })
+        })
+        .collect()
+}
+
+/// Expand `pattern` to the existing paths matching it, sorted and
+/// without duplicates. A pattern without wildcards is its own path if
+/// it exists.
+pub fn expand(pattern: &str) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for alternative in parse(pattern)? {
+        paths.extend(alternative.matches()?);
+    }
+    paths.sort();
+    paths.dedup();
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    // (pattern, path, matches)
+    const CASES: &[(&str, &str, bool)] = &[
+        // Wildcards don't cross directories, and match dotfiles.
+        ("*.rs", "main.rs", true),
+        ("*.rs", ".hidden.rs", true),
+        ("*.rs", "src/main.rs", false),
+        ("*", "", true),
+        ("?.rs", "a.rs", true),
+        ("?.rs", "ab.rs", false),
+        ("?", "/", false),
+        ("src/*", "src/a", true),
+        ("src/*", "src/a/b", false),
+        // '**' matches any number of components.
+        ("**", "a/b/c", true),
+        ("**/*.rs", "main.rs", true),
+        ("**/*.rs", "a/b/main.rs", true),
+        ("a/**", "a/b/c", true),
+        ("a/**", "a", false),
+        ("a/**/b", "a/b", true),
+        ("a/**/b", "a/x/y/b", true),
+        ("a/**/b", "a/x/y/c", false),
+        ("/**/x", "/x", true),
+        // Classes.
+        ("[ab].txt", "b.txt", true),
+        ("[ab].txt", "c.txt", false),
+        ("[a-c]", "b", true),
+        ("[a-c]", "d", false),
+        ("[!a-c]", "d", true),
+        ("[^a-c]", "b", false),
+        ("[]]", "]", true),
+        ("[!]]", "a", true),
+        ("[*?]", "?", true),
+        ("[*?]", "a", false),
+        ("[{}]", "{", true),
+        ("[a-]", "-", true),
+        ("[ab]/x", "a/x", true),
+        // Braces, nested and across directories.
+        ("{a,b}.txt", "a.txt", true),
+        ("{a,b}.txt", "b.txt", true),
+        ("{a,b}.txt", "c.txt", false),
+        ("*.{rs,toml}", "Cargo.toml", true),
+        ("{a,{b,c}}", "c", true),
+        ("{a,{b,c}}", "d", false),
+        ("{src,lib/*}/x", "lib/y/x", true),
+        ("{src,lib/*}/x", "src/x", true),
+        ("{src,lib/*}/x", "src/y/x", false),
+        ("x{,.bak}", "x", true),
+        ("x{,.bak}", "x.bak", true),
+        ("{}", "", true),
+        ("{[,],c}", ",", true),
+        ("{a,**}/b", "x/y/b", true),
+        // Other characters are literal.
+        ("a,b", "a,b", true),
+        ("a]", "a]", true),
+        ("my file", "my file", true),
+        ("*.TXT", "a.txt", false),
+        // Escapes.
+        ("\\*.txt", "*.txt", true),
+        ("\\*.txt", "a.txt", false),
+        ("\\{a,b\\}", "{a,b}", true),
+        ("\\{a,b\\}", "a", false),
+        ("a\\?", "a?", true),
+        ("a\\?", "ab", false),
+        ("\\[a]", "[a]", true),
+        ("\\\\", "\\", true),
+    ];
+
+    fn is_match(pattern: &str, path: &str) -> Result<bool> {
+        for alternative in parse(pattern)? {
+            if alternative.build(&alternative.glob())?.compile_matcher().is_match(path) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    #[test]
+    fn test_pattern_table() -> Result<()> {
+        for (pattern, path, matches) in CASES {
+            assert_eq!(*matches, is_match(pattern, path)?, "{:?} against {:?}", pattern, path);
+        }
+        Ok(())
+    }
+
+    // (pattern, error)
+    const ERRORS: &[(&str, &str)] = &[
+        ("a[bc", "at column 2: unclosed '['"),
+        ("[]", "at column 1: unclosed '['"),
+        ("[z-a]", "at column 2: invalid range 'z-a'"),
+        ("x[a-cz-a]", "at column 6: invalid range 'z-a'"),
+        ("src/{a,b", "at column 5: unclosed '{'"),
+        ("{a,{b,c}", "at column 1: unclosed '{'"),
+        ("a}", "at column 2: unmatched '}'"),
+        ("{a}}", "at column 4: unmatched '}'"),
+        ("ab\\", "at column 3: dangling '\\'"),
+        ("a**", "at column 2: '**' can only be a whole path component"),
+        ("a/***/b", "at column 3: '**' can only be a whole path component"),
+        ("**a", "at column 1: '**' can only be a whole path component"),
+        ("x{a,**}", "at column 5: '**' can only be a whole path component"),
+        ("{a,b}{c,d}{e,f}{g,h}{i,j}{k,l}{m,n}{o,p}{q,r}{s,t}{u,v}", "braces expand to more than 1024 alternatives"),
+    ];
+
+    #[test]
+    fn test_errors() {
+        for (pattern, expected) in ERRORS {
+            let err = parse(pattern).unwrap_err();
+            assert!(matches!(err.downcast_ref::<XcpError>(), Some(XcpError::InvalidArguments(_))), "{:?}", pattern);
+            let msg = err.to_string();
+            assert!(msg.contains(&format!("{:?}", pattern)), "{}", msg);
+            assert!(msg.contains(expected), "{:?}: {}", pattern, msg);
+        }
+    }
+
+    #[test]
+    fn test_expand() -> Result<()> {
+        let dir = TempDir::new()?;
+        let root = dir.path();
+        for file in ["a.txt", "b.txt", "c.rs", ".d.txt", "sub/e.txt", "sub/deep/f.txt", "other/g.txt", "x[1].txt"] {
+            create_dir_all(root.join(file).parent().unwrap())?;
+            write(root.join(file), file)?;
+        }
+        symlink(root.join("sub"), root.join("link"))?;
+        let expand_in = |pattern: &str| -> Result<Vec<String>> {
+            let paths = expand(&format!("{}/{}", root.display(), pattern))?;
+            Ok(paths.iter()
+                .map(|p| p.strip_prefix(root).unwrap().to_string_lossy().into_owned())
+                .collect())
+        };
+
+        assert_eq!(vec![".d.txt", "a.txt", "b.txt", "x[1].txt"], expand_in("*.txt")?);
+        assert_eq!(vec!["a.txt", "c.rs"], expand_in("{c.rs,a.*}")?);
+        assert_eq!(vec!["a.txt"], expand_in("{a,a}.txt")?);
+        assert_eq!(vec!["link/e.txt", "other/g.txt", "sub/e.txt"], expand_in("*/*.txt")?);
+        assert_eq!(vec!["sub/deep/f.txt", "sub/e.txt"], expand_in("sub/**/*.txt")?);
+        // Symlinks aren't followed by '**'.
+        assert_eq!(vec![".d.txt", "a.txt", "b.txt", "other/g.txt", "sub/deep/f.txt", "sub/e.txt", "x[1].txt"],
+                   expand_in("**/*.txt")?);
+        assert_eq!(vec!["x[1].txt"], expand_in("x\\[1\\].txt")?);
+        assert_eq!(vec!["x[1].txt"], expand_in("x[[]1].txt")?);
+        assert_eq!(vec!["sub/deep"], expand_in("s?b/d*")?);
+        assert!(expand_in("missing/*.txt")?.is_empty());
+        assert!(expand_in("missing.txt")?.is_empty());
+        assert_eq!(vec!["a.txt"], expand_in("a.txt")?);
+        assert!(expand_in("a.txt/*")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_literal_prefix() -> Result<()> {
+        let prefix = |pattern: &str| -> Result<(PathBuf, usize)> {
+            Ok(parse(pattern)?.remove(0).literal_prefix())
+        };
+        assert_eq!((PathBuf::from(""), 1), prefix("*.rs")?);
+        assert_eq!((PathBuf::from("/"), 1), prefix("/*")?);
+        assert_eq!((PathBuf::from("/a/b"), 2), prefix("/a/b/*/c")?);
+        assert_eq!((PathBuf::from("./a*b"), 1), prefix("./a\\*b/*")?);
+        assert_eq!((PathBuf::from("a/b"), 0), prefix("a/b")?);
+        Ok(())
+    }
+}
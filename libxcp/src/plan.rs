@@ -0,0 +1,916 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! What a copy would do, without doing it.
+//!
+//! [copy_plan] walks the sources, maps each entry to its destination
+//! and applies the filter, conflict and dereference options of the
+//! [Config], yielding a [PlanEntry] for each action the copy would
+//! take. Entries are produced as the walk proceeds, so a large tree
+//! is never held in memory, and the plan is [Send] so it can be
+//! consumed on another thread. The copy itself, including
+//! `--dry-run` and `--itemize`, is driven by the same plan.
+//!
+//!     # use libxcp::errors::Result;
+//!     # use std::path::PathBuf;
+//!     use libxcp::config::Config;
+//!     use libxcp::plan::{copy_plan, PlanEntry};
+//!     # fn main() -> Result<()> {
+//!     # let dest = tempfile::TempDir::new()?;
+//!
+//!     let sources = vec![PathBuf::from("src")];
+//!     for entry in copy_plan(sources, dest.path(), &Config::default()) {
+//!         if let PlanEntry::CopyFile { src, dest, size } = entry? {
+//!             println!("{:?} -> {:?} ({} bytes)", src, dest, size);
+//!         }
+//!     }
+//!     # Ok(())
+//!     # }
+
+use std::collections::hash_map::{Entry, HashMap};
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fs::{canonicalize, File, Metadata};
+use std::os::unix::fs::MetadataExt;
+use std::io::ErrorKind;
+use std::iter;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use libfs::{device_size, is_not_dir, is_same_dir_tree_entry, open_dir, FileType};
+use log::{debug, error, warn};
+use walkdir::WalkDir;
+
+use crate::checksum::{checksum_file, ChecksumType};
+use crate::config::{Config, NoClobber, Order, PreserveSet, SkipSame};
+use crate::conflict::create_renamed;
+use crate::deref::{DerefTracker, Duplicate};
+use crate::errors::{Result, XcpError};
+use crate::feedback::{Action, NoopUpdater, StatusUpdate, StatusUpdater};
+use crate::lock::is_lock_file;
+use crate::names::{NameMapper, NameProfile};
+use crate::operations::{send_action, NO_CLOBBER_MSG};
+use crate::paths::{dest_names, ignore_filter, parse_ignore, pattern_filter};
+use crate::stamp::Stamp;
+use crate::timestamps::{format_time, is_newer, Granularities};
+
+/// Why an entry isn't copied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The destination is at least as new as the source, with
+    /// [Config::update].
+    UpToDate,
+    /// The destination exists, with [NoClobber::Skip].
+    Exists,
+    /// The destination is newer than the source, with
+    /// [Config::forbid_overwrite_newer] and
+    /// [Config::continue_on_error].
+    DestinationNewer,
+    /// The name can't be used on the destination; see
+    /// [Config::invalid_name].
+    InvalidName,
+    /// The destination has the same contents, with
+    /// [Config::skip_same].
+    Same,
+}
+
+/// An action the copy would take.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PlanEntry {
+    /// Create the directory `dest`, or apply the metadata of `src` to
+    /// it if it exists; see [Config::dir_mode].
+    CreateDir { src: PathBuf, dest: PathBuf },
+    /// Copy the file or block device `src`. With [Config::no_clobber]
+    /// an existing file is only found when `dest` is created, and then
+    /// skipped or, with [NoClobber::Rename], copied under a new name.
+    CopyFile { src: PathBuf, dest: PathBuf, size: u64 },
+    /// Copy the symlink `src`.
+    CreateSymlink { src: PathBuf, dest: PathBuf },
+    /// Create a socket, fifo or character device like `src`.
+    CreateSpecial { src: PathBuf, dest: PathBuf },
+    /// Hard-link `dest` to `existing`, the copy of another link to
+    /// the same file as `src`, with [PreserveSet::LINKS].
+    HardLink { src: PathBuf, existing: PathBuf, dest: PathBuf },
+    /// Delete `dest`, which has no counterpart in the source, with
+    /// [Config::delete]. Directories are deleted with their contents.
+    Delete { dest: PathBuf },
+    /// Leave `dest` alone.
+    Skip { src: PathBuf, dest: PathBuf, reason: SkipReason },
+}
+
+impl PlanEntry {
+    /// The destination path the entry acts on.
+    pub fn dest(&self) -> &Path {
+        match self {
+            PlanEntry::CreateDir { dest, .. }
+            | PlanEntry::CopyFile { dest, .. }
+            | PlanEntry::CreateSymlink { dest, .. }
+            | PlanEntry::CreateSpecial { dest, .. }
+            | PlanEntry::HardLink { dest, .. }
+            | PlanEntry::Delete { dest }
+            | PlanEntry::Skip { dest, .. } => dest,
+        }
+    }
+}
+
+/// A [PlanEntry] with the state the copy needs to carry it out.
+pub(crate) struct Step {
+    /// The index of the source the entry was found in.
+    pub source: usize,
+    /// The depth of the entry in its tree; see [walkdir::DirEntry::depth].
+    pub depth: usize,
+    /// The metadata of the source entry, or of the destination for
+    /// [PlanEntry::Delete].
+    pub meta: Metadata,
+    /// For a further link to an already copied file, the earlier
+    /// destination.
+    pub linked: Option<PathBuf>,
+    pub entry: PlanEntry,
+}
+
+// Nearly all events are steps, so boxing them gains nothing.
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum Event {
+    Step(Step),
+    /// All entries of the source with this index have been planned.
+    Finished(usize),
+}
+
+/// The plan for a copy; see [copy_plan].
+pub struct Plan {
+    config: Arc<Config>,
+    stats: Arc<dyn StatusUpdater>,
+    dest: PathBuf,
+    /// The sources and their destination names, until the walk starts.
+    sources: Vec<(PathBuf, Option<OsString>)>,
+    started: bool,
+    names: NameMapper,
+    walks: Vec<SourceWalk>,
+    next: usize,
+    /// Destinations of multiply-linked files, by source (dev, inode).
+    inodes: HashMap<(u64, u64), PathBuf>,
+    granularities: Granularities,
+    /// Events ready to be returned.
+    queued: VecDeque<Event>,
+    /// An error to be returned, after which the plan is complete.
+    error: Option<anyhow::Error>,
+    done: bool,
+}
+
+/// The plan for copying `sources` to `dest`; see the
+/// [module](self) docs. Setting up the copy may fail, e.g. if two
+/// sources have the same destination, in which case the error is the
+/// only entry. The plan ends after any error.
+pub fn copy_plan(sources: Vec<PathBuf>, dest: &Path, config: &Config) -> Plan {
+    let config = Arc::new(config.clone());
+    let stats: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+    Plan::new(sources, dest, &config, &stats)
+        .unwrap_or_else(|e| Plan::failed(e, dest, &config, &stats))
+}
+
+impl Plan {
+    /// A plan reporting the same updates as a copy to `stats`.
+    pub(crate) fn new(
+        sources: Vec<PathBuf>,
+        dest: &Path,
+        config: &Arc<Config>,
+        stats: &Arc<dyn StatusUpdater>,
+    ) -> Result<Plan> {
+        let targets = dest_names(&sources, dest, config)?;
+        let mut plan = Plan::empty(dest, config, stats);
+        plan.sources = sources.into_iter().zip(targets).collect();
+        Ok(plan)
+    }
+
+    fn failed(err: anyhow::Error, dest: &Path, config: &Arc<Config>, stats: &Arc<dyn StatusUpdater>) -> Plan {
+        Plan { error: Some(err), ..Plan::empty(dest, config, stats) }
+    }
+
+    fn empty(dest: &Path, config: &Arc<Config>, stats: &Arc<dyn StatusUpdater>) -> Plan {
+        Plan {
+            config: config.clone(),
+            stats: stats.clone(),
+            dest: dest.to_path_buf(),
+            sources: Vec::new(),
+            started: false,
+            names: NameMapper::new(NameProfile::default(), config),
+            walks: Vec::new(),
+            next: 0,
+            inodes: HashMap::new(),
+            granularities: Granularities::default(),
+            queued: VecDeque::new(),
+            error: None,
+            done: false,
+        }
+    }
+
+    /// The next entry, along with the state the copy needs.
+    pub(crate) fn next_event(&mut self) -> Option<Result<Event>> {
+        loop {
+            if let Some(event) = self.queued.pop_front() {
+                return Some(Ok(event));
+            }
+            if let Some(err) = self.error.take() {
+                self.done = true;
+                return Some(Err(err));
+            }
+            if self.done {
+                return None;
+            }
+            if let Err(err) = self.advance() {
+                self.error = Some(err);
+            }
+        }
+    }
+
+    // Resolve the destination of each source. This waits until the
+    // walk starts, as the copy may create the destination first.
+    fn start(&mut self) -> Result<()> {
+        self.started = true;
+        self.names = NameMapper::new(dest_profile(&self.dest, &self.config), &self.config);
+        for (source, name) in mem::take(&mut self.sources) {
+            let id = self.walks.len();
+            let target_base = match name {
+                Some(name) => match self.names.target(&source, &self.dest, &name, &self.stats)? {
+                    Some(t) => t,
+                    None => continue,
+                }
+                None => self.dest.clone(),
+            };
+            debug!("Target base is {:?}", target_base);
+            self.walks.push(SourceWalk::new(id, source, target_base, &self.dest, &self.config)?);
+        }
+        Ok(())
+    }
+
+    // Plan the next entry of one of the sources, queueing any events.
+    fn advance(&mut self) -> Result<()> {
+        if !self.started {
+            return self.start();
+        }
+        if self.walks.is_empty() {
+            self.done = true;
+            return Ok(());
+        }
+        let config = self.config.clone();
+        let stats = self.stats.clone();
+
+        // The sources take turns, an entry at a time; see
+        // [SourceWalk]. Files that are reordered are held until the
+        // end of the walk anyway, so then each source is walked in
+        // full before the next.
+        let i = self.next % self.walks.len();
+        self.next = if config.order == Order::Scan { i + 1 } else { i };
+        let walk = &mut self.walks[i];
+
+        if let Some(deleting) = walk.deleting.as_mut() {
+            match next_extraneous(deleting, &walk.source, &walk.target_base, &walk.skipped, &self.names, &config, &stats)? {
+                Some((dest, meta)) => {
+                    self.queued.push_back(walk.step(0, meta, PlanEntry::Delete { dest }));
+                    return Ok(());
+                }
+                None => walk.deleting = None,
+            }
+        }
+        let Some(entry) = walk.entries.next() else {
+            if config.delete && !walk.deleted && walk.source.is_dir() && walk.target_base.is_dir() {
+                walk.deleted = true;
+                walk.deleting = Some(WalkDir::new(&walk.target_base).min_depth(1).into_iter());
+                return Ok(());
+            }
+            let mut walk = self.walks.remove(i);
+            self.next = i;
+            if let Some(dup) = walk.deref.leave(0) {
+                send_duplicate(dup, &stats)?;
+            }
+            self.queued.push_back(Event::Finished(walk.id));
+            return Ok(());
+        };
+        debug!("Got tree entry {:?}", entry);
+        let entry = match entry {
+            Ok(e) => e,
+            // The link is neither copied nor descended into.
+            Err(err) if err.loop_ancestor().is_some() => {
+                let ancestor = err.loop_ancestor().unwrap_or(&walk.source);
+                let link = err.path().unwrap_or(&walk.source);
+                let chain = walk.deref.loop_chain(ancestor, link);
+                warn!("Not following symlink {:?}, which leads back to {:?}", link, ancestor);
+                stats.send(StatusUpdate::Error(XcpError::SymlinkLoop(chain)))?;
+                if !config.continue_on_error {
+                    return Err(XcpError::EarlyShutdown("symlink loop found").into());
+                }
+                return Ok(());
+            }
+            // The source itself may have gone, or been replaced, since
+            // it was checked.
+            Err(err) if err.depth() == 0 && err.io_error().is_some_and(|e| source_changed(&walk.source, e, false).is_some()) => {
+                let err = err.io_error()
+                    .and_then(|e| source_changed(&walk.source, e, false))
+                    .unwrap_or_else(|| XcpError::SourceNotFound(walk.source.clone()));
+                if !config.continue_on_error {
+                    return Err(err.into());
+                }
+                warn!("Skipping source {:?}: {}", walk.source, err);
+                stats.send(StatusUpdate::Error(err))?;
+                return Ok(());
+            }
+            Err(err) if config.continue_on_error => {
+                // Unreadable directories are reported after their
+                // entry has been yielded, so the target directory
+                // already exists; just skip the contents.
+                let path = err.path()
+                    .map(Path::to_path_buf)
+                    .unwrap_or_else(|| walk.source.clone());
+                warn!("Skipping unreadable directory {:?}: {}", path, err);
+                walk.skipped.push(path.clone());
+                stats.send(StatusUpdate::Error(
+                    XcpError::UnreadableDirectory { path, source: err.into() }))?;
+                return Ok(());
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let depth = entry.depth();
+        // Pending directories left without entries to copy are pruned.
+        walk.pending_dirs.retain(|step| step.depth < depth);
+        if let Some(dup) = walk.deref.leave(depth) {
+            send_duplicate(dup, &stats)?;
+        }
+        // The target base has been created by now, unless this is a
+        // dry run.
+        if mem::take(&mut walk.check_base) {
+            if let Ok(meta) = walk.target_base.metadata() {
+                walk.dest_dirs.push(meta);
+            }
+        }
+
+        let epath = entry.into_path();
+        let follow_root = config.dereference || config.dereference_sources;
+        let from = if config.dereference || (depth == 0 && follow_root) {
+            let cpath = canonicalize(&epath)?;
+            debug!("Dereferencing {:?} into {:?}", epath, cpath);
+            cpath
+        } else {
+            epath.clone()
+        };
+        // Every entry is classified by its metadata rather than the
+        // type readdir reported, which some filesystems don't record;
+        // the same stat gives the link count and inode for hard links.
+        // The entry, or a directory above it, may have gone or been
+        // replaced since it was read. Nothing more of a top-level
+        // source that has gone is copied.
+        let mut meta = match config.fs.stat(&from) {
+            Ok(meta) => meta,
+            Err(e) => match source_changed(&from, &e, depth > 0) {
+                Some(err) if config.continue_on_error => {
+                    warn!("Skipping source {:?}: {}", from, err);
+                    stats.send(StatusUpdate::Error(err))?;
+                    walk.skipped.push(epath);
+                    if depth == 0 {
+                        walk.entries = Box::new(iter::empty());
+                    }
+                    return Ok(());
+                }
+                Some(err) => return Err(err.into()),
+                None => return Err(e.into()),
+            },
+        };
+        if depth == 0 && meta.is_dir() {
+            meta = open_source_dir(&from)?;
+        }
+        if walk.dest_dirs.iter().any(|d| is_same_dir_tree_entry(&meta, d)) {
+            warn!("Source directory {:?} is the destination {:?}; aborting", from, self.dest);
+            return Err(XcpError::OverlappingDestination(from, self.dest.clone()).into());
+        }
+        let path = epath.strip_prefix(&walk.source)?;
+        let mut target = if depth == 0 {
+            walk.target_base.clone()
+        } else if !self.names.is_restricted() {
+            walk.target_base.join(path)
+        } else {
+            // Entries under a skipped directory have no parent
+            // target, and are skipped too.
+            let parent = epath.parent().and_then(|p| walk.dir_targets.get(p));
+            let (Some(parent), Some(name)) = (parent, epath.file_name()) else {
+                return Ok(());
+            };
+            match self.names.target(&from, parent, name, &stats)? {
+                Some(t) => t,
+                None => {
+                    let dest = parent.join(name);
+                    let skip = PlanEntry::Skip { src: from, dest, reason: SkipReason::InvalidName };
+                    self.queued.push_back(walk.step(depth, meta, skip));
+                    return Ok(());
+                }
+            }
+        };
+        if self.names.is_restricted() && meta.is_dir() {
+            walk.dir_targets.insert(epath.clone(), target.clone());
+        }
+        if config.dereference && meta.is_dir() {
+            if let Some(original) = walk.deref.enter(depth, &epath, &meta) {
+                warn!("Directory {:?} was already reached as {:?}; copying it again", epath, original);
+            }
+        }
+
+        // Files are created exclusively when opened; see
+        // [create_dest].
+        //
+        // [create_dest]: crate::operations::create_dest
+        match config.no_clobber {
+            Some(mode) if !meta.is_file() && target.exists() => match mode {
+                NoClobber::Fail => {
+                    stats.send(StatusUpdate::Error(
+                        XcpError::DestinationExists { path: target, reason: NO_CLOBBER_MSG }))?;
+                    return Err(XcpError::EarlyShutdown(NO_CLOBBER_MSG).into());
+                }
+                NoClobber::Skip | NoClobber::Rename if meta.is_dir() => {}
+                NoClobber::Skip => {
+                    debug!("Skipping existing destination {:?}", target);
+                    stats.send(StatusUpdate::Skipped { path: target.clone(), bytes: 0, source: Some(from.clone()) })?;
+                    let skip = PlanEntry::Skip { src: from, dest: target, reason: SkipReason::Exists };
+                    self.queued.push_back(walk.step(depth, meta, skip));
+                    return Ok(());
+                }
+                NoClobber::Rename => {
+                    // Symlinks and special files fail if their
+                    // destination exists, so aren't overwritten if the
+                    // new name is taken before they are created.
+                    let ((), renamed) = create_renamed(&target, &config.conflict_suffix, |p| match p.symlink_metadata() {
+                        Ok(_) => Err(ErrorKind::AlreadyExists.into()),
+                        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+                        Err(e) => Err(e),
+                    })?;
+                    stats.send(StatusUpdate::KeptBoth { from: target, to: renamed.clone() })?;
+                    target = renamed;
+                }
+            },
+            _ => {}
+        }
+
+        let ft = FileType::from(meta.file_type());
+        // A renamed file's name isn't known until it is created, so
+        // with [NoClobber::Rename] each link is copied separately.
+        let linked = if matches!(ft, FileType::File) && meta.nlink() > 1 && config.no_clobber != Some(NoClobber::Rename) {
+            match self.inodes.entry((meta.dev(), meta.ino())) {
+                Entry::Occupied(e) => Some(e.get().clone()),
+                Entry::Vacant(e) => {
+                    e.insert(target.clone());
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        if matches!(ft, FileType::File) && config.update && !needs_update(&meta, &target, &mut self.granularities)? {
+            debug!("Destination {:?} is up to date, skipping", target);
+            if !config.dry_run {
+                send_action(&stats, &config, Action::UpToDate(target.clone()))?;
+            }
+            let skip = PlanEntry::Skip { src: from, dest: target, reason: SkipReason::UpToDate };
+            self.queued.push_back(walk.step(depth, meta, skip));
+            return Ok(());
+        }
+        if matches!(ft, FileType::File) && same_contents(&meta, &from, &target, config.skip_same) {
+            debug!("Destination {:?} has the same contents, skipping", target);
+            if !config.dry_run {
+                send_action(&stats, &config, Action::UpToDate(target.clone()))?;
+            }
+            let skip = PlanEntry::Skip { src: from, dest: target, reason: SkipReason::Same };
+            self.queued.push_back(walk.step(depth, meta, skip));
+            return Ok(());
+        }
+        // With --update or --no-clobber these are skipped anyway.
+        if matches!(ft, FileType::File) && !config.update && config.no_clobber.is_none() {
+            if let Some(dest_mtime) = newer_target(&meta, &target, &mut self.granularities)? {
+                if config.forbid_overwrite_newer {
+                    stats.send(StatusUpdate::Error(XcpError::DestinationNewer(target.clone())))?;
+                    if !config.continue_on_error {
+                        return Err(XcpError::EarlyShutdown("destination is newer than the source").into());
+                    }
+                    let skip = PlanEntry::Skip { src: from, dest: target, reason: SkipReason::DestinationNewer };
+                    self.queued.push_back(walk.step(depth, meta, skip));
+                    return Ok(());
+                }
+                let source_mtime = meta.modified()?;
+                warn!("Overwriting {:?}, which is newer than the source {:?} ({} > {})",
+                      target, from, format_time(dest_mtime), format_time(source_mtime));
+                stats.send(StatusUpdate::OverwritingNewer { path: target.clone(), source_mtime, dest_mtime })?;
+            }
+        }
+
+        if !matches!(ft, FileType::Dir) {
+            // The entry is to be copied, so its parents are needed.
+            let pending = mem::take(&mut walk.pending_dirs);
+            self.queued.extend(pending.into_iter().map(Event::Step));
+        }
+
+        let entry = match ft {
+            FileType::File => match &linked {
+                Some(existing) if config.preserve.contains(PreserveSet::LINKS) => {
+                    PlanEntry::HardLink { src: from, existing: existing.clone(), dest: target }
+                }
+                _ => {
+                    if linked.is_none() || !config.cache_linked_sources {
+                        walk.deref.file(meta.len());
+                    }
+                    PlanEntry::CopyFile { src: from, dest: target, size: meta.len() }
+                }
+            },
+            FileType::Symlink => PlanEntry::CreateSymlink { src: from, dest: target },
+            FileType::Dir if config.prune_empty_dirs && depth > 0 && !target.is_dir() => {
+                debug!("Deferring creation of target directory {:?}", target);
+                let dir = PlanEntry::CreateDir { src: from, dest: target };
+                walk.pending_dirs.push(Step { source: walk.id, depth, meta, linked: None, entry: dir });
+                return Ok(());
+            }
+            FileType::Dir => {
+                walk.check_base = depth == 0;
+                PlanEntry::CreateDir { src: from, dest: target }
+            }
+            FileType::Socket | FileType::Char | FileType::Fifo => PlanEntry::CreateSpecial { src: from, dest: target },
+            // A block device given as a source is imaged; ones found
+            // within a tree are not.
+            FileType::Block if depth == 0 => {
+                let size = device_size(&File::open(&from)?)?;
+                PlanEntry::CopyFile { src: from, dest: target, size }
+            }
+            FileType::Block | FileType::Other => {
+                error!("Unsupported filetype found: {:?} -> {:?}", target, ft);
+                return Err(XcpError::UnknownFileType(target).into());
+            }
+        };
+        self.queued.push_back(Event::Step(Step { source: walk.id, depth, meta, linked, entry }));
+        Ok(())
+    }
+}
+
+impl Iterator for Plan {
+    type Item = Result<PlanEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_event()? {
+                Ok(Event::Step(step)) => return Some(Ok(step.entry)),
+                Ok(Event::Finished(_)) => continue,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// A source tree being walked by a [Plan], and the state kept for it.
+///
+/// Given several sources, e.g. on different disks, they are walked
+/// together so that the files of each are copied concurrently, rather
+/// than one disk idling while the other's files are copied. The
+/// workers read from each source device at its own limit; see
+/// [Config::readers_per_device].
+struct SourceWalk {
+    id: usize,
+    source: PathBuf,
+    target_base: PathBuf,
+    entries: Box<dyn Iterator<Item = walkdir::Result<walkdir::DirEntry>> + Send>,
+    /// Destination directories by source path, when names may be
+    /// changed.
+    dir_targets: HashMap<PathBuf, PathBuf>,
+    /// The destination directories, to detect them appearing in the
+    /// source tree, e.g. via a bind mount. The target base is added
+    /// once it has been created.
+    dest_dirs: Vec<Metadata>,
+    /// Whether to add the target base to `dest_dirs`.
+    check_base: bool,
+    /// With [Config::prune_empty_dirs], directories the walk is within
+    /// that are only created once an entry to copy is found in them.
+    pending_dirs: Vec<Step>,
+    /// With [Config::delete], the walk of the target for entries with
+    /// no source counterpart, once the source has been walked.
+    deleting: Option<walkdir::IntoIter>,
+    deleted: bool,
+    /// Source entries skipped after an error, e.g. unreadable
+    /// directories; nothing under them is deleted from the target.
+    skipped: Vec<PathBuf>,
+    deref: DerefTracker,
+}
+
+impl SourceWalk {
+    fn new(id: usize, source: PathBuf, target_base: PathBuf, dest: &Path, config: &Config) -> Result<SourceWalk> {
+        let gitignore = parse_ignore(&source, config)?;
+        let (root, filter) = (source.clone(), config.filter.clone());
+        // A symlinked source is copied as a link unless following it.
+        let entries = WalkDir::new(&source)
+            .follow_links(config.dereference)
+            .follow_root_links(config.dereference || config.dereference_sources)
+            .same_file_system(config.one_file_system)
+            .into_iter()
+            .filter_entry(move |e| ignore_filter(e, &gitignore) && pattern_filter(e, &root, &filter));
+        let dest_dirs = dest.metadata().into_iter()
+            .filter(Metadata::is_dir)
+            .collect();
+        Ok(SourceWalk {
+            id,
+            source,
+            target_base,
+            entries: Box::new(entries),
+            dir_targets: HashMap::new(),
+            dest_dirs,
+            check_base: false,
+            pending_dirs: Vec::new(),
+            deleting: None,
+            deleted: false,
+            skipped: Vec::new(),
+            deref: DerefTracker::new(),
+        })
+    }
+
+    fn step(&self, depth: usize, meta: Metadata, entry: PlanEntry) -> Event {
+        Event::Step(Step { source: self.id, depth, meta, linked: None, entry })
+    }
+}
+
+// The metadata of the top-level source directory `from`, from the
+// directory itself once opened. Checks on a source made before the
+// copy, e.g. that it isn't the destination, are by path; this is the
+// one the copy relies on.
+fn open_source_dir(from: &Path) -> Result<Metadata> {
+    match open_dir(from, false) {
+        Ok(fd) => Ok(fd.metadata()?),
+        Err(libfs::Error::IOError(e)) => match source_changed(from, &e, false) {
+            Some(err) => Err(err.into()),
+            None => Err(e.into()),
+        },
+        Err(e) => Err(e.into()),
+    }
+}
+
+// The error for reading the source `path` failing, if it was because
+// it has gone or been replaced. For a `nested` entry below the
+// top-level source it is a directory above it that may have been
+// replaced.
+fn source_changed(path: &Path, err: &std::io::Error, nested: bool) -> Option<XcpError> {
+    if err.kind() == ErrorKind::NotFound {
+        Some(XcpError::SourceNotFound(path.to_path_buf()))
+    } else if is_not_dir(err) {
+        Some(XcpError::InvalidSource {
+            path: path.to_path_buf(),
+            reason: if nested {
+                "A directory above the source is no longer a directory; it was replaced during the copy."
+            } else {
+                "Source is no longer a directory; it was replaced during the copy."
+            },
+        })
+    } else {
+        None
+    }
+}
+
+// The next entry under the target that has no counterpart in the
+// source tree, and its metadata. Extraneous entries are never touched
+// by the copy workers, so can be deleted while they are still busy.
+// Renamed and excluded entries, and anything under them, are left
+// alone. So are those whose source was `skipped`, or can't be checked;
+// only an entry whose source is known not to exist is deleted.
+fn next_extraneous(
+    it: &mut walkdir::IntoIter,
+    source: &Path,
+    target_base: &Path,
+    skipped: &[PathBuf],
+    names: &NameMapper,
+    config: &Config,
+    stats: &Arc<dyn StatusUpdater>,
+) -> Result<Option<(PathBuf, Metadata)>> {
+    while let Some(entry) = it.next() {
+        let entry = entry?;
+        let rel = entry.path().strip_prefix(target_base)?;
+        let is_dir = entry.file_type().is_dir();
+        if config.filter.as_ref().is_some_and(|f| f.excludes(rel, is_dir)) {
+            if is_dir {
+                it.skip_current_dir();
+            }
+            continue;
+        }
+        // Lock files are held by this or another copy.
+        if is_lock_file(entry.file_name()) {
+            continue;
+        }
+        let src = source.join(rel);
+        if skipped.iter().any(|s| src.starts_with(s)) {
+            if is_dir {
+                it.skip_current_dir();
+            }
+            continue;
+        }
+        match config.fs.stat(&src) {
+            Ok(_) => continue,
+            Err(e) if e.kind() == ErrorKind::NotFound || is_not_dir(&e) => {}
+            Err(e) => {
+                warn!("Not deleting {:?}, as its source {:?} can't be checked: {}", entry.path(), src, e);
+                stats.send(StatusUpdate::Error(XcpError::CopyFailed {
+                    from: src,
+                    to: entry.path().to_path_buf(),
+                    message: format!("Not deleting {:?}, as the source can't be checked: {}", entry.path(), e),
+                    source: e,
+                }))?;
+                if is_dir {
+                    it.skip_current_dir();
+                }
+                continue;
+            }
+        }
+        let meta = entry.path().symlink_metadata()?;
+        if meta.is_dir() {
+            it.skip_current_dir();
+        }
+        if names.is_renamed(entry.path()) {
+            continue;
+        }
+        return Ok(Some((entry.into_path(), meta)));
+    }
+    Ok(None)
+}
+
+// The filename restrictions of the destination; see
+// [NameProfile::probe]. If probing fails the destination is assumed
+// to be unrestricted.
+fn dest_profile(dest: &Path, config: &Config) -> NameProfile {
+    if let Some(ref profile) = config.name_profile {
+        return profile.clone();
+    }
+    let dir = if dest.is_dir() {
+        dest
+    } else {
+        dest.parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+    };
+    NameProfile::probe(dir)
+        .unwrap_or_else(|e| {
+            debug!("Failed to probe filename restrictions of {:?}: {}", dir, e);
+            NameProfile::default()
+        })
+}
+
+fn send_duplicate(dup: Duplicate, stats: &Arc<dyn StatusUpdater>) -> Result<()> {
+    debug!("Copied {} bytes again under {:?}", dup.bytes, dup.path);
+    stats.send(StatusUpdate::Duplicated {
+        path: dup.path,
+        original: dup.original,
+        bytes: dup.bytes,
+    })
+}
+
+// Check if the source is newer than an existing target.
+fn needs_update(meta: &Metadata, target: &Path, granularities: &mut Granularities) -> Result<bool> {
+    let tmeta = match target.metadata() {
+        Ok(m) => m,
+        Err(_) => return Ok(true),
+    };
+    let tdir = target.parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let gran = granularities.get(tdir)?;
+    Ok(is_newer(meta.modified()?, tmeta.modified()?, gran))
+}
+
+// Whether an existing target file has the same contents as the
+// source, with [Config::skip_same]. Files that can't be read are
+// copied, so that the error is reported.
+fn same_contents(meta: &Metadata, from: &Path, target: &Path, mode: Option<SkipSame>) -> bool {
+    let Some(mode) = mode else {
+        return false;
+    };
+    let tmeta = match target.metadata() {
+        Ok(m) if m.is_file() && m.len() == meta.len() => m,
+        _ => return false,
+    };
+    if mode == SkipSame::Stamp {
+        let stamp = Stamp::read(target).ok().flatten().filter(|s| s.is_current(&tmeta));
+        if let Some(stamp) = stamp {
+            // Otherwise the source has changed since it was copied.
+            return stamp.mtime == (meta.mtime(), meta.mtime_nsec());
+        }
+        debug!("No current checksum stamp on {:?}, reading it", target);
+    }
+    match (checksum_file(from, ChecksumType::Blake3), checksum_file(target, ChecksumType::Blake3)) {
+        (Ok(src), Ok(dest)) => src == dest,
+        _ => false,
+    }
+}
+
+// The modification time of an existing target that is newer than the
+// source.
+fn newer_target(meta: &Metadata, target: &Path, granularities: &mut Granularities) -> Result<Option<SystemTime>> {
+    let tmeta = match target.metadata() {
+        Ok(m) if m.is_file() => m,
+        _ => return Ok(None),
+    };
+    let tdir = target.parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let gran = granularities.get(tdir)?;
+    let dest_mtime = tmeta.modified()?;
+    Ok(is_newer(dest_mtime, meta.modified()?, gran).then_some(dest_mtime))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+    use std::os::unix::fs::symlink;
+    use std::thread;
+    use tempfile::TempDir;
+
+    fn plan(sources: &[&Path], dest: &Path, config: &Config) -> Result<Vec<PlanEntry>> {
+        let sources = sources.iter().map(|s| s.to_path_buf()).collect();
+        copy_plan(sources, dest, config).collect()
+    }
+
+    #[test]
+    fn test_plan_entries() -> Result<()> {
+        let dir = TempDir::new()?;
+        let (src, dest) = (dir.path().join("src"), dir.path().join("dest"));
+        create_dir_all(src.join("sub"))?;
+        write(src.join("sub/file"), "data")?;
+        symlink("sub/file", src.join("link"))?;
+
+        let mut entries = plan(&[&src], &dest, &Config::default())?;
+        entries.sort_by(|a, b| a.dest().cmp(b.dest()));
+        assert_eq!(vec![
+            PlanEntry::CreateDir { src: src.clone(), dest: dest.clone() },
+            PlanEntry::CreateSymlink { src: src.join("link"), dest: dest.join("link") },
+            PlanEntry::CreateDir { src: src.join("sub"), dest: dest.join("sub") },
+            PlanEntry::CopyFile { src: src.join("sub/file"), dest: dest.join("sub/file"), size: 4 },
+        ], entries);
+        // Nothing was created.
+        assert!(!dest.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_options() -> Result<()> {
+        let dir = TempDir::new()?;
+        let (src, dest) = (dir.path().join("src"), dir.path().join("dest"));
+        create_dir_all(src.join("empty"))?;
+        create_dir_all(dest.join("extra"))?;
+        symlink("file", src.join("link"))?;
+        symlink("extra", dest.join("link"))?;
+
+        let config = Config {
+            no_clobber: Some(NoClobber::Skip),
+            prune_empty_dirs: true,
+            delete: true,
+            no_target_directory: true,
+            ..Config::default()
+        };
+        let entries = plan(&[&src], &dest, &config)?;
+        // The empty directory is pruned.
+        assert_eq!(vec![
+            PlanEntry::CreateDir { src: src.clone(), dest: dest.clone() },
+            PlanEntry::Skip { src: src.join("link"), dest: dest.join("link"), reason: SkipReason::Exists },
+            PlanEntry::Delete { dest: dest.join("extra") },
+        ], entries);
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_error() {
+        let dir = TempDir::new().unwrap();
+        let (a, b) = (dir.path().join("a/src"), dir.path().join("b/src"));
+        create_dir_all(&a).unwrap();
+        create_dir_all(&b).unwrap();
+        let mut entries = copy_plan(vec![a, b], dir.path(), &Config::default());
+        match entries.next() {
+            Some(Err(err)) => assert!(matches!(err.downcast_ref::<XcpError>(),
+                                               Some(XcpError::DestinationCollision(..)))),
+            e => panic!("Unexpected entry {:?}", e),
+        }
+        assert!(entries.next().is_none());
+    }
+
+    #[test]
+    fn test_plan_send() -> Result<()> {
+        let dir = TempDir::new()?;
+        let src = dir.path().join("src");
+        create_dir_all(&src)?;
+        write(src.join("file"), "data")?;
+        let entries = copy_plan(vec![src], &dir.path().join("dest"), &Config::default());
+        let count = thread::spawn(move || entries.count()).join().unwrap();
+        assert_eq!(2, count);
+        Ok(())
+    }
+}
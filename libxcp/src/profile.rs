@@ -0,0 +1,235 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Tuning of the copy for its destination. FUSE filesystems such as
+//! sshfs, s3fs and gcsfuse often take small writes and have a high
+//! latency for each operation, so the defaults, which suit local
+//! disks, copy slowly to them. [DestCaps::probe] finds what the
+//! destination is, and [Profile::tuning] what to change for it.
+
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+use std::result;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use libfs::{fs_type, mount_option, FsType};
+
+use crate::config::{Config, Order};
+use crate::errors::{unexpected_value, Result, XcpError};
+
+/// The smallest block size with [Profile::Network], so that each
+/// round trip carries as much as possible.
+pub const NETWORK_BLOCK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// The most workers with [Profile::Network]; more mostly add
+/// concurrent metadata operations, which such filesystems serialise.
+pub const NETWORK_WORKERS: usize = 2;
+
+/// How the copy is tuned for its destination. [FromStr] is supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Profile {
+    /// [Profile::Network] for FUSE filesystems, and otherwise
+    /// [Profile::Local].
+    #[default]
+    Auto,
+    /// The defaults, which suit local disks.
+    Local,
+    /// For filesystems with a high latency for each operation; see
+    /// [Tuning].
+    Network,
+}
+
+impl FromStr for Profile {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(Profile::Auto),
+            "local" => Ok(Profile::Local),
+            "network" => Ok(Profile::Network),
+            _ => Err(unexpected_value("profile", s, &["auto", "local", "network"])),
+        }
+    }
+}
+
+/// What is known of the filesystem a destination is on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DestCaps {
+    pub fs_type: FsType,
+    /// The largest write a FUSE filesystem accepts, if it is given as
+    /// a mount option.
+    pub max_write: Option<u64>,
+}
+
+impl DestCaps {
+    /// Probe the filesystem of `dest`, or of its nearest existing
+    /// parent if it is yet to be created.
+    pub fn probe(dest: &Path) -> Result<DestCaps> {
+        let existing = dest.ancestors()
+            .find(|p| !p.as_os_str().is_empty() && p.exists())
+            .unwrap_or(Path::new("."));
+        let fs_type = fs_type(&File::open(existing)?)?;
+        let max_write = match fs_type {
+            FsType::Fuse => mount_option(existing, "max_write")?
+                .and_then(|v| v.parse().ok())
+                .filter(|w| *w > 0),
+            _ => None,
+        };
+        Ok(DestCaps { fs_type, max_write })
+    }
+}
+
+/// Changes to the defaults for a destination; see
+/// [Tuning::apply].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tuning {
+    /// The smallest block size; see [Config::block_size].
+    pub block_size: Option<u64>,
+    /// The most workers; see [Config::workers].
+    pub workers: Option<usize>,
+    /// See [Config::no_fallocate].
+    pub no_fallocate: bool,
+    /// See [Config::batch_dirents].
+    pub batch_dirents: bool,
+    /// Stop copying xattrs once the destination doesn't support them;
+    /// see [Config::xattrs_unsupported].
+    pub latch_xattrs: bool,
+}
+
+impl Profile {
+    /// The profile used for a destination with `caps`.
+    pub fn resolve(self, caps: &DestCaps) -> Profile {
+        match self {
+            Profile::Auto if caps.fs_type == FsType::Fuse => Profile::Network,
+            Profile::Auto => Profile::Local,
+            p => p,
+        }
+    }
+
+    /// The changes to the defaults for a destination with `caps`, if
+    /// any.
+    pub fn tuning(self, caps: &DestCaps) -> Option<Tuning> {
+        match self.resolve(caps) {
+            Profile::Network => {
+                // Blocks are split into whole writes.
+                let block_size = match caps.max_write {
+                    Some(w) => NETWORK_BLOCK_SIZE.div_ceil(w) * w,
+                    None => NETWORK_BLOCK_SIZE,
+                };
+                Some(Tuning {
+                    block_size: Some(block_size),
+                    workers: Some(NETWORK_WORKERS),
+                    no_fallocate: true,
+                    batch_dirents: true,
+                    latch_xattrs: true,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Tuning {
+    /// Apply to `config`. The block size is only raised where it is
+    /// chosen automatically ([Config::auto_block_size]), the workers
+    /// are only reduced, and directory batching needs
+    /// [Order::Scan]. Syncing each file is left as configured.
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(block_size) = self.block_size.filter(|_| config.auto_block_size) {
+            config.block_size = config.block_size.max(block_size);
+        }
+        if let Some(workers) = self.workers {
+            config.workers = config.num_workers().min(workers);
+        }
+        config.no_fallocate |= self.no_fallocate;
+        config.batch_dirents |= self.batch_dirents && config.order == Order::Scan;
+        if self.latch_xattrs {
+            config.xattrs_unsupported = Some(Arc::default());
+        }
+    }
+}
+
+impl fmt::Display for Tuning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(b) = self.block_size {
+            parts.push(format!("blocks of at least {} KiB", b / 1024));
+        }
+        if let Some(w) = self.workers {
+            parts.push(format!("at most {} workers", w));
+        }
+        if self.no_fallocate {
+            parts.push("no preallocation".to_string());
+        }
+        if self.batch_dirents {
+            parts.push("small files created a directory at a time".to_string());
+        }
+        if self.latch_xattrs {
+            parts.push("xattrs only tried until unsupported".to_string());
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+
+    const K: u64 = 1024;
+    const M: u64 = 1024 * 1024;
+
+    #[test]
+    fn test_profile_tuning() {
+        let fuse = |max_write| DestCaps { fs_type: FsType::Fuse, max_write };
+        let local = DestCaps { fs_type: FsType::Ext4, max_write: None };
+
+        assert_eq!(Profile::Network, Profile::Auto.resolve(&fuse(None)));
+        assert_eq!(None, Profile::Auto.tuning(&local));
+        assert_eq!(None, Profile::Local.tuning(&fuse(None)));
+        assert!(Profile::Network.tuning(&local).is_some());
+
+        let block_size = |caps| Profile::Auto.tuning(&caps).unwrap().block_size;
+        assert_eq!(Some(NETWORK_BLOCK_SIZE), block_size(fuse(None)));
+        assert_eq!(Some(NETWORK_BLOCK_SIZE), block_size(fuse(Some(128 * K))));
+        // Rounded up to whole writes.
+        assert_eq!(Some(4 * 96 * K * 11), block_size(fuse(Some(4 * 96 * K))));
+        assert_eq!(Some(8 * M), block_size(fuse(Some(8 * M))));
+    }
+
+    #[test]
+    fn test_tuning_apply() {
+        let tuning = Profile::Network.tuning(&DestCaps { fs_type: FsType::Fuse, max_write: None }).unwrap();
+
+        let mut config = Config { workers: 8, block_size: M, auto_block_size: true, ..Config::default() };
+        tuning.apply(&mut config);
+        assert_eq!(NETWORK_BLOCK_SIZE, config.block_size);
+        assert_eq!(NETWORK_WORKERS, config.workers);
+        assert!(config.no_fallocate && config.batch_dirents);
+        let latch = config.xattrs_unsupported.as_ref().unwrap();
+        assert!(!latch.load(Ordering::Relaxed));
+
+        // A block size that was given, fewer workers, and another
+        // order are kept.
+        let mut config = Config { workers: 1, block_size: M, order: Order::SmallestFirst, ..Config::default() };
+        tuning.apply(&mut config);
+        assert_eq!(M, config.block_size);
+        assert_eq!(1, config.workers);
+        assert!(!config.batch_dirents);
+    }
+}
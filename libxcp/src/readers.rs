@@ -0,0 +1,149 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Limits on the number of files read concurrently from each source
+//! device; see [Config::readers_per_device].
+//!
+//! On a spinning disk parallel reads of different files cause the
+//! heads to seek between them, and throughput can fall below that of
+//! a single sequential reader. Solid-state devices have no such
+//! penalty, so are not limited by default. Writes are never limited.
+//!
+//! [Config::readers_per_device]: crate::config::Config::readers_per_device
+
+use std::collections::BTreeMap;
+use std::sync::{Condvar, Mutex};
+
+use libfs::is_rotational;
+use log::info;
+
+use crate::config::Config;
+use crate::executor::POOL_WAIT;
+
+/// The number of concurrent readers of a rotational device if not
+/// configured. Two keeps one read queued while the other completes,
+/// while limiting seeks between files.
+pub(crate) const ROTATIONAL_READERS: usize = 2;
+
+struct Device {
+    rotational: bool,
+    active: usize,
+}
+
+// Source devices seen by this process, shared between copies so
+// concurrent copies from one disk are also limited.
+static DEVICES: Mutex<BTreeMap<u64, Device>> = Mutex::new(BTreeMap::new());
+static RELEASED: Condvar = Condvar::new();
+
+/// Permission to read from a source device, released on drop.
+pub(crate) struct ReadToken {
+    dev: u64,
+}
+
+impl ReadToken {
+    /// The device this permits reading from.
+    pub(crate) fn dev(&self) -> u64 {
+        self.dev
+    }
+}
+
+impl Drop for ReadToken {
+    fn drop(&mut self) {
+        if let Some(device) = DEVICES.lock().unwrap().get_mut(&self.dev) {
+            device.active -= 1;
+        }
+        RELEASED.notify_all();
+    }
+}
+
+/// Wait until a file can be read from the source device `dev`. This
+/// returns `None` if reads from the device are not limited.
+pub(crate) fn acquire(dev: u64, config: &Config) -> Option<ReadToken> {
+    let mut devices = DEVICES.lock().unwrap();
+    let device = devices.entry(dev).or_insert_with(|| {
+        let rotational = is_rotational(dev).unwrap_or(false);
+        if rotational && config.readers_per_device.is_none() {
+            info!("Source device {} is rotational; reading at most {} files from it at a time",
+                  dev, ROTATIONAL_READERS);
+        }
+        Device { rotational, active: 0 }
+    });
+    let limit = match config.readers_per_device {
+        Some(n) => n,
+        None if device.rotational => ROTATIONAL_READERS,
+        None => return None,
+    };
+    if limit >= config.num_workers() {
+        return None;
+    }
+
+    loop {
+        let device = devices.get_mut(&dev).unwrap();
+        if device.active < limit {
+            device.active += 1;
+            return Some(ReadToken { dev });
+        }
+        if config.executor.on_pool() {
+            // The copies holding the tokens may be queued behind this
+            // one on an application's pool, so run them while waiting.
+            drop(devices);
+            let ran = config.executor.yield_now();
+            devices = DEVICES.lock().unwrap();
+            if !ran {
+                devices = RELEASED.wait_timeout(devices, POOL_WAIT).unwrap().0;
+            }
+        } else {
+            devices = RELEASED.wait(devices).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_readers_limited() {
+        // Not a real device, so not rotational.
+        let dev = u64::MAX;
+        let config = Config { workers: 8, readers_per_device: Some(2), ..Config::default() };
+        let (active, peak) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let threads = (0..8).map(|_| {
+            let (config, active, peak) = (config.clone(), active.clone(), peak.clone());
+            thread::spawn(move || {
+                let _token = acquire(dev, &config).unwrap();
+                let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(10));
+                active.fetch_sub(1, Ordering::SeqCst);
+            })
+        }).collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(2, peak.load(Ordering::SeqCst));
+
+        // Unlimited, explicitly or by default.
+        let config = Config { workers: 8, readers_per_device: Some(8), ..Config::default() };
+        assert!(acquire(dev, &config).is_none());
+        let config = Config { workers: 8, ..Config::default() };
+        assert!(acquire(dev, &config).is_none());
+    }
+}
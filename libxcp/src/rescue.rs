@@ -0,0 +1,226 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Recovery of data from a failing source, e.g. a damaged disk.
+//!
+//! When copying a block fails with `EIO` it is copied again through
+//! userspace, so that a read error can be told apart from a write
+//! error and reported with its offset. A block that can't be read is
+//! retried [Config::read_retries] times and then split in halves,
+//! down to [SECTOR_SIZE], to recover as much of it as possible. With
+//! [Config::fill_errors] the sectors that still can't be read are
+//! written as zeros and returned, rather than failing the copy.
+
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use libfs::{is_io_error, merge_extents, Extent};
+use log::{debug, warn};
+
+use crate::config::Config;
+use crate::errors::{Result, XcpError};
+use crate::operations::DIRECT_IO_ALIGN;
+
+/// The xattr recording the unreadable ranges of a source file that
+/// were filled with zeros in its copy, formatted by [format_ranges].
+pub const BAD_RANGES_XATTR: &str = "user.xcp.bad_ranges";
+
+/// The smallest block read when splitting a block that can't be read.
+pub const SECTOR_SIZE: u64 = 512;
+
+/// The largest block read at once.
+const BUF_MAX: u64 = 16 * 1024 * 1024;
+
+/// Format byte ranges as e.g. `0-512,4096-8192`, with exclusive ends.
+pub fn format_ranges(ranges: &[Range<u64>]) -> String {
+    ranges.iter()
+        .map(|r| format!("{}-{}", r.start, r.end))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Sort ranges, merging any that overlap or are contiguous.
+pub(crate) fn merge_ranges(ranges: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    let extents = ranges.into_iter()
+        .map(|r| Extent { start: r.start, end: r.end, shared: false })
+        .collect();
+    // Merging can't fail.
+    merge_extents(extents)
+        .unwrap_or_default()
+        .into_iter()
+        .map(Range::from)
+        .collect()
+}
+
+/// Copy `len` bytes at offset `off` in both `infd`, the file at
+/// `from`, and `outfd`, recovering what can be read; see the
+/// [module](self) docs. Each block written, including any filled with
+/// zeros, is passed to `observer` in order. Returns the ranges that
+/// were filled.
+pub(crate) fn copy_rescued(
+    from: &Path,
+    infd: &File,
+    outfd: &File,
+    off: u64,
+    len: u64,
+    config: &Config,
+    observer: &mut dyn FnMut(&[u8]),
+) -> Result<Vec<Range<u64>>> {
+    debug!("Recovering {} bytes of {:?} at offset {}", len, from, off);
+    // A device may have been opened with O_DIRECT.
+    let size = cmp::min(len, BUF_MAX) as usize;
+    let mut raw = vec![0; size + DIRECT_IO_ALIGN];
+    let start = raw.as_ptr().align_offset(DIRECT_IO_ALIGN);
+    let buf = &mut raw[start..start + size];
+    let mut filled: Vec<Range<u64>> = Vec::new();
+
+    // Blocks still to copy, last first.
+    let mut pending = (0..len.div_ceil(BUF_MAX)).rev()
+        .map(|i| (off + i * BUF_MAX, cmp::min(BUF_MAX, len - i * BUF_MAX)))
+        .collect::<Vec<(u64, u64)>>();
+
+    while let Some((pos, n)) = pending.pop() {
+        let block = &mut buf[..n as usize];
+        match read_block(from, infd, block, pos, config) {
+            Ok(()) => {}
+            Err(e) if is_io_error(&e) && n > SECTOR_SIZE => {
+                debug!("Failed to read {} bytes of {:?} at offset {}; splitting", n, from, pos);
+                let half = cmp::max(n / 2 / SECTOR_SIZE, 1) * SECTOR_SIZE;
+                pending.push((pos + half, n - half));
+                pending.push((pos, half));
+                continue;
+            }
+            Err(e) if is_io_error(&e) && config.fill_errors => {
+                warn!("Unreadable {} bytes of {:?} at offset {}; writing zeros", n, from, pos);
+                block.fill(0);
+                match filled.last_mut() {
+                    Some(r) if r.end == pos => r.end += n,
+                    _ => filled.push(pos..pos + n),
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(XcpError::SourceTruncated { path: from.to_path_buf(), offset: pos }.into());
+            }
+            Err(e) => {
+                return Err(XcpError::ReadFailed { path: from.to_path_buf(), offset: pos, source: e }.into());
+            }
+        }
+        outfd.write_all_at(block, pos)?;
+        observer(block);
+    }
+
+    Ok(filled)
+}
+
+// Fill `buf` from offset `off`, retrying I/O errors.
+fn read_block(from: &Path, fd: &File, buf: &mut [u8], off: u64, config: &Config) -> io::Result<()> {
+    let mut retries = config.read_retries;
+    let mut n = 0;
+    while n < buf.len() {
+        match config.fs.read_at(from, fd, &mut buf[n..], off + n as u64) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(r) => n += r,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) if is_io_error(&e) && retries > 0 => {
+                debug!("Retrying read of {:?} at offset {}: {}", from, off + n as u64, e);
+                retries -= 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read, write};
+    use std::sync::Arc;
+    use libfs::{FaultInjectingFs, FsOp};
+    use tempfile::TempDir;
+
+    // EIO on all supported platforms.
+    const EIO: i32 = 5;
+
+    fn rescue(data: &[u8], bad: Range<u64>, config: Config) -> (Result<Vec<Range<u64>>>, Vec<u8>, usize) {
+        let dir = TempDir::new().unwrap();
+        let (from, to) = (dir.path().join("from"), dir.path().join("to"));
+        write(&from, data).unwrap();
+        let fs = Arc::new(FaultInjectingFs::new());
+        fs.fail_range(&from, bad, EIO);
+        let config = Config { fs: fs.clone(), ..config };
+
+        let (infd, outfd) = (File::open(&from).unwrap(), File::create(&to).unwrap());
+        let mut observed = Vec::new();
+        let result = copy_rescued(&from, &infd, &outfd, 0, data.len() as u64, &config,
+                                  &mut |b| observed.extend_from_slice(b));
+        if result.is_ok() {
+            assert_eq!(observed, read(&to).unwrap());
+        }
+        (result, observed, fs.calls(FsOp::ReadAt))
+    }
+
+    #[test]
+    fn test_format_ranges() {
+        assert_eq!("", format_ranges(&[]));
+        assert_eq!("0-512,4096-8192", format_ranges(&[0..512, 4096..8192]));
+        assert_eq!(vec![0..1024, 2048..2560], merge_ranges(vec![2048..2560, 512..1024, 0..512]));
+    }
+
+    #[test]
+    fn test_rescue_fill() {
+        let data = vec![1u8; 8192];
+        let config = Config { fill_errors: true, ..Config::default() };
+        let (result, observed, _) = rescue(&data, 1000..1600, config);
+        // Filled to sector boundaries.
+        assert_eq!(vec![512..2048], result.unwrap());
+        assert_eq!(data.len(), observed.len());
+        assert!(observed[..512].iter().all(|b| *b == 1));
+        assert!(observed[512..2048].iter().all(|b| *b == 0));
+        assert!(observed[2048..].iter().all(|b| *b == 1));
+    }
+
+    #[test]
+    fn test_rescue_fails() {
+        let data = vec![1u8; 8192];
+        let (result, _, _) = rescue(&data, 1000..1600, Config::default());
+        let err = result.unwrap_err();
+        match err.downcast_ref::<XcpError>() {
+            Some(XcpError::ReadFailed { offset, source, .. }) => {
+                assert_eq!(512, *offset);
+                assert_eq!(Some(EIO), source.raw_os_error());
+            }
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_rescue_retries() {
+        let data = vec![1u8; 1024];
+        let fill = Config { fill_errors: true, ..Config::default() };
+        // The block, then each half.
+        let (_, _, calls) = rescue(&data, 0..1, Config { read_retries: 0, ..fill.clone() });
+        assert_eq!(3, calls);
+        // Failing reads are tried three times.
+        let (result, _, calls) = rescue(&data, 0..1, Config { read_retries: 2, ..fill });
+        assert_eq!(vec![0..512], result.unwrap());
+        assert_eq!(7, calls);
+    }
+}
@@ -0,0 +1,198 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The outcome of a copy, returned by [CopyDriver::copy]. This is the
+//! synchronous complement to the [StatusUpdate] stream: totals for
+//! the whole copy, and optionally a [FileResult] for each file, with
+//! the details an embedder would otherwise have to piece together
+//! from the updates.
+//!
+//! [CopyDriver::copy]: crate::drivers::CopyDriver::copy
+//! [StatusUpdate]: crate::feedback::StatusUpdate
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::feedback::CopyMethod;
+
+/// The outcome of copying a regular file. Files that fail or are
+/// skipped have no result; they are reported as
+/// [StatusUpdate]s.
+///
+/// [StatusUpdate]: crate::feedback::StatusUpdate
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileResult {
+    pub from: PathBuf,
+    /// Where the file was written. This can differ from the
+    /// destination of the source, e.g. when renamed to keep an
+    /// existing file with [NoClobber::Rename].
+    ///
+    /// [NoClobber::Rename]: crate::config::NoClobber::Rename
+    pub to: PathBuf,
+    /// The bytes copied.
+    pub bytes: u64,
+    pub method: CopyMethod,
+    /// The metadata that wasn't copied as configured, e.g. xattrs the
+    /// destination doesn't support; empty if it was.
+    pub degraded: Vec<String>,
+    /// From opening the file to applying its metadata.
+    pub duration: Duration,
+}
+
+impl FileResult {
+    /// Whether the metadata was copied as configured.
+    pub fn metadata_preserved(&self) -> bool {
+        self.degraded.is_empty()
+    }
+}
+
+/// The totals of the files copied. Symlinks, special files and
+/// hard-links are not counted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CopyStats {
+    pub files: u64,
+    pub bytes: u64,
+    /// Files cloned with a reflink.
+    pub reflinked: u64,
+    /// Files copied by the server of a network filesystem.
+    pub server_side: u64,
+    /// Files whose metadata wasn't copied as configured.
+    pub degraded: u64,
+    /// The result of each file, in the order they completed, if
+    /// [Config::collect_results] is set; otherwise empty.
+    pub results: Vec<FileResult>,
+}
+
+impl CopyStats {
+    /// Count a file, keeping its result if `keep` is set.
+    pub fn add(&mut self, result: FileResult, keep: bool) {
+        self.files += 1;
+        self.bytes += result.bytes;
+        match result.method {
+            CopyMethod::Reflink => self.reflinked += 1,
+            CopyMethod::ServerSide => self.server_side += 1,
+            _ => {}
+        }
+        if !result.metadata_preserved() {
+            self.degraded += 1;
+        }
+        if keep {
+            self.results.push(result);
+        }
+    }
+
+    /// Add the totals and results of another copy.
+    pub fn merge(&mut self, other: CopyStats) {
+        self.files += other.files;
+        self.bytes += other.bytes;
+        self.reflinked += other.reflinked;
+        self.server_side += other.server_side;
+        self.degraded += other.degraded;
+        self.results.extend(other.results);
+    }
+}
+
+/// Collects the [FileResult]s of a copy as its files complete.
+#[derive(Default)]
+pub(crate) struct Results {
+    stats: Mutex<CopyStats>,
+    collect: bool,
+    /// Destinations whose completion is watched, and whether they
+    /// have completed; see [Results::watch].
+    watched: Mutex<HashMap<PathBuf, bool>>,
+}
+
+impl Results {
+    pub(crate) fn new(config: &Config) -> Results {
+        Results {
+            collect: config.collect_results,
+            ..Results::default()
+        }
+    }
+
+    pub(crate) fn record(&self, result: FileResult) {
+        if let Some(done) = self.watched.lock().unwrap().get_mut(&result.to) {
+            *done = true;
+        }
+        self.stats.lock().unwrap().add(result, self.collect);
+    }
+
+    /// Watch for the file copied to `to` completing, e.g. to copy it
+    /// again rather than its source; see [Results::completed].
+    pub(crate) fn watch(&self, to: PathBuf) {
+        self.watched.lock().unwrap().insert(to, false);
+    }
+
+    /// Whether the watched copy to `to` has completed. Failed copies
+    /// are never recorded, so never complete.
+    pub(crate) fn completed(&self, to: &Path) -> bool {
+        self.watched.lock().unwrap().get(to).copied().unwrap_or(false)
+    }
+
+    /// The totals so far, leaving them empty.
+    pub(crate) fn take(&self) -> CopyStats {
+        std::mem::take(&mut *self.stats.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(bytes: u64, method: CopyMethod, degraded: &[&str]) -> FileResult {
+        FileResult {
+            from: PathBuf::from("from"),
+            to: PathBuf::from("to"),
+            bytes,
+            method,
+            degraded: degraded.iter().map(|d| d.to_string()).collect(),
+            duration: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn test_results() {
+        let config = Config::default();
+        let results = Results::new(&config);
+        results.record(result(10, CopyMethod::Reflink, &[]));
+        results.record(result(5, CopyMethod::Kernel, &["xattrs not copied"]));
+        results.record(result(0, CopyMethod::ServerSide, &[]));
+        let stats = results.take();
+        assert_eq!((3, 15, 1, 1, 1), (stats.files, stats.bytes, stats.reflinked, stats.server_side, stats.degraded));
+        assert!(stats.results.is_empty());
+        assert_eq!(CopyStats::default(), results.take());
+
+        let config = Config { collect_results: true, ..Config::default() };
+        let results = Results::new(&config);
+        results.record(result(5, CopyMethod::Kernel, &["xattrs not copied"]));
+        let mut stats = results.take();
+        assert_eq!(1, stats.results.len());
+        assert!(!stats.results[0].metadata_preserved());
+
+        stats.merge(stats.clone());
+        assert_eq!((2, 10, 2, 2), (stats.files, stats.bytes, stats.degraded, stats.results.len() as u64));
+
+        results.watch(PathBuf::from("to"));
+        results.watch(PathBuf::from("other"));
+        results.record(result(5, CopyMethod::Kernel, &[]));
+        assert!(results.completed(Path::new("to")));
+        assert!(!results.completed(Path::new("other")));
+        assert!(!results.completed(Path::new("unwatched")));
+    }
+}
@@ -0,0 +1,134 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Estimates of the space a copy takes on the destination. Each file
+//! takes a whole number of the filesystem's allocation units, so many
+//! small files copied to a filesystem with a large unit, e.g. exFAT
+//! with 64 KiB clusters, can take many times their size.
+
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::errors::Result;
+use crate::plan::{copy_plan, PlanEntry};
+
+/// Copies are only checked for slack where the destination's
+/// allocation unit is at least this many times that of the sources.
+pub const SLACK_RATIO: u64 = 4;
+
+/// Slack smaller than this isn't worth reporting.
+const MIN_SIGNIFICANT_SLACK: u64 = 64 * 1024 * 1024;
+
+/// The size of a set of files, and the space they take on a
+/// filesystem with the given allocation unit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Allocation {
+    /// The allocation unit, in bytes.
+    pub block_size: u64,
+    pub files: u64,
+    /// The total size of the files.
+    pub logical: u64,
+    /// The space taken, with each file rounded up to a whole number
+    /// of blocks.
+    pub allocated: u64,
+}
+
+impl Allocation {
+    pub fn new(block_size: u64) -> Allocation {
+        Allocation {
+            block_size: block_size.max(1),
+            files: 0,
+            logical: 0,
+            allocated: 0,
+        }
+    }
+
+    /// Add a file of `size` bytes.
+    pub fn add(&mut self, size: u64) {
+        self.files += 1;
+        self.logical += size;
+        self.allocated += size.div_ceil(self.block_size) * self.block_size;
+    }
+
+    /// The space lost to partly filled blocks.
+    pub fn slack(&self) -> u64 {
+        self.allocated - self.logical
+    }
+
+    /// Whether the slack is worth reporting: at least as much again
+    /// as the data, and not trivially small.
+    pub fn is_significant(&self) -> bool {
+        self.slack() >= self.logical && self.slack() >= MIN_SIGNIFICANT_SLACK
+    }
+}
+
+/// Project the space the files copied from `sources` to `dest` will
+/// take with an allocation unit of `block_size`, using the sizes
+/// found by walking the [copy_plan].
+pub fn project(sources: Vec<PathBuf>, dest: &Path, config: &Config, block_size: u64) -> Result<Allocation> {
+    let mut allocation = Allocation::new(block_size);
+    for entry in copy_plan(sources, dest, config) {
+        if let PlanEntry::CopyFile { size, .. } = entry? {
+            allocation.add(size);
+        }
+    }
+    Ok(allocation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_allocation() {
+        let mut allocation = Allocation::new(64 * 1024);
+        allocation.add(0);
+        allocation.add(1);
+        allocation.add(64 * 1024);
+        allocation.add(64 * 1024 + 1);
+        assert_eq!(4, allocation.files);
+        assert_eq!(128 * 1024 + 2, allocation.logical);
+        assert_eq!(256 * 1024, allocation.allocated);
+        assert_eq!(128 * 1024 - 2, allocation.slack());
+        assert!(!allocation.is_significant());
+
+        // A million 1 KiB files.
+        let mut small = Allocation::new(64 * 1024);
+        for _ in 0..1_000_000 {
+            small.add(1024);
+        }
+        assert_eq!(64 * small.logical, small.allocated);
+        assert!(small.is_significant());
+    }
+
+    #[test]
+    fn test_project() -> Result<()> {
+        let dir = TempDir::new()?;
+        let src = dir.path().join("src");
+        create_dir_all(src.join("sub"))?;
+        write(src.join("a"), "a")?;
+        write(src.join("sub/b"), vec![0; 5000])?;
+        let config = Config { no_target_directory: true, ..Config::default() };
+
+        let allocation = project(vec![src], &dir.path().join("dest"), &config, 4096)?;
+        assert_eq!(2, allocation.files);
+        assert_eq!(5001, allocation.logical);
+        assert_eq!(3 * 4096, allocation.allocated);
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Staging of copied files; see [Config::staging_dir].
+//!
+//! Each run stages files in its own directory,
+//! `.xcp-staging-<pid>`, under the configured staging directory. It
+//! holds a lock on `.xcp-staging-<pid>.lock` beside it while running,
+//! and removes both when the copy finishes. Directories left by a run
+//! that crashed no longer have a locked lock file, and are removed by
+//! the next run to use the same staging directory.
+//!
+//! [Config::staging_dir]: crate::config::Config::staging_dir
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use libfs::{copy_file, same_device, sync, try_lock_file, Attribute};
+use log::{debug, info, warn};
+
+use crate::config::{Config, PreserveSet};
+use crate::errors::{Result, XcpError};
+use crate::operations::{copy_attributes, NO_CLOBBER_MSG};
+
+const STAGING_PREFIX: &str = ".xcp-staging-";
+const LOCK_SUFFIX: &str = ".lock";
+
+/// The staging directory of a single copy. Dropping it removes the
+/// directory and any files still in it.
+#[derive(Debug)]
+pub(crate) struct Staging {
+    dir: PathBuf,
+    lock_path: PathBuf,
+    // Held for the lifetime of the run.
+    _lock: File,
+    next: AtomicU64,
+    warned: AtomicBool,
+}
+
+impl Staging {
+    /// Create the staging directory for this run if
+    /// [Config::staging_dir] is set, first removing any left by
+    /// earlier runs that are no longer running.
+    pub(crate) fn new(config: &Config) -> Result<Option<Staging>> {
+        let Some(base) = config.staging_dir.as_ref() else {
+            return Ok(None);
+        };
+        if !base.is_dir() {
+            return Err(XcpError::InvalidArguments(
+                format!("Staging directory {:?} is not a directory", base)).into());
+        }
+        remove_stale(base)?;
+
+        let name = format!("{}{}", STAGING_PREFIX, process::id());
+        let lock_path = base.join(format!("{}{}", name, LOCK_SUFFIX));
+        let lock = File::create(&lock_path)?;
+        // Another run could only hold it if it had our pid.
+        if !try_lock_file(&lock)? {
+            return Err(XcpError::CopyError(format!("Staging lock {:?} is held by another copy", lock_path)).into());
+        }
+        let dir = base.join(name);
+        fs::create_dir(&dir)?;
+        debug!("Staging files in {:?}", dir);
+
+        Ok(Some(Staging {
+            dir,
+            lock_path,
+            _lock: lock,
+            next: AtomicU64::new(0),
+            warned: AtomicBool::new(false),
+        }))
+    }
+
+    /// A unique path to stage the destination `to` at.
+    pub(crate) fn path(&self, to: &Path) -> PathBuf {
+        let mut name = OsString::from(format!("{}-", self.next.fetch_add(1, Ordering::Relaxed)));
+        name.push(to.file_name().unwrap_or_default());
+        self.dir.join(name)
+    }
+
+    /// Move a complete staged file into place at `to`. This is a
+    /// rename if they are on the same filesystem, otherwise the file
+    /// is copied again along with its metadata. With
+    /// [Config::no_clobber] an existing `to` is not replaced.
+    pub(crate) fn commit(&self, staged: &Path, to: &Path, config: &Config) -> Result<()> {
+        let parent = to.parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        if same_device(staged, parent)? {
+            debug!("Renaming staged {:?} to {:?}", staged, to);
+            if config.no_clobber.is_some() {
+                // Fails if the destination exists, unlike rename.
+                fs::hard_link(staged, to).map_err(|e| exists_error(e, to))?;
+                fs::remove_file(staged)?;
+            } else {
+                fs::rename(staged, to)?;
+            }
+            return Ok(());
+        }
+
+        if !self.warned.swap(true, Ordering::Relaxed) {
+            warn!("Staging directory {:?} is on a different filesystem to {:?}; files are written twice",
+                  self.dir, to);
+        }
+        debug!("Copying staged {:?} to {:?}", staged, to);
+        if config.no_clobber.is_some() {
+            File::options().write(true).create_new(true).open(to).map_err(|e| exists_error(e, to))?;
+        }
+        copy_file(staged, to)?;
+        let infd = File::open(staged)?;
+        let outfd = File::options().write(true).open(to)?;
+        copy_attributes(to, &infd, &outfd, true, config)?;
+        if config.preserve.contains(PreserveSet::TIMESTAMPS) {
+            config.fs.set_metadata(to, &infd, &outfd, Attribute::Timestamps)?;
+        }
+        sync(&outfd)?;
+        fs::remove_file(staged)?;
+        Ok(())
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        debug!("Removing staging directory {:?}", self.dir);
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            warn!("Failed to remove staging directory {:?}: {}", self.dir, e);
+        }
+        let _ = fs::remove_file(&self.lock_path);
+    }
+}
+
+fn exists_error(err: std::io::Error, to: &Path) -> anyhow::Error {
+    match err.kind() {
+        ErrorKind::AlreadyExists => XcpError::DestinationExists { path: to.to_path_buf(), reason: NO_CLOBBER_MSG }.into(),
+        _ => err.into(),
+    }
+}
+
+// Remove staging directories whose run is no longer holding the
+// lock. A directory without a lock file is always stale, as the lock
+// is created first and removed last.
+fn remove_stale(base: &Path) -> Result<()> {
+    for entry in fs::read_dir(base)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if !name.starts_with(STAGING_PREFIX) || name.ends_with(LOCK_SUFFIX) {
+            continue;
+        }
+        let lock_path = base.join(format!("{}{}", name, LOCK_SUFFIX));
+        let lock = match File::open(&lock_path) {
+            Ok(fd) => Some(fd),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(ref fd) = lock {
+            // Check it is still the lock file after locking; a
+            // concurrent cleanup may have just replaced it.
+            if !try_lock_file(fd)? || fd.metadata()?.ino() != lock_path.metadata().map(|m| m.ino()).unwrap_or(0) {
+                continue;
+            }
+        }
+        info!("Removing stale staging directory {:?}", entry.path());
+        fs::remove_dir_all(entry.path())?;
+        if lock.is_some() {
+            fs::remove_file(&lock_path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_staging_lifecycle() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let base = tdir.path().join("staging");
+        fs::create_dir(&base)?;
+        let config = Config { staging_dir: Some(base.clone()), ..Config::default() };
+
+        // A crashed run, with and without its lock file.
+        fs::create_dir_all(base.join(".xcp-staging-1/sub"))?;
+        fs::write(base.join(".xcp-staging-1/sub/file"), "stale")?;
+        File::create(base.join(".xcp-staging-1.lock"))?;
+        fs::create_dir(base.join(".xcp-staging-2"))?;
+        // A running copy.
+        fs::create_dir(base.join(".xcp-staging-3"))?;
+        let running = File::create(base.join(".xcp-staging-3.lock"))?;
+        assert!(try_lock_file(&running)?);
+        fs::write(base.join("other"), "unrelated")?;
+
+        let staging = Staging::new(&config)?.unwrap();
+        assert!(!base.join(".xcp-staging-1").exists());
+        assert!(!base.join(".xcp-staging-1.lock").exists());
+        assert!(!base.join(".xcp-staging-2").exists());
+        assert!(base.join(".xcp-staging-3").exists());
+        assert!(base.join("other").exists());
+
+        let to = tdir.path().join("file.txt");
+        let staged = staging.path(&to);
+        assert_ne!(staged, staging.path(&to));
+        assert!(staged.starts_with(&staging.dir));
+        fs::write(&staged, "data")?;
+        staging.commit(&staged, &to, &config)?;
+        assert!(!staged.exists());
+        assert_eq!("data", fs::read_to_string(&to)?);
+
+        let staged = staging.path(&to);
+        fs::write(&staged, "new")?;
+        let config = Config { no_clobber: Some(crate::config::NoClobber::Fail), ..config };
+        assert!(staging.commit(&staged, &to, &config).is_err());
+        assert_eq!("data", fs::read_to_string(&to)?);
+
+        let (dir, lock_path) = (staging.dir.clone(), staging.lock_path.clone());
+        drop(staging);
+        assert!(!dir.exists());
+        assert!(!lock_path.exists());
+        Ok(())
+    }
+}
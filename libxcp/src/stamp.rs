@@ -0,0 +1,146 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Checksums stamped on copied files.
+//!
+//! With [Config::stamp_checksum] the BLAKE3 digest of each copied file
+//! is recorded in the [DIGEST_XATTR] xattr of the copy, alongside the
+//! modification time of its source in [MTIME_XATTR]. While the
+//! timestamps are preserved the copy has the same modification time
+//! as the source, so a copy whose modification time still matches
+//! its stamp hasn't been changed since, and the stamped digest can be
+//! trusted rather than reading the file again; see [SkipSame::Stamp].
+//!
+//! [Config::stamp_checksum]: crate::config::Config::stamp_checksum
+//! [SkipSame::Stamp]: crate::config::SkipSame::Stamp
+
+use std::collections::BTreeSet;
+use std::fs::{File, Metadata};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::Mutex;
+
+use libfs::{get_xattr, set_xattr};
+use log::{debug, warn};
+
+use crate::errors::Result;
+
+/// The xattr holding the BLAKE3 digest of a copied file, as hex.
+pub const DIGEST_XATTR: &str = "user.xcp.blake3";
+
+/// The xattr holding the modification time of the source of a copied
+/// file, as seconds since the epoch with a nanosecond fraction.
+pub const MTIME_XATTR: &str = "user.xcp.src_mtime";
+
+/// Destination devices that don't support xattrs; stamps are not
+/// written to these for the rest of the run.
+static UNSTAMPED_DEVS: Mutex<BTreeSet<u64>> = Mutex::new(BTreeSet::new());
+
+/// A checksum stamped on a copied file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stamp {
+    /// The BLAKE3 digest of the file, as hex.
+    pub digest: String,
+    /// The modification time of the source when it was copied, as
+    /// seconds and nanoseconds since the epoch.
+    pub mtime: (i64, i64),
+}
+
+impl Stamp {
+    /// Read the stamp of a file, if it has a valid one.
+    pub fn read(path: &Path) -> Result<Option<Stamp>> {
+        let (Some(digest), Some(mtime)) = (get_xattr(path, DIGEST_XATTR)?, get_xattr(path, MTIME_XATTR)?) else {
+            return Ok(None);
+        };
+        let stamp = String::from_utf8(digest).ok()
+            .zip(String::from_utf8(mtime).ok().as_deref().and_then(parse_mtime))
+            .map(|(digest, mtime)| Stamp { digest, mtime });
+        if stamp.is_none() {
+            debug!("Ignoring malformed checksum stamp on {:?}", path);
+        }
+        Ok(stamp)
+    }
+
+    /// Whether the file was last modified at the stamped time, i.e.
+    /// the stamp describes its contents.
+    pub fn is_current(&self, meta: &Metadata) -> bool {
+        self.mtime == (meta.mtime(), meta.mtime_nsec())
+    }
+}
+
+fn parse_mtime(s: &str) -> Option<(i64, i64)> {
+    let (secs, nsecs) = s.split_once('.')?;
+    Some((secs.parse().ok()?, nsecs.parse().ok()?))
+}
+
+/// Stamp the digest of a copy on it, along with the modification time
+/// of its source. If the destination doesn't support xattrs this
+/// warns once for the device, and isn't retried there.
+pub(crate) fn stamp_file(fd: &File, to: &Path, dev: u64, digest: &str, source: &Metadata) -> Result<()> {
+    if UNSTAMPED_DEVS.lock().unwrap().contains(&dev) {
+        return Ok(());
+    }
+    let mtime = format!("{}.{:09}", source.mtime(), source.mtime_nsec());
+    // The time is written last, so an interrupted stamp is never
+    // current.
+    if set_xattr(fd, DIGEST_XATTR, digest.as_bytes())? && set_xattr(fd, MTIME_XATTR, mtime.as_bytes())? {
+        return Ok(());
+    }
+    if UNSTAMPED_DEVS.lock().unwrap().insert(dev) {
+        warn!("The filesystem of {:?} doesn't support xattrs; not stamping checksums on it", to);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{write, FileTimes};
+    use std::time::{Duration, UNIX_EPOCH};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_stamp_roundtrip() -> Result<()> {
+        let dir = TempDir::new()?;
+        let (from, to) = (dir.path().join("from"), dir.path().join("to"));
+        write(&from, "data")?;
+        write(&to, "data")?;
+        let mtime = UNIX_EPOCH + Duration::new(1_700_000_000, 1234);
+        File::options().write(true).open(&from)?.set_times(FileTimes::new().set_modified(mtime))?;
+
+        assert_eq!(None, Stamp::read(&to)?);
+        let fd = File::options().write(true).open(&to)?;
+        stamp_file(&fd, &to, u64::MAX, "abcd", &from.metadata()?)?;
+        let Some(stamp) = Stamp::read(&to)? else {
+            println!("Xattrs not supported; skipping");
+            return Ok(());
+        };
+        assert_eq!(Stamp { digest: "abcd".to_string(), mtime: (1_700_000_000, 1234) }, stamp);
+
+        // Only current once the copy has the source's time.
+        assert!(!stamp.is_current(&to.metadata()?));
+        fd.set_times(FileTimes::new().set_modified(mtime))?;
+        assert!(stamp.is_current(&to.metadata()?));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_mtime() {
+        assert_eq!(Some((12, 5)), parse_mtime("12.000000005"));
+        assert_eq!(None, parse_mtime("12"));
+        assert_eq!(None, parse_mtime("x.1"));
+    }
+}
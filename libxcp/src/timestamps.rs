@@ -0,0 +1,132 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Granularity-aware timestamp comparison.
+//!
+//! Filesystems store timestamps at different resolutions (e.g. FAT
+//! uses 2 seconds), so a copied file's mtime may be truncated. To
+//! ensure repeated copies converge, timestamps are compared with a
+//! tolerance of the destination filesystem's resolution.
+
+use std::collections::HashMap;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use libfs::timestamp_granularity;
+use log::{debug, warn};
+
+use crate::errors::Result;
+
+/// Returns true if `src` is newer than `dest`, ignoring differences
+/// smaller than `granularity`.
+pub(crate) fn is_newer(src: SystemTime, dest: SystemTime, granularity: Duration) -> bool {
+    match src.duration_since(dest) {
+        Ok(diff) => diff >= granularity.max(Duration::from_nanos(1)),
+        Err(_) => false,
+    }
+}
+
+/// Format a time in UTC, e.g. `2001-09-09 01:46:40.000000000 UTC`,
+/// for log messages.
+pub(crate) fn format_time(time: SystemTime) -> String {
+    let (secs, nanos) = match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+        Err(e) => {
+            let d = e.duration();
+            match d.subsec_nanos() {
+                0 => (-(d.as_secs() as i64), 0),
+                n => (-(d.as_secs() as i64) - 1, 1_000_000_000 - n),
+            }
+        }
+    };
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // The civil date from days since the epoch; see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:09} UTC",
+            year, month, day, secs / 3600, secs % 3600 / 60, secs % 60, nanos)
+}
+
+/// Cache of timestamp granularities, probed once per destination
+/// filesystem.
+#[derive(Default)]
+pub(crate) struct Granularities {
+    cache: HashMap<u64, Duration>,
+}
+
+impl Granularities {
+    /// Return the timestamp granularity of the filesystem containing
+    /// the directory `dir`.
+    pub(crate) fn get(&mut self, dir: &Path) -> Result<Duration> {
+        let dev = dir.metadata()?.dev();
+        let gran = *self.cache.entry(dev).or_insert_with(|| {
+            match timestamp_granularity(dir) {
+                Ok(g) => {
+                    debug!("Timestamp granularity of {:?} is {:?}", dir, g);
+                    g
+                }
+                Err(e) => {
+                    warn!("Failed to probe timestamp granularity of {:?}, assuming nanoseconds: {}", dir, e);
+                    Duration::from_nanos(1)
+                }
+            }
+        });
+        Ok(gran)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_newer() {
+        let t = |s, n| UNIX_EPOCH + Duration::new(s, n);
+        let ns = Duration::from_nanos(1);
+        let fat = Duration::from_secs(2);
+
+        assert!(is_newer(t(100, 1), t(100, 0), ns));
+        assert!(!is_newer(t(100, 0), t(100, 0), ns));
+        assert!(!is_newer(t(100, 0), t(100, 1), ns));
+
+        // Truncated by the destination; should compare equal.
+        assert!(!is_newer(t(101, 500), t(100, 0), fat));
+        assert!(!is_newer(t(100, 0), t(100, 0), fat));
+        assert!(is_newer(t(102, 0), t(100, 0), fat));
+    }
+
+    #[test]
+    fn test_format_time() {
+        let t = |s, n| UNIX_EPOCH + Duration::new(s, n);
+        assert_eq!("1970-01-01 00:00:00.000000000 UTC", format_time(UNIX_EPOCH));
+        assert_eq!("2001-09-09 01:46:40.000000000 UTC", format_time(t(1_000_000_000, 0)));
+        assert_eq!("2000-02-29 23:59:59.000000001 UTC", format_time(t(951_868_799, 1)));
+        assert_eq!("2100-03-01 00:00:00.000000000 UTC", format_time(t(4_107_542_400, 0)));
+        assert_eq!("1969-12-31 23:59:58.500000000 UTC", format_time(UNIX_EPOCH - Duration::from_millis(1500)));
+        assert_eq!("1900-01-01 00:00:00.000000000 UTC", format_time(UNIX_EPOCH - Duration::from_secs(2_208_988_800)));
+    }
+}
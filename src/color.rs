@@ -0,0 +1,60 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The colour policy for terminal output, set with `--color`. Log
+//! messages on the terminal and the progress bar consult the same
+//! decision, so output is either coloured throughout or not at all.
+
+use std::env;
+use std::io::IsTerminal;
+use std::result;
+use std::str::FromStr;
+
+use libxcp::errors::{unexpected_value, XcpError};
+
+use crate::options::Opts;
+
+/// When terminal output is coloured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorMode {
+    /// Only when writing to a terminal, and `NO_COLOR` is not set.
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorMode {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(ColorMode::Auto),
+            "always" => Ok(ColorMode::Always),
+            "never" => Ok(ColorMode::Never),
+            _ => Err(unexpected_value("color", s, &["auto", "always", "never"])),
+        }
+    }
+}
+
+/// Whether output written to `stream` should be coloured.
+pub fn enabled(opts: &Opts, stream: &impl IsTerminal) -> bool {
+    match opts.color {
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+        // See https://no-color.org; an empty value is ignored.
+        ColorMode::Auto => env::var_os("NO_COLOR").map_or(true, |v| v.is_empty()) && stream.is_terminal(),
+    }
+}
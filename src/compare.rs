@@ -0,0 +1,84 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! `--compare-only`: report the differences between two trees
+//! without copying.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+use libxcp::compare::{compare_trees, Change};
+use libxcp::config::Config;
+use libxcp::errors::{Result, XcpError};
+use libxcp::feedback::{ChannelUpdater, StatusUpdate, StatusUpdater};
+use log::info;
+
+use crate::options::Opts;
+use crate::progress;
+
+/// Compare the source and destination, listing each difference and
+/// returning [XcpError::TreesDiffer] if there are any.
+pub fn compare(opts: &Opts) -> Result<()> {
+    let [source, dest] = opts.paths.as_slice() else {
+        return Err(XcpError::InvalidArguments(
+            "--compare-only requires a single source and destination".to_string()).into());
+    };
+    let (source, dest) = (PathBuf::from(source), PathBuf::from(dest));
+    if source.symlink_metadata().is_err() {
+        return Err(XcpError::SourceNotFound(source.clone()).into());
+    }
+    info!("Comparing {:?} with {:?}", source, dest);
+
+    let config = Arc::new(Config::from(opts));
+    let updater = ChannelUpdater::new(&config);
+    let stat_rx = updater.rx_channel();
+    let stats: Arc<dyn StatusUpdater> = Arc::new(updater);
+
+    let handle = thread::spawn(move || compare_trees(&source, &dest, &config, &stats));
+
+    let pb = progress::create_bar(opts, 0)?;
+    for stat in stat_rx {
+        match stat {
+            StatusUpdate::Copied(v) => pb.inc(v),
+            StatusUpdate::Size(v) => pb.inc_size(v),
+            StatusUpdate::FileStarted(id, path) => pb.file_started(id, &path),
+            StatusUpdate::FileCompleted(id, _) => pb.file_completed(id),
+            _ => {}
+        }
+    }
+
+    let items = handle.join()
+        .map_err(|_| XcpError::CopyError("Error during comparison".to_string()))??;
+    for item in &items {
+        pb.item(item);
+    }
+    pb.end();
+
+    if items.is_empty() {
+        info!("Trees are identical");
+        return Ok(());
+    }
+    let missing = items.iter()
+        .filter(|i| i.change == Change::New && i.path.symlink_metadata().is_err())
+        .count();
+    let extra = items.iter().filter(|i| i.change == Change::Extra).count();
+    Err(XcpError::TreesDiffer {
+        differing: items.len() - missing - extra,
+        missing,
+        extra,
+    }.into())
+}
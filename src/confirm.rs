@@ -0,0 +1,157 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Checks for argument mistakes that could overwrite or delete large
+//! amounts of data, such as swapping the source and destination, or
+//! fill the destination. These require confirmation before the copy
+//! starts.
+
+use std::env;
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+use indicatif::HumanBytes;
+use libfs::{fs_space, FsSpace};
+use libxcp::config::Config;
+use libxcp::errors::{Result, XcpError};
+use libxcp::space::{project, Allocation, SLACK_RATIO};
+use log::debug;
+use walkdir::WalkDir;
+
+use crate::options::Opts;
+
+/// A reason to confirm a copy before starting it.
+pub enum Hazard {
+    /// The destination is the root directory.
+    RootDest,
+    /// The destination is the user's home directory, or the copy
+    /// writes directly into it.
+    HomeDest(PathBuf),
+    /// `--delete` is set and the destination has more than the
+    /// threshold of existing entries.
+    MassOverwrite(PathBuf, usize),
+    /// The files fit in the free space of the destination, but not
+    /// once rounded up to its allocation unit.
+    Slack(Allocation, u64),
+}
+
+impl fmt::Display for Hazard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Hazard::RootDest => write!(f, "the destination is the root directory '/'"),
+            Hazard::HomeDest(home) => write!(f, "the copy writes directly into your home directory {:?}", home),
+            Hazard::MassOverwrite(dest, threshold) => write!(
+                f, "--delete is set and {:?} already contains more than {} entries, which may be overwritten or deleted",
+                dest, threshold),
+            Hazard::Slack(allocation, available) => write!(
+                f, "the files total {} but will take an estimated {} in {} allocation units, more than the {} free",
+                HumanBytes(allocation.logical), HumanBytes(allocation.allocated),
+                HumanBytes(allocation.block_size), HumanBytes(*available)),
+        }
+    }
+}
+
+fn home_dir() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .filter(|h| !h.is_empty())
+        .and_then(|h| Path::new(&h).canonicalize().ok())
+}
+
+// Whether a directory tree has more than `limit` entries. This stops
+// counting once the limit is passed.
+fn exceeds_entries(dir: &Path, limit: usize) -> bool {
+    WalkDir::new(dir)
+        .min_depth(1)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .nth(limit)
+        .is_some()
+}
+
+/// Check a copy for hazards. `targets` are the destination paths each
+/// source will be copied to.
+pub fn check(dest: &Path, targets: &[PathBuf], opts: &Opts) -> Vec<Hazard> {
+    let mut hazards = Vec::new();
+    if dest == Path::new("/") {
+        hazards.push(Hazard::RootDest);
+    }
+    if let Some(home) = home_dir() {
+        if dest == home || targets.contains(&home) {
+            hazards.push(Hazard::HomeDest(home));
+        }
+    }
+    if opts.delete && opts.confirm_threshold > 0 {
+        for target in targets.iter().filter(|t| t.is_dir()) {
+            if exceeds_entries(target, opts.confirm_threshold) {
+                hazards.push(Hazard::MassOverwrite(target.clone(), opts.confirm_threshold));
+            }
+        }
+    }
+    hazards
+}
+
+/// The allocation unit and free space of the destination, if the unit
+/// is so much larger than that of the sources that small files may
+/// take far more space than their size; see [libxcp::space].
+pub fn coarse_dest(dest: &Path, sources: &[PathBuf]) -> Option<FsSpace> {
+    let existing = dest.ancestors()
+        .find(|p| !p.as_os_str().is_empty() && p.exists())
+        .unwrap_or(Path::new("."));
+    let space = fs_space(existing).ok()?;
+    let source_unit = sources.iter()
+        .filter_map(|s| fs_space(s).ok())
+        .map(|s| s.block_size)
+        .min()?;
+    (space.block_size >= source_unit.saturating_mul(SLACK_RATIO)).then_some(space)
+}
+
+/// Check whether the copy fits in the free space of a destination
+/// with a coarse allocation unit, but only before it is rounded up to
+/// the unit. This walks the sources.
+pub fn check_space(dest: &Path, sources: &[PathBuf], space: &FsSpace, config: &Config) -> Result<Option<Hazard>> {
+    let projected = project(sources.to_vec(), dest, config, space.block_size)?;
+    debug!("Projected allocation on {:?}: {:?}", dest, projected);
+    Ok((projected.logical <= space.available && projected.allocated > space.available)
+       .then_some(Hazard::Slack(projected, space.available)))
+}
+
+/// Ask for confirmation of any hazards. On a terminal the user is
+/// prompted; otherwise the copy is refused.
+pub fn confirm(hazards: &[Hazard]) -> Result<()> {
+    if hazards.is_empty() {
+        return Ok(());
+    }
+    let reasons = hazards.iter()
+        .map(Hazard::to_string)
+        .collect::<Vec<String>>()
+        .join("; ");
+    if !io::stdin().is_terminal() || !io::stderr().is_terminal() {
+        return Err(XcpError::NotConfirmed(format!("{}. Use --yes to copy anyway.", reasons)).into());
+    }
+
+    for hazard in hazards {
+        eprintln!("Warning: {}.", hazard);
+    }
+    eprint!("Continue? [y/N] ");
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    match answer.trim() {
+        "y" | "Y" | "yes" => Ok(()),
+        _ => Err(XcpError::NotConfirmed(reasons).into()),
+    }
+}
@@ -0,0 +1,212 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Copies a single source to several destinations with '--fanout',
+//! reading it once; see [libxcp::fanout]. Each destination succeeds
+//! or fails independently, and is reported separately.
+
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::Arc;
+use std::thread;
+
+use indicatif::HumanBytes;
+use libfs::{same_inode, SameFile};
+use libxcp::config::Config;
+use libxcp::errors::{Result, XcpError};
+use libxcp::fanout::copy_fanout;
+use libxcp::feedback::{ChannelUpdater, StatusUpdate, StatusUpdater};
+use libxcp::paths::{dest_names, normalize_dest};
+use log::{error, info, warn};
+
+use crate::options::Opts;
+use crate::stall::StallMonitor;
+use crate::{confirm, lock_dest, logging, progress, resolve_source, source_metadata, STALL_CHECK_INTERVAL};
+
+// The progress and outcome of one destination.
+#[derive(Default)]
+struct DestStatus {
+    written: u64,
+    errors: Vec<XcpError>,
+    failed: bool,
+}
+
+/// Copy the first path to each of the rest.
+pub fn fanout(opts: &Opts) -> Result<()> {
+    let (source, dests) = match opts.paths.split_first() {
+        Some((source, dests)) if !dests.is_empty() => (PathBuf::from(source), dests),
+        _ => return Err(XcpError::InvalidArguments(
+            "--fanout requires a source and at least one destination".to_string()).into()),
+    };
+    let is_dir = source_metadata(&source, opts)?.is_dir();
+    if is_dir && !opts.recursive {
+        return Err(XcpError::InvalidSource {
+            path: source.clone(),
+            reason: "Source is directory and --recursive not specified.",
+        }.into());
+    }
+
+    let config = Arc::new(Config::from(opts));
+    let follow = opts.follow_sources();
+    let resolved = resolve_source(&source, follow)?;
+    let mut normalized: Vec<PathBuf> = Vec::with_capacity(dests.len());
+    let mut locks = Vec::with_capacity(dests.len());
+    for dest in dests {
+        let dest = normalize_dest(Path::new(dest), opts.allow_dotdot_dest)?;
+        if normalized.contains(&dest) {
+            return Err(XcpError::InvalidDestination {
+                path: dest,
+                reason: "The same destination is given more than once.",
+            }.into());
+        }
+        if is_dir && !dest.is_dir() && dest.exists() {
+            return Err(XcpError::InvalidDestination {
+                path: dest,
+                reason: "Cannot copy a directory to a file.",
+            }.into());
+        }
+        let name = dest_names(slice::from_ref(&source), &dest, &config)?.pop().flatten();
+        let target = name.map_or_else(|| dest.clone(), |n| dest.join(n));
+        // The walk would descend into a destination within the source.
+        if is_dir && dest.starts_with(&resolved) {
+            return Err(XcpError::InvalidSource {
+                path: source.clone(),
+                reason: "Cannot copy a directory into itself",
+            }.into());
+        }
+        if resolved == target || same_inode(&source, &target, follow)? == SameFile::Same {
+            return Err(XcpError::InvalidSource {
+                path: source.clone(),
+                reason: "Source is same as destination",
+            }.into());
+        }
+        if !(opts.yes || opts.force) {
+            confirm::confirm(&confirm::check(&dest, &[target], opts))?;
+        }
+        locks.push(lock_dest(&dest, opts)?);
+        info!("Copying source {:?} to {:?}", source, dest);
+        normalized.push(dest);
+    }
+    let dests = normalized;
+
+    let updater = ChannelUpdater::new(&config);
+    let stat_rx = updater.rx_channel();
+    let stats: Arc<dyn StatusUpdater> = Arc::new(updater);
+    let handle = {
+        let dests = dests.clone();
+        thread::spawn(move || copy_fanout(&source, &dests, &config, &stats))
+    };
+
+    let pb = progress::create_fanout_bar(opts, 0, &dests)?;
+    let mut status = dests.iter().map(|_| DestStatus::default()).collect::<Vec<_>>();
+    let mut errors = Vec::new();
+    let (mut size, mut skipped) = (0u64, 0u64);
+    let mut stall = StallMonitor::new(opts);
+    loop {
+        let stat = match stat_rx.recv_timeout(STALL_CHECK_INTERVAL) {
+            Ok(stat) => Some(stat),
+            Err(e) if e.is_timeout() => None,
+            Err(_) => break,
+        };
+        stall.check(&*pb)?;
+        let Some(stat) = stat else {
+            pb.tick();
+            continue;
+        };
+        match stat {
+            StatusUpdate::Size(v) => {
+                // The total covers every destination still being
+                // written.
+                size += v;
+                let live = status.iter().filter(|s| !s.failed).count() as u64;
+                pb.inc_size(v * live);
+                pb.dests_inc_size(v);
+            }
+            StatusUpdate::DestCopied { dest, bytes } => {
+                status[dest].written += bytes;
+                pb.inc(bytes);
+                pb.dest_inc(dest, bytes);
+                stall.progress(&*pb);
+            }
+            StatusUpdate::DestError { dest, error } => {
+                logging::error_event(&error);
+                pb.error(&error);
+                let s = &mut status[dest];
+                if !opts.continue_on_error {
+                    s.failed = true;
+                    pb.adjust_size(s.written as i64 - size as i64);
+                    pb.dest_failed(dest);
+                }
+                s.errors.push(error);
+            }
+            StatusUpdate::FileStarted(id, path) => {
+                pb.file_started(id, &path);
+                stall.file_started(id, path, &*pb);
+            }
+            StatusUpdate::FileCompleted(id, _) => {
+                pb.file_completed(id);
+                stall.file_completed(id, &*pb);
+            }
+            StatusUpdate::Skipped { .. } => skipped += 1,
+            StatusUpdate::Error(e) if opts.continue_on_error => {
+                logging::error_event(&e);
+                pb.error(&e);
+                errors.push(e);
+            }
+            StatusUpdate::Error(e) => {
+                logging::error_event(&e);
+                pb.error(&e);
+                error!("Received error: {}", e);
+                return Err(e.into());
+            }
+            _ => {}
+        }
+    }
+
+    handle.join()
+        .map_err(|_| XcpError::CopyError("Error during copy operation".to_string()))??;
+    pb.end();
+
+    if skipped > 0 {
+        info!("Skipped {} existing destination files", skipped);
+    }
+    for (dest, s) in dests.iter().zip(&status) {
+        match s.errors.first() {
+            Some(e) if s.failed => error!("{:?}: failed after writing {}: {}", dest, HumanBytes(s.written), e),
+            Some(_) => warn!("{:?}: wrote {} with {} error(s)", dest, HumanBytes(s.written), s.errors.len()),
+            None => info!("{:?}: wrote {}", dest, HumanBytes(s.written)),
+        }
+    }
+    if !errors.is_empty() {
+        error!("Copy completed with {} error(s) reading the source:", errors.len());
+        for e in &errors {
+            error!("  {}", e);
+        }
+    }
+
+    // Errors reading the source leave every destination incomplete.
+    let incomplete = if errors.is_empty() {
+        status.iter().filter(|s| !s.errors.is_empty()).count()
+    } else {
+        dests.len()
+    };
+    if incomplete > 0 {
+        return Err(XcpError::CopyError(
+            format!("{} of {} destinations incomplete", incomplete, dests.len())).into());
+    }
+    info!("Copy complete");
+    Ok(())
+}
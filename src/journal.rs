@@ -0,0 +1,238 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The operation journal written with '--journal': one JSON record
+//! per line for each change made to the destination, each skipped
+//! file and each error. Records are appended as the status updates
+//! arrive and flushed periodically, so after a crash the journal is
+//! complete up to the last flush, though the final line may be
+//! partial.
+//!
+//! Each run starts with a record of its working directory, which
+//! relative paths in the records that follow are relative to, and a
+//! fingerprint of the options that affect what is copied. Skipped
+//! files and errors record their source, so they can be copied again
+//! with '--replay'; see [crate::replay].
+
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use libxcp::checksum::{ChecksumType, Hasher};
+use libxcp::config::Config;
+use libxcp::errors::Result;
+use libxcp::feedback::{Action, CopyMethod, StatusUpdate};
+use libxcp::rescue::format_ranges;
+use log::warn;
+use serde::Serialize;
+
+/// How often buffered records are written out.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+enum Entry<'a> {
+    Started {
+        cwd: PathBuf,
+        fingerprint: String,
+    },
+    Copied {
+        from: &'a Path,
+        to: &'a Path,
+        bytes: u64,
+        method: CopyMethod,
+    },
+    Mkdir {
+        path: &'a Path,
+    },
+    Symlink {
+        from: &'a Path,
+        to: &'a Path,
+    },
+    Special {
+        from: &'a Path,
+        to: &'a Path,
+    },
+    Renamed {
+        from: &'a Path,
+        to: &'a Path,
+    },
+    KeptBoth {
+        from: &'a Path,
+        to: &'a Path,
+    },
+    Skipped {
+        path: &'a Path,
+        reason: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        source: Option<&'a Path>,
+        /// The size of the source.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        size: Option<u64>,
+    },
+    Degraded {
+        path: &'a Path,
+        detail: &'a str,
+    },
+    Deleted {
+        path: &'a Path,
+    },
+    Damaged {
+        path: &'a Path,
+        bad_ranges: String,
+    },
+    Error {
+        error: &'static str,
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        source: Option<&'a Path>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        dest: Option<&'a Path>,
+        /// The size of the source when the error was recorded.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        size: Option<u64>,
+    },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    /// Seconds since the Unix epoch.
+    time: f64,
+    #[serde(flatten)]
+    entry: Entry<'a>,
+}
+
+pub struct Journal {
+    out: BufWriter<File>,
+    flushed: Instant,
+}
+
+impl Journal {
+    /// Open the journal for appending, creating it if necessary, and
+    /// record the start of a run with `config`.
+    pub fn open(path: &Path, config: &Config) -> Result<Journal> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let mut journal = Journal {
+            out: BufWriter::new(file),
+            flushed: Instant::now(),
+        };
+        journal.write(Entry::Started {
+            cwd: env::current_dir()?,
+            fingerprint: fingerprint(config),
+        })?;
+        Ok(journal)
+    }
+
+    /// Append a record for the update, if it is journalled.
+    pub fn record(&mut self, update: &StatusUpdate) -> Result<()> {
+        match entry(update) {
+            Some(entry) => self.write(entry),
+            None => Ok(()),
+        }
+    }
+
+    fn write(&mut self, entry: Entry<'_>) -> Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64());
+        // Written as a single line, so a crash can at most truncate
+        // the last record.
+        let mut line = serde_json::to_vec(&Record { time, entry })?;
+        line.push(b'\n');
+        self.out.write_all(&line)?;
+        if self.flushed.elapsed() >= FLUSH_INTERVAL {
+            self.out.flush()?;
+            self.flushed = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Write out any buffered records.
+    pub fn finish(mut self) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        if let Err(e) = self.out.flush() {
+            warn!("Failed to write journal: {}", e);
+        }
+    }
+}
+
+fn entry(update: &StatusUpdate) -> Option<Entry<'_>> {
+    let entry = match update {
+        StatusUpdate::Action(Action::FileCopied { from, to, bytes, method }) => Entry::Copied {
+            from,
+            to,
+            bytes: *bytes,
+            method: *method,
+        },
+        StatusUpdate::Action(Action::DirCreated(path)) => Entry::Mkdir { path },
+        StatusUpdate::Action(Action::SymlinkCreated { from, to }) => Entry::Symlink { from, to },
+        StatusUpdate::Action(Action::SpecialCreated { from, to }) => Entry::Special { from, to },
+        StatusUpdate::Action(Action::UpToDate(path)) => Entry::Skipped { path, reason: "up-to-date", source: None, size: None },
+        StatusUpdate::Action(Action::MetadataDegraded { path, detail }) => Entry::Degraded { path, detail },
+        StatusUpdate::Action(Action::Deleted(path)) => Entry::Deleted { path },
+        StatusUpdate::Renamed { from, to } => Entry::Renamed { from, to },
+        StatusUpdate::KeptBoth { from, to } => Entry::KeptBoth { from, to },
+        StatusUpdate::Skipped { path, bytes, source } => Entry::Skipped {
+            path,
+            reason: "exists",
+            source: source.as_deref(),
+            size: source.as_ref().map(|_| *bytes),
+        },
+        StatusUpdate::NameSkipped(path) => Entry::Skipped { path, reason: "invalid-name", source: None, size: None },
+        StatusUpdate::BadRanges { path, ranges } => Entry::Damaged { path, bad_ranges: format_ranges(ranges) },
+        StatusUpdate::Error(e) => Entry::Error {
+            error: e.code(),
+            message: e.to_string(),
+            source: e.source_path(),
+            dest: e.dest_path(),
+            size: e.source_path()
+                .and_then(|p| p.symlink_metadata().ok())
+                .map(|m| m.len()),
+        },
+        _ => return None,
+    };
+    Some(entry)
+}
+
+/// A digest of the options that decide what a copy writes, so a
+/// replay can tell if they have changed. Options that only affect
+/// how the data is copied, such as the number of workers, are left
+/// out.
+pub fn fingerprint(config: &Config) -> String {
+    let options = format!(
+        "{:?}",
+        (
+            (config.no_clobber, &config.conflict_suffix, config.update, config.forbid_overwrite_newer, config.skip_same),
+            (config.preserve, config.preserve_mode, config.no_dir_timestamps, &config.chmod, &config.chown),
+            (config.dereference, config.dereference_sources, config.follow_dest_symlinks),
+            (config.reflink, &config.backup, config.sparse, config.fill_errors, config.invalid_name, config.stamp_checksum),
+        ),
+    );
+    let mut hasher = Hasher::new(ChecksumType::Blake3);
+    hasher.update(options.as_bytes());
+    hasher.finalize()[..16].to_string()
+}
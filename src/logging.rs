@@ -0,0 +1,289 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Log output for `--log-target`. The terminal is handled by
+//! simplelog; syslog and journald are written to directly over their
+//! local sockets, so no extra dependencies are needed.
+
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::fd::AsFd;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::result;
+use std::str::FromStr;
+
+use libxcp::errors::{unexpected_value, Result, XcpError};
+use log::kv::{self, Key, Value, VisitSource};
+use log::{error, Level, LevelFilter, Log, Metadata, Record};
+use simplelog::{
+    ColorChoice, CombinedLogger, Config, ConfigBuilder, SharedLogger, TermLogger, TerminalMode,
+    WriteLogger,
+};
+
+use crate::color;
+use crate::options::Opts;
+
+/// The log target for errors mirrored from the copy status channel.
+/// These are not shown on the terminal, which reports errors itself.
+pub const EVENT_TARGET: &str = "xcp::event";
+
+const SYSLOG_SOCKETS: [&str; 3] = ["/dev/log", "/var/run/syslog", "/var/run/log"];
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+const IDENTIFIER: &str = "xcp";
+
+/// Where log messages are written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogTarget {
+    /// journald when stderr is connected to the journal, otherwise
+    /// stderr.
+    Auto,
+    Stderr,
+    Syslog,
+    Journald,
+    /// Append timestamped lines to a file.
+    File(PathBuf),
+}
+
+impl FromStr for LogTarget {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("file:") {
+            if path.is_empty() {
+                return Err(XcpError::InvalidArguments("'file:' log target requires a path".to_string()));
+            }
+            return Ok(LogTarget::File(PathBuf::from(path)));
+        }
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(LogTarget::Auto),
+            "stderr" => Ok(LogTarget::Stderr),
+            "syslog" => Ok(LogTarget::Syslog),
+            "journald" => Ok(LogTarget::Journald),
+            _ => Err(unexpected_value("log-target", s, &["auto", "stderr", "syslog", "journald", "file:PATH"])),
+        }
+    }
+}
+
+/// Install the logger for the selected targets.
+pub fn init(opts: &Opts) -> Result<()> {
+    let level = opts.log_level();
+    let mut loggers: Vec<Box<dyn SharedLogger>> = Vec::new();
+
+    for target in &opts.log_target {
+        let target = match target {
+            LogTarget::Auto if stderr_is_journal() => &LogTarget::Journald,
+            LogTarget::Auto => &LogTarget::Stderr,
+            t => t,
+        };
+        match target {
+            LogTarget::Auto | LogTarget::Stderr => {
+                let config = ConfigBuilder::new()
+                    .add_filter_ignore_str(EVENT_TARGET)
+                    .build();
+                // stdout may be the copy destination.
+                let (mode, color) = if opts.writes_stdout() {
+                    (TerminalMode::Stderr, color::enabled(opts, &io::stderr()))
+                } else {
+                    (TerminalMode::Mixed, color::enabled(opts, &io::stderr()) && color::enabled(opts, &io::stdout()))
+                };
+                let color = if color { ColorChoice::Always } else { ColorChoice::Never };
+                loggers.push(TermLogger::new(level, config, mode, color));
+            }
+            LogTarget::Syslog => loggers.push(Box::new(SocketLogger::syslog(level)?)),
+            LogTarget::Journald => loggers.push(Box::new(SocketLogger::journald(level)?)),
+            LogTarget::File(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?;
+                let mut config = ConfigBuilder::new();
+                let _ = config.set_time_offset_to_local();
+                loggers.push(WriteLogger::new(level, config.set_time_format_rfc3339().build(), file));
+            }
+        }
+    }
+
+    CombinedLogger::init(loggers)?;
+    Ok(())
+}
+
+/// Mirror an error from the copy status channel to the non-terminal
+/// targets, with the error kind and paths as structured fields.
+pub fn error_event(err: &XcpError) {
+    let source = err.source_path().map(Path::display);
+    let dest = err.dest_path().map(Path::display);
+    error!(target: EVENT_TARGET,
+           error = err.code(),
+           source:% = OptDisplay(source),
+           dest:% = OptDisplay(dest);
+           "{}", err);
+}
+
+// Display wrapper that renders `None` as an empty string; empty
+// fields are omitted from journald records.
+struct OptDisplay<T>(Option<T>);
+
+impl<T: std::fmt::Display> std::fmt::Display for OptDisplay<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(ref v) => v.fmt(f),
+            None => Ok(()),
+        }
+    }
+}
+
+// systemd sets JOURNAL_STREAM to the device and inode of the stream
+// when stderr is connected to the journal.
+fn stderr_is_journal() -> bool {
+    let Ok(stream) = env::var("JOURNAL_STREAM") else {
+        return false;
+    };
+    let meta = io::stderr().as_fd()
+        .try_clone_to_owned()
+        .map(File::from)
+        .and_then(|f| f.metadata());
+    match (stream.split_once(':'), meta) {
+        (Some((dev, ino)), Ok(meta)) => {
+            dev.parse() == Ok(meta.dev()) && ino.parse() == Ok(meta.ino())
+        }
+        _ => false,
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Protocol {
+    Syslog,
+    Journald,
+}
+
+// A logger sending one datagram per record to a local syslog or
+// journald socket. Send failures are ignored, as there is nowhere
+// else to report them.
+struct SocketLogger {
+    protocol: Protocol,
+    level: LevelFilter,
+    socket: UnixDatagram,
+}
+
+impl SocketLogger {
+    fn syslog(level: LevelFilter) -> Result<SocketLogger> {
+        let socket = UnixDatagram::unbound()?;
+        SYSLOG_SOCKETS.iter()
+            .find(|path| socket.connect(path).is_ok())
+            .ok_or_else(|| XcpError::InvalidArguments("No syslog socket found".to_string()))?;
+        Ok(SocketLogger { protocol: Protocol::Syslog, level, socket })
+    }
+
+    fn journald(level: LevelFilter) -> Result<SocketLogger> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNALD_SOCKET)
+            .map_err(|e| XcpError::InvalidArguments(format!("Cannot connect to journald: {}", e)))?;
+        Ok(SocketLogger { protocol: Protocol::Journald, level, socket })
+    }
+}
+
+// Syslog severity; journald uses the same values for PRIORITY.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+// RFC 3164 format as written by syslog(3), less the timestamp which
+// the local daemon adds. Facility is 'user'.
+fn syslog_message(record: &Record) -> Vec<u8> {
+    let pri = 8 + severity(record.level());
+    format!("<{}>{}[{}]: {}", pri, IDENTIFIER, process::id(), record.args()).into_bytes()
+}
+
+// The journald native protocol; newline-separated KEY=value fields,
+// with a length-prefixed form for values containing newlines.
+fn journald_message(record: &Record) -> Vec<u8> {
+    let mut buf = Vec::new();
+    push_field(&mut buf, "MESSAGE", &record.args().to_string());
+    push_field(&mut buf, "PRIORITY", &severity(record.level()).to_string());
+    push_field(&mut buf, "SYSLOG_IDENTIFIER", IDENTIFIER);
+    push_field(&mut buf, "SYSLOG_PID", &process::id().to_string());
+    let _ = record.key_values().visit(&mut JournalFields(&mut buf));
+    buf
+}
+
+fn push_field(buf: &mut Vec<u8>, key: &str, value: &str) {
+    buf.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
+
+// Structured fields are added as XCP_<KEY>.
+struct JournalFields<'a>(&'a mut Vec<u8>);
+
+impl<'kvs> VisitSource<'kvs> for JournalFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> result::Result<(), kv::Error> {
+        let value = value.to_string();
+        if !value.is_empty() {
+            let key = format!("XCP_{}", key.as_str().to_uppercase());
+            push_field(self.0, &key, &value);
+        }
+        Ok(())
+    }
+}
+
+impl Log for SocketLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let msg = match self.protocol {
+            Protocol::Syslog => syslog_message(record),
+            Protocol::Journald => journald_message(record),
+        };
+        let _ = self.socket.send(&msg);
+    }
+
+    fn flush(&self) {}
+}
+
+impl SharedLogger for SocketLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        self
+    }
+}
+
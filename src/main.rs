@@ -14,53 +14,71 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+mod color;
+mod compare;
+mod confirm;
+mod fanout;
+mod journal;
+mod logging;
+mod metrics;
 mod options;
 mod progress;
-
-use std::path::PathBuf;
-use std::{result, thread};
+mod replay;
+mod stall;
+mod stats;
+mod stream;
+#[cfg(feature = "tracing")]
+mod trace;
+mod verify;
+
+use std::collections::HashSet;
+use std::fs::{self, File, Metadata};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::thread;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use glob::{glob, Paths};
+use indicatif::HumanBytes;
+use libfs::{device_model, device_size, same_inode, SameFile};
 use libxcp::config::{Config, Reflink};
 use libxcp::drivers::load_driver;
 use libxcp::errors::{Result, XcpError};
 use libxcp::feedback::{ChannelUpdater, StatusUpdate, StatusUpdater};
-use log::{error, info, warn};
-
-use crate::options::Opts;
-
-fn init_logging(opts: &Opts) -> Result<()> {
-    use simplelog::{ColorChoice, Config, SimpleLogger, TermLogger, TerminalMode};
-
-    TermLogger::init(
-        opts.log_level(),
-        Config::default(),
-        TerminalMode::Mixed,
-        ColorChoice::Auto,
-    ).or_else(
-        |_| SimpleLogger::init(opts.log_level(), Config::default())
-    )?;
-
-    Ok(())
-}
-
-// Expand a list of file-paths or glob-patterns into a list of concrete paths.
+use libxcp::lock::DestLock;
+use libxcp::manifest::Manifest;
+use libxcp::metrics::Metrics;
+use libxcp::operations::copy_byte_range;
+use libxcp::paths::{dest_names, normalize_dest, normalize_dest_entry};
+use libxcp::pattern;
+use libxcp::profile::DestCaps;
+use libxcp::rescue::format_ranges;
+use libxcp::results::CopyStats;
+use libxcp::space::Allocation;
+use log::{debug, error, info, log, log_enabled, warn, Level};
+
+use crate::journal::Journal;
+use crate::metrics::Sampler;
+use crate::options::{Opts, COMPARE_ERROR, ERROR_LIMIT_ERROR, TIMEOUT_ERROR, USAGE_ERROR};
+use crate::stall::StallMonitor;
+use crate::stats::DeviceStats;
+
+/// How often per-device statistics are logged at debug level.
+const DEVICE_LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often to check for stalls while waiting for status updates.
+const STALL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Expand a list of file-paths or glob-patterns into a list of concrete
+// paths; those of each pattern are sorted. See libxcp::pattern.
 // FIXME: This currently eats non-existent files that are not
 // globs. Should we convert empty glob results into errors?
 fn expand_globs(patterns: &[String]) -> Result<Vec<PathBuf>> {
-    let paths = patterns.iter()
-        .map(|s| glob(s.as_str()))
-        .collect::<result::Result<Vec<Paths>, _>>()?
-        .iter_mut()
-        // Force resolve each glob Paths iterator into a vector of the results...
-        .map::<result::Result<Vec<PathBuf>, _>, _>(Iterator::collect)
-        // And lift all the results up to the top.
-        .collect::<result::Result<Vec<Vec<PathBuf>>, _>>()?
-        .iter()
-        .flat_map(ToOwned::to_owned)
-        .collect::<Vec<PathBuf>>();
-
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        paths.extend(pattern::expand(pattern)?);
+    }
     Ok(paths)
 }
 
@@ -75,22 +93,208 @@ fn expand_sources(source_list: &[String], opts: &Opts) -> Result<Vec<PathBuf>> {
     }
 }
 
+// Remove sources that refer to the same file, e.g. from overlapping
+// globs or hard-links. The first occurrence is kept. Unless symlinked
+// sources are followed a symlink is copied as itself, so isn't a
+// duplicate of its target.
+fn dedup_sources(sources: Vec<PathBuf>, opts: &Opts) -> Vec<PathBuf> {
+    let mut seen_paths = HashSet::new();
+    let mut seen_inodes = HashSet::new();
+
+    sources.into_iter()
+        .filter(|source| {
+            let path = resolve_source(source, opts.follow_sources())
+                .unwrap_or_else(|_| source.clone());
+            let inode = source_metadata(source, opts)
+                .map(|m| (m.dev(), m.ino()))
+                .ok();
+            let unique = seen_paths.insert(path)
+                & inode.map_or(true, |i| seen_inodes.insert(i));
+            if !unique {
+                debug!("Dropping duplicate source {:?}", source);
+            }
+            unique
+        })
+        .collect()
+}
+
 fn opts_check(opts: &Opts) -> Result<()> {
     #[cfg(any(target_os = "linux", target_os = "android"))]
     if opts.reflink == Reflink::Never {
         warn!("--reflink=never is selected, however the Linux kernel may override this.");
     }
 
-    if opts.no_clobber && opts.force {
-        return Err(XcpError::InvalidArguments("--force and --noclobber cannot be set at the same time.".to_string()).into());
+    opts.check_conflicts()?;
+    Ok(())
+}
+
+// Resolve a source path for comparison with the normalized
+// destination. Unless followed, a symlinked source is copied as a
+// link so only its parent is resolved.
+fn resolve_source(source: &Path, follow: bool) -> Result<PathBuf> {
+    match (source.parent(), source.file_name()) {
+        (Some(parent), Some(name)) if !follow => {
+            let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+            Ok(parent.canonicalize()?.join(name))
+        }
+        _ => Ok(source.canonicalize()?),
+    }
+}
+
+// The metadata of a source, following it if it is a symlink and
+// symlinked sources are followed; see [Opts::follow_sources].
+fn source_metadata(source: &Path, opts: &Opts) -> Result<Metadata> {
+    let meta = if opts.follow_sources() {
+        source.metadata()
+    } else {
+        source.symlink_metadata()
+    };
+    meta.map_err(|_| XcpError::SourceNotFound(source.to_path_buf()).into())
+}
+
+fn source_is_dir(source: &Path, opts: &Opts) -> bool {
+    source_metadata(source, opts).is_ok_and(|m| m.is_dir())
+}
+
+// Apply the '--profile' tuning for the destination's filesystem,
+// keeping an explicit worker count. The block size is only changed
+// if it wasn't given.
+fn tune_for_dest(config: &mut Config, dest: &Path, opts: &Opts) {
+    let caps = match DestCaps::probe(dest) {
+        Ok(caps) => caps,
+        Err(e) => {
+            debug!("Not tuning the copy; can't probe {:?}: {}", dest, e);
+            return;
+        }
+    };
+    debug!("Destination {:?}: {:?}", dest, caps);
+    if let Some(mut tuning) = opts.profile.tuning(&caps) {
+        if opts.workers.is_some() {
+            tuning.workers = None;
+        }
+        info!("Tuning the copy for a network destination: {}", tuning);
+        tuning.apply(config);
+    }
+}
+
+// Check a destination symlink given with '-T', which as with cp is
+// the entry copied over rather than a directory to copy into.
+// Replacing it with a directory, or with a file when it points at a
+// directory, needs '--remove-destination'; otherwise a file is written
+// through it as usual. Returns whether the link is to be removed
+// before the copy.
+fn check_dest_link(dest: &Path, source: &Path, opts: &Opts) -> Result<bool> {
+    let dir_source = source_is_dir(source, opts);
+    if !dir_source && !dest.is_dir() {
+        return Ok(false);
+    }
+    if !opts.remove_destination {
+        return Err(XcpError::InvalidDestination {
+            path: dest.to_path_buf(),
+            reason: if dir_source {
+                "Cannot replace a symlink with a directory without --remove-destination."
+            } else {
+                "Cannot replace a symlink to a directory with a file without --remove-destination."
+            },
+        }.into());
     }
+    Ok(true)
+}
+
+// Print the model and size of a block device before imaging it, as a
+// last check that it is the intended device.
+fn describe_device(source: &Path) -> Result<()> {
+    let fd = File::open(source)?;
+    let size = device_size(&fd)?;
+    let model = device_model(&fd).unwrap_or_else(|| "unknown model".to_string());
+    eprintln!("Imaging {:?}: {}, {}", source, model, HumanBytes(size));
     Ok(())
 }
 
-fn main() -> Result<()> {
-    let opts = Opts::from_args()?;
-    init_logging(&opts)?;
-    opts_check(&opts)?;
+// Lock the destination for the copy; see '--no-lock' and
+// '--wait-lock'. If the lock can't be created, e.g. because the
+// destination is read-only, the copy goes ahead without it.
+fn lock_dest(dest: &Path, opts: &Opts) -> Result<Option<DestLock>> {
+    if opts.no_lock || opts.dry_run {
+        return Ok(None);
+    }
+    let wait = match opts.wait_lock {
+        None => Duration::ZERO,
+        Some(None) => Duration::MAX,
+        Some(Some(timeout)) => timeout,
+    };
+    match DestLock::acquire(dest, wait) {
+        Ok(lock) => Ok(Some(lock)),
+        Err(e) if matches!(e.downcast_ref(), Some(XcpError::DestinationLocked { .. })) => Err(e),
+        Err(e) => {
+            warn!("Not locking {:?}: {}", dest, e);
+            Ok(None)
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let opts = match Opts::from_args() {
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            return match e.downcast_ref::<XcpError>() {
+                Some(err) if err.is_usage() => ExitCode::from(USAGE_ERROR),
+                _ => ExitCode::FAILURE,
+            };
+        }
+    };
+    let result = run(&opts);
+    let status = match result {
+        Ok(()) => 0,
+        Err(ref e) => {
+            // Matches the report of an error returned from main, unless
+            // fully quiet.
+            if opts.quiet < 2 {
+                eprintln!("Error: {:?}", e);
+            }
+            match e.downcast_ref::<XcpError>() {
+                Some(err) if err.is_usage() => USAGE_ERROR,
+                Some(XcpError::TreesDiffer { .. }) => 1,
+                Some(XcpError::TimedOut(_)) => TIMEOUT_ERROR,
+                Some(XcpError::TooManyErrors(_) | XcpError::ErrorRateExceeded { .. }) => ERROR_LIMIT_ERROR,
+                _ if opts.compare_only => COMPARE_ERROR,
+                _ => 1,
+            }
+        }
+    };
+    progress::summary(&opts, &result, status);
+    ExitCode::from(status)
+}
+
+fn run(opts: &Opts) -> Result<()> {
+    #[cfg(feature = "tracing")]
+    let _trace_guard = match opts.trace_out {
+        Some(ref path) => Some(trace::init(opts, path)?),
+        None => {
+            logging::init(opts)?;
+            None
+        }
+    };
+    #[cfg(not(feature = "tracing"))]
+    logging::init(opts)?;
+    opts_check(opts)?;
+
+    if opts.verify {
+        return verify::verify(opts);
+    }
+    if opts.uses_stdio() {
+        return stream::stream(opts);
+    }
+    if opts.compare_only {
+        return compare::compare(opts);
+    }
+    if opts.fanout {
+        return fanout::fanout(opts);
+    }
+    if let Some(ref journal) = opts.replay {
+        return replay::replay(opts, journal);
+    }
 
     let (dest, source_patterns) = match opts.target_directory {
         Some(ref d) => { (d, opts.paths.as_slice()) }
@@ -98,87 +302,421 @@ fn main() -> Result<()> {
             opts.paths.split_last().ok_or(XcpError::InvalidArguments("Insufficient arguments".to_string()))?
         }
     };
-    let dest = PathBuf::from(dest);
+    // Safety checks and the copy use the resolved destination, so
+    // that symlinks and '..' can't hide where files will be written.
+    // With '-T' a symlink given as the destination is itself replaced,
+    // so isn't followed.
+    let dest = if opts.no_target_directory {
+        normalize_dest_entry(Path::new(dest), opts.allow_dotdot_dest)?
+    } else {
+        normalize_dest(Path::new(dest), opts.allow_dotdot_dest)?
+    };
 
-    let sources = expand_sources(source_patterns, &opts)?;
+    let sources = dedup_sources(expand_sources(source_patterns, opts)?, opts);
+    let replace_link = match sources.first() {
+        Some(source) if dest.is_symlink() => check_dest_link(&dest, source, opts)?,
+        _ => false,
+    };
     if sources.is_empty() {
-        return Err(XcpError::InvalidSource("No source files found.").into());
-    } else if !dest.is_dir() {
-        if sources.len() == 1 && sources[0].is_dir() && dest.exists() {
-            return Err(XcpError::InvalidDestination("Cannot copy a directory to a file.").into());
+        return Err(XcpError::NoSources.into());
+    } else if !dest.is_dir() && !opts.dest_subdir_from_source {
+        if sources.len() == 1 && source_is_dir(&sources[0], opts) && dest.exists() {
+            return Err(XcpError::InvalidDestination {
+                path: dest,
+                reason: "Cannot copy a directory to a file.",
+            }.into());
         } else if sources.len() > 1 {
-            return Err(XcpError::InvalidDestination("Multiple sources and destination is not a directory.").into());
+            return Err(XcpError::InvalidDestination {
+                path: dest,
+                reason: "Multiple sources and destination is not a directory.",
+            }.into());
         }
     }
 
-    // Sanity-check all sources up-front
-    for source in &sources {
+    let mut config = Config::from(opts);
+    // The result of a single file is summarised at the end.
+    config.collect_results = sources.len() == 1 && !source_is_dir(&sources[0], opts);
+    tune_for_dest(&mut config, &dest, opts);
+    let config = Arc::new(config);
+
+    // Sanity-check all sources up-front. These checks are by path, so
+    // only fail fast with a clear error; anything may change before the
+    // copy starts. The driver checks the files it opens again, with
+    // the same errors, and those checks are the ones relied on.
+    let names = dest_names(&sources, &dest, &config)?;
+    let mut targets = Vec::with_capacity(sources.len());
+    for (source, name) in sources.iter().zip(names) {
         info!("Copying source {:?} to {:?}", source, dest);
-        if !source.exists() {
-            return Err(XcpError::InvalidSource("Source does not exist.").into());
+        if source_metadata(source, opts)?.is_dir() && !opts.recursive {
+            return Err(XcpError::InvalidSource {
+                path: source.clone(),
+                reason: "Source is directory and --recursive not specified.",
+            }.into());
         }
-
-        if source.is_dir() && !opts.recursive {
-            return Err(XcpError::InvalidSource("Source is directory and --recursive not specified.").into());
+        let follow = opts.follow_sources();
+        let resolved = resolve_source(source, follow)?;
+        if resolved == dest {
+            return Err(XcpError::InvalidSource {
+                path: source.clone(),
+                reason: "Cannot copy a directory into itself",
+            }.into());
         }
-        if source == &dest {
-            return Err(XcpError::InvalidSource("Cannot copy a directory into itself").into());
+        if opts.quiet == 0 && resolved.symlink_metadata()?.file_type().is_block_device() {
+            describe_device(&resolved)?;
         }
 
-        let sourcedir = source
-            .components()
-            .last()
-            .ok_or(XcpError::InvalidSource("Failed to find source directory name."))?;
+        let target_base = name.map_or_else(|| dest.clone(), |n| dest.join(n));
 
-        let target_base = if dest.exists() && dest.is_dir() && !opts.no_target_directory {
-            dest.join(sourcedir)
-        } else {
-            dest.to_path_buf()
-        };
-
-        if source == &target_base {
-            return Err(XcpError::InvalidSource("Source is same as destination").into());
+        // A symlinked source copied as a link is not the same file as
+        // its target.
+        if resolved == target_base || same_inode(source, &target_base, follow)? == SameFile::Same {
+            return Err(XcpError::InvalidSource {
+                path: source.clone(),
+                reason: "Source is same as destination",
+            }.into());
+        }
+        // The destination is resolved, so this includes symlinks into
+        // the source; the walk would otherwise copy the copy.
+        if source_is_dir(source, opts) && target_base.starts_with(&resolved) {
+            return Err(XcpError::InvalidSource {
+                path: source.clone(),
+                reason: "Cannot copy a directory into itself",
+            }.into());
         }
+        targets.push(target_base);
+    }
+    // Small files may take far more space on a destination with a
+    // much larger allocation unit than the sources.
+    let dest_space = confirm::coarse_dest(&dest, &sources);
+    let space_hazard = match dest_space {
+        Some(ref space) => confirm::check_space(&dest, &sources, space, &config)?,
+        None => None,
+    };
+    // Nothing is written by a dry run.
+    if !(opts.yes || opts.force || opts.dry_run) {
+        let mut hazards = confirm::check(&dest, &targets, opts);
+        hazards.extend(space_hazard);
+        confirm::confirm(&hazards)?;
+    } else if let Some(hazard) = space_hazard {
+        warn!("{}", hazard);
+    }
+    let _lock = lock_dest(&dest, opts)?;
+    if replace_link && !opts.dry_run {
+        debug!("Removing destination symlink {:?}", dest);
+        fs::remove_file(&dest)?;
     }
 
 
     // ========== Start copy ============
 
-    let config = Arc::new(Config::from(&opts));
+    // Manifest paths are relative to the directory the files end up
+    // in; for a single file copied to a file this is its parent.
+    let manifest = opts.manifest.as_ref().map(|_| {
+        let root = if dest.is_dir() || opts.dest_subdir_from_source || (sources.len() == 1 && source_is_dir(&sources[0], opts)) {
+            dest.clone()
+        } else {
+            dest.parent()
+                .filter(|p| !p.as_os_str().is_empty())
+                .unwrap_or(Path::new("."))
+                .to_path_buf()
+        };
+        Manifest::new(&root, opts.manifest_hash)
+    });
+
     let driver = load_driver(opts.driver, &config)?;
+    // Range copies don't use the driver's workers.
+    let metrics = opts.byte_range().is_none().then(|| driver.metrics());
+    let copy: CopyTask = match opts.byte_range() {
+        Some(range) => {
+            if sources.len() != 1 || !sources[0].is_file() {
+                return Err(XcpError::InvalidSource {
+                    path: sources[0].clone(),
+                    reason: "Range copies require a single regular source file.",
+                }.into());
+            }
+            let to = match sources[0].file_name() {
+                Some(name) if dest.is_dir() => dest.join(name),
+                _ => dest,
+            };
+            let config = config.clone();
+            Box::new(move |stats| {
+                copy_byte_range(&sources[0], &to, &range, &config, &stats)?;
+                Ok(CopyStats::default())
+            })
+        }
+        None => Box::new(move |stats| driver.copy(sources, &dest, stats)),
+    };
+    let allocation = dest_space.map(|s| Allocation::new(s.block_size));
+    run_copy(opts, &config, manifest, allocation, metrics, copy)
+}
 
-    let updater = ChannelUpdater::new(&config);
+/// A copy to run with [run_copy], sending its progress to the
+/// updater it is given and returning the files it copied.
+type CopyTask = Box<dyn FnOnce(Arc<dyn StatusUpdater>) -> Result<CopyStats> + Send>;
+
+/// Run a copy on its own thread, reporting its progress and outcome,
+/// and recording it in any manifest and journal. With an
+/// [Allocation] the space the copied files take is estimated, and
+/// reported if much more than their size. Any [Metrics] of the copy
+/// are reported with '--metrics-interval'.
+fn run_copy(opts: &Opts, config: &Arc<Config>, mut manifest: Option<Manifest>,
+            mut allocation: Option<Allocation>, metrics: Option<Arc<Metrics>>, copy: CopyTask) -> Result<()>
+{
+    let mut journal = opts.journal.as_deref()
+        .map(|path| Journal::open(path, config))
+        .transpose()?;
+
+    let updater = ChannelUpdater::new(config);
     let stat_rx = updater.rx_channel();
     let stats: Arc<dyn StatusUpdater> = Arc::new(updater);
-
-    let handle = thread::spawn(move || -> Result<()> {
-        driver.copy(sources, &dest, stats)
-    });
+    let handle = thread::spawn(move || copy(stats));
 
 
     // ========== Collect output and display ============
 
-    let pb = progress::create_bar(&opts, 0)?;
+    let pb = progress::create_bar(opts, 0)?;
 
     // Gather the results as we go; our end of the channel has been
     // moved to the driver call and will end when drained.
-    for stat in stat_rx {
+    let mut errors = Vec::new();
+    let mut items = Vec::new();
+    let mut renamed = Vec::new();
+    let mut name_skipped = Vec::new();
+    let mut bad_ranges = Vec::new();
+    let mut kept = Vec::new();
+    let (mut files, mut reflinked, mut offloaded, mut skipped) = (0u64, 0u64, 0u64, 0u64);
+    let mut overwrote_newer = 0u64;
+    let mut range_cloned = 0u64;
+    let (mut duplicated, mut duplicated_bytes) = (0u64, 0u64);
+    let mut devstats = DeviceStats::default();
+    let mut last_devlog = Instant::now();
+    let mut stall = StallMonitor::new(opts);
+    let mut sampler = metrics.and_then(|m| Sampler::new(opts, m));
+    // Once the walk is complete and all data has been copied the
+    // remaining work is applying metadata, such as directory
+    // timestamps, which is shown by entry instead.
+    let (mut size, mut copied) = (0i64, 0u64);
+    // The bytes of each file as it completes, to check against the
+    // copied total.
+    let mut completed = 0u64;
+    let (mut walked, mut finalizing) = (false, false);
+    let (mut meta_total, mut meta_done) = (0u64, 0u64);
+    loop {
+        let stat = match stat_rx.recv_timeout(STALL_CHECK_INTERVAL) {
+            Ok(stat) => Some(stat),
+            Err(e) if e.is_timeout() => None,
+            Err(_) => break,
+        };
+        stall.check(&*pb)?;
+        if let Some(ref mut s) = sampler {
+            s.check(stat_rx.len(), &*pb);
+        }
+        let Some(stat) = stat else {
+            pb.tick();
+            continue;
+        };
+        if let Some(ref mut j) = journal {
+            j.record(&stat)?;
+        }
         match stat {
-            StatusUpdate::Copied(v) => pb.inc(v),
-            StatusUpdate::Size(v) => pb.inc_size(v),
+            StatusUpdate::Copied(v) => {
+                copied += v;
+                pb.inc(v);
+                stall.progress(&*pb);
+            }
+            StatusUpdate::Size(v) => {
+                size += v as i64;
+                pb.inc_size(v);
+            }
+            StatusUpdate::TotalAdjust(v) => {
+                size += v;
+                pb.adjust_size(v);
+            }
+            StatusUpdate::FileStarted(id, path) => {
+                files += 1;
+                meta_total += 1;
+                pb.file_started(id, &path);
+                stall.file_started(id, path, &*pb);
+            }
+            StatusUpdate::FileCompleted(id, bytes) => {
+                completed += bytes;
+                if let Some(ref mut a) = allocation {
+                    a.add(bytes);
+                }
+                meta_done += 1;
+                pb.file_completed(id);
+                stall.file_completed(id, &*pb);
+            }
+            StatusUpdate::MetadataPending(n) => meta_total += n,
+            StatusUpdate::MetadataDone(n) => meta_done += n,
+            StatusUpdate::WalkCompleted => walked = true,
+            StatusUpdate::Reflinked(_) => reflinked += 1,
+            StatusUpdate::RangeCloned(bytes) => range_cloned += bytes,
+            StatusUpdate::Offloaded(_) => offloaded += 1,
+            StatusUpdate::Skipped { .. } => skipped += 1,
+            StatusUpdate::OverwritingNewer { .. } => overwrote_newer += 1,
+            StatusUpdate::DeviceCopied { source, dest, bytes, elapsed } => {
+                devstats.add(source, dest, bytes, elapsed);
+                if log_enabled!(Level::Debug) && last_devlog.elapsed() >= DEVICE_LOG_INTERVAL {
+                    debug!("Per-device progress:\n{}", devstats);
+                    last_devlog = Instant::now();
+                }
+            }
+            StatusUpdate::Checksum(c) => {
+                if let Some(ref mut m) = manifest {
+                    m.add(c);
+                }
+            }
+            StatusUpdate::Item(i) => items.push(i),
+            StatusUpdate::Renamed { from, to } => renamed.push((from, to)),
+            StatusUpdate::NameSkipped(path) => name_skipped.push(path),
+            StatusUpdate::BadRanges { path, ranges } => bad_ranges.push((path, ranges)),
+            StatusUpdate::KeptBoth { from, to } => kept.push((from, to)),
+            // Only used for the journal.
+            StatusUpdate::Action(_) => {}
+            StatusUpdate::Duplicated { bytes, .. } => {
+                duplicated += 1;
+                duplicated_bytes += bytes;
+            }
+            // Only sent by fan-out copies; see [fanout].
+            StatusUpdate::DestCopied { .. } | StatusUpdate::DestError { .. } => {}
+            StatusUpdate::Error(e) if opts.continue_on_error => {
+                logging::error_event(&e);
+                pb.error(&e);
+                errors.push(e);
+            }
             StatusUpdate::Error(e) => {
-                // FIXME: Optional continue?
+                logging::error_event(&e);
+                pb.error(&e);
                 error!("Received error: {}", e);
                 return Err(e.into());
             }
         }
+        if walked && copied as i64 >= size && (finalizing || meta_done < meta_total) {
+            if !finalizing {
+                info!("All data copied, applying metadata to {} remaining entries", meta_total - meta_done);
+                finalizing = true;
+            }
+            pb.finalizing(meta_done, meta_total);
+        }
     }
 
-    handle.join()
-        .map_err(|_| XcpError::CopyError("Error during copy operation".to_string()))??;
+    let totals = match handle.join().map_err(|_| XcpError::CopyError("Error during copy operation".to_string()))? {
+        Ok(totals) => totals,
+        Err(e) if matches!(e.downcast_ref(), Some(XcpError::TooManyErrors(_) | XcpError::ErrorRateExceeded { .. })) => {
+            pb.end();
+            error!("Copy stopped with {} error(s):", errors.len());
+            for e in &errors {
+                error!("  {}", e);
+            }
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+    // Range copies aren't reported as files.
+    if files > 0 && completed != copied {
+        warn!("Progress reported {} bytes copied, but the completed files total {} bytes", copied, completed);
+    }
+    if let Some(j) = journal {
+        j.finish()?;
+    }
+
+    items.sort_by(|a, b| a.path.cmp(&b.path));
+    for item in &items {
+        pb.item(item);
+    }
 
-    info!("Copy complete");
     pb.end();
 
+    if !renamed.is_empty() {
+        renamed.sort();
+        warn!("Renamed {} entries the destination filesystem cannot represent:", renamed.len());
+        for (from, to) in &renamed {
+            warn!("  {:?} -> {:?}", from, to);
+        }
+    }
+    if !name_skipped.is_empty() {
+        name_skipped.sort();
+        warn!("Skipped {} entries the destination filesystem cannot represent:", name_skipped.len());
+        for path in &name_skipped {
+            warn!("  {:?}", path);
+        }
+    }
+    if !kept.is_empty() {
+        kept.sort();
+        info!("Copied {} entries under new names to keep the existing destinations:", kept.len());
+        for (from, to) in &kept {
+            info!("  {:?} -> {:?}", from, to);
+        }
+    }
+    if !bad_ranges.is_empty() {
+        bad_ranges.sort_by(|a, b| a.0.cmp(&b.0));
+        warn!("Filled unreadable ranges of {} files with zeros:", bad_ranges.len());
+        for (path, ranges) in &bad_ranges {
+            warn!("  {:?}: {}", path, format_ranges(ranges));
+        }
+    }
+    for r in libfs::take_repeats() {
+        log!(r.level, "{}", r);
+    }
+
+    if let (Some(m), Some(path)) = (manifest.as_mut(), opts.manifest.as_ref()) {
+        for (from, to) in renamed {
+            m.add_renamed(from, to);
+        }
+        for (from, to) in kept {
+            m.add_renamed(from, to);
+        }
+        m.root = m.root.canonicalize()?;
+        info!("Writing manifest to {:?}", path);
+        m.write(path)?;
+    }
+
+    if !errors.is_empty() {
+        error!("Copy completed with {} error(s):", errors.len());
+        for e in &errors {
+            error!("  {}", e);
+        }
+        return Err(XcpError::CopyError(format!("{} error(s) during copy", errors.len())).into());
+    }
+
+    if !devstats.is_empty() {
+        info!("Per-device throughput:\n{}", devstats);
+    }
+    if skipped > 0 {
+        info!("Skipped {} existing destination files", skipped);
+    }
+    if overwrote_newer > 0 {
+        warn!("Overwrote {} destination files that were newer than the source", overwrote_newer);
+    }
+    if let Some(a) = allocation.filter(Allocation::is_significant) {
+        warn!("Copied {} files: logical {}, estimated on-disk {} due to the {} allocation unit",
+              a.files, HumanBytes(a.logical), HumanBytes(a.allocated), HumanBytes(a.block_size));
+    }
+    if duplicated > 0 {
+        warn!("Copied {} again for {} directories reached by more than one symlink",
+              HumanBytes(duplicated_bytes), duplicated);
+    }
+    if offloaded > 0 {
+        info!("Copied {} of {} files server-side", offloaded, files);
+    }
+    if files > 0 && opts.reflink != Reflink::Never {
+        info!("Reflinked {} of {} files ({}%)", reflinked, files, reflinked * 100 / files);
+    }
+    if range_cloned > 0 {
+        info!("Reflinked {} of the range", HumanBytes(range_cloned));
+    }
+    #[cfg(feature = "stats")]
+    debug!("Syscalls: {}", libfs::counters());
+    // Only kept for a single file; see [Config::collect_results].
+    if let [result] = totals.results.as_slice() {
+        info!("Copied {:?} to {:?}: {} by {} in {:.2?}",
+              result.from, result.to, HumanBytes(result.bytes), result.method, result.duration);
+        for detail in &result.degraded {
+            info!("  {}", detail);
+        }
+    }
+    info!("Copy complete");
+
     Ok(())
 }
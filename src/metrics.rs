@@ -0,0 +1,92 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Periodic reports of the copy workers' metrics with
+//! '--metrics-interval', for diagnosing slow copies; see
+//! [libxcp::metrics].
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use indicatif::HumanBytes;
+use libxcp::metrics::{Metrics, Snapshot, WorkerState};
+use serde::Serialize;
+
+use crate::options::Opts;
+use crate::progress::ProgressBar;
+
+/// A report of the metrics since the last one.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub reading: usize,
+    pub writing: usize,
+    pub idle: usize,
+    pub metadata: usize,
+    /// Operations waiting for a worker.
+    pub work_queue: u64,
+    /// Status updates waiting to be reported.
+    pub status_queue: usize,
+    /// Bytes per second of each worker.
+    pub rates: Vec<u64>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Workers: {} reading, {} writing, {} idle, {} metadata; work queue {}, status queue {}",
+               self.reading, self.writing, self.idle, self.metadata, self.work_queue, self.status_queue)?;
+        for (i, rate) in self.rates.iter().enumerate() {
+            write!(f, "{} {}/s", if i == 0 { "; rates" } else { "," }, HumanBytes(*rate))?;
+        }
+        Ok(())
+    }
+}
+
+/// Samples a driver's metrics every '--metrics-interval'.
+pub struct Sampler {
+    metrics: Arc<Metrics>,
+    interval: Duration,
+    last: Snapshot,
+}
+
+impl Sampler {
+    /// A sampler, if '--metrics-interval' is set.
+    pub fn new(opts: &Opts, metrics: Arc<Metrics>) -> Option<Sampler> {
+        let interval = Duration::from_secs(opts.metrics_interval?);
+        let last = metrics.snapshot();
+        Some(Sampler { metrics, interval, last })
+    }
+
+    /// Report the metrics if the interval has passed, with the
+    /// number of status updates waiting.
+    pub fn check(&mut self, status_queue: usize, pb: &dyn ProgressBar) {
+        if self.last.taken.elapsed() < self.interval {
+            return;
+        }
+        let now = self.metrics.snapshot();
+        let report = Report {
+            reading: now.count(WorkerState::Reading),
+            writing: now.count(WorkerState::Writing),
+            idle: now.count(WorkerState::Idle),
+            metadata: now.count(WorkerState::Metadata),
+            work_queue: now.queued,
+            status_queue,
+            rates: now.rates(&self.last).into_iter().map(|r| r as u64).collect(),
+        };
+        pb.metrics(&report);
+        self.last = now;
+    }
+}
@@ -14,28 +14,387 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
 use clap::{ArgAction, Parser};
+use libfs::{FsOps, RealFs};
 
-use libxcp::config::{Backup, Config, Reflink};
+use libxcp::checksum::ChecksumType;
+use libxcp::config::{Backup, Chmod, Chown, Config, DirMode, InvalidName, ModeChange, ModeClause, ModeOp, ModeTarget, NoClobber, Order, PreserveSet, Reflink, SkipSame};
+use libxcp::conflict::ConflictTemplate;
 use log::LevelFilter;
 use unbytify::unbytify;
 
 use libxcp::drivers::Drivers;
-use libxcp::errors::Result;
+use libxcp::errors::{unexpected_value, Result, XcpError};
+use libxcp::executor::Executor;
+use libxcp::filter::{read_patterns, Filter};
+use libxcp::operations::ByteRange;
+use libxcp::profile::Profile;
+
+use crate::color::ColorMode;
+use crate::logging::LogTarget;
+use crate::progress::ProgressMode;
+use crate::replay::ReplayFilter;
+
+/// Exit status for invalid arguments, the same as for the usage
+/// errors reported by clap.
+pub const USAGE_ERROR: u8 = 2;
+
+/// Exit status for errors during '--compare-only'; 1 means the trees
+/// differ.
+pub const COMPARE_ERROR: u8 = 2;
+
+/// Exit status when '--timeout' expires, as with timeout(1).
+pub const TIMEOUT_ERROR: u8 = 124;
+
+/// Exit status when '--max-errors' or '--max-error-rate' stops the
+/// copy.
+pub const ERROR_LIMIT_ERROR: u8 = 3;
+
+/// A source or destination path meaning stdin or stdout.
+pub const STDIO_PATH: &str = "-";
+
+// The block size when '--block-size' isn't given, and the minimum when
+// it is chosen from the preferred IO size.
+const DEFAULT_BLOCK_SIZE: u64 = 1024 * 1024;
+
+// A combination of options that can't be used together.
+struct Conflict {
+    flags: (&'static str, &'static str),
+    reason: &'static str,
+    applies: fn(&Opts) -> bool,
+}
+
+// Options that can't be used together, and why. Conflicts are listed
+// here rather than with clap so that the error can explain them.
+const CONFLICTS: &[Conflict] = &[
+    Conflict {
+        flags: ("--force", "--no-clobber"),
+        reason: "--force overwrites existing files, which --no-clobber prevents",
+        // As with cp, '-f' doesn't stop '-i' from asking.
+        applies: |o| o.force && o.no_clobber().is_some() && !o.interactive,
+    },
+    Conflict {
+        flags: ("--no-lock", "--wait-lock"),
+        reason: "--no-lock doesn't take the lock that --wait-lock waits for",
+        applies: |o| o.no_lock && o.wait_lock.is_some(),
+    },
+    Conflict {
+        flags: ("--quiet", "--verbose"),
+        reason: "--quiet hides the logging that --verbose adds",
+        applies: |o| o.quiet > 0 && o.verbose > 0,
+    },
+    Conflict {
+        flags: ("--no-target-directory", "--target-directory"),
+        reason: "the destination can't be both the copy and a directory to copy into",
+        applies: |o| o.no_target_directory && o.target_directory.is_some(),
+    },
+    Conflict {
+        flags: ("--no-target-directory", "--dest-subdir-from-source"),
+        reason: "--no-target-directory copies onto the destination rather than into subdirectories of it",
+        applies: |o| o.no_target_directory && o.dest_subdir_from_source,
+    },
+    Conflict {
+        flags: ("--follow-dest-symlinks", "--remove-destination"),
+        reason: "a destination symlink is either written through or replaced",
+        applies: |o| o.follow_dest_symlinks && o.remove_destination,
+    },
+    Conflict {
+        flags: ("--remove-destination", "--no-clobber"),
+        reason: "--remove-destination removes existing destination symlinks, which --no-clobber prevents",
+        applies: |o| o.remove_destination && o.no_clobber().is_some(),
+    },
+    Conflict {
+        flags: ("--conflict-suffix", "--no-clobber=skip/fail"),
+        reason: "--conflict-suffix names the copies kept with --no-clobber=rename",
+        applies: |o| o.conflict_suffix.is_some() && o.no_clobber.is_some_and(|m| m != NoClobber::Rename),
+    },
+    Conflict {
+        flags: ("--no-clobber=rename", "--staging-dir"),
+        reason: "staged files are renamed into place under their original name",
+        applies: |o| o.no_clobber() == Some(NoClobber::Rename) && o.staging_dir.is_some(),
+    },
+    Conflict {
+        flags: ("--no-clobber=rename", "--preserve=links"),
+        reason: "the name of a renamed copy to link to isn't known until it is created",
+        applies: |o| o.no_clobber() == Some(NoClobber::Rename) && o.preserve().contains(PreserveSet::LINKS),
+    },
+    Conflict {
+        flags: ("--no-clobber=rename", "-"),
+        reason: "streams are written directly to their destination",
+        applies: |o| o.no_clobber() == Some(NoClobber::Rename) && o.uses_stdio(),
+    },
+    Conflict {
+        flags: ("--no-clobber=rename", "--fanout"),
+        reason: "fan-out copies are written directly to each destination",
+        applies: |o| o.no_clobber() == Some(NoClobber::Rename) && o.fanout,
+    },
+    Conflict {
+        flags: ("--offset/--length/--dest-offset", "--recursive"),
+        reason: "byte ranges can only be copied from a single file",
+        applies: |o| o.byte_range().is_some() && o.recursive,
+    },
+    Conflict {
+        flags: ("-", "--recursive"),
+        reason: "stdin and stdout are streamed as a single file",
+        applies: |o| o.uses_stdio() && o.recursive,
+    },
+    Conflict {
+        flags: ("-", "--target-directory"),
+        reason: "stdin has no name to copy into a directory with",
+        applies: |o| o.uses_stdio() && o.target_directory.is_some(),
+    },
+    Conflict {
+        flags: ("-", "--offset/--length/--dest-offset"),
+        reason: "byte ranges require a seekable file",
+        applies: |o| o.uses_stdio() && o.byte_range().is_some(),
+    },
+    Conflict {
+        flags: ("-", "--staging-dir"),
+        reason: "streams are written directly to their destination",
+        applies: |o| o.uses_stdio() && o.staging_dir.is_some(),
+    },
+    Conflict {
+        flags: ("-", "--timeout/--file-timeout"),
+        reason: "timeouts are only enforced by the copy drivers",
+        applies: |o| o.uses_stdio() && (o.timeout.is_some() || o.file_timeout.is_some()),
+    },
+    Conflict {
+        flags: ("--offset/--length/--dest-offset", "--timeout/--file-timeout"),
+        reason: "timeouts are only enforced by the copy drivers",
+        applies: |o| o.byte_range().is_some() && (o.timeout.is_some() || o.file_timeout.is_some()),
+    },
+    Conflict {
+        flags: ("--offset/--length/--dest-offset", "--staging-dir"),
+        reason: "byte ranges are written into the destination in place",
+        applies: |o| o.byte_range().is_some() && o.staging_dir.is_some(),
+    },
+    Conflict {
+        flags: ("-", "--dry-run"),
+        reason: "a stream can only be read by the copy itself",
+        applies: |o| o.uses_stdio() && o.dry_run,
+    },
+    Conflict {
+        flags: ("-", "--compare-only"),
+        reason: "a stream can only be read by the copy itself",
+        applies: |o| o.uses_stdio() && o.compare_only,
+    },
+    Conflict {
+        flags: ("--compare-only", "--delete"),
+        reason: "--compare-only doesn't modify the destination",
+        applies: |o| o.compare_only && o.delete,
+    },
+    Conflict {
+        flags: ("--offset/--length/--dest-offset", "--dry-run"),
+        reason: "byte-range copies bypass the scan that --dry-run reports on",
+        applies: |o| o.byte_range().is_some() && o.dry_run,
+    },
+    Conflict {
+        flags: ("--fanout", "--target-directory"),
+        reason: "--fanout takes the destinations as the paths after the source",
+        applies: |o| o.fanout && o.target_directory.is_some(),
+    },
+    Conflict {
+        flags: ("--fanout", "-"),
+        reason: "a stream can only be copied to a single destination",
+        applies: |o| o.fanout && o.uses_stdio(),
+    },
+    Conflict {
+        flags: ("--fanout", "--offset/--length/--dest-offset"),
+        reason: "byte ranges can only be copied to a single destination",
+        applies: |o| o.fanout && o.byte_range().is_some(),
+    },
+    Conflict {
+        flags: ("--fanout", "--compare-only"),
+        reason: "--compare-only compares a single destination",
+        applies: |o| o.fanout && o.compare_only,
+    },
+    Conflict {
+        flags: ("--fanout", "--dry-run/--itemize"),
+        reason: "fan-out copies bypass the scan that --dry-run and --itemize report on",
+        applies: |o| o.fanout && (o.dry_run || o.itemize),
+    },
+    Conflict {
+        flags: ("--fanout", "--update/--delete"),
+        reason: "fan-out copies don't compare against existing destinations",
+        applies: |o| o.fanout && (o.update || o.delete),
+    },
+    Conflict {
+        flags: ("--fanout", "--skip-same/--stamp-checksum"),
+        reason: "fan-out copies are neither compared with nor checksummed at each destination",
+        applies: |o| o.fanout && (o.skip_same.is_some() || o.stamp_checksum),
+    },
+    Conflict {
+        flags: ("--fanout", "--max-errors/--max-error-rate"),
+        reason: "fan-out copies stop writing to a destination at its first error",
+        applies: |o| o.fanout && (o.max_errors.is_some() || o.max_error_rate.is_some()),
+    },
+    Conflict {
+        flags: ("--fanout", "--staging-dir/--backup"),
+        reason: "fan-out copies are written directly to each destination",
+        applies: |o| o.fanout && (o.staging_dir.is_some() || o.backup != Backup::None),
+    },
+    Conflict {
+        flags: ("--fanout", "--preserve-subvolumes"),
+        reason: "fan-out copies create plain directories",
+        applies: |o| o.fanout && o.preserve_subvolumes(),
+    },
+    Conflict {
+        flags: ("--fanout", "--manifest"),
+        reason: "a manifest describes a single destination",
+        applies: |o| o.fanout && o.manifest.is_some(),
+    },
+    Conflict {
+        flags: ("--fanout", "--metrics-interval"),
+        reason: "fan-out copies don't use the copy workers",
+        applies: |o| o.fanout && o.metrics_interval.is_some(),
+    },
+    Conflict {
+        flags: ("--fanout", "--journal"),
+        reason: "the journal records the changes to a single destination",
+        applies: |o| o.fanout && o.journal.is_some(),
+    },
+    Conflict {
+        flags: ("--replay", "--fanout"),
+        reason: "replayed files are copied to the destinations they were journalled with",
+        applies: |o| o.replay.is_some() && o.fanout,
+    },
+    Conflict {
+        flags: ("--replay", "--manifest"),
+        reason: "replayed files have no common destination for the manifest to describe",
+        applies: |o| o.replay.is_some() && o.manifest.is_some(),
+    },
+    Conflict {
+        flags: ("--replay", "--compare-only"),
+        reason: "--replay copies files",
+        applies: |o| o.replay.is_some() && o.compare_only,
+    },
+    Conflict {
+        flags: ("--replay", "--offset/--length/--dest-offset"),
+        reason: "replayed files are copied whole",
+        applies: |o| o.replay.is_some() && o.byte_range().is_some(),
+    },
+    Conflict {
+        flags: ("-", "--skip-same/--stamp-checksum"),
+        reason: "streams are written directly to their destination",
+        applies: |o| o.uses_stdio() && (o.skip_same.is_some() || o.stamp_checksum),
+    },
+    Conflict {
+        flags: ("-", "--journal"),
+        reason: "streams are written directly to their destination",
+        applies: |o| o.uses_stdio() && o.journal.is_some(),
+    },
+    Conflict {
+        flags: ("--batch-dirents", "--order"),
+        reason: "batched files are copied in scan order",
+        applies: |o| o.batch_dirents && o.order != Order::Scan,
+    },
+    Conflict {
+        flags: ("--dereference", "--no-dereference"),
+        reason: "--no-dereference copies symlinks rather than following them",
+        applies: |o| o.dereference && o.no_dereference,
+    },
+    Conflict {
+        flags: ("--dereference-command-line", "--no-dereference"),
+        reason: "--no-dereference copies symlinked sources rather than following them",
+        applies: |o| o.dereference_command_line && o.no_dereference,
+    },
+    Conflict {
+        flags: ("--preserve/-p/--archive", "--no-perms"),
+        reason: "--no-perms disables the mode and xattrs that --preserve copies; use --no-preserve instead",
+        applies: |o| o.no_perms
+            && o.requested_preserve().intersects(PreserveSet::MODE.union(PreserveSet::XATTR)),
+    },
+    Conflict {
+        flags: ("--preserve/-p/--archive", "--no-timestamps"),
+        reason: "--no-timestamps disables the timestamps that --preserve copies; use --no-preserve instead",
+        applies: |o| o.no_timestamps && o.requested_preserve().contains(PreserveSet::TIMESTAMPS),
+    },
+];
+
+// cp options whose value is optional, so may be given alone, and
+// their value then. xcp also accepts a value after a space, so the
+// value is only implied if the next argument is another option or an
+// existing path; anything else is left to be reported as a bad value.
+const CP_OPTIONAL_VALUES: &[(&str, &str, &[&str])] = &[
+    ("--reflink", "always", &["auto", "always", "never"]),
+    ("--backup", "existing", &["none", "off", "auto", "numbered", "t", "existing", "nil", "simple", "never"]),
+];
+
+/// Whether '--sparse' images block devices; cp's values are accepted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sparse {
+    Auto,
+    Always,
+}
+
+impl FromStr for Sparse {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(Sparse::Auto),
+            "always" => Ok(Sparse::Always),
+            "never" => Err(XcpError::InvalidArguments(
+                "--sparse=never is not supported; the holes of sparse files are always kept".to_string())),
+            _ => Err(unexpected_value("sparse", s, &["auto", "always"])),
+        }
+    }
+}
+
+// Give the implied value to cp options given alone; see
+// [CP_OPTIONAL_VALUES].
+fn imply_cp_values(args: &mut [OsString]) {
+    for i in 0..args.len() {
+        if args[i] == "--" {
+            break;
+        }
+        let Some(&(flag, implied, values)) = CP_OPTIONAL_VALUES.iter().find(|(flag, ..)| args[i] == *flag) else {
+            continue;
+        };
+        let alone = match args.get(i + 1) {
+            None => true,
+            Some(next) if next.to_str().is_some_and(|v| values.contains(&v.to_lowercase().as_str())) => false,
+            Some(next) => next.as_encoded_bytes().starts_with(b"-") || Path::new(next).symlink_metadata().is_ok(),
+        };
+        if alone {
+            args[i] = format!("{}={}", flag, implied).into();
+        }
+    }
+}
 
 #[derive(Clone, Debug, Parser)]
 #[command(
     name = "xcp",
     about = "A (partial) clone of the Unix `cp` command with progress and pluggable drivers.",
     version,
+    after_help = "To check files against a manifest written with '--manifest', run \
+                  'xcp verify [OPTIONS] MANIFEST [ROOT]'.",
 )]
 pub struct Opts {
     /// Verbosity.
     ///
-    /// Can be specified multiple times to increase logging.
+    /// Can be specified multiple times to increase logging. Unlike
+    /// cp, a single '-v' doesn't list each file copied; see
+    /// '--itemize'.
     #[arg(short, long, action = ArgAction::Count)]
     pub verbose: u8,
 
+    /// Quiet mode.
+    ///
+    /// Only print errors and the final error summary, and disable the
+    /// progress bar. Specify twice to print nothing, leaving only the
+    /// exit status.
+    #[arg(short, long, action = ArgAction::Count)]
+    pub quiet: u8,
+
     /// Copy directories recursively
     #[arg(short, long)]
     pub recursive: bool,
@@ -47,33 +406,175 @@ pub struct Opts {
     #[arg(short = 'L', long)]
     pub dereference: bool,
 
+    /// Copy symlinks in source as symlinks.
+    ///
+    /// This is the default unless '--dereference' is given. Symlinks
+    /// given as sources are also copied as symlinks.
+    #[arg(short = 'P', long)]
+    pub no_dereference: bool,
+
+    /// Follow symlinks given as sources.
+    ///
+    /// Symlinks found when recursing are still copied as symlinks.
+    /// This is the default unless '--no-dereference' or '--archive' is
+    /// given.
+    #[arg(short = 'H', long)]
+    pub dereference_command_line: bool,
+
+    /// Archive mode; the same as '-r --preserve=all'.
+    ///
+    /// Symlinks, including those given as sources, are copied as
+    /// symlinks unless '--dereference' or '--dereference-command-line'
+    /// is also given.
+    #[arg(short, long)]
+    pub archive: bool,
+
     /// Number of parallel workers.
     ///
     /// Default is 4; if the value is negative or 0 it uses the number
     /// of logical CPUs.
-    #[arg(short, long, default_value = "4")]
-    pub workers: usize,
+    #[arg(short, long, value_name = "N")]
+    pub workers: Option<usize>,
+
+    /// Read at most N files at once from each source device.
+    ///
+    /// Parallel reads from a spinning disk cause it to seek between
+    /// files, which can be slower than reading them one at a time.
+    /// By default rotational disks are limited to 2 readers, and
+    /// other devices to the number of workers. Writes are not
+    /// limited.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub readers_per_device: Option<u64>,
+
+    /// Apply the metadata of copied files on N separate workers.
+    ///
+    /// Permissions, timestamps, xattrs and '--fsync' are applied to
+    /// each file once its data is copied. Where these are slow, such
+    /// as on network filesystems, a separate pool lets the workers
+    /// carry on copying data meanwhile. By default each worker
+    /// applies the metadata of the files it copies.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub metadata_workers: Option<u64>,
 
     /// Block size for operations.
     ///
     /// Accepts standard size modifiers like "M" and "GB". Actual
-    /// usage internally depends on the driver.
-    #[arg(long,  default_value = "1MB", value_parser=unbytify)]
-    pub block_size: u64,
+    /// usage internally depends on the driver. If not given this is
+    /// 1MB, or the preferred IO size of the source or destination
+    /// filesystem if larger (up to 64MB), rounded to a multiple of it.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub block_size: Option<u64>,
+
+    /// The most memory to use for copy buffers.
+    ///
+    /// Copies through userspace use a buffer of up to a block per
+    /// worker; if these would exceed this size the block size is
+    /// reduced, and if blocks would be smaller than 64KiB so is the
+    /// number of workers, with a warning. Accepts the same sizes as
+    /// '--block-size'. Default is 256MiB.
+    #[arg(long, value_name = "SIZE", value_parser = parse_memory)]
+    pub max_memory: Option<u64>,
 
     /// Do not overwrite an existing file
+    ///
+    /// With '--recursive' existing destination files are skipped and
+    /// the rest of the tree is copied ('skip', the default), or any
+    /// existing destination aborts the copy ('fail'). Copying files
+    /// without '--recursive' always fails if the destination exists,
+    /// unless 'rename' is given. With 'rename' both are kept, and the
+    /// copy is given a new name; see '--conflict-suffix'.
+    #[arg(short, long, value_name = "MODE", num_args = 0..=1, require_equals = true, default_missing_value = "skip")]
+    pub no_clobber: Option<NoClobber>,
+
+    /// Skip existing destination files (compatibility only)
+    ///
+    /// xcp doesn't prompt before overwriting; as with cp when every
+    /// prompt is declined, existing files are skipped. This is
+    /// '--no-clobber' unless that is also given.
+    #[arg(short, long)]
+    pub interactive: bool,
+
+    /// Name copies kept with '--no-clobber=rename' using TEMPLATE.
+    ///
+    /// '{name}' is the original name without its extension, '{ext}'
+    /// the extension (e.g. 'tar.gz'), '{n}' the lowest number giving
+    /// an unused name and '{date}' today's date as YYYY-MM-DD. A dot
+    /// before '{ext}' is dropped if there is no extension. Implies
+    /// '--no-clobber=rename'. Default is '{name} ({n}).{ext}'.
+    #[arg(long, value_name = "TEMPLATE")]
+    pub conflict_suffix: Option<ConflictTemplate>,
+
+    /// Only copy newer files.
+    ///
+    /// Copy only when the source file is newer than the destination
+    /// file or the destination file is missing. Modification times
+    /// are compared at the resolution of the destination filesystem,
+    /// so files copied to e.g. FAT are not re-copied on every run.
     #[arg(short, long)]
-    pub no_clobber: bool,
+    pub update: bool,
+
+    /// Don't overwrite destination files newer than their source.
+    ///
+    /// Each is reported as an error instead; with
+    /// '--continue-on-error' the copy continues. By default these
+    /// files are overwritten with a warning. Not checked with
+    /// '--update' or '--no-clobber', which skip them.
+    #[arg(long)]
+    pub forbid_overwrite_newer: bool,
+
+    /// Skip files whose destination has the same contents.
+    ///
+    /// Destination files of the same size as their source are
+    /// compared by 'checksum', reading both, or by 'stamp', which
+    /// trusts a checksum stamped on the destination with
+    /// '--stamp-checksum' rather than reading either file, as long as
+    /// the destination hasn't been modified since. The stamp holds
+    /// the source's modification time, so a source modified since it
+    /// was copied is copied again. Destinations without a stamp are
+    /// compared by checksum. Skipped files still have their
+    /// attributes and timestamps copied.
+    #[arg(long, value_name = "MODE")]
+    pub skip_same: Option<SkipSame>,
+
+    /// Stamp the checksum of each copied file on the copy.
+    ///
+    /// The BLAKE3 digest of the file and the modification time of its
+    /// source are stored in the 'user.xcp.blake3' and
+    /// 'user.xcp.src_mtime' xattrs, for '--skip-same=stamp'. Stamps
+    /// are only trusted while the copy has its source's modification
+    /// time, so timestamps should be preserved. Destinations without
+    /// xattr support are copied without stamps, with a warning.
+    #[arg(long)]
+    pub stamp_checksum: bool,
 
     /// Force (compatability only)
     ///
     /// Overwrite files; this is the default behaviour, this flag is
     /// for compatibility with `cp` only. See `--no-clobber` for the
     /// inverse flag. Using this in conjunction with `--no-clobber`
-    /// will cause an error.
+    /// will cause an error. This also confirms copies as with
+    /// '--yes'.
     #[arg(short = 'f', long = "force")]
     pub force: bool,
 
+    /// Do not ask for confirmation of risky copies.
+    ///
+    /// Copies into '/' or directly into the home directory, with
+    /// '--delete' into a destination with many existing entries (see
+    /// '--confirm-threshold'), or that won't fit on the destination
+    /// once each file is rounded up to its allocation unit, are
+    /// confirmed interactively when running in a terminal and refused
+    /// otherwise. This skips the checks, e.g. for scripts.
+    #[arg(long)]
+    pub yes: bool,
+
+    /// Confirm '--delete' copies into destinations with more than
+    /// this many entries.
+    ///
+    /// 0 disables the check.
+    #[arg(long, value_name = "N", default_value = "1000")]
+    pub confirm_threshold: usize,
+
     /// Use .gitignore if present.
     ///
     /// NOTE: This is fairly basic at the moment, and only honours a
@@ -82,9 +583,44 @@ pub struct Opts {
     #[arg(long)]
     pub gitignore: bool,
 
+    /// Don't copy entries matching PATTERN.
+    ///
+    /// Patterns are matched against the path of each entry relative
+    /// to the source it is under, so with several sources they apply
+    /// within each. A pattern without a '/' matches names at any
+    /// depth, e.g. '*.o'; a leading '/' anchors it to the source,
+    /// e.g. '/build'; and a trailing '/' only matches directories.
+    /// '*' doesn't match a '/', while '**' matches any number of
+    /// directories; the syntax is that of '--glob', so '{a,b}' matches
+    /// either. Excluded directories aren't descended into, and
+    /// excluded destination entries are kept by '--delete'. May be
+    /// given more than once.
+    #[arg(long, value_name = "PATTERN")]
+    pub exclude: Vec<String>,
+
+    /// Read exclude patterns from FILE, one per line.
+    ///
+    /// Blank lines and lines starting with '#' are ignored. May be
+    /// given more than once.
+    #[arg(long, value_name = "FILE")]
+    pub exclude_from: Vec<PathBuf>,
+
+    /// Copy entries matching PATTERN even if they are excluded.
+    ///
+    /// Takes the same patterns as '--exclude'. Entries under an
+    /// excluded directory can't be included, as it isn't descended
+    /// into.
+    #[arg(long, value_name = "PATTERN")]
+    pub include: Vec<String>,
+
     /// Expand file patterns.
     ///
-    /// Glob (expand) filename patterns natively (note; the shell may still do its own expansion first)
+    /// Glob (expand) filename patterns natively (note; the shell may
+    /// still do its own expansion first). '*' and '?' match within a
+    /// path component, '**' any number of components, '[a-z]' and
+    /// '[!a-z]' character classes, '{a,b}' either alternative, and
+    /// '\' escapes the next character. The matches of each pattern
+    /// are sorted. The same syntax is used by '--exclude'.
     #[arg(short, long)]
     pub glob: bool,
 
@@ -92,23 +628,233 @@ pub struct Opts {
     #[arg(long)]
     pub no_progress: bool,
 
+    /// Progress output mode.
+    ///
+    /// 'bar' (the default) displays a progress bar on stderr. 'json'
+    /// instead writes one JSON event per line to stdout, including
+    /// file start/completion, '--itemize' results, and errors and the
+    /// final outcome with stable error codes, for consumption by
+    /// other tools.
+    #[arg(long, value_name = "MODE", default_value = "bar")]
+    pub progress: ProgressMode,
+
+    /// When to colour terminal output.
+    ///
+    /// 'auto' (the default) colours the log messages and progress bar
+    /// only when written to a terminal, and the NO_COLOR environment
+    /// variable is unset; 'always' and 'never' override this.
+    #[arg(long, value_name = "WHEN", default_value = "auto")]
+    pub color: ColorMode,
+
+    /// Show the files currently being copied.
+    ///
+    /// Lists up to N in-flight files below the progress bar (default
+    /// 4 if no value is given). This is enabled by default with
+    /// '--verbose' when running in a terminal; use 0 to disable.
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "4")]
+    pub show_current: Option<usize>,
+
+    /// Warn when the copy stalls for SECS seconds.
+    ///
+    /// If no data is copied for this long while files are in flight,
+    /// e.g. because a network filesystem has stopped responding, a
+    /// warning naming the file is shown and logged until the copy
+    /// resumes. The default is 30; use 0 to disable.
+    #[arg(long, value_name = "SECS", default_value = "30")]
+    pub stall_warning: u64,
+
+    /// Abort the copy if it stalls for SECS seconds.
+    ///
+    /// For unattended runs; by default a stalled copy waits
+    /// indefinitely. See '--stall-warning'.
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub stall_timeout: Option<u64>,
+
+    /// Report the state of the copy workers every SECS seconds.
+    ///
+    /// For diagnosing slow copies. Each report counts the workers
+    /// reading, writing, idle and applying metadata, and gives the
+    /// number of operations waiting for a worker, the number of
+    /// updates waiting to be reported, and each worker's copy rate.
+    /// Idle workers with an empty queue are waiting on the scan of the
+    /// source; a deep update queue means reporting is the bottleneck.
+    /// Reports are written to stderr, or as 'metrics' events with
+    /// '--progress=json'.
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub metrics_interval: Option<u64>,
+
+    /// Stop the copy after DURATION.
+    ///
+    /// For unattended runs that need a hard stop. The copy stops as
+    /// it would on an error; files in progress are removed, and xcp
+    /// exits with status 124. Takes seconds, or a number with an 's',
+    /// 'm', 'h' or 'd' suffix, e.g. '2h'.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub timeout: Option<Duration>,
+
+    /// Abandon any file that takes longer than DURATION to copy.
+    ///
+    /// The partial file is removed and an error reported for it;
+    /// with '--continue-on-error' the copy continues. This is checked
+    /// between blocks, so a read or write that never returns can't
+    /// be interrupted. Takes the same values as '--timeout'.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub file_timeout: Option<Duration>,
+
+    /// Don't lock the destination.
+    ///
+    /// By default a copy locks its destination for its duration, with
+    /// '.xcp-lock' in a destination directory or '.xcp-lock-NAME'
+    /// beside other destinations, and another copy to the same
+    /// destination fails. A lock left by a copy that has exited is
+    /// broken with a warning.
+    #[arg(long)]
+    pub no_lock: bool,
+
+    /// Wait for another copy's lock on the destination.
+    ///
+    /// Rather than failing, wait up to DURATION for the lock to be
+    /// released, or indefinitely if no value is given. Takes the same
+    /// values as '--timeout'.
+    #[arg(long, value_name = "DURATION", num_args = 0..=1, require_equals = true, value_parser = parse_duration)]
+    pub wait_lock: Option<Option<Duration>>,
+
+    /// Copy the given file attributes.
+    ///
+    /// A comma-separated list of 'mode', 'ownership', 'timestamps',
+    /// 'links', 'context' (the SELinux security context), 'xattr',
+    /// 'flags' (inode flags such as immutable, set last) or 'all',
+    /// added to the default of
+    /// 'mode,timestamps,context,xattr'. Without a list this is
+    /// 'mode,ownership,timestamps', as with 'cp'.
+    ///
+    /// The setuid and setgid bits are cleared when the copy does not
+    /// have the same owner or group as the source, e.g. when not
+    /// copying ownership or when that fails, unless 'mode' is given
+    /// explicitly here or with '-p' or '--archive'.
+    #[arg(long, value_name = "ATTR_LIST", num_args = 0..=1, require_equals = true,
+          default_missing_value = "mode,ownership,timestamps", action = ArgAction::Append)]
+    pub preserve: Vec<PreserveSet>,
+
+    /// The same as '--preserve=mode,ownership,timestamps'.
+    #[arg(short = 'p')]
+    pub preserve_basic: bool,
+
+    /// Do not copy the given file attributes.
+    ///
+    /// Takes the same list as '--preserve', and overrides it; for
+    /// example '-a --no-preserve=ownership' copies all attributes
+    /// except ownership.
+    #[arg(long, value_name = "ATTR_LIST", action = ArgAction::Append)]
+    pub no_preserve: Vec<PreserveSet>,
+
     /// Do not copy the file permissions.
+    ///
+    /// This is the same as '--no-preserve=mode,context,xattr'.
     #[arg(long)]
     pub no_perms: bool,
 
     /// Do not copy the file timestamps.
+    ///
+    /// This is the same as '--no-preserve=timestamps'.
     #[arg(long)]
     pub no_timestamps: bool,
 
+    /// Do not copy directory timestamps.
+    ///
+    /// File timestamps are still copied. Each directory's timestamps
+    /// are otherwise set as soon as all of its entries are complete.
+    #[arg(long)]
+    pub no_dir_timestamps: bool,
+
+    /// Recreate btrfs subvolumes as subvolumes.
+    ///
+    /// Source directories that are btrfs subvolumes are otherwise
+    /// copied as plain directories. Where the destination isn't btrfs
+    /// a plain directory is created with a warning. Existing
+    /// destination directories are copied into unchanged.
+    #[cfg(feature = "btrfs")]
+    #[arg(long)]
+    pub preserve_subvolumes: bool,
+
+    /// Write through a destination symlink to a nonexistent target.
+    ///
+    /// The target is created, but not its parent directories unless
+    /// '--mkdir-parents' is also given. By default writing to a
+    /// dangling destination symlink is an error, as the file would
+    /// be created wherever the link points.
+    #[arg(long)]
+    pub follow_dest_symlinks: bool,
+
+    /// Create missing parent directories of a dangling symlink's target.
+    ///
+    /// Only applies with '--follow-dest-symlinks'.
+    #[arg(long)]
+    pub mkdir_parents: bool,
+
+    /// Replace destination symlinks with regular files.
+    ///
+    /// The symlink itself is removed before the copy, rather than the
+    /// file written through it. With '-T' this also replaces a
+    /// destination symlink to a directory.
+    #[arg(long)]
+    pub remove_destination: bool,
+
     /// Copy ownership.
     ///
     /// Whether to copy ownship (user/group).  This option requires
     /// root permissions or appropriate capabilities; if the attempt
     /// to copy ownership fails a warning is issued but the operation
-    /// continues.
+    /// continues. This is the same as '--preserve=ownership'.
     #[arg(short, long)]
     pub ownership: bool,
 
+    /// Skip xattrs with values larger than this.
+    ///
+    /// Larger values are not read, and a warning is issued instead.
+    /// Accepts standard size modifiers like "M" and "GB"; 0 copies
+    /// xattrs of any size.
+    #[arg(long, value_name = "SIZE", default_value = "64MiB", value_parser = parse_size)]
+    pub xattr_value_limit: u64,
+
+    /// Override the mode of copied files.
+    ///
+    /// A comma-separated list of numeric (e.g. '644') or symbolic
+    /// (e.g. 'u+rwX,go-w') modes, applied in order after any source
+    /// permissions. Prefix an entry with 'D' or 'F' to apply it only
+    /// to directories or files, e.g. 'D755,F644'. Only files and
+    /// directories created by the copy are changed.
+    #[arg(long, value_name = "MODE", value_parser = parse_chmod)]
+    pub chmod: Option<Chmod>,
+
+    /// Override the ownership of copied files.
+    ///
+    /// Takes 'USER:GROUP', 'USER' or ':GROUP', as names or numeric
+    /// ids. As with '--ownership' this requires appropriate
+    /// privileges; if it fails a warning is issued but the operation
+    /// continues.
+    #[arg(long, value_name = "USER:GROUP", value_parser = parse_chown)]
+    pub chown: Option<Chown>,
+
+    /// Preserve hard-links between copied files.
+    ///
+    /// Files with multiple links within the source are copied once,
+    /// and the other links are recreated at the destination. Without
+    /// this each link is copied as a separate file. This is the same
+    /// as '--preserve=links'.
+    #[arg(long)]
+    pub preserve_hardlinks: bool,
+
+    /// Copy hard-linked sources from the destination.
+    ///
+    /// When not preserving hard-links, further links to an already
+    /// copied file are copied from the first destination file rather
+    /// than re-reading the source, which may be faster or allow
+    /// reflinks. The source is re-read if it changes during the copy,
+    /// or the first copy fails.
+    #[arg(long)]
+    pub cache_linked_sources: bool,
+
     /// Driver to use, defaults to 'file-parallel'.
     ///
     /// Currently there are 2; the default "parfile", which
@@ -118,22 +864,161 @@ pub struct Opts {
     #[arg(long, default_value = "parfile")]
     pub driver: Drivers,
 
+    /// How the copy drivers run their work.
+    ///
+    /// The default "threads" starts threads for the copy. With
+    /// "sequential" everything runs on a single thread in the same
+    /// order every time; this is slow, but useful for reproducing
+    /// problems that depend on timing. When built with the 'rayon'
+    /// feature "rayon" runs the copy on a rayon thread pool.
+    #[arg(long, default_value = "threads")]
+    pub executor: Executor,
+
     /// Target should not be a directory.
     ///
     /// Analogous to cp's no-target-directory. Expected behavior is that when
     /// copying a directory to another directory, instead of creating a sub-folder
-    /// in target, overwrite target.
+    /// in target, overwrite target. As with cp, a symlink given as the
+    /// target is not followed; replacing it requires
+    /// '--remove-destination'.
     #[arg(short = 'T', long)]
     pub no_target_directory: bool,
 
-    /// Copy into a subdirectory of the target
+    /// Copy each source into a subdirectory of the target named after it.
+    ///
+    /// The target is always treated as a directory, and is created if
+    /// necessary. Sources with the same name are told apart by their
+    /// parent directories, e.g. '/mnt/disk1/data' and
+    /// '/mnt/disk2/data' are copied to 'disk1_data' and 'disk2_data'.
     #[arg(long)]
+    pub dest_subdir_from_source: bool,
+
+    /// Allow '..' in the destination to climb above its existing parent.
+    ///
+    /// The destination is normalized before copying; the existing
+    /// part of the path is resolved, following symlinks, and the rest
+    /// is normalized lexically. By default a '..' in the non-existent
+    /// part that would leave the resolved directory is an error.
+    #[arg(long)]
+    pub allow_dotdot_dest: bool,
+
+    /// Copy into a subdirectory of the target
+    #[arg(short, long, value_name = "DIRECTORY")]
     pub target_directory: Option<String>,
 
+    /// Stay on the filesystem of each source.
+    ///
+    /// Directories that are mount points are created, but nothing
+    /// beneath them is copied.
+    #[arg(short = 'x', long)]
+    pub one_file_system: bool,
+
+    /// Copy a single source to several destinations, reading it once.
+    ///
+    /// The first path is the source and the rest are destinations,
+    /// each of which is copied to as if it were the only one. An error
+    /// writing to one destination doesn't stop the others; unless
+    /// '--continue-on-error' is given nothing more is written to it.
+    /// The outcome for each destination is reported at the end.
+    /// Sparse files and hard-links are not preserved.
+    #[arg(long)]
+    pub fanout: bool,
+
     /// Sync each file to disk after writing.
     #[arg(long)]
     pub fsync: bool,
 
+    /// Write files under this directory and move them into place once complete.
+    ///
+    /// Each file, with its metadata, is written and synced under
+    /// PATH, then renamed to its destination, so destination files
+    /// are never seen partly written. If PATH is on another
+    /// filesystem the file is copied a second time instead, with a
+    /// warning. Staged files are removed when xcp exits, and any left
+    /// by an earlier run that crashed are removed by the next run
+    /// using the same PATH.
+    #[arg(long, value_name = "PATH")]
+    pub staging_dir: Option<PathBuf>,
+
+    /// Do not preallocate destination files.
+    ///
+    /// Files are written strictly sequentially, and sparse files are
+    /// written in full. Preallocation is skipped automatically on
+    /// filesystems that report it as unsupported; use this for
+    /// targets (e.g. some FUSE mounts) that misbehave instead.
+    #[arg(long)]
+    pub no_fallocate: bool,
+
+    /// Write blocks out of order with the parblock driver on any
+    /// destination.
+    ///
+    /// Compressed files on btrfs and files on FUSE filesystems handle
+    /// out-of-order writes poorly, so blocks are written to them in
+    /// order while still being read in parallel.
+    #[arg(long)]
+    pub force_parblock: bool,
+
+    /// Create small files a directory at a time.
+    ///
+    /// Each worker creates a batch of small files in one destination
+    /// directory, and then copies their data. On some filesystems
+    /// this reduces contention for the directory lock when copying
+    /// many small files. See tests/scripts/bench-dirents.sh.
+    #[arg(long)]
+    pub batch_dirents: bool,
+
+    /// Copy tiny files the same way as any other file.
+    ///
+    /// By default files of up to 512 bytes are read while scanning the
+    /// source and written in a single call, and empty files have no
+    /// data copied. Use this if a filesystem misbehaves with it. See
+    /// tests/scripts/bench-tiny-files.sh.
+    #[arg(long)]
+    pub no_tiny_file_fastpath: bool,
+
+    /// The order to copy files in.
+    ///
+    /// 'scan' (the default) copies files in the order they are found.
+    /// 'largest-first' copies the largest files first, so a large file
+    /// doesn't end up copying alone once the rest are done; this
+    /// helps most with many workers. 'smallest-first' does the
+    /// reverse. Except for very large files, copying starts once the
+    /// whole tree has been scanned.
+    #[arg(long, value_name = "ORDER", default_value = "scan")]
+    pub order: Order,
+
+    /// Tune the copy for the destination's filesystem.
+    ///
+    /// 'network' suits FUSE and other network filesystems where each
+    /// write and metadata operation is a round trip: it copies in
+    /// larger blocks (a multiple of the mount's 'max_write' where
+    /// known) with fewer workers, doesn't preallocate, batches small
+    /// files' directory entries with '--order=scan', and stops trying
+    /// xattrs once the destination rejects them. 'local' changes
+    /// nothing. The default 'auto' uses 'network' for FUSE
+    /// destinations. An explicit '--block-size' or '--workers' is
+    /// kept.
+    #[arg(long, value_name = "PROFILE", default_value = "auto")]
+    pub profile: Profile,
+
+    /// Create sparse images of block devices.
+    ///
+    /// When the source is a block device, blocks of zeros are left as
+    /// holes in the destination rather than written, and the
+    /// destination is not preallocated. cp's '--sparse=always' is the
+    /// same, and '--sparse=auto' the default; the holes of sparse
+    /// files are always kept, so cp's '--sparse=never' isn't
+    /// supported.
+    #[arg(long, value_name = "WHEN", num_args = 0..=1, require_equals = true, default_missing_value = "always")]
+    pub sparse: Option<Sparse>,
+
+    /// Read block devices through the page cache.
+    ///
+    /// By default block devices are read with O_DIRECT, so imaging a
+    /// large device doesn't evict the page cache.
+    #[arg(long)]
+    pub no_direct_io: bool,
+
     /// Reflink options.
     ///
     /// Whether and how to use reflinks. 'auto' (the default) will
@@ -144,6 +1029,10 @@ pub struct Opts {
     /// Note: when using Linux accelerated copy operations (the
     /// default when available) the kernel may choose to reflink
     /// rather than perform a fully copy regardless of this setting.
+    ///
+    /// With 'always' each file is cloned as a single operation with
+    /// no block-level splitting, so large trees can be cloned
+    /// quickly; use '--verbose' to confirm how many were reflinked.
     #[arg(long, default_value = "auto")]
     pub reflink: Reflink,
 
@@ -154,21 +1043,405 @@ pub struct Opts {
     /// backups follow the semantics of `cp` numbered backups
     /// (e.g. `file.txt.~123~`). 'auto' will only create a numbered
     /// backup if a previous backups exists. Default is 'none'.
-    #[arg(long, default_value = "none")]
+    ///
+    /// cp's controls are also accepted. As xcp doesn't make simple
+    /// ('file~') backups, 'existing', 'nil', 'simple' and 'never'
+    /// always make a numbered backup, as does '--backup' without a
+    /// value or '-b'.
+    #[arg(long, value_name = "CONTROL", default_value = "none")]
     pub backup: Backup,
 
+    /// Make a numbered backup of each overwritten file; see '--backup'.
+    #[arg(short = 'b')]
+    pub backup_numbered: bool,
+
+    /// Directory metadata options.
+    ///
+    /// Whether to apply the source permissions, ownership and xattrs
+    /// to destination directories that already exist. Options are
+    /// 'preserve-existing' (the default), which leaves existing
+    /// directories untouched, or 'overwrite'. Newly created
+    /// directories always receive the source metadata.
+    #[arg(long, default_value = "preserve-existing")]
+    pub dir_mode: DirMode,
+
+    /// The mode of directories with no source counterpart.
+    ///
+    /// Applies to directories the copy has to create that aren't
+    /// copied from the source, such as a missing destination with
+    /// '--dest-subdir-from-source' or parents created by
+    /// '--mkdir-parents'. Given in octal, and filtered by the umask;
+    /// the default is '777'.
+    #[arg(long, value_name = "MODE", default_value = "777", value_parser = parse_dir_mode)]
+    pub new_dir_mode: u32,
+
+    /// Don't create destination directories that would be left empty.
+    ///
+    /// Directories are only created once an entry to copy is found in
+    /// them, so those whose contents are all excluded, ignored or
+    /// skipped as up to date are left out. The top-level destination
+    /// is always created.
+    #[arg(long)]
+    pub prune_empty_dirs: bool,
+
+    /// How to handle names the destination filesystem can't represent.
+    ///
+    /// FAT, exFAT and NTFS destinations don't allow some characters
+    /// (e.g. ':' and '?') or trailing dots and spaces in names, and
+    /// don't distinguish names that differ only in case, as don't
+    /// ext4 and F2FS directories with casefolding enabled. Options
+    /// are 'error' (the default), 'skip' which skips the entry, or
+    /// 'sanitize' which replaces invalid characters with '_' and
+    /// adds a numeric suffix to colliding names. Skipped and renamed
+    /// entries are listed at the end of the copy, and renamed ones in
+    /// the manifest.
+    #[arg(long, value_name = "POLICY", default_value = "error")]
+    pub invalid_name: InvalidName,
+
+    /// Continue copying after errors.
+    ///
+    /// Errors copying individual files or reading source directories
+    /// are reported, and the remaining files are still copied. A
+    /// summary of the errors is printed at the end and the exit code
+    /// will indicate failure.
+    #[arg(long)]
+    pub continue_on_error: bool,
+
+    /// Stop after N errors with '--continue-on-error'.
+    ///
+    /// The copy stops as it would on an error; files in progress are
+    /// removed, the errors so far are listed, and xcp exits with
+    /// status 3.
+    #[arg(long, value_name = "N", requires = "continue_on_error",
+          value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_errors: Option<u64>,
+
+    /// Stop after more than N errors in 1000 files with
+    /// '--continue-on-error'.
+    ///
+    /// The errors are counted over the last 1000 files, so this
+    /// stops a long copy that has started to fail throughout, e.g.
+    /// because the destination has gone away, where '--max-errors'
+    /// would need to be set too high to be useful. Stops as
+    /// '--max-errors' does.
+    #[arg(long, value_name = "N", requires = "continue_on_error",
+          value_parser = clap::value_parser!(u64).range(..1000))]
+    pub max_error_rate: Option<u64>,
+
+    /// Continue copying when the destination is full.
+    ///
+    /// By default running out of space on the destination stops the
+    /// copy, even with '--continue-on-error'. With both options set
+    /// the remaining files are still attempted. Partially written
+    /// files are removed in either case.
+    #[arg(long)]
+    pub really_continue_on_enospc: bool,
+
+    /// Retry failed reads of a source file N times.
+    ///
+    /// When reading a source fails with an I/O error, e.g. on a
+    /// damaged disk, the read is retried N times before the block is
+    /// split in halves, down to 512-byte sectors, to recover as much
+    /// of it as possible. The error is reported with the offset of the
+    /// first unreadable sector.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub read_retries: u32,
+
+    /// Write zeros for unreadable parts of source files.
+    ///
+    /// Rather than failing a file with a read error, fill the sectors
+    /// that can't be read with zeros and continue. The files affected
+    /// and their unreadable byte ranges are listed at the end and in
+    /// the journal, and recorded in the 'user.xcp.bad_ranges' xattr of
+    /// the copy where supported.
+    #[arg(long)]
+    pub fill_errors: bool,
+
+    /// Write a manifest of the copied files.
+    ///
+    /// The manifest is a JSON file listing the path (relative to the
+    /// destination), size, modification time and checksum of every
+    /// copied file, sorted by path. It is written once the copy is
+    /// complete. See also '--manifest-hash'.
+    #[arg(long)]
+    pub manifest: Option<PathBuf>,
+
+    /// Append a record of every action taken to a journal.
+    ///
+    /// The journal is a JSON object per line, each with a timestamp
+    /// and the action: a file copied (with the bytes written and how),
+    /// a directory, symlink or special file created, an entry renamed,
+    /// skipped (with the reason), or deleted with '--delete', metadata
+    /// that couldn't be copied, or an error. Each run starts with a
+    /// record of its directory and a fingerprint of the options, so
+    /// skipped and failed files can be copied later with '--replay'.
+    /// Records are flushed every second and when the copy finishes.
+    #[arg(long, value_name = "PATH")]
+    pub journal: Option<PathBuf>,
+
+    /// Copy again the files a journal recorded as skipped or failed.
+    ///
+    /// Reads a journal written with '--journal', and copies each file
+    /// it records as skipped because the destination existed, or as
+    /// failing to copy, from its source to its destination with the
+    /// current options. Files since copied in a later run in the same
+    /// journal are left alone, as are those whose source has gone or
+    /// changed size; these are reported. No paths are given.
+    #[arg(long, value_name = "JOURNAL")]
+    pub replay: Option<PathBuf>,
+
+    /// Only replay the files skipped, or those that failed.
+    ///
+    /// Either 'skipped' or 'errored'; by default both are replayed.
+    #[arg(long, value_name = "RECORDS", requires = "replay")]
+    pub only: Option<ReplayFilter>,
+
+    /// Checksum algorithm for the manifest.
+    ///
+    /// Currently 'blake3' (the default) and 'sha256' are supported.
+    #[arg(long, default_value = "blake3")]
+    pub manifest_hash: ChecksumType,
+
+    /// Show what would be copied without modifying the destination.
+    ///
+    /// The sources are walked and compared as normal, but no files or
+    /// directories are created or removed. Usually combined with
+    /// '--itemize'.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Print a summary of the changes made to the destination.
+    ///
+    /// Outputs one line per changed path, sorted by path, with a
+    /// change vector: '>f+++' for new files ('cd+++' for
+    /// directories), '>fstp' for existing files where 's', 't' and
+    /// 'p' mark a size, modification time or mode difference, and
+    /// '*deleting' for extraneous entries removed by '--delete'.
+    /// Timestamps and modes are not compared if '--no-timestamps' or
+    /// '--no-perms' are given.
+    #[arg(long)]
+    pub itemize: bool,
+
+    /// Compare the source and destination trees without copying.
+    ///
+    /// Takes a single source and destination, which are compared
+    /// directly and walked with the same rules as a copy (e.g.
+    /// '--gitignore' and '--dereference'). Entries are compared by
+    /// type, size, modification time and mode, as for '--itemize', or
+    /// by contents with '--compare-checksum'. Differing, missing and
+    /// extra paths are listed, with extra paths marked '*extra'. The
+    /// exit status is 0 if the trees are identical, 1 if they differ
+    /// and 2 on errors.
+    #[arg(long)]
+    pub compare_only: bool,
+
+    /// Compare file contents with this checksum algorithm.
+    ///
+    /// With '--compare-only', files of the same size are read from
+    /// both trees in parallel and compared by checksum rather than by
+    /// modification time; 'blake3' and 'sha256' are supported. Files
+    /// whose contents differ are listed as '>fc..'.
+    #[arg(long, value_name = "ALGORITHM")]
+    pub compare_checksum: Option<ChecksumType>,
+
+    /// Delete extraneous files from the destination.
+    ///
+    /// Files and directories in the destination that do not exist in
+    /// the corresponding source directory are removed.
+    #[arg(long)]
+    pub delete: bool,
+
+    /// Copy only the bytes from this offset of the source.
+    ///
+    /// Copies a byte range of a single source file, similar to 'dd'.
+    /// Accepts standard size modifiers like "M" and "GB". The range
+    /// must be within the source file. See also '--length' and
+    /// '--dest-offset'.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub offset: Option<u64>,
+
+    /// Copy only this many bytes of the source.
+    ///
+    /// By default the range extends to the end of the source file.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub length: Option<u64>,
+
+    /// Write the copied range at this offset in the destination.
+    ///
+    /// An existing destination is not truncated, and is only
+    /// extended if the range ends past its current length.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub dest_offset: Option<u64>,
+
+    /// Where to write log messages.
+    ///
+    /// One of 'auto' (the default), 'stderr', 'syslog', 'journald' or
+    /// 'file:PATH', and may be given more than once. 'auto' uses
+    /// journald when stderr is connected to the journal. Files are
+    /// appended to with timestamped lines. Copy errors are also sent
+    /// to the syslog, journald and file targets as they occur, with
+    /// the paths and error kind as journald fields.
+    #[arg(long, value_name = "TARGET", default_value = "auto")]
+    pub log_target: Vec<LogTarget>,
+
+    /// Write a trace of the copy to FILE.
+    ///
+    /// The trace is in the Chrome trace-event JSON format, and can be
+    /// loaded into 'chrome://tracing' or Perfetto. It contains spans
+    /// for the source scan, each file copy, the metadata stage and
+    /// individual block operations. Log messages are included as
+    /// events, and are written to the terminal regardless of
+    /// '--log-target'.
+    #[cfg(feature = "tracing")]
+    #[arg(long, value_name = "FILE")]
+    pub trace_out: Option<PathBuf>,
+
     /// Path list.
     ///
     /// Source and destination files, or multiple source(s) to a directory.
+    ///
+    /// A source of '-' reads from stdin, and a destination of '-'
+    /// writes to stdout; only a single file can be copied this way.
     pub paths: Vec<String>,
+
+    /// Set by 'xcp verify MANIFEST [ROOT]', which checks the files
+    /// listed in a manifest rather than copying. ROOT defaults to the
+    /// root recorded in the manifest.
+    #[arg(skip)]
+    pub verify: bool,
+
+    /// The compiled '--exclude' and '--include' patterns.
+    #[arg(skip)]
+    pub filter: Option<Arc<Filter>>,
 }
 
 impl Opts {
     pub fn from_args() -> Result<Opts> {
-        Ok(Opts::parse())
+        let mut args = env::args_os().collect::<Vec<_>>();
+        // A source named 'verify' can be given as './verify'.
+        let verify = args.get(1).is_some_and(|arg| arg == "verify");
+        if verify {
+            args.remove(1);
+        }
+        imply_cp_values(&mut args);
+        let mut opts = Opts::parse_from(args);
+        opts.verify = verify;
+        opts.recursive |= opts.archive;
+        // The cp spellings of native options.
+        if opts.interactive && opts.no_clobber.is_none() {
+            opts.no_clobber = Some(NoClobber::Skip);
+        }
+        if opts.backup_numbered && opts.backup == Backup::None {
+            opts.backup = Backup::Numbered;
+        }
+        opts.filter = opts.compile_filter()?;
+        Ok(opts)
+    }
+
+    // The '--exclude', '--exclude-from' and '--include' patterns.
+    fn compile_filter(&self) -> Result<Option<Arc<Filter>>> {
+        let mut exclude = self.exclude.clone();
+        for file in &self.exclude_from {
+            let patterns = read_patterns(file).map_err(|e| {
+                XcpError::InvalidArguments(format!("Cannot read patterns from {:?}: {}", file, e))
+            })?;
+            exclude.extend(patterns);
+        }
+        if exclude.is_empty() && self.include.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Arc::new(Filter::new(&exclude, &self.include)?)))
+    }
+
+    // The attributes explicitly requested with '--preserve', '-p',
+    // '--archive' and the older per-attribute options.
+    fn requested_preserve(&self) -> PreserveSet {
+        let flags = [
+            (self.archive, PreserveSet::ALL),
+            (self.preserve_basic, PreserveSet::BASIC),
+            (self.ownership, PreserveSet::OWNERSHIP),
+            (self.preserve_hardlinks, PreserveSet::LINKS),
+        ];
+        flags.iter()
+            .filter(|(given, _)| *given)
+            .map(|(_, set)| *set)
+            .chain(self.preserve.iter().copied())
+            .fold(PreserveSet::NONE, PreserveSet::union)
+    }
+
+    /// The attributes to copy; '--no-preserve' takes precedence over
+    /// any other option.
+    pub fn preserve(&self) -> PreserveSet {
+        let mut set = PreserveSet::DEFAULT.union(self.requested_preserve());
+        if self.no_perms {
+            set = set.difference(PreserveSet::MODE.union(PreserveSet::CONTEXT).union(PreserveSet::XATTR));
+        }
+        if self.no_timestamps {
+            set = set.difference(PreserveSet::TIMESTAMPS);
+        }
+        self.no_preserve.iter()
+            .fold(set, |set, remove| set.difference(*remove))
+    }
+
+    /// How existing destinations are handled; '--conflict-suffix'
+    /// implies '--no-clobber=rename'.
+    pub fn no_clobber(&self) -> Option<NoClobber> {
+        self.no_clobber.or(self.conflict_suffix.as_ref().map(|_| NoClobber::Rename))
+    }
+
+    /// Whether to recreate btrfs subvolumes; see
+    /// '--preserve-subvolumes'.
+    pub fn preserve_subvolumes(&self) -> bool {
+        #[cfg(feature = "btrfs")]
+        return self.preserve_subvolumes;
+        #[cfg(not(feature = "btrfs"))]
+        return false;
+    }
+
+    /// The byte range to copy, if any of the range options are given.
+    pub fn byte_range(&self) -> Option<ByteRange> {
+        if self.offset.is_none() && self.length.is_none() && self.dest_offset.is_none() {
+            return None;
+        }
+        Some(ByteRange {
+            offset: self.offset.unwrap_or(0),
+            length: self.length,
+            dest_offset: self.dest_offset,
+        })
+    }
+
+    /// Whether symlinks given as sources are followed, as cp does.
+    pub fn follow_sources(&self) -> bool {
+        self.dereference || self.dereference_command_line
+            || !(self.no_dereference || self.archive)
+    }
+
+    /// Whether stdin or stdout is given as a path; see [STDIO_PATH].
+    pub fn uses_stdio(&self) -> bool {
+        self.paths.iter().any(|p| p == STDIO_PATH)
+    }
+
+    /// Whether the copy is written to stdout, in which case all other
+    /// output must go to stderr.
+    pub fn writes_stdout(&self) -> bool {
+        self.target_directory.is_none() && self.paths.len() > 1
+            && self.paths.last().is_some_and(|p| p == STDIO_PATH)
+    }
+
+    /// Check for options that can't be used together.
+    pub fn check_conflicts(&self) -> result::Result<(), XcpError> {
+        match CONFLICTS.iter().find(|c| (c.applies)(self)) {
+            Some(c) => Err(XcpError::ConflictingOptions(c.flags.0, c.flags.1, c.reason)),
+            None => Ok(()),
+        }
     }
 
     pub fn log_level(&self) -> LevelFilter {
+        match self.quiet {
+            0 => {}
+            1 => return LevelFilter::Error,
+            _ => return LevelFilter::Off,
+        }
         match self.verbose {
             0 => LevelFilter::Warn,
             1 => LevelFilter::Info,
@@ -180,27 +1453,261 @@ impl Opts {
 
 impl From<&Opts> for Config {
     fn from(opts: &Opts) -> Self {
+        // Without progress feedback files are copied in a single block.
+        let whole_files = (opts.no_progress || opts.quiet > 0) && opts.progress == ProgressMode::Bar;
         Config {
-            workers: if opts.workers == 0 {
-                num_cpus::get()
-            } else {
-                opts.workers
+            readers_per_device: opts.readers_per_device.map(|n| n as usize),
+            metadata_workers: opts.metadata_workers.map(|n| n as usize),
+            workers: match opts.workers.unwrap_or(4) {
+                0 => num_cpus::get(),
+                n => n,
             },
-            block_size: if opts.no_progress {
-                usize::MAX as u64
+            block_size: if whole_files {
+                u64::MAX
             } else {
-                opts.block_size
+                opts.block_size.unwrap_or(DEFAULT_BLOCK_SIZE)
             },
+            auto_block_size: !whole_files && opts.block_size.is_none(),
+            max_buffer_memory: opts.max_memory,
             gitignore: opts.gitignore,
-            no_clobber: opts.no_clobber,
-            no_perms: opts.no_perms,
-            no_timestamps: opts.no_timestamps,
-            ownership: opts.ownership,
+            filter: opts.filter.clone(),
+            no_clobber: opts.no_clobber()
+                .map(|m| if opts.recursive || m == NoClobber::Rename { m } else { NoClobber::Fail }),
+            conflict_suffix: opts.conflict_suffix.clone().unwrap_or_default(),
+            update: opts.update,
+            forbid_overwrite_newer: opts.forbid_overwrite_newer,
+            skip_same: opts.skip_same,
+            stamp_checksum: opts.stamp_checksum,
+            preserve: opts.preserve(),
+            no_dir_timestamps: opts.no_dir_timestamps,
+            preserve_subvolumes: opts.preserve_subvolumes(),
+            follow_dest_symlinks: opts.follow_dest_symlinks,
+            mkdir_parents: opts.mkdir_parents,
+            remove_destination: opts.remove_destination,
+            preserve_mode: opts.requested_preserve().contains(PreserveSet::MODE),
+            xattr_value_limit: Some(opts.xattr_value_limit)
+                .filter(|l| *l > 0),
+            xattrs_unsupported: None,
+            cache_linked_sources: opts.cache_linked_sources,
             dereference: opts.dereference,
+            dereference_sources: opts.follow_sources(),
+            one_file_system: opts.one_file_system,
             no_target_directory: opts.no_target_directory,
+            dest_subdir_from_source: opts.dest_subdir_from_source,
+            no_fallocate: opts.no_fallocate,
+            force_parblock: opts.force_parblock,
+            batch_dirents: opts.batch_dirents,
+            tiny_file_fastpath: !opts.no_tiny_file_fastpath,
+            sparse: opts.sparse == Some(Sparse::Always),
+            order: opts.order,
+            no_direct_io: opts.no_direct_io,
             fsync: opts.fsync,
+            staging_dir: opts.staging_dir.clone(),
             reflink: opts.reflink,
             backup: opts.backup,
+            dir_mode: opts.dir_mode,
+            new_dir_mode: opts.new_dir_mode,
+            prune_empty_dirs: opts.prune_empty_dirs,
+            invalid_name: opts.invalid_name,
+            name_profile: None,
+            continue_on_error: opts.continue_on_error,
+            max_errors: opts.max_errors,
+            max_error_rate: opts.max_error_rate,
+            timeout: opts.timeout,
+            file_timeout: opts.file_timeout,
+            really_continue_on_enospc: opts.really_continue_on_enospc,
+            read_retries: opts.read_retries,
+            fill_errors: opts.fill_errors,
+            checksum: opts.manifest.as_ref()
+                .map(|_| opts.manifest_hash),
+            dry_run: opts.dry_run,
+            itemize: opts.itemize,
+            delete: opts.delete,
+            report_actions: opts.journal.is_some(),
+            collect_results: false,
+            compare_checksum: opts.compare_checksum,
+            chmod: opts.chmod.clone(),
+            chown: opts.chown,
+            fs: filesystem(),
+            executor: opts.executor.clone(),
+        }
+    }
+}
+
+// With the `test_faults` feature, fail the operations listed in
+// `XCP_TEST_FAULTS`, one per line as `op:errno:path`, so the
+// integration tests can inject errors; see [libfs::FaultInjectingFs].
+#[cfg(feature = "test_faults")]
+fn filesystem() -> Arc<dyn FsOps> {
+    use libfs::{FaultInjectingFs, FsOp};
+
+    let Ok(faults) = env::var("XCP_TEST_FAULTS") else {
+        return Arc::new(RealFs);
+    };
+    let fs = FaultInjectingFs::new();
+    for fault in faults.lines() {
+        let mut parts = fault.splitn(3, ':');
+        let (Some(op), Some(errno), Some(path)) = (parts.next(), parts.next(), parts.next()) else {
+            panic!("Invalid fault {:?}", fault);
+        };
+        let op = match op {
+            "OpenSource" => FsOp::OpenSource,
+            "CreateDest" => FsOp::CreateDest,
+            "CopyBytes" => FsOp::CopyBytes,
+            "ReadAt" => FsOp::ReadAt,
+            "Mkdir" => FsOp::Mkdir,
+            "Stat" => FsOp::Stat,
+            "SetMetadata" => FsOp::SetMetadata,
+            "Truncate" => FsOp::Truncate,
+            _ => panic!("Invalid fault operation {:?}", op),
+        };
+        fs.fail(op, path, errno.parse().expect("Invalid fault errno"));
+    }
+    Arc::new(fs)
+}
+
+#[cfg(not(feature = "test_faults"))]
+fn filesystem() -> Arc<dyn FsOps> {
+    Arc::new(RealFs)
+}
+
+// Sizes are parsed by unbytify, but its errors don't say what was
+// expected.
+fn parse_size(spec: &str) -> result::Result<u64, String> {
+    unbytify(spec).map_err(|_| "expected a size such as 64K, 1M or 8MiB".to_string())
+}
+
+fn parse_memory(spec: &str) -> result::Result<u64, String> {
+    match parse_size(spec)? {
+        0 => Err("must be greater than 0".to_string()),
+        size => Ok(size),
+    }
+}
+
+fn parse_duration(spec: &str) -> result::Result<Duration, XcpError> {
+    let (num, unit) = match spec.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => spec.split_at(i),
+        None => (spec, "s"),
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => 0,
+    };
+    num.parse::<u64>().ok()
+        .and_then(|n| n.checked_mul(scale))
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+        .ok_or_else(|| XcpError::InvalidArguments(format!("Invalid duration: {}", spec)))
+}
+
+fn parse_dir_mode(mode: &str) -> result::Result<u32, XcpError> {
+    Some(mode)
+        .filter(|m| !m.is_empty() && m.chars().all(|c| c.is_digit(8)))
+        .and_then(|m| u32::from_str_radix(m, 8).ok())
+        .filter(|m| *m <= 0o7777)
+        .ok_or_else(|| XcpError::InvalidArguments(format!("Invalid directory mode: {}", mode)))
+}
+
+fn parse_chmod(spec: &str) -> result::Result<Chmod, XcpError> {
+    let invalid = || XcpError::InvalidArguments(format!("Invalid mode: {}", spec));
+    let mut clauses = Vec::new();
+
+    for entry in spec.split(',') {
+        let (target, entry) = match entry.strip_prefix('D') {
+            Some(rest) => (ModeTarget::Dirs, rest),
+            None => match entry.strip_prefix('F') {
+                Some(rest) => (ModeTarget::Files, rest),
+                None => (ModeTarget::All, entry),
+            },
+        };
+
+        if !entry.is_empty() && entry.chars().all(|c| c.is_digit(8)) {
+            let mode = u32::from_str_radix(entry, 8)
+                .ok()
+                .filter(|m| *m <= 0o7777)
+                .ok_or_else(invalid)?;
+            clauses.push(ModeClause { target, change: ModeChange::Absolute(mode) });
+            continue;
+        }
+
+        let ops_at = entry.find(['+', '-', '=']).ok_or_else(invalid)?;
+        let (whos, mut rest) = entry.split_at(ops_at);
+        let mut who = 0;
+        for c in whos.chars() {
+            who |= match c {
+                'u' => 0o4700,
+                'g' => 0o2070,
+                'o' => 0o1007,
+                'a' => 0o7777,
+                _ => return Err(invalid()),
+            };
+        }
+        if who == 0 {
+            who = 0o7777;
+        }
+
+        // Each operator may be followed by its own permissions,
+        // e.g. 'u+r-w'.
+        while let Some(opc) = rest.chars().next() {
+            let op = match opc {
+                '+' => ModeOp::Add,
+                '-' => ModeOp::Remove,
+                '=' => ModeOp::Set,
+                _ => return Err(invalid()),
+            };
+            rest = &rest[1..];
+            let end = rest.find(['+', '-', '=']).unwrap_or(rest.len());
+            let (permstr, next) = rest.split_at(end);
+            let (mut perms, mut exec_if_any) = (0, false);
+            for c in permstr.chars() {
+                match c {
+                    'r' => perms |= 0o444,
+                    'w' => perms |= 0o222,
+                    'x' => perms |= 0o111,
+                    'X' => exec_if_any = true,
+                    's' => perms |= 0o6000,
+                    't' => perms |= 0o1000,
+                    _ => return Err(invalid()),
+                }
+            }
+            clauses.push(ModeClause {
+                target,
+                change: ModeChange::Symbolic { who, op, perms, exec_if_any },
+            });
+            rest = next;
+        }
+    }
+
+    Ok(Chmod(clauses))
+}
+
+fn parse_chown(spec: &str) -> result::Result<Chown, XcpError> {
+    let (user, group) = match spec.split_once(':') {
+        Some((u, g)) => (u, g),
+        None => (spec, ""),
+    };
+    let lookup = |name: &str, kind: &str, db: fn(&str) -> result::Result<Option<u32>, libfs::Error>| {
+        if name.is_empty() {
+            return Ok(None);
+        }
+        if let Ok(id) = name.parse::<u32>() {
+            return Ok(Some(id));
+        }
+        match db(name) {
+            Ok(Some(id)) => Ok(Some(id)),
+            Ok(None) => Err(XcpError::InvalidArguments(format!("Unknown {}: {}", kind, name))),
+            Err(e) => Err(XcpError::InvalidArguments(format!("Failed to look up {} {}: {}", kind, name, e))),
         }
+    };
+    let chown = Chown {
+        uid: lookup(user, "user", libfs::lookup_user)?,
+        gid: lookup(group, "group", libfs::lookup_group)?,
+    };
+    if chown == Chown::default() {
+        return Err(XcpError::InvalidArguments(format!("Invalid owner: {}", spec)));
     }
+    Ok(chown)
 }
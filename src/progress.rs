@@ -14,21 +14,199 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::result;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::color;
+use crate::metrics::Report;
 use crate::options::Opts;
 
-use libxcp::errors::Result;
+use indicatif::HumanBytes;
+use libxcp::compare::Item;
+use libxcp::errors::{error_code, error_sub_code, unexpected_value, Result, XcpError};
+use serde::Serialize;
+
+/// How progress is reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressMode {
+    /// A progress bar on stderr.
+    Bar,
+    /// One JSON event per line on stdout, or stderr if the copy is
+    /// written to stdout.
+    Json,
+}
+
+impl FromStr for ProgressMode {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "bar" => Ok(ProgressMode::Bar),
+            "json" => Ok(ProgressMode::Json),
+            _ => Err(unexpected_value("progress", s, &["bar", "json"])),
+        }
+    }
+}
 
 struct NoopBar;
 
+struct JsonEvents {
+    stderr: bool,
+}
+
+// The events emitted in JSON mode.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    Size { bytes: u64 },
+    TotalAdjust { bytes: i64 },
+    Copied { bytes: u64 },
+    FileStarted { id: u64, path: &'a Path },
+    FileCompleted { id: u64 },
+    Stalled { seconds: u64, path: &'a Path },
+    Resumed,
+    Item(&'a Item),
+    DestCopied { dest: usize, bytes: u64 },
+    DestFailed { dest: usize },
+    Finalizing { done: u64, total: u64 },
+    Metrics(&'a Report),
+    Error {
+        code: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sub_code: Option<&'static str>,
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        source: Option<&'a Path>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        dest: Option<&'a Path>,
+    },
+    Complete,
+    /// The outcome of the command, after everything else; see
+    /// [summary].
+    Summary {
+        success: bool,
+        exit_code: u8,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        code: Option<&'static str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sub_code: Option<&'static str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+}
+
 struct VisualBar {
     bar: indicatif::ProgressBar,
+    style: indicatif::ProgressStyle,
+    stalled_style: indicatif::ProgressStyle,
+    finalizing_style: indicatif::ProgressStyle,
+    /// The bar shows metadata progress rather than bytes; see
+    /// [ProgressBar::finalizing].
+    finalizing: Cell<bool>,
+    rate: RefCell<Rate>,
+    current: Option<RefCell<CurrentFiles>>,
+    /// A line for each destination of a fan-out copy.
+    dests: Vec<indicatif::ProgressBar>,
+}
+
+/// How often the displayed copy rate is updated.
+const RATE_SAMPLE: Duration = Duration::from_millis(500);
+
+/// The time constant of the rate average; samples older than this
+/// have progressively less weight.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+// An exponentially weighted moving average of the copy rate, as the
+// instantaneous rate varies a lot between blocks and files.
+struct Rate {
+    bytes: u64,
+    since: Instant,
+    average: Option<f64>,
+}
+
+impl Rate {
+    fn new() -> Self {
+        Rate { bytes: 0, since: Instant::now(), average: None }
+    }
+
+    // Add copied bytes, returning the new average if a sample was
+    // taken.
+    fn add(&mut self, bytes: u64) -> Option<f64> {
+        self.bytes += bytes;
+        let elapsed = self.since.elapsed();
+        if elapsed < RATE_SAMPLE {
+            return None;
+        }
+        let secs = elapsed.as_secs_f64();
+        let sample = self.bytes as f64 / secs;
+        // Weight by the sample period, as samples are taken irregularly.
+        let alpha = 1.0 - (-secs / RATE_WINDOW.as_secs_f64()).exp();
+        let average = self.average.map_or(sample, |avg| avg + alpha * (sample - avg));
+        *self = Rate { bytes: 0, since: Instant::now(), average: Some(average) };
+        Some(average)
+    }
+}
+
+// Lines below the bar listing the files currently being copied,
+// keyed by the file id from the status updates.
+struct CurrentFiles {
+    multi: indicatif::MultiProgress,
+    max_lines: usize,
+    lines: Vec<indicatif::ProgressBar>,
+    inflight: BTreeMap<u64, PathBuf>,
 }
 
 pub trait ProgressBar {
     #[allow(unused)]
     fn set_size(&self, size: u64);
     fn inc_size(&self, size: u64);
+    /// Change the total size, e.g. when files are skipped. The total
+    /// never drops below the amount already copied.
+    fn adjust_size(&self, delta: i64);
     fn inc(&self, size: u64);
+    fn file_started(&self, id: u64, path: &Path);
+    fn file_completed(&self, id: u64);
+    /// Called periodically while waiting for updates.
+    fn tick(&self) {
+    }
+    /// Report that no data has been copied for the given number of
+    /// seconds, and the oldest in-flight file; `None` when the copy
+    /// resumes.
+    fn stalled(&self, stall: Option<(u64, &Path)>);
+    /// Report an itemized change. Unlike the other updates these are
+    /// always output, as they are the result of '--itemize'.
+    fn item(&self, item: &Item) {
+        println!("{}", item);
+    }
+    /// Report the metrics of the copy workers; see
+    /// '--metrics-interval'. As with items these are always output.
+    fn metrics(&self, report: &Report) {
+        eprintln!("{}", report);
+    }
+    /// Report an error from the copy, as it is logged.
+    fn error(&self, _err: &XcpError) {
+    }
+    /// Add to the total of each remaining destination of a fan-out
+    /// copy.
+    fn dests_inc_size(&self, _size: u64) {
+    }
+    /// Report bytes written to a destination of a fan-out copy, by
+    /// index.
+    fn dest_inc(&self, _dest: usize, _bytes: u64) {
+    }
+    /// Report that nothing more will be written to a destination of
+    /// a fan-out copy.
+    fn dest_failed(&self, _dest: usize) {
+    }
+    /// Report progress applying metadata, by entry, once all data
+    /// has been copied.
+    fn finalizing(&self, _done: u64, _total: u64) {
+    }
     fn end(&self);
 }
 
@@ -38,9 +216,84 @@ impl ProgressBar for NoopBar {
     }
     fn inc_size(&self, _size: u64) {
     }
+    fn adjust_size(&self, _delta: i64) {
+    }
     fn inc(&self, _size: u64) {
     }
+    fn file_started(&self, _id: u64, _path: &Path) {
+    }
+    fn file_completed(&self, _id: u64) {
+    }
+    fn stalled(&self, _stall: Option<(u64, &Path)>) {
+    }
+    fn end(&self) {
+    }
+}
+
+impl ProgressBar for JsonEvents {
+    fn set_size(&self, _size: u64) {
+    }
+    fn inc_size(&self, size: u64) {
+        self.emit(&Event::Size { bytes: size });
+    }
+    fn adjust_size(&self, delta: i64) {
+        self.emit(&Event::TotalAdjust { bytes: delta });
+    }
+    fn inc(&self, size: u64) {
+        self.emit(&Event::Copied { bytes: size });
+    }
+    fn file_started(&self, id: u64, path: &Path) {
+        self.emit(&Event::FileStarted { id, path });
+    }
+    fn file_completed(&self, id: u64) {
+        self.emit(&Event::FileCompleted { id });
+    }
+    fn stalled(&self, stall: Option<(u64, &Path)>) {
+        match stall {
+            Some((seconds, path)) => self.emit(&Event::Stalled { seconds, path }),
+            None => self.emit(&Event::Resumed),
+        }
+    }
+    fn item(&self, item: &Item) {
+        self.emit(&Event::Item(item));
+    }
+    fn dest_inc(&self, dest: usize, bytes: u64) {
+        self.emit(&Event::DestCopied { dest, bytes });
+    }
+    fn dest_failed(&self, dest: usize) {
+        self.emit(&Event::DestFailed { dest });
+    }
+    fn finalizing(&self, done: u64, total: u64) {
+        self.emit(&Event::Finalizing { done, total });
+    }
+    fn metrics(&self, report: &Report) {
+        self.emit(&Event::Metrics(report));
+    }
+    fn error(&self, err: &XcpError) {
+        self.emit(&Event::Error {
+            code: err.code(),
+            sub_code: err.sub_code(),
+            message: err.to_string(),
+            source: err.source_path(),
+            dest: err.dest_path(),
+        });
+    }
     fn end(&self) {
+        self.emit(&Event::Complete);
+    }
+}
+
+impl JsonEvents {
+    fn emit(&self, event: &Event) {
+        let mut out: Box<dyn Write> = if self.stderr {
+            Box::new(io::stderr().lock())
+        } else {
+            Box::new(io::stdout().lock())
+        };
+        // Output errors (e.g. a closed pipe) are not fatal to the copy.
+        let _ = serde_json::to_writer(&mut out, event)
+            .map_err(io::Error::from)
+            .and_then(|_| writeln!(out));
     }
 }
 
@@ -53,30 +306,286 @@ impl ProgressBar for VisualBar {
         self.bar.inc_length(size);
     }
 
+    fn adjust_size(&self, delta: i64) {
+        let len = self.bar.length().unwrap_or(0).saturating_add_signed(delta);
+        self.bar.set_length(len.max(self.bar.position()));
+    }
+
     fn inc(&self, size: u64) {
         self.bar.inc(size);
+        self.update_rate(size);
+    }
+
+    fn tick(&self) {
+        self.update_rate(0);
+    }
+
+    fn stalled(&self, stall: Option<(u64, &Path)>) {
+        // Stalls are still logged, but the bar no longer shows the
+        // data copied.
+        if self.finalizing.get() {
+            return;
+        }
+        match stall {
+            Some((secs, path)) => {
+                self.bar.set_style(self.stalled_style.clone());
+                self.bar.set_message(format!("Stalled for {}s on {}", secs, path.display()));
+            }
+            None => {
+                self.bar.set_style(self.style.clone());
+                self.bar.set_message("");
+            }
+        }
+    }
+
+    fn file_started(&self, id: u64, path: &Path) {
+        if let Some(ref current) = self.current {
+            let mut current = current.borrow_mut();
+            current.inflight.insert(id, path.to_path_buf());
+            current.refresh();
+        }
+    }
+
+    fn file_completed(&self, id: u64) {
+        if let Some(ref current) = self.current {
+            let mut current = current.borrow_mut();
+            current.inflight.remove(&id);
+            current.refresh();
+        }
+    }
+
+    fn item(&self, item: &Item) {
+        self.bar.suspend(|| println!("{}", item));
+    }
+
+    fn metrics(&self, report: &Report) {
+        self.bar.suspend(|| eprintln!("{}", report));
+    }
+
+    fn dests_inc_size(&self, size: u64) {
+        for line in self.dests.iter().filter(|l| !l.is_finished()) {
+            line.inc_length(size);
+        }
+    }
+
+    fn dest_inc(&self, dest: usize, bytes: u64) {
+        if let Some(line) = self.dests.get(dest) {
+            line.inc(bytes);
+        }
+    }
+
+    fn dest_failed(&self, dest: usize) {
+        if let Some(line) = self.dests.get(dest) {
+            line.abandon_with_message("failed");
+        }
+    }
+
+    fn finalizing(&self, done: u64, total: u64) {
+        if !self.finalizing.replace(true) {
+            self.bar.set_style(self.finalizing_style.clone());
+            self.bar.set_message("");
+        }
+        self.bar.set_length(total);
+        self.bar.set_position(done);
     }
 
     fn end(&self) {
+        if let Some(ref current) = self.current {
+            let mut current = current.borrow_mut();
+            current.inflight.clear();
+            current.refresh();
+        }
+        for line in self.dests.iter().filter(|l| !l.is_finished()) {
+            line.finish();
+        }
         self.bar.finish();
     }
 }
 
 impl VisualBar {
-    fn new(size: u64) -> Result<Self> {
-        let bar = indicatif::ProgressBar::new(size).with_style(
-            indicatif::ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")?
-                .progress_chars("#>-"),
-        );
-        Ok(Self { bar })
+    fn new(size: u64, show_current: usize, dests: &[PathBuf]) -> Result<Self> {
+        let template = "[{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({prefix}, {eta})";
+        let style = indicatif::ProgressStyle::default_bar()
+            .template(template)?
+            .progress_chars("#>-");
+        let stalled_style = indicatif::ProgressStyle::default_bar()
+            .template(&format!("{}\n{{msg:.yellow}}", template))?
+            .progress_chars("#>-");
+        let finalizing_style = indicatif::ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] [{wide_bar:.cyan/blue}] finalizing metadata: {human_pos}/{human_len}")?
+            .progress_chars("#>-");
+        let bar = indicatif::ProgressBar::new(size)
+            .with_style(style.clone())
+            .with_prefix(format!("{}/s", HumanBytes(0)));
+        let multi = indicatif::MultiProgress::new();
+        if show_current > 0 || !dests.is_empty() {
+            multi.add(bar.clone());
+        }
+        let dest_style = indicatif::ProgressStyle::default_bar()
+            .template("{prefix} [{bar:30.green/blue}] {bytes}/{total_bytes} {msg:.red}")?
+            .progress_chars("#>-");
+        let width = dests.iter().map(|d| d.to_string_lossy().chars().count()).max().unwrap_or(0).min(DEST_WIDTH);
+        let dests = dests.iter()
+            .map(|dest| {
+                let name = elide_middle(&dest.to_string_lossy(), width);
+                multi.add(indicatif::ProgressBar::new(0)
+                          .with_style(dest_style.clone())
+                          .with_prefix(format!("{:<width$}", name)))
+            })
+            .collect();
+        let current = (show_current > 0).then(|| RefCell::new(CurrentFiles {
+            multi,
+            max_lines: show_current,
+            lines: Vec::with_capacity(show_current),
+            inflight: BTreeMap::new(),
+        }));
+        Ok(Self {
+            bar,
+            style,
+            stalled_style,
+            finalizing_style,
+            finalizing: Cell::new(false),
+            rate: RefCell::new(Rate::new()),
+            current,
+            dests,
+        })
     }
+
+    // A spinner with the bytes copied and rate, for streams of
+    // unknown length.
+    fn spinner() -> Result<Self> {
+        let template = "[{elapsed_precise}] {spinner:.cyan} {bytes} ({prefix})";
+        let style = indicatif::ProgressStyle::default_spinner()
+            .template(template)?;
+        let stalled_style = indicatif::ProgressStyle::default_spinner()
+            .template(&format!("{}\n{{msg:.yellow}}", template))?;
+        let bar = indicatif::ProgressBar::new_spinner()
+            .with_style(style.clone())
+            .with_prefix(format!("{}/s", HumanBytes(0)));
+        bar.enable_steady_tick(SPINNER_TICK);
+        Ok(Self {
+            bar,
+            finalizing_style: style.clone(),
+            style,
+            stalled_style,
+            finalizing: Cell::new(false),
+            rate: RefCell::new(Rate::new()),
+            current: None,
+            dests: Vec::new(),
+        })
+    }
+
+    fn update_rate(&self, bytes: u64) {
+        if let Some(rate) = self.rate.borrow_mut().add(bytes) {
+            self.bar.set_prefix(format!("{}/s", HumanBytes(rate as u64)));
+        }
+    }
+}
+
+impl CurrentFiles {
+    fn refresh(&mut self) {
+        let want = self.inflight.len().min(self.max_lines);
+        while self.lines.len() < want {
+            let line = indicatif::ProgressBar::new_spinner()
+                .with_style(indicatif::ProgressStyle::with_template("{msg}")
+                            .expect("Invalid static template"));
+            self.lines.push(self.multi.add(line));
+        }
+        while self.lines.len() > want {
+            if let Some(line) = self.lines.pop() {
+                line.finish_and_clear();
+                self.multi.remove(&line);
+            }
+        }
+
+        let width = console::Term::stderr().size().1 as usize;
+        for (line, path) in self.lines.iter().zip(self.inflight.values()) {
+            line.set_message(elide_middle(&path.to_string_lossy(), width.saturating_sub(1)));
+        }
+    }
+}
+
+// Shorten a string to fit the width by replacing the middle with an
+// ellipsis.
+fn elide_middle(s: &str, width: usize) -> String {
+    let len = s.chars().count();
+    if len <= width || width < 5 {
+        return s.to_string();
+    }
+    let tail = (width - 1) / 2;
+    let head = width - 1 - tail;
+    let head_str = s.chars().take(head).collect::<String>();
+    let tail_str = s.chars().skip(len - tail).collect::<String>();
+    format!("{}…{}", head_str, tail_str)
+}
+
+/// The number of in-flight files to display with `-v` if
+/// `--show-current` is not specified.
+const DEFAULT_SHOW_CURRENT: usize = 4;
+
+/// The widest destination name shown by a fan-out bar; longer ones
+/// are elided.
+const DEST_WIDTH: usize = 30;
+
+/// How often the stream spinner is redrawn, as updates may be sparse.
+const SPINNER_TICK: Duration = Duration::from_millis(200);
+
+// Whether the visual bar is shown. It is only drawn on a terminal, so
+// isn't created at all otherwise.
+fn shows_bar(opts: &Opts) -> bool {
+    !(opts.no_progress || opts.quiet > 0) && io::stderr().is_terminal()
+}
+
+// Colour the visual bar as the logs are.
+fn set_bar_color(opts: &Opts) {
+    console::set_colors_enabled_stderr(color::enabled(opts, &io::stderr()));
+}
+
+/// With '--progress=json', emit the result of the command and the
+/// `exit_code` it exits with as the final event.
+pub fn summary(opts: &Opts, result: &Result<()>, exit_code: u8) {
+    if opts.progress != ProgressMode::Json {
+        return;
+    }
+    let err = result.as_ref().err();
+    JsonEvents { stderr: opts.writes_stdout() }.emit(&Event::Summary {
+        success: err.is_none(),
+        exit_code,
+        code: err.map(error_code),
+        sub_code: err.and_then(error_sub_code),
+        message: err.map(|e| e.to_string()),
+    });
 }
 
 pub fn create_bar(opts: &Opts, size: u64) -> Result<Box<dyn ProgressBar>> {
-    if opts.no_progress {
+    create_fanout_bar(opts, size, &[])
+}
+
+/// As [create_bar], with a line below the visual bar for each
+/// destination of a fan-out copy.
+pub fn create_fanout_bar(opts: &Opts, size: u64, dests: &[PathBuf]) -> Result<Box<dyn ProgressBar>> {
+    if opts.progress == ProgressMode::Json {
+        Ok(Box::new(JsonEvents { stderr: opts.writes_stdout() }))
+    } else if !shows_bar(opts) {
         Ok(Box::new(NoopBar {}))
     } else {
-        Ok(Box::new(VisualBar::new(size)?))
+        let show_current = match opts.show_current {
+            Some(n) => n,
+            None if opts.verbose > 0 => DEFAULT_SHOW_CURRENT,
+            None => 0,
+        };
+        set_bar_color(opts);
+        Ok(Box::new(VisualBar::new(size, show_current, dests)?))
+    }
+}
+
+/// Create progress reporting for a stream of unknown length; as
+/// [create_bar], but the visual bar is a spinner without a total.
+pub fn create_stream_bar(opts: &Opts) -> Result<Box<dyn ProgressBar>> {
+    if opts.progress == ProgressMode::Bar && shows_bar(opts) {
+        set_bar_color(opts);
+        Ok(Box::new(VisualBar::spinner()?))
+    } else {
+        create_bar(opts, 0)
     }
 }
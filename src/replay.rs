@@ -0,0 +1,201 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! `xcp --replay JOURNAL`: copy again the files a journal written
+//! with '--journal' recorded as skipped or failed, e.g. once the
+//! conflicts that caused them are resolved, without walking the
+//! source trees again; see [crate::journal].
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use libxcp::config::Config;
+use libxcp::drivers::load_driver;
+use libxcp::errors::{unexpected_value, Result, XcpError};
+use libxcp::results::CopyStats;
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::journal::fingerprint;
+use crate::options::Opts;
+use crate::run_copy;
+
+/// The journalled files to replay; see '--only'.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplayFilter {
+    /// Files skipped because the destination existed.
+    Skipped,
+    /// Files that failed to copy.
+    Errored,
+}
+
+impl FromStr for ReplayFilter {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "skipped" => Ok(ReplayFilter::Skipped),
+            "errored" => Ok(ReplayFilter::Errored),
+            _ => Err(unexpected_value("only", s, &["skipped", "errored"])),
+        }
+    }
+}
+
+// The fields of a journal record used to replay it; see
+// [crate::journal].
+#[derive(Deserialize)]
+struct Record {
+    action: String,
+    reason: Option<String>,
+    path: Option<PathBuf>,
+    from: Option<PathBuf>,
+    to: Option<PathBuf>,
+    source: Option<PathBuf>,
+    dest: Option<PathBuf>,
+    size: Option<u64>,
+    cwd: Option<PathBuf>,
+    fingerprint: Option<String>,
+}
+
+// A file to copy again.
+struct Replayed {
+    source: PathBuf,
+    dest: PathBuf,
+    // The size of the source when it was journalled, if known.
+    size: Option<u64>,
+}
+
+// The files in a journal to replay, in the order they were recorded,
+// and the fingerprints of the options of the runs that recorded them.
+// A file copied by a later run in the journal, e.g. an earlier replay,
+// is dropped.
+fn read_journal(path: &Path, filter: Option<ReplayFilter>) -> Result<(Vec<Replayed>, HashSet<String>)> {
+    let text = fs::read_to_string(path)?;
+    let mut cwd = env::current_dir()?;
+    let mut run_fingerprint = None;
+    let mut fingerprints = HashSet::new();
+    let mut files: Vec<Option<Replayed>> = Vec::new();
+    let mut pending: HashMap<PathBuf, usize> = HashMap::new();
+
+    for (n, line) in text.lines().enumerate() {
+        // The last line may be partial after a crash.
+        let record = match serde_json::from_str::<Record>(line) {
+            Ok(record) => record,
+            Err(e) => {
+                warn!("Ignoring unreadable record on line {} of {:?}: {}", n + 1, path, e);
+                continue;
+            }
+        };
+        let replayed = match (record.action.as_str(), filter) {
+            ("started", _) => {
+                cwd = record.cwd.unwrap_or(cwd);
+                run_fingerprint = record.fingerprint;
+                continue;
+            }
+            ("skipped", None | Some(ReplayFilter::Skipped)) if record.reason.as_deref() == Some("exists") =>
+                record.source.zip(record.path),
+            ("error", None | Some(ReplayFilter::Errored)) => record.source.zip(record.dest),
+            ("copied", _) | ("kept-both", _) => {
+                let copied = if record.action == "copied" { record.to } else { record.from };
+                if let Some(i) = copied.and_then(|dest| pending.remove(&cwd.join(dest))) {
+                    files[i] = None;
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        // Errors that aren't about a single file have no paths.
+        let Some((source, dest)) = replayed else {
+            continue;
+        };
+        let dest = cwd.join(dest);
+        if let Some(i) = pending.insert(dest.clone(), files.len()) {
+            files[i] = None;
+        }
+        files.push(Some(Replayed { source: cwd.join(source), dest, size: record.size }));
+        fingerprints.extend(run_fingerprint.clone());
+    }
+    Ok((files.into_iter().flatten().collect(), fingerprints))
+}
+
+// Why a journalled file can't be safely replayed, if it can't.
+fn unchanged(file: &Replayed) -> result::Result<(), &'static str> {
+    let meta = file.source.symlink_metadata()
+        .map_err(|_| "the source no longer exists")?;
+    if meta.is_file() && file.size.is_some_and(|size| size != meta.len()) {
+        return Err("the source has changed size");
+    }
+    if !file.dest.parent().is_some_and(Path::is_dir) {
+        return Err("the destination directory no longer exists");
+    }
+    Ok(())
+}
+
+/// Copy the files recorded in a journal as skipped or failed, with
+/// the current options. Files whose source has changed since they
+/// were journalled are reported, and cause an error once the rest are
+/// copied.
+pub fn replay(opts: &Opts, journal: &Path) -> Result<()> {
+    if !opts.paths.is_empty() {
+        return Err(XcpError::InvalidArguments(
+            "--replay reads the files to copy from the journal, so takes no paths".to_string()).into());
+    }
+    let (files, fingerprints) = read_journal(journal, opts.only)?;
+    // Each file is copied to exactly the destination journalled.
+    let config = Arc::new(Config { no_target_directory: true, ..Config::from(opts) });
+    let current = fingerprint(&config);
+    if fingerprints.iter().any(|f| *f != current) {
+        warn!("{:?} was written with different options; replaying with the current ones", journal);
+    }
+
+    let mut changed = 0;
+    let files = files.into_iter()
+        .filter(|file| match unchanged(file) {
+            Ok(()) => true,
+            Err(reason) => {
+                warn!("Not replaying {:?} to {:?}: {}", file.source, file.dest, reason);
+                changed += 1;
+                false
+            }
+        })
+        .collect::<Vec<_>>();
+    if files.is_empty() && changed == 0 {
+        info!("Nothing to replay in {:?}", journal);
+        return Ok(());
+    }
+    info!("Replaying {} files from {:?}", files.len(), journal);
+
+    let driver = load_driver(opts.driver, &config)?;
+    let metrics = driver.metrics();
+    run_copy(opts, &config, None, None, Some(metrics), Box::new(move |stats| {
+        let mut totals = CopyStats::default();
+        for file in files {
+            totals.merge(driver.copy(vec![file.source], &file.dest, stats.clone())?);
+        }
+        Ok(totals)
+    }))?;
+
+    if changed > 0 {
+        return Err(XcpError::CopyError(
+            format!("{} journalled files have changed and were not replayed", changed)).into());
+    }
+    Ok(())
+}
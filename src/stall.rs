@@ -0,0 +1,93 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Detection of stalled copies, e.g. on an unresponsive network
+//! filesystem, which would otherwise just leave the progress bar
+//! frozen.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use libxcp::errors::{Result, XcpError};
+use log::{info, warn};
+
+use crate::options::Opts;
+use crate::progress::ProgressBar;
+
+/// Tracks in-flight files and the time of the last progress.
+pub struct StallMonitor {
+    warn_after: Option<Duration>,
+    abort_after: Option<Duration>,
+    inflight: BTreeMap<u64, PathBuf>,
+    last_progress: Instant,
+    /// Whole seconds of the stall last reported, if stalled.
+    reported: Option<u64>,
+}
+
+impl StallMonitor {
+    pub fn new(opts: &Opts) -> StallMonitor {
+        StallMonitor {
+            warn_after: Some(Duration::from_secs(opts.stall_warning)).filter(|d| !d.is_zero()),
+            abort_after: opts.stall_timeout.map(Duration::from_secs),
+            inflight: BTreeMap::new(),
+            last_progress: Instant::now(),
+            reported: None,
+        }
+    }
+
+    pub fn file_started(&mut self, id: u64, path: PathBuf, pb: &dyn ProgressBar) {
+        self.inflight.insert(id, path);
+        self.progress(pb);
+    }
+
+    pub fn file_completed(&mut self, id: u64, pb: &dyn ProgressBar) {
+        self.inflight.remove(&id);
+        self.progress(pb);
+    }
+
+    /// Record that data was copied, clearing any stall.
+    pub fn progress(&mut self, pb: &dyn ProgressBar) {
+        self.last_progress = Instant::now();
+        if let Some(secs) = self.reported.take() {
+            info!("Copy resumed after stalling for {}s", secs);
+            pb.stalled(None);
+        }
+    }
+
+    /// Check for a stall, reporting it if necessary. Returns an error
+    /// if the stall has passed the abort timeout.
+    pub fn check(&mut self, pb: &dyn ProgressBar) -> Result<()> {
+        // The oldest in-flight file is the most likely culprit.
+        let Some(path) = self.inflight.values().next() else {
+            self.last_progress = Instant::now();
+            return Ok(());
+        };
+        let stalled = self.last_progress.elapsed();
+        let secs = stalled.as_secs();
+        if self.abort_after.is_some_and(|abort| stalled >= abort) {
+            return Err(XcpError::CopyStalled(path.clone(), secs).into());
+        }
+        if self.warn_after.is_some_and(|warn| stalled >= warn) && self.reported != Some(secs) {
+            if self.reported.is_none() {
+                warn!("Copy stalled for {}s on {:?}", secs, path);
+            }
+            self.reported = Some(secs);
+            pb.stalled(Some((secs, path)));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Per-device copy statistics, to help identify which disk is the
+//! bottleneck when copying between several mounts.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use indicatif::HumanBytes;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Role {
+    Source,
+    Dest,
+}
+
+// Bytes copied on a device, and the period over which it was active.
+struct DeviceTotal {
+    bytes: u64,
+    first: Instant,
+    last: Instant,
+}
+
+#[derive(Default)]
+pub struct DeviceStats {
+    totals: BTreeMap<(Role, u64), DeviceTotal>,
+}
+
+impl DeviceStats {
+    /// Record a completed file.
+    pub fn add(&mut self, source: u64, dest: u64, bytes: u64, elapsed: Duration) {
+        let now = Instant::now();
+        let start = now.checked_sub(elapsed).unwrap_or(now);
+        for key in [(Role::Source, source), (Role::Dest, dest)] {
+            let total = self.totals.entry(key).or_insert(DeviceTotal {
+                bytes: 0,
+                first: start,
+                last: now,
+            });
+            total.bytes += bytes;
+            total.first = total.first.min(start);
+            total.last = now;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.totals.is_empty()
+    }
+}
+
+impl fmt::Display for DeviceStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<8} {:<12} {:>12} {:>14}", "role", "device", "bytes", "rate")?;
+        for ((role, dev), total) in &self.totals {
+            let role = match role {
+                Role::Source => "source",
+                Role::Dest => "dest",
+            };
+            let secs = (total.last - total.first).as_secs_f64();
+            let rate = if secs > 0.0 {
+                format!("{}/s", HumanBytes((total.bytes as f64 / secs) as u64))
+            } else {
+                "-".to_string()
+            };
+            let (major, minor) = dev_numbers(*dev);
+            write!(f, "\n{:<8} {:<12} {:>12} {:>14}",
+                   role, format!("{}:{}", major, minor), HumanBytes(total.bytes).to_string(), rate)?;
+        }
+        Ok(())
+    }
+}
+
+// Split a device id into major and minor numbers, using the glibc
+// encoding.
+fn dev_numbers(dev: u64) -> (u64, u64) {
+    let major = ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0x0000_0fff);
+    let minor = ((dev >> 12) & 0xffff_ff00) | (dev & 0x0000_00ff);
+    (major, minor)
+}
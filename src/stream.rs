@@ -0,0 +1,129 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Copies from stdin or to stdout, given as '-'. The length isn't
+//! known in advance, so the copy is sequential and bypasses the
+//! drivers, and no metadata is copied.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+use indicatif::HumanBytes;
+use libfs::sync;
+use libxcp::config::Config;
+use libxcp::errors::{Result, XcpError};
+use libxcp::feedback::{ChannelUpdater, StatusUpdate, StatusUpdater};
+use libxcp::operations::{copy_stream, create_stream_dest};
+use log::{debug, info, warn};
+
+use crate::options::{Opts, STDIO_PATH};
+use crate::{lock_dest, progress};
+
+/// Copy a single file or stdin to a file or stdout.
+pub fn stream(opts: &Opts) -> Result<()> {
+    let [source, dest] = opts.paths.as_slice() else {
+        return Err(XcpError::InvalidArguments(
+            "'-' can only be used with a single source and destination".to_string()).into());
+    };
+    let (source, dest) = (PathBuf::from(source), PathBuf::from(dest));
+    let from_stdin = source == Path::new(STDIO_PATH);
+    let to_stdout = dest == Path::new(STDIO_PATH);
+
+    let infd = if from_stdin {
+        None
+    } else if !source.exists() {
+        return Err(XcpError::SourceNotFound(source).into());
+    } else if source.is_dir() {
+        return Err(XcpError::InvalidSource {
+            path: source,
+            reason: "Only a single file can be copied to stdout.",
+        }.into());
+    } else {
+        Some(File::open(&source)?)
+    };
+
+    let config = Arc::new(Config::from(opts));
+    // Taken before the destination is truncated.
+    let _lock = if to_stdout { None } else { lock_dest(&dest, opts)? };
+    let outfd = if to_stdout {
+        None
+    } else if dest.is_dir() {
+        return Err(XcpError::InvalidDestination {
+            path: dest,
+            reason: "Cannot copy stdin into a directory; give a file name.",
+        }.into());
+    } else {
+        Some(create_stream_dest(&dest, &config)?)
+    };
+
+    let updater = ChannelUpdater::new(&config);
+    let stat_rx = updater.rx_channel();
+    let stats: Arc<dyn StatusUpdater> = Arc::new(updater);
+
+    let handle = {
+        let config = config.clone();
+        let name = if from_stdin { PathBuf::from("<stdin>") } else { source.clone() };
+        thread::spawn(move || -> Result<u64> {
+            let mut reader: Box<dyn Read> = match infd {
+                Some(fd) => Box::new(fd),
+                None => Box::new(io::stdin().lock()),
+            };
+            let mut writer: Box<dyn Write> = match outfd {
+                Some(ref fd) => Box::new(fd),
+                None => Box::new(io::stdout().lock()),
+            };
+            let copied = copy_stream(&name, &mut reader, &mut writer, &config, &stats)?;
+            if let (Some(fd), true) = (&outfd, config.fsync) {
+                debug!("Syncing file {:?}", fd);
+                sync(fd)?;
+            }
+            Ok(copied)
+        })
+    };
+
+    let pb = progress::create_stream_bar(opts)?;
+    for stat in stat_rx {
+        match stat {
+            StatusUpdate::Copied(v) => pb.inc(v),
+            StatusUpdate::FileStarted(id, path) => pb.file_started(id, &path),
+            StatusUpdate::FileCompleted(id, _) => pb.file_completed(id),
+            _ => {}
+        }
+    }
+
+    let result = handle.join()
+        .map_err(|_| XcpError::CopyError("Error during copy operation".to_string()))?;
+    pb.end();
+
+    let copied = match result {
+        Ok(copied) => copied,
+        Err(e) => {
+            if !to_stdout {
+                debug!("Removing partial file {:?}", dest);
+                if let Err(e) = fs::remove_file(&dest) {
+                    warn!("Failed to remove partial file {:?}: {}", dest, e);
+                }
+            }
+            return Err(e);
+        }
+    };
+    info!("Copy complete: unknown total, {} copied", HumanBytes(copied));
+
+    Ok(())
+}
@@ -0,0 +1,64 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Chrome trace output for `--trace-out`. This replaces the normal
+//! terminal logger; log records are bridged into tracing so they
+//! appear both in the trace and on the terminal.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use libxcp::errors::Result;
+use log::Level;
+use tracing_chrome::{ChromeLayerBuilder, FlushGuard};
+use tracing_log::{AsTrace, LogTracer};
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::fmt::writer::MakeWriterExt;
+use tracing_subscriber::prelude::*;
+
+use crate::logging::EVENT_TARGET;
+use crate::options::Opts;
+
+/// Install the tracing subscriber. The trace is written out when the
+/// returned guard is dropped.
+pub fn init(opts: &Opts, path: &Path) -> Result<FlushGuard> {
+    let (chrome, guard) = ChromeLayerBuilder::new()
+        .writer(File::create(path)?)
+        .include_args(true)
+        .build();
+
+    // Match the terminal logger; errors to stderr, everything else
+    // to stdout.
+    let writer = io::stderr.with_max_level(Level::Error.as_trace())
+        .or_else(io::stdout);
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_target(false)
+        .without_time()
+        .with_filter(opts.log_level().as_trace())
+        .with_filter(filter_fn(|meta| meta.target() != EVENT_TARGET));
+
+    let subscriber = tracing_subscriber::registry()
+        .with(chrome)
+        .with(fmt);
+    tracing::subscriber::set_global_default(subscriber)?;
+    LogTracer::builder()
+        .with_max_level(opts.log_level())
+        .init()?;
+
+    Ok(guard)
+}
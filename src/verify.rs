@@ -0,0 +1,84 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! `xcp verify MANIFEST [ROOT]`: check previously copied files
+//! against a manifest written with '--manifest'.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+use libxcp::config::Config;
+use libxcp::errors::{Result, XcpError};
+use libxcp::feedback::{ChannelUpdater, StatusUpdate, StatusUpdater};
+use libxcp::manifest::Manifest;
+use log::{error, info};
+
+use crate::options::Opts;
+use crate::progress;
+
+/// Verify the files in a manifest, returning an error if any are
+/// missing or differ.
+pub fn verify(opts: &Opts) -> Result<()> {
+    let (manifest_path, root) = match opts.paths.as_slice() {
+        [manifest] => (manifest, None),
+        [manifest, root] => (manifest, Some(root)),
+        _ => return Err(XcpError::InvalidArguments("Usage: xcp verify [OPTIONS] MANIFEST [ROOT]".to_string()).into()),
+    };
+    let manifest = Manifest::read(Path::new(manifest_path))?;
+    let root = root.map_or_else(|| manifest.root.clone(), PathBuf::from);
+    if !root.is_dir() {
+        return Err(XcpError::InvalidSource {
+            path: root,
+            reason: "Manifest root is not a directory.",
+        }.into());
+    }
+    info!("Verifying {} files under {:?} with {}", manifest.files.len(), root, manifest.algorithm);
+
+    let config = Arc::new(Config::from(opts));
+    let updater = ChannelUpdater::new(&config);
+    let stat_rx = updater.rx_channel();
+    let stats: Arc<dyn StatusUpdater> = Arc::new(updater);
+
+    let total = manifest.files.len();
+    let handle = thread::spawn(move || manifest.verify(&root, &config, &stats));
+
+    let pb = progress::create_bar(opts, 0)?;
+    for stat in stat_rx {
+        match stat {
+            StatusUpdate::Copied(v) => pb.inc(v),
+            StatusUpdate::Size(v) => pb.inc_size(v),
+            StatusUpdate::FileStarted(id, path) => pb.file_started(id, &path),
+            StatusUpdate::FileCompleted(id, _) => pb.file_completed(id),
+            _ => {}
+        }
+    }
+
+    let mismatches = handle.join()
+        .map_err(|_| XcpError::CopyError("Error during verification".to_string()))??;
+    pb.end();
+
+    if !mismatches.is_empty() {
+        error!("{} of {} files do not match the manifest:", mismatches.len(), total);
+        for m in &mismatches {
+            error!("  {}", m);
+        }
+        return Err(XcpError::VerificationFailed { mismatched: mismatches.len(), total }.into());
+    }
+
+    info!("Verified {} files", total);
+    Ok(())
+}
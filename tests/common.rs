@@ -14,9 +14,12 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::fs::{create_dir_all, set_permissions, write, File, Permissions};
-use std::os::unix::fs::{symlink, PermissionsExt};
+use std::ffi::OsStr;
+use std::fs::{create_dir_all, remove_file, set_permissions, write, File, Permissions};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
 use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
 use cfg_if::cfg_if;
 use test_case::test_case;
 
@@ -38,7 +41,7 @@ fn no_args() {
     let out = run(&[]).unwrap();
 
     assert!(!out.status.success());
-    assert!(out.status.code().unwrap() == 1);
+    assert!(out.status.code().unwrap() == 2);
 
     let stderr = String::from_utf8(out.stderr).unwrap();
     assert!(stderr.contains("Insufficient arguments"));
@@ -122,8 +125,48 @@ fn mix_noclobber_force(drv: &str) {
     .unwrap();
 
     assert!(!out.status.success());
+    assert!(out.status.code().unwrap() == 2);
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("--force and --no-clobber cannot be used together"));
+}
+
+#[test_case(&["-q", "-v"], "--quiet and --verbose"; "quiet and verbose")]
+#[test_case(&["-T", "--target-directory", "dir"], "--no-target-directory and --target-directory"; "no target and target directory")]
+#[test_case(&["-T", "--dest-subdir-from-source"], "--no-target-directory and --dest-subdir-from-source"; "no target and subdir from source")]
+#[test_case(&["--offset", "1K", "-r"], "--offset/--length/--dest-offset and --recursive"; "range and recursive")]
+#[test_case(&["--length", "1K", "--dry-run"], "--offset/--length/--dest-offset and --dry-run"; "range and dry run")]
+#[test_case(&["-L", "-P"], "--dereference and --no-dereference"; "dereference and no dereference")]
+#[test_case(&["-p", "--no-perms"], "--preserve/-p/--archive and --no-perms"; "preserve and no perms")]
+#[test_case(&["--preserve=timestamps", "--no-timestamps"], "--preserve/-p/--archive and --no-timestamps"; "preserve and no timestamps")]
+fn conflicting_options(args: &[&str], conflict: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "data").unwrap();
+
+    let mut args = args.to_vec();
+    args.extend([source_path.to_str().unwrap(), dest_path.to_str().unwrap()]);
+    let out = run(&args).unwrap();
+
+    assert!(out.status.code().unwrap() == 2);
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains(&format!("{} cannot be used together", conflict)));
+    assert!(!dest_path.exists());
+}
+
+#[test_case(&["--recursve"], "a similar argument exists: '--recursive'"; "unknown flag")]
+#[test_case(&["--block-size", "12Q"], "expected a size such as 64K, 1M or 8MiB"; "bad size")]
+#[test_case(&["--reflink", "alwys"], "expected one of auto, always, never (did you mean 'always'?)"; "bad value")]
+#[test_case(&["--preserve=mode,ownrship"], "Unexpected value for 'preserve': ownrship"; "bad preserve attribute")]
+#[test_case(&["--no-preserve", "tmestamps"], "(did you mean 'timestamps'?)"; "bad no-preserve attribute")]
+fn invalid_option_suggestions(args: &[&str], expected: &str) {
+    let mut args = args.to_vec();
+    args.extend(["source.txt", "dest.txt"]);
+    let out = run(&args).unwrap();
+
+    assert!(out.status.code().unwrap() == 2);
     let stderr = String::from_utf8(out.stderr).unwrap();
-    assert!(stderr.contains("--force and --noclobber cannot be set at the same time"));
+    assert!(stderr.contains(expected));
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
@@ -146,6 +189,60 @@ fn source_same_as_dest(drv: &str) {
     assert!(stderr.contains("Cannot copy a directory into itself"));
 }
 
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn source_same_as_symlinked_dest(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let dest = dir.path().join("dest");
+    create_dir_all(&dest).unwrap();
+    symlink(dest.canonicalize().unwrap(), dir.path().join("link")).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        dest.to_str().unwrap(),
+        dir.path().join("link/../dest/.").to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Cannot copy a directory into itself"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dest_dotdot_escape(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file.txt"), "data").unwrap();
+    let dest = dir.path().join("missing/../../escaped");
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        source_path.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Destination contains '..'"));
+
+    let dest = dir.path().join("missing/../../").join(dir.path().file_name().unwrap()).join("copied");
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--allow-dotdot-dest",
+        source_path.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&dir.path().join("copied/file.txt"), "data").unwrap());
+}
+
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
 fn source_dir_same_as_dest_stub(drv: &str) {
@@ -318,6 +415,55 @@ fn same_file_no_overwrite(drv: &str) {
     assert!(! out.status.success());
 }
 
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn same_file_via_link_no_overwrite(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    create_file(&source_path, "falskjdfa;lskdjfa").unwrap();
+
+    let hard_path = dir.path().join("hard.txt");
+    std::fs::hard_link(&source_path, &hard_path).unwrap();
+    let sym_path = dir.path().join("sym.txt");
+    std::os::unix::fs::symlink(&source_path, &sym_path).unwrap();
+
+    for dest in [&hard_path, &sym_path] {
+        let out = run(&[
+            "--driver", drv,
+            source_path.to_str().unwrap(),
+            dest.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert!(!out.status.success());
+        assert!(file_contains(&source_path, "falskjdfa;lskdjfa").unwrap());
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn same_file_in_tree_no_overwrite(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file.txt"), "original").unwrap();
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(&dest_base).unwrap();
+    std::fs::hard_link(source_path.join("file.txt"), dest_base.join("file.txt")).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-rT",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Source and destination are the same file"));
+    assert!(file_contains(&source_path.join("file.txt"), "original").unwrap());
+}
+
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
 fn dest_file_exists_noclobber(drv: &str) {
@@ -466,6 +612,179 @@ fn file_copy_perms(drv: &str) {
     }
 }
 
+// Set the largest xattr value the filesystem allows, up to `max`
+// bytes, returning its size. Linux limits values to 64KiB, and many
+// filesystems to a single block.
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+fn set_largest_xattr(path: &std::path::Path, name: &OsStr, max: usize) -> Option<Vec<u8>> {
+    let mut size = max;
+    while size >= 1024 {
+        let value = (0..size).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        if xattr::set(path, name, &value).is_ok() {
+            return Some(value);
+        }
+        size /= 2;
+    }
+    None
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_xattr", ignore = "No FS support")]
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+fn file_copy_large_xattr(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "This is a test file.").unwrap();
+
+    let large = OsStr::new("user.large");
+    let binary = OsStr::from_bytes(b"user.\xff\xfe-name");
+    let value = set_largest_xattr(&source_path, large, 8 * 1024 * 1024)
+        .expect("No xattr space");
+    xattr::set(&source_path, binary, b"binary").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert_eq!(value, xattr::get(&dest_path, large).unwrap().unwrap());
+    assert_eq!(b"binary", xattr::get(&dest_path, binary).unwrap().unwrap().as_slice());
+
+    // Values over the limit are skipped with a warning.
+    std::fs::remove_file(&dest_path).unwrap();
+    let out = run(&[
+        "--driver",
+        drv,
+        "--xattr-value-limit",
+        "512",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert!(String::from_utf8_lossy(&out.stdout).contains("user.large"));
+    assert_eq!(None, xattr::get(&dest_path, large).unwrap());
+    assert_eq!(b"binary", xattr::get(&dest_path, binary).unwrap().unwrap().as_slice());
+}
+
+// Set a mode including special bits, returning false if the
+// environment doesn't allow it (e.g. nosuid mounts or restricted
+// containers).
+fn set_special_mode(path: &std::path::Path, mode: u32) -> bool {
+    let ok = set_permissions(path, Permissions::from_mode(mode)).is_ok()
+        && path.metadata().unwrap().permissions().mode() & 0o7777 == mode;
+    if !ok {
+        println!("Cannot set mode {:o} on {:?}; skipping test", mode, path);
+    }
+    ok
+}
+
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
+fn file_copy_setuid(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    create_file(&source_path, "#!/bin/sh\n").unwrap();
+    if !set_special_mode(&source_path, 0o4755) {
+        return;
+    }
+
+    let out = run(&[
+        "--driver", drv,
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert_eq!(0o4755, dest_path.metadata().unwrap().permissions().mode() & 0o7777);
+}
+
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
+fn dir_copy_setgid_sticky(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    for (sub, mode) in [("shared", 0o2775), ("tmp", 0o1777)] {
+        let path = source_path.join(sub);
+        create_dir_all(&path).unwrap();
+        create_file(&path.join("file.txt"), sub).unwrap();
+        if !set_special_mode(&path, mode) {
+            return;
+        }
+    }
+
+    let dest_base = dir.path().join("dest");
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    let mode = |sub: &str| dest_base.join(sub).metadata().unwrap().permissions().mode() & 0o7777;
+    assert_eq!(0o2775, mode("shared"));
+    assert_eq!(0o1777, mode("tmp"));
+    assert!(file_contains(&dest_base.join("shared/file.txt"), "shared").unwrap());
+}
+
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
+fn file_copy_setuid_other_owner(drv: &str) {
+    use std::os::unix::fs::{chown, MetadataExt};
+
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    create_file(&source_path, "#!/bin/sh\n").unwrap();
+    // Requires root to give the source away.
+    if chown(&source_path, Some(12345), Some(12345)).is_err() {
+        println!("Cannot change ownership; skipping test");
+        return;
+    }
+    if !set_special_mode(&source_path, 0o6755) {
+        return;
+    }
+
+    let copy = |name: &str, args: &[&str]| {
+        let dest = dir.path().join(name);
+        let mut all = vec!["--driver", drv];
+        all.extend(args);
+        all.extend([source_path.to_str().unwrap(), dest.to_str().unwrap()]);
+        assert!(run(&all).unwrap().status.success());
+        dest.metadata().unwrap()
+    };
+
+    // The copy is owned by us, so setuid/setgid are dropped.
+    let meta = copy("plain.bin", &[]);
+    assert_ne!(12345, meta.uid());
+    assert_eq!(0o755, meta.mode() & 0o7777);
+
+    let meta = copy("owned.bin", &["--ownership"]);
+    assert_eq!(12345, meta.uid());
+    assert_eq!(12345, meta.gid());
+    assert_eq!(0o6755, meta.mode() & 0o7777);
+
+    // Unless the mode is preserved explicitly.
+    let meta = copy("preserved.bin", &["--preserve=mode"]);
+    assert_ne!(12345, meta.uid());
+    assert_eq!(0o6755, meta.mode() & 0o7777);
+
+    let meta = copy("preserved-p.bin", &["-p", "--no-preserve=ownership"]);
+    assert_ne!(12345, meta.uid());
+    assert_eq!(0o6755, meta.mode() & 0o7777);
+}
+
 #[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
 #[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
@@ -526,6 +845,48 @@ fn file_copy_timestamps(drv: &str) {
     assert!(timestamps_same(&smeta.modified().unwrap(), &dmeta.modified().unwrap()));
 }
 
+#[cfg_attr(feature = "parblock", test_case("parblock", &[], true; "Test with parallel block driver"))]
+#[cfg_attr(feature = "parblock", test_case("parblock", &["--delete"], true; "Test with parallel block driver and delete"))]
+#[cfg_attr(feature = "parblock", test_case("parblock", &["--no-dir-timestamps"], false; "Test with parallel block driver and no dir timestamps"))]
+#[test_case("parfile", &[], true; "Test with parallel file driver")]
+#[test_case("parfile", &["--delete"], true; "Test with parallel file driver and delete")]
+#[test_case("parfile", &["--order=largest-first"], true; "Test with parallel file driver and largest first")]
+#[test_case("parfile", &["--preserve-hardlinks"], true; "Test with parallel file driver and hard-links")]
+#[test_case("parfile", &["--no-dir-timestamps"], false; "Test with parallel file driver and no dir timestamps")]
+#[test_case("parfile", &["--no-timestamps"], false; "Test with parallel file driver and no timestamps")]
+fn dir_copy_timestamps(drv: &str, args: &[&str], preserved: bool) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("a/b")).unwrap();
+    create_dir_all(source_path.join("c")).unwrap();
+    create_file(&source_path.join("file.txt"), "file").unwrap();
+    create_file(&source_path.join("a/file.txt"), "file a").unwrap();
+    create_file(&source_path.join("a/b/file.txt"), "file b").unwrap();
+    std::fs::hard_link(source_path.join("a/b/file.txt"), source_path.join("c/link.txt")).unwrap();
+    std::os::unix::fs::symlink("file.txt", source_path.join("a/link")).unwrap();
+
+    let dirs = ["", "a", "a/b", "c"];
+    for d in dirs {
+        set_time_past(&source_path.join(d)).unwrap();
+    }
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(dest_base.join("a")).unwrap();
+    create_file(&dest_base.join("a/extra.txt"), "extra").unwrap();
+
+    let mut cmd = vec!["--driver", drv, "-rT", "--dir-mode=overwrite"];
+    cmd.extend_from_slice(args);
+    cmd.extend_from_slice(&[source_path.to_str().unwrap(), dest_base.to_str().unwrap()]);
+    let out = run(&cmd).unwrap();
+    assert!(out.status.success());
+
+    for d in dirs {
+        let smeta = source_path.join(d).metadata().unwrap();
+        let dmeta = dest_base.join(d).metadata().unwrap();
+        assert_eq!(preserved, timestamps_same(&smeta.modified().unwrap(), &dmeta.modified().unwrap()), "{}", d);
+    }
+}
+
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
 fn file_copy_no_timestamps(drv: &str) {
@@ -929,12 +1290,142 @@ fn dir_overwrite_with_noclobber(drv: &str) {
         "--driver",
         drv,
         "-r",
+        "--no-clobber=fail",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+    assert!(file_contains(&dest_file, "orig").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_merge_with_noclobber_skip(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("a.txt"), "new a").unwrap();
+    create_file(&source_path.join("b.txt"), "new b").unwrap();
+    create_file(&source_path.join("sub/c.txt"), "new c").unwrap();
+    create_file(&source_path.join("sub/d.txt"), "new d").unwrap();
+
+    // Half-populate the destination.
+    let dest_base = dir.path().join("dest");
+    create_dir_all(dest_base.join("sub")).unwrap();
+    create_file(&dest_base.join("a.txt"), "old a").unwrap();
+    create_file(&dest_base.join("sub/c.txt"), "old c").unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-r", "-v",
         "--no-clobber",
+        "--no-target-directory",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_base.join("a.txt"), "old a").unwrap());
+    assert!(file_contains(&dest_base.join("b.txt"), "new b").unwrap());
+    assert!(file_contains(&dest_base.join("sub/c.txt"), "old c").unwrap());
+    assert!(file_contains(&dest_base.join("sub/d.txt"), "new d").unwrap());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.contains("Skipped 2 existing destination files"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_merge_with_noclobber_rename(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("a.txt"), "new a").unwrap();
+    create_file(&source_path.join("b.txt"), "new b").unwrap();
+    create_file(&source_path.join(".hidden"), "new hidden").unwrap();
+    create_file(&source_path.join("sub/c.tar.gz"), "new c").unwrap();
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(dest_base.join("sub")).unwrap();
+    create_file(&dest_base.join("a.txt"), "old a").unwrap();
+    create_file(&dest_base.join("a (1).txt"), "old a 1").unwrap();
+    create_file(&dest_base.join(".hidden"), "old hidden").unwrap();
+    create_file(&dest_base.join("sub/c.tar.gz"), "old c").unwrap();
+
+    let journal = dir.path().join("journal.ndjson");
+    let out = run(&[
+        "--driver", drv,
+        "-r", "-v",
+        "--no-clobber=rename",
+        "--no-target-directory",
+        "--journal", journal.to_str().unwrap(),
         source_path.to_str().unwrap(),
         dest_base.to_str().unwrap(),
     ])
     .unwrap();
 
+    assert!(out.status.success());
+    assert!(file_contains(&dest_base.join("a.txt"), "old a").unwrap());
+    assert!(file_contains(&dest_base.join("a (1).txt"), "old a 1").unwrap());
+    assert!(file_contains(&dest_base.join("a (2).txt"), "new a").unwrap());
+    assert!(file_contains(&dest_base.join("b.txt"), "new b").unwrap());
+    assert!(file_contains(&dest_base.join(".hidden"), "old hidden").unwrap());
+    assert!(file_contains(&dest_base.join(".hidden (1)"), "new hidden").unwrap());
+    assert!(file_contains(&dest_base.join("sub/c.tar.gz"), "old c").unwrap());
+    assert!(file_contains(&dest_base.join("sub/c (1).tar.gz"), "new c").unwrap());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.contains("Copied 3 entries under new names"));
+
+    let journal = std::fs::read_to_string(&journal).unwrap();
+    let kept = journal.lines()
+        .filter(|l| l.contains(r#""action":"kept-both""#))
+        .collect::<Vec<_>>();
+    assert_eq!(3, kept.len());
+    assert!(kept.iter().any(|l| l.contains("a (2).txt")));
+}
+
+#[test]
+fn single_file_noclobber_rename() {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source.txt");
+    let dest = dir.path().join("dest.txt");
+    create_file(&source, "new").unwrap();
+    create_file(&dest, "old").unwrap();
+
+    for _ in 0..2 {
+        let out = run(&[
+            "--conflict-suffix", "{name}.copy-{n}.{ext}",
+            source.to_str().unwrap(),
+            dest.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert!(out.status.success());
+    }
+    assert!(file_contains(&dest, "old").unwrap());
+    assert!(file_contains(&dir.path().join("dest.copy-1.txt"), "new").unwrap());
+    assert!(file_contains(&dir.path().join("dest.copy-2.txt"), "new").unwrap());
+
+    let out = run(&[
+        "--no-clobber=skip",
+        "--conflict-suffix", "{name}-{n}",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("--conflict-suffix and --no-clobber=skip/fail cannot be used together"));
+
+    let out = run(&[
+        "--conflict-suffix", "{name}.{ext}",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ])
+    .unwrap();
     assert!(!out.status.success());
 }
 
@@ -980,6 +1471,33 @@ fn dir_copy_containing_symlinks(drv: &str) {
         .is_symlink());
 }
 
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn dir_copy_existing_symlink_fails(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file.txt"), "orig").unwrap();
+    symlink("file.txt", source_path.join("link.txt")).unwrap();
+    let dest_base = dir.path().join("dest");
+
+    let copy = |args: &[&str]| run(&[&["--driver", drv, "-r", "-T"], args,
+                                     &[source_path.to_str().unwrap(), dest_base.to_str().unwrap()]].concat())
+        .unwrap();
+    assert!(copy(&[]).status.success());
+
+    // The symlink already exists on the second copy.
+    let out = copy(&[]);
+    assert_eq!(Some(1), out.status.code());
+    assert!(String::from_utf8(out.stderr).unwrap().contains("File exists"));
+
+    let out = copy(&["--continue-on-error"]);
+    assert_eq!(Some(1), out.status.code());
+    assert!(String::from_utf8(out.stderr).unwrap().contains("Copy completed with 1 error(s)"));
+    assert!(file_contains(&dest_base.join("file.txt"), "orig").unwrap());
+}
+
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
 fn dir_copy_with_hidden_dir(drv: &str) {
@@ -1056,10 +1574,130 @@ fn dir_with_gitignore(drv: &str) {
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn copy_with_glob(drv: &str) {
+fn dir_with_gitignore_prune_empty_dirs(drv: &str) {
     let dir = tempdir_rel().unwrap();
-    let dest = dir.path().join("dest");
-    create_dir_all(&dest).unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("logs/nested")).unwrap();
+    create_dir_all(source_path.join("mixed/deep")).unwrap();
+    create_dir_all(source_path.join("links")).unwrap();
+    create_dir_all(source_path.join("empty")).unwrap();
+    create_file(&source_path.join(".gitignore"), "*.log\n").unwrap();
+    create_file(&source_path.join("logs/a.log"), "a").unwrap();
+    create_file(&source_path.join("logs/nested/b.log"), "b").unwrap();
+    create_file(&source_path.join("mixed/c.log"), "c").unwrap();
+    create_file(&source_path.join("mixed/deep/d.txt"), "d").unwrap();
+    symlink("../mixed/deep/d.txt", source_path.join("links/d.txt")).unwrap();
+
+    let unpruned = dir.path().join("unpruned");
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--gitignore",
+        source_path.to_str().unwrap(),
+        unpruned.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert!(unpruned.join("logs/nested").is_dir());
+    assert!(unpruned.join("empty").is_dir());
+
+    let dest_base = dir.path().join("dest");
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--gitignore",
+        "--prune-empty-dirs",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert!(dest_base.join(".gitignore").exists());
+    assert!(!dest_base.join("logs").exists());
+    assert!(!dest_base.join("empty").exists());
+    assert!(!dest_base.join("mixed/c.log").exists());
+    assert!(files_match(&source_path.join("mixed/deep/d.txt"), &dest_base.join("mixed/deep/d.txt")));
+    assert!(dest_base.join("links/d.txt").is_symlink());
+    // Metadata is applied to the directories that were created.
+    let (from, to) = (source_path.join("mixed").metadata().unwrap(), dest_base.join("mixed").metadata().unwrap());
+    assert_eq!(from.permissions(), to.permissions());
+    assert_eq!(from.modified().unwrap(), to.modified().unwrap());
+
+    // No directories are created when all their contents are ignored.
+    let ignored = dir.path().join("ignored");
+    create_file(&source_path.join(".gitignore"), "*.log\n*.txt\n").unwrap();
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--gitignore",
+        "--prune-empty-dirs",
+        source_path.to_str().unwrap(),
+        ignored.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    let names = std::fs::read_dir(&ignored).unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect::<Vec<_>>();
+    assert_eq!(vec![".gitignore"], names);
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_show_current_no_tty(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file1.txt"), "file 1").unwrap();
+    create_file(&source_path.join("file2.txt"), "file 2").unwrap();
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--show-current", "2",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(out.stdout.is_empty());
+    assert!(file_contains(&dest_base.join("file1.txt"), "file 1").unwrap());
+    assert!(file_contains(&dest_base.join("file2.txt"), "file 2").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_with_glob(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let dest = dir.path().join("dest");
+    create_dir_all(&dest).unwrap();
+
+    let (f1, f2) = (dir.path().join("file1.txt"), dir.path().join("file2.txt"));
+    create_file(&f1, "test").unwrap();
+    create_file(&f2, "test").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--glob",
+        dir.path().join("file*.txt").to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(dest.join("file1.txt").exists());
+    assert!(dest.join("file2.txt").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_with_overlapping_globs(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let dest = dir.path().join("dest");
+    create_dir_all(&dest).unwrap();
 
     let (f1, f2) = (dir.path().join("file1.txt"), dir.path().join("file2.txt"));
     create_file(&f1, "test").unwrap();
@@ -1069,7 +1707,10 @@ fn copy_with_glob(drv: &str) {
         "--driver",
         drv,
         "--glob",
+        "-vv",
         dir.path().join("file*.txt").to_str().unwrap(),
+        dir.path().join("file1*").to_str().unwrap(),
+        f2.to_str().unwrap(),
         dest.to_str().unwrap(),
     ])
     .unwrap();
@@ -1077,6 +1718,57 @@ fn copy_with_glob(drv: &str) {
     assert!(out.status.success());
     assert!(dest.join("file1.txt").exists());
     assert!(dest.join("file2.txt").exists());
+
+    let log = String::from_utf8_lossy(&out.stdout).to_string()
+        + &String::from_utf8_lossy(&out.stderr);
+    assert_eq!(2, log.matches("Dropping duplicate source").count());
+    assert_eq!(2, log.matches("Send copy operation").count());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn symlink_source_and_target(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let file = dir.path().join("file.txt");
+    let link = dir.path().join("link");
+    create_file(&file, "test").unwrap();
+    symlink("file.txt", &link).unwrap();
+
+    // Without following, the link is copied as itself alongside its
+    // target.
+    let dest = dir.path().join("no-deref");
+    create_dir_all(&dest).unwrap();
+    let out = run(&[
+        "--driver", drv,
+        "-P",
+        file.to_str().unwrap(),
+        link.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&dest.join("file.txt"), "test").unwrap());
+    assert!(dest.join("link").is_symlink());
+    assert_eq!(Path::new("file.txt"), dest.join("link").read_link().unwrap());
+
+    // Followed, the link is the same file as its target.
+    let dest = dir.path().join("deref");
+    create_dir_all(&dest).unwrap();
+    let out = run(&[
+        "--driver", drv,
+        "-L",
+        "-vv",
+        file.to_str().unwrap(),
+        link.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&dest.join("file.txt"), "test").unwrap());
+    assert!(!dest.join("link").exists());
+    let log = String::from_utf8_lossy(&out.stdout).to_string()
+        + &String::from_utf8_lossy(&out.stderr);
+    assert_eq!(1, log.matches("Dropping duplicate source").count());
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
@@ -1123,7 +1815,9 @@ fn glob_pattern_error(drv: &str) {
 
     assert!(!out.status.success());
     let stderr = String::from_utf8(out.stderr).unwrap();
-    assert!(stderr.contains("Pattern syntax error"));
+    let column = dir.path().join("file").to_str().unwrap().chars().count() + 1;
+    assert!(stderr.contains(&format!("file***.txt\" at column {}: '**' can only be a whole path component", column)),
+            "{}", stderr);
 }
 
 #[cfg_attr(all(feature = "parblock", not(feature = "test_no_sockets")), test_case("parblock"; "Test with parallel block driver"))]
@@ -1191,7 +1885,7 @@ fn unreadable_file_error(drv: &str) {
 
     create_file(&source_path, text).unwrap();
 
-    let perms = Permissions::from_mode(0);
+    let perms = Permissions::from_mode(0o0);
     set_permissions(&source_path, perms).unwrap();
 
     let out = run(&[
@@ -1217,7 +1911,7 @@ fn dest_file_exists_not_writable(drv: &str) {
         create_file(&source_path, "falskjdfa;lskdjfa").unwrap();
         File::create(&dest_path).unwrap();
     }
-    set_permissions(&dest_path, Permissions::from_mode(0)).unwrap();
+    set_permissions(&dest_path, Permissions::from_mode(0o0)).unwrap();
 
     let out = run(&[
         "--driver",
@@ -1230,6 +1924,84 @@ fn dest_file_exists_not_writable(drv: &str) {
     assert!(!out.status.success());
 }
 
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
+fn unreadable_dir_continue_on_error(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    let unreadable = source_path.join("b");
+    for d in ["a", "b", "c"] {
+        create_dir_all(source_path.join(d)).unwrap();
+        create_file(&source_path.join(d).join("file.txt"), d).unwrap();
+    }
+    set_permissions(&unreadable, Permissions::from_mode(0o0)).unwrap();
+
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--continue-on-error",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    set_permissions(&unreadable, Permissions::from_mode(0o755)).unwrap();
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Unreadable directory skipped"));
+    assert!(stderr.contains(unreadable.to_str().unwrap()));
+
+    assert!(file_contains(&dest_base.join("a/file.txt"), "a").unwrap());
+    assert!(file_contains(&dest_base.join("c/file.txt"), "c").unwrap());
+    assert!(dest_base.join("b").is_dir());
+    assert!(!dest_base.join("b/file.txt").exists());
+}
+
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
+fn unreadable_dir_not_deleted(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("src");
+    let locked = source_path.join("locked");
+    create_dir_all(&locked).unwrap();
+    create_file(&locked.join("data"), "data").unwrap();
+    create_file(&source_path.join("file.txt"), "file").unwrap();
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(dest_base.join("locked")).unwrap();
+    create_file(&dest_base.join("locked/data"), "data").unwrap();
+    create_file(&dest_base.join("extra.txt"), "extra").unwrap();
+    set_permissions(&locked, Permissions::from_mode(0o0)).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-r", "-T",
+        "--delete",
+        "--continue-on-error",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    set_permissions(&locked, Permissions::from_mode(0o755)).unwrap();
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Unreadable directory skipped"));
+
+    // Nothing under the unreadable source is deleted.
+    assert!(file_contains(&dest_base.join("locked/data"), "data").unwrap());
+    assert!(file_contains(&dest_base.join("file.txt"), "file").unwrap());
+    assert!(!dest_base.join("extra.txt").exists());
+}
+
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
 fn copy_dirs_backup(drv: &str) {
@@ -1424,3 +2196,3182 @@ fn test_deep_symlinks(drv: &str) {
     let stderr = String::from_utf8(out.stderr).unwrap();
     assert!(stderr.contains("Too many levels of symbolic links"));
 }
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn symlinked_sources(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let real = dir.path().join("real");
+    create_dir_all(real.join("sub")).unwrap();
+    create_file(&real.join("file.txt"), "data").unwrap();
+    symlink("real", dir.path().join("to-dir")).unwrap();
+    symlink("real/file.txt", dir.path().join("to-file")).unwrap();
+    // A link found when recursing is copied as a link under -H.
+    symlink("../file.txt", real.join("sub/inner")).unwrap();
+    symlink("missing", dir.path().join("dangling")).unwrap();
+
+    for (policy, follow) in [("", true), ("-H", true), ("-P", false), ("-L", true)] {
+        let dest = dir.path().join(format!("dest{}", policy));
+        create_dir_all(&dest).unwrap();
+        let copy = |recursive: bool, name: &str| {
+            let from = dir.path().join(name);
+            let mut args = vec!["--driver", drv, from.to_str().unwrap(), dest.to_str().unwrap()];
+            if recursive {
+                args.push("-r");
+            }
+            if !policy.is_empty() {
+                args.push(policy);
+            }
+            run(&args).unwrap()
+        };
+
+        let out = copy(false, "to-file");
+        assert!(out.status.success(), "{}: {}", policy, String::from_utf8_lossy(&out.stderr));
+        if follow {
+            assert!(!dest.join("to-file").is_symlink(), "{}", policy);
+            assert!(file_contains(&dest.join("to-file"), "data").unwrap());
+        } else {
+            assert_eq!(Path::new("real/file.txt"), dest.join("to-file").read_link().unwrap());
+        }
+
+        // Without -r a followed link to a directory is refused as a
+        // directory would be.
+        let out = copy(false, "to-dir");
+        assert_eq!(!follow, out.status.success(), "{}", policy);
+        if follow {
+            let stderr = String::from_utf8(out.stderr).unwrap();
+            assert!(stderr.contains("--recursive not specified"), "{}: {}", policy, stderr);
+        } else {
+            assert_eq!(Path::new("real"), dest.join("to-dir").read_link().unwrap());
+            std::fs::remove_file(dest.join("to-dir")).unwrap();
+        }
+
+        let out = copy(true, "to-dir");
+        assert!(out.status.success(), "{}: {}", policy, String::from_utf8_lossy(&out.stderr));
+        if follow {
+            assert!(!dest.join("to-dir").is_symlink(), "{}", policy);
+            assert!(file_contains(&dest.join("to-dir/file.txt"), "data").unwrap());
+            // Only -L follows links beneath the source.
+            assert_eq!(policy != "-L", dest.join("to-dir/sub/inner").is_symlink(), "{}", policy);
+        } else {
+            assert_eq!(Path::new("real"), dest.join("to-dir").read_link().unwrap());
+        }
+
+        let out = copy(false, "dangling");
+        assert_eq!(!follow, out.status.success(), "{}", policy);
+        if follow {
+            let stderr = String::from_utf8(out.stderr).unwrap();
+            assert!(stderr.contains("Source does not exist"), "{}: {}", policy, stderr);
+        } else {
+            assert_eq!(Path::new("missing"), dest.join("dangling").read_link().unwrap());
+        }
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn deref_symlinked_dirs(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("real")).unwrap();
+    create_file(&source_path.join("real/file.txt"), "data").unwrap();
+    symlink("real", source_path.join("link")).unwrap();
+
+    let dest_base = dir.path().join("dest");
+    let out = run(&[
+        "--driver", drv,
+        "--recursive",
+        "--dereference",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+
+    let dest_link = dest_base.join("link");
+    assert!(!dest_link.is_symlink());
+    assert!(file_contains(&dest_link.join("file.txt"), "data").unwrap());
+    assert!(file_contains(&dest_base.join("real/file.txt"), "data").unwrap());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.contains("reached by more than one symlink"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn deref_symlink_diamond(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let real = dir.path().join("real");
+    create_dir_all(real.join("sub")).unwrap();
+    create_file(&real.join("sub/file.txt"), "data").unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    symlink("../real", source_path.join("one")).unwrap();
+    symlink("../real", source_path.join("two")).unwrap();
+
+    let dest_base = dir.path().join("dest");
+    let out = run(&[
+        "--driver", drv,
+        "--recursive",
+        "--dereference",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+
+    for name in ["one", "two"] {
+        assert!(file_contains(&dest_base.join(name).join("sub/file.txt"), "data").unwrap());
+    }
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert_eq!(1, stdout.matches("was already reached as").count());
+    assert!(stdout.contains("Copied 4 B again for 1 directories"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn deref_symlink_loop(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("a/b")).unwrap();
+    create_file(&source_path.join("a/b/file.txt"), "data").unwrap();
+    symlink("../..", source_path.join("a/b/up")).unwrap();
+
+    let dest_base = dir.path().join("dest");
+    let out = run(&[
+        "--driver", drv,
+        "--recursive",
+        "--dereference",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ]).unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Symlink loop"));
+    assert!(stderr.contains("a/b/up"));
+
+    // With --continue-on-error the rest of the tree is copied.
+    let dest_base = dir.path().join("dest-continue");
+    let out = run(&[
+        "--driver", drv,
+        "--recursive",
+        "--dereference",
+        "--continue-on-error",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ]).unwrap();
+    assert!(!out.status.success());
+    assert!(file_contains(&dest_base.join("a/b/file.txt"), "data").unwrap());
+    assert!(!dest_base.join("a/b/up").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn deref_mutual_symlinks(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("x")).unwrap();
+    create_dir_all(source_path.join("y")).unwrap();
+    symlink("../y", source_path.join("x/to-y")).unwrap();
+    symlink("../x", source_path.join("y/to-x")).unwrap();
+
+    let dest_base = dir.path().join("dest");
+    let out = run(&[
+        "--driver", drv,
+        "--recursive",
+        "--dereference",
+        "--continue-on-error",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ]).unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Symlink loop"));
+    assert!(dest_base.join("x/to-y").is_dir());
+    assert!(dest_base.join("y/to-x").is_dir());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_with_manifest(drv: &str) {
+    use libxcp::checksum::{checksum_file, ChecksumType};
+    use libxcp::manifest::Manifest;
+
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("b.txt"), "file b").unwrap();
+    create_file(&source_path.join("sub/a.txt"), "file a").unwrap();
+
+    let dest_base = dir.path().join("dest");
+    let manifest_path = dir.path().join("manifest.json");
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--manifest", manifest_path.to_str().unwrap(),
+        "--manifest-hash", "sha256",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+
+    let manifest = Manifest::read(&manifest_path).unwrap();
+    assert_eq!(dest_base.canonicalize().unwrap(), manifest.root);
+    assert_eq!(ChecksumType::Sha256, manifest.algorithm);
+
+    let paths = manifest.files.iter()
+        .map(|f| f.path.to_str().unwrap())
+        .collect::<Vec<&str>>();
+    assert_eq!(vec!["b.txt", "sub/a.txt"], paths);
+
+    for entry in &manifest.files {
+        let file = dest_base.join(&entry.path);
+        assert_eq!(file.metadata().unwrap().len(), entry.size);
+        assert_eq!(checksum_file(&file, ChecksumType::Sha256).unwrap(), entry.checksum);
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_batch_dirents(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    for d in 0..3 {
+        create_dir_all(source.join(format!("dir{}", d))).unwrap();
+        for i in 0..50 {
+            create_file(&source.join(format!("dir{}/file{}.txt", d, i)), &format!("data {} {}", d, i)).unwrap();
+        }
+    }
+    // Copied individually.
+    write(source.join("dir0/large.bin"), vec![7; 1024 * 1024]).unwrap();
+
+    for mode in ["", "skip", "fail"] {
+        let dest = dir.path().join(format!("dest-{}", mode));
+        create_dir_all(dest.join("dir1")).unwrap();
+        create_file(&dest.join("dir1/file20.txt"), "existing").unwrap();
+        let clobber = format!("--no-clobber={}", mode);
+        // A batch shares one reader, so mustn't wait on its own limit.
+        let mut args = vec!["--driver", drv, "-r", "-T", "--batch-dirents", "--readers-per-device=1",
+                            source.to_str().unwrap(), dest.to_str().unwrap()];
+        if !mode.is_empty() {
+            args.push(&clobber);
+        }
+        let out = run(&args).unwrap();
+        assert_eq!(mode != "fail", out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+        let existing = if mode.is_empty() { "data 1 20" } else { "existing" };
+        assert!(file_contains(&dest.join("dir1/file20.txt"), existing).unwrap());
+        for d in 0..3 {
+            for i in 0..50 {
+                let file = dest.join(format!("dir{}/file{}.txt", d, i));
+                if (d, i) == (1, 20) || (mode == "fail" && !file.exists()) {
+                    continue;
+                }
+                // Files created before a failure are still filled.
+                assert!(file_contains(&file, &format!("data {} {}", d, i)).unwrap(), "{:?}", file);
+            }
+        }
+        if mode != "fail" {
+            assert_eq!(1024 * 1024, dest.join("dir0/large.bin").metadata().unwrap().len());
+        }
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_tiny_files(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    create_dir_all(source.join("sub")).unwrap();
+    let files = [
+        ("empty", vec![]),
+        ("tiny.txt", b"tiny".to_vec()),
+        ("sub/limit.bin", vec![1; 512]),
+        ("sub/over.bin", vec![2; 513]),
+        ("large.bin", vec![3; 1024 * 1024]),
+    ];
+    for (name, data) in &files {
+        write(source.join(name), data).unwrap();
+    }
+
+    for args in [vec![], vec!["--no-tiny-file-fastpath"], vec!["--batch-dirents"]] {
+        let dest = dir.path().join(format!("dest{}", args.join("")));
+        create_dir_all(&dest).unwrap();
+        // Overwritten in place, so must be truncated.
+        create_file(&dest.join("tiny.txt"), "a longer existing file").unwrap();
+        let journal = dir.path().join(format!("journal{}.ndjson", args.join("")));
+        let mut cmd = vec!["--driver", drv, "-r", "-T", "--journal", journal.to_str().unwrap()];
+        cmd.extend(&args);
+        cmd.extend([source.to_str().unwrap(), dest.to_str().unwrap()]);
+        let out = run(&cmd).unwrap();
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+        for (name, data) in &files {
+            assert_eq!(*data, std::fs::read(dest.join(name)).unwrap(), "{}", name);
+        }
+        let journal = std::fs::read_to_string(&journal).unwrap();
+        let inline = journal.lines()
+            .filter(|l| l.contains(r#""method":"inline""#))
+            .count();
+        let expected = if args.contains(&"--no-tiny-file-fastpath") { 0 } else { 3 };
+        assert_eq!(expected, inline, "{}", journal);
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_sources_interleaved(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let sources = ["first", "second"].map(|name| dir.path().join(name));
+    for source in &sources {
+        for i in 0..20 {
+            create_dir_all(source.join(format!("dir{}", i))).unwrap();
+            create_file(&source.join(format!("dir{}/file.txt", i)), "data").unwrap();
+        }
+    }
+
+    for order in ["scan", "smallest-first"] {
+        let dest = dir.path().join(format!("dest-{}", order));
+        create_dir_all(&dest).unwrap();
+        let journal_path = dir.path().join(format!("journal-{}.ndjson", order));
+        let out = run(&[
+            "--driver", drv,
+            "-r",
+            "--workers", "1",
+            "--order", order,
+            "--journal", journal_path.to_str().unwrap(),
+            sources[0].to_str().unwrap(),
+            sources[1].to_str().unwrap(),
+            dest.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+        // The source each directory and file was copied from, in the
+        // order they were journalled.
+        let journal = std::fs::read_to_string(&journal_path).unwrap();
+        let copied = |action: &str| journal.lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .filter(|r| r["action"] == action)
+            .map(|r| {
+                let path = r[if action == "mkdir" { "path" } else { "to" }].as_str().unwrap().to_string();
+                Path::new(&path).strip_prefix(&dest).unwrap()
+                    .components().next().unwrap()
+                    .as_os_str().to_str().unwrap().to_string()
+            })
+            .collect::<Vec<String>>();
+        let (dirs, files) = (copied("mkdir"), copied("copied"));
+        assert_eq!(42, dirs.len());
+        assert_eq!(40, files.len());
+
+        if order == "scan" {
+            // Both sources are started at once, and are copied from
+            // throughout.
+            for copied in [&dirs, &files] {
+                for window in copied.chunks(6) {
+                    assert!(window.contains(&"first".to_string()), "{:?}", copied);
+                    assert!(window.contains(&"second".to_string()), "{:?}", copied);
+                }
+            }
+        } else {
+            // Each source is walked in full before the next.
+            assert!(dirs[..21].iter().all(|d| d == "first"), "{:?}", dirs);
+            assert!(dirs[21..].iter().all(|d| d == "second"), "{:?}", dirs);
+        }
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_with_journal(drv: &str) {
+    use libxcp::manifest::Manifest;
+
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("a.txt"), "file a").unwrap();
+    create_file(&source_path.join("b.txt"), "longer file b").unwrap();
+    create_file(&source_path.join("sub/c.txt"), "c").unwrap();
+    symlink("a.txt", source_path.join("link")).unwrap();
+
+    let dest_base = dir.path().join("dest");
+    let journal_path = dir.path().join("journal.ndjson");
+    let manifest_path = dir.path().join("manifest.json");
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--journal", journal_path.to_str().unwrap(),
+        "--manifest", manifest_path.to_str().unwrap(),
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    let read_journal = || {
+        std::fs::read_to_string(&journal_path).unwrap()
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<serde_json::Value>>()
+    };
+    let records = read_journal();
+    assert!(records.iter().all(|r| r["time"].as_f64().unwrap() > 0.0));
+
+    // The copied records reconcile with the source tree.
+    let copied = records.iter()
+        .filter(|r| r["action"] == "copied")
+        .collect::<Vec<_>>();
+    assert_eq!(3, copied.len());
+    assert_eq!(20, copied.iter().map(|r| r["bytes"].as_u64().unwrap()).sum::<u64>());
+    let mut to = copied.iter()
+        .map(|r| PathBuf::from(r["to"].as_str().unwrap()))
+        .collect::<Vec<PathBuf>>();
+    to.sort();
+    assert_eq!(vec![dest_base.join("a.txt"), dest_base.join("b.txt"), dest_base.join("sub/c.txt")], to);
+
+    let mkdirs = records.iter()
+        .filter(|r| r["action"] == "mkdir")
+        .map(|r| PathBuf::from(r["path"].as_str().unwrap()))
+        .collect::<Vec<PathBuf>>();
+    assert_eq!(vec![dest_base.clone(), dest_base.join("sub")], mkdirs);
+
+    let links = records.iter()
+        .filter(|r| r["action"] == "symlink")
+        .collect::<Vec<_>>();
+    assert_eq!(1, links.len());
+    assert_eq!(dest_base.join("link").to_str().unwrap(), links[0]["to"]);
+
+    // The manifest is still written alongside.
+    assert_eq!(3, Manifest::read(&manifest_path).unwrap().files.len());
+
+    // A second run appends, recording the skips and deletions.
+    let first = records.len();
+    create_file(&dest_base.join("extra.txt"), "extra").unwrap();
+    remove_file(dest_base.join("link")).unwrap();
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--update",
+        "--delete",
+        "--no-target-directory",
+        "--journal", journal_path.to_str().unwrap(),
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    let records = read_journal();
+    let rerun = &records[first..];
+    assert!(rerun.iter().all(|r| r["action"] != "copied"));
+    let skipped = rerun.iter()
+        .filter(|r| r["action"] == "skipped" && r["reason"] == "up-to-date")
+        .count();
+    assert_eq!(3, skipped);
+    let deleted = rerun.iter()
+        .filter(|r| r["action"] == "deleted")
+        .map(|r| r["path"].as_str().unwrap())
+        .collect::<Vec<&str>>();
+    assert_eq!(vec![dest_base.join("extra.txt").to_str().unwrap()], deleted);
+    assert!(!dest_base.join("extra.txt").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn replay_journal(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("a.txt"), "file a").unwrap();
+    create_file(&source_path.join("b.txt"), "file b").unwrap();
+    create_file(&source_path.join("sub/c.txt"), "file c").unwrap();
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(dest_base.join("sub")).unwrap();
+    create_file(&dest_base.join("a.txt"), "old a").unwrap();
+    create_file(&dest_base.join("b.txt"), "old b").unwrap();
+    create_file(&dest_base.join("sub/c.txt"), "old c").unwrap();
+    let journal_path = dir.path().join("journal.ndjson");
+    let journal = journal_path.to_str().unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "-T",
+        "--no-clobber=skip",
+        "--journal", journal,
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    // The skips record enough to replay them.
+    let records = std::fs::read_to_string(&journal_path).unwrap()
+        .lines()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .collect::<Vec<serde_json::Value>>();
+    assert_eq!("started", records[0]["action"]);
+    assert!(records[0]["fingerprint"].is_string());
+    let skipped = records.iter()
+        .filter(|r| r["action"] == "skipped" && r["reason"] == "exists")
+        .collect::<Vec<_>>();
+    assert_eq!(3, skipped.len());
+    assert!(skipped.iter().all(|r| r["source"].is_string() && r["size"] == 6));
+
+    // Once the conflicts are resolved the skipped files are copied,
+    // except one whose source has changed since.
+    remove_file(dest_base.join("a.txt")).unwrap();
+    remove_file(dest_base.join("sub/c.txt")).unwrap();
+    create_file(&source_path.join("b.txt"), "file b, changed").unwrap();
+    let out = run(&["--driver", drv, "--replay", journal, "--only", "skipped"]).unwrap();
+    assert!(!out.status.success());
+    let log = String::from_utf8(out.stdout).unwrap();
+    assert!(log.contains("the source has changed size"));
+    assert!(file_contains(&dest_base.join("a.txt"), "file a").unwrap());
+    assert!(file_contains(&dest_base.join("sub/c.txt"), "file c").unwrap());
+    assert!(file_contains(&dest_base.join("b.txt"), "old b").unwrap());
+
+    // Nothing is left to replay but the changed file.
+    create_file(&source_path.join("b.txt"), "file B").unwrap();
+    let out = run(&["--driver", drv, "--replay", journal, "--only", "skipped"]).unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&dest_base.join("b.txt"), "file B").unwrap());
+
+    let out = run(&["--only", "skipped", source_path.to_str().unwrap(), dest_base.to_str().unwrap()]).unwrap();
+    assert!(!out.status.success());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_xattr", ignore = "No FS support")]
+fn copy_stamp_checksum(drv: &str) {
+    use libxcp::checksum::{checksum_file, ChecksumType};
+    use std::time::{Duration, SystemTime};
+
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("a.txt"), "file a").unwrap();
+    create_file(&source_path.join("b.txt"), "file b").unwrap();
+    let dest_base = dir.path().join("dest");
+    let journal_path = dir.path().join("journal.ndjson");
+
+    let copy = |extra: &[&str]| {
+        let _ = std::fs::remove_file(&journal_path);
+        let out = run(&[&[
+            "--driver", drv,
+            "-r",
+            "-T",
+            "--stamp-checksum",
+            "--journal", journal_path.to_str().unwrap(),
+            source_path.to_str().unwrap(),
+            dest_base.to_str().unwrap(),
+        ], extra].concat()).unwrap();
+        assert!(out.status.success());
+        let mut copied = std::fs::read_to_string(&journal_path).unwrap()
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .filter(|r| r["action"] == "copied")
+            .map(|r| PathBuf::from(r["to"].as_str().unwrap()).file_name().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        copied.sort();
+        copied
+    };
+
+    assert_eq!(vec!["a.txt", "b.txt"], copy(&[]));
+    let dest_a = dest_base.join("a.txt");
+    let digest = checksum_file(&dest_a, ChecksumType::Blake3).unwrap();
+    assert_eq!(Some(digest.into_bytes()), xattr::get(&dest_a, "user.xcp.blake3").unwrap());
+    let meta = source_path.join("a.txt").metadata().unwrap();
+    let mtime = format!("{}.{:09}", meta.mtime(), meta.mtime_nsec());
+    assert_eq!(Some(mtime.into_bytes()), xattr::get(&dest_a, "user.xcp.src_mtime").unwrap());
+
+    // Current stamps are trusted.
+    assert!(copy(&["--skip-same=stamp"]).is_empty());
+
+    // A source modified since, even to the same contents, makes the
+    // stamp stale.
+    let touched = SystemTime::now() - Duration::from_secs(60);
+    File::options().write(true).open(source_path.join("a.txt")).unwrap()
+        .set_modified(touched).unwrap();
+    assert_eq!(vec!["a.txt"], copy(&["--skip-same=stamp"]));
+    assert!(copy(&["--skip-same=stamp"]).is_empty());
+
+    // Without a current stamp the contents are compared.
+    File::options().write(true).open(&dest_a).unwrap()
+        .set_modified(touched - Duration::from_secs(60)).unwrap();
+    assert!(copy(&["--skip-same=stamp"]).is_empty());
+    create_file(&dest_base.join("b.txt"), "file c").unwrap();
+    assert_eq!(vec!["b.txt"], copy(&["--skip-same=checksum"]));
+    assert!(file_contains(&dest_base.join("b.txt"), "file b").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn verify_manifest(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("a.txt"), "file a").unwrap();
+    create_file(&source_path.join("b.txt"), "file b").unwrap();
+    create_file(&source_path.join("sub/c.txt"), "file c").unwrap();
+
+    let dest_base = dir.path().join("dest");
+    let manifest_path = dir.path().join("manifest.json");
+    let manifest = manifest_path.to_str().unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--manifest", manifest,
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    let out = run(&["verify", "--driver", drv, manifest]).unwrap();
+    assert!(out.status.success());
+
+    // An explicit root overrides the recorded one.
+    let out = run(&["verify", manifest, source_path.to_str().unwrap()]).unwrap();
+    assert!(out.status.success());
+
+    create_file(&dest_base.join("a.txt"), "file x").unwrap();
+    create_file(&dest_base.join("b.txt"), "longer file b").unwrap();
+    remove_file(dest_base.join("sub/c.txt")).unwrap();
+
+    let out = run(&["verify", manifest]).unwrap();
+    assert_eq!(Some(1), out.status.code());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("\"a.txt\": checksum is"));
+    assert!(stderr.contains("\"b.txt\": size is 13, expected 6"));
+    assert!(stderr.contains("\"sub/c.txt\": missing"));
+    assert!(stderr.contains("3 of 3 files do not match the manifest"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_update_only_newer(drv: &str) {
+    use std::time::{Duration, SystemTime};
+
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("newer.txt"), "new source").unwrap();
+    create_file(&source_path.join("older.txt"), "old source").unwrap();
+    create_file(&source_path.join("missing.txt"), "missing").unwrap();
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(&dest_base).unwrap();
+    create_file(&dest_base.join("newer.txt"), "old dest").unwrap();
+    create_file(&dest_base.join("older.txt"), "new dest").unwrap();
+
+    let now = SystemTime::now();
+    let set_mtime = |path: &std::path::Path, time: SystemTime| {
+        File::options().write(true).open(path).unwrap()
+            .set_modified(time).unwrap();
+    };
+    set_mtime(&source_path.join("newer.txt"), now);
+    set_mtime(&dest_base.join("newer.txt"), now - Duration::from_secs(3600));
+    set_mtime(&source_path.join("older.txt"), now - Duration::from_secs(3600));
+    set_mtime(&dest_base.join("older.txt"), now);
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--update",
+        "--no-target-directory",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_base.join("newer.txt"), "new source").unwrap());
+    assert!(file_contains(&dest_base.join("older.txt"), "new dest").unwrap());
+    assert!(file_contains(&dest_base.join("missing.txt"), "missing").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock", None, 0o775; "Test with parallel block driver"))]
+#[test_case("parfile", None, 0o775; "Test with parallel file driver")]
+#[test_case("parfile", Some("preserve-existing"), 0o775; "Test with explicit preserve")]
+#[test_case("parfile", Some("overwrite"), 0o750; "Test with overwrite")]
+fn copy_dir_mode_existing_dest(drv: &str, mode: Option<&str>, expected: u32) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("existing")).unwrap();
+    create_dir_all(source_path.join("new")).unwrap();
+    create_file(&source_path.join("existing/file.txt"), "existing").unwrap();
+    create_file(&source_path.join("new/file.txt"), "new").unwrap();
+    set_permissions(source_path.join("existing"), Permissions::from_mode(0o750)).unwrap();
+    set_permissions(source_path.join("new"), Permissions::from_mode(0o700)).unwrap();
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(dest_base.join("existing")).unwrap();
+    set_permissions(dest_base.join("existing"), Permissions::from_mode(0o775)).unwrap();
+
+    let mut args = vec!["--driver", drv, "-r", "--no-target-directory"];
+    if let Some(m) = mode {
+        args.extend(["--dir-mode", m]);
+    }
+    args.extend([source_path.to_str().unwrap(), dest_base.to_str().unwrap()]);
+    let out = run(&args).unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_base.join("existing/file.txt"), "existing").unwrap());
+    assert!(file_contains(&dest_base.join("new/file.txt"), "new").unwrap());
+
+    let mode_of = |p: &str| dest_base.join(p).metadata().unwrap().permissions().mode() & 0o7777;
+    assert_eq!(expected, mode_of("existing"));
+    assert_eq!(0o700, mode_of("new"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_itemize_dry_run(drv: &str) {
+    use std::time::{Duration, SystemTime};
+
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("same.txt"), "same").unwrap();
+    create_file(&source_path.join("size.txt"), "longer source").unwrap();
+    create_file(&source_path.join("sub/new.txt"), "new").unwrap();
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(&dest_base).unwrap();
+    create_file(&dest_base.join("same.txt"), "same").unwrap();
+    create_file(&dest_base.join("size.txt"), "short").unwrap();
+    create_file(&dest_base.join("extra.txt"), "extra").unwrap();
+
+    let mtime = SystemTime::now() - Duration::from_secs(3600);
+    for f in ["same.txt", "size.txt"] {
+        File::options().write(true).open(source_path.join(f)).unwrap().set_modified(mtime).unwrap();
+        File::options().write(true).open(dest_base.join(f)).unwrap().set_modified(mtime).unwrap();
+    }
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--itemize",
+        "--dry-run",
+        "--delete",
+        "--no-perms",
+        "--no-target-directory",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let dest = dest_base.to_str().unwrap();
+    let expected = format!("*deleting {dest}/extra.txt\n\
+                            >fs.. {dest}/size.txt\n\
+                            cd+++ {dest}/sub\n\
+                            >f+++ {dest}/sub/new.txt\n");
+    assert_eq!(expected, stdout);
+
+    // Nothing should have changed.
+    assert!(dest_base.join("extra.txt").exists());
+    assert!(!dest_base.join("sub").exists());
+    assert!(file_contains(&dest_base.join("size.txt"), "short").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_itemize_json_delete(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file.txt"), "data").unwrap();
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(dest_base.join("olddir")).unwrap();
+    create_file(&dest_base.join("olddir/old.txt"), "old").unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--itemize",
+        "--delete",
+        "--progress", "json",
+        "--no-target-directory",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_base.join("file.txt"), "data").unwrap());
+    assert!(!dest_base.join("olddir").exists());
+
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let dest = dest_base.to_str().unwrap();
+    let items = stdout.lines()
+        .filter(|l| l.contains(r#""event":"item""#))
+        .collect::<Vec<&str>>();
+    assert_eq!(vec![
+        format!(r#"{{"event":"item","path":"{dest}/file.txt","kind":"file","change":"new"}}"#),
+        format!(r#"{{"event":"item","path":"{dest}/olddir","kind":"dir","change":"deleted"}}"#),
+    ], items);
+    let mut last = stdout.lines().rev();
+    assert_eq!(Some(r#"{"event":"summary","success":true,"exit_code":0}"#), last.next());
+    assert_eq!(Some(r#"{"event":"complete"}"#), last.next());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_json_finalizing(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    // With no data to copy, only metadata remains once the walk is
+    // complete.
+    let source_path = dir.path().join("mydir");
+    for i in 0..20 {
+        create_dir_all(source_path.join(format!("dir{i}/sub"))).unwrap();
+    }
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--progress", "json",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let finalizing = stdout.lines()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .filter(|e| e["event"] == "finalizing")
+        .collect::<Vec<_>>();
+    let last = finalizing.last().unwrap();
+    assert_eq!(41, last["total"]);
+    assert_eq!(41, last["done"]);
+    assert!(finalizing.windows(2).all(|w| w[0]["done"].as_u64() <= w[1]["done"].as_u64()));
+    let mut last = stdout.lines().rev();
+    assert_eq!(Some(r#"{"event":"summary","success":true,"exit_code":0}"#), last.next());
+    assert_eq!(Some(r#"{"event":"complete"}"#), last.next());
+}
+
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock", "D755,F644", 0o755, 0o644; "Test with parallel block driver"))]
+#[test_case("parfile", "D755,F644", 0o755, 0o644; "Test with dir and file modes")]
+#[test_case("parfile", "u+rwX,go-w", 0o755, 0o644; "Test with symbolic mode")]
+#[test_case("parfile", "a=r,Du+wx", 0o744, 0o444; "Test with symbolic set")]
+#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
+fn copy_chmod_override(drv: &str, mode: &str, dir_mode: u32, file_mode: u32) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("sub/file.txt"), "data").unwrap();
+    set_permissions(source_path.join("sub/file.txt"), Permissions::from_mode(0o666)).unwrap();
+    set_permissions(source_path.join("sub"), Permissions::from_mode(0o777)).unwrap();
+
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--chmod", mode,
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    let mode_of = |p: &str| dest_base.join(p).metadata().unwrap().permissions().mode() & 0o7777;
+    assert_eq!(dir_mode, mode_of("sub"));
+    assert_eq!(file_mode, mode_of("sub/file.txt"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_chown_override(drv: &str) {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "data").unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "--chown", "1234:5678",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    // Changing ownership requires privileges; without them it's a
+    // warning only.
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, "data").unwrap());
+    if source_path.metadata().unwrap().uid() == 0 {
+        let meta = dest_path.metadata().unwrap();
+        assert_eq!((1234, 5678), (meta.uid(), meta.gid()));
+    }
+}
+
+#[test]
+fn chown_unknown_user() {
+    let out = run(&["--chown", "no-such-user-xcp:", "a", "b"]).unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Unknown user: no-such-user-xcp"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock", true; "Test with parallel block driver"))]
+#[test_case("parfile", true; "Test with parallel file driver")]
+#[test_case("parfile", false; "Test without preserving links")]
+fn copy_hardlinks_progress(drv: &str, preserve: bool) {
+    use std::fs::hard_link;
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("a")).unwrap();
+    create_dir_all(source_path.join("b")).unwrap();
+    let data = "x".repeat(10_000);
+    create_file(&source_path.join("a/file.txt"), &data).unwrap();
+    hard_link(source_path.join("a/file.txt"), source_path.join("a/link1.txt")).unwrap();
+    hard_link(source_path.join("a/file.txt"), source_path.join("b/link2.txt")).unwrap();
+    create_file(&source_path.join("b/other.txt"), "other").unwrap();
+
+    let dest_base = dir.path().join("dest");
+    let mut args = vec!["--driver", drv, "-r", "--progress", "json"];
+    if preserve {
+        args.push("--preserve-hardlinks");
+    }
+    args.extend([source_path.to_str().unwrap(), dest_base.to_str().unwrap()]);
+    let out = run(&args).unwrap();
+
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let total: u64 = stdout.lines()
+        .filter_map(|l| l.strip_prefix(r#"{"event":"size","bytes":"#))
+        .map(|l| l.trim_end_matches('}').parse::<u64>().unwrap())
+        .sum();
+
+    let ino = |p: &str| dest_base.join(p).metadata().unwrap().ino();
+    for f in ["a/file.txt", "a/link1.txt", "b/link2.txt"] {
+        assert!(file_contains(&dest_base.join(f), &data).unwrap());
+    }
+    if preserve {
+        assert_eq!(10_005, total);
+        assert_eq!(ino("a/file.txt"), ino("a/link1.txt"));
+        assert_eq!(ino("a/file.txt"), ino("b/link2.txt"));
+        assert!(!stdout.contains("--preserve-hardlinks"));
+    } else {
+        assert_eq!(30_005, total);
+        assert_ne!(ino("a/file.txt"), ino("a/link1.txt"));
+        assert!(stdout.contains("consider using --preserve-hardlinks"));
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_no_fallocate(drv: &str) {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file.txt"), &"data".repeat(100_000)).unwrap();
+    {
+        // Data followed by a trailing hole.
+        let mut fd = File::create(source_path.join("sparse.bin")).unwrap();
+        fd.write_all(b"start").unwrap();
+        fd.seek(SeekFrom::Start(1024 * 1024)).unwrap();
+        fd.write_all(b"middle").unwrap();
+        fd.set_len(4 * 1024 * 1024).unwrap();
+    }
+
+    let dest_base = dir.path().join("dest");
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--no-fallocate",
+        "--block-size", "64K",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(files_match(&source_path.join("file.txt"), &dest_base.join("file.txt")));
+    assert!(files_match(&source_path.join("sparse.bin"), &dest_base.join("sparse.bin")));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_device_stats(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file.txt"), &"x".repeat(1000)).unwrap();
+
+    let dest_base = dir.path().join("dest");
+    let out = run(&[
+        "--driver", drv,
+        "-r", "-v",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.contains("Per-device throughput:"));
+    assert!(stdout.lines().any(|l| l.starts_with("source ") && l.contains("1000 B")));
+    assert!(stdout.lines().any(|l| l.starts_with("dest ") && l.contains("1000 B")));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_cache_linked_sources(drv: &str) {
+    use std::fs::hard_link;
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    let data = "x".repeat(10_000);
+    create_file(&source_path.join("file.txt"), &data).unwrap();
+    hard_link(source_path.join("file.txt"), source_path.join("link1.txt")).unwrap();
+    hard_link(source_path.join("file.txt"), source_path.join("link2.txt")).unwrap();
+
+    let dest_base = dir.path().join("dest");
+    let out = run(&[
+        "--driver", drv,
+        "-r", "-v",
+        "--cache-linked-sources",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.contains("Copying 20000 bytes from existing destination files"));
+    let ino = |p: &str| dest_base.join(p).metadata().unwrap().ino();
+    for f in ["file.txt", "link1.txt", "link2.txt"] {
+        assert!(file_contains(&dest_base.join(f), &data).unwrap());
+        assert_eq!(1, dest_base.join(f).metadata().unwrap().nlink());
+    }
+    assert_ne!(ino("file.txt"), ino("link1.txt"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(not(feature = "test_faults"), ignore = "Needs the test_faults feature")]
+fn copy_cache_linked_sources_failed_first(drv: &str) {
+    use std::fs::hard_link;
+
+    let dir = tempdir_rel().unwrap();
+    let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+    create_dir_all(&first).unwrap();
+    create_dir_all(&second).unwrap();
+    let data = "x".repeat(10_000);
+    create_file(&first.join("a.txt"), &data).unwrap();
+    hard_link(first.join("a.txt"), second.join("b.txt")).unwrap();
+    let dest_base = dir.path().join("dest");
+    create_dir_all(&dest_base).unwrap();
+    // EIO copying a.txt, which is found first as the sources take
+    // turns.
+    let faults = format!("CopyBytes:5:{}\nReadAt:5:{}",
+                         dest_base.join("first/a.txt").display(), first.join("a.txt").display());
+
+    let out = run_with_faults(&faults, &[
+        "--driver", drv,
+        "-r",
+        "--continue-on-error",
+        "--cache-linked-sources",
+        first.to_str().unwrap(),
+        second.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert_eq!(Some(1), out.status.code());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Copy completed with 1 error(s)"), "{}", stderr);
+    // The failed copy isn't copied again.
+    assert!(file_contains(&dest_base.join("second/b.txt"), &data).unwrap());
+}
+
+#[cfg(feature = "tracing")]
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_trace_out(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file.txt"), &"x".repeat(1000)).unwrap();
+
+    let dest_base = dir.path().join("dest");
+    let trace = dir.path().join("trace.json");
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--trace-out", trace.to_str().unwrap(),
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    let events: Vec<serde_json::Value> = serde_json::from_slice(&std::fs::read(&trace).unwrap()).unwrap();
+    let has_span = |name: &str| events.iter().any(|e| e["name"] == name);
+    assert!(has_span("scan"));
+    // The block driver copies in per-block spans.
+    assert!(has_span("copy_file") || has_span("copy_file_offset"));
+    assert!(has_span("metadata"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn log_target_file(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("a.txt"), "a").unwrap();
+    create_file(&source_path.join("b.txt"), "b").unwrap();
+
+    // A directory in place of a file causes a copy error.
+    let dest_base = dir.path().join("dest");
+    create_dir_all(dest_base.join("a.txt")).unwrap();
+
+    let log = dir.path().join("xcp.log");
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--continue-on-error",
+        "--no-target-directory",
+        "--log-target", "stderr",
+        "--log-target", &format!("file:{}", log.to_str().unwrap()),
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+    assert!(file_contains(&dest_base.join("b.txt"), "b").unwrap());
+
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    let logged = std::fs::read_to_string(&log).unwrap();
+    // The error is mirrored to the file as it happens, in addition to
+    // the summary on both targets.
+    let mirrored = format!("[ERROR] Error copying {:?}: Is a directory", source_path.join("a.txt"));
+    assert_eq!(1, logged.matches(&mirrored).count());
+    assert_eq!(1, stderr.matches("Is a directory").count());
+    // The summary names the file.
+    assert!(stderr.contains(&format!("{:?}: Is a directory", source_path.join("a.txt"))), "{}", stderr);
+    assert!(stderr.contains("Copy completed with 1 error(s)"));
+    assert!(logged.contains("Copy completed with 1 error(s)"));
+    // Lines start with a full timestamp.
+    for line in logged.lines() {
+        let date = line.split('T').next().unwrap();
+        assert_eq!(10, date.len(), "{}", line);
+        assert_eq!(3, date.split('-').count(), "{}", line);
+    }
+}
+
+#[test_case(&[], false; "Test default colour through a pipe")]
+#[test_case(&["--color=never"], false; "Test colour disabled")]
+#[test_case(&["--color=always"], true; "Test colour forced")]
+fn color_policy(args: &[&str], colored: bool) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("a.txt"), "a").unwrap();
+    create_file(&source_path.join("b.txt"), "b").unwrap();
+
+    // A directory in place of a file causes a copy error, so every
+    // log level is written.
+    let dest_base = dir.path().join("dest");
+    create_dir_all(dest_base.join("a.txt")).unwrap();
+
+    for no_color in [None, Some("1")] {
+        let mut cmd = get_command().unwrap();
+        match no_color {
+            Some(v) => cmd.env("NO_COLOR", v),
+            None => cmd.env_remove("NO_COLOR"),
+        };
+        let out = cmd
+            .args(["-vv", "-r", "--continue-on-error", "--no-target-directory"])
+            .args(args)
+            .args([source_path.to_str().unwrap(), dest_base.to_str().unwrap()])
+            .output()
+            .unwrap();
+        assert!(!out.status.success());
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert!(stderr.contains("Is a directory"));
+        let escaped = out.stdout.iter().chain(stderr.as_bytes()).any(|b| *b == 0x1b);
+        assert_eq!(colored, escaped, "{:?}", stderr);
+    }
+}
+
+#[test]
+fn copy_byte_range() {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("image.raw");
+    let data = (0..8192_u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+    write(&source, &data).unwrap();
+
+    let dest = dir.path().join("slice.bin");
+    let out = run(&[
+        "--offset", "1K",
+        "--length", "2K",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert_eq!(data[1024..3072], std::fs::read(&dest).unwrap()[..]);
+
+    // Writing at an offset must not truncate the destination.
+    write(&dest, vec![0xff; 4096]).unwrap();
+    let out = run(&[
+        "--offset", "7K",
+        "--dest-offset", "1K",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    let copied = std::fs::read(&dest).unwrap();
+    assert_eq!(4096, copied.len());
+    assert_eq!(data[7168..], copied[1024..2048]);
+    assert!(copied[2048..].iter().all(|b| *b == 0xff));
+}
+
+#[test]
+fn copy_byte_range_out_of_bounds() {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("image.raw");
+    write(&source, vec![1; 4096]).unwrap();
+
+    let dest = dir.path().join("slice.bin");
+    write(&dest, "existing").unwrap();
+    let out = run(&[
+        "--offset", "2K",
+        "--length", "4K",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("outside the source length"));
+    assert!(file_contains(&dest, "existing").unwrap());
+}
+
+#[test]
+fn noclobber_racing_creator() {
+    use std::time::{Duration, Instant};
+
+    let dir = tempdir_rel().unwrap();
+    let big = dir.path().join("big.bin");
+    let small = dir.path().join("small.txt");
+    write(&big, vec![1u8; 128 * 1024 * 1024]).unwrap();
+    create_file(&small, "source").unwrap();
+    let dest = dir.path().join("dest");
+    create_dir_all(&dest).unwrap();
+
+    // With a single worker the sources are copied in order, so the
+    // second destination can be created while the first is copying;
+    // i.e. after the sources have been walked but before the second
+    // file is opened.
+    let child = get_command().unwrap()
+        .args([
+            "--driver", "parfile",
+            "--workers", "1",
+            "--no-clobber",
+            big.to_str().unwrap(),
+            small.to_str().unwrap(),
+            dest.to_str().unwrap(),
+        ])
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let start = Instant::now();
+    while !dest.join("big.bin").exists() {
+        assert!(start.elapsed() < Duration::from_secs(30), "Copy did not start");
+        std::thread::yield_now();
+    }
+    File::options().write(true).create_new(true)
+        .open(dest.join("small.txt"))
+        .and_then(|mut f| std::io::Write::write_all(&mut f, b"precious"))
+        .expect("Destination created before the race");
+
+    let out = child.wait_with_output().unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Destination file exists"));
+    assert!(file_contains(&dest.join("small.txt"), "precious").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_into_symlinked_subdir_of_source(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("data");
+    create_dir_all(source.join("sub")).unwrap();
+    create_file(&source.join("file.txt"), "data").unwrap();
+    let link = dir.path().join("link");
+    symlink(source.join("sub").canonicalize().unwrap(), &link).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        source.to_str().unwrap(),
+        link.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Cannot copy a directory into itself"));
+    // Rejected before anything is copied.
+    assert!(!source.join("sub/data").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock", true, &["-r"], "into"; "Test directory into symlinked dir with parallel block driver"))]
+#[test_case("parfile", true, &["-r"], "into"; "Test directory into symlinked dir")]
+#[test_case("parfile", false, &[], "into"; "Test file into symlinked dir")]
+#[test_case("parfile", true, &["-r", "--remove-destination"], "into"; "Test directory into symlinked dir removing destination")]
+#[test_case("parfile", true, &["-rT"], "rejected"; "Test directory over symlinked dir")]
+#[test_case("parfile", false, &["-T"], "rejected"; "Test file over symlinked dir")]
+#[cfg_attr(feature = "parblock", test_case("parblock", true, &["-rT", "--remove-destination"], "replaced"; "Test directory replacing symlinked dir with parallel block driver"))]
+#[test_case("parfile", true, &["-rT", "--remove-destination"], "replaced"; "Test directory replacing symlinked dir")]
+#[test_case("parfile", false, &["-T", "--remove-destination"], "replaced"; "Test file replacing symlinked dir")]
+fn copy_to_symlinked_dir(drv: &str, dir_source: bool, args: &[&str], outcome: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("data");
+    if dir_source {
+        create_dir_all(source.join("sub")).unwrap();
+        create_file(&source.join("sub/file.txt"), "data").unwrap();
+    } else {
+        create_file(&source, "data").unwrap();
+    }
+    let real = dir.path().join("real");
+    create_dir_all(&real).unwrap();
+    let link = dir.path().join("link");
+    symlink("real", &link).unwrap();
+
+    let out = get_command().unwrap()
+        .args(["--driver", drv])
+        .args(args)
+        .args([source.to_str().unwrap(), link.to_str().unwrap()])
+        .output()
+        .unwrap();
+
+    let copied = |path: &Path| if dir_source {
+        compare_trees(&source, path).is_ok()
+    } else {
+        file_contains(path, "data").unwrap()
+    };
+    match outcome {
+        "into" => {
+            assert!(out.status.success());
+            assert!(link.is_symlink());
+            assert!(copied(&real.join("data")));
+        }
+        "replaced" => {
+            assert!(out.status.success());
+            assert!(!link.is_symlink());
+            assert!(copied(&link));
+            assert_eq!(0, std::fs::read_dir(&real).unwrap().count());
+        }
+        _ => {
+            assert!(!out.status.success());
+            assert!(String::from_utf8_lossy(&out.stderr).contains("--remove-destination"));
+            assert!(link.is_symlink());
+            assert_eq!(0, std::fs::read_dir(&real).unwrap().count());
+        }
+    }
+}
+
+// The files in a directory, with their contents and mode, for comparing
+// the results of different copies.
+fn dir_outcome(dir: &std::path::Path) -> Vec<(String, String, u32)> {
+    let mut files = std::fs::read_dir(dir).unwrap()
+        .map(|e| {
+            let e = e.unwrap();
+            let mode = e.metadata().unwrap().permissions().mode() & 0o7777;
+            let text = std::fs::read_to_string(e.path()).unwrap();
+            (e.file_name().into_string().unwrap(), text, mode)
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn single_and_recursive_copies_match(drv: &str) {
+    // '--no-clobber=skip' is excluded, as without '-r' it always fails.
+    let option_sets: &[&[&str]] = &[
+        &[],
+        &["--backup=numbered"],
+        &["--update"],
+        &["--update", "--backup=numbered"],
+        &["--no-clobber=fail"],
+        &["--chmod=600"],
+        &["--no-perms"],
+    ];
+
+    for opts in option_sets {
+        let dir = tempdir_rel().unwrap();
+        let source = dir.path().join("source");
+        let single = dir.path().join("single");
+        let recursive = dir.path().join("recursive");
+        create_dir_all(&source).unwrap();
+        create_file(&source.join("newer.txt"), "new").unwrap();
+        create_file(&source.join("older.txt"), "stale").unwrap();
+        set_permissions(source.join("newer.txt"), Permissions::from_mode(0o640)).unwrap();
+        set_time_past(&source.join("older.txt")).unwrap();
+        for dest in [&single, &recursive] {
+            create_dir_all(dest).unwrap();
+            create_file(&dest.join("newer.txt"), "old").unwrap();
+            create_file(&dest.join("older.txt"), "current").unwrap();
+            set_time_past(&dest.join("newer.txt")).unwrap();
+        }
+
+        let mut single_ok = true;
+        for name in ["newer.txt", "older.txt"] {
+            let (from, to) = (source.join(name), single.join(name));
+            let mut args = vec!["--driver", drv];
+            args.extend_from_slice(opts);
+            args.push(from.to_str().unwrap());
+            args.push(to.to_str().unwrap());
+            single_ok &= run(&args).unwrap().status.success();
+        }
+
+        let mut args = vec!["--driver", drv, "-r", "-T"];
+        args.extend_from_slice(opts);
+        args.push(source.to_str().unwrap());
+        args.push(recursive.to_str().unwrap());
+        let recursive_ok = run(&args).unwrap().status.success();
+
+        assert_eq!(single_ok, recursive_ok, "Exit status differs with {:?}", opts);
+        assert_eq!(dir_outcome(&single), dir_outcome(&recursive), "Results differ with {:?}", opts);
+    }
+}
+
+#[test]
+fn delete_into_large_dest_requires_confirmation() {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(&source).unwrap();
+    create_dir_all(&dest).unwrap();
+    create_file(&source.join("keep.txt"), "new").unwrap();
+    for i in 0..5 {
+        create_file(&dest.join(format!("extra{}.txt", i)), "old").unwrap();
+    }
+    let args = ["-r", "-T", "--delete", "--confirm-threshold=3",
+                source.to_str().unwrap(), dest.to_str().unwrap()];
+
+    let out = run(&args).unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("more than 3 entries"));
+    assert!(stderr.contains("--yes"));
+    assert!(dest.join("extra0.txt").exists());
+    assert!(!dest.join("keep.txt").exists());
+
+    // Below the threshold no confirmation is needed.
+    let out = run(&["-r", "-T", "--delete", "--confirm-threshold=10", args[4], args[5]]).unwrap();
+    assert!(out.status.success());
+    assert!(dest.join("keep.txt").exists());
+    assert!(!dest.join("extra0.txt").exists());
+}
+
+#[test]
+fn copy_into_home_requires_confirmation() {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let home = dir.path().join("home");
+    create_dir_all(&source).unwrap();
+    create_dir_all(&home).unwrap();
+    create_file(&source.join("file.txt"), "data").unwrap();
+    let args = ["-r", "-T", source.to_str().unwrap(), home.to_str().unwrap()];
+
+    let out = get_command().unwrap().env("HOME", &home).args(args).output().unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr).unwrap().contains("home directory"));
+    assert!(!home.join("file.txt").exists());
+
+    // As is copying into it.
+    let out = get_command().unwrap().env("HOME", &home).args(["-r", args[2], args[3]]).output().unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr).unwrap().contains("home directory"));
+    assert!(!home.join("source").exists());
+
+    // A sub-directory of the home directory is fine.
+    let sub = home.join("sub");
+    let out = get_command().unwrap().env("HOME", &home).args(["-r", args[2], sub.to_str().unwrap()]).output().unwrap();
+    assert!(out.status.success());
+    assert!(sub.join("file.txt").exists());
+
+    let out = get_command().unwrap().env("HOME", &home).arg("--yes").args(args).output().unwrap();
+    assert!(out.status.success());
+    assert!(home.join("file.txt").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock", "largest-first"; "Test largest-first with parallel block driver"))]
+#[cfg_attr(feature = "parblock", test_case("parblock", "smallest-first"; "Test smallest-first with parallel block driver"))]
+#[test_case("parfile", "largest-first"; "Test largest-first with parallel file driver")]
+#[test_case("parfile", "smallest-first"; "Test smallest-first with parallel file driver")]
+fn copy_ordered(drv: &str, order: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    for (i, size) in [100, 300_000, 0, 5000, 2_000_000].iter().enumerate() {
+        let sub = source.join(format!("dir{}", i % 2));
+        create_dir_all(&sub).unwrap();
+        write(sub.join(format!("file{}.bin", i)), rand_data(*size)).unwrap();
+    }
+    symlink("dir0/file0.bin", source.join("link")).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--order", order,
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(out.status.success());
+    compare_trees(&source, &dest).unwrap();
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_colliding_sources(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let disk1 = dir.path().join("disk1/data");
+    let disk2 = dir.path().join("disk2/data");
+    let dest = dir.path().join("dest");
+    for disk in [&disk1, &disk2] {
+        create_dir_all(disk).unwrap();
+        create_file(&disk.join("file.txt"), disk.to_str().unwrap()).unwrap();
+    }
+    create_dir_all(&dest).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        disk1.to_str().unwrap(),
+        disk2.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr).unwrap().contains("would both be copied to"));
+    assert!(!dest.join("data").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_dest_subdir_from_source(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let disk1 = dir.path().join("disk1/data");
+    let disk2 = dir.path().join("disk2/data");
+    let disk3 = dir.path().join("disk3");
+    let dest = dir.path().join("pool");
+    for disk in [&disk1, &disk2, &disk3] {
+        create_dir_all(disk).unwrap();
+        create_file(&disk.join("file.txt"), disk.to_str().unwrap()).unwrap();
+    }
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--dest-subdir-from-source",
+        disk1.to_str().unwrap(),
+        disk2.to_str().unwrap(),
+        disk3.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest.join("disk1_data/file.txt"), disk1.to_str().unwrap()).unwrap());
+    assert!(file_contains(&dest.join("disk2_data/file.txt"), disk2.to_str().unwrap()).unwrap());
+    assert!(file_contains(&dest.join("disk3/file.txt"), disk3.to_str().unwrap()).unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_quiet(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(source.join("sub")).unwrap();
+    create_file(&source.join("file.txt"), "data").unwrap();
+    create_file(&source.join("sub/file.txt"), "data").unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-r", "-q",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(out.status.success());
+    assert!(out.stderr.is_empty());
+    assert!(out.stdout.is_empty());
+    assert!(file_contains(&dest.join("sub/file.txt"), "data").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_quiet_error(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let missing = dir.path().join("missing.txt");
+    let dest = dir.path().join("dest.txt");
+
+    let out = run(&[
+        "--driver", drv,
+        "-q",
+        missing.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Source does not exist"));
+
+    let out = run(&[
+        "--driver", drv,
+        "-qq",
+        missing.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(!out.status.success());
+    assert!(out.stderr.is_empty());
+    assert!(out.stdout.is_empty());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_with_stall_timeout(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(&source).unwrap();
+    for i in 0..20 {
+        create_file(&source.join(format!("file{}.txt", i)), &"x".repeat(i * 1000)).unwrap();
+    }
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--stall-warning", "1",
+        "--stall-timeout", "1",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(out.status.success());
+    assert!(!String::from_utf8_lossy(&out.stderr).contains("stalled"));
+    assert!(compare_trees(&source, &dest).is_ok());
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--stall-timeout", "0",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.code().unwrap() == 2);
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_with_metrics_interval(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(&source).unwrap();
+    for i in 0..20 {
+        create_file(&source.join(format!("file{}.txt", i)), &"x".repeat(i * 1000)).unwrap();
+    }
+
+    // The copy is too quick to be reported, but any report must be a
+    // well-formed event.
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--progress=json",
+        "--metrics-interval", "1",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(compare_trees(&source, &dest).is_ok());
+    for line in String::from_utf8_lossy(&out.stdout).lines() {
+        let event: serde_json::Value = serde_json::from_str(line).unwrap();
+        if event["event"] == "metrics" {
+            assert!(event["work_queue"].is_u64());
+            assert!(event["rates"].is_array());
+        }
+    }
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--metrics-interval", "0",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.code().unwrap() == 2);
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock", 100_000, 80_000; "Test shrinking overwrite with parallel block driver"))]
+#[cfg_attr(feature = "parblock", test_case("parblock", 80_000, 100_000; "Test growing overwrite with parallel block driver"))]
+#[test_case("parfile", 100_000, 80_000; "Test shrinking overwrite with parallel file driver")]
+#[test_case("parfile", 80_000, 100_000; "Test growing overwrite with parallel file driver")]
+#[test_case("parfile", 100_000, 10; "Test truncating overwrite with parallel file driver")]
+fn overwrite_existing_length(drv: &str, old: usize, new: usize) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    let data = rand_data(new);
+    write(&source_path, &data).unwrap();
+    write(&dest_path, vec![0xff; old]).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "--block-size", "4K",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(out.status.success());
+    assert_eq!(new as u64, dest_path.metadata().unwrap().len());
+    assert_eq!(data, std::fs::read(&dest_path).unwrap());
+}
+
+#[test]
+fn stream_from_stdin() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let dir = tempdir_rel().unwrap();
+    let dest_path = dir.path().join("dest.bin");
+    let data = rand_data(1024 * 1024);
+
+    let mut child = get_command().unwrap()
+        .args(["-", dest_path.to_str().unwrap()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(&data).unwrap();
+    let out = child.wait_with_output().unwrap();
+
+    assert!(out.status.success());
+    assert_eq!(data, std::fs::read(&dest_path).unwrap());
+}
+
+#[test_case(&[]; "Test streaming to stdout")]
+#[test_case(&["--progress=json"]; "Test streaming to stdout with JSON progress")]
+#[test_case(&["-v"]; "Test streaming to stdout with verbose logging")]
+fn stream_to_stdout(args: &[&str]) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    let data = rand_data(1024 * 1024);
+    write(&source_path, &data).unwrap();
+
+    let out = get_command().unwrap()
+        .args(args)
+        .args([source_path.to_str().unwrap(), "-"])
+        .output()
+        .unwrap();
+
+    assert!(out.status.success());
+    // Only the data goes to stdout.
+    assert_eq!(data, out.stdout);
+}
+
+#[test_case(&["-r", "-", "dest"]; "Test stdin with recursive")]
+#[test_case(&["a", "-", "dest"]; "Test stdin with multiple sources")]
+#[test_case(&["a", "b", "-"]; "Test stdout with multiple sources")]
+fn stream_bad_args(args: &[&str]) {
+    let out = run(args).unwrap();
+    assert_eq!(Some(2), out.status.code());
+}
+
+// Start a copy from stdin to `dest`, which holds the lock on it until
+// its stdin is closed.
+fn hold_lock(dest: &Path) -> std::process::Child {
+    use std::process::Stdio;
+
+    let child = get_command().unwrap()
+        .args(["-", dest.to_str().unwrap()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let lock = dest.with_file_name(format!(".xcp-lock-{}", dest.file_name().unwrap().to_str().unwrap()));
+    let start = std::time::Instant::now();
+    while !lock.exists() || std::fs::read_to_string(&lock).unwrap().is_empty() {
+        assert!(start.elapsed() < std::time::Duration::from_secs(10), "Lock not taken");
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    child
+}
+
+#[test]
+fn lock_held() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "data").unwrap();
+
+    let mut holder = hold_lock(&dest_path);
+    let out = run(&[source_path.to_str().unwrap(), dest_path.to_str().unwrap()]).unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("is locked by another copy"), "{}", stderr);
+    assert!(stderr.contains(&format!("(pid {})", holder.id())), "{}", stderr);
+
+    let out = run(&["--no-lock", source_path.to_str().unwrap(), dest_path.to_str().unwrap()]).unwrap();
+    assert!(out.status.success());
+
+    drop(holder.stdin.take());
+    assert!(holder.wait().unwrap().success());
+    assert!(!dir.path().join(".xcp-lock-dest.txt").exists());
+}
+
+#[test]
+fn lock_wait() {
+    use std::process::Stdio;
+
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "data").unwrap();
+
+    let mut holder = hold_lock(&dest_path);
+    let mut waiter = get_command().unwrap()
+        .args(["--wait-lock", source_path.to_str().unwrap(), dest_path.to_str().unwrap()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    assert!(waiter.try_wait().unwrap().is_none());
+
+    drop(holder.stdin.take());
+    assert!(holder.wait().unwrap().success());
+    assert!(waiter.wait().unwrap().success());
+    assert!(file_contains(&dest_path, "data").unwrap());
+}
+
+#[test]
+fn lock_wait_timeout() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "data").unwrap();
+
+    let mut holder = hold_lock(&dest_path);
+    let start = std::time::Instant::now();
+    let out = run(&["--wait-lock=1", source_path.to_str().unwrap(), dest_path.to_str().unwrap()]).unwrap();
+    assert!(!out.status.success());
+    assert!(start.elapsed() >= std::time::Duration::from_secs(1));
+    assert!(String::from_utf8(out.stderr).unwrap().contains("is locked by another copy"));
+
+    drop(holder.stdin.take());
+    holder.wait().unwrap();
+}
+
+#[test]
+fn lock_stale() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(&source_path).unwrap();
+    create_dir_all(&dest_path).unwrap();
+    create_file(&source_path.join("file.txt"), "data").unwrap();
+    // Left by a copy that didn't exit cleanly.
+    create_file(&dest_path.join(".xcp-lock"), &format!("{} 0\n", i32::MAX)).unwrap();
+
+    let out = run(&["-r", source_path.to_str().unwrap(), dest_path.to_str().unwrap()]).unwrap();
+    assert!(out.status.success());
+    assert!(String::from_utf8(out.stdout).unwrap().contains("Breaking stale lock"));
+    assert!(file_contains(&dest_path.join("source/file.txt"), "data").unwrap());
+    assert!(!dest_path.join(".xcp-lock").exists());
+}
+
+// A dangling symlink `link` in `dir`, to `target/file.txt` under a
+// directory that doesn't exist, given relatively or absolutely.
+fn dangling_link(dir: &Path, absolute: bool, parent_exists: bool) -> (PathBuf, PathBuf) {
+    let target = dir.join("target").join("file.txt");
+    if parent_exists {
+        create_dir_all(target.parent().unwrap()).unwrap();
+    }
+    let link = dir.join("link");
+    let to = match absolute {
+        true => std::env::current_dir().unwrap().join(&target),
+        false => PathBuf::from("target/file.txt"),
+    };
+    symlink(to, &link).unwrap();
+    (link, target)
+}
+
+#[test_case(false; "Test relative dangling destination")]
+#[test_case(true; "Test absolute dangling destination")]
+fn dangling_dest_symlink_fails(absolute: bool) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    create_file(&source_path, "data").unwrap();
+    let (link, target) = dangling_link(dir.path(), absolute, true);
+
+    let out = run(&[source_path.to_str().unwrap(), link.to_str().unwrap()]).unwrap();
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("is a symlink to"));
+    assert!(stderr.contains("link"));
+    assert!(stderr.contains("file.txt"));
+    assert!(!target.exists());
+    assert!(link.is_symlink());
+}
+
+#[test_case(false, true, &[]; "Test following relative dangling destination")]
+#[test_case(true, true, &[]; "Test following absolute dangling destination")]
+#[test_case(false, false, &["--mkdir-parents"]; "Test following relative dangling destination with parents")]
+#[test_case(true, false, &["--mkdir-parents"]; "Test following absolute dangling destination with parents")]
+fn dangling_dest_symlink_follow(absolute: bool, parent_exists: bool, args: &[&str]) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    create_file(&source_path, "data").unwrap();
+    let (link, target) = dangling_link(dir.path(), absolute, parent_exists);
+
+    let out = get_command().unwrap()
+        .arg("--follow-dest-symlinks")
+        .args(args)
+        .args([source_path.to_str().unwrap(), link.to_str().unwrap()])
+        .output()
+        .unwrap();
+
+    assert!(out.status.success());
+    assert!(link.is_symlink());
+    assert!(file_contains(&target, "data").unwrap());
+}
+
+#[test_case(false; "Test following relative dangling destination without parents")]
+#[test_case(true; "Test following absolute dangling destination without parents")]
+fn dangling_dest_symlink_follow_no_parents(absolute: bool) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    create_file(&source_path, "data").unwrap();
+    let (link, target) = dangling_link(dir.path(), absolute, false);
+
+    let out = run(&[
+        "--follow-dest-symlinks",
+        source_path.to_str().unwrap(),
+        link.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(!out.status.success());
+    assert!(!target.parent().unwrap().exists());
+}
+
+#[test_case(false; "Test removing relative dangling destination")]
+#[test_case(true; "Test removing absolute dangling destination")]
+fn dangling_dest_symlink_remove(absolute: bool) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    create_file(&source_path, "data").unwrap();
+    let (link, target) = dangling_link(dir.path(), absolute, true);
+
+    let out = run(&[
+        "--remove-destination",
+        source_path.to_str().unwrap(),
+        link.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(out.status.success());
+    assert!(!link.is_symlink());
+    assert!(file_contains(&link, "data").unwrap());
+    assert!(!target.exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock", &[], false; "Test recursive dangling destination with parallel block driver"))]
+#[test_case("parfile", &[], false; "Test recursive dangling destination with parallel file driver")]
+#[test_case("parfile", &["--follow-dest-symlinks"], true; "Test recursive following dangling destination")]
+#[test_case("parfile", &["--remove-destination"], true; "Test recursive removing dangling destination")]
+fn dangling_dest_symlink_recursive(drv: &str, args: &[&str], succeeds: bool) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file.txt"), "data").unwrap();
+    let dest_path = dir.path().join("dest");
+    create_dir_all(&dest_path).unwrap();
+    symlink("../elsewhere.txt", dest_path.join("file.txt")).unwrap();
+
+    let out = get_command().unwrap()
+        .args(["--driver", drv, "-rT"])
+        .args(args)
+        .args([source_path.to_str().unwrap(), dest_path.to_str().unwrap()])
+        .output()
+        .unwrap();
+
+    assert_eq!(succeeds, out.status.success());
+    let followed = dir.path().join("elsewhere.txt");
+    match args {
+        ["--follow-dest-symlinks"] => assert!(file_contains(&followed, "data").unwrap()),
+        ["--remove-destination"] => {
+            assert!(!dest_path.join("file.txt").is_symlink());
+            assert!(file_contains(&dest_path.join("file.txt"), "data").unwrap());
+            assert!(!followed.exists());
+        }
+        _ => assert!(!followed.exists()),
+    }
+}
+
+#[test_case(&[]; "Test comparing by metadata")]
+#[test_case(&["--compare-checksum", "sha256"]; "Test comparing by checksum")]
+fn compare_only_identical(args: &[&str]) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("file.txt"), "data").unwrap();
+    write(source_path.join("sub/file.bin"), rand_data(1024 * 1024)).unwrap();
+    let out = run(&["-r", source_path.to_str().unwrap(), dest_path.to_str().unwrap()]).unwrap();
+    assert!(out.status.success());
+
+    let out = get_command().unwrap()
+        .arg("--compare-only")
+        .args(args)
+        .args([source_path.to_str().unwrap(), dest_path.to_str().unwrap()])
+        .output()
+        .unwrap();
+
+    assert!(out.status.success());
+    assert!(out.stdout.is_empty());
+    // Nothing was copied or changed.
+    assert!(compare_trees(&source_path, &dest_path).is_ok());
+}
+
+#[test_case(&[], false; "Test comparing different trees by metadata")]
+#[test_case(&["--compare-checksum", "blake3"], true; "Test comparing different trees by checksum")]
+fn compare_only_differences(args: &[&str], checksum: bool) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(&source_path).unwrap();
+    create_dir_all(&dest_path).unwrap();
+    for path in [&source_path, &dest_path] {
+        create_file(&path.join("same.txt"), "same").unwrap();
+    }
+    create_file(&source_path.join("content.txt"), "abcd").unwrap();
+    create_file(&dest_path.join("content.txt"), "abce").unwrap();
+    create_file(&source_path.join("size.txt"), "short").unwrap();
+    create_file(&dest_path.join("size.txt"), "longer").unwrap();
+    create_file(&source_path.join("missing.txt"), "missing").unwrap();
+    create_file(&dest_path.join("extra.txt"), "extra").unwrap();
+    // Align the timestamps of the files that exist in both.
+    for name in ["same.txt", "content.txt", "size.txt"] {
+        let mtime = source_path.join(name).metadata().unwrap().modified().unwrap();
+        File::options().write(true).open(dest_path.join(name)).unwrap()
+            .set_modified(mtime).unwrap();
+    }
+
+    let out = get_command().unwrap()
+        .args(["--compare-only", "--no-perms"])
+        .args(args)
+        .args([source_path.to_str().unwrap(), dest_path.to_str().unwrap()])
+        .output()
+        .unwrap();
+
+    assert_eq!(Some(1), out.status.code());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let lines = stdout.lines().collect::<Vec<&str>>();
+    let dest = dest_path.to_str().unwrap();
+    let mut expected = vec![
+        format!("*extra {}/extra.txt", dest),
+        format!(">f+++ {}/missing.txt", dest),
+        format!(">fs.. {}/size.txt", dest),
+    ];
+    if checksum {
+        expected.insert(0, format!(">fc.. {}/content.txt", dest));
+    }
+    assert_eq!(expected, lines);
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    let differing = if checksum { 2 } else { 1 };
+    assert!(stderr.contains(&format!("Trees differ: {} differing, 1 missing and 1 extra paths", differing)));
+    // The destination is unchanged.
+    assert!(!dest_path.join("missing.txt").exists());
+    assert!(file_contains(&dest_path.join("content.txt"), "abce").unwrap());
+}
+
+#[test]
+fn compare_only_errors() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("missing");
+    let dest_path = dir.path().join("dest");
+    create_dir_all(&dest_path).unwrap();
+
+    let out = run(&["--compare-only", source_path.to_str().unwrap(), dest_path.to_str().unwrap()]).unwrap();
+    assert_eq!(Some(2), out.status.code());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn staging_dir_copy(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let dest_path = dir.path().join("dest");
+    let staging = dir.path().join("staging");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_dir_all(&staging).unwrap();
+    create_file(&source_path.join("file.txt"), "data").unwrap();
+    write(source_path.join("sub/file.bin"), rand_data(1024 * 1024)).unwrap();
+    set_permissions(source_path.join("file.txt"), Permissions::from_mode(0o640)).unwrap();
+
+    // Left by a crashed run.
+    create_dir_all(staging.join(".xcp-staging-1")).unwrap();
+    create_file(&staging.join(".xcp-staging-1/0-file.txt"), "partial").unwrap();
+    create_file(&staging.join(".xcp-staging-1.lock"), "").unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--staging-dir", staging.to_str().unwrap(),
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(out.status.success());
+    assert!(compare_trees(&source_path, &dest_path).is_ok());
+    let mode = dest_path.join("file.txt").metadata().unwrap().permissions().mode() & 0o777;
+    assert_eq!(0o640, mode);
+    assert_eq!(0, std::fs::read_dir(&staging).unwrap().count());
+}
+
+#[test]
+fn staging_dir_no_clobber() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let staging = dir.path().join("staging");
+    create_dir_all(&staging).unwrap();
+    create_file(&source_path, "new").unwrap();
+    create_file(&dest_path, "existing").unwrap();
+
+    let out = run(&[
+        "--no-clobber",
+        "--staging-dir", staging.to_str().unwrap(),
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(!out.status.success());
+    assert!(file_contains(&dest_path, "existing").unwrap());
+    assert_eq!(0, std::fs::read_dir(&staging).unwrap().count());
+}
+
+#[test]
+fn staging_dir_missing() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "data").unwrap();
+
+    let out = run(&[
+        "--staging-dir", dir.path().join("missing").to_str().unwrap(),
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(!out.status.success());
+    assert!(!dest_path.exists());
+}
+
+#[test_case(0o022, None; "umask 022")]
+#[test_case(0o027, None; "umask 027")]
+#[test_case(0o077, None; "umask 077")]
+#[test_case(0o022, Some(0o700); "umask 022 new mode 700")]
+#[test_case(0o027, Some(0o775); "umask 027 new mode 775")]
+fn new_dir_mode_synthesized(umask: u32, new_mode: Option<u32>) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("sub/file.txt"), "data").unwrap();
+    set_permissions(&source_path, Permissions::from_mode(0o751)).unwrap();
+    set_permissions(source_path.join("sub"), Permissions::from_mode(0o705)).unwrap();
+    let dest_base = dir.path().join("a/b");
+
+    let mode_arg = new_mode.map(|m| format!("{:o}", m));
+    let mut args = vec!["-r", "--dest-subdir-from-source"];
+    if let Some(ref m) = mode_arg {
+        args.extend(["--new-dir-mode", m.as_str()]);
+    }
+    args.extend([source_path.to_str().unwrap(), dest_base.to_str().unwrap()]);
+    let out = run_with_umask(umask, &args).unwrap();
+    assert!(out.status.success());
+
+    let mode = |path: &Path| path.metadata().unwrap().permissions().mode() & 0o7777;
+    // Synthesized ancestors follow the umask...
+    let expected = new_mode.unwrap_or(0o777) & !umask;
+    assert_eq!(expected, mode(&dir.path().join("a")));
+    assert_eq!(expected, mode(&dest_base));
+    // ...but copied directories keep their source modes.
+    assert_eq!(0o751, mode(&dest_base.join("source")));
+    assert_eq!(0o705, mode(&dest_base.join("source/sub")));
+    assert!(file_contains(&dest_base.join("source/sub/file.txt"), "data").unwrap());
+}
+
+#[test_case(0o022; "umask 022")]
+#[test_case(0o077; "umask 077")]
+fn new_dir_mode_mkdir_parents(umask: u32) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let link = dir.path().join("link.txt");
+    create_file(&source_path, "data").unwrap();
+    symlink(dir.path().join("x/y/target.txt"), &link).unwrap();
+
+    let out = run_with_umask(umask, &[
+        "--follow-dest-symlinks", "--mkdir-parents",
+        "--new-dir-mode", "750",
+        source_path.to_str().unwrap(),
+        link.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+
+    let mode = |path: &Path| path.metadata().unwrap().permissions().mode() & 0o7777;
+    assert_eq!(0o750 & !umask, mode(&dir.path().join("x")));
+    assert_eq!(0o750 & !umask, mode(&dir.path().join("x/y")));
+    assert!(file_contains(&dir.path().join("x/y/target.txt"), "data").unwrap());
+}
+
+#[test]
+fn new_dir_mode_invalid() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    create_file(&source_path, "data").unwrap();
+
+    let out = run(&[
+        "--new-dir-mode", "789",
+        source_path.to_str().unwrap(),
+        dir.path().join("dest.txt").to_str().unwrap(),
+    ]).unwrap();
+    assert!(!out.status.success());
+}
+
+// Sum the byte counts of JSON progress events of the given type.
+fn event_bytes(stdout: &str, event: &str) -> i64 {
+    stdout.lines()
+        .filter(|l| l.starts_with('{'))
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .filter(|e| e["event"] == event)
+        .map(|e| e["bytes"].as_i64().unwrap())
+        .sum()
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn progress_total_noclobber_skip(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    for (name, len) in [("a.txt", 1000), ("b.txt", 2000), ("sub/c.txt", 3000), ("sub/d.txt", 4000)] {
+        create_file(&source_path.join(name), &"x".repeat(len)).unwrap();
+    }
+
+    // Half-populate the destination.
+    let dest_base = dir.path().join("dest");
+    create_dir_all(dest_base.join("sub")).unwrap();
+    create_file(&dest_base.join("a.txt"), "old a").unwrap();
+    create_file(&dest_base.join("sub/c.txt"), "old c").unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-r", "-v",
+        "--no-clobber",
+        "--progress", "json",
+        "--no-target-directory",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let scanned = event_bytes(&stdout, "size");
+    let adjusted = event_bytes(&stdout, "total_adjust");
+    let copied = event_bytes(&stdout, "copied");
+    assert_eq!(10_000, scanned);
+    assert_eq!(-4000, adjusted);
+    // The bar ends at 100%.
+    assert_eq!(scanned + adjusted, copied);
+    let started = stdout.lines().filter(|l| l.contains(r#""event":"file_started""#)).count();
+    assert_eq!(2, started);
+    assert!(stdout.contains("Skipped 2 existing destination files"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn progress_total_sparse(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("sparse.bin");
+    let dest_path = dir.path().join("dest.bin");
+    {
+        use std::os::unix::fs::FileExt;
+        let file = File::create(&source_path).unwrap();
+        file.set_len(4 * 1024 * 1024).unwrap();
+        file.write_all_at(&[1u8; 4096], 1024 * 1024).unwrap();
+    }
+
+    let out = run(&[
+        "--driver", drv,
+        "--progress", "json",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let total = event_bytes(&stdout, "size") + event_bytes(&stdout, "total_adjust");
+    assert_eq!(total, event_bytes(&stdout, "copied"));
+    assert!(files_match(&source_path, &dest_path));
+}
+
+// A file that takes several seconds to copy with '--block-size 1'.
+// The parblock driver would queue a job per byte, so these tests use
+// the file driver.
+fn slow_file(path: &Path) {
+    write(path, vec![1u8; 8 * 1024 * 1024]).unwrap();
+}
+
+#[test]
+fn timeout_stops_copy() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    slow_file(&source_path);
+
+    let out = run(&[
+        "--driver", "parfile",
+        "--block-size", "1",
+        "--reflink", "never",
+        "--timeout", "1",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ]).unwrap();
+
+    assert_eq!(Some(124), out.status.code());
+    assert!(!dest_path.exists());
+    assert!(String::from_utf8(out.stderr).unwrap().contains("Copy timed out after 1s"));
+}
+
+#[test]
+fn file_timeout_continue() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    slow_file(&source_path.join("slow.bin"));
+    create_file(&source_path.join("small.txt"), "data").unwrap();
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "--driver", "parfile",
+        "-r",
+        "--block-size", "1",
+        "--reflink", "never",
+        "--file-timeout", "1",
+        "--continue-on-error",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ]).unwrap();
+
+    assert_eq!(Some(1), out.status.code());
+    assert!(file_contains(&dest_base.join("small.txt"), "data").unwrap());
+    assert!(!dest_base.join("slow.bin").exists());
+    assert!(String::from_utf8(out.stderr).unwrap().contains("timed out after 1s"));
+}
+
+#[test_case("0"; "zero")]
+#[test_case("1w"; "unknown unit")]
+#[test_case("h"; "no number")]
+fn timeout_invalid(duration: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    create_file(&source_path, "data").unwrap();
+
+    let out = run(&[
+        "--timeout", duration,
+        source_path.to_str().unwrap(),
+        dir.path().join("dest.txt").to_str().unwrap(),
+    ]).unwrap();
+    assert_eq!(Some(2), out.status.code());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn readers_per_device_limited(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    for i in 0..20 {
+        create_file(&source_path.join(format!("sub/file{}.txt", i)), &format!("data {}", i)).unwrap();
+    }
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "--driver", drv,
+        "-r", "-w", "8",
+        "--readers-per-device", "1",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(out.status.success());
+    for i in 0..20 {
+        assert!(file_contains(&dest_base.join(format!("sub/file{}.txt", i)), &format!("data {}", i)).unwrap());
+    }
+
+    let out = run(&[
+        "--readers-per-device", "0",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ]).unwrap();
+    assert_eq!(Some(2), out.status.code());
+}
+
+// Path, mode, mtime, is-symlink, link count and xattr value.
+type Preserved = (PathBuf, u32, i64, bool, u64, Option<Vec<u8>>);
+
+// The metadata of each entry under `root` that --preserve may copy.
+fn preserved_metadata(root: &Path) -> Vec<Preserved> {
+    walkdir::WalkDir::new(root).sort_by_file_name().into_iter()
+        .map(|e| e.unwrap())
+        .filter(|e| e.depth() > 0)
+        .map(|e| {
+            let meta = e.path().symlink_metadata().unwrap();
+            let xattr = if e.file_type().is_file() && cfg!(not(feature = "test_no_xattr")) {
+                xattr::get(e.path(), "user.test").unwrap()
+            } else {
+                None
+            };
+            // Symlink timestamps aren't copied.
+            let mtime = if e.file_type().is_symlink() { 0 } else { meta.mtime() };
+            (e.path().strip_prefix(root).unwrap().to_path_buf(), meta.mode(), mtime,
+             e.file_type().is_symlink(), meta.nlink(), xattr)
+        })
+        .collect()
+}
+
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
+fn archive_equals_preserve_all(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    create_dir_all(source.join("sub")).unwrap();
+    let file = source.join("sub/file.txt");
+    create_file(&file, "data").unwrap();
+    #[cfg(not(feature = "test_no_xattr"))]
+    xattr::set(&file, "user.test", b"value").unwrap();
+    set_permissions(&file, Permissions::from_mode(0o640)).unwrap();
+    std::fs::hard_link(&file, source.join("link.txt")).unwrap();
+    symlink("sub/file.txt", source.join("symlink")).unwrap();
+    for path in [&file, &source.join("sub")] {
+        set_time_past(path).unwrap();
+    }
+
+    let archive = dir.path().join("archive");
+    let explicit = dir.path().join("explicit");
+    for (dest, args) in [(&archive, &["-a"][..]), (&explicit, &["--recursive", "--preserve=all", "--no-dereference"][..])] {
+        let mut args = args.to_vec();
+        args.extend(["--driver", drv, source.to_str().unwrap(), dest.to_str().unwrap()]);
+        let out = run(&args).unwrap();
+        assert!(out.status.success(), "{:?}: {}", args, String::from_utf8_lossy(&out.stderr));
+    }
+
+    let expected = preserved_metadata(&source);
+    assert_eq!(expected, preserved_metadata(&archive));
+    assert_eq!(expected, preserved_metadata(&explicit));
+}
+
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
+fn no_preserve_attributes(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    create_file(&source_path, "data").unwrap();
+    #[cfg(not(feature = "test_no_xattr"))]
+    xattr::set(&source_path, "user.test", b"value").unwrap();
+    set_permissions(&source_path, Permissions::from_mode(0o600)).unwrap();
+    set_time_past(&source_path).unwrap();
+    let source_meta = source_path.metadata().unwrap();
+
+    let copy = |name: &str, args: &[&str]| {
+        let dest_path = dir.path().join(name);
+        let mut args = args.to_vec();
+        args.extend(["--driver", drv, source_path.to_str().unwrap(), dest_path.to_str().unwrap()]);
+        let out = run_with_umask(0o022, &args).unwrap();
+        assert!(out.status.success(), "{:?}: {}", args, String::from_utf8_lossy(&out.stderr));
+        dest_path
+    };
+    let has_xattr = |path: &Path| cfg!(not(feature = "test_no_xattr"))
+        && xattr::get(path, "user.test").unwrap().is_some();
+
+    let dest = copy("no_mode", &["--no-preserve=mode"]);
+    assert_eq!(0o644, dest.metadata().unwrap().mode() & 0o7777);
+    assert_eq!(source_meta.mtime(), dest.metadata().unwrap().mtime());
+    assert_eq!(cfg!(not(feature = "test_no_xattr")), has_xattr(&dest));
+
+    let dest = copy("no_xattr", &["--preserve", "--no-preserve=xattr,timestamps"]);
+    assert_eq!(0o600, dest.metadata().unwrap().mode() & 0o7777);
+    assert_ne!(source_meta.mtime(), dest.metadata().unwrap().mtime());
+    assert!(!has_xattr(&dest));
+
+    let dest = copy("none", &["-a", "--no-preserve=all"]);
+    assert_eq!(0o644, dest.metadata().unwrap().mode() & 0o7777);
+    assert!(!has_xattr(&dest));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock", &[]; "Test with parallel block driver"))]
+#[test_case("parfile", &[]; "Test with parallel file driver")]
+#[test_case("parfile", &["--forbid-overwrite-newer"]; "Test with forbid overwrite newer")]
+#[test_case("parfile", &["--forbid-overwrite-newer", "--continue-on-error"]; "Test with forbid and continue on error")]
+#[test_case("parfile", &["--update"]; "Test with update")]
+fn overwrite_newer(drv: &str, args: &[&str]) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(&source).unwrap();
+    create_dir_all(&dest).unwrap();
+    // Only the first destination file is newer than its source.
+    for name in ["newer.txt", "older.txt"] {
+        create_file(&source.join(name), "source").unwrap();
+    }
+    set_time_past(&source.join("newer.txt")).unwrap();
+    create_file(&dest.join("newer.txt"), "dest").unwrap();
+    create_file(&dest.join("older.txt"), "dest").unwrap();
+    set_time_past(&dest.join("older.txt")).unwrap();
+
+    let mut args = args.to_vec();
+    args.extend(["--driver", drv, "-r", "-T", source.to_str().unwrap(), dest.to_str().unwrap()]);
+    let out = run(&args).unwrap();
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let stderr = String::from_utf8(out.stderr).unwrap();
+
+    let forbid = args.contains(&"--forbid-overwrite-newer");
+    let update = args.contains(&"--update");
+    assert_eq!(!forbid, out.status.success(), "{}", stderr);
+    assert_eq!(forbid || update, file_contains(&dest.join("newer.txt"), "dest").unwrap());
+    assert!(file_contains(&dest.join("older.txt"), "source").unwrap()
+            || (forbid && !args.contains(&"--continue-on-error")));
+
+    let warned = !forbid && !update;
+    assert_eq!(warned, stdout.contains("which is newer than the source"), "{}", stdout);
+    assert_eq!(warned, stdout.contains("Overwrote 1 destination files that were newer than the source"));
+    assert_eq!(forbid, stderr.contains("is newer than the source; not overwriting"), "{}", stderr);
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_xattr", ignore = "No FS support")]
+fn dir_and_symlink_xattrs(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    create_dir_all(source.join("sub")).unwrap();
+    xattr::set(source.join("sub"), "user.test", b"dir").unwrap();
+    let link = source.join("link");
+    symlink("sub", &link).unwrap();
+    // Linux refuses user xattrs on symlinks, but trusted ones can be
+    // set as root.
+    let link_attr = ["user.test", "trusted.test"].into_iter()
+        .find(|name| xattr::set(&link, name, b"link").is_ok());
+
+    for (dest, args) in [("dest", &[][..]), ("no_xattr", &["--no-preserve=xattr"][..])] {
+        let dest = dir.path().join(dest);
+        let mut args = args.to_vec();
+        args.extend(["--driver", drv, "-r", source.to_str().unwrap(), dest.to_str().unwrap()]);
+        let out = run(&args).unwrap();
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        let preserved = args[0] != "--no-preserve=xattr";
+
+        assert!(dest.join("link").is_symlink());
+        let value = xattr::get(dest.join("sub"), "user.test").unwrap();
+        assert_eq!(preserved.then(|| b"dir".to_vec()), value);
+        match link_attr {
+            Some(name) => {
+                let value = xattr::get(dest.join("link"), name).unwrap();
+                assert_eq!(preserved.then(|| b"link".to_vec()), value);
+                // Not copied through the link to its target.
+                assert_ne!(Some(b"link".to_vec()), xattr::get(dest.join("sub"), name).unwrap());
+            }
+            None => println!("Symlink xattrs not supported; skipping symlink check"),
+        }
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn max_memory_limits_buffers(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(&source).unwrap();
+    for i in 0..4 {
+        write(source.join(format!("file{}.bin", i)), rand_data(300 * 1024)).unwrap();
+    }
+
+    let out = run(&[
+        "--driver", drv, "-r", "--workers", "4", "--block-size", "1M", "--max-memory", "128K",
+        source.to_str().unwrap(), dest.to_str().unwrap(),
+    ]).unwrap();
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(out.status.success());
+    assert!(stdout.contains("reduces the block size from 1048576 to 65536 bytes"), "{}", stdout);
+    assert!(stdout.contains("reduces the number of workers from 4 to 2"), "{}", stdout);
+    compare_trees(&source, &dest).unwrap();
+
+    let out = run(&["--max-memory", "0", source.to_str().unwrap(), dest.to_str().unwrap()]).unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr).unwrap().contains("must be greater than 0"));
+}
+
+#[test_case(&[]; "Test fan-out")]
+#[test_case(&["--progress=json"]; "Test fan-out with JSON progress")]
+fn fanout_copies_to_each_destination(args: &[&str]) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    create_dir_all(source.join("sub")).unwrap();
+    create_file(&source.join("small.txt"), "small").unwrap();
+    write(source.join("sub/big.bin"), rand_data(1024 * 1024)).unwrap();
+    symlink("small.txt", source.join("link")).unwrap();
+    // The first destination exists, so the source is copied into it.
+    let dests = [dir.path().join("dest1"), dir.path().join("dest2"), dir.path().join("dest3")];
+    create_dir_all(&dests[0]).unwrap();
+
+    let mut cmd = vec!["--fanout", "-r", "--block-size", "64K", source.to_str().unwrap()];
+    cmd.extend(dests.iter().map(|d| d.to_str().unwrap()));
+    cmd.extend(args);
+    let out = run(&cmd).unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    compare_trees(&source, &dests[0].join("source")).unwrap();
+    for dest in &dests[1..] {
+        compare_trees(&source, dest).unwrap();
+    }
+    if args.contains(&"--progress=json") {
+        let stdout = String::from_utf8(out.stdout).unwrap();
+        for dest in 0..dests.len() {
+            assert!(stdout.contains(&format!("{{\"event\":\"dest_copied\",\"dest\":{}", dest)), "{}", stdout);
+        }
+    }
+}
+
+#[test_case(&[]; "Test fan-out with a failing destination")]
+#[test_case(&["--continue-on-error"]; "Test fan-out with a failing destination and continue on error")]
+fn fanout_failed_destination(args: &[&str]) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    create_dir_all(&source).unwrap();
+    for i in 0..8 {
+        create_file(&source.join(format!("file{}.txt", i)), "data").unwrap();
+    }
+    // A directory in the way of one file in the second destination,
+    // which the source is copied into.
+    let good = dir.path().join("good");
+    let bad = dir.path().join("bad");
+    create_dir_all(bad.join("source/file3.txt/sub")).unwrap();
+
+    let mut cmd = vec!["--fanout", "-r", source.to_str().unwrap(), good.to_str().unwrap(), bad.to_str().unwrap()];
+    cmd.extend(args);
+    let out = run(&cmd).unwrap();
+    let stderr = String::from_utf8(out.stderr).unwrap();
+
+    assert!(!out.status.success());
+    assert!(stderr.contains("1 of 2 destinations incomplete"), "{}", stderr);
+    compare_trees(&source, &good).unwrap();
+    // Only the failed file is missing when continuing.
+    let copied = (0..8).filter(|i| bad.join(format!("source/file{}.txt", i)).is_file()).count();
+    if args.contains(&"--continue-on-error") {
+        assert_eq!(7, copied);
+    } else {
+        assert!(copied < 8);
+    }
+}
+
+#[test_case(&["--fanout", "source"]; "Test fan-out without a destination")]
+#[test_case(&["--fanout", "--target-directory", "dest", "source", "dest2"]; "Test fan-out with target directory")]
+#[test_case(&["--fanout", "--dry-run", "source", "dest1", "dest2"]; "Test fan-out with dry run")]
+#[test_case(&["--fanout", "--delete", "source", "dest1", "dest2"]; "Test fan-out with delete")]
+fn fanout_bad_args(args: &[&str]) {
+    let out = run(args).unwrap();
+    assert_eq!(Some(2), out.status.code());
+}
+
+#[test]
+fn fanout_into_source() {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    create_dir_all(&source).unwrap();
+    create_file(&source.join("file.txt"), "data").unwrap();
+    let inside = source.join("copy");
+
+    let out = run(&["--fanout", "-r", source.to_str().unwrap(), dir.path().join("dest").to_str().unwrap(),
+                    inside.to_str().unwrap()]).unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr).unwrap().contains("Cannot copy a directory into itself"));
+    assert!(!inside.exists());
+}
+
+#[test_case(&["-r", "{src}", "{dest}"]; "recursive")]
+#[test_case(&["-rn", "{src}", "{dest}"]; "no clobber")]
+#[test_case(&["-ri", "{src}", "{dest}"]; "interactive declined")]
+#[test_case(&["-rf", "{src}", "{dest}"]; "force")]
+#[test_case(&["-ru", "{src}", "{dest}"]; "update")]
+#[test_case(&["-a", "{src}", "{dest}"]; "archive")]
+#[test_case(&["-rp", "--preserve=timestamps", "{src}", "{dest}"]; "preserve")]
+#[test_case(&["-rL", "{src}", "{dest}"]; "dereference")]
+#[test_case(&["-rP", "{src}", "{dest}"]; "no dereference")]
+#[test_case(&["-rT", "{src}", "{dest}"]; "no target directory")]
+#[test_case(&["-rt", "{dest}", "{src}"]; "target directory")]
+#[test_case(&["-rx", "{src}", "{dest}"]; "one file system")]
+#[test_case(&["-r", "--reflink=auto", "{src}", "{dest}"]; "reflink")]
+#[test_case(&["-r", "--sparse=always", "{src}", "{dest}"]; "sparse")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn cp_compatible_options(args: &[&str]) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    create_dir_all(source.join("sub")).unwrap();
+    create_file(&source.join("a.txt"), "new a").unwrap();
+    create_file(&source.join("sub/b.txt"), "new b").unwrap();
+    symlink("a.txt", source.join("link")).unwrap();
+    // Older than the existing destination, for '-u'.
+    set_time_past(&source.join("a.txt")).unwrap();
+
+    let mut dests = Vec::new();
+    for tool in ["cp", "xcp"] {
+        let dest = dir.path().join(tool);
+        create_dir_all(dest.join("source")).unwrap();
+        create_file(&dest.join("source/a.txt"), "old a").unwrap();
+        let args = args.iter()
+            .map(|a| a.replace("{src}", source.to_str().unwrap()).replace("{dest}", dest.to_str().unwrap()))
+            .collect::<Vec<_>>();
+        let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+        let out = if tool == "cp" {
+            std::process::Command::new("cp").args(&args).output().unwrap()
+        } else {
+            run(&args).unwrap()
+        };
+        assert!(out.status.success(), "{} {:?}: {}", tool, args, String::from_utf8_lossy(&out.stderr));
+        dests.push(dest);
+    }
+    compare_trees(&dests[0], &dests[1]).unwrap();
+    compare_trees(&dests[1], &dests[0]).unwrap();
+}
+
+#[test_case(&["-rb"]; "short")]
+#[test_case(&["-r", "--backup"]; "bare long")]
+#[test_case(&["--backup", "-r"]; "bare long before option")]
+#[test_case(&["-r", "--backup=existing"]; "existing")]
+#[test_case(&["-r", "--backup=simple"]; "simple")]
+fn cp_backup_options(args: &[&str]) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source.txt");
+    let dest = dir.path().join("dest");
+    create_dir_all(&dest).unwrap();
+    create_file(&source, "new").unwrap();
+    create_file(&dest.join("source.txt"), "old").unwrap();
+
+    let mut args = args.to_vec();
+    args.extend([source.to_str().unwrap(), dest.to_str().unwrap()]);
+    let out = run(&args).unwrap();
+
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(file_contains(&dest.join("source.txt"), "new").unwrap());
+    assert!(file_contains(&dest.join("source.txt.~1~"), "old").unwrap());
+}
+
+#[test]
+fn cp_optional_values() {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("file.txt");
+    let dest = dir.path().join("dest.txt");
+    create_file(&source, "new").unwrap();
+    create_file(&dest, "old").unwrap();
+
+    // The source is not taken as the value of '--backup'.
+    let out = run(&["--backup", source.to_str().unwrap(), dest.to_str().unwrap()]).unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(file_contains(&dest, "new").unwrap());
+    assert!(file_contains(&dir.path().join("dest.txt.~1~"), "old").unwrap());
+
+    let out = run(&["--sparse=never", source.to_str().unwrap(), dest.to_str().unwrap()]).unwrap();
+    assert_eq!(Some(2), out.status.code());
+    assert!(String::from_utf8(out.stderr).unwrap().contains("--sparse=never is not supported"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_network_profile(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    for (i, size) in [100, 300_000, 0, 5000, 9_000_000].iter().enumerate() {
+        let sub = source.join(format!("dir{}", i % 2));
+        create_dir_all(&sub).unwrap();
+        write(sub.join(format!("file{}.bin", i)), rand_data(*size)).unwrap();
+    }
+    symlink("dir0/file0.bin", source.join("link")).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "-w", "8",
+        "--profile=network",
+        "--batch-dirents",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    compare_trees(&source, &dest).unwrap();
+
+    let out = run(&["--profile=remote", source.to_str().unwrap(), dest.to_str().unwrap()]).unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr).unwrap().contains("remote"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn skip_same_refreshes_metadata(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(&source).unwrap();
+    create_dir_all(&dest).unwrap();
+    create_file(&source.join("file.txt"), "same").unwrap();
+    create_file(&dest.join("file.txt"), "same").unwrap();
+    set_time_past(&source.join("file.txt")).unwrap();
+    set_permissions(source.join("file.txt"), Permissions::from_mode(0o640)).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-r", "-T",
+        "--skip-same=checksum",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    // The contents aren't copied, but the attributes are.
+    let (from, to) = (source.join("file.txt").metadata().unwrap(), dest.join("file.txt").metadata().unwrap());
+    assert_eq!((from.mtime(), from.mtime_nsec()), (to.mtime(), to.mtime_nsec()));
+    if !cfg!(feature = "test_no_perms") {
+        assert_eq!(0o640, to.mode() & 0o777);
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn max_errors(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    for i in 0..5 {
+        let sub = source_path.join(format!("dir{}", i));
+        create_dir_all(&sub).unwrap();
+        create_file(&sub.join("file.txt"), "data").unwrap();
+        // Each is an error with --dereference.
+        symlink("..", sub.join("loop")).unwrap();
+    }
+
+    let copy = |extra: &[&str]| run(&[&[
+        "--driver", drv,
+        "--recursive",
+        "--dereference",
+        "--continue-on-error",
+        source_path.to_str().unwrap(),
+        dir.path().join("dest").to_str().unwrap(),
+    ], extra].concat()).unwrap();
+
+    let out = copy(&["--max-errors", "2"]);
+    assert_eq!(Some(3), out.status.code());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Copy stopped after 2 errors"), "{}", stderr);
+    assert!(stderr.contains("Symlink loop"));
+
+    let out = copy(&["--max-error-rate", "1"]);
+    assert_eq!(Some(3), out.status.code());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Copy stopped after 2 errors in the last"), "{}", stderr);
+
+    // Under the limit the copy completes, with the usual failure.
+    let out = copy(&["--max-errors", "6"]);
+    assert_eq!(Some(1), out.status.code());
+
+    let out = run(&["--max-errors", "2", source_path.to_str().unwrap(), dir.path().join("other").to_str().unwrap()]).unwrap();
+    assert_eq!(Some(2), out.status.code());
+    assert!(String::from_utf8(out.stderr).unwrap().contains("--continue-on-error"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn exclude_patterns(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let sources = [dir.path().join("one"), dir.path().join("two")];
+    for source in &sources {
+        create_dir_all(source.join("build")).unwrap();
+        create_dir_all(source.join("src/build")).unwrap();
+        create_file(&source.join("build/out.txt"), "out").unwrap();
+        create_file(&source.join("src/build/out.txt"), "out").unwrap();
+        create_file(&source.join("src/main.o"), "obj").unwrap();
+        create_file(&source.join("src/keep.o"), "obj").unwrap();
+        create_file(&source.join("top.log"), "log").unwrap();
+        create_file(&source.join("src/nested.log"), "log").unwrap();
+        // A file named like the directory pattern.
+        create_file(&source.join("src/build.txt"), "file").unwrap();
+    }
+    let patterns = dir.path().join("excludes");
+    create_file(&patterns, "# Objects\n*.o\n").unwrap();
+
+    let dest = dir.path().join("dest");
+    create_dir_all(dest.join("one/build")).unwrap();
+    create_file(&dest.join("one/build/old.txt"), "old").unwrap();
+    create_file(&dest.join("one/stale.txt"), "stale").unwrap();
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--delete",
+        "--exclude", "/build/",
+        "--exclude", "/top.log",
+        "--exclude-from", patterns.to_str().unwrap(),
+        "--include", "keep.o",
+        sources[0].to_str().unwrap(),
+        sources[1].to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    for name in ["one", "two"] {
+        let root = dest.join(name);
+        // Anchored to each source root.
+        assert!(!root.join("build/out.txt").exists());
+        assert!(!root.join("top.log").exists());
+        assert!(file_contains(&root.join("src/build/out.txt"), "out").unwrap());
+        assert!(file_contains(&root.join("src/nested.log"), "log").unwrap());
+        assert!(!root.join("src/main.o").exists());
+        assert!(file_contains(&root.join("src/keep.o"), "obj").unwrap());
+        assert!(file_contains(&root.join("src/build.txt"), "file").unwrap());
+    }
+    // Excluded destination entries are kept by --delete.
+    assert!(file_contains(&dest.join("one/build/old.txt"), "old").unwrap());
+    assert!(!dest.join("one/stale.txt").exists());
+
+    let out = run(&["--exclude", "[a", sources[0].to_str().unwrap(), dest.to_str().unwrap()]).unwrap();
+    assert_eq!(Some(2), out.status.code());
+    assert!(String::from_utf8(out.stderr).unwrap().contains("Invalid pattern \"[a\""));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn json_error_codes(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("a.txt"), "a").unwrap();
+    create_file(&source_path.join("b.txt"), "b").unwrap();
+    // A file can't be copied over a directory.
+    let dest_path = dir.path().join("dest");
+    create_dir_all(dest_path.join("source/a.txt")).unwrap();
+
+    let parse = |out: &std::process::Output| String::from_utf8_lossy(&out.stdout).lines()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .collect::<Vec<serde_json::Value>>();
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--continue-on-error",
+        "--progress=json",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert_eq!(Some(1), out.status.code());
+    let events = parse(&out);
+    let errors = events.iter().filter(|e| e["event"] == "error").collect::<Vec<_>>();
+    assert_eq!(1, errors.len());
+    assert_eq!("copy_failed", errors[0]["code"]);
+    assert_eq!("is_a_directory", errors[0]["sub_code"]);
+    assert!(errors[0]["source"].as_str().unwrap().ends_with("a.txt"));
+    let summary = events.last().unwrap();
+    assert_eq!("summary", summary["event"]);
+    assert_eq!(false, summary["success"]);
+    assert_eq!(1, summary["exit_code"]);
+    assert_eq!("copy_error", summary["code"]);
+    assert!(file_contains(&dest_path.join("source/b.txt"), "b").unwrap());
+
+    // Errors before the copy starts only have a summary.
+    let missing = dir.path().join("missing");
+    let out = run(&["--progress=json", missing.to_str().unwrap(), dest_path.to_str().unwrap()]).unwrap();
+    let events = parse(&out);
+    assert_eq!(1, events.len());
+    assert_eq!("source_not_found", events[0]["code"]);
+    assert_eq!(false, events[0]["success"]);
+
+    let out = run(&["--progress=json", "--force", "--no-clobber",
+                    source_path.to_str().unwrap(), dest_path.to_str().unwrap()]).unwrap();
+    assert_eq!(Some(2), out.status.code());
+    let summary = parse(&out).pop().unwrap();
+    assert_eq!(2, summary["exit_code"]);
+    assert_eq!("conflicting_options", summary["code"]);
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_with_glob_braces(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(&dest).unwrap();
+    for file in ["a.txt", "b.txt", "c.txt", "sub/d.txt", "sub/e.rs", "x[1].txt"] {
+        create_dir_all(source.join(file).parent().unwrap()).unwrap();
+        create_file(&source.join(file), file).unwrap();
+    }
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--glob",
+        source.join("{a,s*/{d,e}}.{txt,rs}").to_str().unwrap(),
+        source.join("x\\[1\\].txt").to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    for file in ["a.txt", "d.txt", "e.rs", "x[1].txt"] {
+        assert!(dest.join(file).exists(), "{}", file);
+    }
+    assert!(!dest.join("b.txt").exists());
+    assert!(!dest.join("c.txt").exists());
+
+    // The same syntax excludes.
+    let excluded = dir.path().join("excluded");
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--exclude",
+        "{a,sub/{d,e}}.{txt,rs}",
+        "--exclude",
+        "{x\\[1\\]}.txt",
+        source.to_str().unwrap(),
+        excluded.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(excluded.join("b.txt").exists());
+    assert!(excluded.join("c.txt").exists());
+    assert!(excluded.join("sub").is_dir());
+    for file in ["a.txt", "sub/d.txt", "sub/e.rs", "x[1].txt"] {
+        assert!(!excluded.join(file).exists(), "{}", file);
+    }
+
+    let out = run(&["--driver", drv, "-r", "--exclude", "{a,b", source.to_str().unwrap(), excluded.to_str().unwrap()])
+        .unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Invalid pattern \"{a,b\" at column 1: unclosed '{'"), "{}", stderr);
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(not(feature = "test_faults"), ignore = "Needs the test_faults feature")]
+fn metadata_failure_fails_copy(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("a.txt"), "a").unwrap();
+    create_file(&source_path.join("b.txt"), "b").unwrap();
+    let dest_path = dir.path().join("dest");
+    // EIO setting the metadata of b.txt.
+    let faults = format!("SetMetadata:5:{}", dest_path.join("b.txt").display());
+
+    let out = run_with_faults(&faults, &[
+        "--driver", drv,
+        "-r",
+        "--continue-on-error",
+        "--progress=json",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert_eq!(Some(1), out.status.code());
+    let events = String::from_utf8_lossy(&out.stdout).lines()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .collect::<Vec<_>>();
+    let errors = events.iter().filter(|e| e["event"] == "error").collect::<Vec<_>>();
+    assert_eq!(1, errors.len());
+    assert_eq!("copy_failed", errors[0]["code"]);
+    assert!(errors[0]["dest"].as_str().unwrap().ends_with("b.txt"), "{:?}", errors[0]);
+    assert_eq!(false, events.last().unwrap()["success"]);
+    assert!(file_contains(&dest_path.join("a.txt"), "a").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(not(feature = "test_faults"), ignore = "Needs the test_faults feature")]
+fn in_place_truncate_failure_fails_copy(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    write(&source_path, vec![1; 10_000]).unwrap();
+    // Long enough to be overwritten in place.
+    let dest_path = dir.path().join("dest.bin");
+    write(&dest_path, vec![2; 15_000]).unwrap();
+    // EIO truncating the old tail.
+    let faults = format!("Truncate:5:{}", dest_path.display());
+
+    let out = run_with_faults(&faults, &[
+        "--driver", drv,
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert_eq!(Some(1), out.status.code());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Input/output error"), "{}", stderr);
+    assert_eq!(15_000, dest_path.metadata().unwrap().len());
+}
@@ -0,0 +1,153 @@
+#![allow(unused)]
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Filesystems to run integration tests against.
+//!
+//! [FsUnderTest] provides a directory on a given [Fs]. Apart from
+//! [Fs::Host], the filesystem the tests are built on, each is a
+//! loopback image created and mounted for the test, which requires
+//! root and the matching `mkfs`. If either is missing the fixture is
+//! not created and the test should return early. Tests assert against
+//! the filesystem's [Caps], so the same test body checks both that a
+//! feature works where it is supported and that it falls back
+//! gracefully where it isn't.
+
+use std::fs::{create_dir, File};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use tempfile::TempDir;
+
+use crate::util::tempdir_rel;
+
+/// Size of the loopback images. They are sparse, so this only needs
+/// to be above the minimum for each filesystem.
+const IMAGE_SIZE: u64 = 512 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fs {
+    /// The filesystem containing the build directory; its
+    /// capabilities are given by the `test_no_*` features.
+    Host,
+    Ext4,
+    Xfs,
+    Btrfs,
+    Vfat,
+}
+
+/// The features a filesystem is expected to support.
+#[derive(Clone, Copy, Debug)]
+pub struct Caps {
+    pub reflink: bool,
+    pub sparse: bool,
+    pub xattr: bool,
+    pub perms: bool,
+    pub symlinks: bool,
+}
+
+impl Fs {
+    pub fn caps(self) -> Caps {
+        match self {
+            Fs::Host => Caps {
+                reflink: cfg!(not(feature = "test_no_reflink")),
+                sparse: cfg!(not(feature = "test_no_sparse")),
+                xattr: cfg!(not(feature = "test_no_xattr")),
+                perms: cfg!(not(feature = "test_no_perms")),
+                symlinks: cfg!(not(feature = "test_no_symlinks")),
+            },
+            Fs::Ext4 => Caps { reflink: false, sparse: true, xattr: true, perms: true, symlinks: true },
+            Fs::Xfs | Fs::Btrfs => Caps { reflink: true, sparse: true, xattr: true, perms: true, symlinks: true },
+            Fs::Vfat => Caps { reflink: false, sparse: false, xattr: false, perms: false, symlinks: false },
+        }
+    }
+
+    // The mkfs command and arguments, without the image path.
+    fn mkfs(self) -> Option<(&'static str, &'static [&'static str])> {
+        match self {
+            Fs::Host => None,
+            Fs::Ext4 => Some(("mkfs.ext4", &["-q", "-F"])),
+            Fs::Xfs => Some(("mkfs.xfs", &["-q", "-m", "reflink=1"])),
+            Fs::Btrfs => Some(("mkfs.btrfs", &["-q"])),
+            Fs::Vfat => Some(("mkfs.vfat", &[])),
+        }
+    }
+
+    fn mount_options(self) -> &'static str {
+        match self {
+            // Permission changes are otherwise an error.
+            Fs::Vfat => "loop,quiet",
+            _ => "loop",
+        }
+    }
+}
+
+/// A directory on a filesystem under test. Loopback mounts are
+/// unmounted on drop.
+pub struct FsUnderTest {
+    pub fs: Fs,
+    dir: TempDir,
+    mnt: Option<PathBuf>,
+}
+
+impl FsUnderTest {
+    /// Create the filesystem, or return `None` if that isn't possible
+    /// here; the reason is printed.
+    pub fn new(fs: Fs) -> Option<FsUnderTest> {
+        let dir = tempdir_rel().ok()?;
+        let Some((mkfs, args)) = fs.mkfs() else {
+            return Some(FsUnderTest { fs, dir, mnt: None });
+        };
+
+        let img = dir.path().join("fs.img");
+        let mnt = dir.path().join("mnt");
+        File::create(&img).ok()?.set_len(IMAGE_SIZE).ok()?;
+        create_dir(&mnt).ok()?;
+
+        let made = Command::new(mkfs).args(args).arg(&img).output();
+        if !made.as_ref().is_ok_and(|out| out.status.success()) {
+            eprintln!("Cannot create {:?} with {}, skipping: {:?}", fs, mkfs, made);
+            return None;
+        }
+        let mounted = Command::new("mount")
+            .args(["-o", fs.mount_options()])
+            .arg(&img)
+            .arg(&mnt)
+            .output();
+        if !mounted.as_ref().is_ok_and(|out| out.status.success()) {
+            eprintln!("Cannot mount {:?} (requires root), skipping: {:?}", fs, mounted);
+            return None;
+        }
+        Some(FsUnderTest { fs, dir, mnt: Some(mnt) })
+    }
+
+    /// A writable directory on the filesystem.
+    pub fn path(&self) -> &Path {
+        self.mnt.as_deref().unwrap_or(self.dir.path())
+    }
+
+    pub fn caps(&self) -> Caps {
+        self.fs.caps()
+    }
+}
+
+impl Drop for FsUnderTest {
+    fn drop(&mut self) {
+        if let Some(ref mnt) = self.mnt {
+            let _ = Command::new("umount").arg(mnt).output();
+        }
+    }
+}
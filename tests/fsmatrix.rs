@@ -0,0 +1,368 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Filesystem-specific behaviour, run against each filesystem that can
+//! be created here; see [fsfixture].
+
+mod fsfixture;
+mod util;
+
+#[cfg(all(target_os = "linux", feature = "use_linux"))]
+mod test {
+    use std::fs::{set_permissions, write, Permissions};
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    use test_case::test_matrix;
+
+    use crate::fsfixture::{Fs, FsUnderTest};
+    use crate::util::*;
+
+    #[test_matrix([Fs::Host, Fs::Ext4, Fs::Xfs, Fs::Btrfs, Fs::Vfat], ["parfile", "parblock"])]
+    fn reflink_always(fs: Fs, drv: &str) {
+        let Some(fut) = FsUnderTest::new(fs) else { return };
+        let source_path = fut.path().join("source.bin");
+        let dest_path = fut.path().join("dest.bin");
+        write(&source_path, rand_data(128 * 1024)).unwrap();
+
+        let out = run(&[
+            "--driver", drv,
+            "--reflink=always",
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+        .unwrap();
+
+        assert_eq!(fut.caps().reflink, out.status.success());
+        if fut.caps().reflink {
+            assert!(files_match(&source_path, &dest_path));
+        } else {
+            let stderr = String::from_utf8(out.stderr).unwrap();
+            assert!(stderr.contains("Failed to reflink file"));
+        }
+    }
+
+    #[test_matrix([Fs::Host, Fs::Ext4, Fs::Xfs, Fs::Btrfs, Fs::Vfat], ["parfile", "parblock"])]
+    fn reflink_auto(fs: Fs, drv: &str) {
+        let Some(fut) = FsUnderTest::new(fs) else { return };
+        let source_path = fut.path().join("source.bin");
+        let dest_path = fut.path().join("dest.bin");
+        write(&source_path, rand_data(128 * 1024)).unwrap();
+
+        let out = run(&[
+            "--driver", drv,
+            "--reflink=auto",
+            "-v",
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+        .unwrap();
+
+        // Falls back to a copy where unsupported.
+        assert!(out.status.success());
+        assert!(files_match(&source_path, &dest_path));
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert_eq!(fut.caps().reflink, stderr.contains("Reflinked 1 of 1 files"));
+    }
+
+    #[test_matrix([Fs::Host, Fs::Ext4, Fs::Xfs, Fs::Btrfs, Fs::Vfat], ["parfile", "parblock"])]
+    fn sparse_copy(fs: Fs, drv: &str) {
+        let Some(fut) = FsUnderTest::new(fs) else { return };
+        let source_path = fut.path().join("sparse.bin");
+        let dest_path = fut.path().join("dest.bin");
+        create_sparse(&source_path, 0, 0).unwrap();
+        assert_eq!(fut.caps().sparse, probably_sparse(&source_path).unwrap());
+
+        let out = run(&[
+            "--driver", drv,
+            "--reflink=never",
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+        .unwrap();
+
+        assert!(out.status.success());
+        assert!(files_match(&source_path, &dest_path));
+        assert_eq!(fut.caps().sparse, probably_sparse(&dest_path).unwrap());
+    }
+
+    #[test_matrix([Fs::Host, Fs::Ext4, Fs::Xfs, Fs::Btrfs, Fs::Vfat])]
+    fn xattr_copy(fs: Fs) {
+        let Some(fut) = FsUnderTest::new(fs) else { return };
+        let source_path = fut.path().join("source.txt");
+        let dest_path = fut.path().join("dest.txt");
+        create_file(&source_path, "xattrs").unwrap();
+
+        let set = xattr::set(&source_path, "user.test", b"my test");
+        assert_eq!(fut.caps().xattr, set.is_ok());
+
+        let out = run(&[source_path.to_str().unwrap(), dest_path.to_str().unwrap()]).unwrap();
+
+        assert!(out.status.success());
+        assert!(file_contains(&dest_path, "xattrs").unwrap());
+        if fut.caps().xattr {
+            assert_eq!(Some(b"my test".to_vec()), xattr::get(&dest_path, "user.test").unwrap());
+        }
+    }
+
+    #[test_matrix([Fs::Host, Fs::Ext4, Fs::Xfs, Fs::Btrfs, Fs::Vfat])]
+    fn perms_copy(fs: Fs) {
+        // As elsewhere, this only disables the test; it may work.
+        if fs == Fs::Host && cfg!(feature = "test_no_perms") {
+            return;
+        }
+        let Some(fut) = FsUnderTest::new(fs) else { return };
+        let source_path = fut.path().join("source.txt");
+        let dest_path = fut.path().join("dest.txt");
+        create_file(&source_path, "perms").unwrap();
+        set_permissions(&source_path, Permissions::from_mode(0o640)).unwrap();
+
+        let out = run(&[source_path.to_str().unwrap(), dest_path.to_str().unwrap()]).unwrap();
+
+        assert!(out.status.success());
+        let mode = dest_path.metadata().unwrap().permissions().mode() & 0o777;
+        assert_eq!(fut.caps().perms, mode == 0o640);
+    }
+
+    #[test_matrix([Fs::Host, Fs::Ext4, Fs::Xfs, Fs::Btrfs, Fs::Vfat])]
+    fn symlink_copy(fs: Fs) {
+        let Some(fut) = FsUnderTest::new(fs) else { return };
+        let source_path = fut.path().join("mydir");
+        let dest_path = fut.path().join("dest");
+        std::fs::create_dir(&source_path).unwrap();
+        create_file(&source_path.join("file.txt"), "target").unwrap();
+
+        let linked = symlink("file.txt", source_path.join("link"));
+        assert_eq!(fut.caps().symlinks, linked.is_ok());
+
+        let out = run(&["-r", source_path.to_str().unwrap(), dest_path.to_str().unwrap()]).unwrap();
+
+        assert!(out.status.success());
+        assert!(file_contains(&dest_path.join("file.txt"), "target").unwrap());
+        if fut.caps().symlinks {
+            assert!(dest_path.join("link").is_symlink());
+            assert!(file_contains(&dest_path.join("link"), "target").unwrap());
+        }
+    }
+
+    // Each copy method ends with the same metadata stage, so the
+    // destinations are indistinguishable by stat.
+    #[test_matrix([Fs::Host, Fs::Ext4, Fs::Xfs, Fs::Btrfs, Fs::Vfat], ["parfile", "parblock"])]
+    fn metadata_by_method(fs: Fs, drv: &str) {
+        use std::os::unix::fs::MetadataExt;
+
+        let Some(fut) = FsUnderTest::new(fs) else { return };
+        let caps = fut.caps();
+        let source_path = fut.path().join("source");
+        std::fs::create_dir(&source_path).unwrap();
+        let names = ["data.bin", "tiny.txt", "empty.txt"];
+        write(source_path.join("data.bin"), rand_data(128 * 1024)).unwrap();
+        create_file(&source_path.join("tiny.txt"), "tiny").unwrap();
+        create_file(&source_path.join("empty.txt"), "").unwrap();
+        for name in names {
+            let file = source_path.join(name);
+            if caps.perms {
+                set_permissions(&file, Permissions::from_mode(0o640)).unwrap();
+            }
+            if caps.xattr {
+                xattr::set(&file, "user.test", name.as_bytes()).unwrap();
+            }
+            set_time_past(&file).unwrap();
+        }
+
+        let manifest = fut.path().join("manifest.txt");
+        let mut methods = vec![
+            ("kernel", vec!["--reflink=never"]),
+            ("userspace", vec!["--reflink=never", "--manifest", manifest.to_str().unwrap()]),
+            ("no-fastpath", vec!["--reflink=never", "--no-tiny-file-fastpath"]),
+        ];
+        if caps.reflink {
+            methods.push(("reflink", vec!["--reflink=always"]));
+        }
+
+        let stat = |file: &std::path::Path| {
+            let meta = file.metadata().unwrap();
+            (meta.len(), meta.mode(), meta.mtime(), meta.mtime_nsec())
+        };
+        for (method, args) in methods {
+            let dest_path = fut.path().join(method);
+            let out = run(&[&["--driver", drv, "-r"], args.as_slice(), &[
+                source_path.to_str().unwrap(),
+                dest_path.to_str().unwrap(),
+            ]].concat()).unwrap();
+            assert!(out.status.success(), "{}", method);
+
+            for name in names {
+                let (from, to) = (source_path.join(name), dest_path.join(name));
+                assert!(files_match(&from, &to));
+                assert_eq!(stat(&from), stat(&to), "{} copied by {}", name, method);
+                if caps.xattr {
+                    assert_eq!(Some(name.as_bytes().to_vec()), xattr::get(&to, "user.test").unwrap());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn staging_cross_fs() {
+        let Some(fut) = FsUnderTest::new(Fs::Ext4) else { return };
+        let dir = tempdir_rel().unwrap();
+        let source_path = dir.path().join("source.txt");
+        let dest_path = dir.path().join("dest.txt");
+        create_file(&source_path, "staged").unwrap();
+        set_permissions(&source_path, Permissions::from_mode(0o640)).unwrap();
+
+        let out = run(&[
+            "--staging-dir", fut.path().to_str().unwrap(),
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+        .unwrap();
+
+        assert!(out.status.success());
+        assert!(file_contains(&dest_path, "staged").unwrap());
+        let mode = dest_path.metadata().unwrap().permissions().mode() & 0o777;
+        assert_eq!(0o640, mode);
+        let stdout = String::from_utf8(out.stdout).unwrap();
+        assert!(stdout.contains("on a different filesystem"));
+        let left = std::fs::read_dir(fut.path()).unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().starts_with(".xcp-staging-"))
+            .count();
+        assert_eq!(0, left);
+    }
+
+    #[cfg(feature = "btrfs")]
+    #[test_matrix(["parfile", "parblock"])]
+    fn preserve_subvolumes(drv: &str) {
+        use std::os::unix::fs::MetadataExt;
+        use std::process::Command;
+
+        let Some(fut) = FsUnderTest::new(Fs::Btrfs) else { return };
+        let source_path = fut.path().join("source");
+        std::fs::create_dir(&source_path).unwrap();
+        let made = Command::new("btrfs").args(["subvolume", "create"]).arg(source_path.join("sub")).output()
+            .is_ok_and(|out| out.status.success());
+        if !made {
+            eprintln!("Cannot create a btrfs subvolume, skipping");
+            return;
+        }
+        std::fs::create_dir(source_path.join("sub/plain")).unwrap();
+        create_file(&source_path.join("sub/plain/file.txt"), "subvolume data").unwrap();
+
+        // A subvolume root is always inode 256.
+        let is_subvolume = |p: &std::path::Path| p.metadata().unwrap().ino() == 256;
+        let dest_path = fut.path().join("dest");
+        let out = run(&[
+            "--driver", drv,
+            "-r", "--preserve-subvolumes",
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert!(out.status.success());
+        assert!(is_subvolume(&dest_path.join("sub")));
+        assert!(!is_subvolume(&dest_path.join("sub/plain")));
+        assert!(file_contains(&dest_path.join("sub/plain/file.txt"), "subvolume data").unwrap());
+
+        // Elsewhere the subvolume becomes a plain directory.
+        let Some(other) = FsUnderTest::new(Fs::Ext4) else { return };
+        let dest_path = other.path().join("dest");
+        let out = run(&[
+            "--driver", drv,
+            "-r", "--preserve-subvolumes",
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert!(out.status.success());
+        assert!(file_contains(&dest_path.join("sub/plain/file.txt"), "subvolume data").unwrap());
+        let stdout = String::from_utf8(out.stdout).unwrap();
+        assert!(stdout.contains("the destination is not btrfs"));
+    }
+    // Set or clear inode flags with chattr(1). Returns false where
+    // that isn't permitted; the immutable flag needs
+    // CAP_LINUX_IMMUTABLE.
+    fn chattr(flags: &str, path: &std::path::Path) -> bool {
+        std::process::Command::new("chattr").arg(flags).arg(path).output()
+            .is_ok_and(|out| out.status.success())
+    }
+
+    fn is_immutable(path: &std::path::Path) -> bool {
+        let out = std::process::Command::new("lsattr").arg("-d").arg(path).output().unwrap();
+        let out = String::from_utf8(out.stdout).unwrap();
+        out.split_whitespace().next().unwrap().contains('i')
+    }
+
+    #[test_matrix(["parfile", "parblock"])]
+    fn immutable_destinations(drv: &str) {
+        let Some(fut) = FsUnderTest::new(Fs::Ext4) else { return };
+        let source_path = fut.path().join("source");
+        std::fs::create_dir(&source_path).unwrap();
+        let locked = source_path.join("locked.txt");
+        create_file(&locked, "first").unwrap();
+        create_file(&source_path.join("plain.txt"), "plain").unwrap();
+        if !chattr("+i", &locked) {
+            eprintln!("Cannot set the immutable flag, skipping");
+            return;
+        }
+
+        let dest_path = fut.path().join("dest");
+        std::fs::create_dir(&dest_path).unwrap();
+        let copy = |extra: &[&str]| {
+            let mut args = vec!["--driver", drv, "-r", "-v"];
+            args.extend_from_slice(extra);
+            args.extend([source_path.to_str().unwrap(), dest_path.to_str().unwrap()]);
+            run(&args).unwrap()
+        };
+
+        // The flag is set on the copy once its data and metadata are.
+        let out = copy(&["--preserve=flags"]);
+        assert!(out.status.success());
+        let dest_locked = dest_path.join("source/locked.txt");
+        assert!(file_contains(&dest_locked, "first").unwrap());
+        assert!(is_immutable(&dest_locked));
+        assert!(!is_immutable(&dest_path.join("source/plain.txt")));
+
+        // Rewriting it fails with a targeted error unless the flags are
+        // being preserved, in which case they are cleared and set again.
+        assert!(chattr("-i", &locked));
+        write(&locked, "second").unwrap();
+        assert!(chattr("+i", &locked));
+        let out = copy(&[]);
+        assert!(!out.status.success());
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert!(stderr.contains("is immutable or append-only"), "{}", stderr);
+        assert!(stderr.contains("locked.txt"), "{}", stderr);
+        assert!(file_contains(&dest_locked, "first").unwrap());
+
+        let out = copy(&["--preserve=flags"]);
+        assert!(out.status.success());
+        assert!(file_contains(&dest_locked, "second").unwrap());
+        assert!(is_immutable(&dest_locked));
+        let stdout = String::from_utf8(out.stdout).unwrap();
+        assert!(stdout.contains("Clearing the immutable and append-only flags"), "{}", stdout);
+
+        // An immutable extraneous entry can't be deleted.
+        let extra = dest_path.join("source/extra.txt");
+        create_file(&extra, "extra").unwrap();
+        assert!(chattr("+i", &extra));
+        let out = copy(&["--preserve=flags", "--delete"]);
+        assert!(!out.status.success());
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert!(stderr.contains("extra.txt"), "{}", stderr);
+        assert!(stderr.contains("can't be removed"), "{}", stderr);
+        assert!(extra.exists());
+    }
+}
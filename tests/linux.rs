@@ -18,7 +18,7 @@ mod util;
 
 #[cfg(all(target_os = "linux", feature = "use_linux"))]
 mod test {
-    use std::{process::Command, fs::{File, OpenOptions}, io::SeekFrom};
+    use std::{process::Command, fs::{create_dir_all, File, OpenOptions}, io::SeekFrom};
     use std::io::{Seek, Write};
     use libfs::{map_extents, sync};
     use test_case::test_case;
@@ -37,14 +37,14 @@ mod test {
         {
             let mut infd = File::create(&source_path).unwrap();
             let data = rand_data(size);
-            infd.write(&data).unwrap();
+            infd.write_all(&data).unwrap();
         }
 
         {
             let infd = File::open(&source_path).unwrap();
             let inext = map_extents(&infd).unwrap().unwrap();
             // Single file, extent not shared.
-            assert_eq!(false, inext[0].shared);
+            assert!(!inext[0].shared);
         }
 
         let out = run(&[
@@ -65,8 +65,8 @@ mod test {
             // Extents should be shared.
             let inext = map_extents(&infd).unwrap().unwrap();
             let outext = map_extents(&outfd).unwrap().unwrap();
-            assert_eq!(true, inext[0].shared);
-            assert_eq!(true, outext[0].shared);
+            assert!(inext[0].shared);
+            assert!(outext[0].shared);
         }
 
         {
@@ -77,7 +77,7 @@ mod test {
                 .open(&dest_path).unwrap();
             outfd.seek(SeekFrom::Start(0)).unwrap();
             let data = rand_data(size);
-            outfd.write(&data).unwrap();
+            outfd.write_all(&data).unwrap();
             // brtfs at least seems to need this to force CoW and
             // de-share the extents.
             sync(&outfd).unwrap();
@@ -89,12 +89,68 @@ mod test {
             // First extent should now be un-shared.
             let inext = map_extents(&infd).unwrap().unwrap();
             let outext = map_extents(&outfd).unwrap().unwrap();
-            assert_eq!(false, inext[0].shared);
-            assert_eq!(false, outext[0].shared);
+            assert!(!inext[0].shared);
+            assert!(!outext[0].shared);
         }
 
     }
 
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_reflink", ignore = "No FS support")]
+    fn copy_tree_reflink_always(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let source_path = dir.path().join("source");
+        let dest_path = dir.path().join("dest");
+        create_dir_all(source_path.join("sub")).unwrap();
+        for i in 0..10 {
+            let mut fd = File::create(source_path.join(format!("sub/file{}.bin", i))).unwrap();
+            fd.write_all(&rand_data(16 * 1024)).unwrap();
+        }
+
+        let out = run(&[
+            "--driver", drv,
+            "-r", "-v",
+            "--reflink=always",
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+            .unwrap();
+
+        assert!(out.status.success());
+        let stdout = String::from_utf8(out.stdout).unwrap();
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert!(stdout.contains("Reflinked 10 of 10 files (100%)") || stderr.contains("Reflinked 10 of 10 files (100%)"));
+        for i in 0..10 {
+            let f = format!("sub/file{}.bin", i);
+            assert!(files_match(&source_path.join(&f), &dest_path.join(&f)));
+        }
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(not(feature = "test_no_reflink"), ignore = "Reflinks supported")]
+    fn file_copy_reflink_always_unsupported(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let source_path = dir.path().join("source.bin");
+        let dest_path = dir.path().join("dest.bin");
+        create_file(&source_path, "data").unwrap();
+
+        let out = run(&[
+            "--driver", drv,
+            "--reflink=always",
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+            .unwrap();
+
+        assert!(!out.status.success());
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert!(stderr.contains(source_path.to_str().unwrap()));
+        assert!(stderr.contains("os error"));
+        assert!(!dest_path.exists());
+    }
+
     #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
     #[test_case("parfile"; "Test with parallel file driver")]
     #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
@@ -126,6 +182,49 @@ mod test {
         assert_eq!(from_data, to_data);
     }
 
+    // Offsets above 4GB would be truncated on 32-bit platforms if
+    // handled as usize. Without extent support the block driver
+    // would copy the holes, so it is skipped.
+    #[cfg_attr(all(feature = "parblock", not(feature = "test_no_extents")), test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn test_sparse_over_4gb(drv: &str) {
+        use std::fs::OpenOptions;
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        let dir = tempdir_rel().unwrap();
+        let from = dir.path().join("sparse.bin");
+        let to = dir.path().join("target.bin");
+
+        let len = 5 * 1024 * 1024 * 1024 + 8;
+        let marks = [(4 * 1024 * 1024 * 1024 - 4, b"c00lc0d3"), (len - 8, b"t41ld4t4")];
+        {
+            let mut fd = File::create(&from).unwrap();
+            fd.set_len(len).unwrap();
+            for (off, data) in marks {
+                fd.seek(SeekFrom::Start(off)).unwrap();
+                fd.write_all(data).unwrap();
+            }
+        }
+
+        let out = run(&[
+            "--driver",
+            drv,
+            from.to_str().unwrap(),
+            to.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+
+        assert_eq!(len, to.metadata().unwrap().len());
+        let mut fd = OpenOptions::new().read(true).open(&to).unwrap();
+        for (off, data) in marks {
+            let mut buf = [0; 8];
+            fd.seek(SeekFrom::Start(off)).unwrap();
+            fd.read_exact(&mut buf).unwrap();
+            assert_eq!(data, &buf);
+        }
+    }
+
     #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
     #[test_case("parfile"; "Test with parallel file driver")]
     #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
@@ -156,6 +255,38 @@ mod test {
         assert_eq!(from_data, to_data);
     }
 
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn test_sparse_manifest_checksum(drv: &str) {
+        use libxcp::checksum::{checksum_file, ChecksumType};
+        use libxcp::manifest::Manifest;
+
+        let dir = tempdir_rel().unwrap();
+        let from = dir.path().join("sparse.bin");
+        let to = dir.path().join("target.bin");
+        let manifest_path = dir.path().join("manifest.json");
+
+        create_sparse(&from, 1024, 1024).unwrap();
+        assert!(probably_sparse(&from).unwrap());
+
+        let out = run(&[
+            "--driver",
+            drv,
+            "--manifest",
+            manifest_path.to_str().unwrap(),
+            from.to_str().unwrap(),
+            to.to_str().unwrap(),
+        ]).unwrap();
+
+        assert!(out.status.success());
+        assert!(probably_sparse(&to).unwrap());
+
+        let manifest = Manifest::read(&manifest_path).unwrap();
+        assert_eq!(1, manifest.files.len());
+        assert_eq!(checksum_file(&from, ChecksumType::Blake3).unwrap(), manifest.files[0].checksum);
+    }
+
     #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
     #[test_case("parfile"; "Test with parallel file driver")]
     #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
@@ -274,4 +405,177 @@ mod test {
         println!("Compare trees...");
         compare_trees(&src, &dest).unwrap();
     }
+
+    // A size-limited tmpfs, unmounted on drop. Requires root.
+    struct SmallTmpfs(std::path::PathBuf);
+
+    impl SmallTmpfs {
+        fn mount(dir: &std::path::Path, size: &str) -> Option<SmallTmpfs> {
+            std::fs::create_dir_all(dir).unwrap();
+            let out = Command::new("mount")
+                .args(["-t", "tmpfs", "-o", &format!("size={}", size), "tmpfs", dir.to_str().unwrap()])
+                .output()
+                .ok()?;
+            out.status.success().then(|| SmallTmpfs(dir.to_path_buf()))
+        }
+    }
+
+    impl Drop for SmallTmpfs {
+        fn drop(&mut self) {
+            let _ = Command::new("umount").arg(&self.0).output();
+        }
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock", false; "Test with parallel block driver"))]
+    #[test_case("parfile", false; "Test with parallel file driver")]
+    #[test_case("parfile", true; "Test with parallel file driver and really continue")]
+    fn copy_destination_full(drv: &str, really_continue: bool) {
+        let dir = tempdir_rel().unwrap();
+        let source = dir.path().join("source");
+        let dest = dir.path().join("dest");
+        std::fs::create_dir_all(&source).unwrap();
+
+        let Some(_mount) = SmallTmpfs::mount(&dest, "1m") else {
+            println!("Cannot mount tmpfs; skipping test");
+            return;
+        };
+
+        let size = 300 * 1024;
+        for i in 0..8 {
+            let mut fd = File::create(source.join(format!("file{}.bin", i))).unwrap();
+            fd.write_all(&rand_data(size)).unwrap();
+        }
+
+        let mut args = vec![
+            "--driver", drv,
+            "-r",
+            "--continue-on-error",
+            "--block-size", "64K",
+            "--no-target-directory",
+        ];
+        if really_continue {
+            args.push("--really-continue-on-enospc");
+        }
+        args.extend([source.to_str().unwrap(), dest.to_str().unwrap()]);
+        let out = run(&args).unwrap();
+
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("Destination full"));
+        if !really_continue {
+            assert_eq!(1, stderr.matches("Destination full").count());
+        }
+
+        // Only complete files should remain.
+        let mut copied = 0;
+        for entry in std::fs::read_dir(&dest).unwrap() {
+            let entry = entry.unwrap();
+            assert_eq!(size as u64, entry.metadata().unwrap().len());
+            assert!(files_match(&source.join(entry.file_name()), &entry.path()));
+            copied += 1;
+        }
+        assert!(copied < 8);
+    }
+
+    // A bind mount, unmounted on drop. Requires root.
+    struct BindMount(std::path::PathBuf);
+
+    impl BindMount {
+        fn mount(from: &std::path::Path, to: &std::path::Path) -> Option<BindMount> {
+            std::fs::create_dir_all(to).unwrap();
+            let out = Command::new("mount")
+                .args(["--bind", from.to_str().unwrap(), to.to_str().unwrap()])
+                .output()
+                .ok()?;
+            out.status.success().then(|| BindMount(to.to_path_buf()))
+        }
+    }
+
+    impl Drop for BindMount {
+        fn drop(&mut self) {
+            let _ = Command::new("umount").arg(&self.0).output();
+        }
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    fn copy_into_bind_mounted_source(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let source = dir.path().join("data");
+        create_dir_all(source.join("sub")).unwrap();
+        File::create(source.join("sub/file.txt")).unwrap();
+        let bind = dir.path().join("data-bind");
+
+        let Some(_mount) = BindMount::mount(&source, &bind) else {
+            println!("Cannot bind mount; skipping test");
+            return;
+        };
+
+        let out = run(&[
+            "--driver", drv,
+            "-r",
+            source.to_str().unwrap(),
+            bind.to_str().unwrap(),
+        ]).unwrap();
+
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("possibly via a bind mount"));
+        assert!(!source.join("data/sub/file.txt").exists());
+    }
+
+    struct LoopDevice(String);
+
+    impl LoopDevice {
+        // Attach an image as a loop device. This requires root, so
+        // returns None if it fails.
+        fn attach(img: &std::path::Path) -> Option<LoopDevice> {
+            let out = Command::new("losetup")
+                .args(["-f", "--show", img.to_str().unwrap()])
+                .output()
+                .ok()?;
+            out.status.success()
+                .then(|| LoopDevice(String::from_utf8_lossy(&out.stdout).trim().to_string()))
+        }
+    }
+
+    impl Drop for LoopDevice {
+        fn drop(&mut self) {
+            let _ = Command::new("losetup").args(["-d", &self.0]).output();
+        }
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn device_image_copy_sparse(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let img = dir.path().join("disk.img");
+        let dest = dir.path().join("copy.img");
+        {
+            let mut fd = File::create(&img).unwrap();
+            fd.set_len(20 * 1024 * 1024).unwrap();
+            fd.write_all(&rand_data(4096)).unwrap();
+            fd.seek(SeekFrom::End(-4096)).unwrap();
+            fd.write_all(&rand_data(4096)).unwrap();
+        }
+
+        let Some(dev) = LoopDevice::attach(&img) else {
+            println!("Cannot attach loop device; skipping test");
+            return;
+        };
+
+        let out = run(&[
+            "--driver", drv,
+            "--sparse",
+            &dev.0,
+            dest.to_str().unwrap(),
+        ]).unwrap();
+
+        assert!(out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("20.00 MiB"));
+        assert!(files_match(&img, &dest));
+        assert!(probably_sparse(&dest).unwrap());
+    }
 }
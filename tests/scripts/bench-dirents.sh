@@ -0,0 +1,42 @@
+#!/usr/bin/bash
+
+# Compare the wall time of copying many small files in a few
+# directories with and without '--batch-dirents'. The copy is made
+# under DEST_ROOT, so to measure a particular filesystem (e.g. ext4 or
+# xfs) create one with make-filesystems.sh and pass its mount point.
+#
+# Usage: bench-dirents.sh [DEST_ROOT] [NUM_FILES] [NUM_DIRS] [XCP_ARGS...]
+
+set -euo pipefail
+
+# chdir to source root
+cd "$(dirname "$0")"/../..
+
+dest_root=${1:-$(mktemp -d)}
+nfiles=${2:-200000}
+ndirs=${3:-4}
+shift 3 || true
+
+work=$(mktemp -d)
+dest=$(mktemp -d -p "$dest_root")
+trap 'rm -rf "$work" "$dest"' EXIT
+
+cargo build --release --locked
+
+echo >&2 "==== creating $nfiles small files in $ndirs directories ===="
+src=$work/src
+for ((d = 0; d < ndirs; d++)); do
+  mkdir -p "$src/d$d"
+done
+for ((i = 0; i < nfiles; i++)); do
+  echo "$i" >"$src/d$((i % ndirs))/file$i"
+done
+sync
+
+for args in "" "--batch-dirents"; do
+  rm -rf "$dest/copy"
+  sync
+  echo >&2 "==== ${args:-default} on $(stat -f -c %T "$dest") ===="
+  # shellcheck disable=SC2086
+  time ./target/release/xcp --no-progress -r $args "$@" "$src" "$dest/copy"
+done
@@ -0,0 +1,56 @@
+#!/usr/bin/bash
+
+# Count the directory-creation syscalls made copying a deep, wide
+# tree of directories. Requires strace. To compare against another
+# build (e.g. of an earlier commit) pass its binary as XCP_OTHER.
+#
+# Usage: [XCP_OTHER=path/to/xcp] bench-mkdir.sh [DEPTH] [WIDTH] [XCP_ARGS...]
+
+set -euo pipefail
+
+# chdir to source root
+cd "$(dirname "$0")"/../..
+
+depth=${1:-6}
+width=${2:-4}
+shift 2 || true
+
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+
+cargo build --release --locked
+
+echo >&2 "==== creating tree of depth $depth, width $width ===="
+src=$work/src
+mkdir "$src"
+level=("$src")
+for ((d = 0; d < depth; d++)); do
+  next=()
+  for dir in "${level[@]}"; do
+    for ((w = 0; w < width; w++)); do
+      mkdir "$dir/d$w"
+      echo "$d.$w" >"$dir/d$w/file"
+      next+=("$dir/d$w")
+    done
+  done
+  level=("${next[@]}")
+done
+echo >&2 "$(find "$src" -type d | wc -l) directories"
+
+# Each build copies twice; into a new tree, and then over the
+# existing one.
+count() {
+  local xcp=$1 dest=$2
+  mkdir -p "$dest"
+  echo >&2 "==== $xcp ===="
+  strace -f -c -e trace=mkdir,mkdirat,stat,newfstatat,statx \
+    "$xcp" --no-progress -r "${@:3}" "$src" "$dest" 2>&1 >/dev/null \
+    | grep -E "calls|mkdir|stat|total"
+}
+
+count ./target/release/xcp "$work/dest" "$@"
+count ./target/release/xcp "$work/dest" "$@"
+if [[ -n ${XCP_OTHER:-} ]]; then
+  count "$XCP_OTHER" "$work/dest-other" "$@"
+  count "$XCP_OTHER" "$work/dest-other" "$@"
+fi
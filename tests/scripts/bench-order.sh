@@ -0,0 +1,44 @@
+#!/usr/bin/bash
+
+# Compare the wall time of copying a mixed tree, many small files plus
+# a few large ones placed last in scan order, with each '--order'.
+# With 'scan' the large files tend to finish alone after the small
+# ones; 'largest-first' should overlap them.
+#
+# Usage: bench-order.sh [NUM_SMALL] [LARGE_MB] [XCP_ARGS...]
+
+set -euo pipefail
+
+# chdir to source root
+cd "$(dirname "$0")"/../..
+
+nsmall=${1:-20000}
+large_mb=${2:-1024}
+shift 2 || true
+
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+
+cargo build --release --locked
+
+echo >&2 "==== creating $nsmall small files and 2 x ${large_mb}MB ===="
+src=$work/src
+for ((d = 0; d < nsmall / 1000 + 1; d++)); do
+  mkdir -p "$src/a$d"
+done
+for ((i = 0; i < nsmall; i++)); do
+  head -c $((RANDOM + 1)) /dev/urandom >"$src/a$((i / 1000))/file$i"
+done
+# Named to sort after the small files in most directory orders.
+mkdir -p "$src/zz"
+for n in 1 2; do
+  head -c $((large_mb * 1024 * 1024)) /dev/urandom >"$src/zz/large$n"
+done
+sync
+
+for order in scan largest-first smallest-first; do
+  rm -rf "$work/dest"
+  sync
+  echo >&2 "==== --order=$order ===="
+  time ./target/release/xcp --no-progress -r --order="$order" "$@" "$src" "$work/dest"
+done
@@ -0,0 +1,47 @@
+#!/usr/bin/bash
+
+# Benchmark cloning a large tree of small files with
+# '--reflink=always' on a btrfs loopback image. Requires root (or
+# sudo) and btrfs-progs.
+#
+# Usage: bench-reflink.sh [NUM_FILES] [XCP_ARGS...]
+
+set -euo pipefail
+
+# chdir to source root
+cd "$(dirname "$0")"/../..
+
+nfiles=${1:-100000}
+shift || true
+
+work=$(mktemp -d)
+img=$work/btrfs.img
+root=$work/mnt
+
+cleanup() {
+  sudo umount "$root" 2>/dev/null || true
+  rm -rf "$work"
+}
+trap cleanup EXIT
+
+cargo build --release --locked
+
+fallocate --length 4G "$img"
+mkfs.btrfs --quiet "$img"
+mkdir "$root"
+sudo mount -o loop "$img" "$root"
+sudo chown "$USER" "$root"
+
+echo >&2 "==== creating $nfiles files ===="
+src=$root/src
+for ((d = 0; d < nfiles / 1000 + 1; d++)); do
+  mkdir -p "$src/$d"
+done
+for ((i = 0; i < nfiles; i++)); do
+  head -c $((RANDOM + 1)) /dev/urandom >"$src/$((i / 1000))/file$i"
+done
+sync
+
+echo >&2 "==== cloning ===="
+time ./target/release/xcp -v --no-progress --reflink=always -r "$@" "$src" "$root/dest" 2>&1 \
+  | grep -E "Reflinked|ERROR"
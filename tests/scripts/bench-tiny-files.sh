@@ -0,0 +1,54 @@
+#!/usr/bin/bash
+
+# Compare the wall time of copying a tree of mostly tiny files with and
+# without '--no-tiny-file-fastpath'. The sizes roughly follow those of
+# a node_modules tree: a tenth of the files are empty, a third are
+# under 512 bytes, and the rest are mostly a few KB with a tail of
+# larger ones. The copy is made under DEST_ROOT; see
+# bench-dirents.sh.
+#
+# Usage: bench-tiny-files.sh [DEST_ROOT] [NUM_FILES] [XCP_ARGS...]
+
+set -euo pipefail
+
+# chdir to source root
+cd "$(dirname "$0")"/../..
+
+dest_root=${1:-$(mktemp -d)}
+nfiles=${2:-50000}
+shift 2 || true
+
+work=$(mktemp -d)
+dest=$(mktemp -d -p "$dest_root")
+trap 'rm -rf "$work" "$dest"' EXIT
+
+cargo build --release --locked
+
+echo >&2 "==== creating $nfiles files ===="
+src=$work/src
+for ((i = 0; i < nfiles; i++)); do
+  dir=$src/pkg$((i / 100))/lib$((i % 7))
+  [[ -d $dir ]] || mkdir -p "$dir"
+  pick=$((RANDOM % 100))
+  if ((pick < 10)); then
+    size=0
+  elif ((pick < 45)); then
+    size=$((RANDOM % 512 + 1))
+  elif ((pick < 85)); then
+    size=$((RANDOM % 7680 + 512))
+  elif ((pick < 98)); then
+    size=$((RANDOM % 57344 + 8192))
+  else
+    size=$((RANDOM * 32))
+  fi
+  head -c "$size" /dev/urandom >"$dir/file$i.js"
+done
+sync
+
+for args in "" "--no-tiny-file-fastpath"; do
+  rm -rf "$dest/copy"
+  sync
+  echo >&2 "==== ${args:-default} on $(stat -f -c %T "$dest") ===="
+  # shellcheck disable=SC2086
+  time ./target/release/xcp --no-progress -r $args "$@" "$src" "$dest/copy"
+done
@@ -9,7 +9,7 @@ cd "$(dirname "$0")"/../..
 fs=$(df --output=fstype . | tail -n 1)
 
 # list features supported by all filesystems
-features=(use_linux "$@")
+features=(use_linux test_faults "$@")
 
 # disable tests that will not work on this filesystem
 case "$fs" in
@@ -21,7 +21,7 @@ use rand::{Rng, RngCore, SeedableRng, thread_rng};
 use rand_distr::{Alphanumeric, Pareto, Triangular, Standard};
 use rand_xorshift::XorShiftRng;
 use std::cmp;
-use std::env::current_dir;
+use std::env::{self, current_dir};
 use std::fs::{create_dir_all, File, FileTimes};
 use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
@@ -34,13 +34,62 @@ use walkdir::WalkDir;
 
 pub type TResult = result::Result<(), Error>;
 
+/// The xcp command. If `XCP_TEST_EXECUTOR` is set it is passed as
+/// '--executor', to run the suite on another executor.
 pub fn get_command() -> Result<Command, Error> {
     let exe = env!("CARGO_BIN_EXE_xcp");
-    Ok(Command::new(exe))
+    let mut cmd = Command::new(exe);
+    cmd.args(executor_args());
+    Ok(cmd)
+}
+
+fn executor_args() -> Vec<String> {
+    match env::var("XCP_TEST_EXECUTOR") {
+        Ok(executor) => vec!["--executor".to_string(), executor],
+        Err(_) => Vec::new(),
+    }
+}
+
+// The arguments with any '--executor' added; 'verify' must come first.
+fn with_executor(args: &[&str]) -> Vec<String> {
+    let (verb, rest) = match args.split_first() {
+        Some((&"verify", rest)) => (Some("verify".to_string()), rest),
+        _ => (None, args),
+    };
+    verb.into_iter()
+        .chain(executor_args())
+        .chain(rest.iter().map(|a| a.to_string()))
+        .collect()
 }
 
 pub fn run(args: &[&str]) -> Result<Output, Error> {
-    let out = get_command()?.args(args).output()?;
+    let out = Command::new(env!("CARGO_BIN_EXE_xcp")).args(with_executor(args)).output()?;
+    println!("STDOUT: {}", String::from_utf8_lossy(&out.stdout));
+    println!("STDERR: {}", String::from_utf8_lossy(&out.stderr));
+    Ok(out)
+}
+
+/// As [run], with the umask of the xcp process set to `umask`.
+pub fn run_with_umask(umask: u32, args: &[&str]) -> Result<Output, Error> {
+    let exe = env!("CARGO_BIN_EXE_xcp");
+    let out = Command::new("sh")
+        .arg("-c")
+        .arg(format!("umask {:03o} && exec \"$0\" \"$@\"", umask))
+        .arg(exe)
+        .args(with_executor(args))
+        .output()?;
+    println!("STDOUT: {}", String::from_utf8_lossy(&out.stdout));
+    println!("STDERR: {}", String::from_utf8_lossy(&out.stderr));
+    Ok(out)
+}
+
+/// As [run], failing the operations in `faults` if xcp is built with
+/// the `test_faults` feature; see `XCP_TEST_FAULTS`.
+pub fn run_with_faults(faults: &str, args: &[&str]) -> Result<Output, Error> {
+    let out = Command::new(env!("CARGO_BIN_EXE_xcp"))
+        .env("XCP_TEST_FAULTS", faults)
+        .args(with_executor(args))
+        .output()?;
     println!("STDOUT: {}", String::from_utf8_lossy(&out.stdout));
     println!("STDERR: {}", String::from_utf8_lossy(&out.stderr));
     Ok(out)